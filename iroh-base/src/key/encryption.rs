@@ -31,14 +31,29 @@ impl SharedSecret {
 
     /// Seals the provided cleartext.
     pub fn seal(&self, buffer: &mut dyn Buffer) {
-        use aead::{AeadCore, AeadInPlace, OsRng};
+        use aead::{AeadCore, OsRng};
 
         let nonce = crypto_box::ChaChaBox::generate_nonce(&mut OsRng);
+        self.seal_in_place(&nonce.into(), buffer);
+    }
+
+    /// Seals the provided cleartext using a fixed nonce.
+    ///
+    /// Only meant for producing deterministic test vectors, reusing a nonce breaks the
+    /// confidentiality of the messages.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn seal_with_nonce(&self, nonce: &[u8; NONCE_LEN], buffer: &mut dyn Buffer) {
+        self.seal_in_place(nonce, buffer);
+    }
+
+    fn seal_in_place(&self, nonce: &[u8; NONCE_LEN], buffer: &mut dyn Buffer) {
+        use aead::AeadInPlace;
+
         self.0
-            .encrypt_in_place(&nonce, &[], buffer)
+            .encrypt_in_place(nonce.into(), &[], buffer)
             .expect("encryption failed");
 
-        buffer.extend_from_slice(&nonce).expect("buffer too small");
+        buffer.extend_from_slice(nonce).expect("buffer too small");
     }
 
     /// Opens the ciphertext, which must have been created using `Self::seal`, and places the clear text into the provided buffer.
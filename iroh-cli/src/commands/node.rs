@@ -7,8 +7,13 @@ use comfy_table::Table;
 use comfy_table::{presets::NOTHING, Cell};
 use futures::{Stream, StreamExt};
 use human_time::ToHumanTimeString;
+use indicatif::HumanBytes;
 use iroh::client::Iroh;
-use iroh::net::{key::PublicKey, magic_endpoint::ConnectionInfo, magicsock::DirectAddrInfo};
+use iroh::net::{
+    key::PublicKey,
+    magic_endpoint::ConnectionInfo,
+    magicsock::{DirectAddrInfo, PathPing},
+};
 use iroh::rpc_protocol::ProviderService;
 use quic_rpc::ServiceConnection;
 
@@ -19,6 +24,8 @@ pub enum NodeCommands {
     Connections,
     /// Get connection information about a particular node
     Connection { node_id: PublicKey },
+    /// Ping a node on all its known paths, to check whether hole punching succeeded
+    Ping { node_id: PublicKey },
     /// Get status of the running node.
     Status,
     /// Get statistics and metrics from the running node.
@@ -60,6 +67,10 @@ impl NodeCommands {
                     None => println!("Not Found"),
                 }
             }
+            Self::Ping { node_id } => {
+                let paths = iroh.node.ping(node_id).await?;
+                println!("{}", fmt_pings(paths));
+            }
             Self::Shutdown { force } => {
                 iroh.node.shutdown(force).await?;
             }
@@ -123,6 +134,13 @@ fn fmt_connection(info: ConnectionInfo) -> String {
         conn_type,
         latency,
         last_used,
+        relay_reachability,
+        time_to_direct,
+        no_direct_path,
+        relay_policy: _,
+        bytes_sent,
+        bytes_recv,
+        family_stats: _,
     } = info;
     let timestamp = time::OffsetDateTime::now_utc()
         .format(&time::format_description::well_known::Rfc2822)
@@ -135,8 +153,18 @@ fn fmt_connection(info: ConnectionInfo) -> String {
         .map(|r| r.to_string())
         .unwrap_or_else(|| String::from("unknown"));
     table.add_row([bold_cell("relay url"), relay_url.into()]);
+    table.add_row([
+        bold_cell("relay reachability"),
+        relay_reachability.to_string().into(),
+    ]);
     table.add_row([bold_cell("connection type"), conn_type.to_string().into()]);
     table.add_row([bold_cell("latency"), fmt_latency(latency).into()]);
+    let direct_path = match (time_to_direct, no_direct_path) {
+        (Some(time), _) => format!("after {}", time.to_human_time_string()),
+        (None, Some(reason)) => format!("none ({reason})"),
+        (None, None) => String::from("pending"),
+    };
+    table.add_row([bold_cell("direct path"), direct_path.into()]);
     table.add_row([
         bold_cell("last used"),
         last_used
@@ -144,6 +172,15 @@ fn fmt_connection(info: ConnectionInfo) -> String {
             .map(Cell::new)
             .unwrap_or_else(never),
     ]);
+    table.add_row([
+        bold_cell("traffic"),
+        format!(
+            "{} sent, {} received",
+            HumanBytes(bytes_sent),
+            HumanBytes(bytes_recv)
+        )
+        .into(),
+    ]);
     table.add_row([bold_cell("known addresses"), addrs.len().into()]);
 
     let general_info = table.to_string();
@@ -152,6 +189,21 @@ fn fmt_connection(info: ConnectionInfo) -> String {
     format!("{general_info}\n\n{addrs_info}",)
 }
 
+fn fmt_pings(paths: Vec<PathPing>) -> String {
+    let mut table = Table::new();
+    table
+        .load_preset(NOTHING)
+        .set_header(["path", "latency"].into_iter().map(bold_cell));
+    for PathPing { path, latency } in paths {
+        let latency = match latency {
+            Some(latency) => latency.to_human_time_string(),
+            None => String::from("timed out"),
+        };
+        table.add_row([path.to_string(), latency]);
+    }
+    table.to_string()
+}
+
 fn direct_addr_row(info: DirectAddrInfo) -> comfy_table::Row {
     let DirectAddrInfo {
         addr,
@@ -308,5 +308,6 @@ fn fmt_relay_mode(relay_mode: &RelayMode) -> String {
             .map(|url| url.to_string())
             .collect::<Vec<_>>()
             .join(", "),
+        RelayMode::Signed(map) => format!("Relay servers signed by {}", map.publisher()),
     }
 }
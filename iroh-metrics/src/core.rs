@@ -2,7 +2,13 @@ use anyhow::Error;
 use erased_set::ErasedSyncSet;
 use once_cell::sync::OnceCell;
 #[cfg(feature = "metrics")]
-use prometheus_client::{encoding::text::encode, registry::Registry};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, RwLock},
+};
+
+#[cfg(feature = "metrics")]
+use prometheus_client::{encoding::text::encode, metrics::family::Family, registry::Registry};
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use tracing::trace;
@@ -77,6 +83,190 @@ impl Counter {
     }
 }
 
+/// Open Metrics [`Gauge`] to measure a current value.
+///
+/// Single value metric which can go up and down, like the length of a queue.
+#[derive(Debug, Clone)]
+pub struct Gauge {
+    /// The actual prometheus gauge.
+    #[cfg(feature = "metrics")]
+    pub gauge: prometheus_client::metrics::gauge::Gauge,
+    /// What this gauge measures.
+    pub description: &'static str,
+}
+
+impl Gauge {
+    /// Constructs a new gauge, based on the given `description`.
+    pub fn new(description: &'static str) -> Self {
+        Gauge {
+            #[cfg(feature = "metrics")]
+            gauge: Default::default(),
+            description,
+        }
+    }
+
+    /// Set the [`Gauge`] to `v`, returning the previous value.
+    #[cfg(feature = "metrics")]
+    pub fn set(&self, v: i64) -> i64 {
+        self.gauge.set(v)
+    }
+
+    /// Set the [`Gauge`] to `v`, returning the previous value.
+    #[cfg(not(feature = "metrics"))]
+    pub fn set(&self, _v: i64) -> i64 {
+        0
+    }
+
+    /// Get the current value of the [`Gauge`].
+    pub fn get(&self) -> i64 {
+        #[cfg(feature = "metrics")]
+        {
+            self.gauge.get()
+        }
+        #[cfg(not(feature = "metrics"))]
+        0
+    }
+}
+
+/// Open Metrics [`Histogram`] to measure the distribution of values.
+///
+/// Counts the observed values in buckets, like the latencies of operations.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// The actual prometheus histogram.
+    #[cfg(feature = "metrics")]
+    pub histogram: prometheus_client::metrics::histogram::Histogram,
+    /// What this histogram measures.
+    pub description: &'static str,
+}
+
+impl Histogram {
+    /// Constructs a new histogram with the upper bounds of its `buckets`, based on the given
+    /// `description`.
+    ///
+    /// A bucket for all values above the last bound is added.
+    pub fn new(description: &'static str, buckets: &[f64]) -> Self {
+        #[cfg(not(feature = "metrics"))]
+        let _ = buckets;
+        Histogram {
+            #[cfg(feature = "metrics")]
+            histogram: prometheus_client::metrics::histogram::Histogram::new(
+                buckets.iter().copied(),
+            ),
+            description,
+        }
+    }
+
+    /// Constructs a new histogram for durations in seconds, from 100µs to about 6.5s.
+    pub fn new_latency(description: &'static str) -> Self {
+        const BUCKETS: [f64; 9] = [
+            0.0001, 0.0004, 0.0016, 0.0064, 0.0256, 0.1024, 0.4096, 1.6384, 6.5536,
+        ];
+        Self::new(description, &BUCKETS)
+    }
+
+    /// Record the value `v`.
+    #[cfg(feature = "metrics")]
+    pub fn observe(&self, v: f64) {
+        self.histogram.observe(v)
+    }
+
+    /// Record the value `v`.
+    #[cfg(not(feature = "metrics"))]
+    pub fn observe(&self, _v: f64) {}
+}
+
+/// Open Metrics [`Counter`]s, one for every value of a label.
+///
+/// For events which need to be attributed to a value only known at runtime, like the
+/// protocol of a connection.  Keep the number of distinct values small, every value is
+/// a separate time series.
+#[derive(Debug, Clone)]
+pub struct LabeledCounter {
+    /// The actual prometheus counters.
+    #[cfg(feature = "metrics")]
+    pub family: Family<[(&'static str, String); 1], prometheus_client::metrics::counter::Counter>,
+    /// The label values counted so far, to list them without going through prometheus.
+    #[cfg(feature = "metrics")]
+    values: Arc<RwLock<BTreeSet<String>>>,
+    /// The name of the label.
+    pub label: &'static str,
+    /// What this counter measures.
+    pub description: &'static str,
+}
+
+impl LabeledCounter {
+    /// Constructs a new labeled counter, based on the given `label` name and `description`.
+    pub fn new(label: &'static str, description: &'static str) -> Self {
+        LabeledCounter {
+            #[cfg(feature = "metrics")]
+            family: Default::default(),
+            #[cfg(feature = "metrics")]
+            values: Default::default(),
+            label,
+            description,
+        }
+    }
+
+    /// Increase the counter for the label `value` by 1, returning the previous value.
+    pub fn inc(&self, value: &str) -> u64 {
+        self.inc_by(value, 1)
+    }
+
+    /// Increase the counter for the label `value` by `v`, returning the previous value.
+    #[cfg(feature = "metrics")]
+    pub fn inc_by(&self, value: &str, v: u64) -> u64 {
+        if !self.values.read().expect("poisoned").contains(value) {
+            self.values
+                .write()
+                .expect("poisoned")
+                .insert(value.to_string());
+        }
+        self.family
+            .get_or_create(&[(self.label, value.to_string())])
+            .inc_by(v)
+    }
+
+    /// Increase the counter for the label `value` by `v`, returning the previous value.
+    #[cfg(not(feature = "metrics"))]
+    pub fn inc_by(&self, _value: &str, _v: u64) -> u64 {
+        0
+    }
+
+    /// Get the current value of the counter for the label `value`.
+    pub fn get(&self, value: &str) -> u64 {
+        #[cfg(feature = "metrics")]
+        {
+            if !self.values.read().expect("poisoned").contains(value) {
+                return 0;
+            }
+            self.family
+                .get_or_create(&[(self.label, value.to_string())])
+                .get()
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = value;
+            0
+        }
+    }
+
+    /// The label values which were counted so far.
+    pub fn values(&self) -> Vec<String> {
+        #[cfg(feature = "metrics")]
+        {
+            self.values
+                .read()
+                .expect("poisoned")
+                .iter()
+                .cloned()
+                .collect()
+        }
+        #[cfg(not(feature = "metrics"))]
+        Vec::new()
+    }
+}
+
 /// Description of a group of metrics.
 pub trait Metric:
     Default + struct_iterable::Iterable + Sized + std::fmt::Debug + 'static + Send + Sync
@@ -90,6 +280,12 @@ pub trait Metric:
         for (metric, counter) in this.iter() {
             if let Some(counter) = counter.downcast_ref::<Counter>() {
                 sub_registry.register(metric, counter.description, counter.counter.clone());
+            } else if let Some(counter) = counter.downcast_ref::<LabeledCounter>() {
+                sub_registry.register(metric, counter.description, counter.family.clone());
+            } else if let Some(gauge) = counter.downcast_ref::<Gauge>() {
+                sub_registry.register(metric, gauge.description, gauge.gauge.clone());
+            } else if let Some(histogram) = counter.downcast_ref::<Histogram>() {
+                sub_registry.register(metric, histogram.description, histogram.histogram.clone());
             }
         }
         this
@@ -31,6 +31,38 @@ macro_rules! inc_by {
     };
 }
 
+/// Increment the given labeled counter by 1 for the label `value`.
+#[macro_export]
+macro_rules! inc_labeled {
+    ($m:ty, $f:ident, $value:expr) => {
+        <$m as $crate::core::Metric>::with_metric(|m| m.$f.inc($value));
+    };
+}
+
+/// Increment the given labeled counter `n` for the label `value`.
+#[macro_export]
+macro_rules! inc_labeled_by {
+    ($m:ty, $f:ident, $value:expr, $n:expr) => {
+        <$m as $crate::core::Metric>::with_metric(|m| m.$f.inc_by($value, $n));
+    };
+}
+
+/// Set the given gauge to `v`.
+#[macro_export]
+macro_rules! set {
+    ($m:ty, $f:ident, $v:expr) => {
+        <$m as $crate::core::Metric>::with_metric(|m| m.$f.set($v));
+    };
+}
+
+/// Record the value `v` in the given histogram.
+#[macro_export]
+macro_rules! observe {
+    ($m:ty, $f:ident, $v:expr) => {
+        <$m as $crate::core::Metric>::with_metric(|m| m.$f.observe($v));
+    };
+}
+
 /// Report usage statistics to the configured endpoint.
 #[allow(unused_variables)]
 pub async fn report_usage_stats(report: &UsageStatsReport) {
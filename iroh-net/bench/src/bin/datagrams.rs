@@ -0,0 +1,147 @@
+//! Measures the throughput of QUIC datagrams between two endpoints on loopback.
+//!
+//! This is the path the TUN adapter of `iroh-net` uses for VPN packets: the client sends
+//! datagrams of the size of a packet, the server receives them through a
+//! [`iroh_net::datagram::channel`] in batches.  The goal is more than 1 Gbit/s.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use clap::Parser;
+use iroh_net::{datagram, relay::RelayMode, MagicEndpoint, NodeAddr};
+use tracing::trace;
+
+use iroh_net_bench::{configure_tracing_subscriber, rt, ALPN};
+
+#[derive(Parser, Debug, Clone, Copy)]
+#[clap(name = "datagrams")]
+struct Opt {
+    /// Size of each datagram, the MTU of a TUN device
+    #[clap(long, default_value = "1150")]
+    size: usize,
+    /// How long to send datagrams for, in seconds
+    #[clap(long, default_value = "10")]
+    duration: u64,
+    /// Maximum number of datagrams received in one batch
+    #[clap(long, default_value = "64")]
+    batch_size: usize,
+    /// Starting guess for maximum UDP payload size
+    #[clap(long, default_value = "1200")]
+    initial_mtu: u16,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    configure_tracing_subscriber();
+
+    let runtime = rt();
+    let endpoint = runtime.block_on(endpoint(&opt)).expect("server endpoint");
+    let server_addr = {
+        let port = endpoint.local_addr().expect("local addr").0.port();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        NodeAddr::new(endpoint.node_id()).with_direct_addresses([addr])
+    };
+
+    let server_thread = std::thread::spawn(move || {
+        let _guard = tracing::error_span!("server").entered();
+        runtime.block_on(server(endpoint, opt))
+    });
+    let client_thread = std::thread::spawn(move || {
+        let _guard = tracing::error_span!("client").entered();
+        rt().block_on(client(server_addr, opt))
+    });
+
+    let sent = client_thread.join().expect("client thread");
+    let received = server_thread.join().expect("server thread");
+    match (sent, received) {
+        (Ok(sent), Ok((received, bytes, elapsed))) => {
+            let gbits = (bytes * 8) as f64 / elapsed.as_secs_f64() / 1e9;
+            let lost = sent.saturating_sub(received);
+            println!("sent {sent} datagrams of {} bytes", opt.size);
+            println!("received {received} datagrams in {elapsed:.2?}, {gbits:.2} Gbit/s");
+            println!(
+                "lost {lost} datagrams ({:.2}%)",
+                lost as f64 * 100.0 / sent.max(1) as f64
+            );
+        }
+        (Err(err), _) => eprintln!("client failed: {err:#}"),
+        (_, Err(err)) => eprintln!("server failed: {err:#}"),
+    }
+}
+
+async fn endpoint(opt: &Opt) -> Result<MagicEndpoint> {
+    let mut config = quinn::TransportConfig::default();
+    config.initial_mtu(opt.initial_mtu);
+    config.datagram_receive_buffer_size(Some(16 * 1024 * 1024));
+    config.datagram_send_buffer_size(16 * 1024 * 1024);
+    MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .transport_config(config)
+        .bind(0)
+        .await
+}
+
+/// Receives datagrams until the client closes the connection.
+///
+/// Returns the number of datagrams and bytes received, and the time between the first and
+/// the last datagram.
+async fn server(endpoint: MagicEndpoint, opt: Opt) -> Result<(u64, u64, Duration)> {
+    let connecting = endpoint.accept().await.context("endpoint closed")?;
+    let conn = connecting.await.context("handshake failed")?;
+    let node = iroh_net::magic_endpoint::get_remote_node_id(&conn)?;
+
+    let (connections, mut receiver) = datagram::channel(datagram::DEFAULT_CAPACITY);
+    connections.add(node, conn);
+    // The receiver is done once the connection is closed.
+    drop(connections);
+
+    let mut batch = Vec::with_capacity(opt.batch_size);
+    let mut count = 0;
+    let mut bytes = 0;
+    let mut first = None;
+    let mut last = Instant::now();
+    while receiver.recv_batch(&mut batch, opt.batch_size).await > 0 {
+        last = Instant::now();
+        first.get_or_insert(last);
+        count += batch.len() as u64;
+        bytes += batch.iter().map(|(_, d)| d.len() as u64).sum::<u64>();
+        batch.clear();
+    }
+    let elapsed = first.map(|first| last - first).unwrap_or_default();
+    Ok((count, bytes, elapsed))
+}
+
+/// Sends datagrams for the configured duration, returning how many were sent.
+async fn client(server_addr: NodeAddr, opt: Opt) -> Result<u64> {
+    let endpoint = endpoint(&opt).await?;
+    let conn = endpoint
+        .connect(server_addr, ALPN)
+        .await
+        .context("unable to connect")?;
+    trace!("connected");
+
+    let data = Bytes::from(vec![0xAB; opt.size]);
+    let deadline = Instant::now() + Duration::from_secs(opt.duration);
+    let mut sent = 0;
+    while Instant::now() < deadline {
+        // Let the connection flush instead of dropping queued datagrams.
+        if conn.datagram_send_buffer_space() < data.len() {
+            tokio::task::yield_now().await;
+            continue;
+        }
+        conn.send_datagram(data.clone())
+            .context("failed to send datagram")?;
+        sent += 1;
+    }
+
+    // Give the last datagrams time to arrive.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    conn.close(0u32.into(), b"done");
+    endpoint.close(0u32.into(), b"done").await?;
+    Ok(sent)
+}
@@ -0,0 +1,142 @@
+//! Measures how fast an endpoint answers disco pings from many nodes.
+//!
+//! Every node sends a ping from its own key, so the endpoint learns about all of them, as it
+//! would with thousands of peers.  The pings are sent from `--window` sockets, each waiting
+//! for the pong of one node before pinging with the next one.  Compare the default of
+//! `--shards 0` with a number of shards close to the number of cores.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use iroh_net::{
+    key::{PublicKey, SecretKey},
+    relay::RelayMode,
+    test_utils::{disco_ping, disco_pong_tx_id},
+    MagicEndpoint,
+};
+use tokio::{net::UdpSocket, task::JoinSet};
+
+use iroh_net_bench::{configure_tracing_subscriber, ALPN};
+
+#[derive(Parser, Debug, Clone, Copy)]
+#[clap(name = "disco")]
+struct Opt {
+    /// Number of nodes pinging the endpoint
+    #[clap(long, default_value = "10000")]
+    peers: usize,
+    /// Value of the endpoint's `disco_shards` option
+    #[clap(long, default_value = "0")]
+    shards: usize,
+    /// Number of pings in flight
+    #[clap(long, default_value = "256")]
+    window: usize,
+    /// How long to wait for a pong, in milliseconds
+    #[clap(long, default_value = "1000")]
+    timeout: u64,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    configure_tracing_subscriber();
+
+    // The shards only run in parallel on a multi-threaded runtime.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let res = runtime.block_on(run(opt));
+    match res {
+        Ok((answered, elapsed)) => {
+            let lost = opt.peers - answered;
+            println!("{} nodes, {} shards", opt.peers, opt.shards);
+            println!(
+                "{answered} pings answered in {elapsed:.2?}, {:.0} pings/s",
+                answered as f64 / elapsed.as_secs_f64()
+            );
+            println!(
+                "lost {lost} pings ({:.2}%)",
+                lost as f64 * 100.0 / opt.peers.max(1) as f64
+            );
+        }
+        Err(err) => eprintln!("failed: {err:#}"),
+    }
+}
+
+/// Pings the endpoint from every node, returning the number of pongs and the time taken.
+async fn run(opt: Opt) -> Result<(usize, Duration)> {
+    let endpoint = MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .disco_shards(opt.shards)
+        .bind(0)
+        .await?;
+    let port = endpoint.local_addr()?.0.port();
+    let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+    let node_id = endpoint.node_id();
+
+    // Key generation is not part of the measurement.
+    let peers: Arc<Vec<SecretKey>> =
+        Arc::new((0..opt.peers).map(|_| SecretKey::generate()).collect());
+
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for i in 0..opt.window.max(1) {
+        let peers = peers.clone();
+        tasks.spawn(ping_nodes(peers, i, opt, addr, node_id));
+    }
+    let mut answered = 0;
+    while let Some(res) = tasks.join_next().await {
+        answered += res??;
+    }
+    let elapsed = start.elapsed();
+
+    endpoint.close(0u32.into(), b"done").await?;
+    Ok((answered, elapsed))
+}
+
+/// Pings the endpoint from every `window`th node starting at `first`, one after the other.
+///
+/// Returns the number of pongs received.
+async fn ping_nodes(
+    peers: Arc<Vec<SecretKey>>,
+    first: usize,
+    opt: Opt,
+    addr: SocketAddr,
+    node_id: PublicKey,
+) -> Result<usize> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let timeout = Duration::from_millis(opt.timeout);
+    let mut buf = vec![0u8; 2048];
+    let mut answered = 0;
+    for i in (first..peers.len()).step_by(opt.window.max(1)) {
+        let peer = &peers[i];
+        let mut tx_id = [0u8; 12];
+        tx_id[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        socket
+            .send_to(&disco_ping(peer, &node_id, tx_id), addr)
+            .await?;
+
+        // Skip anything else, like the pings the endpoint sends back to new nodes.
+        let wait = async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if disco_pong_tx_id(peer, &buf[..len]) == Some(tx_id) {
+                    return anyhow::Ok(());
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(res) => {
+                res?;
+                answered += 1;
+            }
+            Err(_) => tracing::debug!(node = i, "no pong"),
+        }
+    }
+    Ok(answered)
+}
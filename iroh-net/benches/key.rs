@@ -5,7 +5,7 @@ use rand::RngCore;
 
 pub fn seal_to(c: &mut Criterion) {
     let mut group = c.benchmark_group("seal_to");
-    for i in [64, 1024, 2048].iter() {
+    for i in [64, 1024, 1200, 2048].iter() {
         let mut text = vec![0u8; *i];
         rand::thread_rng().fill_bytes(&mut text);
 
@@ -40,7 +40,7 @@ pub fn seal_to(c: &mut Criterion) {
 
 pub fn open_from(c: &mut Criterion) {
     let mut group = c.benchmark_group("open_from");
-    for i in [64, 1024, 2048].iter() {
+    for i in [64, 1024, 1200, 2048].iter() {
         let mut text = vec![0u8; *i];
         rand::thread_rng().fill_bytes(&mut text);
 
@@ -0,0 +1,33 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use iroh_net::test_utils::prepare_dual_path_send;
+use rand::RngCore;
+
+/// Sending to a node over a UDP and a relay path at the same time.
+pub fn dual_path_send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dual_path_send");
+    let destination = "[fd00::1]:1234".parse().unwrap();
+    let addr = "192.0.2.1:1234".parse().unwrap();
+    for segments in [1usize, 10, 40].iter() {
+        let mut contents = vec![0u8; 1200 * segments];
+        rand::thread_rng().fill_bytes(&mut contents);
+        let transmits: Vec<_> = (0..10)
+            .map(|_| quinn_udp::Transmit {
+                destination,
+                ecn: None,
+                contents: Bytes::from(contents.clone()),
+                segment_size: (*segments > 1).then_some(1200),
+                src_ip: None,
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("segments", segments), segments, |b, _| {
+            let mut buf = Vec::new();
+            b.iter(|| black_box(prepare_dual_path_send(&mut buf, &transmits, addr)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dual_path_send);
+criterion_main!(benches);
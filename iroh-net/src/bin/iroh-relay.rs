@@ -22,7 +22,7 @@ use iroh_net::key::SecretKey;
 use iroh_net::relay::http::{
     ServerBuilder as RelayServerBuilder, TlsAcceptor, TlsConfig as RelayTlsConfig,
 };
-use iroh_net::relay::{self};
+use iroh_net::relay::{self, ServerLimits};
 use iroh_net::stun;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
@@ -196,6 +196,10 @@ struct Config {
     tls: Option<TlsConfig>,
     /// Rate limiting configuration
     limits: Option<Limits>,
+    /// File to append the access log of the relay server to, one JSON object per line.
+    ///
+    /// No access log is written if not set.
+    access_log_path: Option<PathBuf>,
     #[cfg(feature = "metrics")]
     /// Metrics serve address. If not set, metrics are not served.
     metrics_addr: Option<SocketAddr>,
@@ -234,6 +238,12 @@ struct Limits {
     accept_conn_limit: Option<f64>,
     /// Burst limit for accepting new connection. Unlimited if not set.
     accept_conn_burst: Option<usize>,
+    /// Maximum number of clients connected at the same time. Unlimited if not set.
+    max_clients: Option<usize>,
+    /// Maximum number of bytes queued for a single client. Unlimited if not set.
+    max_client_queued_bytes: Option<usize>,
+    /// Maximum number of bytes queued for all clients together. Unlimited if not set.
+    max_buffered_bytes: Option<usize>,
 }
 
 impl Default for Config {
@@ -247,6 +257,7 @@ impl Default for Config {
             enable_relay: true,
             tls: None,
             limits: None,
+            access_log_path: None,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
         }
@@ -433,8 +444,17 @@ async fn run(
         (None, HeaderMap::new(), 0)
     };
 
+    let limits = cfg
+        .limits
+        .map(|limits| ServerLimits {
+            max_clients: limits.max_clients,
+            max_client_queued_bytes: limits.max_client_queued_bytes,
+            max_buffered_bytes: limits.max_buffered_bytes,
+        })
+        .unwrap_or_default();
     let mut builder = RelayServerBuilder::new(addr)
         .secret_key(secret_key.map(Into::into))
+        .limits(limits)
         .headers(headers)
         .tls_config(tls_config.clone())
         .relay_override(Box::new(relay_disabled_handler))
@@ -442,6 +462,15 @@ async fn run(
         .request_handler(Method::GET, "/index.html", Box::new(root_handler))
         .request_handler(Method::GET, "/derp/probe", Box::new(probe_handler))
         .request_handler(Method::GET, "/robots.txt", Box::new(robots_handler));
+    if let Some(path) = cfg.access_log_path {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("unable to open access log {}", path.display()))?;
+        let log = relay::JsonLinesLog::new(std::io::BufWriter::new(file));
+        builder = builder.access_log(Arc::new(log));
+    }
     // if tls is enabled, we need to serve this endpoint from a non-tls connection
     // which we check for below
     if tls_config.is_none() {
@@ -0,0 +1,283 @@
+//! Sending small messages to named groups of nodes.
+//!
+//! Gossip and presence protocols repeatedly send the same small message to a set of nodes.
+//! [`PeerGroups`] keeps the members of named groups and a connection to each of them, and
+//! [`PeerGroups::broadcast_datagram`] sends a message to all members of a group as unreliable
+//! QUIC datagrams.
+//!
+//! Every datagram travels on the best path the [`MagicEndpoint`] knows for its member.  The
+//! members reached directly are sent to first, followed by the ones behind each relay server
+//! in turn, so the writes to a relay connection are queued back to back and can be flushed
+//! together.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures::future::join_all;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::{
+    magic_endpoint::get_remote_node_id, magicsock::ConnectionType, relay::RelayUrl, MagicEndpoint,
+    NodeId,
+};
+
+/// Named groups of nodes to broadcast to, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct PeerGroups {
+    endpoint: MagicEndpoint,
+    alpn: Vec<u8>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    groups: HashMap<String, BTreeSet<NodeId>>,
+    connections: HashMap<NodeId, quinn::Connection>,
+}
+
+/// The outcome of a [`PeerGroups::broadcast_datagram`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BroadcastReport {
+    /// The members the datagram was handed to QUIC for.
+    ///
+    /// Datagrams are unreliable, this does not mean they arrived.
+    pub sent: Vec<NodeId>,
+    /// The members which could not be connected to, or whose connection refused the datagram.
+    pub failed: Vec<NodeId>,
+}
+
+impl PeerGroups {
+    /// Creates groups connecting to members on `endpoint` with the `alpn` protocol.
+    ///
+    /// The members have to accept connections for `alpn` and QUIC datagrams.
+    pub fn new(endpoint: MagicEndpoint, alpn: &[u8]) -> Self {
+        Self {
+            endpoint,
+            alpn: alpn.to_vec(),
+            inner: Default::default(),
+        }
+    }
+
+    /// Sets the members of the group `name`, creating it if it does not exist.
+    pub fn set_group(&self, name: impl Into<String>, members: impl IntoIterator<Item = NodeId>) {
+        let mut inner = self.inner.lock();
+        inner
+            .groups
+            .insert(name.into(), members.into_iter().collect());
+        inner.prune_connections();
+    }
+
+    /// Removes the group `name`.
+    pub fn remove_group(&self, name: &str) {
+        let mut inner = self.inner.lock();
+        inner.groups.remove(name);
+        inner.prune_connections();
+    }
+
+    /// Adds `node` to the group `name`, creating it if it does not exist.
+    pub fn join(&self, name: &str, node: NodeId) {
+        let mut inner = self.inner.lock();
+        inner
+            .groups
+            .entry(name.to_string())
+            .or_default()
+            .insert(node);
+    }
+
+    /// Removes `node` from the group `name`.
+    pub fn leave(&self, name: &str, node: NodeId) {
+        let mut inner = self.inner.lock();
+        if let Some(members) = inner.groups.get_mut(name) {
+            members.remove(&node);
+        }
+        inner.prune_connections();
+    }
+
+    /// The members of the group `name`, `None` if there is no such group.
+    pub fn members(&self, name: &str) -> Option<Vec<NodeId>> {
+        let inner = self.inner.lock();
+        inner
+            .groups
+            .get(name)
+            .map(|members| members.iter().copied().collect())
+    }
+
+    /// Uses `conn` for broadcasts to its remote node, e.g. a connection it opened to us.
+    ///
+    /// Replaces any connection to the node used before.
+    pub fn add_connection(&self, conn: quinn::Connection) -> Result<()> {
+        let node = get_remote_node_id(&conn)?;
+        self.inner.lock().connections.insert(node, conn);
+        Ok(())
+    }
+
+    /// Sends `data` as a datagram to every member of the group `name`.
+    ///
+    /// Members without an open connection are connected to first, using the addressing
+    /// information of the endpoint or discovery.  Fails only if there is no such group, a
+    /// member which can not be reached is reported in [`BroadcastReport::failed`].
+    pub async fn broadcast_datagram(&self, name: &str, data: Bytes) -> Result<BroadcastReport> {
+        let me = self.endpoint.node_id();
+        let (mut connected, missing) = {
+            let mut inner = self.inner.lock();
+            let Some(members) = inner.groups.get(name) else {
+                bail!("unknown group {name:?}");
+            };
+            let members: Vec<_> = members.iter().copied().filter(|n| *n != me).collect();
+            let mut connected = Vec::new();
+            let mut missing = Vec::new();
+            for node in members {
+                match inner.connection(&node) {
+                    Some(conn) => connected.push((node, conn)),
+                    None => missing.push(node),
+                }
+            }
+            (connected, missing)
+        };
+
+        let mut report = BroadcastReport::default();
+        let dials = missing.into_iter().map(|node| async move {
+            let res = self
+                .endpoint
+                .connect_by_node_id(&node, &self.alpn)
+                .await
+                .with_context(|| format!("failed to connect to {}", node.fmt_short()));
+            (node, res)
+        });
+        for (node, res) in join_all(dials).await {
+            match res {
+                Ok(conn) => {
+                    self.inner.lock().connections.insert(node, conn.clone());
+                    connected.push((node, conn));
+                }
+                Err(err) => {
+                    debug!("broadcast: {err:#}");
+                    report.failed.push(node);
+                }
+            }
+        }
+
+        connected.sort_by_cached_key(|(node, _)| self.path_order(node));
+        for (node, conn) in connected {
+            match conn.send_datagram(data.clone()) {
+                Ok(()) => report.sent.push(node),
+                Err(err) => {
+                    debug!(node = %node.fmt_short(), "broadcast: failed to send datagram: {err}");
+                    report.failed.push(node);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Orders members reached directly first, then the ones behind each relay together.
+    fn path_order(&self, node: &NodeId) -> (u8, Option<RelayUrl>) {
+        let conn_type = self
+            .endpoint
+            .connection_info(*node)
+            .map(|info| info.conn_type);
+        match conn_type {
+            Some(ConnectionType::Direct(_)) => (0, None),
+            Some(ConnectionType::Relay(url)) | Some(ConnectionType::Mixed(_, url)) => {
+                (1, Some(url))
+            }
+            Some(ConnectionType::None) | None => (2, None),
+        }
+    }
+}
+
+impl Inner {
+    /// The open connection to `node`, forgetting it if it was closed.
+    fn connection(&mut self, node: &NodeId) -> Option<quinn::Connection> {
+        let conn = self.connections.get(node)?;
+        if conn.close_reason().is_some() {
+            self.connections.remove(node);
+            return None;
+        }
+        Some(conn.clone())
+    }
+
+    /// Drops the connections to nodes which are no longer in any group.
+    fn prune_connections(&mut self) {
+        let groups = &self.groups;
+        self.connections
+            .retain(|node, _| groups.values().any(|members| members.contains(node)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayMode;
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/broadcast";
+
+    async fn endpoint() -> MagicEndpoint {
+        MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind(0)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_datagram() {
+        let _logging_guard = iroh_test::logging::setup();
+        let sender = endpoint().await;
+        let groups = PeerGroups::new(sender.clone(), TEST_ALPN);
+
+        let mut receivers = Vec::new();
+        let mut members = Vec::new();
+        for _ in 0..3 {
+            let ep = endpoint().await;
+            sender.add_node_addr(ep.my_addr().await.unwrap()).unwrap();
+            members.push(ep.node_id());
+            receivers.push(tokio::spawn(async move {
+                let conn = ep.accept().await.unwrap().await.unwrap();
+                let datagram = conn.read_datagram().await.unwrap();
+                (ep, datagram)
+            }));
+        }
+        // A member which can not be reached.
+        let unreachable = crate::key::SecretKey::generate().public();
+        members.push(unreachable);
+        groups.set_group("presence", members);
+
+        assert!(groups
+            .broadcast_datagram("other", Bytes::from_static(b"hi"))
+            .await
+            .is_err());
+        let report = groups
+            .broadcast_datagram("presence", Bytes::from_static(b"hi"))
+            .await
+            .unwrap();
+        assert_eq!(report.sent.len(), 3);
+        assert_eq!(report.failed, vec![unreachable]);
+
+        for receiver in receivers {
+            let (_ep, datagram) = receiver.await.unwrap();
+            assert_eq!(datagram, Bytes::from_static(b"hi"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_membership() {
+        let groups = PeerGroups::new(endpoint().await, TEST_ALPN);
+        let a = crate::key::SecretKey::generate().public();
+        let b = crate::key::SecretKey::generate().public();
+        assert_eq!(groups.members("friends"), None);
+        groups.join("friends", a);
+        groups.join("friends", b);
+        assert_eq!(groups.members("friends").unwrap().len(), 2);
+        groups.leave("friends", a);
+        assert_eq!(groups.members("friends"), Some(vec![b]));
+        groups.remove_group("friends");
+        assert_eq!(groups.members("friends"), None);
+    }
+}
@@ -1,6 +1,13 @@
 //! Configuration types.
 
-use std::{collections::BTreeMap, fmt::Display, net::SocketAddr};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::relay::RelayUrl;
 
@@ -31,6 +38,8 @@ pub enum EndpointType {
     Portmapped,
     /// Hard NAT: STUN'ed IPv4 address + local fixed port.
     Stun4LocalPort,
+    /// Endpoint is the relayed address of an allocation on a TURN server.
+    Turn,
 }
 
 impl Display for EndpointType {
@@ -41,6 +50,7 @@ impl Display for EndpointType {
             EndpointType::Stun => write!(f, "stun"),
             EndpointType::Portmapped => write!(f, "portmap"),
             EndpointType::Stun4LocalPort => write!(f, "stun4localport"),
+            EndpointType::Turn => write!(f, "turn"),
         }
     }
 }
@@ -51,6 +61,9 @@ pub struct NetInfo {
     /// Says whether the host's NAT mappings vary based on the destination IP.
     pub mapping_varies_by_dest_ip: Option<bool>,
 
+    /// Whether the host appears to be behind a carrier-grade NAT, `None` means unknown.
+    pub cgnat: Option<bool>,
+
     /// If their router does hairpinning. It reports true even if there's no NAT involved.
     pub hair_pinning: Option<bool>,
 
@@ -84,11 +97,108 @@ pub struct NetInfo {
     /// LinkType is the current link type, if known.
     pub link_type: Option<LinkType>,
 
-    /// The fastest recent time to reach various relay STUN servers, in seconds.
+    /// The fastest recent time to reach the STUN server of each relay.
     ///
     /// This should only be updated rarely, or when there's a
     /// material change, as any change here also gets uploaded to the control plane.
-    pub relay_latency: BTreeMap<String, f64>,
+    ///
+    /// Use [`legacy_relay_latency`] to (de)serialize this in the former format keyed by
+    /// strings like `"https://relay.example./-v4"`.
+    pub relay_latency: BTreeMap<RelayUrl, RelayLatency>,
+}
+
+/// The latencies to the STUN server of a relay, see [`NetInfo::relay_latency`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayLatency {
+    /// The latency over IPv4, if the relay was reached over IPv4.
+    pub v4: Option<Duration>,
+    /// The latency over IPv6, if the relay was reached over IPv6.
+    pub v6: Option<Duration>,
+    /// When the relay was last probed, `None` if not known.
+    pub last_probe: Option<SystemTime>,
+}
+
+impl RelayLatency {
+    /// The lower of the IPv4 and IPv6 latencies.
+    pub fn best(&self) -> Option<Duration> {
+        match (self.v4, self.v6) {
+            (Some(v4), Some(v6)) => Some(v4.min(v6)),
+            (v4, v6) => v4.or(v6),
+        }
+    }
+}
+
+/// Serde compatibility for [`NetInfo::relay_latency`].
+///
+/// Before [`RelayLatency`], the latencies were a map of seconds keyed by the relay URL with
+/// a `-v4` or `-v6` suffix.  Use this module with `#[serde(with = ...)]` to keep that format
+/// on the wire: it serializes the former format and deserializes either format.
+pub mod legacy_relay_latency {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::RelayLatency;
+    use crate::relay::RelayUrl;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AnyFormat {
+        Structured(BTreeMap<RelayUrl, RelayLatency>),
+        Legacy(BTreeMap<String, f64>),
+    }
+
+    /// Converts the latencies to the former format.
+    pub fn to_legacy(latencies: &BTreeMap<RelayUrl, RelayLatency>) -> BTreeMap<String, f64> {
+        let mut legacy = BTreeMap::new();
+        for (url, latency) in latencies {
+            if let Some(v4) = latency.v4 {
+                legacy.insert(format!("{url}-v4"), v4.as_secs_f64());
+            }
+            if let Some(v6) = latency.v6 {
+                legacy.insert(format!("{url}-v6"), v6.as_secs_f64());
+            }
+        }
+        legacy
+    }
+
+    /// Converts latencies in the former format, skipping keys which do not parse.
+    pub fn from_legacy(legacy: &BTreeMap<String, f64>) -> BTreeMap<RelayUrl, RelayLatency> {
+        let mut latencies: BTreeMap<RelayUrl, RelayLatency> = BTreeMap::new();
+        for (key, secs) in legacy {
+            let Ok(latency) = Duration::try_from_secs_f64(*secs) else {
+                continue;
+            };
+            if let Some(url) = key.strip_suffix("-v4") {
+                if let Ok(url) = url.parse() {
+                    latencies.entry(url).or_default().v4 = Some(latency);
+                }
+            } else if let Some(url) = key.strip_suffix("-v6") {
+                if let Ok(url) = url.parse() {
+                    latencies.entry(url).or_default().v6 = Some(latency);
+                }
+            }
+        }
+        latencies
+    }
+
+    /// Serializes the latencies in the former format.
+    pub fn serialize<S: Serializer>(
+        latencies: &BTreeMap<RelayUrl, RelayLatency>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        to_legacy(latencies).serialize(serializer)
+    }
+
+    /// Deserializes latencies in either format.
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<RelayUrl, RelayLatency>, D::Error> {
+        Ok(match AnyFormat::deserialize(deserializer)? {
+            AnyFormat::Structured(latencies) => latencies,
+            AnyFormat::Legacy(legacy) => from_legacy(&legacy),
+        })
+    }
 }
 
 impl NetInfo {
@@ -103,6 +213,7 @@ impl NetInfo {
             _ => true, // ignore for comparison if only one report had this info
         };
         self.mapping_varies_by_dest_ip == other.mapping_varies_by_dest_ip
+            && self.cgnat == other.cgnat
             && self.hair_pinning == other.hair_pinning
             && self.working_ipv6 == other.working_ipv6
             && self.os_has_ipv6 == other.os_has_ipv6
@@ -126,3 +237,50 @@ pub enum LinkType {
     /// LTE, 4G, 3G, etc.
     Mobile,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Wire {
+        #[serde(with = "legacy_relay_latency")]
+        relay_latency: BTreeMap<RelayUrl, RelayLatency>,
+    }
+
+    #[test]
+    fn test_legacy_relay_latency() {
+        let url: RelayUrl = "https://relay.example".parse().unwrap();
+        let latency = RelayLatency {
+            v4: Some(Duration::from_millis(20)),
+            v6: Some(Duration::from_millis(30)),
+            last_probe: None,
+        };
+        assert_eq!(latency.best(), Some(Duration::from_millis(20)));
+        let wire = Wire {
+            relay_latency: [(url.clone(), latency)].into(),
+        };
+
+        let json = serde_json::to_value(&wire).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "relay_latency": {
+                    "https://relay.example./-v4": 0.02,
+                    "https://relay.example./-v6": 0.03,
+                }
+            })
+        );
+        assert_eq!(serde_json::from_value::<Wire>(json).unwrap(), wire);
+
+        // The structured format is accepted as well.
+        let structured = serde_json::json!({
+            "relay_latency": { "https://relay.example./": latency }
+        });
+        assert_eq!(serde_json::from_value::<Wire>(structured).unwrap(), wire);
+
+        // Unknown keys of the former format are skipped.
+        let legacy = [("garbage".to_string(), 1.0)].into();
+        assert!(legacy_relay_latency::from_legacy(&legacy).is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//! Receiving the QUIC datagrams of many connections on one channel.
+//!
+//! Protocols which tunnel packets, like a VPN, receive datagrams from many nodes and process
+//! them all in the same place.  Awaiting `read_datagram` on every connection and handing each
+//! datagram on separately costs a task wake-up per packet.  With [`channel`] the datagrams of
+//! all connections added to the [`DatagramConnections`] are forwarded into one bounded queue
+//! instead, which the [`DatagramReceiver`] drains in batches into a buffer it reuses.
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::NodeId;
+
+/// The default number of datagrams queued before the connections stop being read.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// A datagram received from a node.
+pub type Datagram = (NodeId, Bytes);
+
+/// Creates a queue of up to `capacity` datagrams, see the [module docs](self).
+///
+/// While the queue is full the connections are not read, and QUIC drops the datagrams
+/// arriving in the meantime once its own receive buffer is full.
+pub fn channel(capacity: usize) -> (DatagramConnections, DatagramReceiver) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let cancel = CancellationToken::new();
+    let connections = DatagramConnections {
+        sender,
+        cancel: cancel.clone(),
+    };
+    (connections, DatagramReceiver { receiver, cancel })
+}
+
+/// Adds connections to read datagrams from to a [`channel`].
+#[derive(Debug, Clone)]
+pub struct DatagramConnections {
+    sender: mpsc::Sender<Datagram>,
+    cancel: CancellationToken,
+}
+
+impl DatagramConnections {
+    /// Queues the datagrams `node` sends on `conn`, until the connection closes.
+    pub fn add(&self, node: NodeId, conn: quinn::Connection) {
+        let sender = self.sender.clone();
+        let cancel = self.cancel.clone();
+        tokio::spawn(async move {
+            loop {
+                let res = tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    res = conn.read_datagram() => res,
+                };
+                match res {
+                    Ok(datagram) => {
+                        if sender.send((node, datagram)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        debug!(node = %node.fmt_short(), "stopped receiving datagrams: {err}");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Receives the datagrams queued by a [`channel`].
+///
+/// Reading the connections stops when the receiver is dropped.
+#[derive(Debug)]
+pub struct DatagramReceiver {
+    receiver: mpsc::Receiver<Datagram>,
+    cancel: CancellationToken,
+}
+
+impl DatagramReceiver {
+    /// Receives the next datagram.
+    ///
+    /// Returns `None` once all [`DatagramConnections`] are dropped and the queue is empty.
+    pub async fn recv(&mut self) -> Option<Datagram> {
+        self.receiver.recv().await
+    }
+
+    /// Appends up to `limit` datagrams to `batch`, waiting for at least one.
+    ///
+    /// Returns the number of datagrams appended, which is only 0 once [`Self::recv`] would
+    /// return `None`.  Reuse `batch` across calls to avoid allocating.
+    pub async fn recv_batch(&mut self, batch: &mut Vec<Datagram>, limit: usize) -> usize {
+        self.receiver.recv_many(batch, limit).await
+    }
+}
+
+impl Drop for DatagramReceiver {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{relay::RelayMode, MagicEndpoint};
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/datagram";
+
+    async fn endpoint() -> MagicEndpoint {
+        MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind(0)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_recv_batch() {
+        let _logging_guard = iroh_test::logging::setup();
+        let receiver_ep = endpoint().await;
+        let (connections, mut receiver) = channel(16);
+        let addr = receiver_ep.my_addr().await.unwrap();
+
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let ep = endpoint().await;
+            let conn = ep.connect(addr.clone(), TEST_ALPN).await.unwrap();
+            let accepted = receiver_ep.accept().await.unwrap().await.unwrap();
+            connections.add(ep.node_id(), accepted);
+            senders.push((ep, conn));
+        }
+        for (i, (_, conn)) in senders.iter().enumerate() {
+            conn.send_datagram(Bytes::from(vec![i as u8; 100])).unwrap();
+        }
+
+        let mut batch = Vec::with_capacity(16);
+        while batch.len() < 2 {
+            receiver.recv_batch(&mut batch, 16).await;
+        }
+        batch.sort_by_key(|(_, datagram)| datagram[0]);
+        for (i, (node, datagram)) in batch.iter().enumerate() {
+            assert_eq!(*node, senders[i].0.node_id());
+            assert_eq!(datagram, &Bytes::from(vec![i as u8; 100]));
+        }
+    }
+}
@@ -21,6 +21,7 @@
 use std::{
     fmt::Display,
     net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
@@ -31,15 +32,24 @@ use crate::{key, net::ip::to_canonical, relay::RelayUrl};
 use super::{key::PublicKey, stun};
 
 // TODO: custom magicn
-/// The 6 byte header of all discovery messages.
+/// The 6 byte header of discovery messages sealed with the original suite, version 0.
 pub const MAGIC: &str = "TS💬"; // 6 bytes: 0x54 53 f0 9f 92 ac
 pub const MAGIC_LEN: usize = MAGIC.as_bytes().len();
+/// The 6 byte header of discovery messages sealed with a newer suite.
+///
+/// The sender key is followed by the version of the suite and the length prefixed key
+/// material of the suite, see [`SealedBox`].  Nodes which only know version 0 ignore these.
+pub const MAGIC_SUITE: &str = "TS💭"; // 6 bytes: 0x54 53 f0 9f 92 ad
 
 /// Current Version.
 const V0: u8 = 0;
+/// Version of a [`Ping`] carrying a [`Cookie`].
+const V1: u8 = 1;
 
 pub(crate) const KEY_LEN: usize = 32;
 const TX_LEN: usize = 12;
+/// Length of a [`Cookie`].
+pub const COOKIE_LEN: usize = 16;
 
 // Sizes for the inner message structure.
 
@@ -47,6 +57,7 @@ const TX_LEN: usize = 12;
 const HEADER_LEN: usize = 2;
 
 const PING_LEN: usize = TX_LEN + key::PUBLIC_KEY_LENGTH;
+const GOODBYE_LEN: usize = TX_LEN + 8;
 const EP_LENGTH: usize = 16 + 2; // 16 byte IP address + 2 byte port
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -55,6 +66,8 @@ pub enum MessageType {
     Ping = 0x01,
     Pong = 0x02,
     CallMeMaybe = 0x03,
+    Goodbye = 0x04,
+    Challenge = 0x05,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -65,15 +78,32 @@ impl TryFrom<u8> for MessageType {
             0x01 => Ok(MessageType::Ping),
             0x02 => Ok(MessageType::Pong),
             0x03 => Ok(MessageType::CallMeMaybe),
+            0x04 => Ok(MessageType::Goodbye),
+            0x05 => Ok(MessageType::Challenge),
             _ => Err(value),
         }
     }
 }
 
 const MESSAGE_HEADER_LEN: usize = MAGIC_LEN + KEY_LEN;
+/// Suite version | Key material length
+const SUITE_HEADER_LEN: usize = 1 + 2;
+
+/// The sealed part of a disco message, see [`source_and_box`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealedBox<'a> {
+    /// Version of the suite the message is sealed with.
+    pub suite: u8,
+    /// What the suite needs besides the node keys to open the message, e.g. the key
+    /// encapsulated by a KEM.  Always empty for version 0.
+    pub key_material: &'a [u8],
+    /// The sealed message.
+    pub sealed: &'a [u8],
+}
 
+/// Encodes a message sealed with version 0 of the disco suites.
 pub fn encode_message(sender: &PublicKey, seal: Vec<u8>) -> Vec<u8> {
-    let mut out = Vec::with_capacity(MESSAGE_HEADER_LEN);
+    let mut out = Vec::with_capacity(MESSAGE_HEADER_LEN + seal.len());
     out.extend_from_slice(MAGIC.as_bytes());
     out.extend_from_slice(sender.as_bytes());
     out.extend(seal);
@@ -81,25 +111,81 @@ pub fn encode_message(sender: &PublicKey, seal: Vec<u8>) -> Vec<u8> {
     out
 }
 
+/// Encodes a message sealed with the disco suite `suite`.
+///
+/// Messages sealed with version 0 are encoded like [`encode_message`], so nodes which do not
+/// know about suites can read them.
+pub fn encode_suite_message(
+    sender: &PublicKey,
+    suite: u8,
+    key_material: &[u8],
+    seal: Vec<u8>,
+) -> Vec<u8> {
+    if suite == 0 {
+        debug_assert!(key_material.is_empty(), "version 0 has no key material");
+        return encode_message(sender, seal);
+    }
+    let key_material_len =
+        u16::try_from(key_material.len()).expect("key material fits a disco message");
+    let mut out =
+        Vec::with_capacity(MESSAGE_HEADER_LEN + SUITE_HEADER_LEN + key_material.len() + seal.len());
+    out.extend_from_slice(MAGIC_SUITE.as_bytes());
+    out.extend_from_slice(sender.as_bytes());
+    out.push(suite);
+    out.extend_from_slice(&key_material_len.to_be_bytes());
+    out.extend_from_slice(key_material);
+    out.extend(seal);
+
+    out
+}
+
 /// Reports whether p looks like it's a packet containing an encrypted disco message.
 pub fn looks_like_disco_wrapper(p: &[u8]) -> bool {
     if p.len() < MESSAGE_HEADER_LEN {
         return false;
     }
 
-    &p[..MAGIC_LEN] == MAGIC.as_bytes()
+    &p[..MAGIC_LEN] == MAGIC.as_bytes() || &p[..MAGIC_LEN] == MAGIC_SUITE.as_bytes()
 }
 
-/// If `p` looks like a disco message it returns the slice of `p` that represents the disco public key source,
-/// and the part that is the box.
-pub fn source_and_box(p: &[u8]) -> Option<(PublicKey, &[u8])> {
+/// If `p` looks like a disco message it returns the disco public key of the sender, and the
+/// sealed part.
+pub fn source_and_box(p: &[u8]) -> Option<(PublicKey, SealedBox<'_>)> {
     if !looks_like_disco_wrapper(p) {
         return None;
     }
 
     let source = &p[MAGIC_LEN..MAGIC_LEN + KEY_LEN];
     let sender = PublicKey::try_from(source).ok()?;
-    let sealed_box = &p[MAGIC_LEN + KEY_LEN..];
+    let rest = &p[MAGIC_LEN + KEY_LEN..];
+    if &p[..MAGIC_LEN] == MAGIC.as_bytes() {
+        let sealed_box = SealedBox {
+            suite: 0,
+            key_material: &[],
+            sealed: rest,
+        };
+        return Some((sender, sealed_box));
+    }
+
+    if rest.len() < SUITE_HEADER_LEN {
+        return None;
+    }
+    let suite = rest[0];
+    if suite == 0 {
+        // Version 0 is only ever sent with `MAGIC`.
+        return None;
+    }
+    let key_material_len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+    let rest = &rest[SUITE_HEADER_LEN..];
+    if rest.len() < key_material_len {
+        return None;
+    }
+    let (key_material, sealed) = rest.split_at(key_material_len);
+    let sealed_box = SealedBox {
+        suite,
+        key_material,
+        sealed,
+    };
     Some((sender, sealed_box))
 }
 
@@ -109,8 +195,18 @@ pub enum Message {
     Ping(Ping),
     Pong(Pong),
     CallMeMaybe(CallMeMaybe),
+    /// Sent to the nodes we are talking to when shutting down.
+    ///
+    /// Lets them consider our paths dead right away instead of waiting for timeouts.
+    Goodbye(Goodbye),
+    /// Sent in response to a ping from an unknown node, which needs to repeat the ping with
+    /// the cookie before it is answered.
+    Challenge(Challenge),
 }
 
+/// A stateless cookie proving that a node can receive at the address it pings from.
+pub type Cookie = [u8; COOKIE_LEN];
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ping {
     /// Random client-generated per-ping transaction ID.
@@ -120,6 +216,18 @@ pub struct Ping {
     /// It shouldn't be trusted by itself, but can be combined with
     /// netmap data to reduce the discokey:nodekey relation from 1:N to 1:1.
     pub node_key: PublicKey,
+
+    /// The home relay of the ping sender, if it has one.
+    ///
+    /// Appended after the node key on the wire, which older versions ignore.
+    pub home_relay: Option<RelayUrl>,
+
+    /// The cookie of a [`Challenge`] to this ping.
+    ///
+    /// A ping with a cookie is sent as version 1, with the cookie between the node key and
+    /// the home relay.  It is only sent to nodes which sent the challenge, older versions
+    /// never receive it.
+    pub cookie: Option<Cookie>,
 }
 
 /// A response a Ping.
@@ -174,6 +282,31 @@ impl Display for SendAddr {
     }
 }
 
+/// Sent when shutting down, see [`Message::Goodbye`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Goodbye {
+    /// Random transaction ID, the same for the copies sent on all paths.
+    pub tx_id: stun::TransactionId,
+    /// When the goodbye was sent, in milliseconds since the Unix epoch.
+    ///
+    /// Goodbyes which are not recent are ignored, so a captured goodbye can not be replayed
+    /// later on to tear down the paths to the node.
+    pub sent_at: u64,
+}
+
+/// A challenge to a ping from an unknown node.
+///
+/// The node repeats the ping with the cookie, proving that it receives at the address it
+/// pings from.  The challenge is smaller than the ping, so it can not be used to amplify
+/// traffic to a spoofed address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// The transaction ID of the challenged ping.
+    pub tx_id: stun::TransactionId,
+    /// The cookie to repeat the ping with.
+    pub cookie: Cookie,
+}
+
 /// Message sent only over the relay to request that the recipient try
 /// to open up a magicsock path back to the sender.
 ///
@@ -190,24 +323,51 @@ pub struct CallMeMaybe {
 
 impl Ping {
     fn from_bytes(ver: u8, p: &[u8]) -> Result<Self> {
-        ensure!(ver == V0, "invalid version");
+        ensure!(ver == V0 || ver == V1, "invalid version");
         // Deliberately lax on longer-than-expected messages, for future compatibility.
         ensure!(p.len() >= PING_LEN, "message too short");
         let tx_id: [u8; TX_LEN] = p[..TX_LEN].try_into().expect("length checked");
         let raw_key = &p[TX_LEN..TX_LEN + key::PUBLIC_KEY_LENGTH];
         let node_key = PublicKey::try_from(raw_key)?;
         let tx_id = stun::TransactionId::from(tx_id);
-
-        Ok(Ping { tx_id, node_key })
+        let (cookie, rest) = if ver == V1 {
+            ensure!(p.len() >= PING_LEN + COOKIE_LEN, "message too short");
+            let cookie: Cookie = p[PING_LEN..PING_LEN + COOKIE_LEN]
+                .try_into()
+                .expect("length checked");
+            (Some(cookie), &p[PING_LEN + COOKIE_LEN..])
+        } else {
+            (None, &p[PING_LEN..])
+        };
+        // Lax as well, an unparsable home relay is treated as not advertised.
+        let home_relay = std::str::from_utf8(rest)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .and_then(|s| s.parse::<Url>().ok())
+            .map(RelayUrl::from);
+
+        Ok(Ping {
+            tx_id,
+            node_key,
+            home_relay,
+            cookie,
+        })
     }
 
     fn as_bytes(&self) -> Vec<u8> {
-        let header = msg_header(MessageType::Ping, V0);
+        let ver = if self.cookie.is_some() { V1 } else { V0 };
+        let header = msg_header(MessageType::Ping, ver);
         let mut out = vec![0u8; PING_LEN + HEADER_LEN];
 
         out[..HEADER_LEN].copy_from_slice(&header);
         out[HEADER_LEN..HEADER_LEN + TX_LEN].copy_from_slice(&self.tx_id);
         out[HEADER_LEN + TX_LEN..].copy_from_slice(self.node_key.as_ref());
+        if let Some(ref cookie) = self.cookie {
+            out.extend_from_slice(cookie);
+        }
+        if let Some(ref url) = self.home_relay {
+            out.extend_from_slice(url.to_string().as_bytes());
+        }
 
         out
     }
@@ -275,7 +435,8 @@ fn socket_addr_as_bytes(addr: &SocketAddr) -> [u8; EP_LENGTH] {
 impl Pong {
     fn from_bytes(ver: u8, p: &[u8]) -> Result<Self> {
         ensure!(ver == V0, "invalid version");
-        let tx_id: [u8; TX_LEN] = p[..TX_LEN].try_into().context("message too short")?;
+        ensure!(p.len() >= TX_LEN, "message too short");
+        let tx_id: [u8; TX_LEN] = p[..TX_LEN].try_into().expect("length checked");
         let tx_id = stun::TransactionId::from(tx_id);
         let src = send_addr_from_bytes(&p[TX_LEN..])?;
 
@@ -293,6 +454,70 @@ impl Pong {
     }
 }
 
+impl Challenge {
+    fn from_bytes(ver: u8, p: &[u8]) -> Result<Self> {
+        ensure!(ver == V0, "invalid version");
+        ensure!(p.len() >= TX_LEN + COOKIE_LEN, "message too short");
+        let tx_id: [u8; TX_LEN] = p[..TX_LEN].try_into().expect("length checked");
+        let cookie = p[TX_LEN..TX_LEN + COOKIE_LEN]
+            .try_into()
+            .expect("length checked");
+        Ok(Challenge {
+            tx_id: tx_id.into(),
+            cookie,
+        })
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut out = msg_header(MessageType::Challenge, V0).to_vec();
+        out.extend_from_slice(&self.tx_id);
+        out.extend_from_slice(&self.cookie);
+        out
+    }
+}
+
+impl Goodbye {
+    /// Creates a goodbye sent at `now`, with a random transaction ID.
+    pub fn new(now: SystemTime) -> Self {
+        Goodbye {
+            tx_id: stun::TransactionId::default(),
+            sent_at: unix_millis(now),
+        }
+    }
+
+    /// Whether the goodbye was sent within `max_age` of `now`.
+    ///
+    /// The clocks of the nodes may differ, so goodbyes sent up to `max_age` after `now` are
+    /// accepted as well.
+    pub fn is_recent(&self, now: SystemTime, max_age: Duration) -> bool {
+        unix_millis(now).abs_diff(self.sent_at) <= max_age.as_millis() as u64
+    }
+
+    fn from_bytes(ver: u8, p: &[u8]) -> Result<Self> {
+        ensure!(ver == V0, "invalid version");
+        ensure!(p.len() >= GOODBYE_LEN, "message too short");
+        let tx_id: [u8; TX_LEN] = p[..TX_LEN].try_into().expect("length checked");
+        let sent_at = p[TX_LEN..GOODBYE_LEN].try_into().expect("length checked");
+        Ok(Goodbye {
+            tx_id: tx_id.into(),
+            sent_at: u64::from_le_bytes(sent_at),
+        })
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut out = msg_header(MessageType::Goodbye, V0).to_vec();
+        out.extend_from_slice(&self.tx_id);
+        out.extend_from_slice(&self.sent_at.to_le_bytes());
+        out
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 impl CallMeMaybe {
     fn from_bytes(ver: u8, p: &[u8]) -> Result<Self> {
         ensure!(ver == V0, "invalid version");
@@ -351,6 +576,14 @@ impl Message {
                 let cm = CallMeMaybe::from_bytes(ver, p)?;
                 Ok(Message::CallMeMaybe(cm))
             }
+            MessageType::Goodbye => {
+                let goodbye = Goodbye::from_bytes(ver, p)?;
+                Ok(Message::Goodbye(goodbye))
+            }
+            MessageType::Challenge => {
+                let challenge = Challenge::from_bytes(ver, p)?;
+                Ok(Message::Challenge(challenge))
+            }
         }
     }
 
@@ -360,6 +593,8 @@ impl Message {
             Message::Ping(ping) => ping.as_bytes(),
             Message::Pong(pong) => pong.as_bytes(),
             Message::CallMeMaybe(cm) => cm.as_bytes(),
+            Message::Goodbye(goodbye) => goodbye.as_bytes(),
+            Message::Challenge(challenge) => challenge.as_bytes(),
         }
     }
 }
@@ -376,6 +611,12 @@ impl Display for Message {
             Message::CallMeMaybe(_) => {
                 write!(f, "CallMeMaybe")
             }
+            Message::Goodbye(goodbye) => {
+                write!(f, "Goodbye(tx={})", hex::encode(goodbye.tx_id))
+            }
+            Message::Challenge(challenge) => {
+                write!(f, "Challenge(tx={})", hex::encode(challenge.tx_id))
+            }
         }
     }
 }
@@ -404,9 +645,33 @@ mod tests {
                     tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
                     node_key: PublicKey::try_from(&[
                         190, 243, 65, 104, 37, 102, 175, 75, 243, 22, 69, 200, 167, 107, 24, 63, 216, 140, 120, 43, 4, 112, 16, 62, 117, 155, 45, 215, 72, 175, 40, 189][..]).unwrap(),
+                    home_relay: None,
+                    cookie: None,
                 }),
                 want: "01 00 01 02 03 04 05 06 07 08 09 0a 0b 0c be f3 41 68 25 66 af 4b f3 16 45 c8 a7 6b 18 3f d8 8c 78 2b 04 70 10 3e 75 9b 2d d7 48 af 28 bd",
             },
+            Test {
+                name: "ping_with_home_relay",
+                m: Message::Ping(Ping {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    node_key: PublicKey::try_from(&[
+                        190, 243, 65, 104, 37, 102, 175, 75, 243, 22, 69, 200, 167, 107, 24, 63, 216, 140, 120, 43, 4, 112, 16, 62, 117, 155, 45, 215, 72, 175, 40, 189][..]).unwrap(),
+                    home_relay: Some("https://relay.example".parse().unwrap()),
+                    cookie: None,
+                }),
+                want: "01 00 01 02 03 04 05 06 07 08 09 0a 0b 0c be f3 41 68 25 66 af 4b f3 16 45 c8 a7 6b 18 3f d8 8c 78 2b 04 70 10 3e 75 9b 2d d7 48 af 28 bd 68 74 74 70 73 3a 2f 2f 72 65 6c 61 79 2e 65 78 61 6d 70 6c 65 2e 2f",
+            },
+            Test {
+                name: "ping_with_cookie",
+                m: Message::Ping(Ping {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    node_key: PublicKey::try_from(&[
+                        190, 243, 65, 104, 37, 102, 175, 75, 243, 22, 69, 200, 167, 107, 24, 63, 216, 140, 120, 43, 4, 112, 16, 62, 117, 155, 45, 215, 72, 175, 40, 189][..]).unwrap(),
+                    home_relay: Some("https://relay.example".parse().unwrap()),
+                    cookie: Some([0xcc; COOKIE_LEN]),
+                }),
+                want: "01 01 01 02 03 04 05 06 07 08 09 0a 0b 0c be f3 41 68 25 66 af 4b f3 16 45 c8 a7 6b 18 3f d8 8c 78 2b 04 70 10 3e 75 9b 2d d7 48 af 28 bd cc cc cc cc cc cc cc cc cc cc cc cc cc cc cc cc 68 74 74 70 73 3a 2f 2f 72 65 6c 61 79 2e 65 78 61 6d 70 6c 65 2e 2f",
+            },
             Test {
                 name: "pong",
                 m: Message::Pong(Pong{
@@ -438,6 +703,22 @@ mod tests {
                 }),
                 want: "03 00 00 00 00 00 00 00 00 00 00 00 ff ff 01 02 03 04 37 02 20 01 00 00 00 00 00 00 00 00 00 00 00 00 34 56 15 03",
             },
+            Test {
+                name: "goodbye",
+                m: Message::Goodbye(Goodbye {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    sent_at: 0x0102030405,
+                }),
+                want: "04 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 05 04 03 02 01 00 00 00",
+            },
+            Test {
+                name: "challenge",
+                m: Message::Challenge(Challenge {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    cookie: [0xcc; COOKIE_LEN],
+                }),
+                want: "05 00 01 02 03 04 05 06 07 08 09 0a 0b 0c cc cc cc cc cc cc cc cc cc cc cc cc cc cc cc cc",
+            },
         ];
         for test in tests {
             println!("{}", test.name);
@@ -462,6 +743,8 @@ mod tests {
         let msg = Message::Ping(Ping {
             tx_id: stun::TransactionId::default(),
             node_key: sender_key.public(),
+            home_relay: None,
+            cookie: None,
         });
 
         let shared = sender_key.shared(&recv_key.public());
@@ -475,7 +758,9 @@ mod tests {
 
         let (raw_key, seal_back) = source_and_box(&bytes).unwrap();
         assert_eq!(raw_key, sender_key.public());
-        assert_eq!(seal_back, seal);
+        assert_eq!(seal_back.suite, 0);
+        assert_eq!(seal_back.sealed, seal);
+        let seal_back = seal_back.sealed;
 
         let shared_recv = recv_key.shared(&sender_key.public());
         let mut open_seal = seal_back.to_vec();
@@ -485,4 +770,210 @@ mod tests {
         let msg_back = Message::from_bytes(&open_seal).unwrap();
         assert_eq!(msg_back, msg);
     }
+
+    #[test]
+    fn test_suite_extraction() {
+        let sender = SecretKey::generate().public();
+        let seal = vec![9u8; 40];
+
+        // Version 0 is encoded like before suites existed.
+        let bytes = encode_suite_message(&sender, 0, &[], seal.clone());
+        assert_eq!(bytes, encode_message(&sender, seal.clone()));
+
+        let bytes = encode_suite_message(&sender, 3, &[1, 2, 3], seal.clone());
+        assert!(looks_like_disco_wrapper(&bytes));
+        assert_eq!(&bytes[..MAGIC_LEN], MAGIC_SUITE.as_bytes());
+        let (source, sealed_box) = source_and_box(&bytes).unwrap();
+        assert_eq!(source, sender);
+        assert_eq!(
+            sealed_box,
+            SealedBox {
+                suite: 3,
+                key_material: &[1, 2, 3],
+                sealed: &seal,
+            }
+        );
+
+        // Truncated key material.
+        let header_len = MESSAGE_HEADER_LEN + SUITE_HEADER_LEN;
+        assert!(source_and_box(&bytes[..header_len + 2]).is_none());
+        // Version 0 with the suite magic.
+        let mut bytes = bytes;
+        bytes[MESSAGE_HEADER_LEN] = 0;
+        assert!(source_and_box(&bytes).is_none());
+    }
+
+    /// Test vectors for the full wire encoding of sealed disco messages.
+    ///
+    /// The sender's secret key is `[1u8; 32]`, the receiver's `[2u8; 32]` (both ed25519
+    /// seeds) and every message is sealed with the nonce `[7u8; 24]`.  Other implementations
+    /// can use these to check that they are wire compatible.
+    #[test]
+    fn test_seal_vectors() {
+        struct Test {
+            name: &'static str,
+            m: Message,
+            want: &'static str,
+        }
+        let sender_key = SecretKey::from([1u8; 32]);
+        let recv_key = SecretKey::from([2u8; 32]);
+        let nonce = [7u8; 24];
+
+        let tests = [
+            Test {
+                name: "ping",
+                m: Message::Ping(Ping {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    node_key: sender_key.public(),
+                    home_relay: None,
+                    cookie: None,
+                }),
+                want: "54 53 f0 9f 92 ac 8a 88 e3 dd 74 09 f1 95 fd 52 db 2d 3c ba 5d 72 ca 67 09 bf 1d 94 12 1b f3 74 88 01 b4 0f 6f 5c 57 d2 7c bb 1e f2 6c 98 b7 31 c3 3a 2b 5c cb 4c 1b a2 0c ad 34 59 cf c4 e2 03 6f b4 37 02 11 ac ec 83 1d 5b bf b0 67 8b 6c 8b 10 c8 ca b3 97 5a 2b 8c b1 17 51 9d ca 41 9c 09 90 f8 06 fc 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07",
+            },
+            Test {
+                name: "pong",
+                m: Message::Pong(Pong {
+                    tx_id: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12].into(),
+                    src: SendAddr::Udp("2.3.4.5:1234".parse().unwrap()),
+                }),
+                want: "54 53 f0 9f 92 ac 8a 88 e3 dd 74 09 f1 95 fd 52 db 2d 3c ba 5d 72 ca 67 09 bf 1d 94 12 1b f3 74 88 01 b4 0f 6f 5c 63 a6 80 ed 8a 10 67 dd b2 8d 35 a6 a1 d2 f8 05 18 a2 0c ad 34 59 cf c4 e2 03 6f b4 37 02 9b 24 0f 5e 69 52 4e 25 9a d9 b7 59 d3 70 94 c5 58 ef 26 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07",
+            },
+            Test {
+                name: "call_me_maybe",
+                m: Message::CallMeMaybe(CallMeMaybe { my_numbers: Vec::new() }),
+                want: "54 53 f0 9f 92 ac 8a 88 e3 dd 74 09 f1 95 fd 52 db 2d 3c ba 5d 72 ca 67 09 bf 1d 94 12 1b f3 74 88 01 b4 0f 6f 5c 2a 54 08 5b 29 30 34 2a d9 44 6d ad 58 07 d9 12 19 a2 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07 07",
+            },
+        ];
+        for test in tests {
+            println!("{}", test.name);
+            let want = hex::decode(test.want.replace(' ', "")).unwrap();
+
+            let mut seal = test.m.as_bytes();
+            sender_key
+                .shared(&recv_key.public())
+                .seal_with_nonce(&nonce, &mut seal);
+            let got = encode_message(&sender_key.public(), seal);
+            assert_eq!(got, want, "wrong sealed bytes");
+
+            let (source, sealed_box) = source_and_box(&want).expect("not a disco message");
+            assert_eq!(source, sender_key.public());
+            let mut open = sealed_box.sealed.to_vec();
+            recv_key
+                .shared(&source)
+                .open(&mut open)
+                .expect("failed to open");
+            assert_eq!(
+                Message::from_bytes(&open).unwrap(),
+                test.m,
+                "wrong opened message"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use proptest::prelude::*;
+
+    use crate::key::SecretKey;
+
+    use super::*;
+
+    fn secret_key() -> impl Strategy<Value = SecretKey> {
+        prop::array::uniform32(any::<u8>()).prop_map(SecretKey::from)
+    }
+
+    fn tx_id() -> impl Strategy<Value = stun::TransactionId> {
+        prop::array::uniform12(any::<u8>()).prop_map(stun::TransactionId::from)
+    }
+
+    /// Generates socket addresses which survive the canonicalization on parsing.
+    fn socket_addr() -> impl Strategy<Value = SocketAddr> {
+        let v4 = any::<Ipv4Addr>().prop_map(IpAddr::V4);
+        let v6 = any::<Ipv6Addr>()
+            .prop_filter("ipv4-mapped", |ip| ip.to_ipv4_mapped().is_none())
+            .prop_map(IpAddr::V6);
+        (prop_oneof![v4, v6], any::<u16>()).prop_map(|(ip, port)| SocketAddr::new(ip, port))
+    }
+
+    fn relay_url() -> impl Strategy<Value = RelayUrl> {
+        "[a-z]{1,20}(\\.[a-z]{1,10}){0,3}".prop_map(|host| {
+            let url: Url = format!("https://{host}").parse().unwrap();
+            url.into()
+        })
+    }
+
+    fn send_addr() -> impl Strategy<Value = SendAddr> {
+        let udp = socket_addr().prop_map(SendAddr::Udp);
+        let relay = relay_url().prop_map(SendAddr::Relay);
+        prop_oneof![udp, relay]
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        let ping = (
+            tx_id(),
+            secret_key(),
+            prop::option::of(relay_url()),
+            prop::option::of(any::<Cookie>()),
+        )
+            .prop_map(|(tx_id, key, home_relay, cookie)| {
+                Message::Ping(Ping {
+                    tx_id,
+                    node_key: key.public(),
+                    home_relay,
+                    cookie,
+                })
+            });
+        let pong =
+            (tx_id(), send_addr()).prop_map(|(tx_id, src)| Message::Pong(Pong { tx_id, src }));
+        let call_me_maybe = prop::collection::vec(socket_addr(), 0..16)
+            .prop_map(|my_numbers| Message::CallMeMaybe(CallMeMaybe { my_numbers }));
+        let goodbye = (tx_id(), any::<u64>())
+            .prop_map(|(tx_id, sent_at)| Message::Goodbye(Goodbye { tx_id, sent_at }));
+        let challenge = (tx_id(), any::<Cookie>())
+            .prop_map(|(tx_id, cookie)| Message::Challenge(Challenge { tx_id, cookie }));
+        prop_oneof![ping, pong, call_me_maybe, goodbye, challenge]
+    }
+
+    proptest! {
+        // Test that we can roundtrip a message to bytes
+        #[test]
+        fn message_roundtrip(msg in message()) {
+            let back = Message::from_bytes(&msg.as_bytes()).unwrap();
+            prop_assert_eq!(msg, back);
+        }
+
+        // Test that parsing arbitrary bytes never panics
+        #[test]
+        fn message_from_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..128)) {
+            let _ = Message::from_bytes(&bytes);
+        }
+
+        // Test that a sealed message can be opened by the receiver, and only by the receiver
+        #[test]
+        fn seal_open_roundtrip(
+            msg in message(),
+            sender_key in secret_key(),
+            recv_key in secret_key(),
+            other_key in secret_key(),
+        ) {
+            let mut seal = msg.as_bytes();
+            sender_key.shared(&recv_key.public()).seal(&mut seal);
+            let packet = encode_message(&sender_key.public(), seal);
+
+            let (source, sealed_box) = source_and_box(&packet).unwrap();
+            prop_assert_eq!(source, sender_key.public());
+
+            let mut open = sealed_box.sealed.to_vec();
+            recv_key.shared(&source).open(&mut open).unwrap();
+            prop_assert_eq!(Message::from_bytes(&open).unwrap(), msg);
+
+            if other_key.public() != recv_key.public() {
+                let mut open = sealed_box.sealed.to_vec();
+                prop_assert!(other_key.shared(&source).open(&mut open).is_err());
+            }
+        }
+    }
 }
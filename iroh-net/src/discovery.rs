@@ -521,6 +521,47 @@ mod tests {
         Ok(())
     }
 
+    /// This test adds a node without any addressing information before connecting.
+    /// Connect must still invoke the discovery, as there is no path to the node.
+    #[tokio::test]
+    async fn magic_endpoint_discovery_with_node_id_only() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let disco_shared = TestDiscoveryShared::default();
+        let ep1 = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            new_endpoint(secret, disco).await
+        };
+        let ep2 = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            new_endpoint(secret, disco).await
+        };
+        // wait for out address to be updated and thus published at least once
+        ep1.my_addr().await?;
+        ep2.add_node_addr(NodeAddr::new(ep1.node_id()))?;
+        let _conn = ep2.connect(NodeAddr::new(ep1.node_id()), TEST_ALPN).await?;
+        Ok(())
+    }
+
+    /// Connecting to a node known only by its node id must fail right away if discovery
+    /// finds nothing, rather than timing out in the QUIC handshake.
+    #[tokio::test]
+    async fn magic_endpoint_discovery_with_node_id_only_fails() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let ep1 = new_endpoint(SecretKey::generate(), EmptyDiscovery).await;
+        let ep2 = new_endpoint(SecretKey::generate(), EmptyDiscovery).await;
+        ep2.add_node_addr(NodeAddr::new(ep1.node_id()))?;
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            ep2.connect(NodeAddr::new(ep1.node_id()), TEST_ALPN),
+        )
+        .await?;
+        let err = res.expect_err("connect without any path must fail");
+        assert!(err.to_string().contains("No addressing information"));
+        Ok(())
+    }
+
     async fn new_endpoint(secret: SecretKey, disco: impl Discovery + 'static) -> MagicEndpoint {
         MagicEndpoint::builder()
             .secret_key(secret)
@@ -10,7 +10,9 @@
 #![recursion_limit = "256"]
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
+pub mod broadcast;
 pub mod config;
+pub mod datagram;
 pub mod defaults;
 pub mod dialer;
 mod disco;
@@ -24,9 +26,13 @@ pub mod netcheck;
 pub mod ping;
 pub mod portmapper;
 pub mod relay;
+pub mod storage;
 pub mod stun;
 pub mod ticket;
 pub mod tls;
+pub mod transport;
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub mod tun;
 pub mod util;
 
 pub use magic_endpoint::{AddrInfo, MagicEndpoint, NodeAddr};
@@ -1,11 +1,21 @@
 //! An endpoint that leverages a [quinn::Endpoint] backed by a [magicsock::MagicSock].
 
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Bytes;
 use derive_more::Debug;
 use futures::StreamExt;
+use iroh_metrics::{inc, inc_by, inc_labeled};
 use quinn_proto::VarInt;
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption};
 use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
 use tracing::{debug, trace};
 
@@ -15,8 +25,17 @@ use crate::{
     discovery::{Discovery, DiscoveryTask},
     dns::{default_resolver, DnsResolver},
     key::{PublicKey, SecretKey},
-    magicsock::{self, ConnectionTypeStream, MagicSock},
+    magicsock::{
+        self, AddrFilter, BufferConfig, ConnectionType, ConnectionTypeStream, EndpointUpdateStream,
+        FamilyStats, InjectError, Ipv6Config, LocalAddrSource, MagicSock, MagicSockEventStream,
+        Metrics as MagicsockMetrics, NodeOrAddr, PathPing, PathTuning, PortHopping, PresenceStream,
+        RelayPolicy, RouteTable, SelfTestReport, SendTap, Socks5Config, StateDump, Timeouts,
+        TurnConfig,
+    },
+    net::ip,
+    netcheck::{ReportProgress, StunServer},
     relay::{RelayMap, RelayMode, RelayUrl},
+    storage::{self, FsStorage, Storage, SECRET_KEY_KEY, SECRET_KEY_VERSION},
     tls, NodeId,
 };
 
@@ -24,6 +43,10 @@ pub use super::magicsock::{EndpointInfo as ConnectionInfo, LocalEndpointsStream}
 
 pub use iroh_base::node_addr::{AddrInfo, NodeAddr};
 
+/// The number of TLS sessions kept to resume connections, see
+/// [`MagicEndpointBuilder::session_store`].
+const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
 /// The delay we add before starting a discovery in [`MagicEndpoint::connect`] if the user provided
 /// new direct addresses (to try these addresses before starting the discovery).
 const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
@@ -33,16 +56,40 @@ const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 pub struct MagicEndpointBuilder {
     secret_key: Option<SecretKey>,
     relay_mode: RelayMode,
+    relay_map_publisher: Option<PublicKey>,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: Option<quinn::TransportConfig>,
+    transport_presets: TransportPresets,
     concurrent_connections: Option<u32>,
     keylog: bool,
     discovery: Option<Box<dyn Discovery>>,
     /// Path for known peers. See [`MagicEndpointBuilder::peers_data_path`].
     peers_path: Option<PathBuf>,
+    storage: Option<Arc<dyn Storage>>,
     dns_resolver: Option<DnsResolver>,
+    metered_hint: bool,
+    udp_proxy: Option<Socks5Config>,
+    turn: Option<TurnConfig>,
+    stun_servers: Vec<StunServer>,
+    addr_filter: Option<Box<dyn AddrFilter>>,
+    path_tuning: PathTuning,
+    timeouts: Timeouts,
+    buffers: BufferConfig,
+    ipv6: Ipv6Config,
+    port_hopping: Option<PortHopping>,
+    fwmark: Option<u32>,
+    bind_device: Option<String>,
+    local_addrs: LocalAddrSource,
+    seal_relay_packets: bool,
+    challenge_unknown_senders: bool,
+    disco_shards: usize,
+    dedup_recv: bool,
+    #[debug("{:?}", session_store.as_ref().map(|_| "ClientSessionStore"))]
+    session_store: Option<Arc<dyn ClientSessionStore>>,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_relay_cert_verify: bool,
+    #[cfg(any(test, feature = "test-utils"))]
+    rng_seed: Option<u64>,
 }
 
 impl Default for MagicEndpointBuilder {
@@ -50,15 +97,38 @@ impl Default for MagicEndpointBuilder {
         Self {
             secret_key: Default::default(),
             relay_mode: RelayMode::Default,
+            relay_map_publisher: None,
             alpn_protocols: Default::default(),
             transport_config: Default::default(),
+            transport_presets: Default::default(),
             concurrent_connections: Default::default(),
             keylog: Default::default(),
             discovery: Default::default(),
             peers_path: None,
+            storage: None,
             dns_resolver: None,
+            metered_hint: false,
+            udp_proxy: None,
+            turn: None,
+            stun_servers: Vec::new(),
+            addr_filter: None,
+            path_tuning: Default::default(),
+            timeouts: Default::default(),
+            buffers: Default::default(),
+            ipv6: Default::default(),
+            port_hopping: None,
+            fwmark: None,
+            bind_device: None,
+            local_addrs: Default::default(),
+            seal_relay_packets: false,
+            challenge_unknown_senders: false,
+            disco_shards: 0,
+            dedup_recv: false,
+            session_store: None,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
+            #[cfg(any(test, feature = "test-utils"))]
+            rng_seed: None,
         }
     }
 }
@@ -97,6 +167,15 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Seed the random choices of the socket, see [`magicsock::Options::rng_seed`].
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
     /// Sets the relay servers to assist in establishing connectivity.
     ///
     /// relay servers are used to discover other peers by [`PublicKey`] and also help
@@ -113,6 +192,19 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Only accepts custom relay maps signed by this publisher key.
+    ///
+    /// When set, a [`RelayMode::Custom`] map is rejected and a [`RelayMode::Signed`] map must
+    /// carry a valid signature by this key, otherwise [`bind`] will result in an error.  A
+    /// [`RelayMode::Signed`] map requires this to be set.  The built-in
+    /// [`RelayMode::Default`] map is not affected.
+    ///
+    /// [`bind`]: MagicEndpointBuilder::bind
+    pub fn relay_map_publisher(mut self, publisher: PublicKey) -> Self {
+        self.relay_map_publisher = Some(publisher);
+        self
+    }
+
     /// Set a custom [quinn::TransportConfig] for this endpoint.
     ///
     /// The transport config contains parameters governing the QUIC state machine.
@@ -125,6 +217,44 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Set a [quinn::TransportConfig] preset used when dialing the given node.
+    ///
+    /// This takes precedence over presets registered with
+    /// [`MagicEndpointBuilder::path_transport_config`].  Presets only apply to outgoing
+    /// connections, incoming connections always use the endpoint's
+    /// [`MagicEndpointBuilder::transport_config`].
+    ///
+    /// Note that unlike the default config used for dialing, presets do not enable keep-alives
+    /// unless configured to.
+    pub fn node_transport_config(
+        mut self,
+        node_id: NodeId,
+        transport_config: quinn::TransportConfig,
+    ) -> Self {
+        self.transport_presets
+            .by_node
+            .insert(node_id, Arc::new(transport_config));
+        self
+    }
+
+    /// Set a [quinn::TransportConfig] preset used when dialing nodes over a kind of path.
+    ///
+    /// The [`PathKind`] is determined from the path the magic socket currently uses for the
+    /// node, e.g. a preset with a larger initial window for [`PathKind::Lan`] or a longer idle
+    /// timeout for [`PathKind::Relay`].  Like
+    /// [`MagicEndpointBuilder::node_transport_config`] this only applies to outgoing
+    /// connections.
+    pub fn path_transport_config(
+        mut self,
+        path_kind: PathKind,
+        transport_config: quinn::TransportConfig,
+    ) -> Self {
+        self.transport_presets
+            .by_path
+            .insert(path_kind, Arc::new(transport_config));
+        self
+    }
+
     /// Maximum number of simultaneous connections to accept.
     ///
     /// New incoming connections are only accepted if the total number of incoming or outgoing
@@ -143,6 +273,24 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Sets the storage for the state kept across restarts.
+    ///
+    /// The known peers are persisted in it, unless a [`Self::peers_data_path`] is set.  If no
+    /// [`Self::secret_key`] is set, the secret key is read from the storage, or generated and
+    /// stored on the first start, so the node keeps its ID.  If a stored key can not be read,
+    /// binding fails rather than replacing it.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Persists the state kept across restarts in the directory at `path`.
+    ///
+    /// Shorthand for [`Self::storage`] with an [`FsStorage`].
+    pub fn data_dir(self, path: PathBuf) -> Self {
+        self.storage(Arc::new(FsStorage::new(path)))
+    }
+
     /// Optionally set a discovery mechanism for this endpoint.
     ///
     /// If you want to combine multiple discovery services, you can pass a
@@ -169,6 +317,161 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Hint that the network is metered, e.g. a cellular connection.
+    ///
+    /// Reduces optional traffic from the start, see [`MagicEndpoint::set_metered`] to change
+    /// this later.
+    pub fn metered_hint(mut self, metered: bool) -> Self {
+        self.metered_hint = metered;
+        self
+    }
+
+    /// Sends the direct UDP path through a SOCKS5 proxy.
+    ///
+    /// For networks which block UDP egress but provide a proxy supporting `UDP ASSOCIATE`.
+    /// If the proxy turns out to be unusable, all traffic goes through the relay.
+    pub fn udp_proxy(mut self, proxy: Socks5Config) -> Self {
+        self.udp_proxy = Some(proxy);
+        self
+    }
+
+    /// Set plain STUN servers to discover our public addresses with, besides the relays.
+    ///
+    /// These do not need to be relay servers, see [`magicsock::Options::stun_servers`].
+    pub fn stun_servers(mut self, servers: Vec<StunServer>) -> Self {
+        self.stun_servers = servers;
+        self
+    }
+
+    /// Send the direct UDP path through an allocation on a TURN server.
+    ///
+    /// For deployments with existing TURN infrastructure, see [`magicsock::Options::turn`].
+    /// If no allocation can be created, UDP is used directly.
+    pub fn turn_server(mut self, turn: TurnConfig) -> Self {
+        self.turn = Some(turn);
+        self
+    }
+
+    /// Optionally set a filter for the direct addresses other nodes advertise.
+    ///
+    /// The filter can drop or rewrite addresses before they are probed, see [`AddrFilter`].
+    pub fn addr_filter(mut self, filter: Box<dyn AddrFilter>) -> Self {
+        self.addr_filter = Some(filter);
+        self
+    }
+
+    /// Set the limits for probing the direct paths of other nodes.
+    pub fn path_tuning(mut self, path_tuning: PathTuning) -> Self {
+        self.path_tuning = path_tuning;
+        self
+    }
+
+    /// Set the timeouts of disco pings, netcheck reports and relay connections.
+    ///
+    /// On links with long round trips use e.g. `Timeouts::default().scaled(3.0)`.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Set the capacities of the internal queues and what happens when they are full.
+    pub fn buffers(mut self, buffers: BufferConfig) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Set how IPv6 is used, see [`MagicSock::set_ipv6_config`].
+    pub fn ipv6_config(mut self, ipv6: Ipv6Config) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+
+    /// Move the IPv4 socket to a new port on a schedule, to evade per-port throttling.
+    ///
+    /// See [`PortHopping`] for details.  Not used by default.
+    pub fn port_hopping(mut self, hopping: PortHopping) -> Self {
+        self.port_hopping = Some(hopping);
+        self
+    }
+
+    /// Set a firewall mark (`SO_MARK`) on all UDP sockets and relay connections.
+    ///
+    /// For policy routing setups on Linux, requires `CAP_NET_ADMIN`.  Binding fails on other
+    /// platforms.
+    pub fn fwmark(mut self, mark: u32) -> Self {
+        self.fwmark = Some(mark);
+        self
+    }
+
+    /// Pin all UDP sockets and relay connections to the network interface named `device`.
+    ///
+    /// For routers and multi-homed hosts which need iroh traffic on a specific uplink.  Only
+    /// supported on Linux, macOS and iOS, binding fails on other platforms.
+    pub fn bind_device(mut self, device: impl Into<String>) -> Self {
+        self.bind_device = Some(device.into());
+        self
+    }
+
+    /// Seal the QUIC packets sent through relay servers, so the relays can not correlate them.
+    ///
+    /// All nodes communicating with this one need to enable this too, see
+    /// [`magicsock::Options::seal_relay_packets`].
+    pub fn seal_relay_packets(mut self, seal: bool) -> Self {
+        self.seal_relay_packets = seal;
+        self
+    }
+
+    /// Challenge pings over UDP from unknown nodes before keeping any state for them.
+    ///
+    /// Protects against floods of pings from spoofed addresses, at the cost of a round trip
+    /// for new direct connections, see [`magicsock::Options::challenge_unknown_senders`].
+    pub fn challenge_unknown_senders(mut self, challenge: bool) -> Self {
+        self.challenge_unknown_senders = challenge;
+        self
+    }
+
+    /// Set the number of tasks handling disco messages and heartbeats of nodes in parallel.
+    ///
+    /// Helps nodes talking to thousands of other nodes, on a multi-threaded runtime.  See
+    /// [`magicsock::Options::disco_shards`].
+    pub fn disco_shards(mut self, shards: usize) -> Self {
+        self.disco_shards = shards;
+        self
+    }
+
+    /// Drop QUIC packets which arrive both directly and through a relay.
+    ///
+    /// See [`magicsock::Options::dedup_recv`].
+    pub fn dedup_recv(mut self, dedup: bool) -> Self {
+        self.dedup_recv = dedup;
+        self
+    }
+
+    /// Set where the TLS sessions used to resume connections are kept.
+    ///
+    /// Resuming a session saves a round trip when connecting to a node again.  Sessions are
+    /// keyed by the local and the remote node, and by default kept in memory for the
+    /// lifetime of the endpoint.  Pass a store shared with an earlier endpoint to keep
+    /// resuming sessions when the endpoint is recreated.  Endpoints with different secret
+    /// keys never resume each other's sessions, so a store can safely be shared between
+    /// them.
+    ///
+    /// The rustls version in use does not allow serializing sessions, so they can not be
+    /// persisted across restarts of the application.
+    pub fn session_store(mut self, store: Arc<dyn ClientSessionStore>) -> Self {
+        self.session_store = Some(store);
+        self
+    }
+
+    /// Set where the local addresses advertised to other nodes come from.
+    ///
+    /// By default the network interfaces are enumerated, use [`LocalAddrSource::Static`] to
+    /// advertise a fixed set of addresses instead.
+    pub fn local_addrs(mut self, local_addrs: LocalAddrSource) -> Self {
+        self.local_addrs = local_addrs;
+        self
+    }
+
     /// Bind the magic endpoint on the specified socket address.
     ///
     /// The *bind_port* is the port that should be bound locally.
@@ -180,11 +483,31 @@ impl MagicEndpointBuilder {
             RelayMode::Disabled => RelayMap::empty(),
             RelayMode::Default => default_relay_map(),
             RelayMode::Custom(relay_map) => {
+                ensure!(
+                    self.relay_map_publisher.is_none(),
+                    "Custom relay server map is not signed"
+                );
+                ensure!(!relay_map.is_empty(), "Empty custom relay server map",);
+                relay_map
+            }
+            RelayMode::Signed(signed) => {
+                let publisher = self
+                    .relay_map_publisher
+                    .context("Signed relay server map without a trusted publisher key")?;
+                let relay_map = signed.verify(&publisher)?;
                 ensure!(!relay_map.is_empty(), "Empty custom relay server map",);
                 relay_map
             }
         };
-        let secret_key = self.secret_key.unwrap_or_else(SecretKey::generate);
+        let secret_key = match (self.secret_key, self.storage.clone()) {
+            (Some(secret_key), _) => secret_key,
+            (None, Some(storage)) => {
+                tokio::task::spawn_blocking(move || load_secret_key(&*storage))
+                    .await?
+                    .context("failed to persist the secret key")?
+            }
+            (None, None) => SecretKey::generate(),
+        };
         let mut server_config = make_server_config(
             &secret_key,
             self.alpn_protocols,
@@ -203,15 +526,111 @@ impl MagicEndpointBuilder {
             secret_key,
             relay_map,
             nodes_path: self.peers_path,
+            storage: self.storage,
             discovery: self.discovery,
             dns_resolver,
+            runtime: None,
+            first_packet_policy: Default::default(),
+            retry_ipv6_bind: true,
+            netcheck_sockets: Default::default(),
+            stun_servers: self.stun_servers,
+            metered_hint: self.metered_hint,
+            udp_proxy: self.udp_proxy,
+            turn: self.turn,
+            addr_filter: self.addr_filter,
+            path_tuning: self.path_tuning,
+            timeouts: self.timeouts,
+            buffers: self.buffers,
+            ipv6: self.ipv6,
+            port_hopping: self.port_hopping,
+            fwmark: self.fwmark,
+            bind_device: self.bind_device,
+            local_addrs: self.local_addrs,
+            seal_relay_packets: self.seal_relay_packets,
+            challenge_unknown_senders: self.challenge_unknown_senders,
+            disco_shards: self.disco_shards,
+            dedup_recv: self.dedup_recv,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            rng_seed: self.rng_seed,
         };
-        MagicEndpoint::bind(Some(server_config), msock_opts, self.keylog).await
+        let mut ep = MagicEndpoint::bind(Some(server_config), msock_opts, self.keylog).await?;
+        ep.transport_presets = Arc::new(self.transport_presets);
+        if let Some(session_store) = self.session_store {
+            ep.session_store = session_store;
+        }
+        Ok(ep)
     }
 }
 
+/// The kind of path over which a node is reached.
+///
+/// Used to select a [quinn::TransportConfig] preset when dialing, see
+/// [`MagicEndpointBuilder::path_transport_config`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PathKind {
+    /// A direct path to a private or link-local address.
+    Lan,
+    /// A direct path to a public address.
+    Direct,
+    /// A path via a relay server, possibly while a direct path is still being tested.
+    Relay,
+}
+
+impl PathKind {
+    /// The kind of path for a [`ConnectionType`], `None` if there is no path.
+    fn from_conn_type(conn_type: &ConnectionType) -> Option<Self> {
+        match conn_type {
+            ConnectionType::Direct(addr) => {
+                let ip = ip::to_canonical(addr.ip());
+                if ip::is_private(&ip) || ip::is_link_local(ip) {
+                    Some(Self::Lan)
+                } else {
+                    Some(Self::Direct)
+                }
+            }
+            ConnectionType::Relay(_) | ConnectionType::Mixed(..) => Some(Self::Relay),
+            ConnectionType::None => None,
+        }
+    }
+}
+
+/// Transport config presets applied when dialing.
+#[derive(Debug, Default)]
+struct TransportPresets {
+    by_node: HashMap<NodeId, Arc<quinn::TransportConfig>>,
+    by_path: HashMap<PathKind, Arc<quinn::TransportConfig>>,
+}
+
+impl TransportPresets {
+    /// Returns the preset for the node, falling back to the preset for the path kind.
+    fn get(
+        &self,
+        node_id: &NodeId,
+        path_kind: Option<PathKind>,
+    ) -> Option<Arc<quinn::TransportConfig>> {
+        self.by_node
+            .get(node_id)
+            .or_else(|| path_kind.and_then(|kind| self.by_path.get(&kind)))
+            .cloned()
+    }
+}
+
+/// Reads the secret key from `storage`, generating and storing one if there is none.
+///
+/// Fails if the stored key can not be read, rather than replacing it with a new identity.
+fn load_secret_key(storage: &dyn Storage) -> Result<SecretKey> {
+    let stored = storage::try_load(storage, SECRET_KEY_KEY, SECRET_KEY_VERSION)
+        .context("failed to load the secret key")?;
+    if let Some(secret_key) = stored {
+        return Ok(secret_key);
+    }
+    let secret_key = SecretKey::generate();
+    storage::store(storage, SECRET_KEY_KEY, SECRET_KEY_VERSION, &secret_key)?;
+    Ok(secret_key)
+}
+
 /// Create a [`quinn::ServerConfig`] with the given secret key and limits.
 pub fn make_server_config(
     secret_key: &SecretKey,
@@ -234,6 +653,10 @@ pub struct MagicEndpoint {
     endpoint: quinn::Endpoint,
     keylog: bool,
     cancel_token: CancellationToken,
+    transport_presets: Arc<TransportPresets>,
+    /// Where the TLS sessions to resume connections with are kept.
+    #[debug("ClientSessionStore")]
+    session_store: Arc<dyn ClientSessionStore>,
 }
 
 impl MagicEndpoint {
@@ -277,6 +700,8 @@ impl MagicEndpoint {
             endpoint,
             keylog,
             cancel_token: CancellationToken::new(),
+            transport_presets: Default::default(),
+            session_store: Arc::new(ClientSessionMemoryCache::new(DEFAULT_SESSION_CACHE_SIZE)),
         })
     }
 
@@ -347,6 +772,20 @@ impl MagicEndpoint {
         self.msock.my_relay()
     }
 
+    /// Get the current latency estimates for each relay server.
+    ///
+    /// See [`MagicSock::relay_latencies`] for how these are measured.
+    pub fn relay_latencies(&self) -> BTreeMap<RelayUrl, magicsock::RelayLatency> {
+        self.msock.relay_latencies()
+    }
+
+    /// Get why the current home relay was chosen.
+    ///
+    /// Returns `None` if there is no home relay.
+    pub fn home_relay_decision(&self) -> Option<magicsock::HomeRelayDecision> {
+        self.msock.home_relay_decision()
+    }
+
     /// Get the [`NodeAddr`] for this endpoint.
     pub async fn my_addr(&self) -> Result<NodeAddr> {
         let addrs = self
@@ -388,6 +827,13 @@ impl MagicEndpoint {
         self.msock.tracked_endpoint(node_id)
     }
 
+    /// Pings a node on all its known paths and returns the round trip time per path.
+    ///
+    /// See [`MagicSock::ping`] for details.
+    pub async fn ping(&self, node_id: PublicKey) -> Result<Vec<PathPing>> {
+        self.msock.ping(node_id).await
+    }
+
     pub(crate) fn cancelled(&self) -> WaitForCancellationFuture<'_> {
         self.cancel_token.cancelled()
     }
@@ -422,10 +868,11 @@ impl MagicEndpoint {
     /// must support this `alpn`, otherwise the connection attempt will fail with an error.
     ///
     /// If the [`NodeAddr`] contains only [`NodeId`] and no direct addresses and no relay servers,
-    /// a discovery service will be invoked, if configured, to try and discover the node's
-    /// addressing information. The discovery services must be configured globally per [`MagicEndpoint`]
-    /// with [`MagicEndpointBuilder::discovery`]. The discovery service will also be invoked if
-    /// none of the existing or provided direct addresses are reachable.
+    /// and none are known from earlier, a discovery service will be invoked, if configured, to
+    /// try and discover the node's addressing information. The discovery services must be
+    /// configured globally per [`MagicEndpoint`] with [`MagicEndpointBuilder::discovery`]. The
+    /// discovery service will also be invoked if none of the existing or provided direct
+    /// addresses are reachable.
     ///
     /// If addresses or relay servers are neither provided nor can be discovered, the connection
     /// attempt will fail with an error.
@@ -446,7 +893,7 @@ impl MagicEndpoint {
 
         // Get the mapped IPv6 address from the magic socket. Quinn will connect to this address.
         let (addr, discovery) = match self.msock.get_mapping_addr(&node_id) {
-            Some(addr) => {
+            Some(addr) if self.has_path_info(node_id) => {
                 // We got a mapped address, which means we either spoke to this endpoint before, or
                 // the user provided addressing info with the [`NodeAddr`].
                 // This does not mean that we can actually connect to any of these addresses.
@@ -462,13 +909,20 @@ impl MagicEndpoint {
                 (addr, discovery)
             }
 
-            None => {
-                // We have not spoken to this endpoint before, and the user provided no direct
-                // addresses or relay URLs. Thus, we start a discovery task and wait for the first
-                // result to arrive, and only then continue, because otherwise we wouldn't have any
-                // path to the remote endpoint.
-                let mut discovery = DiscoveryTask::start(self.clone(), node_id)?;
-                discovery.first_arrived().await?;
+            _ => {
+                // We have not spoken to this endpoint before or only know its node id, and the
+                // user provided no direct addresses or relay URLs. Thus, we start a discovery task
+                // and wait for the first result to arrive, and only then continue, because
+                // otherwise we wouldn't have any path to the remote endpoint.
+                let no_addr_info = || {
+                    format!(
+                        "No addressing information known for node {}",
+                        node_id.fmt_short()
+                    )
+                };
+                let mut discovery =
+                    DiscoveryTask::start(self.clone(), node_id).with_context(no_addr_info)?;
+                discovery.first_arrived().await.with_context(no_addr_info)?;
                 let addr = self.msock.get_mapping_addr(&node_id).ok_or_else(|| {
                     anyhow!("Failed to retrieve the mapped address from the magic socket. Unable to dial node {node_id:?}")
                 })?;
@@ -493,33 +947,85 @@ impl MagicEndpoint {
         conn
     }
 
+    /// Whether the magic socket knows a relay URL or any direct address for the node.
+    fn has_path_info(&self, node_id: NodeId) -> bool {
+        self.connection_info(node_id)
+            .is_some_and(|info| info.relay_url.is_some() || !info.addrs.is_empty())
+    }
+
     async fn connect_quinn(
         &self,
         node_id: &PublicKey,
         alpn: &[u8],
         addr: SocketAddr,
     ) -> Result<quinn::Connection> {
+        let path_kind = self
+            .connection_info(*node_id)
+            .and_then(|info| PathKind::from_conn_type(&info.conn_type));
         let client_config = {
             let alpn_protocols = vec![alpn.to_vec()];
-            let tls_client_config = tls::make_client_config(
+            let mut tls_client_config = tls::make_client_config(
                 &self.secret_key,
                 Some(*node_id),
                 alpn_protocols,
                 self.keylog,
             )?;
+            tls_client_config.resumption = Resumption::store(self.session_store.clone());
             let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
-            let mut transport_config = quinn::TransportConfig::default();
-            transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
-            client_config.transport_config(Arc::new(transport_config));
+            let transport_config = match self.transport_presets.get(node_id, path_kind) {
+                Some(preset) => {
+                    debug!(?path_kind, "using transport config preset");
+                    preset
+                }
+                None => {
+                    let mut transport_config = quinn::TransportConfig::default();
+                    transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
+                    Arc::new(transport_config)
+                }
+            };
+            client_config.transport_config(transport_config);
             client_config
         };
 
-        // TODO: We'd eventually want to replace "localhost" with something that makes more sense.
+        let server_name = tls::server_name(&self.secret_key.public(), node_id);
         let connect = self
             .endpoint
-            .connect_with(client_config, addr, "localhost")?;
+            .connect_with(client_config, addr, &server_name)?;
 
-        connect.await.context("failed connecting to provider")
+        match path_kind {
+            None => inc!(MagicsockMetrics, handshakes_no_path),
+            Some(PathKind::Lan) => inc!(MagicsockMetrics, handshakes_lan),
+            Some(PathKind::Direct) => inc!(MagicsockMetrics, handshakes_direct),
+            Some(PathKind::Relay) => inc!(MagicsockMetrics, handshakes_relay),
+        }
+        let start = Instant::now();
+        let conn = connect.await;
+        let elapsed = start.elapsed().as_millis() as u64;
+        let alpn = String::from_utf8_lossy(alpn);
+        match conn {
+            Ok(_) => {
+                inc_labeled!(MagicsockMetrics, alpn_connections, &alpn);
+                // A handshake which started without a direct path, but finished with one,
+                // benefited from hole punching while it was in progress.
+                let is_direct = |kind| matches!(kind, Some(PathKind::Lan | PathKind::Direct));
+                let path_kind_now = self
+                    .connection_info(*node_id)
+                    .and_then(|info| PathKind::from_conn_type(&info.conn_type));
+                if !is_direct(path_kind) && is_direct(path_kind_now) {
+                    inc!(MagicsockMetrics, handshake_success_upgraded);
+                    inc_by!(MagicsockMetrics, handshake_time_upgraded_ms, elapsed);
+                } else {
+                    inc!(MagicsockMetrics, handshake_success);
+                    inc_by!(MagicsockMetrics, handshake_time_ms, elapsed);
+                }
+            }
+            Err(_) => {
+                inc!(MagicsockMetrics, handshake_failure);
+                inc_labeled!(MagicsockMetrics, alpn_handshake_failures, &alpn);
+            }
+        }
+
+        conn.context("failed connecting to provider")
     }
 
     /// Inform the magic socket about addresses of the peer.
@@ -532,7 +1038,8 @@ impl MagicEndpoint {
     ///
     /// If no UDP addresses are added, and `relay_url` is `None`, it will error.
     /// If no UDP addresses are added, and the given `relay_url` cannot be dialed, it will error.
-    // TODO: This is infallible, stop returning a result.
+    ///
+    /// Returns a [`ClosedError`](magicsock::ClosedError) if the endpoint is closed.
     pub fn add_node_addr(&self, node_addr: NodeAddr) -> Result<()> {
         // Connecting to ourselves is not supported.
         if node_addr.node_id == self.node_id() {
@@ -541,10 +1048,64 @@ impl MagicEndpoint {
                 node_addr.node_id.fmt_short()
             );
         }
-        self.msock.add_node_addr(node_addr);
+        self.msock.add_node_addr(node_addr)?;
         Ok(())
     }
 
+    /// Replaces the routes of virtual IP addresses to nodes.
+    ///
+    /// See [`MagicSock::set_routes`] for details.
+    pub fn set_routes(&self, routes: RouteTable) {
+        self.msock.set_routes(routes);
+    }
+
+    /// Returns the node the virtual IP address `ip` is routed to.
+    pub fn route_lookup(&self, ip: IpAddr) -> Option<NodeId> {
+        self.msock.route_lookup(ip)
+    }
+
+    /// Watches whether `nodes` are connected to our home relay server.
+    ///
+    /// See [`MagicSock::watch_presence`] for details.
+    pub async fn watch_presence(
+        &self,
+        nodes: impl IntoIterator<Item = NodeId>,
+    ) -> Result<PresenceStream> {
+        self.msock.watch_presence(nodes).await
+    }
+
+    /// Sets whether QUIC packets to `node` may be sent through relay servers.
+    ///
+    /// See [`MagicSock::set_node_relay_policy`].
+    pub fn set_node_relay_policy(&self, node: NodeId, policy: RelayPolicy) {
+        self.msock.set_node_relay_policy(node, policy)
+    }
+
+    /// Forgets all addressing information about a node, returning whether it was known.
+    ///
+    /// Connections to the node stop working, close them first.
+    pub fn remove_node(&self, node_id: &NodeId) -> bool {
+        self.msock.remove_node(node_id)
+    }
+
+    /// Checks that this endpoint can connect to other nodes from this host.
+    ///
+    /// Connects to an ephemeral node in this process, which uses the same relays and DNS
+    /// resolver, over direct addresses and over the home relay, and runs disco and a QUIC
+    /// handshake, see [`SelfTestReport`].  Useful as a sanity check after installing an
+    /// application.  Takes a few seconds, and up to ten seconds per failing check.  Other
+    /// connections of this endpoint are not affected.
+    pub async fn self_test(&self) -> SelfTestReport {
+        magicsock::self_test::run(self).await
+    }
+
+    /// Captures the connectivity state, to attach to bug reports.
+    ///
+    /// See [`MagicSock::dump_state`].
+    pub fn dump_state(&self, redact: bool) -> StateDump {
+        self.msock.dump_state(redact)
+    }
+
     /// Get a reference to the DNS resolver used in this [`MagicEndpoint`].
     pub fn dns_resolver(&self) -> &DnsResolver {
         self.msock.dns_resolver()
@@ -576,15 +1137,122 @@ impl MagicEndpoint {
     /// this.
     ///
     /// Even when the network did not change, or iroh was already able to detect
-    /// the network change itself, there is no harm in calling this function.
+    /// the network change itself, there is no harm in calling this function.  It does
+    /// nothing once the endpoint is closed.
     pub async fn network_change(&self) {
-        self.msock.network_change().await;
+        self.msock.network_change().await.ok();
+    }
+
+    /// Re-discovers our addresses, e.g. when the application knows the network changed.
+    ///
+    /// See [`MagicSock::trigger_endpoint_update`] for details.
+    pub fn trigger_endpoint_update(&self, reason: impl Into<Cow<'static, str>>) -> Result<()> {
+        self.msock.trigger_endpoint_update(reason)?;
+        Ok(())
+    }
+
+    /// Returns a stream of the endpoint updates finished from now on.
+    ///
+    /// See [`MagicSock::endpoint_updates`] for details.
+    pub fn endpoint_updates(&self) -> EndpointUpdateStream {
+        self.msock.endpoint_updates()
+    }
+
+    /// Returns a stream of the state changes of the endpoint from now on.
+    ///
+    /// See [`MagicSock::subscribe`] for details.
+    pub fn subscribe(&self) -> MagicSockEventStream {
+        self.msock.subscribe()
+    }
+
+    /// Handles a packet received on behalf of the endpoint over another transport.
+    ///
+    /// See [`MagicSock::inject_recv`] for details.
+    pub fn inject_recv(&self, from: NodeOrAddr, packet: Bytes) -> Result<(), InjectError> {
+        self.msock.inject_recv(from, packet)
+    }
+
+    /// Installs a tap which receives every QUIC datagram sent to a node.
+    ///
+    /// See [`MagicSock::send_tap`] for details.
+    pub fn send_tap(&self) -> SendTap {
+        self.msock.send_tap()
+    }
+
+    /// Suspends or resumes all network activity, keeping the state about other nodes.
+    ///
+    /// See [`MagicSock::set_offline`] for details.
+    pub async fn set_offline(&self, offline: bool) -> Result<()> {
+        self.msock.set_offline(offline).await?;
+        Ok(())
+    }
+
+    /// Prepares for the operating system going to sleep.
+    ///
+    /// See [`MagicSock::suspend`] for details.
+    pub async fn suspend(&self) -> Result<()> {
+        self.msock.suspend().await?;
+        Ok(())
+    }
+
+    /// Recovers the connectivity after the operating system woke up.
+    ///
+    /// Call this from the operating system's wake up notification, see
+    /// [`MagicSock::resume`] for details.
+    pub async fn resume(&self) -> Result<()> {
+        self.msock.resume().await?;
+        Ok(())
+    }
+
+    /// Sets whether the network is metered, reducing optional traffic.
+    ///
+    /// See [`MagicSock::set_metered`] for details.
+    pub fn set_metered(&self, metered: bool) {
+        self.msock.set_metered(metered);
+    }
+
+    /// Changes how IPv6 is used, without rebinding.
+    ///
+    /// See [`MagicSock::set_ipv6_config`] for details.
+    pub fn set_ipv6_config(&self, config: Ipv6Config) {
+        self.msock.set_ipv6_config(config);
+    }
+
+    /// Returns the outcomes of recent probes to direct paths, per IP family.
+    ///
+    /// See [`MagicSock::family_stats`] for details.
+    pub fn family_stats(&self) -> FamilyStats {
+        self.msock.family_stats()
+    }
+
+    /// Watches the progress of finding the nearest relay.
+    ///
+    /// See [`MagicSock::netcheck_progress`] for details.
+    pub fn netcheck_progress(&self) -> tokio::sync::watch::Receiver<Option<ReportProgress>> {
+        self.msock.netcheck_progress()
+    }
+
+    /// Simulates bad network conditions for the data sent to `node_id`, or clears them.
+    ///
+    /// See [`MagicSock::set_link_conditions`] for details.
+    #[cfg(feature = "net-conditioner")]
+    pub fn set_link_conditions(
+        &self,
+        node_id: NodeId,
+        conditions: Option<magicsock::LinkConditions>,
+    ) {
+        self.msock.set_link_conditions(node_id, conditions);
+    }
+
+    /// Whether the endpoint is open and not suspended with [`MagicEndpoint::set_offline`].
+    pub fn is_online(&self) -> bool {
+        self.msock.is_online()
     }
 
-    #[cfg(test)]
     pub(crate) fn magic_sock(&self) -> &MagicSock {
         &self.msock
     }
+
     #[cfg(test)]
     pub(crate) fn endpoint(&self) -> &quinn::Endpoint {
         &self.endpoint
@@ -596,11 +1264,28 @@ pub async fn accept_conn(
     mut conn: quinn::Connecting,
 ) -> Result<(PublicKey, String, quinn::Connection)> {
     let alpn = get_alpn(&mut conn).await?;
-    let conn = conn.await?;
+    let conn = finish_connecting(conn, &alpn).await?;
     let peer_id = get_remote_node_id(&conn)?;
     Ok((peer_id, alpn, conn))
 }
 
+/// Completes the handshake of an incoming connection using the protocol `alpn`.
+///
+/// The same as awaiting `connecting`, but also records the connection in the per-ALPN
+/// metrics.  Connections made with [`MagicEndpoint::connect`] or accepted with
+/// [`accept_conn`] are recorded already.
+pub async fn finish_connecting(
+    connecting: quinn::Connecting,
+    alpn: &str,
+) -> Result<quinn::Connection, quinn::ConnectionError> {
+    let conn = connecting.await;
+    match conn {
+        Ok(_) => inc_labeled!(MagicsockMetrics, alpn_connections, alpn),
+        Err(_) => inc_labeled!(MagicsockMetrics, alpn_handshake_failures, alpn),
+    }
+    conn
+}
+
 /// Extract the ALPN protocol from the peer's TLS certificate.
 pub async fn get_alpn(connecting: &mut quinn::Connecting) -> Result<String> {
     let data = connecting.handshake_data().await?;
@@ -645,7 +1330,7 @@ mod tests {
     use rand_core::SeedableRng;
     use tracing::{error_span, info, info_span, Instrument};
 
-    use crate::{magicsock::ConnectionType, test_utils::run_relay_server};
+    use crate::{magicsock::ConnectionType, relay::SignedRelayMap, test_utils::run_relay_server};
 
     use super::*;
 
@@ -665,6 +1350,106 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_path_kind() {
+        let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let cases = [
+            (
+                ConnectionType::Direct(([192, 168, 1, 2], 1234).into()),
+                Some(PathKind::Lan),
+            ),
+            (
+                ConnectionType::Direct(([169, 254, 0, 1], 1234).into()),
+                Some(PathKind::Lan),
+            ),
+            (
+                ConnectionType::Direct("[fe80::1]:1234".parse().unwrap()),
+                Some(PathKind::Lan),
+            ),
+            (
+                ConnectionType::Direct(([1, 2, 3, 4], 1234).into()),
+                Some(PathKind::Direct),
+            ),
+            (
+                ConnectionType::Relay(relay_url.clone()),
+                Some(PathKind::Relay),
+            ),
+            (
+                ConnectionType::Mixed(([192, 168, 1, 2], 1234).into(), relay_url),
+                Some(PathKind::Relay),
+            ),
+            (ConnectionType::None, None),
+        ];
+        for (conn_type, expected) in cases {
+            assert_eq!(
+                PathKind::from_conn_type(&conn_type),
+                expected,
+                "{conn_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_transport_presets() {
+        let node_a = SecretKey::generate().public();
+        let node_b = SecretKey::generate().public();
+        let node_preset = Arc::new(quinn::TransportConfig::default());
+        let relay_preset = Arc::new(quinn::TransportConfig::default());
+        let presets = TransportPresets {
+            by_node: [(node_a, node_preset.clone())].into_iter().collect(),
+            by_path: [(PathKind::Relay, relay_preset.clone())]
+                .into_iter()
+                .collect(),
+        };
+
+        // Node presets take precedence over path presets.
+        let got = presets.get(&node_a, Some(PathKind::Relay)).unwrap();
+        assert!(Arc::ptr_eq(&got, &node_preset));
+        let got = presets.get(&node_b, Some(PathKind::Relay)).unwrap();
+        assert!(Arc::ptr_eq(&got, &relay_preset));
+        assert!(presets.get(&node_b, Some(PathKind::Lan)).is_none());
+        assert!(presets.get(&node_b, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_relay_map_publisher() {
+        let _guard = iroh_test::logging::setup();
+        let publisher = SecretKey::generate();
+        let relay_map = RelayMap::from_url("https://relay.example".parse().unwrap());
+        let signed = SignedRelayMap::sign(&relay_map, &publisher);
+
+        // Unsigned maps are rejected once a publisher is configured.
+        let res = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map.clone()))
+            .relay_map_publisher(publisher.public())
+            .bind(0)
+            .await;
+        assert!(res.is_err());
+
+        // So are maps signed by anyone else.
+        let res = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Signed(signed.clone()))
+            .relay_map_publisher(SecretKey::generate().public())
+            .bind(0)
+            .await;
+        assert!(res.is_err());
+
+        // Signed maps can not be checked without a publisher.
+        let res = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Signed(signed.clone()))
+            .bind(0)
+            .await;
+        assert!(res.is_err());
+
+        let ep = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Signed(signed))
+            .relay_map_publisher(publisher.public())
+            .bind(0)
+            .await
+            .unwrap();
+        ep.close(0u32.into(), b"done").await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_connect_self() {
         let _guard = iroh_test::logging::setup();
@@ -827,6 +1612,87 @@ mod tests {
         assert_eq!(conn_addr, direct_addr);
     }
 
+    #[tokio::test]
+    async fn save_load_storage() {
+        let _guard = iroh_test::logging::setup();
+
+        let storage: Arc<dyn Storage> = Arc::new(storage::MemStorage::new());
+        let new_endpoint = || {
+            MagicEndpoint::builder()
+                .storage(storage.clone())
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .bind(0)
+        };
+
+        let peer_id = SecretKey::generate().public();
+        let direct_addr: SocketAddr =
+            (std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 8758u16).into();
+        let node_addr = NodeAddr::new(peer_id).with_direct_addresses([direct_addr]);
+
+        let endpoint = new_endpoint().await.unwrap();
+        let node_id = endpoint.node_id();
+        endpoint.add_node_addr(node_addr).unwrap();
+        endpoint.close(0u32.into(), b"done").await.unwrap();
+
+        // The restarted endpoint keeps its ID and knows the peer.
+        let endpoint = new_endpoint().await.unwrap();
+        assert_eq!(endpoint.node_id(), node_id);
+        let ConnectionInfo { mut addrs, .. } = endpoint.connection_info(peer_id).unwrap();
+        assert_eq!(addrs.pop().unwrap().addr, direct_addr);
+        endpoint.close(0u32.into(), b"done").await.unwrap();
+
+        // A damaged key is not replaced by a new identity.
+        storage.put(SECRET_KEY_KEY, b"garbage").unwrap();
+        assert!(new_endpoint().await.is_err());
+        assert_eq!(
+            storage.get(SECRET_KEY_KEY).unwrap().as_deref(),
+            Some(&b"garbage"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn magic_endpoint_watch_presence() {
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map, _relay_url, _relay_guard) = run_relay_server().await.unwrap();
+        let new_endpoint = |secret_key: SecretKey| {
+            MagicEndpoint::builder()
+                .insecure_skip_relay_cert_verify(true)
+                .secret_key(secret_key)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .bind(0)
+        };
+        async fn wait_for(events: &mut PresenceStream, node: NodeId, online: bool) {
+            let event = async {
+                while let Some(event) = events.next().await {
+                    let event = event.unwrap();
+                    assert_eq!(event.node, node);
+                    if event.online == online {
+                        return;
+                    }
+                }
+                panic!("presence stream ended");
+            };
+            tokio::time::timeout(Duration::from_secs(10), event)
+                .await
+                .expect("timeout waiting for presence");
+        }
+
+        let watcher = new_endpoint(SecretKey::generate()).await.unwrap();
+        let peer_key = SecretKey::generate();
+        let peer_id = peer_key.public();
+        let mut events = watcher.watch_presence([peer_id]).await.unwrap();
+
+        // The presence is only visible to the watcher once the peer watches it as well.
+        let peer = new_endpoint(peer_key).await.unwrap();
+        let _peer_events = peer.watch_presence([watcher.node_id()]).await.unwrap();
+        wait_for(&mut events, peer_id, true).await;
+
+        peer.close(0u32.into(), b"bye").await.unwrap();
+        wait_for(&mut events, peer_id, false).await;
+    }
+
     #[tokio::test]
     async fn magic_endpoint_relay_connect_loop() {
         let _logging_guard = iroh_test::logging::setup();
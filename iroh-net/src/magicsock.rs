@@ -19,26 +19,28 @@
 // pub(crate) use conn::tests as conn_tests;
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
     fmt::Display,
     io,
-    net::{IpAddr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4},
     path::PathBuf,
     pin::Pin,
     sync::{
-        atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
     task::{ready, Context, Poll, Waker},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, ensure, Context as _, Result};
+use arc_swap::{ArcSwap, ArcSwapOption};
 use bytes::Bytes;
 use futures::{FutureExt, Stream};
-use iroh_metrics::{inc, inc_by};
+use iroh_metrics::{inc, inc_by, observe, set};
 use quinn::AsyncUdpSocket;
-use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use smallvec::{smallvec, SmallVec};
 use tokio::{
     sync::{self, mpsc, Mutex},
@@ -47,7 +49,7 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{
-    debug, error, error_span, info, info_span, instrument, trace, trace_span, warn, Instrument,
+    debug, error_span, info, info_span, instrument, trace, trace_span, warn, Instrument,
 };
 use watchable::Watchable;
 
@@ -56,34 +58,86 @@ use crate::{
     disco::{self, SendAddr},
     discovery::Discovery,
     dns::DnsResolver,
-    key::{PublicKey, SecretKey, SharedSecret},
+    key::{PublicKey, SecretKey},
     magic_endpoint::NodeAddr,
-    net::{interfaces, ip::LocalAddresses, netmon, IpFamily},
-    netcheck, portmapper,
-    relay::{RelayMap, RelayUrl},
+    net::{interfaces, ip::LocalAddresses, netmon, IpFamily, SocketOptions},
+    netcheck::{self, StunServer},
+    portmapper,
+    relay::{self, RelayMap, RelayUrl, MAX_WATCHED_PEERS},
+    storage::{self, Storage, NODES_KEY, NODES_VERSION},
     stun, AddrInfo,
 };
 
 use self::{
+    actor_queue::{ActorReceiver, ActorSender, MessageClass},
+    admission::Admission,
+    clock::{ClockEvent, ResumeDetector},
+    dedup::PacketDedup,
+    disco_crypto::{DiscoBoxError, DiscoSecrets},
+    disco_shards::DiscoShards,
+    events::EventWatchers,
+    inject::SendTapSlot,
+    log_limit::{error_limited, warn_limited},
     metrics::Metrics as MagicsockMetrics,
-    node_map::{NodeMap, PingAction, PingRole, SendPing},
+    node_map::{LocalConditions, NodeMap, PingAction, PingRole, SendPing, PING_TIMEOUT_DURATION},
+    presence::PresenceWatchers,
     relay_actor::{RelayActor, RelayActorMessage, RelayReadResult},
+    relay_latency::RelayLatencyMap,
+    state_dump::Redactor,
     udp_conn::UdpConn,
 };
 
+mod actor_queue;
+mod addr_filter;
+mod admission;
+mod clock;
+#[cfg(feature = "net-conditioner")]
+mod conditioner;
+mod dedup;
+mod disco_crypto;
+mod disco_shards;
+mod endpoint_updates;
+mod events;
+mod inject;
+mod log_limit;
 mod metrics;
 mod node_map;
+mod presence;
 mod relay_actor;
+mod relay_latency;
+mod relay_seal;
+mod routes;
+pub(crate) mod self_test;
+pub(crate) mod socks5;
+mod state_dump;
 mod timer;
+mod turn;
 mod udp_conn;
+mod udp_relay;
 
 pub use crate::net::UdpSocket;
 
+pub use self::addr_filter::AddrFilter;
+#[cfg(feature = "net-conditioner")]
+pub use self::conditioner::LinkConditions;
+pub use self::endpoint_updates::{EndpointUpdate, EndpointUpdateStream};
+pub use self::events::{Lagged, MagicSockEvent, MagicSockEventStream};
+pub use self::inject::{InjectError, NodeOrAddr, SendTap, TappedDatagram};
 pub use self::metrics::Metrics;
 pub use self::node_map::{
-    ConnectionType, ConnectionTypeStream, ControlMsg, DirectAddrInfo, EndpointInfo,
+    ConnectionType, ConnectionTypeStream, ControlMsg, DirectAddrInfo, EndpointInfo, FamilyStats,
+    NoDirectPathReason, PathPing, PingPath, ProbeStats, RelayPolicy, RelayReachability,
+};
+pub use self::presence::{PresenceEvent, PresenceStream};
+pub use self::relay_latency::{HomeRelayDecision, HomeRelayReason, RelayLatency};
+pub use self::routes::RouteTable;
+pub use self::self_test::{CheckOutcome, PathReport, SelfTestReport};
+pub use self::socks5::Socks5Config;
+pub use self::state_dump::{
+    EndpointDump, NetcheckDump, NodeAddrDump, NodeDump, RecordedEvent, RelayDump, StateDump,
 };
 pub use self::timer::Timer;
+pub use self::turn::TurnConfig;
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
 /// expire at 30 seconds, so this is a few seconds shy of that.
@@ -94,8 +148,22 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How often to save node data.
 const SAVE_NODES_INTERVAL: Duration = Duration::from_secs(30);
 
-/// Maximum duration to wait for a netcheck report.
-const NETCHECK_REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of queued actor messages handled in one go, before timers and other
+/// events get a chance to run.
+///
+/// Which messages are handled first is up to the [`ActorReceiver`], see [`actor_queue`].
+const ACTOR_MESSAGE_BATCH_SIZE: usize = 32;
+
+/// How long closing the socket waits for goodbye messages to be sent.
+const GOODBYE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// On metered networks only every this many heartbeats is sent.
+const METERED_HEARTBEAT_FACTOR: u64 = 3;
+
+/// Minimum time between sending our changed endpoints to the active nodes.
+///
+/// Changes within this time are sent together on a later heartbeat.
+const ENDPOINTS_PUSH_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Contains options for `MagicSock::listen`.
 #[derive(derive_more::Debug)]
@@ -113,6 +181,11 @@ pub struct Options {
     /// Path to store known nodes.
     pub nodes_path: Option<std::path::PathBuf>,
 
+    /// Storage for the state kept across restarts, see [`crate::storage`].
+    ///
+    /// The known nodes are persisted in it, unless `nodes_path` is set.
+    pub storage: Option<Arc<dyn Storage>>,
+
     /// Optional node discovery mechanism.
     pub discovery: Option<Box<dyn Discovery>>,
 
@@ -122,11 +195,140 @@ pub struct Options {
     /// configuration.
     pub dns_resolver: DnsResolver,
 
+    /// Optional tokio runtime to spawn the magicsock tasks on.
+    ///
+    /// If not set, the runtime from which [`MagicSock::new`] is polled is used, and creating
+    /// the magicsock outside of a tokio runtime fails.
+    pub runtime: Option<tokio::runtime::Handle>,
+
+    /// How to handle QUIC packets sent to a node before any path to it is known.
+    pub first_packet_policy: FirstPacketPolicy,
+
+    /// Whether to retry binding the IPv6 socket if this failed at startup.
+    ///
+    /// If set, binding is retried on network changes and periodically, so that IPv6
+    /// connectivity which appears later is used without having to restart the
+    /// [`MagicSock`].
+    pub retry_ipv6_bind: bool,
+
+    /// Which sockets netcheck sends its STUN probes from.
+    pub netcheck_sockets: NetcheckSockets,
+
+    /// Plain STUN servers netcheck probes for our public addresses, besides the relays.
+    ///
+    /// These need not be part of the relay map, so public STUN infrastructure can be used
+    /// even if no relays are configured.
+    pub stun_servers: Vec<StunServer>,
+
+    /// Hint that the network is metered, e.g. a cellular connection.
+    ///
+    /// Reduces optional traffic, see [`MagicSock::set_metered`].
+    pub metered_hint: bool,
+
+    /// Sends the direct UDP path through a SOCKS5 proxy supporting `UDP ASSOCIATE`.
+    ///
+    /// For networks which block UDP egress but provide such a proxy.  If the proxy can not
+    /// be reached or does not support UDP, no direct paths are used and all traffic goes
+    /// through the relay.
+    ///
+    /// No traffic bypasses the proxy: the relays are connected to through it, netcheck is not
+    /// run and no addresses are advertised, nodes learn the proxy's address from our pings.
+    pub udp_proxy: Option<Socks5Config>,
+
+    /// Sends the direct UDP path through an allocation on a TURN server.
+    ///
+    /// For deployments with existing TURN infrastructure.  Only the relayed address of the
+    /// allocation is advertised, so other nodes reach us through the TURN server even behind
+    /// NATs which defeat hole punching.  If no allocation can be created, UDP is used as
+    /// usual.  Can not be combined with [`Options::udp_proxy`].
+    pub turn: Option<TurnConfig>,
+
+    /// Optional filter for the direct addresses advertised by other nodes.
+    pub addr_filter: Option<Box<dyn AddrFilter>>,
+
+    /// Limits for probing the direct paths of nodes.
+    pub path_tuning: PathTuning,
+
+    /// Timeouts of disco pings, netcheck reports and relay connections.
+    pub timeouts: Timeouts,
+
+    /// Capacities of the internal queues and what happens when they are full.
+    pub buffers: BufferConfig,
+
+    /// How IPv6 is used, see [`MagicSock::set_ipv6_config`].
+    pub ipv6: Ipv6Config,
+
+    /// Moves the IPv4 socket to a new port on a schedule, see [`PortHopping`].
+    ///
+    /// Ignored while datagrams are sent through a UDP proxy or TURN server.
+    pub port_hopping: Option<PortHopping>,
+
+    /// Firewall mark (`SO_MARK`) set on the UDP sockets and the TCP connections to relays.
+    ///
+    /// Lets policy routing steer the traffic of the socket, also on the sockets bound later
+    /// on.  Only supported on Linux and requires `CAP_NET_ADMIN`, binding fails otherwise.
+    pub fwmark: Option<u32>,
+
+    /// Name of the network interface the UDP sockets and the TCP connections to relays are
+    /// pinned to.
+    ///
+    /// For routers and multi-homed hosts which need to force the traffic onto one uplink.
+    /// Also applies to the sockets bound later on.  Only supported on Linux, where it
+    /// requires `CAP_NET_RAW` before Linux 5.7, and on macOS and iOS.  Binding fails
+    /// otherwise.
+    pub bind_device: Option<String>,
+
+    /// Where the local addresses advertised as endpoints come from.
+    pub local_addrs: LocalAddrSource,
+
+    /// Seals the QUIC packets sent through relay servers with the disco shared secret.
+    ///
+    /// For deployments which require that relay servers can not correlate the packets of a
+    /// connection by its QUIC connection IDs.  Sealed packets received from relays are
+    /// always opened, but with this set, packets which are not sealed are dropped, so all
+    /// nodes of such a deployment need to enable it.  Costs 44 bytes and a ChaCha20-Poly1305
+    /// pass per packet, only for packets sent through a relay.
+    pub seal_relay_packets: bool,
+
+    /// Challenges pings over UDP from unknown nodes with a stateless cookie.
+    ///
+    /// Without this, a flood of pings from spoofed addresses and fresh node keys makes us
+    /// allocate state for every one of them.  With this set, such a ping is only answered
+    /// with a challenge, and the node is admitted once it repeats the ping with the cookie
+    /// of the challenge.  This costs a round trip for direct connections to nodes we did not
+    /// know before, and nodes of older versions, which do not answer challenges, can only
+    /// reach us after contacting us through a relay.
+    pub challenge_unknown_senders: bool,
+
+    /// Number of tasks handling disco messages and heartbeats of nodes in parallel.
+    ///
+    /// For nodes talking to thousands of other nodes, where handling them on a single task
+    /// limits the disco traffic.  The node map is split into as many shards, and the
+    /// messages and heartbeats of a node are always handled by the task of its shard.
+    ///
+    /// The disco messages from UDP are handled by these tasks in any case.  Zero, the
+    /// default, uses a single task for them and leaves the messages from relays to the actor.
+    /// Any other number uses as many tasks, which handle the messages from relays as well.
+    pub disco_shards: usize,
+
+    /// Drop QUIC packets received on both the direct path and the relay.
+    ///
+    /// While no direct path is confirmed, nodes send on both paths, so the packets may arrive
+    /// twice.  Dropping the duplicates saves decrypting them in QUIC, at the cost of hashing
+    /// every received packet.
+    pub dedup_recv: bool,
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
     #[cfg(any(test, feature = "test-utils"))]
     pub insecure_skip_relay_cert_verify: bool,
+
+    /// Seed for the random choices of the socket, like the jitter of the periodic STUN.
+    ///
+    /// Makes simulations of several sockets reproducible.  May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub rng_seed: Option<u64>,
 }
 
 impl Default for Options {
@@ -136,10 +338,332 @@ impl Default for Options {
             secret_key: SecretKey::generate(),
             relay_map: RelayMap::empty(),
             nodes_path: None,
+            storage: None,
             discovery: None,
             dns_resolver: crate::dns::default_resolver().clone(),
+            runtime: None,
+            first_packet_policy: Default::default(),
+            retry_ipv6_bind: true,
+            netcheck_sockets: Default::default(),
+            stun_servers: Vec::new(),
+            metered_hint: false,
+            udp_proxy: None,
+            turn: None,
+            addr_filter: None,
+            path_tuning: Default::default(),
+            timeouts: Default::default(),
+            buffers: Default::default(),
+            ipv6: Default::default(),
+            port_hopping: None,
+            fwmark: None,
+            bind_device: None,
+            local_addrs: Default::default(),
+            seal_relay_packets: false,
+            challenge_unknown_senders: false,
+            disco_shards: 0,
+            dedup_recv: false,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
+            #[cfg(any(test, feature = "test-utils"))]
+            rng_seed: None,
+        }
+    }
+}
+
+/// The sockets netcheck sends its STUN probes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NetcheckSockets {
+    /// Probe from the sockets used for the QUIC traffic.
+    ///
+    /// This directly discovers the public mappings of these sockets, but interleaves the
+    /// STUN probes with any active QUIC flows.
+    #[default]
+    Main,
+    /// Probe from ephemeral sockets, so the STUN probes do not disturb active QUIC flows.
+    ///
+    /// The public mapping of an ephemeral socket is of no use to other nodes, so every
+    /// `main_every`-th netcheck still probes from the main sockets.  The mappings found
+    /// then are advertised until the next such netcheck.
+    Ephemeral {
+        /// How many netchecks run for every netcheck from the main sockets.
+        main_every: u32,
+    },
+}
+
+impl NetcheckSockets {
+    /// Whether the netcheck with sequence number `run` should use the main sockets.
+    fn use_main_sockets(&self, run: u64) -> bool {
+        match self {
+            Self::Main => true,
+            Self::Ephemeral { main_every } => run % u64::from((*main_every).max(1)) == 0,
+        }
+    }
+}
+
+/// Policy for QUIC packets sent to a node for which no path is known yet.
+///
+/// The first packets to a new node are often sent while its addressing information is
+/// still being resolved.  Rather than failing these sends and relying on the QUIC handshake
+/// retransmits, the [`MagicSock`] stages them in a small queue per node and sends them as
+/// soon as a path becomes known through [`MagicSock::add_node_addr`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirstPacketPolicy {
+    /// The maximum number of packets staged per node.
+    ///
+    /// When the queue is full the oldest packet is dropped.  Zero disables staging, sends
+    /// to a node without any path fail immediately.
+    pub max_packets: usize,
+    /// How long a staged packet is kept before it is dropped.
+    pub max_age: Duration,
+}
+
+impl FirstPacketPolicy {
+    /// A policy which never stages packets.
+    pub const fn disabled() -> Self {
+        Self {
+            max_packets: 0,
+            max_age: Duration::ZERO,
+        }
+    }
+}
+
+impl Default for FirstPacketPolicy {
+    fn default() -> Self {
+        Self {
+            max_packets: 16,
+            max_age: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Limits for probing the direct paths of a node.
+///
+/// When a node advertises many direct addresses, pinging all of them at once sends a burst
+/// of packets which can trip intrusion detection or rate limiting.  Instead only a limited
+/// number of pings is in flight per node.  Addresses on the local network are probed first,
+/// then IPv6 and then the public IPv4 addresses, and the rest as earlier pings complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathTuning {
+    /// The maximum number of pings to direct addresses in flight per node.
+    ///
+    /// Zero means no limit, all addresses are pinged at once.
+    pub max_concurrent_probes: usize,
+}
+
+impl PathTuning {
+    /// How many more probes can be started with `in_flight` probes outstanding.
+    fn probe_budget(&self, in_flight: usize) -> usize {
+        match self.max_concurrent_probes {
+            0 => usize::MAX,
+            max => max.saturating_sub(in_flight),
+        }
+    }
+}
+
+impl Default for PathTuning {
+    fn default() -> Self {
+        Self {
+            max_concurrent_probes: 8,
+        }
+    }
+}
+
+/// Timeouts of the socket, see [`Options::timeouts`].
+///
+/// On links with long round trips, e.g. satellite links, all of them can be stretched at once
+/// with [`Timeouts::scaled`], tests can shrink them.  Intervals like the heartbeat are not
+/// included, the trust in direct paths is tied to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long to wait for the pong to a disco ping before the path is considered down.
+    pub ping: Duration,
+    /// How long to wait for a netcheck report.
+    pub netcheck_report: Duration,
+    /// How long to wait for the TCP connection to a relay server.
+    pub relay_dial: Duration,
+    /// How long to wait for a connection to a relay server, including dialing and the
+    /// handshake.
+    pub relay_connect: Duration,
+    /// How long to wait for the answer to a ping to a relay server.
+    pub relay_ping: Duration,
+}
+
+impl Timeouts {
+    /// Returns the timeouts multiplied by `factor`.
+    ///
+    /// Panics if `factor` is negative, not finite or the result overflows.
+    pub fn scaled(self, factor: f64) -> Self {
+        Self {
+            ping: self.ping.mul_f64(factor),
+            netcheck_report: self.netcheck_report.mul_f64(factor),
+            relay_dial: self.relay_dial.mul_f64(factor),
+            relay_connect: self.relay_connect.mul_f64(factor),
+            relay_ping: self.relay_ping.mul_f64(factor),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            ping: PING_TIMEOUT_DURATION,
+            netcheck_report: Duration::from_secs(10),
+            relay_dial: relay::http::DEFAULT_DIAL_TIMEOUT,
+            relay_connect: relay::http::DEFAULT_CONNECT_TIMEOUT,
+            relay_ping: relay::http::DEFAULT_PING_TIMEOUT,
+        }
+    }
+}
+
+/// What happens to a packet received from a relay while the actor's queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the packet and count it in the `relay_recv_dropped` metric.
+    ///
+    /// The relay connection keeps being read, so its control messages are not delayed.
+    #[default]
+    Drop,
+    /// Wait for room in the queue.
+    ///
+    /// Stops reading from the relay connection meanwhile, which pushes back on the relay
+    /// server and through it on the sending nodes.
+    Block,
+}
+
+/// Capacities of the internal queues of the socket, see [`Options::buffers`].
+///
+/// Larger queues absorb longer bursts, e.g. of packets received from a relay, at the cost of
+/// memory and latency.  All capacities must be greater than zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// Disco messages waiting to be sent over UDP.
+    ///
+    /// Messages are dropped when it is full, counted in the `udp_disco_dropped` metric.
+    pub network_send: usize,
+    /// Packets received from relays, waiting to be read from the socket.
+    pub network_recv: usize,
+    /// Messages to the relay actor.
+    ///
+    /// Data packets wait for room, other messages are dropped when it is full, counted in
+    /// the `relay_actor_msgs_dropped` metric.
+    pub relay_actor: usize,
+    /// Messages to the actor of the socket, per priority, including the packets received
+    /// from relays.
+    pub actor: usize,
+    /// What happens to packets received from relays while the actor's queue is full.
+    pub overflow: OverflowPolicy,
+}
+
+impl BufferConfig {
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.network_send > 0
+                && self.network_recv > 0
+                && self.relay_actor > 0
+                && self.actor > 0,
+            "buffer capacities must be greater than zero"
+        );
+        Ok(())
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            network_send: 256,
+            network_recv: 128,
+            relay_actor: 256,
+            actor: 256,
+            overflow: OverflowPolicy::Drop,
+        }
+    }
+}
+
+/// Moving the IPv4 socket to a new port on a schedule.
+///
+/// Some middleboxes throttle UDP flows per port after a while.  With port hopping a new socket
+/// is bound on a random port every [`PortHopping::interval`], datagrams are sent from it
+/// right away and the active nodes are pinged from it, so they learn the new source address.
+/// The new endpoints are discovered and pushed to the active nodes with call-me-maybes.  The
+/// old socket keeps receiving for [`PortHopping::overlap`], until the nodes switched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortHopping {
+    /// How long a port is used before hopping to the next one.
+    pub interval: Duration,
+    /// How long the previous port keeps receiving after a hop.
+    ///
+    /// Should leave enough time to discover the new endpoints and for the nodes to verify
+    /// them, at least half a minute.
+    pub overlap: Duration,
+}
+
+impl Default for PortHopping {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10 * 60),
+            overlap: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Whether a use of IPv6 is enabled, see [`Ipv6Config`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ipv6Toggle {
+    /// Enabled, the default.
+    #[default]
+    On,
+    /// Disabled.
+    Off,
+    /// Enabled unless the last netcheck found IPv6 to not work.
+    Auto,
+}
+
+/// How IPv6 is used on a socket bound to IPv6.
+///
+/// Some networks have broken IPv6, e.g. router advertisements but no forwarding.  There,
+/// nodes should not rely on our IPv6 addresses, while the socket stays bound so it can be
+/// used again once IPv6 works.  Can be changed at runtime with [`MagicSock::set_ipv6_config`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ipv6Config {
+    /// Whether our IPv6 endpoints are advertised to other nodes.
+    pub advertise: Ipv6Toggle,
+    /// Whether IPv6 paths are preferred over IPv4 paths of similar latency.
+    ///
+    /// When off, IPv4 paths are preferred instead.
+    pub prefer: Ipv6Toggle,
+}
+
+/// Where the local addresses advertised as endpoints come from.
+///
+/// These are the addresses other nodes on the same network can reach us on directly.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LocalAddrSource {
+    /// Use the addresses of the network interfaces.
+    ///
+    /// The interfaces are enumerated by the network monitor when the OS reports a change,
+    /// endpoint updates use the addresses of the latest enumeration.
+    #[default]
+    Interfaces,
+    /// Do not enumerate the network interfaces, advertise these addresses instead.
+    ///
+    /// For hosts with very many virtual interfaces, where enumerating them is too
+    /// expensive, or sandboxes which do not allow it.  The addresses are advertised with the
+    /// port of the socket of their address family.
+    Static(Vec<IpAddr>),
+}
+
+impl LocalAddrSource {
+    /// Returns the local addresses, `interfaces` being those of the network interfaces.
+    fn local_addresses(
+        &self,
+        interfaces: &sync::watch::Receiver<LocalAddresses>,
+    ) -> LocalAddresses {
+        match self {
+            Self::Interfaces => interfaces.borrow().clone(),
+            Self::Static(ips) => LocalAddresses {
+                loopback: Vec::new(),
+                regular: ips.clone(),
+            },
         }
     }
 }
@@ -168,12 +692,14 @@ pub struct MagicSock {
 /// The actual implementation of `MagicSock`.
 #[derive(derive_more::Debug)]
 struct Inner {
-    actor_sender: mpsc::Sender<ActorMessage>,
+    actor_sender: ActorSender,
     relay_actor_sender: mpsc::Sender<RelayActorMessage>,
     /// String representation of the node_id of this node.
     me: String,
     /// Used for receiving relay messages.
     relay_recv_receiver: flume::Receiver<RelayRecvResult>,
+    /// Sends packets injected with [`MagicSock::inject_recv`] to the `relay_recv_receiver`.
+    injected_recv_sender: flume::Sender<RelayRecvResult>,
     /// Stores wakers, to be called when relay_recv_ch receives new data.
     network_recv_wakers: parking_lot::Mutex<Option<Waker>>,
     network_send_wakers: parking_lot::Mutex<Option<Waker>>,
@@ -181,11 +707,17 @@ struct Inner {
     /// The DNS resolver to be used in this magicsock.
     dns_resolver: DnsResolver,
 
+    /// Handle to the runtime on which all magicsock tasks are spawned.
+    #[debug(skip)]
+    rt: tokio::runtime::Handle,
+
     /// Key for this node.
     secret_key: SecretKey,
 
     /// Cached version of the Ipv4 and Ipv6 addrs of the current connection.
-    local_addrs: std::sync::RwLock<(SocketAddr, Option<SocketAddr>)>,
+    ///
+    /// Read on every receive, so readers load a snapshot instead of taking a lock.
+    local_addrs: ArcSwap<(SocketAddr, Option<SocketAddr>)>,
 
     /// Preferred port from `Options::port`; 0 means auto.
     port: AtomicU16,
@@ -194,33 +726,86 @@ struct Inner {
     closing: AtomicBool,
     /// Close was called.
     closed: AtomicBool,
+    /// All network activity is suspended, see [`MagicSock::set_offline`].
+    offline: AtomicBool,
+    /// The network is metered, see [`MagicSock::set_metered`].
+    metered: AtomicBool,
+    /// Whether UDP may only be sent through a SOCKS5 proxy or a TURN server, see
+    /// [`Options::udp_proxy`] and [`Options::turn`].
+    udp_proxy: bool,
+    /// The SOCKS5 proxy all traffic goes through, see [`Options::udp_proxy`].
+    socks5_proxy: Option<Socks5Config>,
+    /// Options of the sockets, see [`Options::fwmark`] and [`Options::bind_device`].
+    socket_options: SocketOptions,
+    /// See [`Options::timeouts`].
+    timeouts: Timeouts,
+    /// See [`Options::buffers`].
+    buffers: BufferConfig,
+    /// Cancelled to shut down the actor.
+    ///
+    /// This is separate from the actor channel, so the shutdown does not have to wait for
+    /// all queued messages to be processed.
+    shutdown_token: CancellationToken,
     /// If the last netcheck report, reports IPv6 to be available.
     ipv6_reported: Arc<AtomicBool>,
 
     /// None (or zero nodes) means relay is disabled.
     relay_map: RelayMap,
     /// Nearest relay node ID; 0 means none/unknown.
-    my_relay: std::sync::RwLock<Option<RelayUrl>>,
+    ///
+    /// Read for every disco message sent, so readers load a snapshot instead of taking a lock.
+    my_relay: ArcSwapOption<RelayUrl>,
+    /// Why `my_relay` was chosen.
+    home_relay_decision: parking_lot::Mutex<Option<HomeRelayDecision>>,
+    /// Moving average of the latency to each relay server.
+    relay_latencies: RelayLatencyMap,
     /// Tracks the networkmap node entity for each node discovery key.
     node_map: NodeMap,
     /// UDP IPv4 socket
     pconn4: UdpConn,
     /// UDP IPv6 socket
-    pconn6: Option<UdpConn>,
+    ///
+    /// Unset if binding failed, in which case it may be bound later by the actor.
+    pconn6: OnceLock<UdpConn>,
     /// Netcheck client
     net_checker: netcheck::Client,
     /// The state for an active DiscoKey.
     disco_secrets: DiscoSecrets,
+    /// Whether QUIC packets sent through relays are sealed, see
+    /// [`Options::seal_relay_packets`].
+    seal_relay_packets: bool,
+    /// Cookies for pings from unknown nodes, see [`Options::challenge_unknown_senders`].
+    admission: Option<Admission>,
+    /// Tasks handling disco messages, see [`Options::disco_shards`].
+    disco_shards: DiscoShards,
+    /// Recently received QUIC packets, if [`Options::dedup_recv`] is set.
+    recv_dedup: Option<PacketDedup>,
+    /// Rotates the [`RecvSource`] polled first by [`Inner::poll_recv`].
+    recv_rotation: AtomicUsize,
     udp_state: quinn_udp::UdpState,
 
-    /// Send buffer used in `poll_send_udp`
+    /// Buffer for the transmits rewritten to the UDP address in `poll_send`.
     send_buffer: parking_lot::Mutex<Vec<quinn_udp::Transmit>>,
+    /// How to handle sends to nodes without any known path.
+    first_packet_policy: FirstPacketPolicy,
+    /// Transmits waiting for a path to their node, see [`FirstPacketPolicy`].
+    staged_transmits: parking_lot::Mutex<HashMap<QuicMappedAddr, StagedTransmits>>,
+    /// Routes of virtual IP addresses to nodes, see [`MagicSock::set_routes`].
+    routes: parking_lot::RwLock<RouteTable>,
+    /// Subscriptions to the presence of nodes, see [`MagicSock::watch_presence`].
+    presence: PresenceWatchers,
+    /// Simulated network conditions, see [`MagicSock::set_link_conditions`].
+    #[cfg(feature = "net-conditioner")]
+    conditioner: conditioner::Conditioner,
     /// UDP disco (ping) queue
     udp_disco_sender: mpsc::Sender<(SocketAddr, PublicKey, disco::Message)>,
 
     /// Optional discovery service
     discovery: Option<Box<dyn Discovery>>,
 
+    /// Optional filter for advertised direct addresses, see [`Options::addr_filter`].
+    addr_filter: Option<Box<dyn AddrFilter>>,
+
     /// Our discovered endpoints
     endpoints: Watchable<DiscoveredEndpoints>,
 
@@ -230,6 +815,15 @@ struct Inner {
 
     /// Indicates the update endpoint state.
     endpoints_update_state: EndpointUpdateState,
+    /// Subscriptions to the state changes, see [`MagicSock::subscribe`], which also keep the
+    /// recent events for [`MagicSock::dump_state`].
+    event_watchers: EventWatchers,
+    /// The tap of [`MagicSock::send_tap`], if installed.
+    send_tap: SendTapSlot,
+    /// The last netcheck report, for [`MagicSock::dump_state`].
+    last_netcheck_report: ArcSwapOption<netcheck::Report>,
+    /// How IPv6 is used, see [`MagicSock::set_ipv6_config`].
+    ipv6_config: parking_lot::Mutex<Ipv6Config>,
 
     /// Skip verification of SSL certificates from relay servers
     ///
@@ -243,17 +837,15 @@ impl Inner {
     ///
     /// If `None`, then we are not connected to any relay nodes.
     fn my_relay(&self) -> Option<RelayUrl> {
-        self.my_relay.read().expect("not poisoned").clone()
+        self.my_relay.load().as_deref().cloned()
     }
 
     /// Sets the relay node with the best latency.
     ///
     /// If we are not connected to any relay nodes, set this to `None`.
     fn set_my_relay(&self, my_relay: Option<RelayUrl>) -> Option<RelayUrl> {
-        let mut lock = self.my_relay.write().expect("not poisoned");
-        let old = lock.take();
-        *lock = my_relay;
-        old
+        let old = self.my_relay.swap(my_relay.map(Arc::new));
+        old.map(|old| (*old).clone())
     }
 
     fn is_closing(&self) -> bool {
@@ -264,13 +856,56 @@ impl Inner {
         self.closed.load(Ordering::SeqCst)
     }
 
+    fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    fn is_metered(&self) -> bool {
+        self.metered.load(Ordering::Relaxed)
+    }
+
+    /// Whether `toggle` is on, using the last netcheck report for [`Ipv6Toggle::Auto`].
+    ///
+    /// Before the first report IPv6 is assumed to work.
+    fn ipv6_enabled(&self, toggle: Ipv6Toggle) -> bool {
+        match toggle {
+            Ipv6Toggle::On => true,
+            Ipv6Toggle::Off => false,
+            Ipv6Toggle::Auto => self
+                .last_netcheck_report
+                .load()
+                .as_ref()
+                .map_or(true, |report| report.ipv6),
+        }
+    }
+
+    /// Applies the [`Ipv6Config::prefer`] setting to the paths of all nodes.
+    fn update_prefer_ipv6(&self) {
+        let prefer = self.ipv6_config.lock().prefer;
+        self.node_map.set_prefer_ipv4(!self.ipv6_enabled(prefer));
+    }
+
+    /// Whether direct UDP paths can not be used, because the SOCKS5 proxy is unusable or the
+    /// TURN allocation was lost.
+    fn udp_blocked(&self) -> bool {
+        self.udp_proxy && !self.pconn4.proxy_alive()
+    }
+
+    /// Returns an error once [`MagicSock::close`] was called.
+    fn ensure_open(&self) -> Result<(), ClosedError> {
+        if self.is_closing() || self.is_closed() {
+            return Err(ClosedError);
+        }
+        Ok(())
+    }
+
     fn public_key(&self) -> PublicKey {
         self.secret_key.public()
     }
 
     /// Get the cached version of the Ipv4 and Ipv6 addrs of the current connection.
     fn local_addr(&self) -> (SocketAddr, Option<SocketAddr>) {
-        *self.local_addrs.read().expect("not poisoned")
+        **self.local_addrs.load()
     }
     fn normalized_local_addr(&self) -> io::Result<SocketAddr> {
         let (v4, v6) = self.local_addr();
@@ -286,6 +921,9 @@ impl Inner {
     ) -> Poll<io::Result<usize>> {
         let bytes_total: usize = transmits.iter().map(|t| t.contents.len()).sum();
         inc_by!(MagicsockMetrics, send_data, bytes_total as _);
+        if self.is_metered() {
+            inc_by!(MagicsockMetrics, send_data_metered, bytes_total as _);
+        }
 
         if self.is_closed() {
             inc_by!(MagicsockMetrics, send_data_network_down, bytes_total as _);
@@ -295,9 +933,15 @@ impl Inner {
             )));
         }
 
-        let mut n = 0;
+        if self.is_offline() {
+            // Pretend the transmits were sent, QUIC treats them as lost.
+            trace!(count = transmits.len(), "offline, dropping transmits");
+            inc_by!(MagicsockMetrics, send_data_network_down, bytes_total as _);
+            return Poll::Ready(Ok(transmits.len()));
+        }
+
         if transmits.is_empty() {
-            return Poll::Ready(Ok(n));
+            return Poll::Ready(Ok(0));
         }
         trace!(
             "sending:\n{}",
@@ -318,38 +962,28 @@ impl Inner {
             )
         );
 
-        let dest = transmits[0].destination;
-        for transmit in transmits.iter() {
-            if transmit.destination != dest {
-                break;
-            }
-            n += 1;
-        }
-
-        // Copy the transmits into an owned buffer, because we will have to modify the send
-        // addresses to translate from the quic mapped address to the actual UDP address.
-        // To avoid allocating on each call to `poll_send`, we use a fixed buffer.
-        let mut transmits = {
-            let mut buf = self.send_buffer.lock();
-            buf.clear();
-            buf.reserve(n);
-            buf.extend_from_slice(&transmits[..n]);
-            buf
-        };
+        // Only the transmits to the first destination are sent, quinn will call us again
+        // for the rest.
+        let mut transmits = first_destination_group(transmits);
+        let dest = QuicMappedAddr(transmits[0].destination);
 
-        let dest = QuicMappedAddr(dest);
+        #[cfg(feature = "net-conditioner")]
+        if let Some(taken) = self.condition_transmits(dest, transmits) {
+            return Poll::Ready(Ok(taken));
+        }
 
         let mut transmits_sent = 0;
         match self
             .node_map
             .get_send_addrs_for_quic_mapped_addr(&dest, self.ipv6_reported.load(Ordering::Relaxed))
         {
-            Some((public_key, udp_addr, relay_url, mut msgs)) => {
+            Some((public_key, udp_addr, relay_url, mut msgs, sent_counter)) => {
+                let udp_addr = udp_addr.filter(|_| !self.udp_blocked());
                 let mut pings_sent = false;
                 // If we have pings to send, we *have* to send them out first.
                 if !msgs.is_empty() {
                     if let Err(err) = ready!(self.poll_handle_ping_actions(cx, &mut msgs)) {
-                        warn!(node = %public_key.fmt_short(), "failed to handle ping actions: {err:?}");
+                        warn_limited!(node = %public_key.fmt_short(), "failed to handle ping actions: {err:?}");
                     }
                     pings_sent = true;
                 }
@@ -362,26 +996,24 @@ impl Inner {
 
                 // send udp
                 if let Some(addr) = udp_addr {
-                    // rewrite target addresses.
-                    for t in transmits.iter_mut() {
-                        t.destination = addr;
-                    }
-                    match self.poll_send_udp(addr, &transmits, cx) {
+                    let mut udp_transmits = self.send_buffer.lock();
+                    address_transmits(&mut udp_transmits, transmits, addr);
+                    match self.poll_send_udp(addr, &udp_transmits, cx) {
                         Poll::Ready(Ok(n)) => {
                             trace!(node = %public_key.fmt_short(), dst = %addr, transmit_count=n, "sent transmits over UDP");
-                            // truncate the transmits vec to `n`. these transmits will be sent to
+                            // truncate the transmits to `n`. these transmits will be sent to
                             // the relay further below. We only want to send those transmits to the relay that were
                             // sent to UDP, because the next transmits will be sent on the next
                             // call to poll_send, which will happen immediately after, because we
                             // are always returning Poll::Ready if poll_send_udp returned
                             // Poll::Ready.
-                            transmits.truncate(n);
+                            transmits = &transmits[..n];
                             transmits_sent = transmits.len();
                             udp_sent = true;
                             // record metrics.
                         }
                         Poll::Ready(Err(err)) => {
-                            error!(node = %public_key.fmt_short(), ?addr, "failed to send udp: {err:?}");
+                            error_limited!(node = %public_key.fmt_short(), ?addr, "failed to send udp: {err:?}");
                             udp_error = Some(err);
                         }
                         Poll::Pending => {
@@ -392,7 +1024,8 @@ impl Inner {
 
                 // send relay
                 if let Some(ref relay_url) = relay_url {
-                    match self.poll_send_relay(relay_url, public_key, split_packets(&transmits)) {
+                    let contents = self.relay_contents(public_key, transmits);
+                    match self.poll_send_relay(relay_url, public_key, contents) {
                         Poll::Ready(sent) => {
                             relay_sent = sent;
                             transmits_sent = transmits.len();
@@ -405,8 +1038,28 @@ impl Inner {
                 }
 
                 if udp_addr.is_none() && relay_url.is_none() {
+                    if self.send_tap.tap(public_key, transmits) {
+                        trace!(node = %public_key.fmt_short(), count = transmits.len(), "no UDP or relay addr, sent transmits to the send tap");
+                        return Poll::Ready(Ok(transmits.len()));
+                    }
                     // Handle no addresses being available
-                    warn!(node = %public_key.fmt_short(), "failed to send: no UDP or relay addr");
+                    if self.stage_transmits(dest, transmits) {
+                        debug!(node = %public_key.fmt_short(), count = transmits.len(), "no UDP or relay addr yet, staged transmits");
+                        return Poll::Ready(Ok(transmits.len()));
+                    }
+                    if self.node_map.relay_withheld(&public_key) {
+                        inc_by!(
+                            MagicsockMetrics,
+                            send_data_relay_withheld,
+                            transmits.len() as _
+                        );
+                        warn_limited!(node = %public_key.fmt_short(), "failed to send: no direct path, and the relay policy forbids the relay");
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            "no direct path to node, and its relay policy forbids the relay",
+                        )));
+                    }
+                    warn_limited!(node = %public_key.fmt_short(), "failed to send: no UDP or relay addr");
                     return Poll::Ready(Err(io::Error::new(
                         io::ErrorKind::NotConnected,
                         "no UDP or relay address available for node",
@@ -422,8 +1075,14 @@ impl Inner {
                     return Poll::Pending;
                 }
 
-                if !relay_sent && !udp_sent && !pings_sent {
-                    warn!(node = %public_key.fmt_short(), "failed to send: no UDP or relay addr");
+                // The tap never applies backpressure, so it only gets what was sent elsewhere.
+                let tap_sent = self.send_tap.tap(public_key, transmits);
+                if tap_sent {
+                    transmits_sent = transmits.len();
+                }
+
+                if !relay_sent && !udp_sent && !pings_sent && !tap_sent {
+                    warn_limited!(node = %public_key.fmt_short(), "failed to send: no UDP or relay addr");
                     let err = udp_error.unwrap_or_else(|| {
                         io::Error::new(
                             io::ErrorKind::NotConnected,
@@ -440,10 +1099,15 @@ impl Inner {
                     send_relay = ?relay_url,
                     "sent transmits"
                 );
+                let bytes_sent: usize = transmits[..transmits_sent]
+                    .iter()
+                    .map(|t| t.contents.len())
+                    .sum();
+                sent_counter.fetch_add(bytes_sent as u64, Ordering::Relaxed);
                 Poll::Ready(Ok(transmits_sent))
             }
             None => {
-                error!(dst=%dest, "no endpoint for mapped address");
+                error_limited!(dst=%dest, "no endpoint for mapped address");
                 Poll::Ready(Err(io::Error::new(
                     io::ErrorKind::NotConnected,
                     "trying to send to unknown endpoint",
@@ -452,6 +1116,84 @@ impl Inner {
         }
     }
 
+    /// Applies the [`LinkConditions`] set for the node at `dest`, if any.
+    ///
+    /// Returns the number of transmits taken over: they are either dropped or handed to the
+    /// actor, which sends them once their delay passed.
+    #[cfg(feature = "net-conditioner")]
+    fn condition_transmits(
+        &self,
+        dest: QuicMappedAddr,
+        transmits: &[quinn_udp::Transmit],
+    ) -> Option<usize> {
+        let conditions = self
+            .conditioner
+            .find(|node| self.node_map.get_quic_mapped_addr_for_node_key(node) == Some(dest))?;
+        let now = Instant::now();
+        let delays = conditions.delays(transmits.len());
+        for (transmit, delay) in transmits.iter().zip(delays) {
+            let Some(delay) = delay else {
+                trace!(dst = %dest, "simulated loss, dropping transmit");
+                continue;
+            };
+            let msg = ActorMessage::SendConditioned(dest, transmit.clone(), now + delay);
+            if self.actor_sender.try_send(msg).is_err() {
+                trace!(dst = %dest, "actor queue full, dropping conditioned transmit");
+            }
+        }
+        Some(transmits.len())
+    }
+
+    /// Stages transmits to a node for which no path is known yet.
+    ///
+    /// Returns `false` if staging is disabled by the [`FirstPacketPolicy`].
+    fn stage_transmits(&self, dest: QuicMappedAddr, transmits: &[quinn_udp::Transmit]) -> bool {
+        let policy = self.first_packet_policy;
+        if policy.max_packets == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut staged = self.staged_transmits.lock();
+        // Forget about destinations which never got a path.
+        staged.retain(|_, queue| {
+            let dropped = queue.prune(now, policy.max_age);
+            inc_by!(MagicsockMetrics, send_data_staged_dropped, dropped as u64);
+            !queue.is_empty()
+        });
+        let queue = staged.entry(dest).or_default();
+        for transmit in transmits {
+            if queue.len() == policy.max_packets {
+                queue.pop_front();
+                inc!(MagicsockMetrics, send_data_staged_dropped);
+            }
+            queue.push_back(now, transmit.clone());
+            inc!(MagicsockMetrics, send_data_staged);
+        }
+        true
+    }
+
+    /// Asks the actor to send out any transmits staged for the node.
+    ///
+    /// Called wherever a first path to the node may have been learned: from the application,
+    /// a disco message or relayed traffic of the node.
+    fn flush_staged_transmits(&self, node: &PublicKey) {
+        if self.staged_transmits.lock().is_empty() {
+            return;
+        }
+        let Some(dest) = self.node_map.get_quic_mapped_addr_for_node_key(node) else {
+            return;
+        };
+        if !self.staged_transmits.lock().contains_key(&dest) {
+            return;
+        }
+        if let Err(err) = self
+            .actor_sender
+            .try_send(ActorMessage::FlushStagedTransmits(dest))
+        {
+            warn!(node = %node.fmt_short(), "failed to flush staged transmits: {err:?}");
+        }
+    }
+
     fn poll_send_udp(
         &self,
         addr: SocketAddr,
@@ -459,7 +1201,13 @@ impl Inner {
         cx: &mut Context<'_>,
     ) -> Poll<io::Result<usize>> {
         let conn = self.conn_for_addr(addr)?;
-        let n = ready!(conn.poll_send(&self.udp_state, cx, transmits))?;
+        let n = match ready!(conn.poll_send(&self.udp_state, cx, transmits)) {
+            Ok(n) => n,
+            Err(err) => {
+                record_udp_send_error(addr, &err);
+                return Poll::Ready(Err(err));
+            }
+        };
         let total_bytes: u64 = transmits
             .iter()
             .take(n)
@@ -473,12 +1221,32 @@ impl Inner {
         Poll::Ready(Ok(n))
     }
 
+    /// Whether datagrams sent on any of the paths might get fragmented by the IP layer.
+    ///
+    /// The relay path carries datagrams inside a TCP stream and never fragments them, so only
+    /// the UDP sockets matter.
+    fn may_fragment(&self) -> bool {
+        self.pconn4.may_fragment() || self.pconn6.get().is_some_and(|c| c.may_fragment())
+    }
+
+    /// The maximum number of GSO segments a single transmit can carry on every path.
+    ///
+    /// Transmits sent over the relay are split into their segments, so this is bounded only
+    /// by the UDP sockets.
+    fn max_transmit_segments(&self) -> usize {
+        self.udp_state.max_gso_segments()
+    }
+
     fn conn_for_addr(&self, addr: SocketAddr) -> io::Result<&UdpConn> {
+        if self.pconn4.is_proxied() {
+            // The proxy relays to both address families.
+            return Ok(&self.pconn4);
+        }
         let sock = match addr {
             SocketAddr::V4(_) => &self.pconn4,
             SocketAddr::V6(_) => self
                 .pconn6
-                .as_ref()
+                .get()
                 .ok_or(io::Error::new(io::ErrorKind::Other, "no IPv6 connection"))?,
         };
         Ok(sock)
@@ -491,7 +1259,6 @@ impl Inner {
         bufs: &mut [io::IoSliceMut<'_>],
         metas: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
-        // FIXME: currently ipv4 load results in ipv6 traffic being ignored
         debug_assert_eq!(bufs.len(), metas.len(), "non matching bufs & metas");
         if self.is_closed() {
             return Poll::Ready(Err(io::Error::new(
@@ -500,23 +1267,45 @@ impl Inner {
             )));
         }
 
-        // order of polling is: UDPv4, UDPv6, relay
-        let msgs = match self.pconn4.poll_recv(cx, bufs, metas)? {
-            Poll::Pending | Poll::Ready(0) => match &self.pconn6 {
-                Some(conn) => match conn.poll_recv(cx, bufs, metas)? {
-                    Poll::Pending | Poll::Ready(0) => {
-                        return self.poll_recv_relay(cx, bufs, metas);
-                    }
-                    Poll::Ready(n) => n,
+        // Quinn polls until we return pending, so a source which is always ready would starve
+        // the sources polled after it.  Each call starts with the next source instead, and the
+        // sources which are pending register the waker.
+        let first = self.recv_rotation.fetch_add(1, Ordering::Relaxed);
+        for i in 0..RecvSource::ALL.len() {
+            let source = RecvSource::ALL[(first + i) % RecvSource::ALL.len()];
+            let msgs = match source {
+                RecvSource::Ipv4 => ready_msgs(self.pconn4.poll_recv(cx, bufs, metas)?),
+                RecvSource::Ipv6 => match self.pconn6.get() {
+                    Some(conn) => ready_msgs(conn.poll_recv(cx, bufs, metas)?),
+                    None => None,
                 },
-                None => {
-                    return self.poll_recv_relay(cx, bufs, metas);
+                RecvSource::Relay => ready_msgs(self.poll_recv_relay(cx, bufs, metas)?),
+            };
+            match (source, msgs) {
+                (_, None) => continue,
+                (RecvSource::Relay, Some(msgs)) => return Poll::Ready(Ok(msgs)),
+                (_, Some(msgs)) => {
+                    self.process_udp_recv(bufs, metas, msgs);
+                    return Poll::Ready(Ok(msgs));
                 }
-            },
-            Poll::Ready(n) => n,
-        };
+            }
+        }
+        Poll::Pending
+    }
 
+    /// Handles the `msgs` datagrams received on a UDP socket.
+    ///
+    /// Forwards the STUN and disco packets, and maps the source addresses of the QUIC packets
+    /// to the [`QuicMappedAddr`] of their node.  Datagrams without QUIC packets to deliver get
+    /// a length of zero, so quinn skips them.
+    fn process_udp_recv(
+        &self,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+        msgs: usize,
+    ) {
         let dst_ip = self.normalized_local_addr().ok().map(|addr| addr.ip());
+        let now = Instant::now();
 
         let mut quic_packets_total = 0;
 
@@ -524,30 +1313,36 @@ impl Inner {
             let mut start = 0;
             let mut is_quic = false;
             let mut quic_packets_count = 0;
+            // The bytes of the STUN, disco and duplicate packets, the last one may be short.
+            let mut non_quic_len = 0;
 
             // find disco and stun packets and forward them to the actor
-            loop {
-                let end = start + meta.stride;
-                if end > meta.len {
-                    break;
-                }
+            while start < meta.len {
+                let end = (start + meta.stride).min(meta.len);
                 let packet = &buf[start..end];
                 let packet_is_quic = if stun::is(packet) {
-                    trace!(src = %meta.addr, len = %meta.stride, "UDP recv: stun packet");
+                    trace!(src = %meta.addr, len = packet.len(), "UDP recv: stun packet");
                     let packet2 = Bytes::copy_from_slice(packet);
                     self.net_checker.receive_stun_packet(packet2, meta.addr);
                     false
                 } else if let Some((sender, sealed_box)) = disco::source_and_box(packet) {
                     // Disco?
-                    trace!(src = %meta.addr, len = %meta.stride, "UDP recv: disco packet");
-                    self.handle_disco_message(
+                    trace!(src = %meta.addr, len = packet.len(), "UDP recv: disco packet");
+                    self.dispatch_disco_message(
                         sender,
                         sealed_box,
                         DiscoMessageSource::Udp(meta.addr),
                     );
                     false
+                } else if self
+                    .recv_dedup
+                    .as_ref()
+                    .is_some_and(|dedup| is_duplicate(dedup, packet, now))
+                {
+                    trace!(src = %meta.addr, len = packet.len(), "UDP recv: duplicate quic packet");
+                    false
                 } else {
-                    trace!(src = %meta.addr, len = %meta.stride, "UDP recv: quic packet");
+                    trace!(src = %meta.addr, len = packet.len(), "UDP recv: quic packet");
                     true
                 };
 
@@ -560,15 +1355,19 @@ impl Inner {
                     // [`quinn::EndpointConfig::grease_quic_bit`] is set to `false`
                     // (which we always do in MagicEndpoint::bind).
                     buf[start] = 0u8;
+                    non_quic_len += end - start;
                 }
                 start = end;
             }
 
             if is_quic {
                 // remap addr
-                match self.node_map.receive_udp(meta.addr) {
+                match self
+                    .node_map
+                    .receive_udp(meta.addr, meta.len - non_quic_len)
+                {
                     None => {
-                        warn!(src = ?meta.addr, count = %quic_packets_count, len = meta.len, "UDP recv quic packets: no node state found, skipping");
+                        warn_limited!(src = ?meta.addr, count = %quic_packets_count, len = meta.len, "UDP recv quic packets: no node state found, skipping");
                         // if we have no node state for the from addr, set len to 0 to make quinn skip the buf completely.
                         meta.len = 0;
                     }
@@ -591,8 +1390,6 @@ impl Inner {
             inc_by!(MagicsockMetrics, recv_datagrams, quic_packets_total as _);
             trace!("UDP recv: {} packets", quic_packets_total);
         }
-
-        Poll::Ready(Ok(msgs))
     }
 
     #[instrument(skip_all, fields(name = %self.me))]
@@ -623,6 +1420,13 @@ impl Inner {
                     inc_by!(MagicsockMetrics, recv_data_relay, bytes.len() as _);
                     trace!(src = %meta.addr, node = %node_id.fmt_short(), count = meta.len / meta.stride, len = meta.len, "recv quic packets from relay");
                     buf_out[..bytes.len()].copy_from_slice(&bytes);
+                    if let Some(ref dedup) = self.recv_dedup {
+                        if is_duplicate(dedup, &bytes, Instant::now()) {
+                            trace!(node = %node_id.fmt_short(), "dropping duplicate quic packet from relay");
+                            // Makes quinn ignore the packet, see `poll_recv`.
+                            buf_out[0] = 0u8;
+                        }
+                    }
                     *meta_out = meta;
                     num_msgs += 1;
                 }
@@ -638,24 +1442,114 @@ impl Inner {
         }
     }
 
-    /// Handles a discovery message.
-    #[instrument("disco_in", skip_all, fields(node = %sender.fmt_short(), %src))]
-    fn handle_disco_message(&self, sender: PublicKey, sealed_box: &[u8], src: DiscoMessageSource) {
-        trace!("handle_disco_message start");
-        if self.is_closed() {
-            return;
+    /// Handles a disco message, on its shard if [`DiscoShards::handles`] its source.
+    fn dispatch_disco_message(
+        &self,
+        sender: PublicKey,
+        sealed_box: disco::SealedBox<'_>,
+        src: DiscoMessageSource,
+    ) {
+        if self.disco_shards.handles(&src) {
+            self.disco_shards.dispatch(sender, sealed_box, src);
+        } else {
+            self.handle_disco_message(sender, sealed_box, src);
         }
+    }
+
+    /// Handles a packet received outside of the socket's own paths, see [`inject`].
+    fn inject_recv(&self, from: NodeOrAddr, packet: Bytes) -> Result<(), InjectError> {
+        self.ensure_open()?;
+        inc!(MagicsockMetrics, recv_injected);
+        let (node_id, quic_mapped_addr) = match from {
+            NodeOrAddr::Node(node_id) => {
+                if stun::is(&packet) || disco::source_and_box(&packet).is_some() {
+                    return Err(InjectError::NotQuic);
+                }
+                let quic_mapped_addr = self
+                    .node_map
+                    .get_quic_mapped_addr_for_node_key(&node_id)
+                    .ok_or(InjectError::UnknownNode(node_id))?;
+                (node_id, quic_mapped_addr)
+            }
+            NodeOrAddr::Addr(addr) => {
+                if stun::is(&packet) {
+                    trace!(src = %addr, len = packet.len(), "injected recv: stun packet");
+                    self.net_checker.receive_stun_packet(packet, addr);
+                    return Ok(());
+                }
+                if let Some((sender, sealed_box)) = disco::source_and_box(&packet) {
+                    trace!(src = %addr, len = packet.len(), "injected recv: disco packet");
+                    self.dispatch_disco_message(sender, sealed_box, DiscoMessageSource::Udp(addr));
+                    return Ok(());
+                }
+                self.node_map
+                    .receive_udp(addr, packet.len())
+                    .ok_or(InjectError::UnknownAddr(addr))?
+            }
+        };
+        trace!(node = %node_id.fmt_short(), len = packet.len(), "injected recv: quic packet");
+        // Delivered like a packet from the relay, see `poll_recv_relay`.
+        let meta = quinn_udp::RecvMeta {
+            len: packet.len(),
+            stride: packet.len(),
+            addr: quic_mapped_addr.0,
+            dst_ip: self.normalized_local_addr().ok().map(|addr| addr.ip()),
+            ecn: None,
+        };
+        self.injected_recv_sender
+            .try_send(Ok((node_id, meta, packet)))
+            .map_err(|err| match err {
+                flume::TrySendError::Full(_) => InjectError::Full,
+                flume::TrySendError::Disconnected(_) => InjectError::Closed,
+            })?;
+        if let Some(waker) = self.network_recv_wakers.lock().take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Handles a discovery message.
+    #[instrument("disco_in", skip_all, fields(node = %sender.fmt_short(), %src))]
+    fn handle_disco_message(
+        &self,
+        sender: PublicKey,
+        sealed_box: disco::SealedBox<'_>,
+        src: DiscoMessageSource,
+    ) {
+        trace!("handle_disco_message start");
+        if self.is_closed() {
+            return;
+        }
+
+        // Unknown nodes pinging over UDP need to pass a challenge first, see
+        // `Options::challenge_unknown_senders`.
+        let challenge_addr = match (&self.admission, &src) {
+            (Some(_), DiscoMessageSource::Udp(addr))
+                if self
+                    .node_map
+                    .get_quic_mapped_addr_for_node_key(&sender)
+                    .is_none() =>
+            {
+                Some(*addr)
+            }
+            _ => None,
+        };
 
         // We're now reasonably sure we're expecting communication from
         // this node, do the heavy crypto lifting to see what they want.
-        let dm = match self.disco_secrets.unseal_and_decode(
-            &self.secret_key,
-            sender,
-            sealed_box.to_vec(),
-        ) {
+        let dm = match challenge_addr {
+            Some(_) => {
+                self.disco_secrets
+                    .unseal_and_decode_uncached(&self.secret_key, sender, sealed_box)
+            }
+            None => self
+                .disco_secrets
+                .unseal_and_decode(&self.secret_key, sender, sealed_box),
+        };
+        let dm = match dm {
             Ok(dm) => dm,
             Err(DiscoBoxError::Open(err)) => {
-                warn!(?err, "failed to open disco box");
+                warn_limited!(?err, "failed to open disco box");
                 inc!(MagicsockMetrics, recv_disco_bad_key);
                 return;
             }
@@ -678,6 +1572,12 @@ impl Inner {
             inc!(MagicsockMetrics, recv_disco_udp);
         }
 
+        if let Some(addr) = challenge_addr {
+            if !self.admit(sender, addr, &dm) {
+                return;
+            }
+        }
+
         let span = trace_span!("handle_disco", ?dm);
         let _guard = span.enter();
         trace!("receive disco message");
@@ -688,15 +1588,24 @@ impl Inner {
             }
             disco::Message::Pong(pong) => {
                 inc!(MagicsockMetrics, recv_disco_pong);
-                self.node_map.handle_pong(sender, &src, pong);
+                for action in self.node_map.handle_pong(sender, &src, pong) {
+                    if let PingAction::SendPing(ping) = action {
+                        self.send_ping_queued(ping);
+                    }
+                }
+                self.flush_staged_transmits(&sender);
             }
-            disco::Message::CallMeMaybe(cm) => {
+            disco::Message::CallMeMaybe(mut cm) => {
                 inc!(MagicsockMetrics, recv_disco_call_me_maybe);
-                if !matches!(src, DiscoMessageSource::Relay { .. }) {
+                let DiscoMessageSource::Relay { ref url, .. } = src else {
                     warn!("call-me-maybe packets should only come via relay");
                     return;
                 };
-                let ping_actions = self.node_map.handle_call_me_maybe(sender, cm);
+                if let Some(filter) = &self.addr_filter {
+                    let my_numbers = std::mem::take(&mut cm.my_numbers);
+                    cm.my_numbers = addr_filter::apply(filter.as_ref(), &sender, my_numbers);
+                }
+                let ping_actions = self.node_map.handle_call_me_maybe(sender, url, cm);
                 for action in ping_actions {
                     match action {
                         PingAction::SendCallMeMaybe { .. } => {
@@ -707,17 +1616,105 @@ impl Inner {
                         }
                     }
                 }
+                self.flush_staged_transmits(&sender);
+            }
+            disco::Message::Goodbye(goodbye) => {
+                inc!(MagicsockMetrics, recv_disco_goodbye);
+                self.node_map.handle_goodbye(sender, &goodbye);
+            }
+            disco::Message::Challenge(challenge) => {
+                inc!(MagicsockMetrics, recv_disco_challenge);
+                self.handle_challenge(challenge, sender, src);
             }
         }
         trace!("disco message handled");
     }
 
+    /// Decides whether a disco message from an unknown node over UDP is handled.
+    ///
+    /// Only pings carrying a valid cookie are.  A ping without a cookie is answered with a
+    /// [`disco::Challenge`], without keeping any state for the node.
+    fn admit(&self, sender: PublicKey, addr: SocketAddr, dm: &disco::Message) -> bool {
+        let Some(admission) = &self.admission else {
+            return true;
+        };
+        let now = Instant::now();
+        match dm {
+            disco::Message::Ping(ping) => match ping.cookie {
+                Some(ref cookie) if admission.verify(cookie, &sender, addr, now) => {
+                    debug!(node = %sender.fmt_short(), %addr, "admitting node with valid cookie");
+                    return true;
+                }
+                Some(_) => {
+                    debug!(node = %sender.fmt_short(), %addr, "dropping ping with invalid cookie");
+                }
+                None => {
+                    let challenge = disco::Message::Challenge(disco::Challenge {
+                        tx_id: ping.tx_id,
+                        cookie: admission.cookie(&sender, addr, now),
+                    });
+                    debug!(node = %sender.fmt_short(), %addr, "challenging ping from unknown node");
+                    if !self.queue_disco_message_udp(addr, sender, challenge) {
+                        debug!(%addr, "failed to queue challenge");
+                    }
+                    return false;
+                }
+            },
+            _ => {
+                debug!(node = %sender.fmt_short(), %addr, %dm, "dropping disco message from unknown node");
+            }
+        }
+        inc!(MagicsockMetrics, recv_disco_unadmitted);
+        false
+    }
+
+    /// Repeats a ping to a node which challenged it, with the cookie of the challenge.
+    fn handle_challenge(
+        &self,
+        challenge: disco::Challenge,
+        sender: PublicKey,
+        src: DiscoMessageSource,
+    ) {
+        let DiscoMessageSource::Udp(addr) = src else {
+            warn!("challenges should only come via UDP");
+            return;
+        };
+        let dst = SendAddr::Udp(addr);
+        // Only answer challenges to our own pings, so we can not be made to send pings.
+        if !self
+            .node_map
+            .is_ping_pending(&sender, &dst, challenge.tx_id)
+        {
+            debug!(node = %sender.fmt_short(), %addr, "ignoring challenge to unknown ping");
+            return;
+        }
+        debug!(node = %sender.fmt_short(), %addr, tx = %hex::encode(challenge.tx_id), "repeating challenged ping");
+        let ping = disco::Message::Ping(disco::Ping {
+            tx_id: challenge.tx_id,
+            node_key: self.public_key(),
+            home_relay: self.my_relay(),
+            cookie: Some(challenge.cookie),
+        });
+        if !self.send_disco_message_queued(dst, sender, ping) {
+            warn!(%addr, "failed to queue ping with cookie");
+        }
+    }
+
     /// Handle a ping message.
     fn handle_ping(&self, dm: disco::Ping, sender: &PublicKey, src: DiscoMessageSource) {
         // Insert the ping into the node map, and return whether a ping with this tx_id was already
         // received.
         let addr: SendAddr = src.clone().into();
         let handled = self.node_map.handle_ping(*sender, addr.clone(), dm.tx_id);
+        if let Some(home_relay) = dm.home_relay {
+            // Otherwise any node could make us connect to a server of its choosing.
+            if self.relay_map.contains_node(&home_relay) {
+                self.node_map.set_home_relay(*sender, home_relay);
+            } else {
+                debug!(%home_relay, "ignoring advertised home relay, not in our relay map");
+            }
+        }
+        self.flush_staged_transmits(sender);
         match handled.role {
             PingRole::Duplicate => {
                 debug!(%src, tx = %hex::encode(dm.tx_id), "received ping: endpoint already confirmed, skip");
@@ -754,9 +1751,36 @@ impl Inner {
         }
     }
 
+    /// Tells the nodes we are actively talking to that we are shutting down.
+    ///
+    /// Best effort, the caller should bound the time spent with a timeout.
+    async fn send_goodbyes(&self) {
+        for (node, addrs) in self.node_map.goodbye_addrs(Instant::now()) {
+            // The same goodbye on all paths, the node handles the first one to arrive.
+            let goodbye = disco::Message::Goodbye(disco::Goodbye::new(SystemTime::now()));
+            for addr in addrs {
+                match addr {
+                    SendAddr::Udp(addr) => {
+                        self.send_disco_message_udp(addr, node, &goodbye).await.ok();
+                    }
+                    SendAddr::Relay(ref url) => {
+                        self.send_disco_message_relay(url, node, goodbye.clone());
+                    }
+                }
+            }
+        }
+    }
+
     fn encode_disco_message(&self, dst_key: PublicKey, msg: &disco::Message) -> Bytes {
-        self.disco_secrets
-            .encode_and_seal(&self.secret_key, dst_key, msg)
+        match msg {
+            // Challenged nodes are not admitted yet, they may not fill up the secrets.
+            disco::Message::Challenge(_) => {
+                DiscoSecrets::encode_and_seal_uncached(&self.secret_key, dst_key, msg)
+            }
+            _ => self
+                .disco_secrets
+                .encode_and_seal(&self.secret_key, dst_key, msg),
+        }
     }
 
     fn send_ping_queued(&self, ping: SendPing) {
@@ -770,12 +1794,11 @@ impl Inner {
         let msg = disco::Message::Ping(disco::Ping {
             tx_id,
             node_key: self.public_key(),
+            home_relay: self.my_relay(),
+            cookie: None,
         });
         let sent = match dst {
-            SendAddr::Udp(addr) => self
-                .udp_disco_sender
-                .try_send((addr, dst_node, msg))
-                .is_ok(),
+            SendAddr::Udp(addr) => self.queue_disco_message_udp(addr, dst_node, msg),
             SendAddr::Relay(ref url) => self.send_disco_message_relay(url, dst_node, msg),
         };
         if sent {
@@ -784,7 +1807,7 @@ impl Inner {
             self.node_map
                 .notify_ping_sent(id, dst, tx_id, purpose, msg_sender);
         } else {
-            warn!(dst = ?dst, tx = %hex::encode(tx_id), ?purpose, "failed to send ping: queues full");
+            warn_limited!(dst = ?dst, tx = %hex::encode(tx_id), ?purpose, "failed to send ping: queues full");
         }
     }
 
@@ -799,6 +1822,8 @@ impl Inner {
         let msg = disco::Message::Ping(disco::Ping {
             tx_id: *tx_id,
             node_key: self.public_key(),
+            home_relay: self.my_relay(),
+            cookie: None,
         });
         ready!(self.poll_send_disco_message(dst.clone(), *dst_node, msg, cx))?;
         let msg_sender = self.actor_sender.clone();
@@ -822,11 +1847,28 @@ impl Inner {
         msg: disco::Message,
     ) -> bool {
         match dst {
-            SendAddr::Udp(addr) => self.udp_disco_sender.try_send((addr, dst_key, msg)).is_ok(),
+            SendAddr::Udp(addr) => self.queue_disco_message_udp(addr, dst_key, msg),
             SendAddr::Relay(ref url) => self.send_disco_message_relay(url, dst_key, msg),
         }
     }
 
+    /// Queues a disco message to be sent over UDP, returns false if the queue is full.
+    fn queue_disco_message_udp(
+        &self,
+        dst: SocketAddr,
+        dst_key: PublicKey,
+        msg: disco::Message,
+    ) -> bool {
+        match self.udp_disco_sender.try_send((dst, dst_key, msg)) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                inc!(MagicsockMetrics, udp_disco_dropped);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
     /// Send a disco message. UDP messages will be polled to send directly on the UDP socket.
     fn poll_send_disco_message(
         &self,
@@ -852,10 +1894,27 @@ impl Inner {
         dst_key: PublicKey,
         msg: disco::Message,
     ) -> bool {
+        if self.is_offline() {
+            trace!(node = %dst_key.fmt_short(), %url, %msg, "offline, not sending disco message");
+            return false;
+        }
         debug!(node = %dst_key.fmt_short(), %url, %msg, "send disco message (relay)");
         let pkt = self.encode_disco_message(dst_key, &msg);
         inc!(MagicsockMetrics, send_disco_relay);
-        match self.poll_send_relay(url, dst_key, smallvec![pkt]) {
+        let sent = match msg {
+            disco::Message::CallMeMaybe(_) => {
+                // Hole punching needs the call-me-maybe, so it may take another relay if
+                // the node's relay is unreachable.
+                let relay_msg = RelayActorMessage::SendCallMeMaybe {
+                    url: url.clone(),
+                    contents: smallvec![pkt],
+                    peer: dst_key,
+                };
+                self.poll_queue_relay_msg(url, dst_key, relay_msg)
+            }
+            _ => self.poll_send_relay(url, dst_key, smallvec![pkt]),
+        };
+        match sent {
             Poll::Ready(true) => {
                 inc!(MagicsockMetrics, sent_disco_relay);
                 disco_message_sent(&msg);
@@ -889,6 +1948,14 @@ impl Inner {
                 "connection closed",
             )));
         }
+        if self.is_offline() {
+            trace!(%dst, %msg, "offline, not sending disco message");
+            return Poll::Ready(Ok(false));
+        }
+        if self.udp_blocked() {
+            trace!(%dst, %msg, "UDP proxy unusable, not sending disco message");
+            return Poll::Ready(Ok(false));
+        }
         let pkt = self.encode_disco_message(dst_key, msg);
         // TODO: These metrics will be wrong with the poll impl
         // Also - do we need it? I'd say the `sent_disco_udp` below is enough.
@@ -904,7 +1971,7 @@ impl Inner {
         Poll::Ready(match sent {
             Ok(0) => {
                 // Can't send. (e.g. no IPv6 locally)
-                warn!(%dst, node = %dst_key.fmt_short(), ?msg, "failed to send disco message");
+                warn_limited!(%dst, node = %dst_key.fmt_short(), ?msg, "failed to send disco message");
                 Ok(false)
             }
             Ok(_n) => {
@@ -914,7 +1981,7 @@ impl Inner {
                 Ok(true)
             }
             Err(err) => {
-                warn!(%dst, node = %dst_key.fmt_short(), ?msg, ?err, "failed to send disco message");
+                warn_limited!(%dst, node = %dst_key.fmt_short(), ?msg, ?err, "failed to send disco message");
                 Err(err)
             }
         })
@@ -962,6 +2029,19 @@ impl Inner {
         Poll::Ready(Ok(()))
     }
 
+    /// Splits transmits into the packets sent through a relay, sealing them if configured.
+    fn relay_contents(&self, node: PublicKey, transmits: &[quinn_udp::Transmit]) -> RelayContents {
+        let contents = split_packets(transmits);
+        if !self.seal_relay_packets {
+            return contents;
+        }
+        let secret = self.disco_secrets.shared(&self.secret_key, node);
+        contents
+            .iter()
+            .map(|packet| relay_seal::seal(&**secret, packet))
+            .collect()
+    }
+
     fn poll_send_relay(
         &self,
         url: &RelayUrl,
@@ -974,17 +2054,31 @@ impl Inner {
             contents,
             peer: node,
         };
+        self.poll_queue_relay_msg(url, node, msg)
+    }
+
+    /// Queues a send message for the relay actor.
+    fn poll_queue_relay_msg(
+        &self,
+        url: &RelayUrl,
+        node: PublicKey,
+        msg: RelayActorMessage,
+    ) -> Poll<bool> {
         match self.relay_actor_sender.try_send(msg) {
             Ok(_) => {
                 trace!(node = %node.fmt_short(), relay_url = %url, "send relay: message queued");
                 Poll::Ready(true)
             }
             Err(mpsc::error::TrySendError::Closed(_)) => {
-                warn!(node = %node.fmt_short(), relay_url = %url, "send relay: message dropped, channel to actor is closed");
+                inc!(MagicsockMetrics, send_relay_error_closed);
+                warn_limited!(node = %node.fmt_short(), relay_url = %url, "send relay: message dropped, channel to actor is closed");
                 Poll::Ready(false)
             }
             Err(mpsc::error::TrySendError::Full(_)) => {
-                warn!(node = %node.fmt_short(), relay_url = %url, "send relay: message dropped, channel to actor is full");
+                // Prefer a direct path for a while, rather than piling onto the relay.
+                inc!(MagicsockMetrics, send_relay_error_queue);
+                self.node_map.notify_relay_congested(&node);
+                debug!(node = %node.fmt_short(), relay_url = %url, "send relay: channel to actor is full");
                 Poll::Pending
             }
         }
@@ -995,7 +2089,7 @@ impl Inner {
         let msg = disco::Message::CallMeMaybe(msg);
         for (public_key, url) in self.pending_call_me_maybes.lock().drain() {
             if !self.send_disco_message_relay(&url, public_key, msg.clone()) {
-                warn!(node = %public_key.fmt_short(), "relay channel full, dropping call-me-maybe");
+                warn_limited!(node = %public_key.fmt_short(), "relay channel full, dropping call-me-maybe");
             }
         }
     }
@@ -1006,12 +2100,22 @@ impl Inner {
             let msg = endpoints.to_call_me_maybe_message();
             let msg = disco::Message::CallMeMaybe(msg);
             if !self.send_disco_message_relay(url, dst_key, msg) {
-                warn!(dstkey = %dst_key.fmt_short(), relayurl = ?url,
+                warn_limited!(dstkey = %dst_key.fmt_short(), relayurl = ?url,
                       "relay channel full, dropping call-me-maybe");
             } else {
                 debug!(dstkey = %dst_key.fmt_short(), relayurl = ?url, "call-me-maybe sent");
             }
         } else {
+            if !endpoints.is_empty() {
+                // Most likely our endpoints did not change, so teach them to the node right
+                // away instead of holding up hole punching until the re-stun completed.  A
+                // fresh call-me-maybe follows once it did.
+                let msg = disco::Message::CallMeMaybe(endpoints.to_call_me_maybe_message());
+                if self.send_disco_message_relay(url, dst_key, msg) {
+                    debug!(dstkey = %dst_key.fmt_short(), relayurl = ?url,
+                           "call-me-maybe with stale endpoints sent");
+                }
+            }
             self.pending_call_me_maybes
                 .lock()
                 .insert(dst_key, url.clone());
@@ -1023,8 +2127,15 @@ impl Inner {
         }
     }
 
-    /// Triggers an address discovery. The provided why string is for debug logging only.
-    fn re_stun(&self, why: &'static str) {
+    /// Triggers an address discovery.
+    ///
+    /// The reason is logged and reported in the [`EndpointUpdate`].
+    fn re_stun(&self, why: impl Into<Cow<'static, str>>) {
+        let why = why.into();
+        if self.is_offline() {
+            debug!("re_stun: {}, skipped while offline", why);
+            return;
+        }
         debug!("re_stun: {}", why);
         inc!(MagicsockMetrics, re_stun_calls);
         self.endpoints_update_state.schedule_run(why);
@@ -1095,10 +2206,10 @@ impl DiscoMessageSource {
 #[derive(Debug)]
 struct EndpointUpdateState {
     /// If running, set to the reason for the currently the update.
-    running: sync::watch::Sender<Option<&'static str>>,
+    running: sync::watch::Sender<Option<Cow<'static, str>>>,
     /// If set, this means we will start a new endpoint update state as soon as the current one
     /// is finished.
-    want_update: parking_lot::Mutex<Option<&'static str>>,
+    want_update: parking_lot::Mutex<Option<Cow<'static, str>>>,
 }
 
 impl EndpointUpdateState {
@@ -1112,9 +2223,12 @@ impl EndpointUpdateState {
 
     /// Schedules a new run, either starting it immediately if none is running or
     /// scheduling it for later.
-    fn schedule_run(&self, why: &'static str) {
+    ///
+    /// Runs scheduled while one is already waiting are coalesced into it, keeping the reason
+    /// of the first one.
+    fn schedule_run(&self, why: Cow<'static, str>) {
         if self.is_running() {
-            let _ = self.want_update.lock().insert(why);
+            self.want_update.lock().get_or_insert(why);
         } else {
             self.run(why);
         }
@@ -1126,23 +2240,29 @@ impl EndpointUpdateState {
     }
 
     /// Trigger a new run.
-    fn run(&self, why: &'static str) {
-        self.running.send(Some(why)).ok();
+    ///
+    /// Also stored while the actor has not subscribed yet, so a run triggered right after
+    /// creating the socket is not lost.
+    fn run(&self, why: Cow<'static, str>) {
+        self.running.send_replace(Some(why));
     }
 
     /// Clears the current running state.
     fn finish_run(&self) {
-        self.running.send(None).ok();
+        self.running.send_replace(None);
     }
 
     /// Returns the next update, if one is set.
-    fn next_update(&self) -> Option<&'static str> {
+    fn next_update(&self) -> Option<Cow<'static, str>> {
         self.want_update.lock().take()
     }
 }
 
 impl MagicSock {
     /// Creates a magic `MagicSock` listening on `opts.port`.
+    ///
+    /// The background tasks of the socket are all spawned on [`Options::runtime`], or on
+    /// the tokio runtime which polls the returned future if that is not set.
     pub async fn new(opts: Options) -> Result<Self> {
         let me = opts.secret_key.public().fmt_short();
         if crate::util::relay_only_mode() {
@@ -1157,34 +2277,130 @@ impl MagicSock {
     }
 
     async fn with_name(me: String, opts: Options) -> Result<Self> {
-        let port_mapper = portmapper::Client::default();
-
         let Options {
             port,
             secret_key,
             relay_map,
             discovery,
             nodes_path,
+            storage,
             dns_resolver,
+            runtime,
+            first_packet_policy,
+            retry_ipv6_bind,
+            netcheck_sockets,
+            stun_servers,
+            metered_hint,
+            udp_proxy,
+            turn,
+            addr_filter,
+            path_tuning,
+            timeouts,
+            buffers,
+            ipv6,
+            port_hopping,
+            fwmark,
+            bind_device,
+            local_addrs,
+            seal_relay_packets,
+            challenge_unknown_senders,
+            disco_shards,
+            dedup_recv,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            rng_seed,
         } = opts;
+        ensure!(
+            udp_proxy.is_none() || turn.is_none(),
+            "a UDP proxy and a TURN server can not be used together"
+        );
+        buffers.validate()?;
+
+        // All tasks of this magicsock, including the ones spawned by the port mapper, the
+        // net checker and the network monitor, are spawned on this runtime.
+        let rt = match runtime {
+            Some(rt) => rt,
+            None => tokio::runtime::Handle::try_current().map_err(|_| {
+                anyhow!("MagicSock must be created inside a tokio runtime unless Options::runtime is set")
+            })?,
+        };
+        let port_mapper = {
+            let _guard = rt.enter();
+            portmapper::Client::default()
+        };
 
         let nodes_path = match nodes_path {
             Some(path) => {
                 let path = path.canonicalize().unwrap_or(path);
-                let parent = path.parent().ok_or_else(|| {
-                    anyhow::anyhow!("no parent directory found for '{}'", path.display())
-                })?;
-                tokio::fs::create_dir_all(&parent).await?;
+                let parent = path
+                    .parent()
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("no parent directory found for '{}'", path.display())
+                    })?
+                    .to_path_buf();
+                rt.spawn_blocking(move || std::fs::create_dir_all(parent))
+                    .await??;
                 Some(path)
             }
             None => None,
         };
 
-        let (relay_recv_sender, relay_recv_receiver) = flume::bounded(128);
+        let (relay_recv_sender, relay_recv_receiver) = flume::bounded(buffers.network_recv);
 
-        let (pconn4, pconn6) = bind(port)?;
+        let socket_options = SocketOptions {
+            fwmark,
+            bind_device,
+        };
+        let (pconn4, pconn6) = {
+            // Registering the sockets requires the runtime's IO driver.
+            let _guard = rt.enter();
+            bind(port, &socket_options)?
+        };
+        let (pconn4, pconn6, retry_ipv6_bind) = match udp_proxy {
+            None => (pconn4, pconn6, retry_ipv6_bind),
+            Some(ref config) => {
+                // All datagrams are sent from the IPv4 socket through the proxy.
+                let config = config.clone();
+                let association = rt
+                    .spawn(async move { socks5::Association::connect(&config).await })
+                    .await?
+                    .and_then(|association| {
+                        ensure!(
+                            association.relay_addr().is_ipv4(),
+                            "proxy relays UDP from an IPv6 address"
+                        );
+                        Ok(association)
+                    });
+                let pconn4 = match association {
+                    Ok(association) => pconn4.with_relay(association),
+                    Err(err) => {
+                        inc!(MagicsockMetrics, udp_proxy_unusable);
+                        warn!("SOCKS5 proxy is unusable for UDP, only using the relay: {err:#}");
+                        pconn4
+                    }
+                };
+                (pconn4, None, false)
+            }
+        };
+        let (pconn4, pconn6, retry_ipv6_bind) = match turn {
+            None => (pconn4, pconn6, retry_ipv6_bind),
+            Some(config) => {
+                let socket = pconn4.as_socket();
+                let allocation = rt
+                    .spawn(async move { turn::Allocation::allocate(socket, &config).await })
+                    .await?;
+                match allocation {
+                    // All datagrams are sent from the IPv4 socket through the allocation.
+                    Ok(allocation) => (pconn4.with_relay(allocation), None, false),
+                    Err(err) => {
+                        inc!(MagicsockMetrics, turn_unusable);
+                        warn!("TURN server is unusable, not using it: {err:#}");
+                        (pconn4, pconn6, retry_ipv6_bind)
+                    }
+                }
+            }
+        };
         let port = pconn4.port();
 
         // NOTE: we can end up with a zero port if `std::net::UdpSocket::socket_addr` fails
@@ -1197,85 +2413,171 @@ impl MagicSock {
         let ipv4_addr = pconn4.local_addr()?;
         let ipv6_addr = pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
-        let net_checker = netcheck::Client::new(Some(port_mapper.clone()), dns_resolver.clone())?;
+        let net_checker = {
+            let _guard = rt.enter();
+            netcheck::Client::new(Some(port_mapper.clone()), dns_resolver.clone())?
+        };
+        let has_stun_servers = !stun_servers.is_empty();
+        if has_stun_servers {
+            net_checker.set_stun_servers(stun_servers).await?;
+        }
 
-        let (actor_sender, actor_receiver) = mpsc::channel(256);
-        let (relay_actor_sender, relay_actor_receiver) = mpsc::channel(256);
-        let (udp_disco_sender, mut udp_disco_receiver) = mpsc::channel(256);
+        let (actor_sender, actor_receiver) = actor_queue::channel(buffers.actor);
+        let (relay_actor_sender, relay_actor_receiver) = mpsc::channel(buffers.relay_actor);
+        let (udp_disco_sender, mut udp_disco_receiver) = mpsc::channel(buffers.network_send);
+        let (disco_shards, disco_shard_receivers) = DiscoShards::new(disco_shards);
 
+        let node_map_shards = disco_shard_receivers.len();
         // load the node data
-        let node_map = match nodes_path.as_ref() {
-            Some(path) if path.exists() => match NodeMap::load_from_file(path) {
-                Ok(node_map) => {
-                    let count = node_map.node_count();
-                    debug!(count, "loaded node map");
-                    node_map
+        let node_map = match (nodes_path.as_ref(), storage.clone()) {
+            (Some(path), _) if path.exists() => {
+                match NodeMap::load_from_file(path, node_map_shards, addr_filter.as_deref()) {
+                    Ok(node_map) => {
+                        let count = node_map.node_count();
+                        debug!(count, "loaded node map");
+                        node_map
+                    }
+                    Err(e) => {
+                        debug!(%e, "failed to load node map: using default");
+                        NodeMap::new(node_map_shards)
+                    }
                 }
-                Err(e) => {
-                    debug!(%e, "failed to load node map: using default");
-                    NodeMap::default()
+            }
+            (None, Some(storage)) => {
+                let node_addrs: Option<Vec<NodeAddr>> = rt
+                    .spawn_blocking(move || storage::load(&*storage, NODES_KEY, NODES_VERSION))
+                    .await?;
+                let node_map = NodeMap::new(node_map_shards);
+                for mut node_addr in node_addrs.unwrap_or_default() {
+                    if let Some(filter) = &addr_filter {
+                        addr_filter::apply_to_node_addr(filter.as_ref(), &mut node_addr);
+                    }
+                    node_map.add_node_addr(node_addr);
                 }
-            },
-            _ => NodeMap::default(),
+                debug!(count = node_map.node_count(), "loaded node map");
+                node_map
+            }
+            _ => NodeMap::new(node_map_shards),
         };
+        node_map.set_metered(metered_hint);
+        node_map.set_path_tuning(path_tuning);
+        node_map.set_timeouts(timeouts);
+        let event_watchers = EventWatchers::default();
+        node_map.set_event_watchers(event_watchers.clone());
+        node_map.set_prefer_ipv4(ipv6.prefer == Ipv6Toggle::Off);
 
         let udp_state = quinn_udp::UdpState::default();
         let inner = Arc::new(Inner {
             me,
             port: AtomicU16::new(port),
             secret_key,
-            local_addrs: std::sync::RwLock::new((ipv4_addr, ipv6_addr)),
+            local_addrs: ArcSwap::from_pointee((ipv4_addr, ipv6_addr)),
             closing: AtomicBool::new(false),
             closed: AtomicBool::new(false),
+            offline: AtomicBool::new(false),
+            metered: AtomicBool::new(metered_hint),
+            udp_proxy: udp_proxy.is_some() || pconn4.relayed_addr().is_some(),
+            socks5_proxy: udp_proxy,
+            socket_options,
+            timeouts,
+            buffers,
+            shutdown_token: CancellationToken::new(),
             relay_recv_receiver,
+            injected_recv_sender: relay_recv_sender.clone(),
             network_recv_wakers: parking_lot::Mutex::new(None),
             network_send_wakers: parking_lot::Mutex::new(None),
             actor_sender: actor_sender.clone(),
             ipv6_reported: Arc::new(AtomicBool::new(false)),
             relay_map,
             my_relay: Default::default(),
+            home_relay_decision: Default::default(),
+            relay_latencies: Default::default(),
             pconn4: pconn4.clone(),
-            pconn6: pconn6.clone(),
+            pconn6: pconn6.map(OnceLock::from).unwrap_or_default(),
             net_checker: net_checker.clone(),
             disco_secrets: DiscoSecrets::default(),
+            seal_relay_packets,
+            admission: challenge_unknown_senders.then(Admission::new),
+            disco_shards,
+            recv_dedup: dedup_recv.then(Default::default),
+            recv_rotation: AtomicUsize::new(0),
             node_map,
             relay_actor_sender: relay_actor_sender.clone(),
             udp_state,
             send_buffer: Default::default(),
+            first_packet_policy,
+            staged_transmits: Default::default(),
+            routes: Default::default(),
+            presence: Default::default(),
+            #[cfg(feature = "net-conditioner")]
+            conditioner: Default::default(),
             udp_disco_sender,
             discovery,
+            addr_filter,
             endpoints: Watchable::new(Default::default()),
             pending_call_me_maybes: Default::default(),
             endpoints_update_state: EndpointUpdateState::new(),
+            event_watchers,
+            send_tap: Default::default(),
+            last_netcheck_report: Default::default(),
+            ipv6_config: parking_lot::Mutex::new(ipv6),
             dns_resolver,
+            rt: rt.clone(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
         });
 
         let mut actor_tasks = JoinSet::default();
+        DiscoShards::spawn(disco_shard_receivers, &inner, &mut actor_tasks, &rt);
 
         let relay_actor = RelayActor::new(inner.clone(), actor_sender.clone());
         let relay_actor_cancel_token = relay_actor.cancel_token();
-        actor_tasks.spawn(
+        actor_tasks.spawn_on(
             async move {
                 relay_actor.run(relay_actor_receiver).await;
             }
             .instrument(info_span!("relay-actor")),
+            &rt,
         );
 
         let inner2 = inner.clone();
-        actor_tasks.spawn(async move {
-            while let Some((dst, dst_key, msg)) = udp_disco_receiver.recv().await {
-                if let Err(err) = inner2.send_disco_message_udp(dst, dst_key, &msg).await {
-                    warn!(%dst, node = %dst_key.fmt_short(), ?err, "failed to send disco message (UDP)");
+        actor_tasks.spawn_on(
+            async move {
+                while let Some((dst, dst_key, msg)) = udp_disco_receiver.recv().await {
+                    set!(
+                        MagicsockMetrics,
+                        udp_disco_queue_len,
+                        udp_disco_receiver.len() as i64
+                    );
+                    let start = Instant::now();
+                    if let Err(err) = inner2.send_disco_message_udp(dst, dst_key, &msg).await {
+                        warn_limited!(%dst, node = %dst_key.fmt_short(), ?err, "failed to send disco message (UDP)");
+                    }
+                    observe!(
+                        MagicsockMetrics,
+                        udp_disco_send_duration,
+                        start.elapsed().as_secs_f64()
+                    );
                 }
-            }
-        });
+            },
+            &rt,
+        );
+
+        #[cfg(any(test, feature = "test-utils"))]
+        let mut rng = match rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        #[cfg(not(any(test, feature = "test-utils")))]
+        let mut rng = StdRng::from_entropy();
 
         let inner2 = inner.clone();
-        let network_monitor = netmon::Monitor::new().await?;
-        actor_tasks.spawn(
+        // The monitor spawns its own tasks, so create it on the magicsock runtime.
+        let network_monitor = rt.spawn(netmon::Monitor::new()).await??;
+        actor_tasks.spawn_on(
             async move {
+                // The interval needs the runtime's time driver.
+                let periodic_re_stun_timer = new_re_stun_timer(&mut rng, false);
                 let actor = Actor {
                     msg_receiver: actor_receiver,
                     msg_sender: actor_sender,
@@ -1283,14 +2585,31 @@ impl MagicSock {
                     relay_actor_cancel_token,
                     inner: inner2,
                     relay_recv_sender,
-                    periodic_re_stun_timer: new_re_stun_timer(false),
+                    periodic_re_stun_timer,
+                    rng,
+                    resume_detector: ResumeDetector::new(),
+                    endpoints_update_started: None,
+                    endpoints_push_pending: false,
+                    last_endpoints_push: None,
                     net_info_last: None,
                     nodes_path,
+                    storage,
                     port_mapper,
                     pconn4,
-                    pconn6,
+                    retry_ipv6_bind,
+                    port_hopping,
                     no_v4_send: false,
                     net_checker,
+                    netcheck_sockets,
+                    has_stun_servers,
+                    netcheck_runs: 0,
+                    netcheck_on_main_sockets: true,
+                    main_sockets_report: None,
+                    endpoints_report: None,
+                    portmap_changed: false,
+                    #[cfg(feature = "net-conditioner")]
+                    conditioned: Default::default(),
+                    local_addrs,
                     network_monitor,
                 };
 
@@ -1299,6 +2618,7 @@ impl MagicSock {
                 }
             }
             .instrument(info_span!("actor")),
+            &rt,
         );
 
         let c = MagicSock {
@@ -1309,6 +2629,9 @@ impl MagicSock {
         Ok(c)
     }
 
+    // After `close` the methods which act on the magic socket return a `ClosedError`, while
+    // the ones only reporting state keep returning the last known state.
+
     /// Retrieve connection information about nodes in the network.
     pub fn tracked_endpoints(&self) -> Vec<EndpointInfo> {
         self.inner.node_map.endpoint_infos(Instant::now())
@@ -1319,6 +2642,30 @@ impl MagicSock {
         self.inner.node_map.endpoint_info(&node_key)
     }
 
+    /// Pings a node on its relay path and on all its known direct paths.
+    ///
+    /// Returns the round trip time per path once all pongs arrived or timed out, after at
+    /// most five seconds.  Shows whether hole punching succeeded without reading the logs.
+    /// The pongs update the paths like any other, so a path answering fast may become the
+    /// one used.  Fails if the node is not known.
+    pub async fn ping(&self, node_id: PublicKey) -> Result<Vec<PathPing>> {
+        self.inner.ensure_open()?;
+        let (mut msgs, waiters) = self
+            .inner
+            .node_map
+            .ping_all_paths(&node_id)
+            .context("unknown node")?;
+        futures::future::poll_fn(|cx| self.inner.poll_handle_ping_actions(cx, &mut msgs)).await?;
+        let results = waiters.into_iter().map(|(path, waiter)| async move {
+            let latency = time::timeout(self.inner.timeouts.ping, waiter)
+                .await
+                .ok()
+                .and_then(Result::ok);
+            PathPing { path, latency }
+        });
+        Ok(futures::future::join_all(results).await)
+    }
+
     /// Returns the local endpoints as a stream.
     ///
     /// The [`MagicSock`] continuously monitors the local endpoints, the network addresses
@@ -1331,6 +2678,8 @@ impl MagicSock {
     /// stream will always return the first set of endpoints immediately, which are the most
     /// recently discovered endpoints.
     ///
+    /// The stream ends once the [`MagicSock`] is closed.
+    ///
     /// # Examples
     ///
     /// To get the current endpoints, drop the stream after the first item was received:
@@ -1363,124 +2712,522 @@ impl MagicSock {
     /// # Errors
     ///
     /// Will return an error if there is no address information known about the
-    /// given `node_id`.
+    /// given `node_id`, or a [`ClosedError`] if the [`MagicSock`] is closed.
     pub fn conn_type_stream(&self, node_id: &PublicKey) -> Result<node_map::ConnectionTypeStream> {
+        self.inner.ensure_open()?;
         self.inner.node_map.conn_type_stream(node_id)
     }
 
     /// Get the cached version of the Ipv4 and Ipv6 addrs of the current connection.
+    ///
+    /// Returns a [`ClosedError`] if the [`MagicSock`] is closed, as the sockets are no
+    /// longer bound.
     pub fn local_addr(&self) -> Result<(SocketAddr, Option<SocketAddr>)> {
+        self.inner.ensure_open()?;
         Ok(self.inner.local_addr())
     }
 
     /// Triggers an address discovery. The provided why string is for debug logging only.
+    ///
+    /// See [`Self::trigger_endpoint_update`], which also takes owned reasons.
     #[instrument(skip_all, fields(me = %self.inner.me))]
-    pub fn re_stun(&self, why: &'static str) {
-        self.inner.re_stun(why);
+    pub fn re_stun(&self, why: &'static str) -> Result<(), ClosedError> {
+        self.trigger_endpoint_update(why)
     }
 
-    /// Returns the [`SocketAddr`] which can be used by the QUIC layer to dial this node.
+    /// Triggers an endpoint update, re-discovering our addresses.
     ///
-    /// Note this is a user-facing API and does not wrap the [`SocketAddr`] in a
-    /// `QuicMappedAddr` as we do internally.
-    pub fn get_mapping_addr(&self, node_key: &PublicKey) -> Option<SocketAddr> {
-        self.inner
-            .node_map
-            .get_quic_mapped_addr_for_node_key(node_key)
-            .map(|a| a.0)
+    /// If an update is running already, another one is started once it finished.  Updates
+    /// triggered while one is waiting to start are merged into it, and only the first
+    /// reason is reported.  The `reason` is logged and reported in the [`EndpointUpdate`] of
+    /// [`Self::endpoint_updates`].  Skipped while offline, see [`Self::set_offline`].
+    #[instrument(skip_all, fields(me = %self.inner.me))]
+    pub fn trigger_endpoint_update(
+        &self,
+        reason: impl Into<Cow<'static, str>>,
+    ) -> Result<(), ClosedError> {
+        self.inner.ensure_open()?;
+        self.inner.re_stun(reason);
+        Ok(())
     }
 
-    /// Returns the relay node with the best latency.
+    /// Returns a stream of the endpoint updates finished from now on.
     ///
-    /// If `None`, then we currently have no verified connection to a relay node.
-    pub fn my_relay(&self) -> Option<RelayUrl> {
-        self.inner.my_relay()
+    /// Each [`EndpointUpdate`] reports why the update was started, how long it took and the
+    /// endpoints it found, whether it was started by the socket or with
+    /// [`Self::trigger_endpoint_update`].  The stream ends when the socket is closed, and
+    /// yields [`Lagged`] if read too slowly, like [`Self::subscribe`].
+    pub fn endpoint_updates(&self) -> EndpointUpdateStream {
+        EndpointUpdateStream::new(self.inner.event_watchers.subscribe())
     }
 
-    #[instrument(skip_all, fields(me = %self.inner.me))]
-    /// Add addresses for a node to the magic socket's addresbook.
-    pub fn add_node_addr(&self, addr: NodeAddr) {
-        self.inner.node_map.add_node_addr(addr);
+    /// Returns a stream of the state changes of the socket from now on.
+    ///
+    /// Reports nodes being added and removed, the paths to nodes switching from a relay to
+    /// a direct address, changes of our home relay, network and endpoints, and the presence
+    /// of watched nodes, see [`MagicSockEvent`].  Up to [`EVENT_CHANNEL_CAPACITY`] events are
+    /// buffered, a stream read too slowly misses the oldest ones and yields [`Lagged`]
+    /// instead.  The stream ends when the socket is closed.
+    ///
+    /// [`EVENT_CHANNEL_CAPACITY`]: events::EVENT_CHANNEL_CAPACITY
+    pub fn subscribe(&self) -> MagicSockEventStream {
+        self.inner.event_watchers.subscribe()
     }
 
-    /// Get a reference to the DNS resolver used in this [`MagicSock`].
-    pub fn dns_resolver(&self) -> &DnsResolver {
-        &self.inner.dns_resolver
+    /// Handles a packet the application received on behalf of the socket.
+    ///
+    /// For integrations which carry packets over transports the socket does not know about,
+    /// see [`NodeOrAddr`] for how the packet is attributed.  Packets to send over such a
+    /// transport are taken from the [`Self::send_tap`].
+    pub fn inject_recv(&self, from: NodeOrAddr, packet: Bytes) -> Result<(), InjectError> {
+        self.inner.inject_recv(from, packet)
     }
 
-    /// Closes the connection.
+    /// Installs a tap which receives every QUIC datagram sent to a node, replacing any
+    /// previous one.
     ///
-    /// Only the first close does anything. Any later closes return nil.
-    #[instrument(skip_all, fields(me = %self.inner.me))]
-    pub async fn close(&self) -> Result<()> {
-        if self.inner.is_closed() {
-            return Ok(());
-        }
-        self.inner.closing.store(true, Ordering::Relaxed);
-        self.inner.actor_sender.send(ActorMessage::Shutdown).await?;
-        self.inner.closed.store(true, Ordering::SeqCst);
-        self.inner.endpoints.shutdown();
-
-        let mut tasks = self.actor_tasks.lock().await;
-
-        // give the tasks a moment to shutdown cleanly
-        let tasks_ref = &mut tasks;
-        let shutdown_done = time::timeout(Duration::from_millis(100), async move {
-            while let Some(task) = tasks_ref.join_next().await {
-                if let Err(err) = task {
-                    warn!("unexpected error in task shutdown: {:?}", err);
-                }
-            }
-        })
-        .await;
-        if shutdown_done.is_ok() {
-            debug!("tasks shutdown complete");
-        } else {
-            // shutdown all tasks
-            debug!("aborting remaining {}/3 tasks", tasks.len());
-            tasks.shutdown().await;
-        }
-
-        Ok(())
+    /// The datagrams are still sent on the UDP and relay paths as well, if the node has any.
+    /// Without those, sending succeeds with only the tap.  The tap is removed when the
+    /// returned stream is dropped and ends when the socket is closed.
+    pub fn send_tap(&self) -> SendTap {
+        self.inner.send_tap.install()
     }
 
-    /// Reference to optional discovery service
-    pub fn discovery(&self) -> Option<&dyn Discovery> {
-        self.inner.discovery.as_ref().map(Box::as_ref)
+    /// Suspends or resumes all network activity.
+    ///
+    /// While offline no heartbeats, STUN probes or relay connections are made and sent data
+    /// is dropped, but all state about the nodes is kept.  Going online again reconnects to
+    /// the home relay and re-checks the network, like after a major network change.
+    ///
+    /// Useful to honour the operating system's airplane mode or metered connection
+    /// signals without closing the socket.
+    pub async fn set_offline(&self, offline: bool) -> Result<(), ClosedError> {
+        self.inner.ensure_open()?;
+        self.inner
+            .actor_sender
+            .send(ActorMessage::SetOffline(offline))
+            .await
+            .map_err(|_| ClosedError)
     }
 
-    /// Call to notify the system of potential network changes.
-    pub async fn network_change(&self) {
+    /// Prepares for the operating system going to sleep.
+    ///
+    /// Goes offline like [`Self::set_offline`] and saves the known nodes, if configured.
+    /// Returns once this is done, so it can be awaited from the operating system's
+    /// notification before the system sleeps.
+    pub async fn suspend(&self) -> Result<(), ClosedError> {
+        self.inner.ensure_open()?;
+        let (s, r) = sync::oneshot::channel();
         self.inner
             .actor_sender
-            .send(ActorMessage::NetworkChange)
+            .send(ActorMessage::Suspend(s))
             .await
-            .ok();
+            .map_err(|_| ClosedError)?;
+        r.await.map_err(|_| ClosedError)
     }
 
-    #[cfg(test)]
-    async fn force_network_change(&self, is_major: bool) {
+    /// Recovers the connectivity after the operating system woke up.
+    ///
+    /// Goes online again if [`Self::suspend`] or [`Self::set_offline`] were used, and handles
+    /// the wake up as a major network change: retries binding the IPv6 socket, closes relay
+    /// connections from addresses which are gone, reconnects to the home relay and re-checks
+    /// the network.  All paths are distrusted, and every active node is pinged on all its
+    /// paths and sent a call-me-maybe at once, instead of waiting for the paths to time out.
+    ///
+    /// Without calling this, a resume is only noticed by the next heartbeat.
+    pub async fn resume(&self) -> Result<(), ClosedError> {
+        self.inner.ensure_open()?;
+        let (s, r) = sync::oneshot::channel();
         self.inner
             .actor_sender
-            .send(ActorMessage::ForceNetworkChange(is_major))
+            .send(ActorMessage::Resume(s))
             .await
-            .ok();
+            .map_err(|_| ClosedError)?;
+        r.await.map_err(|_| ClosedError)
     }
-}
 
-/// Stream returning local endpoints of a [`MagicSock`] as they change.
-#[derive(Debug)]
-pub struct LocalEndpointsStream {
-    initial: Option<DiscoveredEndpoints>,
-    inner: watchable::WatcherStream<DiscoveredEndpoints>,
-}
+    /// Sets whether the network is metered, e.g. a cellular connection.
+    ///
+    /// On a metered network optional traffic is reduced: heartbeats to keep paths alive are
+    /// sent less often and hole punching is retried less aggressively.  Data sent while
+    /// metered is also counted in the `send_data_metered` metric.
+    pub fn set_metered(&self, metered: bool) {
+        if self.inner.metered.swap(metered, Ordering::Relaxed) != metered {
+            info!(metered, "metered network hint changed");
+            self.inner.node_map.set_metered(metered);
+        }
+    }
 
-impl Stream for LocalEndpointsStream {
-    type Item = Vec<config::Endpoint>;
+    /// Simulates bad network conditions for the data sent to `node`, or clears them.
+    ///
+    /// Each packet of the QUIC connections to `node` is delayed or dropped as described by
+    /// the [`LinkConditions`], on whichever path it is sent.  Disco messages, which drive
+    /// hole punching, are not affected.  Takes effect immediately, and can be changed at any
+    /// time.
+    ///
+    /// Only available with the `net-conditioner` feature, for testing.
+    #[cfg(feature = "net-conditioner")]
+    pub fn set_link_conditions(&self, node: PublicKey, conditions: Option<LinkConditions>) {
+        info!(node = %node.fmt_short(), ?conditions, "simulated link conditions changed");
+        self.inner.conditioner.set(node, conditions);
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = &mut *self;
-        if let Some(initial_endpoints) = this.initial.take() {
+    /// Changes how IPv6 is used, without rebinding the socket.
+    ///
+    /// With [`Ipv6Config::advertise`] off our IPv6 endpoints are withdrawn, the socket keeps
+    /// receiving on IPv6 from nodes which still know them.  With [`Ipv6Config::prefer`] off
+    /// IPv4 paths are chosen over IPv6 paths of similar latency.  Toggles set to
+    /// [`Ipv6Toggle::Auto`] follow whether the last netcheck found IPv6 to work.
+    pub fn set_ipv6_config(&self, config: Ipv6Config) {
+        let old = std::mem::replace(&mut *self.inner.ipv6_config.lock(), config);
+        if old == config {
+            return;
+        }
+        info!(?config, "IPv6 config changed");
+        self.inner.update_prefer_ipv6();
+        if old.advertise != config.advertise {
+            self.inner.re_stun("ipv6-config-changed");
+        }
+    }
+
+    /// How IPv6 is used, see [`MagicSock::set_ipv6_config`].
+    pub fn ipv6_config(&self) -> Ipv6Config {
+        *self.inner.ipv6_config.lock()
+    }
+
+    /// Outcomes of recent probes to the direct paths of all nodes, per IP family.
+    ///
+    /// Only global addresses are counted.  Once IPv6 probes fail much more often than IPv4
+    /// probes, IPv4 paths are probed first and preferred, also for new nodes.  The outcomes
+    /// for a single node are in [`EndpointInfo::family_stats`].
+    pub fn family_stats(&self) -> FamilyStats {
+        self.inner.node_map.family_stats()
+    }
+
+    /// Watches the progress of the netcheck reports, see [`netcheck::ReportProgress`].
+    ///
+    /// On first startup finding the nearest relay takes a moment, this allows showing how
+    /// it progresses.
+    pub fn netcheck_progress(&self) -> sync::watch::Receiver<Option<netcheck::ReportProgress>> {
+        self.inner.net_checker.watch_progress()
+    }
+
+    /// Whether the network is considered metered, see [`MagicSock::set_metered`].
+    pub fn is_metered(&self) -> bool {
+        self.inner.is_metered()
+    }
+
+    /// Whether the socket is open and not suspended with [`MagicSock::set_offline`].
+    pub fn is_online(&self) -> bool {
+        !self.inner.is_closed() && !self.inner.is_offline()
+    }
+
+    /// Returns the [`SocketAddr`] which can be used by the QUIC layer to dial this node.
+    ///
+    /// Note this is a user-facing API and does not wrap the [`SocketAddr`] in a
+    /// `QuicMappedAddr` as we do internally.
+    pub fn get_mapping_addr(&self, node_key: &PublicKey) -> Option<SocketAddr> {
+        self.inner
+            .node_map
+            .get_quic_mapped_addr_for_node_key(node_key)
+            .map(|a| a.0)
+    }
+
+    /// Returns the relay node with the best latency.
+    ///
+    /// If `None`, then we currently have no verified connection to a relay node.
+    pub fn my_relay(&self) -> Option<RelayUrl> {
+        self.inner.my_relay()
+    }
+
+    /// Returns the current latency estimates for each relay server.
+    ///
+    /// The estimates are moving averages of the STUN latencies of the netcheck reports and,
+    /// separately, of the round trip times of the periodic pings on the open relay
+    /// connections, which are updated more often than netcheck runs.  Relays for which no
+    /// latency was measured yet are not included.
+    pub fn relay_latencies(&self) -> BTreeMap<RelayUrl, RelayLatency> {
+        self.inner.relay_latencies.latencies()
+    }
+
+    /// Returns why the current home relay was chosen.
+    ///
+    /// `None` if there is no home relay.
+    pub fn home_relay_decision(&self) -> Option<HomeRelayDecision> {
+        self.inner.home_relay_decision.lock().clone()
+    }
+
+    /// Returns the maximum number of GSO segments a single transmit may contain.
+    ///
+    /// This is a best-effort value which holds for all paths to a node, so it does not
+    /// change when a connection switches between a direct and a relayed path.  It is 1 if
+    /// the platform does not support GSO.
+    pub fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    #[instrument(skip_all, fields(me = %self.inner.me))]
+    /// Add addresses for a node to the magic socket's addresbook.
+    pub fn add_node_addr(&self, mut addr: NodeAddr) -> Result<(), ClosedError> {
+        self.inner.ensure_open()?;
+        let node_id = addr.node_id;
+        if let Some(filter) = &self.inner.addr_filter {
+            addr_filter::apply_to_node_addr(filter.as_ref(), &mut addr);
+        }
+        self.inner.node_map.add_node_addr(addr);
+        self.inner.flush_staged_transmits(&node_id);
+        Ok(())
+    }
+
+    /// Sets whether QUIC packets to `node` may be sent through relay servers.
+    ///
+    /// For traffic which must never pass through third-party relays, see [`RelayPolicy`].
+    /// The policy also applies if the node is not known yet.  While the policy withholds the
+    /// relay and no direct path to the node is known, sending to it fails, so its
+    /// connections fail or time out, and [`MagicSock::conn_type_stream`] reports
+    /// [`ConnectionType::None`].
+    pub fn set_node_relay_policy(&self, node: PublicKey, policy: RelayPolicy) {
+        self.inner.node_map.set_relay_policy(node, policy);
+    }
+
+    /// Forgets everything about a node, returning whether it was known.
+    ///
+    /// This releases the address the QUIC layer uses for the node.  Connections to the node
+    /// stop working, so only remove nodes without connections.  Adding the node again later
+    /// allocates a new address.
+    pub fn remove_node(&self, node_id: &PublicKey) -> bool {
+        let Some(mapped_addr) = self.inner.node_map.remove_node(node_id) else {
+            return false;
+        };
+        debug!(node = %node_id.fmt_short(), %mapped_addr, "removed node");
+        if let Some(staged) = self.inner.staged_transmits.lock().remove(&mapped_addr) {
+            inc_by!(
+                MagicsockMetrics,
+                send_data_staged_dropped,
+                staged.len() as u64
+            );
+        }
+        self.inner.pending_call_me_maybes.lock().remove(node_id);
+        true
+    }
+
+    /// Replaces the routes of virtual IP addresses to nodes.
+    ///
+    /// The routes are not used by the socket itself.  They are for a layer on top which
+    /// tunnels IP packets, e.g. from a TUN device, to find the node for each packet with
+    /// [`MagicSock::route_lookup`].
+    pub fn set_routes(&self, routes: RouteTable) {
+        debug!(count = routes.len(), "routes updated");
+        *self.inner.routes.write() = routes;
+    }
+
+    /// The current routes, see [`MagicSock::set_routes`].
+    pub fn routes(&self) -> RouteTable {
+        self.inner.routes.read().clone()
+    }
+
+    /// Returns the node the virtual IP address `ip` is routed to.
+    pub fn route_lookup(&self, ip: IpAddr) -> Option<PublicKey> {
+        self.inner.routes.read().lookup(ip)
+    }
+
+    /// Watches whether `nodes` are connected to our home relay server.
+    ///
+    /// The stream reports the current presence of each of the nodes first, and then every
+    /// time one of them connects to or disconnects from the home relay.  Nodes connected to
+    /// other relay servers are reported as offline.  Events may repeat the current presence,
+    /// e.g. when the home relay changes or other nodes are watched.
+    ///
+    /// The relay server only reports nodes which watch this node as well, or which sent it a
+    /// packet over the relay, so that nodes can not be probed by strangers.  Other nodes are
+    /// not reported at all.  Home relay servers which do not announce support for presence
+    /// are not asked, and report nothing.  At most [`MAX_WATCHED_PEERS`] nodes can be watched
+    /// altogether.
+    pub async fn watch_presence(
+        &self,
+        nodes: impl IntoIterator<Item = PublicKey>,
+    ) -> Result<PresenceStream> {
+        self.inner.ensure_open()?;
+        let stream = self
+            .inner
+            .presence
+            .add(
+                nodes.into_iter().collect(),
+                MAX_WATCHED_PEERS,
+                self.inner.event_watchers.subscribe(),
+            )
+            .map_err(|count| {
+                anyhow!("watching {count} nodes, more than the maximum of {MAX_WATCHED_PEERS}")
+            })?;
+        let nodes = self.inner.presence.nodes();
+        self.inner
+            .relay_actor_sender
+            .send(RelayActorMessage::WatchPresence(nodes))
+            .await
+            .map_err(|_| ClosedError)?;
+        Ok(stream)
+    }
+
+    /// Number of addresses allocated for the QUIC layer to address nodes, one per known node.
+    ///
+    /// Addresses are released when nodes are pruned after a period of inactivity or removed
+    /// with [`MagicSock::remove_node`].
+    pub fn mapped_addr_count(&self) -> usize {
+        self.inner.node_map.mapped_addr_count()
+    }
+
+    /// Captures the connectivity state, to attach to bug reports.
+    ///
+    /// With `redact` set, IP addresses and node ids are left out, see [`StateDump`].
+    pub fn dump_state(&self, redact: bool) -> StateDump {
+        let inner = &self.inner;
+        let redactor = Redactor { redact };
+        let (v4, v6) = **inner.local_addrs.load();
+        let relay_latencies = inner.relay_latencies.latencies();
+        StateDump {
+            taken_at_unix_ms: state_dump::unix_ms(SystemTime::now()),
+            redacted: redact,
+            node_id: (!redact).then(|| inner.public_key().to_string()),
+            online: !inner.is_offline(),
+            metered: inner.is_metered(),
+            local_addrs: std::iter::once(v4)
+                .chain(v6)
+                .map(|addr| redactor.addr(addr))
+                .collect(),
+            endpoints: inner
+                .endpoints
+                .read()
+                .iter()
+                .map(|ep| redactor.endpoint(ep))
+                .collect(),
+            relay: RelayDump {
+                home: inner.my_relay().map(|url| url.to_string()),
+                reason: inner
+                    .home_relay_decision
+                    .lock()
+                    .as_ref()
+                    .map(|decision| decision.reason.to_string()),
+                latencies_ms: relay_latencies
+                    .iter()
+                    .filter_map(|(url, latency)| {
+                        Some((url.to_string(), state_dump::ms(latency.stun?)))
+                    })
+                    .collect(),
+                rtts_ms: relay_latencies
+                    .iter()
+                    .filter_map(|(url, latency)| {
+                        Some((url.to_string(), state_dump::ms(latency.rtt?)))
+                    })
+                    .collect(),
+            },
+            netcheck: inner
+                .last_netcheck_report
+                .load()
+                .as_deref()
+                .map(|report| redactor.netcheck(report)),
+            nodes: inner
+                .node_map
+                .endpoint_infos(Instant::now())
+                .into_iter()
+                .map(|info| redactor.node(info))
+                .collect(),
+            events: inner.event_watchers.recent(redactor),
+        }
+    }
+
+    /// Get a reference to the DNS resolver used in this [`MagicSock`].
+    pub fn dns_resolver(&self) -> &DnsResolver {
+        &self.inner.dns_resolver
+    }
+
+    /// Closes the connection.
+    ///
+    /// Only the first close does anything. Any later closes return nil.
+    #[instrument(skip_all, fields(me = %self.inner.me))]
+    pub async fn close(&self) -> Result<()> {
+        if self.inner.is_closed() {
+            return Ok(());
+        }
+        // Say goodbye while the sockets and the relay actor are still running.
+        time::timeout(GOODBYE_TIMEOUT, self.inner.send_goodbyes())
+            .await
+            .ok();
+        self.inner.closing.store(true, Ordering::Relaxed);
+        self.inner.shutdown_token.cancel();
+        self.inner.closed.store(true, Ordering::SeqCst);
+        self.inner.endpoints.shutdown();
+
+        let mut tasks = self.actor_tasks.lock().await;
+
+        // give the tasks a moment to shutdown cleanly
+        let tasks_ref = &mut tasks;
+        let shutdown_done = time::timeout(Duration::from_millis(100), async move {
+            while let Some(task) = tasks_ref.join_next().await {
+                if let Err(err) = task {
+                    warn!("unexpected error in task shutdown: {:?}", err);
+                }
+            }
+        })
+        .await;
+        if shutdown_done.is_ok() {
+            debug!("tasks shutdown complete");
+        } else {
+            // shutdown all tasks
+            debug!("aborting remaining {}/3 tasks", tasks.len());
+            tasks.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    /// Reference to optional discovery service
+    pub fn discovery(&self) -> Option<&dyn Discovery> {
+        self.inner.discovery.as_ref().map(Box::as_ref)
+    }
+
+    /// Call to notify the system of potential network changes.
+    pub async fn network_change(&self) -> Result<(), ClosedError> {
+        self.inner.ensure_open()?;
+        self.inner
+            .actor_sender
+            .send(ActorMessage::NetworkChange)
+            .await
+            .map_err(|_| ClosedError)
+    }
+
+    #[cfg(test)]
+    async fn force_network_change(&self, is_major: bool) {
+        self.inner
+            .actor_sender
+            .send(ActorMessage::ForceNetworkChange(is_major))
+            .await
+            .ok();
+    }
+}
+
+/// Checks a received QUIC packet with the [`PacketDedup`], counting it in the metrics.
+fn is_duplicate(dedup: &PacketDedup, packet: &[u8], now: Instant) -> bool {
+    inc!(MagicsockMetrics, recv_dedup_checked);
+    let duplicate = dedup.is_duplicate(packet, now);
+    if duplicate {
+        inc!(MagicsockMetrics, recv_dedup_dropped);
+    }
+    duplicate
+}
+
+/// Stream returning local endpoints of a [`MagicSock`] as they change.
+#[derive(Debug)]
+pub struct LocalEndpointsStream {
+    initial: Option<DiscoveredEndpoints>,
+    inner: watchable::WatcherStream<DiscoveredEndpoints>,
+}
+
+impl Stream for LocalEndpointsStream {
+    type Item = Vec<config::Endpoint>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(initial_endpoints) = this.initial.take() {
             if !initial_endpoints.is_empty() {
                 return Poll::Ready(Some(initial_endpoints.into_iter().collect()));
             }
@@ -1508,55 +3255,194 @@ impl Stream for LocalEndpointsStream {
     }
 }
 
-#[derive(Debug, Default)]
-struct DiscoSecrets(parking_lot::Mutex<HashMap<PublicKey, SharedSecret>>);
+/// The [`MagicSock`] was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("magic socket is closed")]
+pub struct ClosedError;
 
-impl DiscoSecrets {
-    fn get(
-        &self,
-        secret: &SecretKey,
-        node_id: PublicKey,
-    ) -> parking_lot::MappedMutexGuard<SharedSecret> {
-        parking_lot::MutexGuard::map(self.0.lock(), |inner| {
-            inner
-                .entry(node_id)
-                .or_insert_with(|| secret.shared(&node_id))
-        })
+type RelayRecvResult = Result<(PublicKey, quinn_udp::RecvMeta, Bytes), io::Error>;
+
+/// Source of the external address obtained by port mapping.
+///
+/// Implemented by [`portmapper::Client`], and by fakes in tests so the endpoint
+/// determination can be exercised without a router.
+trait PortMapper {
+    /// The most recent external address obtained by port mapping, if any.
+    fn external_address(&self) -> Option<SocketAddrV4>;
+}
+
+impl PortMapper for portmapper::Client {
+    fn external_address(&self) -> Option<SocketAddrV4> {
+        *self.watch_external_address().borrow()
+    }
+}
+
+/// Determines our endpoints, in the order they are advertised to other nodes.
+///
+/// Combines the port mapping, the netcheck report `nr`, the local addresses of our sockets
+/// and the machine's interface addresses `local_ips`.  `port` is the port the user asked
+/// to bind, zero if any.
+fn determine_endpoints(
+    port_mapper: &impl PortMapper,
+    nr: Option<&netcheck::Report>,
+    local_addr_v4: Option<SocketAddr>,
+    local_addr_v6: Option<SocketAddr>,
+    local_ips: LocalAddresses,
+    port: u16,
+) -> Vec<config::Endpoint> {
+    // endpoint -> how it was found
+    let mut already = HashMap::new();
+    // unique endpoints
+    let mut eps = Vec::new();
+
+    macro_rules! add_addr {
+        ($already:expr, $eps:expr, $ipp:expr, $et:expr) => {
+            #[allow(clippy::map_entry)]
+            if !$already.contains_key(&$ipp) {
+                $already.insert($ipp, $et);
+                $eps.push(config::Endpoint {
+                    addr: $ipp,
+                    typ: $et,
+                });
+            }
+        };
     }
 
-    pub fn encode_and_seal(
-        &self,
-        secret_key: &SecretKey,
-        node_id: PublicKey,
-        msg: &disco::Message,
-    ) -> Bytes {
-        let mut seal = msg.as_bytes();
-        self.get(secret_key, node_id).seal(&mut seal);
-        disco::encode_message(&secret_key.public(), seal).into()
+    if let Some(portmap_ext) = port_mapper.external_address().map(SocketAddr::V4) {
+        add_addr!(already, eps, portmap_ext, config::EndpointType::Portmapped);
     }
 
-    pub fn unseal_and_decode(
-        &self,
-        secret: &SecretKey,
-        node_id: PublicKey,
-        mut sealed_box: Vec<u8>,
-    ) -> Result<disco::Message, DiscoBoxError> {
-        self.get(secret, node_id)
-            .open(&mut sealed_box)
-            .map_err(DiscoBoxError::Open)?;
-        disco::Message::from_bytes(&sealed_box).map_err(DiscoBoxError::Parse)
+    if let Some(nr) = nr {
+        if let Some(global_v4) = nr.global_v4 {
+            add_addr!(already, eps, global_v4.into(), config::EndpointType::Stun);
+
+            // If they're behind a hard NAT and are using a fixed
+            // port locally, assume they might've added a static
+            // port mapping on their router to the same explicit
+            // port that we are running with. Worst case it's an invalid candidate mapping.
+            if nr.mapping_varies_by_dest_ip.unwrap_or_default() && port != 0 {
+                let mut addr = global_v4;
+                addr.set_port(port);
+                add_addr!(
+                    already,
+                    eps,
+                    addr.into(),
+                    config::EndpointType::Stun4LocalPort
+                );
+            }
+        }
+        if let Some(global_v6) = nr.global_v6 {
+            add_addr!(already, eps, global_v6.into(), config::EndpointType::Stun);
+        }
+    }
+    let is_unspecified_v4 = local_addr_v4
+        .map(|a| a.ip().is_unspecified())
+        .unwrap_or(false);
+    let is_unspecified_v6 = local_addr_v6
+        .map(|a| a.ip().is_unspecified())
+        .unwrap_or(false);
+
+    let LocalAddresses {
+        regular: mut ips,
+        loopback,
+    } = local_ips;
+
+    if is_unspecified_v4 || is_unspecified_v6 {
+        if ips.is_empty() && eps.is_empty() {
+            // Only include loopback addresses if we have no
+            // interfaces at all to use as endpoints and don't
+            // have a public IPv4 or IPv6 address. This allows
+            // for localhost testing when you're on a plane and
+            // offline, for example.
+            ips = loopback;
+        }
+        let v4_port = local_addr_v4.and_then(|addr| {
+            if addr.ip().is_unspecified() {
+                Some(addr.port())
+            } else {
+                None
+            }
+        });
+
+        let v6_port = local_addr_v6.and_then(|addr| {
+            if addr.ip().is_unspecified() {
+                Some(addr.port())
+            } else {
+                None
+            }
+        });
+
+        for ip in ips {
+            match ip {
+                IpAddr::V4(_) => {
+                    if let Some(port) = v4_port {
+                        add_addr!(
+                            already,
+                            eps,
+                            SocketAddr::new(ip, port),
+                            config::EndpointType::Local
+                        );
+                    }
+                }
+                IpAddr::V6(_) => {
+                    if let Some(port) = v6_port {
+                        add_addr!(
+                            already,
+                            eps,
+                            SocketAddr::new(ip, port),
+                            config::EndpointType::Local
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    if !is_unspecified_v4 {
+        if let Some(addr) = local_addr_v4 {
+            // Our local endpoint is bound to a particular address.
+            // Do not offer addresses on other local interfaces.
+            add_addr!(already, eps, addr, config::EndpointType::Local);
+        }
+    }
+
+    if !is_unspecified_v6 {
+        if let Some(addr) = local_addr_v6 {
+            // Our local endpoint is bound to a particular address.
+            // Do not offer addresses on other local interfaces.
+            add_addr!(already, eps, addr, config::EndpointType::Local);
+        }
     }
-}
 
-#[derive(Debug, thiserror::Error)]
-enum DiscoBoxError {
-    #[error("Failed to open crypto box")]
-    Open(anyhow::Error),
-    #[error("Failed to parse disco message")]
-    Parse(anyhow::Error),
+    // Note: the endpoints are intentionally returned in priority order,
+    // from "farthest but most reliable" to "closest but least
+    // reliable." Addresses returned from STUN should be globally
+    // addressable, but might go farther on the network than necessary.
+    // Local interface addresses might have lower latency, but not be
+    // globally addressable.
+    //
+    // The STUN address(es) are first, unless our NAT does not support hairpinning: then
+    // nodes behind the same NAT can not reach us on the STUN IPv4 address and should
+    // try the local addresses first.
+    // Despite this sorting, clients are not relying on this sorting for decisions;
+    if nr.and_then(|nr| nr.hair_pinning) == Some(false) {
+        demote_reflexive_v4_endpoints(&mut eps);
+    }
+    eps
 }
 
-type RelayRecvResult = Result<(PublicKey, quinn_udp::RecvMeta, Bytes), io::Error>;
+/// Moves the IPv4 endpoints discovered by STUN after all other endpoints.
+///
+/// The relative order of the endpoints is otherwise kept.
+fn demote_reflexive_v4_endpoints(eps: &mut [config::Endpoint]) {
+    eps.sort_by_key(|ep| {
+        ep.addr.is_ipv4()
+            && matches!(
+                ep.typ,
+                config::EndpointType::Stun | config::EndpointType::Stun4LocalPort
+            )
+    });
+}
 
 /// Reports whether x and y represent the same set of endpoints. The order doesn't matter.
 fn endpoint_sets_equal(xs: &[config::Endpoint], ys: &[config::Endpoint]) -> bool {
@@ -1605,8 +3491,12 @@ impl AsyncUdpSocket for MagicSock {
         self.inner.poll_recv(cx, bufs, metas)
     }
 
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        match &*self.inner.local_addrs.read().expect("not poisoned") {
+        match **self.inner.local_addrs.load() {
             (ipv4, None) => {
                 // Pretend to be IPv6, because our QuinnMappedAddrs
                 // need to be IPv6.
@@ -1616,40 +3506,95 @@ impl AsyncUdpSocket for MagicSock {
                 };
                 Ok(SocketAddr::new(ip, ipv4.port()))
             }
-            (_, Some(ipv6)) => Ok(*ipv6),
+            (_, Some(ipv6)) => Ok(ipv6),
         }
     }
 }
 
 #[derive(Debug)]
 enum ActorMessage {
-    Shutdown,
     ReceiveRelay(RelayReadResult),
     EndpointPingExpired(usize, stun::TransactionId),
-    NetcheckReport(Result<Option<Arc<netcheck::Report>>>, &'static str),
+    NetcheckReport(Result<Option<Arc<netcheck::Report>>>, Cow<'static, str>),
     NetworkChange,
+    FlushStagedTransmits(QuicMappedAddr),
+    /// Send a transmit held back by the simulated network conditions once it is due.
+    #[cfg(feature = "net-conditioner")]
+    SendConditioned(QuicMappedAddr, quinn_udp::Transmit, Instant),
+    /// A latency sample measured on an active relay connection.
+    RelayLatency(RelayUrl, Duration),
+    /// The relay server reported the node as disconnected.
+    RelayPeerGone(RelayUrl, PublicKey),
+    /// The home relay server reported a change in the presence of a watched node.
+    RelayPresence(PresenceEvent),
+    /// Suspend or resume all network activity.
+    SetOffline(bool),
+    /// The operating system is going to sleep, see [`MagicSock::suspend`].
+    Suspend(sync::oneshot::Sender<()>),
+    /// The operating system woke up, see [`MagicSock::resume`].
+    Resume(sync::oneshot::Sender<()>),
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
 
+impl ActorMessage {
+    /// The queue of the actor inbox this message goes into.
+    fn class(&self) -> MessageClass {
+        match self {
+            Self::ReceiveRelay(_) | Self::FlushStagedTransmits(_) => MessageClass::Data,
+            #[cfg(feature = "net-conditioner")]
+            Self::SendConditioned(..) => MessageClass::Data,
+            // Queued behind the received relay packets, so a pong which arrived in time is
+            // handled before its ping expires.
+            Self::EndpointPingExpired(..) => MessageClass::Data,
+            Self::RelayLatency(..) | Self::RelayPeerGone(..) | Self::RelayPresence(_) => {
+                MessageClass::Disco
+            }
+            Self::NetcheckReport(..)
+            | Self::NetworkChange
+            | Self::SetOffline(_)
+            | Self::Suspend(_)
+            | Self::Resume(_) => MessageClass::Control,
+            #[cfg(test)]
+            Self::ForceNetworkChange(_) => MessageClass::Control,
+        }
+    }
+}
+
 struct Actor {
     inner: Arc<Inner>,
-    msg_receiver: mpsc::Receiver<ActorMessage>,
-    msg_sender: mpsc::Sender<ActorMessage>,
+    msg_receiver: ActorReceiver,
+    msg_sender: ActorSender,
     relay_actor_sender: mpsc::Sender<RelayActorMessage>,
     relay_actor_cancel_token: CancellationToken,
     /// Channel to send received relay messages on, for processing.
     relay_recv_sender: flume::Sender<RelayRecvResult>,
     /// When set, is an AfterFunc timer that will call MagicSock::do_periodic_stun.
     periodic_re_stun_timer: time::Interval,
+    /// Source of the random choices, seeded by [`Options::rng_seed`] in tests.
+    rng: StdRng,
+    /// Notices suspends between the endpoint heartbeats.
+    resume_detector: ResumeDetector,
+    /// When the running endpoint update started.
+    endpoints_update_started: Option<Instant>,
+    /// Whether our endpoints changed since they were last sent to the active nodes.
+    endpoints_push_pending: bool,
+    /// When our endpoints were last sent to the active nodes.
+    last_endpoints_push: Option<Instant>,
     /// The `NetInfo` provided in the last call to `net_info_func`. It's used to deduplicate calls to netInfoFunc.
     net_info_last: Option<config::NetInfo>,
     /// Path where connection info from [`Inner::node_map`] is persisted.
     nodes_path: Option<PathBuf>,
+    /// Storage where connection info is persisted if there is no `nodes_path`.
+    storage: Option<Arc<dyn Storage>>,
 
-    // The underlying UDP sockets used to send/rcv packets.
+    // The underlying UDP sockets used to send/rcv packets, the IPv6 one is in
+    // [`Inner::pconn6`] as it can be bound later.
     pconn4: UdpConn,
-    pconn6: Option<UdpConn>,
+    /// Whether to retry binding the IPv6 socket, see [`Options::retry_ipv6_bind`].
+    retry_ipv6_bind: bool,
+    /// How the IPv4 socket moves to new ports, see [`Options::port_hopping`].
+    port_hopping: Option<PortHopping>,
 
     /// The NAT-PMP/PCP/UPnP prober/client, for requesting port mappings from NAT devices.
     port_mapper: portmapper::Client,
@@ -1661,6 +3606,28 @@ struct Actor {
 
     /// The prober that discovers local network conditions, including the closest relay relay and NAT mappings.
     net_checker: netcheck::Client,
+    /// Which sockets netcheck probes from, see [`Options::netcheck_sockets`].
+    netcheck_sockets: NetcheckSockets,
+    /// Whether netcheck probes plain STUN servers, see [`Options::stun_servers`].
+    has_stun_servers: bool,
+    /// Number of netchecks started.
+    netcheck_runs: u64,
+    /// Whether the netcheck in progress probes from the main sockets.
+    netcheck_on_main_sockets: bool,
+    /// The last netcheck report which probed from the main sockets.
+    ///
+    /// Provides the public mappings of the main sockets while netcheck probes from
+    /// ephemeral sockets.
+    main_sockets_report: Option<Arc<netcheck::Report>>,
+    /// The netcheck report our current endpoints were determined from.
+    endpoints_report: Option<Arc<netcheck::Report>>,
+    /// Whether the port mapping changed since the last endpoint update started.
+    portmap_changed: bool,
+    /// Transmits held back by the simulated network conditions until they are due.
+    #[cfg(feature = "net-conditioner")]
+    conditioned: conditioner::DelayedTransmits,
+    /// Where our local addresses come from, see [`Options::local_addrs`].
+    local_addrs: LocalAddrSource,
 
     network_monitor: netmon::Monitor,
 }
@@ -1685,9 +3652,17 @@ impl Actor {
             time::Instant::now() + HEARTBEAT_INTERVAL,
             HEARTBEAT_INTERVAL,
         );
+        // Where the clock keeps running during a suspend, do not catch up on all the missed
+        // heartbeats at once after resuming.
+        endpoint_heartbeat_timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
         let mut endpoints_update_receiver = self.inner.endpoints_update_state.running.subscribe();
+        if endpoints_update_receiver.borrow().is_some() {
+            // Triggered before the actor started.
+            endpoints_update_receiver.mark_changed();
+        }
         let mut portmap_watcher = self.port_mapper.watch_external_address();
-        let mut save_nodes_timer = if self.nodes_path.is_some() {
+        let persist_nodes = self.nodes_path.is_some() || self.storage.is_some();
+        let mut save_nodes_timer = if persist_nodes {
             tokio::time::interval_at(
                 time::Instant::now() + SAVE_NODES_INTERVAL,
                 SAVE_NODES_INTERVAL,
@@ -1696,53 +3671,87 @@ impl Actor {
             tokio::time::interval(Duration::MAX)
         };
 
+        let mut port_hop_timer = match self.port_hopping {
+            Some(hopping) => {
+                time::interval_at(time::Instant::now() + hopping.interval, hopping.interval)
+            }
+            None => time::interval(Duration::MAX),
+        };
+        port_hop_timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        // Closes the socket of the previous port once the overlap after a hop ended.
+        let drop_previous_port = time::sleep(Duration::MAX);
+        tokio::pin!(drop_previous_port);
+        let mut previous_port_open = false;
+
+        let shutdown_token = self.inner.shutdown_token.clone();
         loop {
+            // Checked on every iteration, so a backlog of messages does not delay the shutdown.
+            if shutdown_token.is_cancelled() {
+                self.shutdown().await;
+                return Ok(());
+            }
+            let conditioned_due = self.next_conditioned_due();
             tokio::select! {
+                _ = shutdown_token.cancelled() => {
+                    trace!("tick: shutdown");
+                }
                 Some(msg) = self.msg_receiver.recv() => {
                     trace!(?msg, "tick: msg");
-                    if self.handle_actor_message(msg).await {
-                        return Ok(());
-                    }
+                    self.handle_actor_messages(msg).await;
                 }
                 tick = self.periodic_re_stun_timer.tick() => {
                     trace!("tick: re_stun {:?}", tick);
+                    self.maybe_bind_ipv6();
                     self.inner.re_stun("periodic");
                 }
                 Ok(()) = portmap_watcher.changed() => {
                     trace!("tick: portmap changed");
                     let new_external_address = *portmap_watcher.borrow();
                     debug!("external address updated: {new_external_address:?}");
+                    self.note_portmap_change(new_external_address);
                     self.inner.re_stun("portmap_updated");
                 },
-                _ = endpoint_heartbeat_timer.tick() => {
-                    trace!("tick: endpoint heartbeat {} endpoints", self.inner.node_map.node_count());
-                    // TODO: this might trigger too many packets at once, pace this
-
-                    self.inner.node_map.prune_inactive();
-                    let msgs = self.inner.node_map.endpoints_stayin_alive();
-                    self.handle_ping_actions(msgs).await;
+                _ = endpoint_heartbeat_timer.tick(), if !self.inner.is_offline() => {
+                    // The heartbeats of the nodes are sent by the shard tasks.
+                    trace!("tick: heartbeat {} endpoints", self.inner.node_map.node_count());
+                    if self.check_resumed().await {
+                        continue;
+                    }
+                    self.maybe_push_endpoints().await;
                 }
                 _ = endpoints_update_receiver.changed() => {
-                    let reason = *endpoints_update_receiver.borrow();
+                    let reason = endpoints_update_receiver.borrow().clone();
                     trace!("tick: endpoints update receiver {:?}", reason);
                     if let Some(reason) = reason {
                         self.update_endpoints(reason).await;
                     }
                 }
-                _ = save_nodes_timer.tick(), if self.nodes_path.is_some() => {
+                _ = port_hop_timer.tick(), if self.port_hopping.is_some() => {
+                    trace!("tick: port hop");
+                    if self.hop_port().await {
+                        let overlap = self.port_hopping.map(|h| h.overlap).unwrap_or_default();
+                        drop_previous_port.as_mut().reset(time::Instant::now() + overlap);
+                        previous_port_open = true;
+                    }
+                }
+                _ = &mut drop_previous_port, if previous_port_open => {
+                    trace!("tick: drop previous port");
+                    self.pconn4.drop_previous();
+                    previous_port_open = false;
+                }
+                _ = save_nodes_timer.tick(), if persist_nodes => {
                     trace!("tick: nodes_timer");
-                    let path = self.nodes_path.as_ref().expect("precondition: `is_some()`");
-
                     self.inner.node_map.prune_inactive();
-                    match self.inner.node_map.save_to_file(path).await {
-                        Ok(count) => debug!(count, "nodes persisted"),
-                        Err(e) => debug!(%e, "failed to persist known nodes"),
-                    }
+                    self.save_nodes().await;
                 }
                 Some(is_major) = link_change_r.recv() => {
                     trace!("tick: link change {}", is_major);
                     self.handle_network_change(is_major).await;
                 }
+                _ = time::sleep_until(conditioned_due.unwrap_or_else(Instant::now).into()), if conditioned_due.is_some() => {
+                    trace!("tick: conditioned transmits due");
+                    self.send_due_conditioned().await;
+                }
                 else => {
                     trace!("tick: other");
                 }
@@ -1751,7 +3760,16 @@ impl Actor {
     }
 
     async fn handle_network_change(&mut self, is_major: bool) {
+        if self.inner.is_offline() {
+            // Handled as a major change when going online again.
+            debug!("link change detected while offline, ignoring");
+            return;
+        }
         debug!("link change detected: major? {}", is_major);
+        self.inner
+            .event_watchers
+            .notify(MagicSockEvent::NetworkChanged { major: is_major });
+        self.maybe_bind_ipv6();
 
         if is_major {
             self.inner.dns_resolver.clear_cache();
@@ -1763,8 +3781,139 @@ impl Actor {
         }
     }
 
-    async fn handle_ping_actions(&mut self, mut msgs: Vec<PingAction>) {
-        if msgs.is_empty() {
+    /// Handles a resume from suspend as a major network change, see [`ResumeDetector`].
+    ///
+    /// Returns whether the system was resumed.
+    async fn check_resumed(&mut self) -> bool {
+        match self.resume_detector.check() {
+            ClockEvent::Normal => false,
+            ClockEvent::Resumed { slept } => {
+                info!("resumed after about {slept:?}, re-checking the network");
+                inc!(MagicsockMetrics, actor_resumed);
+                self.inner
+                    .event_watchers
+                    .notify(MagicSockEvent::Resumed { slept });
+                self.handle_network_change(true).await;
+                true
+            }
+            ClockEvent::WallClockBack { by } => {
+                debug!("wall clock set back by {by:?}");
+                false
+            }
+        }
+    }
+
+    async fn set_offline(&mut self, offline: bool) {
+        if self.inner.offline.swap(offline, Ordering::Relaxed) == offline {
+            return;
+        }
+        self.inner
+            .event_watchers
+            .notify(MagicSockEvent::Offline(offline));
+        if offline {
+            info!("going offline");
+            self.send_relay_actor(RelayActorMessage::CloseAll);
+        } else {
+            info!("going online");
+            // There were no heartbeats while offline.
+            self.resume_detector.reset();
+            self.handle_network_change(true).await;
+            if let Some(url) = self.inner.my_relay() {
+                self.send_relay_actor(RelayActorMessage::SetHome { url });
+            }
+        }
+    }
+
+    /// Goes offline and saves the known nodes before the system sleeps.
+    async fn suspend(&mut self) {
+        info!("suspending");
+        self.set_offline(true).await;
+        if self.nodes_path.is_some() || self.storage.is_some() {
+            self.save_nodes().await;
+        }
+    }
+
+    /// Recovers the connectivity after the system woke up, see [`MagicSock::resume`].
+    async fn resume(&mut self) {
+        info!("resuming");
+        if self.inner.is_offline() {
+            self.set_offline(false).await;
+        } else {
+            // The system slept without being suspended first.
+            self.resume_detector.reset();
+            self.handle_network_change(true).await;
+        }
+        let msgs = self.inner.node_map.call_me_maybe_active();
+        self.handle_ping_actions(msgs).await;
+    }
+
+    /// Tries to bind the IPv6 socket if this failed before.
+    ///
+    /// Once bound, the socket is used for all further sends and receives, and its addresses
+    /// are advertised with the next endpoint update.
+    fn maybe_bind_ipv6(&mut self) {
+        if !self.retry_ipv6_bind || self.inner.pconn6.get().is_some() {
+            return;
+        }
+        let conn = match bind_ipv6(self.pconn4.port(), &self.inner.socket_options) {
+            Ok(conn) => conn,
+            Err(err) => {
+                trace!("IPv6 still unavailable: {err:?}");
+                return;
+            }
+        };
+        let local_addr = conn.local_addr().ok();
+        if self.inner.pconn6.set(conn).is_err() {
+            return;
+        }
+        info!(?local_addr, "bound IPv6 socket");
+        self.inner.local_addrs.rcu(|addrs| (addrs.0, local_addr));
+        // Make sure the new socket gets polled for receives.
+        if let Some(waker) = self.inner.network_recv_wakers.lock().take() {
+            waker.wake();
+        }
+        self.inner.re_stun("ipv6-bound");
+    }
+
+    /// Moves the IPv4 socket to a new port, see [`PortHopping`].
+    ///
+    /// Returns whether the socket moved, the previous socket then needs to be dropped after
+    /// the overlap.
+    async fn hop_port(&mut self) -> bool {
+        if self.inner.is_offline() || self.pconn4.is_proxied() {
+            return false;
+        }
+        let local_addr = match self.pconn4.hop() {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn!("failed to hop to a new port: {err:#}");
+                inc!(MagicsockMetrics, bind_error);
+                return false;
+            }
+        };
+        info!(%local_addr, "hopped to a new port");
+        inc!(MagicsockMetrics, port_hops);
+        self.inner.local_addrs.rcu(|addrs| (local_addr, addrs.1));
+        if let Ok(port) = local_addr.port().try_into() {
+            self.port_mapper.update_local_port(port);
+        }
+        // Make sure the new socket gets polled for receives.
+        if let Some(waker) = self.inner.network_recv_wakers.lock().take() {
+            waker.wake();
+        }
+
+        // The nodes drop QUIC packets from addresses they do not know, a ping from the new
+        // port teaches them the new address right away.
+        let msgs = self.inner.node_map.notify_local_port_changed();
+        self.handle_ping_actions(msgs).await;
+        // Push the new endpoints as soon as they are discovered.
+        self.last_endpoints_push = None;
+        self.inner.re_stun("port-hop");
+        true
+    }
+
+    async fn handle_ping_actions(&mut self, mut msgs: Vec<PingAction>) {
+        if msgs.is_empty() {
             return;
         }
         if let Err(err) =
@@ -1774,37 +3923,223 @@ impl Actor {
         }
     }
 
-    /// Processes an incoming actor message.
+    /// Processes an incoming actor message and any further messages already queued.
     ///
-    /// Returns `true` if it was a shutdown.
-    async fn handle_actor_message(&mut self, msg: ActorMessage) -> bool {
-        match msg {
-            ActorMessage::Shutdown => {
-                debug!("shutting down");
-
-                self.inner.node_map.notify_shutdown();
-                if let Some(path) = self.nodes_path.as_ref() {
-                    match self.inner.node_map.save_to_file(path).await {
-                        Ok(count) => {
-                            debug!(count, "known nodes persisted")
-                        }
-                        Err(e) => debug!(%e, "failed to persist known nodes"),
-                    }
+    /// At most [`ACTOR_MESSAGE_BATCH_SIZE`] messages are handled, so that the other branches
+    /// of the actor loop are not starved under load.  The batch is cut short once a shutdown
+    /// was requested.
+    async fn handle_actor_messages(&mut self, first: ActorMessage) {
+        let queued = self.queued_messages() + 1;
+        inc!(MagicsockMetrics, actor_msg_batches);
+        inc_by!(MagicsockMetrics, actor_queue_depth, queued as u64);
+        set!(MagicsockMetrics, actor_queue_len, queued as i64);
+
+        let mut msg = first;
+        for i in 1.. {
+            inc!(MagicsockMetrics, actor_msgs);
+            let start = Instant::now();
+            self.handle_actor_message(msg).await;
+            observe!(
+                MagicsockMetrics,
+                actor_msg_duration,
+                start.elapsed().as_secs_f64()
+            );
+            if self.inner.shutdown_token.is_cancelled() {
+                break;
+            }
+            if i == ACTOR_MESSAGE_BATCH_SIZE {
+                if self.queued_messages() > 0 {
+                    inc!(MagicsockMetrics, actor_batch_budget_exhausted);
                 }
-                self.port_mapper.deactivate();
-                self.relay_actor_cancel_token.cancel();
-
-                // Ignore errors from pconnN
-                // They will frequently have been closed already by a call to connBind.Close.
-                debug!("stopping connections");
-                if let Some(ref conn) = self.pconn6 {
-                    conn.close().await.ok();
+                break;
+            }
+            msg = match self.msg_receiver.try_recv() {
+                Some(msg) => {
+                    trace!(?msg, "tick: queued msg");
+                    msg
                 }
-                self.pconn4.close().await.ok();
+                None => break,
+            };
+        }
+    }
+
+    /// Sends the transmits staged for `dest` now that a path might be known.
+    ///
+    /// If there still is no path the transmits stay staged.
+    async fn flush_staged_transmits(&mut self, dest: QuicMappedAddr) {
+        let Some(mut queue) = self.inner.staged_transmits.lock().remove(&dest) else {
+            return;
+        };
+        let dropped = queue.prune(Instant::now(), self.inner.first_packet_policy.max_age);
+        inc_by!(MagicsockMetrics, send_data_staged_dropped, dropped as u64);
+        if queue.is_empty() {
+            return;
+        }
+        let have_ipv6 = self.inner.ipv6_reported.load(Ordering::Relaxed);
+        let Some((public_key, udp_addr, relay_url, msgs, _)) = self
+            .inner
+            .node_map
+            .get_send_addrs_for_quic_mapped_addr(&dest, have_ipv6)
+        else {
+            return;
+        };
+        self.handle_ping_actions(msgs).await;
+        if udp_addr.is_none() && relay_url.is_none() {
+            trace!(node = %public_key.fmt_short(), "still no path, keeping staged transmits");
+            let mut staged = self.inner.staged_transmits.lock();
+            if let Some(newer) = staged.remove(&dest) {
+                queue.append(newer, self.inner.first_packet_policy.max_packets);
+            }
+            staged.insert(dest, queue);
+            return;
+        }
+
+        let transmits = queue.into_transmits();
+        debug!(node = %public_key.fmt_short(), count = transmits.len(), "flushing staged transmits");
+        inc_by!(
+            MagicsockMetrics,
+            send_data_staged_flushed,
+            transmits.len() as u64
+        );
+        self.send_transmits(public_key, udp_addr, relay_url, transmits)
+            .await;
+    }
+
+    /// When the next transmit held back by the simulated network conditions is due.
+    #[cfg(feature = "net-conditioner")]
+    fn next_conditioned_due(&self) -> Option<Instant> {
+        self.conditioned.next_due()
+    }
+
+    #[cfg(not(feature = "net-conditioner"))]
+    fn next_conditioned_due(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Sends the transmits held back by the simulated network conditions which are due.
+    #[cfg(feature = "net-conditioner")]
+    async fn send_due_conditioned(&mut self) {
+        while let Some((dest, transmit)) = self.conditioned.pop_due(Instant::now()) {
+            self.send_conditioned(dest, transmit).await;
+        }
+    }
+
+    #[cfg(not(feature = "net-conditioner"))]
+    #[allow(clippy::unused_async)]
+    async fn send_due_conditioned(&mut self) {}
+
+    /// Sends a transmit held back by the simulated network conditions.
+    #[cfg(feature = "net-conditioner")]
+    async fn send_conditioned(&mut self, dest: QuicMappedAddr, transmit: quinn_udp::Transmit) {
+        if self.inner.is_closed() || self.inner.is_offline() {
+            return;
+        }
+        let have_ipv6 = self.inner.ipv6_reported.load(Ordering::Relaxed);
+        let Some((public_key, udp_addr, relay_url, msgs, _)) = self
+            .inner
+            .node_map
+            .get_send_addrs_for_quic_mapped_addr(&dest, have_ipv6)
+        else {
+            return;
+        };
+        self.handle_ping_actions(msgs).await;
+        let udp_addr = udp_addr.filter(|_| !self.inner.udp_blocked());
+        self.send_transmits(public_key, udp_addr, relay_url, vec![transmit])
+            .await;
+    }
 
-                debug!("shutdown complete");
-                return true;
+    /// Sends `transmits` to the node on its UDP path and relay, outside of `poll_send`.
+    async fn send_transmits(
+        &self,
+        public_key: PublicKey,
+        udp_addr: Option<SocketAddr>,
+        relay_url: Option<RelayUrl>,
+        mut transmits: Vec<quinn_udp::Transmit>,
+    ) {
+        if let Some(addr) = udp_addr {
+            for t in transmits.iter_mut() {
+                t.destination = addr;
+            }
+            let mut sent = 0;
+            while sent < transmits.len() {
+                let res = futures::future::poll_fn(|cx| {
+                    self.inner.poll_send_udp(addr, &transmits[sent..], cx)
+                })
+                .await;
+                match res {
+                    Ok(0) => break,
+                    Ok(n) => sent += n,
+                    Err(err) => {
+                        warn_limited!(node = %public_key.fmt_short(), %addr, "failed to send transmits: {err:?}");
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(ref url) = relay_url {
+            let contents = self.inner.relay_contents(public_key, &transmits);
+            if self
+                .inner
+                .poll_send_relay(url, public_key, contents)
+                .is_pending()
+            {
+                warn_limited!(node = %public_key.fmt_short(), relay_url = %url, "relay channel full, dropped transmits");
             }
+        }
+    }
+
+    /// Number of messages currently waiting in the actor inbox.
+    fn queued_messages(&self) -> usize {
+        self.msg_receiver.len()
+    }
+
+    /// Persists the known nodes to the `nodes_path` or the storage, if any.
+    async fn save_nodes(&self) {
+        let res = if let Some(path) = self.nodes_path.as_ref() {
+            self.inner.node_map.save_to_file(path).await
+        } else if let Some(storage) = self.storage.clone() {
+            let node_addrs = self.inner.node_map.known_node_addresses();
+            let count = node_addrs.len();
+            tokio::task::spawn_blocking(move || {
+                storage::store(&*storage, NODES_KEY, NODES_VERSION, &node_addrs)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|res| res.map(|()| count))
+        } else {
+            return;
+        };
+        match res {
+            Ok(count) => debug!(count, "known nodes persisted"),
+            Err(e) => debug!(%e, "failed to persist known nodes"),
+        }
+    }
+
+    /// Shuts down the actor, after [`MagicSock::close`] cancelled the shutdown token.
+    async fn shutdown(&mut self) {
+        debug!("shutting down");
+
+        self.inner.node_map.notify_shutdown();
+        self.inner.event_watchers.close();
+        self.inner.send_tap.close();
+        self.save_nodes().await;
+        self.port_mapper.deactivate();
+        self.relay_actor_cancel_token.cancel();
+
+        // Ignore errors from pconnN
+        // They will frequently have been closed already by a call to connBind.Close.
+        debug!("stopping connections");
+        if let Some(conn) = self.inner.pconn6.get() {
+            conn.close().await.ok();
+        }
+        self.pconn4.close().await.ok();
+
+        debug!("shutdown complete");
+    }
+
+    /// Processes an incoming actor message.
+    async fn handle_actor_message(&mut self, msg: ActorMessage) {
+        match msg {
             ActorMessage::ReceiveRelay(read_result) => {
                 let passthroughs = self.process_relay_read_result(read_result);
                 for passthrough in passthroughs {
@@ -1819,7 +4154,11 @@ impl Actor {
                 }
             }
             ActorMessage::EndpointPingExpired(id, txid) => {
-                self.inner.node_map.notify_ping_timeout(id, txid);
+                for action in self.inner.node_map.notify_ping_timeout(id, txid) {
+                    if let PingAction::SendPing(ping) = action {
+                        self.inner.send_ping_queued(ping);
+                    }
+                }
             }
             ActorMessage::NetcheckReport(report, why) => {
                 match report {
@@ -1835,13 +4174,50 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
+            ActorMessage::FlushStagedTransmits(dest) => {
+                self.flush_staged_transmits(dest).await;
+            }
+            #[cfg(feature = "net-conditioner")]
+            ActorMessage::SendConditioned(dest, transmit, due) => {
+                self.conditioned.push(due, dest, transmit);
+            }
+            ActorMessage::RelayLatency(url, latency) => {
+                // Only relays from our map are candidates for the home relay.
+                if self.inner.relay_map.contains_node(&url) {
+                    self.inner.relay_latencies.add_rtt_sample(&url, latency);
+                    self.maybe_switch_home_relay();
+                }
+            }
+            ActorMessage::RelayPeerGone(url, node) => {
+                self.inner.node_map.notify_relay_peer_gone(&url, node);
+            }
+            ActorMessage::RelayPresence(event) => {
+                trace!(node = %event.node.fmt_short(), online = event.online, "presence");
+                self.inner
+                    .event_watchers
+                    .notify(MagicSockEvent::Presence(event));
+                if !self.inner.presence.is_watched(&event.node) {
+                    // All subscriptions for the node were dropped, stop watching it.
+                    let nodes = self.inner.presence.nodes();
+                    self.send_relay_actor(RelayActorMessage::WatchPresence(nodes));
+                }
+            }
+            ActorMessage::SetOffline(offline) => {
+                self.set_offline(offline).await;
+            }
+            ActorMessage::Suspend(s) => {
+                self.suspend().await;
+                s.send(()).ok();
+            }
+            ActorMessage::Resume(s) => {
+                self.resume().await;
+                s.send(()).ok();
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
             }
         }
-
-        false
     }
 
     fn normalized_local_addr(&self) -> io::Result<SocketAddr> {
@@ -1856,7 +4232,7 @@ impl Actor {
         // TODO: think more about this
         // needs to pretend ipv6 always as the fake addrs are ipv6
         let mut ipv6_addr = None;
-        if let Some(ref conn) = self.pconn6 {
+        if let Some(conn) = self.inner.pconn6.get() {
             ipv6_addr = Some(conn.local_addr());
         }
         let ipv4_addr = self.pconn4.local_addr();
@@ -1872,7 +4248,8 @@ impl Actor {
         }
         let url = &dm.url;
 
-        let quic_mapped_addr = self.inner.node_map.receive_relay(url, dm.src);
+        let quic_mapped_addr = self.inner.node_map.receive_relay(url, dm.src, dm.buf.len());
+        self.inner.flush_staged_transmits(&dm.src);
 
         // the relay packet is made up of multiple udp packets, prefixed by a u16 be length prefix
         //
@@ -1889,6 +4266,26 @@ impl Actor {
                         // Message was internal, do not bubble up.
                         continue;
                     }
+                    let part = if relay_seal::is_sealed(&part) {
+                        let secret = self
+                            .inner
+                            .disco_secrets
+                            .shared(&self.inner.secret_key, dm.src);
+                        match relay_seal::open(&**secret, &part) {
+                            Some(part) => part,
+                            None => {
+                                inc!(MagicsockMetrics, recv_relay_bad_seal);
+                                debug!(src = %dm.src.fmt_short(), "dropping relayed packet which failed to open");
+                                continue;
+                            }
+                        }
+                    } else if self.inner.seal_relay_packets {
+                        inc!(MagicsockMetrics, recv_relay_unsealed);
+                        debug!(src = %dm.src.fmt_short(), "dropping relayed packet which is not sealed");
+                        continue;
+                    } else {
+                        part
+                    };
 
                     let meta = quinn_udp::RecvMeta {
                         len: part.len(),
@@ -1916,159 +4313,74 @@ impl Actor {
     /// never be invoked directly.  Some day this will be refactored to not allow this easy
     /// mistake to be made.
     #[instrument(level = "debug", skip_all)]
-    async fn update_endpoints(&mut self, why: &'static str) {
+    async fn update_endpoints(&mut self, why: Cow<'static, str>) {
         inc!(MagicsockMetrics, update_endpoints);
+        self.endpoints_update_started = Some(Instant::now());
 
         debug!("starting endpoint update ({})", why);
+        if std::mem::take(&mut self.portmap_changed) {
+            // A mapping whose lease expired, or which the router lost, must not stay
+            // advertised until netcheck completes.  The netcheck report of the current
+            // endpoints still holds for the others.
+            self.store_endpoints_update(self.endpoints_report.clone())
+                .await;
+        }
         self.port_mapper.procure_mapping();
         self.update_net_info(why).await;
     }
 
+    /// Replaces the public mappings in a report from ephemeral sockets with the ones of the
+    /// main sockets.
+    fn with_main_socket_mappings(
+        &mut self,
+        report: Option<Arc<netcheck::Report>>,
+    ) -> Option<Arc<netcheck::Report>> {
+        let report = report?;
+        if self.netcheck_on_main_sockets {
+            self.main_sockets_report = Some(report.clone());
+            return Some(report);
+        }
+        let mut report = (*report).clone();
+        let main = self.main_sockets_report.as_deref();
+        report.global_v4 = main.and_then(|main| main.global_v4);
+        report.global_v6 = main.and_then(|main| main.global_v6);
+        report.mapping_varies_by_dest_ip = main.and_then(|main| main.mapping_varies_by_dest_ip);
+        Some(Arc::new(report))
+    }
+
     /// Stores the results of a successful endpoint update.
     async fn store_endpoints_update(&mut self, nr: Option<Arc<netcheck::Report>>) {
-        let portmap_watcher = self.port_mapper.watch_external_address();
-
-        // endpoint -> how it was found
-        let mut already = HashMap::new();
-        // unique endpoints
-        let mut eps = Vec::new();
-
-        macro_rules! add_addr {
-            ($already:expr, $eps:expr, $ipp:expr, $et:expr) => {
-                #[allow(clippy::map_entry)]
-                if !$already.contains_key(&$ipp) {
-                    $already.insert($ipp, $et);
-                    $eps.push(config::Endpoint {
-                        addr: $ipp,
-                        typ: $et,
-                    });
-                }
-            };
+        self.endpoints_report = nr.clone();
+        let mut eps = match self.pconn4.relayed_addr() {
+            // Datagrams not relayed by the TURN server are dropped, so this is the only
+            // address we can be reached at.
+            Some(addr) => vec![config::Endpoint {
+                addr,
+                typ: config::EndpointType::Turn,
+            }],
+            // Our own addresses would reveal us behind the proxy.
+            None if self.inner.socks5_proxy.is_some() => Vec::new(),
+            None => determine_endpoints(
+                &self.port_mapper,
+                nr.as_deref(),
+                self.pconn4.local_addr().ok(),
+                self.inner.pconn6.get().and_then(|c| c.local_addr().ok()),
+                self.local_addrs
+                    .local_addresses(&self.network_monitor.local_addresses()),
+                self.inner.port.load(Ordering::Relaxed),
+            ),
+        };
+        let advertise_ipv6 = self.inner.ipv6_config.lock().advertise;
+        if !self.inner.ipv6_enabled(advertise_ipv6) {
+            eps.retain(|ep| ep.addr.is_ipv4());
         }
-
-        let maybe_port_mapped = *portmap_watcher.borrow();
-
-        if let Some(portmap_ext) = maybe_port_mapped.map(SocketAddr::V4) {
-            add_addr!(already, eps, portmap_ext, config::EndpointType::Portmapped);
+        if eps
+            .iter()
+            .any(|ep| ep.typ == config::EndpointType::Portmapped)
+        {
             self.set_net_info_have_port_map().await;
         }
 
-        if let Some(nr) = nr {
-            if let Some(global_v4) = nr.global_v4 {
-                add_addr!(already, eps, global_v4.into(), config::EndpointType::Stun);
-
-                // If they're behind a hard NAT and are using a fixed
-                // port locally, assume they might've added a static
-                // port mapping on their router to the same explicit
-                // port that we are running with. Worst case it's an invalid candidate mapping.
-                let port = self.inner.port.load(Ordering::Relaxed);
-                if nr.mapping_varies_by_dest_ip.unwrap_or_default() && port != 0 {
-                    let mut addr = global_v4;
-                    addr.set_port(port);
-                    add_addr!(
-                        already,
-                        eps,
-                        addr.into(),
-                        config::EndpointType::Stun4LocalPort
-                    );
-                }
-            }
-            if let Some(global_v6) = nr.global_v6 {
-                add_addr!(already, eps, global_v6.into(), config::EndpointType::Stun);
-            }
-        }
-        let local_addr_v4 = self.pconn4.local_addr().ok();
-        let local_addr_v6 = self.pconn6.as_ref().and_then(|c| c.local_addr().ok());
-
-        let is_unspecified_v4 = local_addr_v4
-            .map(|a| a.ip().is_unspecified())
-            .unwrap_or(false);
-        let is_unspecified_v6 = local_addr_v6
-            .map(|a| a.ip().is_unspecified())
-            .unwrap_or(false);
-
-        let LocalAddresses {
-            regular: mut ips,
-            loopback,
-        } = LocalAddresses::new();
-
-        if is_unspecified_v4 || is_unspecified_v6 {
-            if ips.is_empty() && eps.is_empty() {
-                // Only include loopback addresses if we have no
-                // interfaces at all to use as endpoints and don't
-                // have a public IPv4 or IPv6 address. This allows
-                // for localhost testing when you're on a plane and
-                // offline, for example.
-                ips = loopback;
-            }
-            let v4_port = local_addr_v4.and_then(|addr| {
-                if addr.ip().is_unspecified() {
-                    Some(addr.port())
-                } else {
-                    None
-                }
-            });
-
-            let v6_port = local_addr_v6.and_then(|addr| {
-                if addr.ip().is_unspecified() {
-                    Some(addr.port())
-                } else {
-                    None
-                }
-            });
-
-            for ip in ips {
-                match ip {
-                    IpAddr::V4(_) => {
-                        if let Some(port) = v4_port {
-                            add_addr!(
-                                already,
-                                eps,
-                                SocketAddr::new(ip, port),
-                                config::EndpointType::Local
-                            );
-                        }
-                    }
-                    IpAddr::V6(_) => {
-                        if let Some(port) = v6_port {
-                            add_addr!(
-                                already,
-                                eps,
-                                SocketAddr::new(ip, port),
-                                config::EndpointType::Local
-                            );
-                        }
-                    }
-                }
-            }
-        }
-
-        if !is_unspecified_v4 {
-            if let Some(addr) = local_addr_v4 {
-                // Our local endpoint is bound to a particular address.
-                // Do not offer addresses on other local interfaces.
-                add_addr!(already, eps, addr, config::EndpointType::Local);
-            }
-        }
-
-        if !is_unspecified_v6 {
-            if let Some(addr) = local_addr_v6 {
-                // Our local endpoint is bound to a particular address.
-                // Do not offer addresses on other local interfaces.
-                add_addr!(already, eps, addr, config::EndpointType::Local);
-            }
-        }
-
-        // Note: the endpoints are intentionally returned in priority order,
-        // from "farthest but most reliable" to "closest but least
-        // reliable." Addresses returned from STUN should be globally
-        // addressable, but might go farther on the network than necessary.
-        // Local interface addresses might have lower latency, but not be
-        // globally addressable.
-        //
-        // The STUN address(es) are always first.
-        // Despite this sorting, clients are not relying on this sorting for decisions;
-
         let updated = self
             .inner
             .endpoints
@@ -2078,26 +4390,82 @@ impl Actor {
             let eps = self.inner.endpoints.read();
             eps.log_endpoint_change();
             self.inner.publish_my_addr();
+            self.endpoints_push_pending = true;
         }
 
         // Regardless of whether our local endpoints changed, we now want to send any queued
         // call-me-maybe messages.
         self.inner.send_queued_call_me_maybes();
+        self.maybe_push_endpoints().await;
+    }
+
+    /// Sends our changed endpoints to the nodes we have active sessions with.
+    ///
+    /// Otherwise they keep pinging our old addresses until they ask for new ones.  Sent at
+    /// most once per [`ENDPOINTS_PUSH_INTERVAL`], later changes are sent by a heartbeat.
+    async fn maybe_push_endpoints(&mut self) {
+        if !self.endpoints_push_pending || self.inner.is_offline() {
+            return;
+        }
+        if self
+            .last_endpoints_push
+            .is_some_and(|last| last.elapsed() < ENDPOINTS_PUSH_INTERVAL)
+        {
+            return;
+        }
+        self.endpoints_push_pending = false;
+        self.last_endpoints_push = Some(Instant::now());
+        let msgs = self.inner.node_map.notify_endpoints_changed();
+        if !msgs.is_empty() {
+            debug!(
+                count = msgs.len(),
+                "sending changed endpoints to active nodes"
+            );
+            inc_by!(MagicsockMetrics, endpoints_pushed, msgs.len() as u64);
+        }
+        self.handle_ping_actions(msgs).await;
+    }
+
+    /// Notes that the port mapping changed, so the next endpoint update starts by updating
+    /// the port mapped endpoint, see [`Actor::update_endpoints`].
+    fn note_portmap_change(&mut self, external_address: Option<SocketAddrV4>) {
+        let withdrawn = self.inner.endpoints.read().iter().any(|ep| {
+            ep.typ == config::EndpointType::Portmapped
+                && Some(ep.addr) != external_address.map(SocketAddr::V4)
+        });
+        if withdrawn {
+            debug!("withdrawing port mapped endpoint");
+            inc!(MagicsockMetrics, portmap_endpoint_withdrawn);
+        }
+        self.portmap_changed = true;
     }
 
     /// Called when an endpoints update is done, no matter if it was successful or not.
-    fn finalize_endpoints_update(&mut self, why: &'static str) {
+    fn finalize_endpoints_update(&mut self, why: Cow<'static, str>) {
+        let duration = self
+            .endpoints_update_started
+            .take()
+            .map(|started| started.elapsed())
+            .unwrap_or_default();
+        debug!("endpoint update done ({}) in {:?}", why, duration);
+        self.inner
+            .event_watchers
+            .notify(MagicSockEvent::EndpointUpdated(EndpointUpdate {
+                reason: why,
+                duration,
+                endpoints: self.inner.endpoints.read().iter().cloned().collect(),
+            }));
+
         let new_why = self.inner.endpoints_update_state.next_update();
         if !self.inner.is_closed() {
             if let Some(new_why) = new_why {
                 self.inner.endpoints_update_state.run(new_why);
                 return;
             }
-            self.periodic_re_stun_timer = new_re_stun_timer(true);
+            self.periodic_re_stun_timer = new_re_stun_timer(&mut self.rng, true);
         }
 
         self.inner.endpoints_update_state.finish_run();
-        debug!("endpoint update done ({})", why);
     }
 
     /// Updates `NetInfo.HavePortMap` to true.
@@ -2130,9 +4498,18 @@ impl Actor {
     /// and this should never be invoked directly.  Some day this will be refactored to not
     /// allow this easy mistake to be made.
     #[instrument(level = "debug", skip_all)]
-    async fn update_net_info(&mut self, why: &'static str) {
-        if self.inner.relay_map.is_empty() {
-            debug!("skipping netcheck, empty RelayMap");
+    async fn update_net_info(&mut self, why: Cow<'static, str>) {
+        if self.inner.socks5_proxy.is_some() {
+            // Its probes would reveal our address, see `Options::udp_proxy`.
+            debug!("skipping netcheck, traffic goes through a SOCKS5 proxy");
+            self.msg_sender
+                .send(ActorMessage::NetcheckReport(Ok(None), why))
+                .await
+                .ok();
+            return;
+        }
+        if self.inner.relay_map.is_empty() && !self.has_stun_servers {
+            debug!("skipping netcheck, empty RelayMap and no STUN servers");
             self.msg_sender
                 .send(ActorMessage::NetcheckReport(Ok(None), why))
                 .await
@@ -2141,8 +4518,18 @@ impl Actor {
         }
 
         let relay_map = self.inner.relay_map.clone();
-        let pconn4 = Some(self.pconn4.as_socket());
-        let pconn6 = self.pconn6.as_ref().map(|p| p.as_socket());
+        self.netcheck_on_main_sockets = self.netcheck_sockets.use_main_sockets(self.netcheck_runs);
+        self.netcheck_runs += 1;
+        // Without sockets netcheck binds ephemeral ones itself.
+        let (pconn4, pconn6) = if self.netcheck_on_main_sockets {
+            (
+                Some(self.pconn4.as_socket()),
+                self.inner.pconn6.get().map(|p| p.as_socket()),
+            )
+        } else {
+            inc!(MagicsockMetrics, netcheck_ephemeral_sockets);
+            (None, None)
+        };
 
         debug!("requesting netcheck report");
         match self
@@ -2152,8 +4539,9 @@ impl Actor {
         {
             Ok(rx) => {
                 let msg_sender = self.msg_sender.clone();
-                tokio::task::spawn(async move {
-                    let report = time::timeout(NETCHECK_REPORT_TIMEOUT, rx).await;
+                let netcheck_timeout = self.inner.timeouts.netcheck_report;
+                self.inner.rt.spawn(async move {
+                    let report = time::timeout(netcheck_timeout, rx).await;
                     let report: anyhow::Result<_> = match report {
                         Ok(Ok(Ok(report))) => Ok(Some(report)),
                         Ok(Ok(Err(err))) => Err(err),
@@ -2177,6 +4565,8 @@ impl Actor {
 
     async fn handle_netcheck_report(&mut self, report: Option<Arc<netcheck::Report>>) {
         if let Some(ref report) = report {
+            self.inner.last_netcheck_report.store(Some(report.clone()));
+            self.inner.update_prefer_ipv6();
             self.inner
                 .ipv6_reported
                 .store(report.ipv6, Ordering::Relaxed);
@@ -2187,11 +4577,21 @@ impl Actor {
                 !r.ipv4_can_send
             );
             self.no_v4_send = !r.ipv4_can_send;
+            self.inner
+                .node_map
+                .set_varying_mapping(r.mapping_varies_by_dest_ip.unwrap_or_default());
+            self.inner.node_map.set_local_conditions(LocalConditions {
+                ipv4: Some(r.ipv4_can_send),
+                ipv6: Some(r.ipv6),
+                symmetric_nat: r.mapping_varies_by_dest_ip.unwrap_or_default(),
+                direct_disabled: crate::util::relay_only_mode() || self.inner.udp_blocked(),
+            });
 
             let have_port_map = self.port_mapper.watch_external_address().borrow().is_some();
             let mut ni = config::NetInfo {
                 relay_latency: Default::default(),
                 mapping_varies_by_dest_ip: r.mapping_varies_by_dest_ip,
+                cgnat: r.cgnat,
                 hair_pinning: r.hair_pinning,
                 portmap_probe: r.portmap_probe.clone(),
                 have_port_map,
@@ -2203,54 +4603,94 @@ impl Actor {
                 preferred_relay: r.preferred_relay.clone(),
                 link_type: None,
             };
-            for (rid, d) in r.relay_v4_latency.iter() {
-                ni.relay_latency
-                    .insert(format!("{rid}-v4"), d.as_secs_f64());
+            for (url, latency) in r.relay_latency.iter() {
+                self.inner.relay_latencies.add_stun_sample(url, latency);
             }
-            for (rid, d) in r.relay_v6_latency.iter() {
-                ni.relay_latency
-                    .insert(format!("{rid}-v6"), d.as_secs_f64());
+            let probed_at = SystemTime::now();
+            for (url, d) in r.relay_v4_latency.iter() {
+                let latency = ni.relay_latency.entry(url.clone()).or_default();
+                latency.v4 = Some(d);
+                latency.last_probe = Some(probed_at);
+            }
+            for (url, d) in r.relay_v6_latency.iter() {
+                let latency = ni.relay_latency.entry(url.clone()).or_default();
+                latency.v6 = Some(d);
+                latency.last_probe = Some(probed_at);
             }
 
+            let mut reason = HomeRelayReason::Netcheck;
             if ni.preferred_relay.is_none() {
                 // Perhaps UDP is blocked. Pick a deterministic but arbitrary one.
                 ni.preferred_relay = self.pick_relay_fallback();
+                reason = HomeRelayReason::Fallback;
             }
 
-            if !self.set_nearest_relay(ni.preferred_relay.clone()) {
+            if !self.set_nearest_relay(ni.preferred_relay.clone(), reason) {
                 ni.preferred_relay = None;
             }
 
             // TODO: set link type
             self.call_net_info_callback(ni).await;
+        } else if self.inner.socks5_proxy.is_some() {
+            // Netcheck is skipped, the latencies of the relay connections may switch the
+            // home relay later on.
+            let relay_url = self.pick_relay_fallback();
+            self.set_nearest_relay(relay_url, HomeRelayReason::Fallback);
         }
+        let report = self.with_main_socket_mappings(report);
         self.store_endpoints_update(report).await;
     }
 
-    fn set_nearest_relay(&mut self, relay_url: Option<RelayUrl>) -> bool {
+    fn set_nearest_relay(&mut self, relay_url: Option<RelayUrl>, reason: HomeRelayReason) -> bool {
         let my_relay = self.inner.my_relay();
         if relay_url == my_relay {
             // No change.
             return true;
         }
         let old_relay = self.inner.set_my_relay(relay_url.clone());
+        self.inner
+            .event_watchers
+            .notify(MagicSockEvent::HomeRelayChanged(relay_url.clone()));
+        *self.inner.home_relay_decision.lock() = relay_url.clone().map(|url| HomeRelayDecision {
+            url,
+            reason,
+            latencies: self.inner.relay_latencies.latencies(),
+            at: Instant::now(),
+        });
 
         if let Some(ref relay_url) = relay_url {
             inc!(MagicsockMetrics, relay_home_change);
 
             // On change, notify all currently connected relay servers and
             // start connecting to our home relay if we are not already.
-            info!("home is now relay {}, was {:?}", relay_url, old_relay);
+            info!(%reason, "home is now relay {}, was {:?}", relay_url, old_relay);
             self.inner.publish_my_addr();
 
-            self.send_relay_actor(RelayActorMessage::SetHome {
-                url: relay_url.clone(),
-            });
+            // While offline the connection is made when going online again.
+            if !self.inner.is_offline() {
+                self.send_relay_actor(RelayActorMessage::SetHome {
+                    url: relay_url.clone(),
+                });
+            }
         }
 
         true
     }
 
+    /// Switches the home relay if another relay has become significantly faster.
+    ///
+    /// This reacts to the latency measured on the relay connections, without waiting for
+    /// the next netcheck report.
+    fn maybe_switch_home_relay(&mut self) {
+        let Some(home) = self.inner.my_relay() else {
+            return;
+        };
+        if let Some(url) = self.inner.relay_latencies.better_than(&home) {
+            debug!(%url, %home, "switching home relay based on latency");
+            self.set_nearest_relay(Some(url), HomeRelayReason::Latency);
+        }
+    }
+
     /// Returns a deterministic relay node to connect to. This is only used if netcheck
     /// couldn't find the nearest one, for instance, if UDP is blocked and thus STUN
     /// latency checks aren't working.
@@ -2273,7 +4713,7 @@ impl Actor {
         }
 
         let ids = self.inner.relay_map.urls().collect::<Vec<_>>();
-        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut rng = StdRng::seed_from_u64(0);
         ids.choose(&mut rng).map(|c| (*c).clone())
     }
 
@@ -2307,7 +4747,8 @@ impl Actor {
                 warn!("unable to send to relay actor, already closed");
             }
             Err(mpsc::error::TrySendError::Full(_)) => {
-                warn!("dropping message for relay actor, channel is full");
+                inc!(MagicsockMetrics, relay_actor_msgs_dropped);
+                warn_limited!("dropping message for relay actor, channel is full");
             }
         }
     }
@@ -2324,7 +4765,7 @@ impl Actor {
                     // TODO: return here?
                     warn!("Received relay disco message from connection for {}, but with message from {}", relay_node_src.fmt_short(), source.fmt_short());
                 }
-                self.inner.handle_disco_message(
+                self.inner.dispatch_disco_message(
                     source,
                     sealed_box,
                     DiscoMessageSource::Relay {
@@ -2339,10 +4780,9 @@ impl Actor {
     }
 }
 
-fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
+fn new_re_stun_timer(rng: &mut impl Rng, initial_delay: bool) -> time::Interval {
     // Pick a random duration between 20 and 26 seconds (just under 30s,
     // a common UDP NAT timeout on Linux,etc)
-    let mut rng = rand::thread_rng();
     let d: Duration = rng.gen_range(Duration::from_secs(20)..=Duration::from_secs(26));
     if initial_delay {
         debug!("scheduling periodic_stun to run in {}s", d.as_secs());
@@ -2357,14 +4797,17 @@ fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
 }
 
 /// Initial connection setup.
-fn bind(port: u16) -> Result<(UdpConn, Option<UdpConn>)> {
-    let pconn4 = UdpConn::bind(port, IpFamily::V4).context("bind IPv4 failed")?;
+fn bind(port: u16, opts: &SocketOptions) -> Result<(UdpConn, Option<UdpConn>)> {
+    let pconn4 = UdpConn::bind_with_options(port, IpFamily::V4, opts.clone()).map_err(|err| {
+        inc!(MagicsockMetrics, bind_error);
+        err.context("bind IPv4 failed")
+    })?;
     let ip4_port = pconn4.local_addr()?.port();
-    let ip6_port = ip4_port.checked_add(1).unwrap_or(ip4_port - 1);
 
-    let pconn6 = match UdpConn::bind(ip6_port, IpFamily::V6) {
+    let pconn6 = match bind_ipv6(ip4_port, opts) {
         Ok(conn) => Some(conn),
         Err(err) => {
+            inc!(MagicsockMetrics, bind_error);
             info!("bind ignoring IPv6 bind failure: {:?}", err);
             None
         }
@@ -2373,47 +4816,157 @@ fn bind(port: u16) -> Result<(UdpConn, Option<UdpConn>)> {
     Ok((pconn4, pconn6))
 }
 
-#[derive(derive_more::Debug, Default, Clone)]
-struct DiscoveredEndpoints {
-    /// Records the endpoints found during the previous
-    /// endpoint discovery. It's used to avoid duplicate endpoint change notifications.
-    last_endpoints: Vec<config::Endpoint>,
+/// Binds the IPv6 socket, preferably on the port next to the IPv4 one.
+fn bind_ipv6(ip4_port: u16, opts: &SocketOptions) -> Result<UdpConn> {
+    let ip6_port = ip4_port.checked_add(1).unwrap_or(ip4_port - 1);
+    UdpConn::bind_with_options(ip6_port, IpFamily::V6, opts.clone())
+}
 
-    /// The last time the endpoints were updated, even if there was no change.
-    last_endpoints_time: Option<Instant>,
+/// A source of received datagrams, see [`Inner::poll_recv`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecvSource {
+    Ipv4,
+    Ipv6,
+    Relay,
 }
 
-impl PartialEq for DiscoveredEndpoints {
-    fn eq(&self, other: &Self) -> bool {
-        endpoint_sets_equal(&self.last_endpoints, &other.last_endpoints)
+impl RecvSource {
+    const ALL: [Self; 3] = [Self::Ipv4, Self::Ipv6, Self::Relay];
+}
+
+/// Returns the number of received datagrams, if there are any.
+fn ready_msgs(poll: Poll<usize>) -> Option<usize> {
+    match poll {
+        Poll::Ready(n) if n > 0 => Some(n),
+        _ => None,
     }
 }
 
-impl DiscoveredEndpoints {
-    fn new(endpoints: Vec<config::Endpoint>) -> Self {
-        Self {
-            last_endpoints: endpoints,
-            last_endpoints_time: Some(Instant::now()),
+/// Coarse cause of a failed UDP send.
+///
+/// Separates errors caused by the local host, like a firewall rejecting the send, from the
+/// network not having a route to the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UdpSendError {
+    /// The local host refused to send the datagram.
+    Denied,
+    /// There is no route to the destination network or host.
+    Unreachable,
+    /// Any other error.
+    Other,
+}
+
+impl UdpSendError {
+    fn classify(err: &io::Error) -> Self {
+        if err.kind() == io::ErrorKind::PermissionDenied {
+            return Self::Denied;
+        }
+        #[cfg(unix)]
+        if let Some(libc::ENETUNREACH | libc::EHOSTUNREACH) = err.raw_os_error() {
+            return Self::Unreachable;
         }
+        Self::Other
     }
+}
 
-    fn into_iter(self) -> impl Iterator<Item = config::Endpoint> {
-        self.last_endpoints.into_iter()
+/// Records a failed UDP send to `addr` in the metrics.
+fn record_udp_send_error(addr: SocketAddr, err: &io::Error) {
+    if addr.is_ipv6() {
+        inc!(MagicsockMetrics, send_ipv6_error);
+    } else {
+        inc!(MagicsockMetrics, send_ipv4_error);
     }
+    match UdpSendError::classify(err) {
+        UdpSendError::Denied => inc!(MagicsockMetrics, send_udp_error_denied),
+        UdpSendError::Unreachable => inc!(MagicsockMetrics, send_udp_error_unreachable),
+        UdpSendError::Other => {}
+    }
+}
 
-    fn iter(&self) -> impl Iterator<Item = &config::Endpoint> + '_ {
-        self.last_endpoints.iter()
+/// Transmits staged for a single node, oldest first.
+#[derive(Debug, Default)]
+struct StagedTransmits(VecDeque<(Instant, quinn_udp::Transmit)>);
+
+impl StagedTransmits {
+    fn len(&self) -> usize {
+        self.0.len()
     }
 
     fn is_empty(&self) -> bool {
-        self.last_endpoints.is_empty()
+        self.0.is_empty()
     }
 
-    fn fresh_enough(&self) -> bool {
-        match self.last_endpoints_time.as_ref() {
-            None => false,
-            Some(time) => time.elapsed() <= ENDPOINTS_FRESH_ENOUGH_DURATION,
-        }
+    fn push_back(&mut self, staged_at: Instant, transmit: quinn_udp::Transmit) {
+        self.0.push_back((staged_at, transmit));
+    }
+
+    fn pop_front(&mut self) -> Option<quinn_udp::Transmit> {
+        self.0.pop_front().map(|(_, transmit)| transmit)
+    }
+
+    /// Appends the newer transmits, keeping at most `max_packets` of the newest ones.
+    fn append(&mut self, mut newer: StagedTransmits, max_packets: usize) {
+        self.0.append(&mut newer.0);
+        while self.0.len() > max_packets {
+            self.0.pop_front();
+            inc!(MagicsockMetrics, send_data_staged_dropped);
+        }
+    }
+
+    /// Removes all transmits older than `max_age`, returning how many were removed.
+    fn prune(&mut self, now: Instant, max_age: Duration) -> usize {
+        let before = self.0.len();
+        self.0
+            .retain(|(staged_at, _)| now.saturating_duration_since(*staged_at) <= max_age);
+        before - self.0.len()
+    }
+
+    fn into_transmits(self) -> Vec<quinn_udp::Transmit> {
+        self.0.into_iter().map(|(_, transmit)| transmit).collect()
+    }
+}
+
+#[derive(derive_more::Debug, Default, Clone)]
+struct DiscoveredEndpoints {
+    /// Records the endpoints found during the previous
+    /// endpoint discovery. It's used to avoid duplicate endpoint change notifications.
+    last_endpoints: Vec<config::Endpoint>,
+
+    /// The last time the endpoints were updated, even if there was no change.
+    last_endpoints_time: Option<Instant>,
+}
+
+impl PartialEq for DiscoveredEndpoints {
+    fn eq(&self, other: &Self) -> bool {
+        endpoint_sets_equal(&self.last_endpoints, &other.last_endpoints)
+    }
+}
+
+impl DiscoveredEndpoints {
+    fn new(endpoints: Vec<config::Endpoint>) -> Self {
+        Self {
+            last_endpoints: endpoints,
+            last_endpoints_time: Some(Instant::now()),
+        }
+    }
+
+    fn into_iter(self) -> impl Iterator<Item = config::Endpoint> {
+        self.last_endpoints.into_iter()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &config::Endpoint> + '_ {
+        self.last_endpoints.iter()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.last_endpoints.is_empty()
+    }
+
+    fn fresh_enough(&self) -> bool {
+        match self.last_endpoints_time.as_ref() {
+            None => false,
+            Some(time) => time.elapsed() <= ENDPOINTS_FRESH_ENOUGH_DURATION,
+        }
     }
 
     fn to_call_me_maybe_message(&self) -> disco::CallMeMaybe {
@@ -2435,13 +4988,55 @@ impl DiscoveredEndpoints {
     }
 }
 
+/// Returns the leading transmits which share the destination of the first one.
+///
+/// This is a subslice of `transmits`, so grouping the transmits does not allocate.
+fn first_destination_group(transmits: &[quinn_udp::Transmit]) -> &[quinn_udp::Transmit] {
+    let Some(first) = transmits.first() else {
+        return transmits;
+    };
+    let n = transmits
+        .iter()
+        .position(|transmit| transmit.destination != first.destination)
+        .unwrap_or(transmits.len());
+    &transmits[..n]
+}
+
+/// Copies the transmits into `buf`, addressed to the UDP `addr`.
+///
+/// The transmits passed to `poll_send` are addressed to the [`QuicMappedAddr`] of the node,
+/// so they need to be rewritten before sending them over UDP.  The buffer is reused across
+/// calls to avoid allocating, and the contents are shared with the original transmits.
+pub(crate) fn address_transmits(
+    buf: &mut Vec<quinn_udp::Transmit>,
+    transmits: &[quinn_udp::Transmit],
+    addr: SocketAddr,
+) {
+    buf.clear();
+    buf.extend(transmits.iter().map(|transmit| quinn_udp::Transmit {
+        destination: addr,
+        ..transmit.clone()
+    }));
+}
+
 /// Split a number of transmits into individual packets.
 ///
 /// For each transmit, if it has a segment size, it will be split into
 /// multiple packets according to that segment size. If it does not have a
 /// segment size, the contents will be sent as a single packet.
-fn split_packets(transmits: &[quinn_udp::Transmit]) -> RelayContents {
-    let mut res = SmallVec::with_capacity(transmits.len());
+///
+/// The packets share the buffers of the transmits, no contents are copied.
+pub(crate) fn split_packets(transmits: &[quinn_udp::Transmit]) -> RelayContents {
+    let count = transmits
+        .iter()
+        .map(|transmit| match transmit.segment_size {
+            Some(segment_size) if segment_size > 0 => {
+                transmit.contents.len().div_ceil(segment_size)
+            }
+            _ => 1,
+        })
+        .sum();
+    let mut res = SmallVec::with_capacity(count);
     for transmit in transmits {
         let contents = &transmit.contents;
         if let Some(segment_size) = transmit.segment_size {
@@ -2515,9 +5110,6 @@ impl Iterator for PacketSplitIter {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct QuicMappedAddr(SocketAddr);
 
-/// Counter to always generate unique addresses for [`QuicMappedAddr`].
-static ADDR_COUNTER: AtomicU64 = AtomicU64::new(1);
-
 impl QuicMappedAddr {
     /// The Prefix/L of our Unique Local Addresses.
     const ADDR_PREFIXL: u8 = 0xfd;
@@ -2526,20 +5118,38 @@ impl QuicMappedAddr {
     /// The Subnet ID used in our Unique Local Addresses.
     const ADDR_SUBNET: [u8; 2] = [0; 2];
 
-    /// Generates a globally unique fake UDP address.
+    /// Returns the fake UDP address of the endpoint with `id` in the node map.
     ///
-    /// This generates and IPv6 Unique Local Address according to RFC 4193.
-    pub(crate) fn generate() -> Self {
+    /// This is an IPv6 Unique Local Address according to RFC 4193.  The ids, and with them
+    /// the addresses, are unique within the node map of one [`MagicSock`], so several
+    /// sockets in one process do not share any state.
+    pub(crate) fn for_endpoint(id: usize) -> Self {
         let mut addr = [0u8; 16];
         addr[0] = Self::ADDR_PREFIXL;
         addr[1..6].copy_from_slice(&Self::ADDR_GLOBAL_ID);
         addr[6..8].copy_from_slice(&Self::ADDR_SUBNET);
-
-        let counter = ADDR_COUNTER.fetch_add(1, Ordering::Relaxed);
-        addr[8..16].copy_from_slice(&counter.to_be_bytes());
+        addr[8..16].copy_from_slice(&(id as u64).to_be_bytes());
 
         Self(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(addr)), 12345))
     }
+
+    /// Returns the id of the endpoint this is the address of, see [`Self::for_endpoint`].
+    ///
+    /// `None` if this is not a mapped address.
+    pub(crate) fn endpoint_id(&self) -> Option<usize> {
+        let IpAddr::V6(ip) = self.0.ip() else {
+            return None;
+        };
+        let addr = ip.octets();
+        let is_mapped = addr[0] == Self::ADDR_PREFIXL
+            && addr[1..6] == Self::ADDR_GLOBAL_ID
+            && addr[6..8] == Self::ADDR_SUBNET;
+        if !is_mapped {
+            return None;
+        }
+        let id = u64::from_be_bytes(addr[8..16].try_into().expect("8 bytes"));
+        Some(id as usize)
+    }
 }
 
 impl std::fmt::Display for QuicMappedAddr {
@@ -2558,6 +5168,12 @@ fn disco_message_sent(msg: &disco::Message) {
         disco::Message::CallMeMaybe(_) => {
             inc!(MagicsockMetrics, sent_disco_call_me_maybe);
         }
+        disco::Message::Goodbye(_) => {
+            inc!(MagicsockMetrics, sent_disco_goodbye);
+        }
+        disco::Message::Challenge(_) => {
+            inc!(MagicsockMetrics, sent_disco_challenge);
+        }
     }
 }
 
@@ -2645,7 +5261,7 @@ pub(crate) mod tests {
                         direct_addresses: new_eps.iter().map(|ep| ep.addr).collect(),
                     },
                 };
-                m.endpoint.magic_sock().add_node_addr(addr);
+                m.endpoint.magic_sock().add_node_addr(addr).ok();
             }
         }
 
@@ -3281,6 +5897,78 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_udp_send_error_classify() {
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(UdpSendError::classify(&err), UdpSendError::Denied);
+        #[cfg(unix)]
+        {
+            let err = io::Error::from_raw_os_error(libc::ENETUNREACH);
+            assert_eq!(UdpSendError::classify(&err), UdpSendError::Unreachable);
+            let err = io::Error::from_raw_os_error(libc::EHOSTUNREACH);
+            assert_eq!(UdpSendError::classify(&err), UdpSendError::Unreachable);
+            let err = io::Error::from_raw_os_error(libc::EPERM);
+            assert_eq!(UdpSendError::classify(&err), UdpSendError::Denied);
+        }
+        let err = io::Error::from(io::ErrorKind::WouldBlock);
+        assert_eq!(UdpSendError::classify(&err), UdpSendError::Other);
+    }
+
+    #[test]
+    fn test_timeouts_scaled() {
+        let timeouts = Timeouts::default().scaled(3.0);
+        assert_eq!(timeouts.ping, PING_TIMEOUT_DURATION * 3);
+        assert_eq!(timeouts.netcheck_report, Duration::from_secs(30));
+        assert_eq!(timeouts.relay_dial, Duration::from_millis(4500));
+        assert_eq!(timeouts.relay_connect, Duration::from_secs(30));
+        assert_eq!(timeouts.relay_ping, Duration::from_secs(15));
+        assert_eq!(Timeouts::default().scaled(1.0), Timeouts::default());
+    }
+
+    #[tokio::test]
+    async fn test_buffers_validate() {
+        let buffers = BufferConfig {
+            relay_actor: 0,
+            ..Default::default()
+        };
+        let res = MagicSock::new(Options {
+            buffers,
+            ..Default::default()
+        })
+        .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_re_stun_timer_seeded() {
+        let period = |seed| new_re_stun_timer(&mut StdRng::seed_from_u64(seed), true).period();
+        assert_eq!(period(1), period(1));
+        assert!((Duration::from_secs(20)..=Duration::from_secs(26)).contains(&period(1)));
+    }
+
+    #[test]
+    fn test_first_destination_group() {
+        fn mk_transmit(destination: &str) -> quinn_udp::Transmit {
+            quinn_udp::Transmit {
+                destination: destination.parse().unwrap(),
+                ecn: None,
+                contents: Bytes::from_static(b"hello"),
+                segment_size: None,
+                src_ip: None,
+            }
+        }
+        let a = mk_transmit("127.0.0.1:1");
+        let b = mk_transmit("127.0.0.1:2");
+
+        assert!(first_destination_group(&[]).is_empty());
+        assert_eq!(first_destination_group(&[a.clone(), a.clone()]).len(), 2);
+        let transmits = [a.clone(), a.clone(), b.clone(), a];
+        let group = first_destination_group(&transmits);
+        assert_eq!(group.len(), 2);
+        assert!(std::ptr::eq(group.as_ptr(), transmits.as_ptr()));
+        assert_eq!(first_destination_group(&[b.clone(), b]).len(), 2);
+    }
+
     #[test]
     fn test_split_packets() {
         fn mk_transmit(contents: &[u8], segment_size: Option<usize>) -> quinn_udp::Transmit {
@@ -3332,6 +6020,843 @@ pub(crate) mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_close_with_actor_backlog() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        // Fill the actor inbox.
+        while ms
+            .inner
+            .actor_sender
+            .try_send(ActorMessage::FlushStagedTransmits(
+                QuicMappedAddr::for_endpoint(0),
+            ))
+            .is_ok()
+        {}
+
+        time::timeout(Duration::from_secs(1), ms.close())
+            .await
+            .context("close blocked by actor backlog")??;
+        assert!(matches!(
+            ms.inner.actor_sender.try_send(ActorMessage::NetworkChange),
+            Err(mpsc::error::TrySendError::Closed(_))
+        ));
+        Ok(())
+    }
+
+    /// A port mapper which reports a fixed external address.
+    struct FakePortMapper(Option<SocketAddrV4>);
+
+    impl PortMapper for FakePortMapper {
+        fn external_address(&self) -> Option<SocketAddrV4> {
+            self.0
+        }
+    }
+
+    /// Netcheck reports for the network scenarios the endpoint determination handles.
+    enum FakeNetcheck {
+        /// UDP works and a STUN server reported our public IPv4 address.
+        Ipv4 { global: &'static str },
+        /// Behind a NAT which maps to a different port per destination.
+        HardNat { global: &'static str },
+        /// Only IPv6 works.
+        Ipv6Only { global: &'static str },
+        /// UDP is blocked, no STUN server could be reached.
+        UdpBlocked,
+    }
+
+    impl FakeNetcheck {
+        fn report(&self) -> netcheck::Report {
+            match self {
+                Self::Ipv4 { global } => netcheck::Report {
+                    udp: true,
+                    ipv4: true,
+                    ipv4_can_send: true,
+                    global_v4: Some(global.parse().unwrap()),
+                    mapping_varies_by_dest_ip: Some(false),
+                    ..Default::default()
+                },
+                Self::HardNat { global } => netcheck::Report {
+                    mapping_varies_by_dest_ip: Some(true),
+                    ..Self::Ipv4 { global }.report()
+                },
+                Self::Ipv6Only { global } => netcheck::Report {
+                    udp: true,
+                    ipv6: true,
+                    ipv6_can_send: true,
+                    os_has_ipv6: true,
+                    global_v6: Some(global.parse().unwrap()),
+                    ..Default::default()
+                },
+                Self::UdpBlocked => netcheck::Report::default(),
+            }
+        }
+    }
+
+    fn local_ips(regular: &[&str]) -> LocalAddresses {
+        LocalAddresses {
+            loopback: vec!["127.0.0.1".parse().unwrap()],
+            regular: regular.iter().map(|ip| ip.parse().unwrap()).collect(),
+        }
+    }
+
+    fn endpoints(eps: Vec<config::Endpoint>) -> Vec<(String, config::EndpointType)> {
+        eps.into_iter()
+            .map(|ep| (ep.addr.to_string(), ep.typ))
+            .collect()
+    }
+
+    #[test]
+    fn test_determine_endpoints() {
+        use config::EndpointType::*;
+        let any_v4 = Some("0.0.0.0:5000".parse().unwrap());
+        let any_v6 = Some("[::]:5001".parse().unwrap());
+        let no_portmap = FakePortMapper(None);
+
+        // Port mapping available.
+        let eps = determine_endpoints(
+            &FakePortMapper(Some("203.0.113.1:1000".parse().unwrap())),
+            Some(
+                &FakeNetcheck::Ipv4 {
+                    global: "203.0.113.1:2000",
+                }
+                .report(),
+            ),
+            any_v4,
+            None,
+            local_ips(&["192.168.1.2"]),
+            0,
+        );
+        assert_eq!(
+            endpoints(eps),
+            [
+                ("203.0.113.1:1000".to_string(), Portmapped),
+                ("203.0.113.1:2000".to_string(), Stun),
+                ("192.168.1.2:5000".to_string(), Local),
+            ]
+        );
+
+        // A hard NAT with a fixed local port.
+        let eps = determine_endpoints(
+            &no_portmap,
+            Some(
+                &FakeNetcheck::HardNat {
+                    global: "203.0.113.1:2000",
+                }
+                .report(),
+            ),
+            any_v4,
+            None,
+            local_ips(&["192.168.1.2"]),
+            5000,
+        );
+        assert_eq!(
+            endpoints(eps),
+            [
+                ("203.0.113.1:2000".to_string(), Stun),
+                ("203.0.113.1:5000".to_string(), Stun4LocalPort),
+                ("192.168.1.2:5000".to_string(), Local),
+            ]
+        );
+
+        // IPv6 only.
+        let eps = determine_endpoints(
+            &no_portmap,
+            Some(
+                &FakeNetcheck::Ipv6Only {
+                    global: "[2001:db8::1]:3000",
+                }
+                .report(),
+            ),
+            any_v4,
+            any_v6,
+            local_ips(&["2001:db8::2"]),
+            0,
+        );
+        assert_eq!(
+            endpoints(eps),
+            [
+                ("[2001:db8::1]:3000".to_string(), Stun),
+                ("[2001:db8::2]:5001".to_string(), Local),
+            ]
+        );
+
+        // UDP blocked, only the local addresses are known.
+        let eps = determine_endpoints(
+            &no_portmap,
+            Some(&FakeNetcheck::UdpBlocked.report()),
+            any_v4,
+            any_v6,
+            local_ips(&["192.168.1.2"]),
+            0,
+        );
+        assert_eq!(endpoints(eps), [("192.168.1.2:5000".to_string(), Local)]);
+
+        // Without any interfaces the loopback addresses are used.
+        let eps = determine_endpoints(&no_portmap, None, any_v4, None, local_ips(&[]), 0);
+        assert_eq!(endpoints(eps), [("127.0.0.1:5000".to_string(), Local)]);
+
+        // Bound to a specific address, only that one is used.
+        let eps = determine_endpoints(
+            &no_portmap,
+            None,
+            Some("192.168.1.2:5000".parse().unwrap()),
+            None,
+            local_ips(&["192.168.1.2", "10.0.0.2"]),
+            5000,
+        );
+        assert_eq!(endpoints(eps), [("192.168.1.2:5000".to_string(), Local)]);
+    }
+
+    #[test]
+    fn test_determine_endpoints_portmap_expired() {
+        use config::EndpointType::*;
+        let any_v4 = Some("0.0.0.0:5000".parse().unwrap());
+        let report = FakeNetcheck::Ipv4 {
+            global: "203.0.113.1:2000",
+        }
+        .report();
+
+        let mapped = FakePortMapper(Some("203.0.113.1:1000".parse().unwrap()));
+        let eps = determine_endpoints(&mapped, Some(&report), any_v4, None, local_ips(&[]), 0);
+        assert_eq!(eps[0].typ, Portmapped);
+
+        // Re-determining from the same report withdraws only the port mapped endpoint.
+        let expired = FakePortMapper(None);
+        let eps = determine_endpoints(&expired, Some(&report), any_v4, None, local_ips(&[]), 0);
+        assert_eq!(endpoints(eps), [("203.0.113.1:2000".to_string(), Stun)]);
+    }
+
+    #[test]
+    fn test_local_addr_source() {
+        let (tx, interfaces) = sync::watch::channel(local_ips(&["192.168.1.2"]));
+        let source = LocalAddrSource::Interfaces;
+        assert_eq!(
+            source.local_addresses(&interfaces),
+            local_ips(&["192.168.1.2"])
+        );
+        tx.send_replace(local_ips(&["10.0.0.2"]));
+        assert_eq!(
+            source.local_addresses(&interfaces),
+            local_ips(&["10.0.0.2"])
+        );
+
+        // Static addresses do not follow the interfaces.
+        let ips = vec!["203.0.113.1".parse().unwrap()];
+        let source = LocalAddrSource::Static(ips.clone());
+        let addrs = source.local_addresses(&interfaces);
+        assert_eq!(addrs.regular, ips);
+        assert!(addrs.loopback.is_empty());
+    }
+
+    #[test]
+    fn test_demote_reflexive_v4_endpoints() {
+        let ep = |addr: &str, typ| config::Endpoint {
+            addr: addr.parse().unwrap(),
+            typ,
+        };
+        let mut eps = vec![
+            ep("203.0.113.1:1234", config::EndpointType::Stun),
+            ep("203.0.113.1:4321", config::EndpointType::Stun4LocalPort),
+            ep("[2001:db8::1]:1234", config::EndpointType::Stun),
+            ep("192.168.1.2:1234", config::EndpointType::Local),
+            ep("10.0.0.2:1234", config::EndpointType::Local),
+        ];
+        demote_reflexive_v4_endpoints(&mut eps);
+        let addrs: Vec<_> = eps.iter().map(|ep| ep.addr.to_string()).collect();
+        assert_eq!(
+            addrs,
+            [
+                "[2001:db8::1]:1234",
+                "192.168.1.2:1234",
+                "10.0.0.2:1234",
+                "203.0.113.1:1234",
+                "203.0.113.1:4321",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_netcheck_sockets() {
+        let runs = |sockets: NetcheckSockets| {
+            (0..6)
+                .filter(|run| sockets.use_main_sockets(*run))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(runs(NetcheckSockets::Main), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(runs(NetcheckSockets::Ephemeral { main_every: 3 }), [0, 3]);
+        assert_eq!(
+            runs(NetcheckSockets::Ephemeral { main_every: 0 }),
+            [0, 1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_coalesced_update_keeps_first_reason() {
+        let state = EndpointUpdateState::new();
+        state.schedule_run("first".into());
+        assert_eq!(state.running.borrow().as_deref(), Some("first"));
+        state.schedule_run("second".into());
+        state.schedule_run("third".into());
+        assert_eq!(state.next_update().as_deref(), Some("second"));
+        assert_eq!(state.next_update(), None);
+    }
+
+    #[tokio::test]
+    async fn test_trigger_endpoint_update() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let mut updates = ms.endpoint_updates();
+        let reason = format!("user request {}", 1);
+        ms.trigger_endpoint_update(reason.clone())?;
+
+        // Skip the updates the socket started itself.
+        let update = time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = updates.next().await.expect("stream ended").unwrap();
+                if update.reason == reason {
+                    break update;
+                }
+            }
+        })
+        .await
+        .context("timeout")?;
+        assert!(update.duration < Duration::from_secs(5));
+        assert!(!update.endpoints.is_empty());
+
+        ms.close().await?;
+        assert!(time::timeout(Duration::from_secs(1), updates.next())
+            .await?
+            .is_none());
+        assert_eq!(ms.trigger_endpoint_update("closed"), Err(ClosedError));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_node_events() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let mut events = ms.subscribe();
+        let node_id = SecretKey::generate().public();
+        ms.add_node_addr(
+            NodeAddr::new(node_id).with_direct_addresses(["127.0.0.1:4433".parse().unwrap()]),
+        )?;
+        assert!(ms.remove_node(&node_id));
+
+        // Skip the events about our own addresses.
+        let node_events = time::timeout(Duration::from_secs(5), async {
+            let mut node_events = Vec::new();
+            while node_events.len() < 2 {
+                let event = events.next().await.expect("stream ended").unwrap();
+                if matches!(
+                    event,
+                    MagicSockEvent::NodeAdded(_) | MagicSockEvent::NodeRemoved(_)
+                ) {
+                    node_events.push(event);
+                }
+            }
+            node_events
+        })
+        .await
+        .context("timeout")?;
+        assert_eq!(
+            node_events,
+            [
+                MagicSockEvent::NodeAdded(node_id),
+                MagicSockEvent::NodeRemoved(node_id)
+            ]
+        );
+
+        ms.close().await?;
+        assert!(time::timeout(Duration::from_secs(1), events.next())
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inject_recv_send_tap() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let a = MagicSock::new(Default::default()).await?;
+        let b = MagicSock::new(Default::default()).await?;
+        let a_id = a.inner.public_key();
+        let b_id = b.inner.public_key();
+        // Neither knows an address of the other.
+        a.add_node_addr(NodeAddr::new(b_id))?;
+        b.add_node_addr(NodeAddr::new(a_id))?;
+
+        let mut tap = a.send_tap();
+        let transmit = quinn_udp::Transmit {
+            destination: a.get_mapping_addr(&b_id).context("no mapping addr")?,
+            ecn: None,
+            contents: Bytes::from_static(b"hello"),
+            segment_size: None,
+            src_ip: None,
+        };
+        let transmits = [transmit];
+        let sent = futures::future::poll_fn(|cx| a.inner.poll_send(cx, &transmits)).await?;
+        assert_eq!(sent, 1);
+        // Sent to the tap only, instead of staged until a path is known.
+        assert!(a.inner.staged_transmits.lock().is_empty());
+        let datagram = time::timeout(Duration::from_secs(1), tap.next())
+            .await?
+            .context("tap ended")?;
+        assert_eq!(
+            datagram,
+            TappedDatagram {
+                node_id: b_id,
+                contents: transmits[0].contents.clone(),
+            }
+        );
+
+        b.inject_recv(NodeOrAddr::Node(a_id), datagram.contents)?;
+        let mut buf = [0u8; 1500];
+        let mut metas = [quinn_udp::RecvMeta::default()];
+        let n = time::timeout(
+            Duration::from_secs(1),
+            futures::future::poll_fn(|cx| {
+                let mut bufs = [io::IoSliceMut::new(&mut buf)];
+                b.inner.poll_recv(cx, &mut bufs, &mut metas)
+            }),
+        )
+        .await??;
+        assert_eq!(n, 1);
+        assert_eq!(Some(metas[0].addr), b.get_mapping_addr(&a_id));
+        assert_eq!(&buf[..metas[0].len], b"hello");
+
+        let unknown = SecretKey::generate().public();
+        assert_eq!(
+            b.inject_recv(NodeOrAddr::Node(unknown), Bytes::from_static(b"hello")),
+            Err(InjectError::UnknownNode(unknown))
+        );
+        let addr: SocketAddr = "127.0.0.1:1".parse()?;
+        assert_eq!(
+            b.inject_recv(NodeOrAddr::Addr(addr), Bytes::from_static(b"hello")),
+            Err(InjectError::UnknownAddr(addr))
+        );
+
+        // Without the tap, the transmits are staged again.
+        drop(tap);
+        futures::future::poll_fn(|cx| a.inner.poll_send(cx, &transmits)).await?;
+        assert!(!a.inner.staged_transmits.lock().is_empty());
+
+        a.close().await?;
+        b.close().await?;
+        assert_eq!(
+            b.inject_recv(NodeOrAddr::Node(a_id), Bytes::from_static(b"hello")),
+            Err(InjectError::Closed)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_offline() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let node_id = SecretKey::generate().public();
+        ms.add_node_addr(NodeAddr::new(node_id).with_direct_addresses(["127.0.0.1:1".parse()?]))?;
+        assert!(ms.is_online());
+
+        async fn wait_online(ms: &MagicSock, online: bool) {
+            time::timeout(Duration::from_secs(1), async {
+                while ms.is_online() != online {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("timeout");
+        }
+
+        ms.set_offline(true).await?;
+        wait_online(&ms, false).await;
+        // The state is kept while offline.
+        assert!(ms.tracked_endpoint(node_id).is_some());
+
+        ms.set_offline(false).await?;
+        wait_online(&ms, true).await;
+        assert!(ms.tracked_endpoint(node_id).is_some());
+
+        ms.close().await?;
+        assert!(!ms.is_online());
+        assert_eq!(ms.set_offline(true).await, Err(ClosedError));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_suspend_resume() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let node_id = SecretKey::generate().public();
+        ms.add_node_addr(NodeAddr::new(node_id).with_direct_addresses(["127.0.0.1:1".parse()?]))?;
+
+        // Both return once done.
+        ms.suspend().await?;
+        assert!(!ms.is_online());
+        assert!(ms.tracked_endpoint(node_id).is_some());
+        ms.resume().await?;
+        assert!(ms.is_online());
+        assert!(ms.tracked_endpoint(node_id).is_some());
+
+        // Resuming without suspending first is fine.
+        ms.resume().await?;
+        assert!(ms.is_online());
+
+        ms.close().await?;
+        assert_eq!(ms.suspend().await, Err(ClosedError));
+        assert_eq!(ms.resume().await, Err(ClosedError));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_udp_proxy_unusable() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        // Nothing listens on this address anymore.
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let ms = MagicSock::new(Options {
+            udp_proxy: Some(Socks5Config::new(proxy)),
+            ..Default::default()
+        })
+        .await?;
+        assert!(ms.inner.udp_blocked());
+        assert!(ms.inner.pconn6.get().is_none());
+        ms.close().await?;
+
+        let ms = MagicSock::new(Default::default()).await?;
+        assert!(!ms.inner.udp_blocked());
+        ms.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_addr_filter() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        #[derive(Debug)]
+        struct NoLoopback;
+
+        impl AddrFilter for NoLoopback {
+            fn filter(&self, _node: &PublicKey, addr: SocketAddr) -> Option<SocketAddr> {
+                (!addr.ip().is_loopback()).then_some(addr)
+            }
+        }
+
+        let ms = MagicSock::new(Options {
+            addr_filter: Some(Box::new(NoLoopback)),
+            ..Default::default()
+        })
+        .await?;
+        let node_id = SecretKey::generate().public();
+        let addr: SocketAddr = "203.0.113.1:1234".parse()?;
+        ms.add_node_addr(
+            NodeAddr::new(node_id).with_direct_addresses([addr, "127.0.0.1:1234".parse()?]),
+        )?;
+        let info = ms.tracked_endpoint(node_id).expect("node added");
+        let addrs: Vec<_> = info.addrs.iter().map(|info| info.addr).collect();
+        assert_eq!(addrs, [addr]);
+        ms.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_challenge_unknown_senders() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let endpoint = |challenge| {
+            MagicEndpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![ALPN.to_vec()])
+                .challenge_unknown_senders(challenge)
+                .bind(0)
+        };
+        let server = endpoint(true).await?;
+        let client = endpoint(false).await?;
+        let port = server.local_addr()?.0.port();
+        let server_addr = NodeAddr::new(server.node_id())
+            .with_direct_addresses([SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port))]);
+
+        // The server does not know the client, so the client's pings are challenged before
+        // the connection can be established.
+        let accept = tokio::spawn({
+            let server = server.clone();
+            async move { anyhow::Ok(server.accept().await.context("closed")?.await?) }
+        });
+        let conn = time::timeout(Duration::from_secs(10), client.connect(server_addr, ALPN))
+            .await
+            .context("timeout connecting")??;
+        let _server_conn = time::timeout(Duration::from_secs(10), accept).await???;
+        assert!(server
+            .magic_sock()
+            .tracked_endpoint(client.node_id())
+            .is_some());
+
+        conn.close(0u32.into(), b"done");
+        client.close(0u32.into(), b"done").await?;
+        server.close(0u32.into(), b"done").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recv_ipv6_under_ipv4_load() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        if !crate::netcheck::os_has_ipv6() {
+            return Ok(());
+        }
+
+        let endpoint = || {
+            MagicEndpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![ALPN.to_vec()])
+                .bind(0)
+        };
+        let server = endpoint().await?;
+        let client = endpoint().await?;
+        let (addr4, Some(addr6)) = server.local_addr()? else {
+            return Ok(());
+        };
+
+        // Saturate the IPv4 socket of the server with datagrams it drops.
+        let flood_sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let flood_dst = SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, addr4.port()));
+        let flood = tokio::spawn(async move {
+            let junk = [0x42u8; 1200];
+            loop {
+                flood_sock.send_to(&junk, flood_dst).await.ok();
+            }
+        });
+
+        // The connection over IPv6 still gets through.
+        let server_addr =
+            NodeAddr::new(server.node_id()).with_direct_addresses([SocketAddr::from((
+                std::net::Ipv6Addr::LOCALHOST,
+                addr6.port(),
+            ))]);
+        let accept = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.context("closed")?.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let msg = recv.read_to_end(100).await?;
+                send.write_all(&msg).await?;
+                send.finish().await?;
+                anyhow::Ok(conn)
+            }
+        });
+        let conn = time::timeout(Duration::from_secs(10), client.connect(server_addr, ALPN))
+            .await
+            .context("timeout connecting")??;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        let echo = time::timeout(Duration::from_secs(10), recv.read_to_end(100))
+            .await
+            .context("timeout echoing")??;
+        assert_eq!(echo, b"hello");
+        let _server_conn = time::timeout(Duration::from_secs(10), accept).await???;
+        flood.abort();
+
+        conn.close(0u32.into(), b"done");
+        client.close(0u32.into(), b"done").await?;
+        server.close(0u32.into(), b"done").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_disco_shards() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+
+        let endpoint = || {
+            MagicEndpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![ALPN.to_vec()])
+                .disco_shards(2)
+                .bind(0)
+        };
+        let server = endpoint().await?;
+        let client = endpoint().await?;
+        let port = server.local_addr()?.0.port();
+        let server_addr = NodeAddr::new(server.node_id())
+            .with_direct_addresses([SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port))]);
+
+        let accept = tokio::spawn({
+            let server = server.clone();
+            async move { anyhow::Ok(server.accept().await.context("closed")?.await?) }
+        });
+        let conn = time::timeout(Duration::from_secs(10), client.connect(server_addr, ALPN))
+            .await
+            .context("timeout connecting")??;
+        let _server_conn = time::timeout(Duration::from_secs(10), accept).await???;
+
+        // The pongs handled on the shards confirm the direct path.
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                let info = client.magic_sock().tracked_endpoint(server.node_id());
+                if info.is_some_and(|info| matches!(info.conn_type, ConnectionType::Direct(_))) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("no direct path")?;
+
+        conn.close(0u32.into(), b"done");
+        client.close(0u32.into(), b"done").await?;
+        // The shard tasks stop with the socket.
+        time::timeout(Duration::from_secs(1), server.close(0u32.into(), b"done"))
+            .await
+            .context("timeout closing")??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_api_after_close() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let node_id = SecretKey::generate().public();
+        ms.add_node_addr(NodeAddr::new(node_id).with_direct_addresses(["127.0.0.1:1".parse()?]))?;
+        ms.close().await?;
+
+        // Closing again is fine.
+        ms.close().await?;
+
+        // Acting on the socket fails.
+        assert_eq!(ms.re_stun("test"), Err(ClosedError));
+        assert_eq!(ms.add_node_addr(NodeAddr::new(node_id)), Err(ClosedError));
+        assert_eq!(ms.network_change().await, Err(ClosedError));
+        let err = ms.local_addr().unwrap_err();
+        assert_eq!(err.downcast_ref::<ClosedError>(), Some(&ClosedError));
+        let err = ms.conn_type_stream(&node_id).unwrap_err();
+        assert_eq!(err.downcast_ref::<ClosedError>(), Some(&ClosedError));
+
+        // Reporting state keeps working.
+        assert!(ms.tracked_endpoint(node_id).is_some());
+        assert_eq!(ms.tracked_endpoints().len(), 1);
+        assert!(ms.get_mapping_addr(&node_id).is_some());
+        ms.my_relay();
+        ms.relay_latencies();
+        ms.max_transmit_segments();
+        time::timeout(
+            Duration::from_secs(1),
+            ms.local_endpoints().collect::<Vec<_>>(),
+        )
+        .await
+        .context("local endpoints stream did not end")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stage_transmits_without_path() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let node_id = SecretKey::generate().public();
+        ms.add_node_addr(NodeAddr::new(node_id))?;
+        let dest = ms.get_mapping_addr(&node_id).context("no mapping addr")?;
+
+        let transmits: Vec<_> = (0..20u8)
+            .map(|i| quinn_udp::Transmit {
+                destination: dest,
+                ecn: None,
+                contents: Bytes::from(vec![i; 8]),
+                segment_size: None,
+                src_ip: None,
+            })
+            .collect();
+        let mut sent = 0;
+        while sent < transmits.len() {
+            sent +=
+                futures::future::poll_fn(|cx| ms.inner.poll_send(cx, &transmits[sent..])).await?;
+        }
+
+        // Only the newest packets are kept.
+        let max_packets = FirstPacketPolicy::default().max_packets;
+        let first_kept = (transmits.len() - max_packets) as u8;
+        assert_eq!(
+            ms.inner.staged_transmits.lock()[&QuicMappedAddr(dest)].len(),
+            max_packets
+        );
+
+        // Once a path is known the staged packets are sent.
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        ms.add_node_addr(NodeAddr::new(node_id).with_direct_addresses([receiver.local_addr()?]))?;
+        let mut buf = [0u8; 1500];
+        let received = time::timeout(Duration::from_secs(5), async {
+            loop {
+                // Skip the disco pings sent to the new path.
+                let (len, _) = receiver.recv_from(&mut buf).await?;
+                if buf[..len] == [first_kept; 8] {
+                    return anyhow::Ok(());
+                }
+            }
+        })
+        .await;
+        assert!(matches!(received, Ok(Ok(()))), "staged packets not sent");
+        assert!(ms.inner.staged_transmits.lock().is_empty());
+
+        ms.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_staged_transmits_on_ping() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let ms = MagicSock::new(Default::default()).await?;
+        let node_id = SecretKey::generate().public();
+        ms.add_node_addr(NodeAddr::new(node_id))?;
+        let dest = ms.get_mapping_addr(&node_id).context("no mapping addr")?;
+        let transmit = quinn_udp::Transmit {
+            destination: dest,
+            ecn: None,
+            contents: Bytes::from_static(b"staged"),
+            segment_size: None,
+            src_ip: None,
+        };
+        futures::future::poll_fn(|cx| ms.inner.poll_send(cx, std::slice::from_ref(&transmit)))
+            .await?;
+        assert!(!ms.inner.staged_transmits.lock().is_empty());
+
+        // The node pings us first, which gives us a path to it.
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let ping = disco::Ping {
+            tx_id: stun::TransactionId::default(),
+            node_key: node_id,
+            home_relay: None,
+            cookie: None,
+        };
+        ms.inner.handle_ping(
+            ping,
+            &node_id,
+            DiscoMessageSource::Udp(receiver.local_addr()?),
+        );
+        let mut buf = [0u8; 1500];
+        let received = time::timeout(Duration::from_secs(5), async {
+            loop {
+                // Skip the pong and the pings sent to the new path.
+                let (len, _) = receiver.recv_from(&mut buf).await?;
+                if &buf[..len] == b"staged" {
+                    return anyhow::Ok(());
+                }
+            }
+        })
+        .await;
+        assert!(matches!(received, Ok(Ok(()))), "staged packets not sent");
+        assert!(ms.inner.staged_transmits.lock().is_empty());
+
+        ms.close().await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_local_endpoints() {
         let _guard = iroh_test::logging::setup();
@@ -3349,4 +6874,25 @@ pub(crate) mod tests {
         println!("{eps1:?}");
         assert_eq!(eps0, eps1);
     }
+
+    #[test]
+    fn test_runtime_option() {
+        let _guard = iroh_test::logging::setup();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // Outside of a tokio runtime we need an explicit handle.
+        let res = futures::executor::block_on(MagicSock::new(Default::default()));
+        assert!(res.is_err());
+
+        let opts = Options {
+            runtime: Some(rt.handle().clone()),
+            ..Default::default()
+        };
+        let ms = futures::executor::block_on(MagicSock::new(opts)).unwrap();
+        rt.block_on(ms.close()).unwrap();
+    }
 }
@@ -0,0 +1,255 @@
+//! The inbox of the magicsock actor, with priorities for the kinds of messages.
+//!
+//! The actor receives control messages, like network changes or going offline, messages
+//! about the disco state of paths, and the packets received from relays.  In a single queue
+//! a burst of relay packets at line rate delays the control messages behind it, e.g. a
+//! shutdown or a network change waits for thousands of packets to be processed.
+//!
+//! The [`ActorReceiver`] keeps a queue per [`MessageClass`] and prefers control over disco
+//! over data.  So that lower classes are not starved by a flood of higher ones, each class
+//! may only be served [`MessageClass::budget`] times per round.  A new round starts once no
+//! class with budget left has a message waiting.
+//!
+//! Messages are only ordered within their class.  Messages which must not overtake received
+//! packets, like the expiry of a ping whose pong may be queued, are in the data class.
+
+use tokio::sync::mpsc::{
+    self,
+    error::{SendError, TrySendError},
+};
+
+use super::ActorMessage;
+
+/// The kind of an [`ActorMessage`], in order of priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MessageClass {
+    /// Changes to the state of the socket.
+    Control = 0,
+    /// Disco and relay signals about the paths to other nodes.
+    Disco = 1,
+    /// Packets to send or received, and what must be handled in order with them.
+    Data = 2,
+}
+
+impl MessageClass {
+    const ALL: [MessageClass; 3] = [Self::Control, Self::Disco, Self::Data];
+
+    /// Number of messages of the class handled per round, when all classes are busy.
+    fn budget(self) -> usize {
+        match self {
+            Self::Control => 8,
+            Self::Disco => 16,
+            Self::Data => 32,
+        }
+    }
+}
+
+/// Creates the inbox of the actor, with room for `capacity` messages per class.
+pub(super) fn channel(capacity: usize) -> (ActorSender, ActorReceiver) {
+    let (control, control_r) = mpsc::channel(capacity);
+    let (disco, disco_r) = mpsc::channel(capacity);
+    let (data, data_r) = mpsc::channel(capacity);
+    let sender = ActorSender {
+        senders: [control, disco, data],
+    };
+    let receiver = ActorReceiver {
+        receivers: [control_r, disco_r, data_r],
+        served: [0; 3],
+    };
+    (sender, receiver)
+}
+
+/// Sends messages to the actor, into the queue of their [`MessageClass`].
+#[derive(Debug, Clone)]
+pub(super) struct ActorSender {
+    senders: [mpsc::Sender<ActorMessage>; 3],
+}
+
+impl ActorSender {
+    /// Sends `msg`, waiting for room in its queue.
+    pub(super) async fn send(&self, msg: ActorMessage) -> Result<(), SendError<ActorMessage>> {
+        self.senders[msg.class() as usize].send(msg).await
+    }
+
+    /// Sends `msg` if there is room in its queue.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn try_send(&self, msg: ActorMessage) -> Result<(), TrySendError<ActorMessage>> {
+        self.senders[msg.class() as usize].try_send(msg)
+    }
+}
+
+/// Receives the messages of the actor by priority, see the [module docs](self).
+#[derive(Debug)]
+pub(super) struct ActorReceiver {
+    receivers: [mpsc::Receiver<ActorMessage>; 3],
+    /// Messages handled per class in the current round.
+    served: [usize; 3],
+}
+
+impl ActorReceiver {
+    /// Receives the next message, waiting for one if none is queued.
+    ///
+    /// Returns `None` once all senders are gone.
+    pub(super) async fn recv(&mut self) -> Option<ActorMessage> {
+        if let Some(msg) = self.try_recv() {
+            return Some(msg);
+        }
+        let [control, disco, data] = &mut self.receivers;
+        let (class, msg) = tokio::select! {
+            biased;
+            Some(msg) = control.recv() => (MessageClass::Control, msg),
+            Some(msg) = disco.recv() => (MessageClass::Disco, msg),
+            Some(msg) = data.recv() => (MessageClass::Data, msg),
+            else => return None,
+        };
+        self.served[class as usize] += 1;
+        Some(msg)
+    }
+
+    /// Receives the next queued message, if any.
+    pub(super) fn try_recv(&mut self) -> Option<ActorMessage> {
+        if let Some(msg) = self.try_recv_within_budget() {
+            return Some(msg);
+        }
+        // All classes with messages used up their budget, start a new round.
+        self.served = [0; 3];
+        self.try_recv_within_budget()
+    }
+
+    fn try_recv_within_budget(&mut self) -> Option<ActorMessage> {
+        for class in MessageClass::ALL {
+            let i = class as usize;
+            if self.served[i] >= class.budget() {
+                continue;
+            }
+            if let Ok(msg) = self.receivers[i].try_recv() {
+                self.served[i] += 1;
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    /// Number of messages waiting in all queues.
+    pub(super) fn len(&self) -> usize {
+        self.receivers.iter().map(|r| r.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{magicsock::QuicMappedAddr, stun};
+
+    fn class_of(msg: Option<ActorMessage>) -> MessageClass {
+        msg.expect("message queued").class()
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let (sender, mut receiver) = channel(64);
+        for _ in 0..10 {
+            sender
+                .send(ActorMessage::FlushStagedTransmits(
+                    QuicMappedAddr::for_endpoint(0),
+                ))
+                .await
+                .unwrap();
+        }
+        sender
+            .send(ActorMessage::EndpointPingExpired(
+                0,
+                stun::TransactionId::default(),
+            ))
+            .await
+            .unwrap();
+        sender
+            .send(ActorMessage::RelayLatency(
+                "https://relay.example".parse().unwrap(),
+                Duration::from_millis(10),
+            ))
+            .await
+            .unwrap();
+        sender.send(ActorMessage::NetworkChange).await.unwrap();
+        assert_eq!(receiver.len(), 13);
+
+        // Queued last, but handled first.
+        assert_eq!(class_of(receiver.recv().await), MessageClass::Control);
+        assert_eq!(class_of(receiver.recv().await), MessageClass::Disco);
+        for _ in 0..10 {
+            assert_eq!(class_of(receiver.try_recv()), MessageClass::Data);
+        }
+        // The ping expires only after the packets queued before it were handled.
+        assert!(matches!(
+            receiver.try_recv(),
+            Some(ActorMessage::EndpointPingExpired(..))
+        ));
+        assert!(receiver.try_recv().is_none());
+
+        drop(sender);
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_control_latency_bounded() {
+        let (sender, mut receiver) = channel(256);
+        for _ in 0..256 {
+            sender
+                .try_send(ActorMessage::FlushStagedTransmits(
+                    QuicMappedAddr::for_endpoint(0),
+                ))
+                .unwrap();
+        }
+        // Use up the budget of control messages for this round.
+        for _ in 0..MessageClass::Control.budget() {
+            sender.try_send(ActorMessage::NetworkChange).unwrap();
+            assert_eq!(class_of(receiver.try_recv()), MessageClass::Control);
+        }
+
+        // Even so, the next one does not wait for the whole data backlog.
+        sender.try_send(ActorMessage::NetworkChange).unwrap();
+        let mut waited = 0;
+        while class_of(receiver.try_recv()) != MessageClass::Control {
+            waited += 1;
+        }
+        assert_eq!(waited, MessageClass::Data.budget());
+    }
+
+    #[tokio::test]
+    async fn test_no_starvation() {
+        let (sender, mut receiver) = channel(256);
+        let rounds = 4;
+        let control = MessageClass::Control.budget() * rounds * 2;
+        for _ in 0..control {
+            sender.try_send(ActorMessage::NetworkChange).unwrap();
+        }
+        let data = MessageClass::Data.budget() * rounds;
+        for _ in 0..data {
+            sender
+                .try_send(ActorMessage::FlushStagedTransmits(
+                    QuicMappedAddr::for_endpoint(0),
+                ))
+                .unwrap();
+        }
+
+        // While both queues are full, every round serves both classes by their budgets.
+        let mut served_data = 0;
+        let per_round = MessageClass::Control.budget() + MessageClass::Data.budget();
+        for _ in 0..per_round * rounds {
+            if class_of(receiver.try_recv()) == MessageClass::Data {
+                served_data += 1;
+            }
+        }
+        assert_eq!(served_data, data);
+
+        // The remaining control messages drain on their own.
+        let mut rest = 0;
+        while let Some(msg) = receiver.try_recv() {
+            assert_eq!(msg.class(), MessageClass::Control);
+            rest += 1;
+        }
+        assert_eq!(rest, control - MessageClass::Control.budget() * rounds);
+    }
+}
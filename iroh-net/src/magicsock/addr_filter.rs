@@ -0,0 +1,84 @@
+//! Filtering the direct addresses advertised by other nodes.
+
+use std::{fmt::Debug, net::SocketAddr};
+
+use iroh_metrics::inc;
+
+use super::metrics::Metrics as MagicsockMetrics;
+use crate::{key::PublicKey, NodeAddr};
+
+/// Filters or rewrites the direct addresses other nodes advertise, before they are probed.
+///
+/// Applied to the addresses added with [`super::MagicSock::add_node_addr`], which includes
+/// the ones found by discovery, to the addresses loaded from the stored node map and to the
+/// addresses in call-me-maybe messages.  This allows
+/// complying with network policies, e.g. by not probing private ranges when we know we are
+/// not on the same LAN as the node.
+///
+/// Addresses a node actually sends us packets from are not filtered: these are paths which
+/// already work.
+pub trait AddrFilter: Debug + Send + Sync + 'static {
+    /// Returns the address to probe for `addr` advertised by `node`, or `None` to drop it.
+    fn filter(&self, node: &PublicKey, addr: SocketAddr) -> Option<SocketAddr>;
+}
+
+/// Applies `filter` to the advertised `addrs` of `node`.
+pub(super) fn apply<I>(filter: &dyn AddrFilter, node: &PublicKey, addrs: I) -> Vec<SocketAddr>
+where
+    I: IntoIterator<Item = SocketAddr>,
+{
+    addrs
+        .into_iter()
+        .filter_map(|addr| {
+            let filtered = filter.filter(node, addr);
+            if filtered.is_none() {
+                inc!(MagicsockMetrics, advertised_addrs_filtered);
+            }
+            filtered
+        })
+        .collect()
+}
+
+/// Applies `filter` to the direct addresses of `addr`.
+pub(super) fn apply_to_node_addr(filter: &dyn AddrFilter, addr: &mut NodeAddr) {
+    let direct_addresses = std::mem::take(&mut addr.info.direct_addresses);
+    addr.info.direct_addresses = apply(filter, &addr.node_id, direct_addresses)
+        .into_iter()
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    /// Drops private addresses and rewrites one port.
+    #[derive(Debug)]
+    struct Policy;
+
+    impl AddrFilter for Policy {
+        fn filter(&self, _node: &PublicKey, mut addr: SocketAddr) -> Option<SocketAddr> {
+            match addr {
+                SocketAddr::V4(v4) if v4.ip().is_private() => None,
+                _ => {
+                    if addr.port() == 1234 {
+                        addr.set_port(4321);
+                    }
+                    Some(addr)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let node = SecretKey::generate().public();
+        let addrs = ["192.168.1.2:1234", "203.0.113.1:1234", "203.0.113.1:5678"]
+            .map(|addr| addr.parse().unwrap());
+        let filtered = apply(&Policy, &node, addrs);
+        assert_eq!(
+            filtered,
+            ["203.0.113.1:4321", "203.0.113.1:5678"].map(|addr| addr.parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Stateless cookies for admitting unknown nodes which ping us over UDP.
+//!
+//! Anyone can generate node keys, and pings over UDP can come from spoofed addresses.
+//! Answering every ping from an unknown node allocates node state, so a flood of such pings
+//! can exhaust our memory.  With [`super::Options::challenge_unknown_senders`] a ping from an
+//! unknown node is answered with a [`crate::disco::Challenge`] carrying a cookie instead, similar to
+//! the `HelloVerifyRequest` of DTLS.  The node is only admitted once it repeats the ping with
+//! the cookie, proving that it receives at the address it pings from.
+//!
+//! The cookie is a MAC over the node and its address, keyed by a secret of this process and
+//! the current time window, so no state is kept for challenged nodes.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use ring::hmac;
+
+use crate::{
+    disco::{Cookie, COOKIE_LEN},
+    key::PublicKey,
+};
+
+/// How long a cookie is valid at least.
+///
+/// A cookie is accepted in the time window it was created in and in the next one.
+const COOKIE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Creates and verifies the cookies of [`crate::disco::Challenge`]s.
+#[derive(derive_more::Debug)]
+pub(super) struct Admission {
+    #[debug(skip)]
+    key: hmac::Key,
+    start: Instant,
+}
+
+impl Admission {
+    pub(super) fn new() -> Self {
+        let secret: [u8; 32] = rand::random();
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            start: Instant::now(),
+        }
+    }
+
+    /// Creates the cookie for `node` pinging from `addr`.
+    pub(super) fn cookie(&self, node: &PublicKey, addr: SocketAddr, now: Instant) -> Cookie {
+        self.cookie_in_window(node, addr, self.window(now))
+    }
+
+    /// Whether `cookie` was created for `node` pinging from `addr` and has not expired.
+    pub(super) fn verify(
+        &self,
+        cookie: &Cookie,
+        node: &PublicKey,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> bool {
+        let window = self.window(now);
+        [Some(window), window.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .any(|window| constant_time_eq(cookie, &self.cookie_in_window(node, addr, window)))
+    }
+
+    fn window(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_secs() / COOKIE_WINDOW.as_secs()
+    }
+
+    fn cookie_in_window(&self, node: &PublicKey, addr: SocketAddr, window: u64) -> Cookie {
+        let mut ctx = hmac::Context::with_key(&self.key);
+        ctx.update(node.as_bytes());
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        ctx.update(&ip.octets());
+        ctx.update(&addr.port().to_be_bytes());
+        ctx.update(&window.to_be_bytes());
+        let tag = ctx.sign();
+        tag.as_ref()[..COOKIE_LEN]
+            .try_into()
+            .expect("tag is longer than a cookie")
+    }
+}
+
+fn constant_time_eq(a: &Cookie, b: &Cookie) -> bool {
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_cookie() {
+        let admission = Admission::new();
+        let node = SecretKey::generate().public();
+        let addr: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let now = Instant::now();
+        let cookie = admission.cookie(&node, addr, now);
+
+        assert!(admission.verify(&cookie, &node, addr, now));
+        assert!(admission.verify(&cookie, &node, addr, now + COOKIE_WINDOW));
+
+        // bound to the node, the address and the time
+        let other = SecretKey::generate().public();
+        assert!(!admission.verify(&cookie, &other, addr, now));
+        let moved: SocketAddr = "1.2.3.4:5679".parse().unwrap();
+        assert!(!admission.verify(&cookie, &node, moved, now));
+        assert!(!admission.verify(&cookie, &node, addr, now + COOKIE_WINDOW * 2));
+
+        // and to the secret of the process
+        assert!(!Admission::new().verify(&cookie, &node, addr, now));
+    }
+}
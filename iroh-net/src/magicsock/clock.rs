@@ -0,0 +1,235 @@
+//! Noticing when the system was suspended, or its wall clock jumped.
+//!
+//! The trust in paths, the ping timers and the heartbeats all use [`Instant`], which does
+//! not jump when the wall clock is set.  How it behaves across a suspend depends on the
+//! platform however: on Linux and macOS it stops while the system sleeps, so after a resume
+//! all paths are still trusted although the network has most likely changed.  On Windows it
+//! keeps running, so all timers expire at once.
+//!
+//! The [`ResumeDetector`] is checked on every heartbeat and compares how much time passed
+//! since the last one on [`Instant`] and on a clock which keeps running during a suspend.
+//! After a resume the socket handles a major network change, which re-checks the network and
+//! pings all paths again instead of trusting them.  A heartbeat which is merely late, e.g.
+//! because the actor is overloaded, advances both clocks alike and is not taken for a resume.
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// How much more time the suspend clock needs to have advanced than [`Instant`] for a resume.
+const RESUME_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// What happened between two checks of the [`ResumeDetector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ClockEvent {
+    /// Both clocks advanced as expected.
+    Normal,
+    /// The system was most likely suspended for about `slept`.
+    ///
+    /// Where the wall clock is the suspend clock, see [`suspend_clock`], a wall clock set
+    /// forward by more than [`RESUME_THRESHOLD`] looks the same.  Handling it as a resume
+    /// only costs a network check.
+    Resumed { slept: Duration },
+    /// The wall clock was set back.  Nothing depends on it, so this is only logged.
+    WallClockBack { by: Duration },
+}
+
+/// Detects suspends from the time between checks, see the [module docs](self).
+#[derive(Debug)]
+pub(super) struct ResumeDetector {
+    last: Instant,
+    last_suspend_clock: Duration,
+    last_wall: SystemTime,
+}
+
+impl ResumeDetector {
+    /// Creates a detector, which is checked regularly.
+    pub(super) fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            last_suspend_clock: suspend_clock(),
+            last_wall: SystemTime::now(),
+        }
+    }
+
+    /// Starts over from now, for when the checks were paused on purpose.
+    pub(super) fn reset(&mut self) {
+        self.last = Instant::now();
+        self.last_suspend_clock = suspend_clock();
+        self.last_wall = SystemTime::now();
+    }
+
+    /// Checks how much time passed since the last check.
+    pub(super) fn check(&mut self) -> ClockEvent {
+        self.check_at(Instant::now(), suspend_clock(), SystemTime::now())
+    }
+
+    fn check_at(
+        &mut self,
+        now: Instant,
+        now_suspend: Duration,
+        now_wall: SystemTime,
+    ) -> ClockEvent {
+        let elapsed = now.saturating_duration_since(self.last);
+        let elapsed_suspend = now_suspend.saturating_sub(self.last_suspend_clock);
+        let elapsed_wall = now_wall.duration_since(self.last_wall);
+        self.last = now;
+        self.last_suspend_clock = now_suspend;
+        self.last_wall = now_wall;
+
+        let elapsed_wall = match elapsed_wall {
+            Ok(elapsed_wall) => elapsed_wall,
+            Err(err) => {
+                return ClockEvent::WallClockBack {
+                    by: err.duration() + elapsed,
+                }
+            }
+        };
+        if elapsed_suspend > elapsed + RESUME_THRESHOLD {
+            return ClockEvent::Resumed {
+                slept: elapsed_suspend - elapsed,
+            };
+        }
+        if elapsed > elapsed_wall + RESUME_THRESHOLD {
+            return ClockEvent::WallClockBack {
+                by: elapsed - elapsed_wall,
+            };
+        }
+        ClockEvent::Normal
+    }
+}
+
+/// Reads a clock which, unlike [`Instant`], keeps running while the system is suspended.
+///
+/// This is `CLOCK_BOOTTIME` on Linux and Android and `CLOCK_MONOTONIC` on macOS and iOS,
+/// where [`Instant`] uses clocks which stop.  Elsewhere the wall clock is used, on Windows
+/// it runs alike with [`Instant`] and no suspends are detected.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn suspend_clock() -> Duration {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const CLOCK: libc::clockid_t = libc::CLOCK_BOOTTIME;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec to write to.
+    let res = unsafe { libc::clock_gettime(CLOCK, &mut ts) };
+    if res != 0 {
+        return wall_clock();
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn suspend_clock() -> Duration {
+    wall_clock()
+}
+
+fn wall_clock() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_resume_detector() {
+        let mut detector = ResumeDetector::new();
+        let mut now = detector.last;
+        let mut now_suspend = detector.last_suspend_clock;
+        let mut now_wall = detector.last_wall;
+
+        // regular heartbeats, with some jitter
+        for _ in 0..3 {
+            now += INTERVAL;
+            now_suspend += INTERVAL;
+            now_wall += INTERVAL + Duration::from_millis(300);
+            assert_eq!(
+                detector.check_at(now, now_suspend, now_wall),
+                ClockEvent::Normal
+            );
+        }
+
+        // suspended, only the suspend clock and the wall clock ran
+        now += INTERVAL;
+        now_suspend += Duration::from_secs(3600);
+        now_wall += Duration::from_secs(3600);
+        assert_eq!(
+            detector.check_at(now, now_suspend, now_wall),
+            ClockEvent::Resumed {
+                slept: Duration::from_secs(3595)
+            }
+        );
+
+        // the actor stalled, all clocks ran
+        now += Duration::from_secs(600);
+        now_suspend += Duration::from_secs(600);
+        now_wall += Duration::from_secs(600);
+        assert_eq!(
+            detector.check_at(now, now_suspend, now_wall),
+            ClockEvent::Normal
+        );
+
+        // the wall clock is set back, later checks continue from the new time
+        now += INTERVAL;
+        now_suspend += INTERVAL;
+        let set_back = now_wall - Duration::from_secs(60);
+        assert_eq!(
+            detector.check_at(now, now_suspend, set_back),
+            ClockEvent::WallClockBack {
+                by: Duration::from_secs(65)
+            }
+        );
+        now += INTERVAL;
+        now_suspend += INTERVAL;
+        now_wall = set_back + INTERVAL;
+        assert_eq!(
+            detector.check_at(now, now_suspend, now_wall),
+            ClockEvent::Normal
+        );
+
+        // the wall clock is set forward, which is no resume
+        now += INTERVAL;
+        now_suspend += INTERVAL;
+        now_wall += Duration::from_secs(3600);
+        assert_eq!(
+            detector.check_at(now, now_suspend, now_wall),
+            ClockEvent::Normal
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut detector = ResumeDetector::new();
+        // as if suspended since the last check
+        detector.last_suspend_clock = detector
+            .last_suspend_clock
+            .saturating_sub(Duration::from_secs(600));
+        detector.reset();
+        assert_eq!(detector.check(), ClockEvent::Normal);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_suspend_clock_runs() {
+        let start = suspend_clock();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(suspend_clock() > start);
+    }
+}
@@ -0,0 +1,267 @@
+//! Simulating bad network conditions towards individual nodes.
+//!
+//! Only built with the `net-conditioner` feature.  Applications can use it to test how their
+//! protocols behave over slow or lossy links, without setting up external tooling.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use super::QuicMappedAddr;
+use crate::key::PublicKey;
+
+/// Artificial conditions applied to the data sent to a node.
+///
+/// See [`super::MagicSock::set_link_conditions`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// Maximum random delay added on top of the `latency`.
+    ///
+    /// Packets may be reordered when this is not zero.
+    pub jitter: Duration,
+    /// Probability for a packet to be dropped, from `0.0` to `1.0`.
+    pub loss: f64,
+}
+
+impl LinkConditions {
+    /// Returns the delay for the next packet, or `None` if it is dropped.
+    fn sample(&self, rng: &mut impl Rng) -> Option<Duration> {
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            return None;
+        }
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rng.gen_range(Duration::ZERO..=self.jitter)
+        };
+        Some(self.latency + jitter)
+    }
+
+    /// Returns the delay for each of `count` packets, `None` for the dropped ones.
+    pub(super) fn delays(&self, count: usize) -> Vec<Option<Duration>> {
+        let mut rng = rand::thread_rng();
+        (0..count).map(|_| self.sample(&mut rng)).collect()
+    }
+}
+
+/// The [`LinkConditions`] set for each node.
+#[derive(Debug, Default)]
+pub(super) struct Conditioner {
+    links: parking_lot::Mutex<HashMap<PublicKey, LinkConditions>>,
+}
+
+impl Conditioner {
+    /// Sets the conditions for `node`, clearing them if `None`.
+    pub(super) fn set(&self, node: PublicKey, conditions: Option<LinkConditions>) {
+        let mut links = self.links.lock();
+        match conditions {
+            Some(conditions) => links.insert(node, conditions),
+            None => links.remove(&node),
+        };
+    }
+
+    /// Returns the conditions of the first node matching `is_node`.
+    ///
+    /// Cheap if no conditions are set, which is the normal case.
+    pub(super) fn find(&self, is_node: impl Fn(&PublicKey) -> bool) -> Option<LinkConditions> {
+        let links = self.links.lock();
+        links
+            .iter()
+            .find_map(|(node, conditions)| is_node(node).then_some(*conditions))
+    }
+}
+
+/// Transmits held back by the [`LinkConditions`], ordered by when they are due.
+///
+/// Kept by the actor, so the send path does not need to spawn a task per delayed transmit.
+#[derive(Debug, Default)]
+pub(super) struct DelayedTransmits(VecDeque<(Instant, QuicMappedAddr, quinn_udp::Transmit)>);
+
+impl DelayedTransmits {
+    /// Holds back `transmit` to `dest` until `due`.
+    ///
+    /// Transmits due at the same time keep their order.
+    pub(super) fn push(
+        &mut self,
+        due: Instant,
+        dest: QuicMappedAddr,
+        transmit: quinn_udp::Transmit,
+    ) {
+        let index = self.0.partition_point(|(other, _, _)| *other <= due);
+        self.0.insert(index, (due, dest, transmit));
+    }
+
+    /// When the next transmit is due.
+    pub(super) fn next_due(&self) -> Option<Instant> {
+        self.0.front().map(|(due, _, _)| *due)
+    }
+
+    /// Takes the next transmit if it is due at `now`.
+    pub(super) fn pop_due(
+        &mut self,
+        now: Instant,
+    ) -> Option<(QuicMappedAddr, quinn_udp::Transmit)> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.0
+            .pop_front()
+            .map(|(_, dest, transmit)| (dest, transmit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{relay::RelayMode, MagicEndpoint};
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/conditioner";
+
+    #[test]
+    fn test_sample() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+            loss: 0.25,
+        };
+        let samples: Vec<_> = (0..1000).map(|_| conditions.sample(&mut rng)).collect();
+        let dropped = samples.iter().filter(|delay| delay.is_none()).count();
+        assert!((150..350).contains(&dropped), "dropped {dropped}");
+        for delay in samples.into_iter().flatten() {
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(150));
+        }
+
+        let lossless = LinkConditions::default();
+        assert_eq!(lossless.sample(&mut rng), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_find() {
+        let conditioner = Conditioner::default();
+        let node = crate::key::SecretKey::generate().public();
+        assert_eq!(conditioner.find(|_| true), None);
+
+        let conditions = LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        conditioner.set(node, Some(conditions));
+        assert_eq!(conditioner.find(|n| *n == node), Some(conditions));
+        assert_eq!(conditioner.find(|_| false), None);
+        conditioner.set(node, None);
+        assert_eq!(conditioner.find(|_| true), None);
+    }
+
+    #[test]
+    fn test_delayed_transmits() {
+        let transmit = |byte: u8| quinn_udp::Transmit {
+            destination: "127.0.0.1:1".parse().unwrap(),
+            ecn: None,
+            contents: vec![byte].into(),
+            segment_size: None,
+            src_ip: None,
+        };
+        let dest = QuicMappedAddr::for_endpoint(0);
+        let now = Instant::now();
+        let mut delayed = DelayedTransmits::default();
+        assert_eq!(delayed.next_due(), None);
+        delayed.push(now + Duration::from_millis(20), dest, transmit(2));
+        delayed.push(now + Duration::from_millis(10), dest, transmit(0));
+        delayed.push(now + Duration::from_millis(10), dest, transmit(1));
+        assert_eq!(delayed.next_due(), Some(now + Duration::from_millis(10)));
+
+        assert!(delayed.pop_due(now).is_none());
+        let due = |delayed: &mut DelayedTransmits, at| {
+            delayed
+                .pop_due(now + Duration::from_millis(at))
+                .map(|(_, transmit)| transmit.contents[0])
+        };
+        assert_eq!(due(&mut delayed, 15), Some(0));
+        assert_eq!(due(&mut delayed, 15), Some(1));
+        assert_eq!(due(&mut delayed, 15), None);
+        assert_eq!(due(&mut delayed, 20), Some(2));
+        assert_eq!(delayed.next_due(), None);
+    }
+
+    #[tokio::test]
+    async fn test_link_conditions() {
+        let _logging_guard = iroh_test::logging::setup();
+        let endpoint = || {
+            MagicEndpoint::builder()
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .relay_mode(RelayMode::Disabled)
+                .bind(0)
+        };
+        let listener = endpoint().await.unwrap();
+        let dialer = endpoint().await.unwrap();
+        let listener_addr = listener.my_addr().await.unwrap();
+        let listener_id = listener_addr.node_id;
+
+        tokio::spawn(async move {
+            while let Some(connecting) = listener.accept().await {
+                tokio::spawn(async move {
+                    let conn = connecting.await?;
+                    // Echo each stream on its own task: finishing a stream waits for the
+                    // delayed acknowledgements of the previous link conditions.
+                    while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+                        tokio::spawn(async move {
+                            let msg = recv.read_to_end(100).await?;
+                            send.write_all(&msg).await?;
+                            send.finish().await?;
+                            anyhow::Ok(())
+                        });
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+
+        // Nothing gets through a link dropping all packets.
+        dialer.set_link_conditions(
+            listener_id,
+            Some(LinkConditions {
+                loss: 1.0,
+                ..Default::default()
+            }),
+        );
+        let connect = dialer.connect(listener_addr.clone(), TEST_ALPN);
+        assert!(tokio::time::timeout(Duration::from_secs(1), connect)
+            .await
+            .is_err());
+
+        // Latency delays every round trip.
+        let latency = Duration::from_millis(200);
+        dialer.set_link_conditions(
+            listener_id,
+            Some(LinkConditions {
+                latency,
+                ..Default::default()
+            }),
+        );
+        let conn = dialer.connect(listener_addr, TEST_ALPN).await.unwrap();
+        let start = Instant::now();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().await.unwrap();
+        assert_eq!(recv.read_to_end(100).await.unwrap(), b"hello");
+        assert!(start.elapsed() >= latency);
+
+        // Clearing the conditions restores the link.
+        dialer.set_link_conditions(listener_id, None);
+        let start = Instant::now();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().await.unwrap();
+        assert_eq!(recv.read_to_end(100).await.unwrap(), b"hello");
+        assert!(start.elapsed() < latency);
+    }
+}
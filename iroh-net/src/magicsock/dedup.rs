@@ -0,0 +1,171 @@
+//! Dropping QUIC packets received on both the direct path and the relay.
+//!
+//! While no direct path is confirmed, the sender sends every QUIC packet on its best guess
+//! of a direct path as well as through the relay.  Once the direct path works, the receiver
+//! gets most packets twice, and QUIC has to decrypt each duplicate before discarding it.
+//! With [`super::Options::dedup_recv`] the socket remembers a hash of the packets received
+//! within [`DEDUP_WINDOW`] and drops the ones it has seen already.
+//!
+//! QUIC packets are encrypted with a different nonce each, so their first bytes already tell
+//! them apart.  Only [`HASHED_LEN`] bytes are hashed to keep this cheap for large packets.
+//!
+//! The hashes are spread over [`SHARDS`] sets by their value, each locked on its own for a
+//! single packet, so the receive paths of the UDP sockets and the relays don't wait on each
+//! other.  Together they remember up to [`MAX_REMEMBERED`] packets, which covers the whole
+//! window up to that many packets per second.
+
+use std::{
+    collections::{hash_map::RandomState, HashSet, VecDeque},
+    hash::{BuildHasher, Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// How long a packet is remembered.
+///
+/// Long enough for the copy sent through a relay to arrive after the direct one.
+pub(super) const DEDUP_WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of independently locked sets the hashes are spread over.
+const SHARDS: usize = 16;
+
+/// Maximum number of packets remembered, older ones are forgotten early.
+///
+/// About 300k packets per second, or 3 Gbit/s of full sized packets, for [`DEDUP_WINDOW`].
+const MAX_REMEMBERED: usize = 1 << 18;
+
+/// Maximum number of packets remembered by each shard.
+const MAX_REMEMBERED_PER_SHARD: usize = MAX_REMEMBERED / SHARDS;
+
+/// Number of bytes of a packet which are hashed.
+const HASHED_LEN: usize = 128;
+
+/// The hashes of recently received packets, see the [module docs](self).
+#[derive(Debug)]
+pub(super) struct PacketDedup {
+    hasher: RandomState,
+    shards: Box<[Mutex<Shard>]>,
+}
+
+/// The hashes of one shard, in the order they were received.
+#[derive(Debug, Default)]
+struct Shard {
+    seen: HashSet<u64>,
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Default for PacketDedup {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+impl PacketDedup {
+    /// Returns whether `packet` was received within [`DEDUP_WINDOW`] before `now`.
+    ///
+    /// Otherwise the packet is remembered.
+    pub(super) fn is_duplicate(&self, packet: &[u8], now: Instant) -> bool {
+        let mut hasher = self.hasher.build_hasher();
+        packet.len().hash(&mut hasher);
+        packet[..packet.len().min(HASHED_LEN)].hash(&mut hasher);
+        let hash = hasher.finish();
+        // The low bits pick the set in the `HashSet`, so use the high ones for the shard.
+        let shard = (hash >> 32) as usize % SHARDS;
+        self.shards[shard].lock().insert(hash, now)
+    }
+}
+
+impl Shard {
+    /// Remembers `hash`, returns whether it was already remembered.
+    fn insert(&mut self, hash: u64, now: Instant) -> bool {
+        self.expire(now);
+        if !self.seen.insert(hash) {
+            return true;
+        }
+        if self.order.len() == MAX_REMEMBERED_PER_SHARD {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back((hash, now));
+        false
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((hash, received)) = self.order.front() {
+            if now.saturating_duration_since(*received) < DEDUP_WINDOW {
+                break;
+            }
+            self.seen.remove(hash);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup() {
+        let dedup = PacketDedup::default();
+        let now = Instant::now();
+        let a = [0x40; 1200];
+        let mut b = a;
+        b[1] = 0x41;
+        // only differs in length within the hashed bytes
+        let c = &a[..1100];
+
+        assert!(!dedup.is_duplicate(&a, now));
+        assert!(!dedup.is_duplicate(&b, now));
+        assert!(!dedup.is_duplicate(c, now));
+        assert!(dedup.is_duplicate(&a, now + Duration::from_millis(100)));
+        assert!(dedup.is_duplicate(&b, now + Duration::from_millis(100)));
+
+        // forgotten after the window
+        assert!(!dedup.is_duplicate(&a, now + DEDUP_WINDOW));
+        assert!(dedup.is_duplicate(&a, now + DEDUP_WINDOW));
+    }
+
+    #[test]
+    fn test_dedup_rate() {
+        // 100k packets per second for a full window, all still remembered
+        let dedup = PacketDedup::default();
+        let now = Instant::now();
+        let count = 100_000u32;
+        let step = DEDUP_WINDOW / count;
+        for i in 0..count {
+            assert!(!dedup.is_duplicate(&i.to_be_bytes(), now + step * i));
+        }
+        let end = now + step * count - Duration::from_millis(1);
+        assert!(dedup.is_duplicate(&0u32.to_be_bytes(), end));
+        assert!(dedup.is_duplicate(&(count - 1).to_be_bytes(), end));
+    }
+
+    #[test]
+    fn test_dedup_bounded() {
+        let dedup = PacketDedup::default();
+        let now = Instant::now();
+        for i in 0..MAX_REMEMBERED as u32 * 2 {
+            assert!(!dedup.is_duplicate(&i.to_be_bytes(), now));
+        }
+        let remembered: usize = dedup
+            .shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock();
+                assert_eq!(shard.seen.len(), shard.order.len());
+                shard.order.len()
+            })
+            .sum();
+        assert!(remembered <= MAX_REMEMBERED);
+        // the oldest ones were forgotten, the latest not
+        assert!(!dedup.is_duplicate(&0u32.to_be_bytes(), now));
+        let last = MAX_REMEMBERED as u32 * 2 - 1;
+        assert!(dedup.is_duplicate(&last.to_be_bytes(), now));
+    }
+}
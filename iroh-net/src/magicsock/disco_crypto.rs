@@ -0,0 +1,487 @@
+//! The cryptography sealing disco messages, behind versioned suites.
+//!
+//! Disco messages are sealed with a cipher set up between the two nodes from their keys.
+//! How the cipher is keyed, by a key agreement or a key encapsulation, and the cipher form a
+//! [`DiscoSuite`], identified by a version.  Version 0 is [`X25519Suite`], the X25519 key
+//! agreement of the node keys with XChaCha20-Poly1305, which every node supports.
+//!
+//! Every message carries the version of the suite it is sealed with, see
+//! [`disco::SealedBox`], together with the key material the suite needs to open it, like
+//! the key encapsulated by a KEM.  Messages sealed with version 0 are encoded as before
+//! suites existed.
+//!
+//! Newer suites, like a post-quantum hybrid, are added to [`DiscoSuites`] without changing
+//! how messages are handled.  Which suite is used is negotiated per node by
+//! [`DiscoSecrets`]: messages to a node are sealed with the suite the node last sealed its
+//! messages with, starting with version 0, so a node which upgrades is answered with the new
+//! suite from its first message on.
+
+use std::{collections::HashMap, fmt::Debug};
+
+use aead::Buffer;
+use anyhow::{anyhow, ensure, Result};
+use bytes::Bytes;
+use tracing::{debug, warn};
+
+use crate::{
+    disco::{self, SealedBox},
+    key::{PublicKey, SecretKey, SharedSecret},
+};
+
+/// Seals or opens messages for a single node.
+pub(super) trait DiscoCipher: Debug + Send + Sync {
+    /// Seals the cleartext in `buffer`.
+    fn seal(&self, buffer: &mut dyn Buffer);
+
+    /// Opens the sealed message in `buffer`, leaving the cleartext.
+    fn open(&self, buffer: &mut dyn Buffer) -> Result<()>;
+}
+
+/// A way to key a cipher between two nodes, together with the cipher.
+///
+/// With a key agreement like X25519 both nodes derive the same cipher from their keys and no
+/// key material is sent.  With a KEM the sealing node encapsulates a key to the other node,
+/// and the encapsulated key is sent along with the messages so the node can decapsulate it.
+pub(super) trait DiscoSuite: Debug + Send + Sync {
+    /// The version identifying the suite.
+    fn version(&self) -> u8;
+
+    /// Sets up the cipher sealing the messages from `secret` to `node`.
+    ///
+    /// Returns the cipher and the key material sent along with the messages it seals.
+    fn seal_to(
+        &self,
+        secret: &SecretKey,
+        node: &PublicKey,
+    ) -> Result<(Box<dyn DiscoCipher>, Bytes)>;
+
+    /// Sets up the cipher opening the messages from `node` to `secret`, which were sent
+    /// with `key_material`.
+    fn open_from(
+        &self,
+        secret: &SecretKey,
+        node: &PublicKey,
+        key_material: &[u8],
+    ) -> Result<Box<dyn DiscoCipher>>;
+}
+
+impl DiscoCipher for SharedSecret {
+    fn seal(&self, buffer: &mut dyn Buffer) {
+        SharedSecret::seal(self, buffer)
+    }
+
+    fn open(&self, buffer: &mut dyn Buffer) -> Result<()> {
+        SharedSecret::open(self, buffer)
+    }
+}
+
+/// Version 0: X25519 with XChaCha20-Poly1305, the original disco cryptography.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct X25519Suite;
+
+impl DiscoSuite for X25519Suite {
+    fn version(&self) -> u8 {
+        0
+    }
+
+    fn seal_to(
+        &self,
+        secret: &SecretKey,
+        node: &PublicKey,
+    ) -> Result<(Box<dyn DiscoCipher>, Bytes)> {
+        Ok((Box::new(secret.shared(node)), Bytes::new()))
+    }
+
+    fn open_from(
+        &self,
+        secret: &SecretKey,
+        node: &PublicKey,
+        key_material: &[u8],
+    ) -> Result<Box<dyn DiscoCipher>> {
+        ensure!(key_material.is_empty(), "unexpected key material");
+        Ok(Box::new(secret.shared(node)))
+    }
+}
+
+/// The suites this node supports, by version.
+#[derive(Debug)]
+pub(super) struct DiscoSuites(Vec<Box<dyn DiscoSuite>>);
+
+impl Default for DiscoSuites {
+    fn default() -> Self {
+        Self::new(vec![Box::new(X25519Suite)])
+    }
+}
+
+impl DiscoSuites {
+    /// Creates the set of `suites`, which must include version 0.
+    ///
+    /// # Panics
+    ///
+    /// If version 0 is missing or a version is used twice.
+    pub(super) fn new(mut suites: Vec<Box<dyn DiscoSuite>>) -> Self {
+        suites.sort_by_key(|suite| suite.version());
+        assert_eq!(
+            suites.first().map(|suite| suite.version()),
+            Some(0),
+            "version 0 must be supported"
+        );
+        assert!(
+            suites.windows(2).all(|w| w[0].version() != w[1].version()),
+            "duplicate suite version"
+        );
+        Self(suites)
+    }
+
+    fn get(&self, version: u8) -> Result<&dyn DiscoSuite> {
+        self.0
+            .iter()
+            .find(|suite| suite.version() == version)
+            .map(|suite| suite.as_ref())
+            .ok_or_else(|| anyhow!("unsupported disco suite {version}"))
+    }
+}
+
+/// A cipher sealing messages to a node, with the key material sent along.
+#[derive(Debug)]
+struct SealCipher {
+    version: u8,
+    cipher: Box<dyn DiscoCipher>,
+    key_material: Bytes,
+}
+
+/// A cipher opening messages from a node, for the key material they are sent with.
+#[derive(Debug)]
+struct OpenCipher {
+    version: u8,
+    key_material: Bytes,
+    cipher: Box<dyn DiscoCipher>,
+}
+
+/// The ciphers set up with a node, and the version used for sealing.
+#[derive(Debug)]
+struct NodeCiphers {
+    version: u8,
+    seal: Vec<SealCipher>,
+    /// The last opening cipher per version.
+    open: Vec<OpenCipher>,
+}
+
+impl NodeCiphers {
+    fn new() -> Self {
+        Self {
+            version: 0,
+            seal: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    /// The cipher sealing messages to the node with the negotiated version.
+    ///
+    /// Falls back to version 0 if the suite can not seal to the node, e.g. because its KEM
+    /// key is not known.
+    fn seal_cipher(
+        &mut self,
+        suites: &DiscoSuites,
+        secret: &SecretKey,
+        node: &PublicKey,
+    ) -> &SealCipher {
+        let version = self.version;
+        if let Some(i) = self.seal.iter().position(|c| c.version == version) {
+            return &self.seal[i];
+        }
+        let sealing = suites
+            .get(version)
+            .and_then(|suite| suite.seal_to(secret, node));
+        let (version, (cipher, key_material)) = match sealing {
+            Ok(sealing) => (version, sealing),
+            Err(err) => {
+                warn!(node = %node.fmt_short(), %version, ?err, "failed to set up disco suite, falling back to version 0");
+                self.version = 0;
+                if let Some(i) = self.seal.iter().position(|c| c.version == 0) {
+                    return &self.seal[i];
+                }
+                let sealing = X25519Suite
+                    .seal_to(secret, node)
+                    .expect("version 0 always seals");
+                (0, sealing)
+            }
+        };
+        self.seal.push(SealCipher {
+            version,
+            cipher,
+            key_material,
+        });
+        self.seal.last().expect("just pushed")
+    }
+
+    /// The cipher opening a message from the node.
+    fn open_cipher(
+        &mut self,
+        suites: &DiscoSuites,
+        secret: &SecretKey,
+        node: &PublicKey,
+        sealed_box: &SealedBox<'_>,
+    ) -> Result<&dyn DiscoCipher> {
+        let pos = self.open.iter().position(|c| c.version == sealed_box.suite);
+        if let Some(i) = pos {
+            if self.open[i].key_material == sealed_box.key_material {
+                return Ok(self.open[i].cipher.as_ref());
+            }
+        }
+        let cipher =
+            suites
+                .get(sealed_box.suite)?
+                .open_from(secret, node, sealed_box.key_material)?;
+        let open = OpenCipher {
+            version: sealed_box.suite,
+            key_material: Bytes::copy_from_slice(sealed_box.key_material),
+            cipher,
+        };
+        let i = match pos {
+            Some(i) => {
+                self.open[i] = open;
+                i
+            }
+            None => {
+                self.open.push(open);
+                self.open.len() - 1
+            }
+        };
+        Ok(self.open[i].cipher.as_ref())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(super) enum DiscoBoxError {
+    #[error("Failed to open crypto box")]
+    Open(anyhow::Error),
+    #[error("Failed to parse disco message")]
+    Parse(anyhow::Error),
+}
+
+/// The ciphers set up with other nodes, see the [module docs](self).
+#[derive(Debug, Default)]
+pub(super) struct DiscoSecrets {
+    suites: DiscoSuites,
+    nodes: parking_lot::Mutex<HashMap<PublicKey, NodeCiphers>>,
+}
+
+impl DiscoSecrets {
+    /// Returns the X25519 shared secret with `node`.
+    ///
+    /// Used for sealing relayed packets, which needs the same cipher in both directions and
+    /// has no room for key material, so it always uses version 0.
+    pub(super) fn shared(
+        &self,
+        secret: &SecretKey,
+        node: PublicKey,
+    ) -> parking_lot::MappedMutexGuard<'_, Box<dyn DiscoCipher>> {
+        parking_lot::MutexGuard::map(self.nodes.lock(), |nodes| {
+            let state = nodes.entry(node).or_insert_with(NodeCiphers::new);
+            let i = match state.seal.iter().position(|c| c.version == 0) {
+                Some(i) => i,
+                None => {
+                    let (cipher, key_material) = X25519Suite
+                        .seal_to(secret, &node)
+                        .expect("version 0 always seals");
+                    state.seal.push(SealCipher {
+                        version: 0,
+                        cipher,
+                        key_material,
+                    });
+                    state.seal.len() - 1
+                }
+            };
+            &mut state.seal[i].cipher
+        })
+    }
+
+    pub(super) fn encode_and_seal(
+        &self,
+        secret_key: &SecretKey,
+        node_id: PublicKey,
+        msg: &disco::Message,
+    ) -> Bytes {
+        let mut seal = msg.as_bytes();
+        let mut nodes = self.nodes.lock();
+        let state = nodes.entry(node_id).or_insert_with(NodeCiphers::new);
+        let sealing = state.seal_cipher(&self.suites, secret_key, &node_id);
+        sealing.cipher.seal(&mut seal);
+        disco::encode_suite_message(
+            &secret_key.public(),
+            sealing.version,
+            &sealing.key_material,
+            seal,
+        )
+        .into()
+    }
+
+    /// Like [`Self::encode_and_seal`], without keeping the cipher.
+    ///
+    /// Always seals with version 0, as nothing is known about the node.
+    pub(super) fn encode_and_seal_uncached(
+        secret_key: &SecretKey,
+        node_id: PublicKey,
+        msg: &disco::Message,
+    ) -> Bytes {
+        let mut seal = msg.as_bytes();
+        secret_key.shared(&node_id).seal(&mut seal);
+        disco::encode_message(&secret_key.public(), seal).into()
+    }
+
+    /// Opens and decodes a message from `node_id`.
+    ///
+    /// Switches the messages to the node over to the suite the message was sealed with.
+    pub(super) fn unseal_and_decode(
+        &self,
+        secret: &SecretKey,
+        node_id: PublicKey,
+        sealed_box: SealedBox<'_>,
+    ) -> Result<disco::Message, DiscoBoxError> {
+        let mut nodes = self.nodes.lock();
+        let state = nodes.entry(node_id).or_insert_with(NodeCiphers::new);
+        let mut buf = sealed_box.sealed.to_vec();
+        state
+            .open_cipher(&self.suites, secret, &node_id, &sealed_box)
+            .and_then(|cipher| cipher.open(&mut buf))
+            .map_err(DiscoBoxError::Open)?;
+        if sealed_box.suite != state.version {
+            debug!(node = %node_id.fmt_short(), from = state.version, to = sealed_box.suite, "switching disco suite");
+            state.version = sealed_box.suite;
+        }
+        disco::Message::from_bytes(&buf).map_err(DiscoBoxError::Parse)
+    }
+
+    /// Like [`Self::unseal_and_decode`], without keeping the cipher.
+    pub(super) fn unseal_and_decode_uncached(
+        &self,
+        secret: &SecretKey,
+        node_id: PublicKey,
+        sealed_box: SealedBox<'_>,
+    ) -> Result<disco::Message, DiscoBoxError> {
+        let mut buf = sealed_box.sealed.to_vec();
+        self.suites
+            .get(sealed_box.suite)
+            .and_then(|suite| suite.open_from(secret, &node_id, sealed_box.key_material))
+            .and_then(|cipher| cipher.open(&mut buf))
+            .map_err(DiscoBoxError::Open)?;
+        disco::Message::from_bytes(&buf).map_err(DiscoBoxError::Parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stand-in for a KEM suite: an ephemeral X25519 key is encapsulated with every
+    /// sealing cipher.
+    #[derive(Debug)]
+    struct EphemeralSuite;
+
+    impl DiscoSuite for EphemeralSuite {
+        fn version(&self) -> u8 {
+            1
+        }
+
+        fn seal_to(
+            &self,
+            _secret: &SecretKey,
+            node: &PublicKey,
+        ) -> Result<(Box<dyn DiscoCipher>, Bytes)> {
+            let ephemeral = SecretKey::generate();
+            let key_material = Bytes::copy_from_slice(ephemeral.public().as_bytes());
+            Ok((Box::new(ephemeral.shared(node)), key_material))
+        }
+
+        fn open_from(
+            &self,
+            secret: &SecretKey,
+            _node: &PublicKey,
+            key_material: &[u8],
+        ) -> Result<Box<dyn DiscoCipher>> {
+            let ephemeral = PublicKey::try_from(key_material)?;
+            Ok(Box::new(secret.shared(&ephemeral)))
+        }
+    }
+
+    fn goodbye() -> disco::Message {
+        disco::Message::Goodbye(disco::Goodbye {
+            tx_id: [7u8; 12].into(),
+            sent_at: 7,
+        })
+    }
+
+    /// Seals a goodbye from `secret` to `node` with `suite`, as sent on the wire.
+    fn sealed(secret: &SecretKey, node: &PublicKey, suite: &dyn DiscoSuite) -> Vec<u8> {
+        let mut buf = goodbye().as_bytes();
+        let (cipher, key_material) = suite.seal_to(secret, node).unwrap();
+        cipher.seal(&mut buf);
+        disco::encode_suite_message(&secret.public(), suite.version(), &key_material, buf)
+    }
+
+    fn parse(packet: &[u8]) -> SealedBox<'_> {
+        disco::source_and_box(packet).unwrap().1
+    }
+
+    #[test]
+    fn test_negotiate_suite() {
+        let a = SecretKey::generate();
+        let b = SecretKey::generate();
+        let secrets = DiscoSecrets {
+            suites: DiscoSuites::new(vec![Box::new(EphemeralSuite), Box::new(X25519Suite)]),
+            nodes: Default::default(),
+        };
+        let version = |secrets: &DiscoSecrets| secrets.nodes.lock()[&a.public()].version;
+        let unseal = |packet: &[u8]| secrets.unseal_and_decode(&b, a.public(), parse(packet));
+
+        // Starts with version 0, which opens messages from nodes with only version 0.
+        let msg = unseal(&sealed(&a, &b.public(), &X25519Suite));
+        assert_eq!(msg.unwrap(), goodbye());
+        assert_eq!(version(&secrets), 0);
+        let packet = secrets.encode_and_seal(&b, a.public(), &goodbye());
+        assert_eq!(&packet[..disco::MAGIC_LEN], disco::MAGIC.as_bytes());
+
+        // The node upgraded, it is answered with the new suite from now on.
+        let msg = unseal(&sealed(&a, &b.public(), &EphemeralSuite));
+        assert_eq!(msg.unwrap(), goodbye());
+        assert_eq!(version(&secrets), 1);
+        let packet = secrets.encode_and_seal(&b, a.public(), &goodbye());
+        let sealed_box = parse(&packet);
+        assert_eq!(sealed_box.suite, 1);
+        let mut buf = sealed_box.sealed.to_vec();
+        EphemeralSuite
+            .open_from(&a, &b.public(), sealed_box.key_material)
+            .unwrap()
+            .open(&mut buf)
+            .unwrap();
+        assert_eq!(buf, goodbye().as_bytes());
+
+        // And downgraded again.
+        let msg = unseal(&sealed(&a, &b.public(), &X25519Suite));
+        assert_eq!(msg.unwrap(), goodbye());
+        assert_eq!(version(&secrets), 0);
+
+        // Messages sealed for someone else do not open, and change nothing.
+        let other = SecretKey::generate();
+        let msg = unseal(&sealed(&a, &other.public(), &EphemeralSuite));
+        assert!(matches!(msg, Err(DiscoBoxError::Open(_))));
+        assert_eq!(version(&secrets), 0);
+
+        // Nor do messages claiming another suite.
+        let mut packet = sealed(&a, &b.public(), &EphemeralSuite);
+        packet[disco::MAGIC_LEN + disco::KEY_LEN] = 2;
+        assert!(matches!(unseal(&packet), Err(DiscoBoxError::Open(_))));
+        assert_eq!(version(&secrets), 0);
+
+        let packet = sealed(&a, &b.public(), &EphemeralSuite);
+        let msg = secrets.unseal_and_decode_uncached(&b, a.public(), parse(&packet));
+        assert_eq!(msg.unwrap(), goodbye());
+    }
+
+    #[test]
+    #[should_panic(expected = "version 0 must be supported")]
+    fn test_suites_need_version_0() {
+        DiscoSuites::new(vec![Box::new(EphemeralSuite)]);
+    }
+}
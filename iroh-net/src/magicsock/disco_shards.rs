@@ -0,0 +1,203 @@
+//! Handling disco messages and heartbeats of nodes on their own tasks.
+//!
+//! Disco messages from UDP are detected in the task driving the QUIC endpoint, and the ones
+//! from relays in the actor.  Opening and answering them is mostly crypto, so handling them
+//! there lets a burst of disco traffic delay the QUIC packets received after it.  They are
+//! passed to shard tasks instead, with a bounded queue: when the shards fall behind, disco
+//! messages are dropped rather than QUIC packets delayed.
+//!
+//! With thousands of nodes a single task becomes the bottleneck, so there can be several
+//! shard tasks, which run in parallel on a multi-threaded runtime.  The [`NodeMap`] is split
+//! into as many shards, and the task of a shard handles the disco messages of its nodes, in
+//! order, and sends their heartbeats.  The actor is left with what concerns all nodes, like
+//! netcheck and the home relay.  [`super::Options::disco_shards`] sets the number of shards
+//! and which messages they handle.
+//!
+//! [`NodeMap`]: super::node_map::NodeMap
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use iroh_metrics::inc;
+use tokio::{sync::mpsc, task::JoinSet, time};
+use tracing::{debug, info_span, trace, Instrument};
+
+use super::{
+    metrics::Metrics as MagicsockMetrics, node_map::shard_index, DiscoMessageSource, Inner,
+    HEARTBEAT_INTERVAL, METERED_HEARTBEAT_FACTOR,
+};
+use crate::{disco::SealedBox, key::PublicKey};
+
+/// Number of disco messages queued per shard before further ones are dropped.
+const SHARD_QUEUE_LEN: usize = 1024;
+
+/// Senders to the shard tasks, see the [module docs](self).
+#[derive(Debug)]
+pub(super) struct DiscoShards {
+    senders: Vec<mpsc::Sender<QueuedMessage>>,
+    /// Whether the messages from relays are passed to the shards too.
+    shard_relay: bool,
+}
+
+impl DiscoShards {
+    /// Creates the senders for [`super::Options::disco_shards`], with their receivers.
+    ///
+    /// This is the only place interpreting the option.  The node map needs to be split into as
+    /// many shards as there are receivers, which are passed to [`Self::spawn`] once the
+    /// [`Inner`] exists.
+    pub(super) fn new(count: usize) -> (Self, Vec<ShardReceiver>) {
+        let shard_relay = count > 0;
+        let (senders, receivers) = (0..count.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(SHARD_QUEUE_LEN);
+                (sender, ShardReceiver(receiver))
+            })
+            .unzip();
+        let shards = Self {
+            senders,
+            shard_relay,
+        };
+        (shards, receivers)
+    }
+
+    /// Spawns the shard tasks, one per shard of the node map.
+    ///
+    /// The tasks hold on to `inner`, they stop once the magic socket is shut down.
+    pub(super) fn spawn(
+        receivers: Vec<ShardReceiver>,
+        inner: &Arc<Inner>,
+        tasks: &mut JoinSet<()>,
+        rt: &tokio::runtime::Handle,
+    ) {
+        let count = receivers.len();
+        for (i, ShardReceiver(mut receiver)) in receivers.into_iter().enumerate() {
+            let inner = inner.clone();
+            tasks.spawn_on(
+                async move {
+                    // Spread the heartbeats of the shards over the interval.
+                    let offset = HEARTBEAT_INTERVAL * i as u32 / count as u32;
+                    let mut heartbeat_timer = time::interval_at(
+                        time::Instant::now() + HEARTBEAT_INTERVAL + offset,
+                        HEARTBEAT_INTERVAL,
+                    );
+                    // Where the clock keeps running during a suspend, do not catch up on all
+                    // the missed heartbeats at once after resuming.
+                    heartbeat_timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+                    let mut heartbeat_ticks: u64 = 0;
+                    loop {
+                        let msg = tokio::select! {
+                            _ = inner.shutdown_token.cancelled() => break,
+                            msg = receiver.recv() => match msg {
+                                Some(msg) => msg,
+                                None => break,
+                            },
+                            _ = heartbeat_timer.tick() => {
+                                heartbeat(&inner, i, &mut heartbeat_ticks).await;
+                                continue;
+                            }
+                        };
+                        let sealed_box = SealedBox {
+                            suite: msg.suite,
+                            key_material: &msg.key_material,
+                            sealed: &msg.sealed,
+                        };
+                        inner.handle_disco_message(msg.sender, sealed_box, msg.src);
+                    }
+                }
+                .instrument(info_span!("disco-shard", shard = i)),
+                rt,
+            );
+        }
+    }
+
+    /// Whether disco messages from `src` are passed to the shard tasks.
+    pub(super) fn handles(&self, src: &DiscoMessageSource) -> bool {
+        match src {
+            DiscoMessageSource::Udp(_) => true,
+            DiscoMessageSource::Relay { .. } => self.shard_relay,
+        }
+    }
+
+    /// Passes a disco message to the shard of its sender.
+    ///
+    /// The message is dropped if the shard is too far behind.
+    pub(super) fn dispatch(
+        &self,
+        sender: PublicKey,
+        sealed_box: SealedBox<'_>,
+        src: DiscoMessageSource,
+    ) {
+        let shard = &self.senders[shard_index(&sender, self.senders.len())];
+        let msg = QueuedMessage {
+            sender,
+            suite: sealed_box.suite,
+            key_material: Bytes::copy_from_slice(sealed_box.key_material),
+            sealed: Bytes::copy_from_slice(sealed_box.sealed),
+            src,
+        };
+        if shard.try_send(msg).is_err() {
+            inc!(MagicsockMetrics, recv_disco_shard_dropped);
+            debug!(node = %sender.fmt_short(), "disco shard is full, dropping message");
+        }
+    }
+}
+
+/// Prunes the inactive nodes of `shard` and pings the paths which need to be kept alive.
+async fn heartbeat(inner: &Inner, shard: usize, ticks: &mut u64) {
+    if inner.is_offline() {
+        return;
+    }
+    trace!(shard, "tick: endpoint heartbeat");
+    inner.node_map.prune_inactive_shard(shard);
+    *ticks += 1;
+    if inner.is_metered() && *ticks % METERED_HEARTBEAT_FACTOR != 0 {
+        inc!(MagicsockMetrics, heartbeats_skipped_metered);
+        return;
+    }
+    // TODO: this might trigger too many packets at once, pace this
+    let mut msgs = inner.node_map.endpoints_stayin_alive(shard);
+    if let Err(err) =
+        futures::future::poll_fn(|cx| inner.poll_handle_ping_actions(cx, &mut msgs)).await
+    {
+        debug!("failed to send pings: {err:?}");
+    }
+}
+
+/// A disco message waiting for its shard.
+#[derive(Debug)]
+struct QueuedMessage {
+    sender: PublicKey,
+    suite: u8,
+    key_material: Bytes,
+    sealed: Bytes,
+    src: DiscoMessageSource,
+}
+
+/// The receiving end of a shard, see [`DiscoShards::new`].
+#[derive(Debug)]
+pub(super) struct ShardReceiver(mpsc::Receiver<QueuedMessage>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_handles() {
+        let udp = DiscoMessageSource::Udp("127.0.0.1:1".parse().unwrap());
+        let relay = DiscoMessageSource::Relay {
+            url: "https://relay.example".parse().unwrap(),
+            key: SecretKey::generate().public(),
+        };
+
+        let (shards, receivers) = DiscoShards::new(0);
+        assert_eq!(receivers.len(), 1);
+        assert!(shards.handles(&udp));
+        assert!(!shards.handles(&relay));
+
+        let (shards, receivers) = DiscoShards::new(4);
+        assert_eq!(receivers.len(), 4);
+        assert!(shards.handles(&udp));
+        assert!(shards.handles(&relay));
+    }
+}
@@ -0,0 +1,58 @@
+//! Reporting the endpoint updates of a [`super::MagicSock`].
+//!
+//! An endpoint update re-discovers our own addresses: it runs a netcheck, refreshes the port
+//! mapping and advertises the resulting endpoints.  Updates are started by the socket itself
+//! on network changes and periodically, or by the application with
+//! [`super::MagicSock::trigger_endpoint_update`].  [`super::MagicSock::endpoint_updates`]
+//! reports every finished update, with why it was started and how long it took.
+
+use std::{
+    borrow::Cow,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+
+use super::events::{Lagged, MagicSockEvent, MagicSockEventStream};
+use crate::config;
+
+/// A finished endpoint update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointUpdate {
+    /// Why the update was started.
+    ///
+    /// If more updates were requested while one was running, this is the reason of the last
+    /// one requested.
+    pub reason: Cow<'static, str>,
+    /// How long the update took.
+    pub duration: Duration,
+    /// Our endpoints after the update.
+    pub endpoints: Vec<config::Endpoint>,
+}
+
+/// Stream of [`EndpointUpdate`]s, returned by [`super::MagicSock::endpoint_updates`].
+///
+/// A view of the [`MagicSockEvent::EndpointUpdated`] events of the socket.
+#[derive(Debug)]
+pub struct EndpointUpdateStream {
+    events: MagicSockEventStream,
+}
+
+impl EndpointUpdateStream {
+    pub(super) fn new(events: MagicSockEventStream) -> Self {
+        Self { events }
+    }
+}
+
+impl Stream for EndpointUpdateStream {
+    type Item = Result<EndpointUpdate, Lagged>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_filtered(cx, |event| match event {
+            MagicSockEvent::EndpointUpdated(update) => Some(update),
+            _ => None,
+        })
+    }
+}
@@ -0,0 +1,239 @@
+//! Reporting state changes of a [`super::MagicSock`] as a stream of events.
+//!
+//! [`super::MagicSock::subscribe`] returns a stream of the [`MagicSockEvent`]s from then on,
+//! so applications can react to nodes coming and going, paths becoming direct and our own
+//! addressing changing without polling.
+//!
+//! All state changes go through the one [`EventWatchers`] broadcast: the endpoint update and
+//! presence streams are filtered views of it, and the event log of state dumps records it.
+//! Each stream buffers up to [`EVENT_CHANNEL_CAPACITY`] events, a stream which is not read
+//! fast enough misses the oldest ones and reports this as [`Lagged`].
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast;
+use tokio_util::sync::ReusableBoxFuture;
+
+use super::{
+    endpoint_updates::EndpointUpdate,
+    presence::PresenceEvent,
+    state_dump::{EventLog, RecordedEvent, Redactor},
+};
+use crate::{key::PublicKey, relay::RelayUrl};
+
+/// Number of events buffered for each stream until the oldest are dropped.
+pub(super) const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// A state change of the socket, see [`super::MagicSock::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MagicSockEvent {
+    /// A node was added by the application, or a node which contacted us answered a ping.
+    ///
+    /// Nodes which only contacted us are not reported, as anyone can claim to be a node.
+    NodeAdded(PublicKey),
+    /// A node reported as added was removed from the node map.
+    NodeRemoved(PublicKey),
+    /// A node said goodbye when shutting down, none of its paths work anymore.
+    ///
+    /// The node is kept in the node map, so it can be reached again once it is back.
+    NodeOffline(PublicKey),
+    /// The data sent to a node switched from going through a relay to a direct path.
+    PathUpgraded {
+        /// The node.
+        node_id: PublicKey,
+        /// The direct address now used.
+        addr: SocketAddr,
+    },
+    /// Our home relay changed, `None` if we have none now.
+    HomeRelayChanged(Option<RelayUrl>),
+    /// An endpoint update finished, see [`super::MagicSock::endpoint_updates`].
+    EndpointUpdated(EndpointUpdate),
+    /// The presence of a watched node changed, see [`super::MagicSock::watch_presence`].
+    Presence(PresenceEvent),
+    /// The network changed, a major change re-checks all paths.
+    NetworkChanged {
+        /// Whether the change was major, e.g. a different network was joined.
+        major: bool,
+    },
+    /// The system resumed from a suspend, handled as a major network change.
+    Resumed {
+        /// About how long the system was suspended.
+        slept: Duration,
+    },
+    /// The socket went offline (`true`) or online again, see [`super::MagicSock::set_offline`].
+    Offline(bool),
+}
+
+/// Events were dropped because a stream was not read fast enough.
+///
+/// Holds the number of dropped events, which for filtered streams may include events of
+/// other kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("missed {0} events")]
+pub struct Lagged(pub u64);
+
+type RecvFuture = ReusableBoxFuture<
+    'static,
+    (
+        Result<MagicSockEvent, broadcast::error::RecvError>,
+        broadcast::Receiver<MagicSockEvent>,
+    ),
+>;
+
+/// Stream of [`MagicSockEvent`]s, returned by [`super::MagicSock::subscribe`].
+///
+/// Ends when the socket is closed.
+pub struct MagicSockEventStream {
+    recv: RecvFuture,
+}
+
+impl std::fmt::Debug for MagicSockEventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MagicSockEventStream")
+            .finish_non_exhaustive()
+    }
+}
+
+async fn recv(
+    mut receiver: broadcast::Receiver<MagicSockEvent>,
+) -> (
+    Result<MagicSockEvent, broadcast::error::RecvError>,
+    broadcast::Receiver<MagicSockEvent>,
+) {
+    let res = receiver.recv().await;
+    (res, receiver)
+}
+
+impl MagicSockEventStream {
+    fn new(receiver: broadcast::Receiver<MagicSockEvent>) -> Self {
+        Self {
+            recv: ReusableBoxFuture::new(recv(receiver)),
+        }
+    }
+
+    /// Polls the next event `filter` maps to `Some`, skipping the others.
+    pub(super) fn poll_filtered<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut filter: impl FnMut(MagicSockEvent) -> Option<T>,
+    ) -> Poll<Option<Result<T, Lagged>>> {
+        loop {
+            match ready!(self.poll_next_unpin(cx)) {
+                Some(Ok(event)) => {
+                    if let Some(item) = filter(event) {
+                        return Poll::Ready(Some(Ok(item)));
+                    }
+                }
+                Some(Err(lagged)) => return Poll::Ready(Some(Err(lagged))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Stream for MagicSockEventStream {
+    type Item = Result<MagicSockEvent, Lagged>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (res, receiver) = ready!(self.recv.poll(cx));
+        self.recv.set(recv(receiver));
+        match res {
+            Ok(event) => Poll::Ready(Some(Ok(event))),
+            Err(broadcast::error::RecvError::Lagged(n)) => Poll::Ready(Some(Err(Lagged(n)))),
+            Err(broadcast::error::RecvError::Closed) => Poll::Ready(None),
+        }
+    }
+}
+
+/// The broadcast of all [`MagicSockEvent`]s, which also records them for state dumps.
+///
+/// Cheaply clonable, the clones share the streams.
+#[derive(Debug, Clone)]
+pub(super) struct EventWatchers {
+    /// `None` once closed, dropping the only sender ends all streams.
+    sender: Arc<parking_lot::Mutex<Option<broadcast::Sender<MagicSockEvent>>>>,
+    log: Arc<EventLog>,
+}
+
+impl Default for EventWatchers {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            sender: Arc::new(parking_lot::Mutex::new(Some(sender))),
+            log: Default::default(),
+        }
+    }
+}
+
+impl EventWatchers {
+    /// Creates a new stream of the events from now on.
+    ///
+    /// The stream ends right away if the watchers are closed.
+    pub(super) fn subscribe(&self) -> MagicSockEventStream {
+        let receiver = match *self.sender.lock() {
+            Some(ref sender) => sender.subscribe(),
+            None => broadcast::channel(1).1,
+        };
+        MagicSockEventStream::new(receiver)
+    }
+
+    /// Reports an event to all streams and records it.
+    pub(super) fn notify(&self, event: MagicSockEvent) {
+        self.log.record(event.clone());
+        if let Some(ref sender) = *self.sender.lock() {
+            // Fails only if there are no streams.
+            sender.send(event).ok();
+        }
+    }
+
+    /// The recently reported events, oldest first.
+    pub(super) fn recent(&self, redactor: Redactor) -> Vec<RecordedEvent> {
+        self.log.snapshot(redactor)
+    }
+
+    /// Ends all streams.
+    pub(super) fn close(&self) {
+        self.sender.lock().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[tokio::test]
+    async fn test_watchers() {
+        let watchers = EventWatchers::default();
+        let mut a = watchers.subscribe();
+        let b = watchers.clone().subscribe();
+        drop(b);
+
+        let node = SecretKey::generate().public();
+        watchers.notify(MagicSockEvent::NodeAdded(node));
+        assert_eq!(a.next().await, Some(Ok(MagicSockEvent::NodeAdded(node))));
+        assert_eq!(watchers.recent(Redactor { redact: false }).len(), 1);
+
+        watchers.close();
+        assert_eq!(a.next().await, None);
+        assert_eq!(watchers.subscribe().next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lagged() {
+        let watchers = EventWatchers::default();
+        let mut stream = watchers.subscribe();
+        for _ in 0..EVENT_CHANNEL_CAPACITY + 2 {
+            watchers.notify(MagicSockEvent::Offline(true));
+        }
+        assert_eq!(stream.next().await, Some(Err(Lagged(2))));
+        assert_eq!(stream.next().await, Some(Ok(MagicSockEvent::Offline(true))));
+    }
+}
@@ -0,0 +1,187 @@
+//! Exchanging packets over transports the socket does not know about.
+//!
+//! Some integrations receive packets for the socket themselves, e.g. through a vendor SDK
+//! which carries datagrams between devices, or on a UDP socket shared with other protocols.
+//! [`super::MagicSock::inject_recv`] hands such packets to the socket, which handles them
+//! like packets received on its own paths.  Packets from a [`NodeOrAddr::Node`] are treated
+//! like packets from the relay, packets from a [`NodeOrAddr::Addr`] like packets received on
+//! the UDP socket, including STUN and disco packets.
+//!
+//! The counterpart for sending is the [`SendTap`] of [`super::MagicSock::send_tap`].  While
+//! it is installed, every QUIC datagram sent to a node is also handed to the tap, which
+//! counts as a path of its own: sending to a node without UDP or relay addresses succeeds.
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use arc_swap::ArcSwapOption;
+use bytes::Bytes;
+use futures::Stream;
+use iroh_metrics::inc;
+use tokio::sync::mpsc;
+
+use super::{metrics::Metrics as MagicsockMetrics, split_packets, ClosedError};
+use crate::key::{NodeId, PublicKey};
+
+/// Number of datagrams buffered for a [`SendTap`] before further ones are dropped.
+const SEND_TAP_CAPACITY: usize = 512;
+
+/// Where an injected packet was received from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeOrAddr {
+    /// Received from the node, over a transport without addresses.
+    ///
+    /// The node must be known to the socket, and the packet must be a QUIC datagram.
+    Node(NodeId),
+    /// Received from the UDP address, as if on the socket's own UDP socket.
+    ///
+    /// QUIC datagrams are attributed to the node with this address.  Disco replies are sent
+    /// to the address over the socket's own UDP socket.
+    Addr(SocketAddr),
+}
+
+/// Failure to inject a received packet.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InjectError {
+    /// The socket is closed.
+    #[error("magic socket is closed")]
+    Closed,
+    /// The node the packet was received from is not known.
+    #[error("unknown node {}", .0.fmt_short())]
+    UnknownNode(NodeId),
+    /// No node is known for the address the packet was received from.
+    #[error("no node known for address {0}")]
+    UnknownAddr(SocketAddr),
+    /// Packets from nodes without an address must be QUIC datagrams.
+    #[error("disco and STUN packets need an address")]
+    NotQuic,
+    /// The receive queue is full, the packet was dropped.
+    #[error("receive queue is full")]
+    Full,
+}
+
+impl From<ClosedError> for InjectError {
+    fn from(_: ClosedError) -> Self {
+        Self::Closed
+    }
+}
+
+/// A QUIC datagram sent to a node, handed to the [`SendTap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TappedDatagram {
+    /// The node the datagram is sent to.
+    pub node_id: NodeId,
+    /// The datagram, ready to be injected at the node with [`NodeOrAddr::Node`].
+    pub contents: Bytes,
+}
+
+/// Stream of the datagrams sent to nodes, returned by [`super::MagicSock::send_tap`].
+///
+/// The tap is removed when this is dropped.  Datagrams which are not read fast enough are
+/// dropped, like on a congested path.
+#[derive(Debug)]
+pub struct SendTap {
+    receiver: mpsc::Receiver<TappedDatagram>,
+}
+
+impl Stream for SendTap {
+    type Item = TappedDatagram;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// The sender of the installed [`SendTap`], if any.
+#[derive(Debug, Default)]
+pub(super) struct SendTapSlot {
+    sender: ArcSwapOption<mpsc::Sender<TappedDatagram>>,
+}
+
+impl SendTapSlot {
+    /// Installs a new tap, replacing the previous one.
+    pub(super) fn install(&self) -> SendTap {
+        let (sender, receiver) = mpsc::channel(SEND_TAP_CAPACITY);
+        self.sender.store(Some(Arc::new(sender)));
+        SendTap { receiver }
+    }
+
+    /// Hands the datagrams of the transmits sent to `node` to the tap.
+    ///
+    /// Returns whether a tap is installed.
+    pub(super) fn tap(&self, node: PublicKey, transmits: &[quinn_udp::Transmit]) -> bool {
+        let guard = self.sender.load();
+        let Some(sender) = guard.as_deref() else {
+            return false;
+        };
+        if sender.is_closed() {
+            // Only remove the tap we looked at, not one installed since.
+            self.sender
+                .compare_and_swap(&guard, None::<Arc<mpsc::Sender<TappedDatagram>>>);
+            return false;
+        }
+        for contents in split_packets(transmits) {
+            let datagram = TappedDatagram {
+                node_id: node,
+                contents,
+            };
+            match sender.try_send(datagram) {
+                Ok(()) => inc!(MagicsockMetrics, send_tapped),
+                Err(_) => inc!(MagicsockMetrics, send_tap_dropped),
+            }
+        }
+        true
+    }
+
+    /// Ends the installed tap.
+    pub(super) fn close(&self) {
+        self.sender.store(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::key::SecretKey;
+
+    fn transmit(contents: &'static [u8], segment_size: Option<usize>) -> quinn_udp::Transmit {
+        quinn_udp::Transmit {
+            destination: "127.0.0.1:1".parse().unwrap(),
+            ecn: None,
+            contents: Bytes::from_static(contents),
+            segment_size,
+            src_ip: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_tap() {
+        let slot = SendTapSlot::default();
+        let node = SecretKey::generate().public();
+        assert!(!slot.tap(node, &[transmit(b"lost", None)]));
+
+        let mut tap = slot.install();
+        // GSO transmits are split into their datagrams.
+        assert!(slot.tap(node, &[transmit(b"a", None), transmit(b"bbcc", Some(2))]));
+        for contents in [&b"a"[..], b"bb", b"cc"] {
+            let datagram = tap.next().await.unwrap();
+            assert_eq!(datagram.node_id, node);
+            assert_eq!(datagram.contents, contents);
+        }
+
+        // Dropping the stream removes the tap.
+        drop(tap);
+        assert!(!slot.tap(node, &[transmit(b"c", None)]));
+        assert!(slot.sender.load().is_none());
+
+        let mut tap = slot.install();
+        slot.close();
+        assert!(tap.next().await.is_none());
+    }
+}
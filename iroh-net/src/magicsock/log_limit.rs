@@ -0,0 +1,130 @@
+//! Rate limiting for warnings logged on hot paths.
+//!
+//! Some warnings, like packets from unknown nodes or full send queues, are logged for every
+//! packet.  Under adverse conditions this means thousands of lines per second, which hides
+//! everything else and costs more than handling the packets.  [`warn_limited!`] and
+//! [`error_limited!`] log at most once per [`LOG_INTERVAL`] for each place they are used
+//! from.  The next line logged records how many were suppressed in between, and the
+//! `log_suppressed` metric counts all of them.
+
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use iroh_metrics::inc;
+
+use super::metrics::Metrics as MagicsockMetrics;
+
+/// How often a warning is logged at most, per place it is logged from.
+pub(super) const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Logs a warning like [`tracing::warn!`], at most once per [`LOG_INTERVAL`].
+///
+/// The logged event has a `suppressed` field with the number of warnings skipped since the
+/// last one.
+macro_rules! warn_limited {
+    ($($arg:tt)+) => {
+        if let Some(suppressed) =
+            $crate::magicsock::log_limit::check(concat!(module_path!(), ":", line!()))
+        {
+            ::tracing::warn!(suppressed, $($arg)+);
+        }
+    };
+}
+
+/// Logs an error like [`tracing::error!`], at most once per [`LOG_INTERVAL`].
+///
+/// See [`warn_limited!`].
+macro_rules! error_limited {
+    ($($arg:tt)+) => {
+        if let Some(suppressed) =
+            $crate::magicsock::log_limit::check(concat!(module_path!(), ":", line!()))
+        {
+            ::tracing::error!(suppressed, $($arg)+);
+        }
+    };
+}
+
+pub(super) use error_limited;
+pub(super) use warn_limited;
+
+/// Whether the line logged from `key` should be logged now.
+///
+/// Returns the number of suppressed lines since the last one if so.
+pub(super) fn check(key: &'static str) -> Option<u64> {
+    static LIMITER: OnceLock<LogLimiter> = OnceLock::new();
+    LIMITER
+        .get_or_init(|| LogLimiter::new(LOG_INTERVAL))
+        .check(key, Instant::now())
+}
+
+/// Tracks when lines were logged, by the place they are logged from.
+#[derive(Debug)]
+struct LogLimiter {
+    interval: Duration,
+    keys: parking_lot::Mutex<HashMap<&'static str, KeyState>>,
+}
+
+#[derive(Debug)]
+struct KeyState {
+    last_logged: Instant,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            keys: Default::default(),
+        }
+    }
+
+    fn check(&self, key: &'static str, now: Instant) -> Option<u64> {
+        let mut keys = self.keys.lock();
+        match keys.get_mut(key) {
+            Some(state) if now.saturating_duration_since(state.last_logged) < self.interval => {
+                state.suppressed += 1;
+                inc!(MagicsockMetrics, log_suppressed);
+                None
+            }
+            Some(state) => {
+                state.last_logged = now;
+                Some(std::mem::take(&mut state.suppressed))
+            }
+            None => {
+                keys.insert(
+                    key,
+                    KeyState {
+                        last_logged: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_limiter() {
+        let limiter = LogLimiter::new(LOG_INTERVAL);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check("a", now), Some(0));
+        assert_eq!(limiter.check("a", now), None);
+        assert_eq!(limiter.check("a", now + Duration::from_secs(1)), None);
+        // other places are limited on their own
+        assert_eq!(limiter.check("b", now), Some(0));
+
+        let later = now + LOG_INTERVAL;
+        assert_eq!(limiter.check("a", later), Some(2));
+        assert_eq!(limiter.check("a", later), None);
+        assert_eq!(limiter.check("b", later), Some(0));
+    }
+}
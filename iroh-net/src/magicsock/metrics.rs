@@ -1,5 +1,5 @@
 use iroh_metrics::{
-    core::{Counter, Metric},
+    core::{Counter, Gauge, Histogram, LabeledCounter, Metric},
     struct_iterable::Iterable,
 };
 
@@ -10,6 +10,42 @@ pub struct Metrics {
     pub rebind_calls: Counter,
     pub re_stun_calls: Counter,
     pub update_endpoints: Counter,
+    /// Number of times a port mapped endpoint was withdrawn because the mapping changed.
+    pub portmap_endpoint_withdrawn: Counter,
+    /// Number of times binding one of the UDP sockets failed.
+    pub bind_error: Counter,
+    /// Number of times the IPv4 socket moved to a new port, see [`super::PortHopping`].
+    pub port_hops: Counter,
+
+    // Actor message processing
+    /// Number of messages handled by the actor.
+    pub actor_msgs: Counter,
+    /// Number of times the actor woke up to handle a batch of messages.
+    pub actor_msg_batches: Counter,
+    /// Sum of the actor queue depths seen at the start of each batch.
+    ///
+    /// Divide by `actor_msg_batches` for the average queue depth.
+    pub actor_queue_depth: Counter,
+    /// Number of batches which hit the batch size limit with messages still queued.
+    pub actor_batch_budget_exhausted: Counter,
+    /// Number of messages queued for the actor, at the start of the last batch.
+    pub actor_queue_len: Gauge,
+    /// Time the actor took to handle a message, in seconds.
+    pub actor_msg_duration: Histogram,
+    /// Number of messages queued for the relay actor, when it last took one.
+    pub relay_actor_queue_len: Gauge,
+    /// Time the relay actor took to handle a message, in seconds.
+    pub relay_actor_msg_duration: Histogram,
+    /// Number of disco messages queued for sending over UDP, when one was last taken.
+    pub udp_disco_queue_len: Gauge,
+    /// Time it took to send a disco message over UDP, in seconds.
+    pub udp_disco_send_duration: Histogram,
+    /// Number of disco messages dropped because the UDP send queue was full.
+    pub udp_disco_dropped: Counter,
+    /// Number of messages to the relay actor dropped because its queue was full.
+    pub relay_actor_msgs_dropped: Counter,
+    /// Number of packets received from relays dropped because the actor's queue was full.
+    pub relay_recv_dropped: Counter,
 
     // Sends (data or disco)
     pub send_relay_queued: Counter,
@@ -20,13 +56,32 @@ pub struct Metrics {
     pub send_ipv4_error: Counter,
     pub send_ipv6: Counter,
     pub send_ipv6_error: Counter,
+    /// Number of UDP sends rejected by the local host, usually a firewall (`EPERM`/`EACCES`).
+    pub send_udp_error_denied: Counter,
+    /// Number of UDP sends failing because the destination network or host is unreachable.
+    pub send_udp_error_unreachable: Counter,
     pub send_relay: Counter,
     pub send_relay_error: Counter,
 
     // Data packets (non-disco)
     pub send_data: Counter,
     pub send_data_network_down: Counter,
+    /// Bytes of data sent while the network was hinted to be metered.
+    pub send_data_metered: Counter,
+    /// Number of QUIC transmits staged because no path to the node was known yet.
+    pub send_data_staged: Counter,
+    /// Number of staged transmits dropped because the queue was full or they expired.
+    pub send_data_staged_dropped: Counter,
+    /// Number of staged transmits sent once a path to the node became known.
+    pub send_data_staged_flushed: Counter,
+    /// Number of QUIC transmits failed because the relay policy of the node withheld its
+    /// relay and no direct path was known.
+    pub send_data_relay_withheld: Counter,
     pub recv_data_relay: Counter,
+    /// Number of sealed packets from relays dropped because they failed to open.
+    pub recv_relay_bad_seal: Counter,
+    /// Number of packets from relays dropped because they were not sealed.
+    pub recv_relay_unsealed: Counter,
     pub recv_data_ipv4: Counter,
     pub recv_data_ipv6: Counter,
     /// Number of QUIC datagrams received.
@@ -40,9 +95,19 @@ pub struct Metrics {
     pub sent_disco_ping: Counter,
     pub sent_disco_pong: Counter,
     pub sent_disco_call_me_maybe: Counter,
+    pub sent_disco_goodbye: Counter,
     pub recv_disco_bad_peer: Counter,
+    /// Number of disco messages whose sealed box failed to open.
     pub recv_disco_bad_key: Counter,
     pub recv_disco_bad_parse: Counter,
+    /// Number of challenges sent to pings from unknown nodes.
+    pub sent_disco_challenge: Counter,
+    /// Number of challenges received to our pings.
+    pub recv_disco_challenge: Counter,
+    /// Number of disco messages from unknown nodes dropped because they were not admitted.
+    pub recv_disco_unadmitted: Counter,
+    /// Number of disco messages dropped because the queue of their shard was full.
+    pub recv_disco_shard_dropped: Counter,
 
     pub recv_disco_udp: Counter,
     pub recv_disco_relay: Counter,
@@ -51,9 +116,50 @@ pub struct Metrics {
     pub recv_disco_call_me_maybe: Counter,
     pub recv_disco_call_me_maybe_bad_node: Counter,
     pub recv_disco_call_me_maybe_bad_disco: Counter,
+    pub recv_disco_goodbye: Counter,
+    /// Number of pings to direct addresses deferred by the probe limit.
+    pub probes_deferred: Counter,
+    /// Number of advertised direct addresses dropped by the [`super::AddrFilter`].
+    pub advertised_addrs_filtered: Counter,
 
     // How many times our relay home node DI has changed from non-zero to a different non-zero.
     pub relay_home_change: Counter,
+    /// Number of times the home relay connection was re-established after a network change.
+    pub relay_home_reconnect: Counter,
+    /// Number of call-me-maybe messages sent over another relay than the node's relay.
+    pub relay_call_me_maybe_alternate: Counter,
+    /// Number of call-me-maybes sent to active nodes because our endpoints changed.
+    pub endpoints_pushed: Counter,
+    /// Number of relay connection attempts which failed during the TLS handshake.
+    pub relay_connect_error_tls: Counter,
+    /// Number of endpoint heartbeats skipped because the network is metered.
+    pub heartbeats_skipped_metered: Counter,
+    /// Number of times the system was resumed from suspend, handled as a major network change.
+    pub actor_resumed: Counter,
+    /// Number of warnings not logged because the same warning was logged recently.
+    pub log_suppressed: Counter,
+    /// Number of received QUIC packets checked for duplicates, with `dedup_recv` enabled.
+    pub recv_dedup_checked: Counter,
+    /// Number of received QUIC packets dropped as duplicates.
+    ///
+    /// Divide by `recv_dedup_checked` for the duplication rate.
+    pub recv_dedup_dropped: Counter,
+    /// Number of datagrams injected with `MagicSock::inject_recv`.
+    pub recv_injected: Counter,
+    /// Number of datagrams handed to the send tap.
+    pub send_tapped: Counter,
+    /// Number of datagrams dropped because the send tap was not read fast enough.
+    pub send_tap_dropped: Counter,
+    /// Number of netchecks which probed from ephemeral sockets.
+    pub netcheck_ephemeral_sockets: Counter,
+    /// Number of times the SOCKS5 UDP proxy was unusable, so only the relay was used.
+    pub udp_proxy_unusable: Counter,
+    /// Number of times no TURN allocation could be created, so UDP was used directly.
+    pub turn_unusable: Counter,
+    /// Number of mapped addresses allocated for nodes.
+    pub mapped_addrs_allocated: Counter,
+    /// Number of mapped addresses released, because their node was pruned or removed.
+    pub mapped_addrs_released: Counter,
 
     /*
      * Connection Metrics
@@ -66,6 +172,46 @@ pub struct Metrics {
     pub num_relay_conns_added: Counter,
     /// The number of connections to peers we have removed over relay.
     pub num_relay_conns_removed: Counter,
+    /// Number of nodes for which a direct path was verified.
+    pub direct_path_established: Counter,
+    /// Time from the first contact with a node to a verified direct path, in seconds.
+    pub direct_path_time: Histogram,
+    /// Number of nodes without a direct path because direct paths are disabled.
+    pub direct_path_failed_policy: Counter,
+    /// Number of nodes without a direct path because they advertised no addresses.
+    pub direct_path_failed_no_addrs: Counter,
+    /// Number of nodes without a direct path because of no usable IP family.
+    pub direct_path_failed_ip_family: Counter,
+    /// Number of nodes without a direct path while we are behind a symmetric NAT.
+    pub direct_path_failed_symmetric_nat: Counter,
+    /// Number of nodes without a direct path for other reasons.
+    pub direct_path_failed_hole_punching: Counter,
+
+    /*
+     * QUIC handshake metrics
+     */
+    /// Number of outgoing handshakes started while no path to the node was known.
+    pub handshakes_no_path: Counter,
+    /// Number of outgoing handshakes started over a direct path to a private address.
+    pub handshakes_lan: Counter,
+    /// Number of outgoing handshakes started over a direct path to a public address.
+    pub handshakes_direct: Counter,
+    /// Number of outgoing handshakes started over a relay.
+    pub handshakes_relay: Counter,
+    /// Number of outgoing handshakes which failed.
+    pub handshake_failure: Counter,
+    /// Number of connections established, by ALPN.
+    pub alpn_connections: LabeledCounter,
+    /// Number of failed handshakes, by ALPN.
+    pub alpn_handshake_failures: LabeledCounter,
+    /// Number of outgoing handshakes which completed on the path they started on.
+    pub handshake_success: Counter,
+    /// Number of outgoing handshakes which completed after upgrading to a direct path.
+    pub handshake_success_upgraded: Counter,
+    /// Total time in milliseconds until established for `handshake_success`.
+    pub handshake_time_ms: Counter,
+    /// Total time in milliseconds until established for `handshake_success_upgraded`.
+    pub handshake_time_upgraded_ms: Counter,
 }
 
 impl Default for Metrics {
@@ -77,6 +223,24 @@ impl Default for Metrics {
             rebind_calls: Counter::new("rebind_calls"),
             re_stun_calls: Counter::new("restun_calls"),
             update_endpoints: Counter::new("update_endpoints"),
+            portmap_endpoint_withdrawn: Counter::new("portmap_endpoint_withdrawn"),
+            bind_error: Counter::new("bind_error"),
+            port_hops: Counter::new("port_hops"),
+
+            // Actor message processing
+            actor_msgs: Counter::new("actor_msgs"),
+            actor_msg_batches: Counter::new("actor_msg_batches"),
+            actor_queue_depth: Counter::new("actor_queue_depth"),
+            actor_batch_budget_exhausted: Counter::new("actor_batch_budget_exhausted"),
+            actor_queue_len: Gauge::new("actor_queue_len"),
+            actor_msg_duration: Histogram::new_latency("actor_msg_duration"),
+            relay_actor_queue_len: Gauge::new("relay_actor_queue_len"),
+            relay_actor_msg_duration: Histogram::new_latency("relay_actor_msg_duration"),
+            udp_disco_queue_len: Gauge::new("udp_disco_queue_len"),
+            udp_disco_send_duration: Histogram::new_latency("udp_disco_send_duration"),
+            udp_disco_dropped: Counter::new("udp_disco_dropped"),
+            relay_actor_msgs_dropped: Counter::new("relay_actor_msgs_dropped"),
+            relay_recv_dropped: Counter::new("relay_recv_dropped"),
 
             // Sends (data or disco)
             send_relay_queued: Counter::new("send_relay_queued"),
@@ -87,13 +251,22 @@ impl Default for Metrics {
             send_ipv4_error: Counter::new("send_ipv4_error"),
             send_ipv6: Counter::new("send_ipv6"),
             send_ipv6_error: Counter::new("send_ipv6_error"),
+            send_udp_error_denied: Counter::new("send_udp_error_denied"),
+            send_udp_error_unreachable: Counter::new("send_udp_error_unreachable"),
             send_relay: Counter::new("send_relay"),
             send_relay_error: Counter::new("send_relay_error"),
 
             // Data packets (non-disco)
             send_data: Counter::new("send_data"),
             send_data_network_down: Counter::new("send_data_network_down"),
+            send_data_metered: Counter::new("send_data_metered"),
+            send_data_staged: Counter::new("send_data_staged"),
+            send_data_staged_dropped: Counter::new("send_data_staged_dropped"),
+            send_data_staged_flushed: Counter::new("send_data_staged_flushed"),
+            send_data_relay_withheld: Counter::new("send_data_relay_withheld"),
             recv_data_relay: Counter::new("recv_data_relay"),
+            recv_relay_bad_seal: Counter::new("recv_relay_bad_seal"),
+            recv_relay_unsealed: Counter::new("recv_relay_unsealed"),
             recv_data_ipv4: Counter::new("recv_data_ipv4"),
             recv_data_ipv6: Counter::new("recv_data_ipv6"),
             recv_datagrams: Counter::new("recv_datagrams"),
@@ -106,9 +279,14 @@ impl Default for Metrics {
             sent_disco_ping: Counter::new("disco_sent_ping"),
             sent_disco_pong: Counter::new("disco_sent_pong"),
             sent_disco_call_me_maybe: Counter::new("disco_sent_callmemaybe"),
+            sent_disco_goodbye: Counter::new("disco_sent_goodbye"),
             recv_disco_bad_peer: Counter::new("disco_recv_bad_peer"),
             recv_disco_bad_key: Counter::new("disco_recv_bad_key"),
             recv_disco_bad_parse: Counter::new("disco_recv_bad_parse"),
+            sent_disco_challenge: Counter::new("disco_sent_challenge"),
+            recv_disco_challenge: Counter::new("disco_recv_challenge"),
+            recv_disco_unadmitted: Counter::new("disco_recv_unadmitted"),
+            recv_disco_shard_dropped: Counter::new("recv_disco_shard_dropped"),
 
             recv_disco_udp: Counter::new("disco_recv_udp"),
             recv_disco_relay: Counter::new("disco_recv_relay"),
@@ -117,9 +295,29 @@ impl Default for Metrics {
             recv_disco_call_me_maybe: Counter::new("disco_recv_callmemaybe"),
             recv_disco_call_me_maybe_bad_node: Counter::new("disco_recv_callmemaybe_bad_node"),
             recv_disco_call_me_maybe_bad_disco: Counter::new("disco_recv_callmemaybe_bad_disco"),
+            recv_disco_goodbye: Counter::new("disco_recv_goodbye"),
+            probes_deferred: Counter::new("probes_deferred"),
+            advertised_addrs_filtered: Counter::new("advertised_addrs_filtered"),
 
             // How many times our relay home node DI has changed from non-zero to a different non-zero.
             relay_home_change: Counter::new("relay_home_change"),
+            relay_home_reconnect: Counter::new("relay_home_reconnect"),
+            relay_call_me_maybe_alternate: Counter::new("relay_call_me_maybe_alternate"),
+            endpoints_pushed: Counter::new("endpoints_pushed"),
+            relay_connect_error_tls: Counter::new("relay_connect_error_tls"),
+            heartbeats_skipped_metered: Counter::new("heartbeats_skipped_metered"),
+            actor_resumed: Counter::new("actor_resumed"),
+            log_suppressed: Counter::new("log_suppressed"),
+            recv_dedup_checked: Counter::new("recv_dedup_checked"),
+            recv_dedup_dropped: Counter::new("recv_dedup_dropped"),
+            recv_injected: Counter::new("recv_injected"),
+            send_tapped: Counter::new("send_tapped"),
+            send_tap_dropped: Counter::new("send_tap_dropped"),
+            netcheck_ephemeral_sockets: Counter::new("netcheck_ephemeral_sockets"),
+            udp_proxy_unusable: Counter::new("udp_proxy_unusable"),
+            turn_unusable: Counter::new("turn_unusable"),
+            mapped_addrs_allocated: Counter::new("mapped_addrs_allocated"),
+            mapped_addrs_released: Counter::new("mapped_addrs_released"),
 
             num_direct_conns_added: Counter::new(
                 "number of direct connections to a peer we have added",
@@ -127,6 +325,28 @@ impl Default for Metrics {
             num_direct_conns_removed: Counter::new(
                 "number of direct connections to a peer we have removed",
             ),
+            direct_path_established: Counter::new("direct_path_established"),
+            direct_path_time: Histogram::new(
+                "direct_path_time",
+                &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0],
+            ),
+            direct_path_failed_policy: Counter::new("direct_path_failed_policy"),
+            direct_path_failed_no_addrs: Counter::new("direct_path_failed_no_addrs"),
+            direct_path_failed_ip_family: Counter::new("direct_path_failed_ip_family"),
+            direct_path_failed_symmetric_nat: Counter::new("direct_path_failed_symmetric_nat"),
+            direct_path_failed_hole_punching: Counter::new("direct_path_failed_hole_punching"),
+
+            handshakes_no_path: Counter::new("handshakes_no_path"),
+            handshakes_lan: Counter::new("handshakes_lan"),
+            handshakes_direct: Counter::new("handshakes_direct"),
+            handshakes_relay: Counter::new("handshakes_relay"),
+            handshake_failure: Counter::new("handshake_failure"),
+            alpn_connections: LabeledCounter::new("alpn", "Number of connections established"),
+            alpn_handshake_failures: LabeledCounter::new("alpn", "Number of failed handshakes"),
+            handshake_success: Counter::new("handshake_success"),
+            handshake_success_upgraded: Counter::new("handshake_success_upgraded"),
+            handshake_time_ms: Counter::new("handshake_time_ms"),
+            handshake_time_upgraded_ms: Counter::new("handshake_time_upgraded_ms"),
         }
     }
 }
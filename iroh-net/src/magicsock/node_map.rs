@@ -1,27 +1,34 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     hash::Hash,
     net::{IpAddr, SocketAddr},
     path::Path,
     pin::Pin,
+    sync::{atomic::AtomicU64, Arc},
     task::{Context, Poll},
-    time::Instant,
+    time::{Instant, SystemTime},
 };
 
 use anyhow::{ensure, Context as _};
 use futures::Stream;
 use iroh_metrics::inc;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard, RwLock};
 use stun_rs::TransactionId;
 use tokio::io::AsyncWriteExt;
-use tracing::{debug, info, instrument, trace, warn};
+use tracing::{debug, info, instrument, trace};
 
 use self::endpoint::{Endpoint, Options, PingHandled};
+use self::family_stats::SharedFamilyStats;
 use super::{
-    metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoMessageSource, QuicMappedAddr,
+    actor_queue::ActorSender,
+    addr_filter::{self, AddrFilter},
+    events::{EventWatchers, MagicSockEvent},
+    log_limit::warn_limited,
+    metrics::Metrics as MagicsockMetrics,
+    DiscoMessageSource, PathTuning, QuicMappedAddr, Timeouts,
 };
 use crate::{
-    disco::{CallMeMaybe, Pong, SendAddr},
+    disco::{CallMeMaybe, Goodbye, Pong, SendAddr},
     key::PublicKey,
     relay::RelayUrl,
     stun, NodeAddr,
@@ -29,14 +36,28 @@ use crate::{
 
 mod best_addr;
 mod endpoint;
+mod family_stats;
 
-pub use endpoint::{ConnectionType, ControlMsg, DirectAddrInfo, EndpointInfo};
-pub(super) use endpoint::{DiscoPingPurpose, PingAction, PingRole, SendPing};
+pub use endpoint::{
+    ConnectionType, ControlMsg, DirectAddrInfo, EndpointInfo, NoDirectPathReason, PathPing,
+    PingPath, RelayPolicy, RelayReachability,
+};
+pub(super) use endpoint::{
+    DiscoPingPurpose, LocalConditions, PingAction, PingRole, PingWaiter, SendPing,
+    PING_TIMEOUT_DURATION,
+};
+pub use family_stats::{FamilyStats, ProbeStats};
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
 const MAX_INACTIVE_NODES: usize = 30;
 
+/// Number of nodes added by a call-me-maybe which did not answer a ping yet.
+///
+/// Anyone can send call-me-maybe messages, further unknown nodes are ignored until some of
+/// these are confirmed or removed.
+const MAX_UNCONFIRMED_NODES: usize = 32;
+
 /// Map of the [`Endpoint`] information for all the known nodes.
 ///
 /// Each endpoint is also known as a "Node" in the "(iroh) network", but this is a bit of a
@@ -58,18 +79,80 @@ const MAX_INACTIVE_NODES: usize = 30;
 ///   These come and go as the node moves around on the internet
 ///
 /// An index of nodeInfos by node key, QuicMappedAddr, and discovered ip:port endpoints.
-#[derive(Default, Debug)]
+///
+/// The nodes are split into shards by [`shard_index`] of their key, each behind its own lock,
+/// so that the shard tasks handling the disco messages and heartbeats of different nodes do
+/// not contend.  The id of a node is a multiple of the number of shards plus its shard, which
+/// finds the shard of lookups by [`QuicMappedAddr`].  Only the ip:port index is shared by all
+/// shards.
+#[derive(Debug)]
 pub(super) struct NodeMap {
-    inner: Mutex<NodeMapInner>,
+    shards: Vec<Mutex<NodeMapInner>>,
+    /// The ip:port index, shared with all the shards.
+    by_ip_port: IpPortIndex,
 }
 
+/// The node receiving from each ip:port, by id.
+///
+/// Shared by the shards of a [`NodeMap`], it is only locked while a shard is locked or by
+/// itself, never the other way round.
+type IpPortIndex = Arc<RwLock<HashMap<IpPort, usize>>>;
+
 #[derive(Default, Debug)]
 pub(super) struct NodeMapInner {
     by_node_key: HashMap<PublicKey, usize>,
-    by_ip_port: HashMap<IpPort, usize>,
+    by_ip_port: IpPortIndex,
+    /// The ip:ports this shard mapped to each of its endpoints in `by_ip_port`, so they are
+    /// removed with the endpoint.  They may have been mapped to another endpoint since.
+    ip_ports_by_id: HashMap<usize, HashSet<IpPort>>,
     by_quic_mapped_addr: HashMap<QuicMappedAddr, usize>,
     by_id: HashMap<usize, Endpoint>,
-    next_id: usize,
+    ids: EndpointIds,
+    /// Whether the last netcheck report found our NAT mapping varies by destination.
+    varying_mapping: bool,
+    /// Whether the network is metered, which makes hole punching less aggressive.
+    metered: bool,
+    /// What we know about our network, to explain nodes without a direct path.
+    local_conditions: LocalConditions,
+    /// Limits for probing direct paths, applied to every endpoint.
+    path_tuning: PathTuning,
+    /// The timeouts, of which the endpoints use the ping timeout.
+    timeouts: Timeouts,
+    /// The relay policies other than [`RelayPolicy::Allow`], kept for nodes not known yet.
+    relay_policies: HashMap<PublicKey, RelayPolicy>,
+    /// Whether IPv4 paths are preferred over IPv6 paths of similar latency.
+    prefer_ipv4: bool,
+    /// Where nodes being added and removed are reported.
+    events: EventWatchers,
+    /// Outcomes of the probes to direct paths of all nodes, per IP family.
+    family_stats: SharedFamilyStats,
+    /// The ids of the nodes added by a call-me-maybe, until a ping to them is answered.
+    unconfirmed: HashSet<usize>,
+    /// The ids of the nodes reported with [`MagicSockEvent::NodeAdded`]: those added by the
+    /// application, and those which answered a ping.
+    announced: HashSet<usize>,
+}
+
+/// Hands out the ids of the endpoints of one shard, see [`NodeMap`].
+#[derive(Debug)]
+struct EndpointIds {
+    next: usize,
+    /// The number of shards.
+    step: usize,
+}
+
+impl Default for EndpointIds {
+    fn default() -> Self {
+        Self { next: 0, step: 1 }
+    }
+}
+
+impl EndpointIds {
+    fn next(&mut self) -> usize {
+        let id = self.next;
+        self.next = self.next.wrapping_add(self.step);
+        id
+    }
 }
 
 #[derive(Clone)]
@@ -80,41 +163,127 @@ enum EndpointId<'a> {
     IpPort(&'a IpPort),
 }
 
+impl Default for NodeMap {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
 impl NodeMap {
-    /// Create a new [`NodeMap`] from data stored in `path`.
-    pub fn load_from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        Ok(Self::from_inner(NodeMapInner::load_from_file(path)?))
+    /// Creates an empty [`NodeMap`] split into `shards`, at least one.
+    pub fn new(shards: usize) -> Self {
+        let count = shards.max(1);
+        let by_ip_port = IpPortIndex::default();
+        let family_stats = SharedFamilyStats::default();
+        let shards = (0..count)
+            .map(|shard| {
+                Mutex::new(NodeMapInner {
+                    by_ip_port: by_ip_port.clone(),
+                    ids: EndpointIds {
+                        next: shard,
+                        step: count,
+                    },
+                    family_stats: family_stats.clone(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Self { shards, by_ip_port }
+    }
+
+    /// Create a new [`NodeMap`] with `shards` from data stored in `path`.
+    ///
+    /// The stored direct addresses go through `addr_filter`, like newly added ones.
+    pub fn load_from_file(
+        path: impl AsRef<Path>,
+        shards: usize,
+        addr_filter: Option<&dyn AddrFilter>,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        ensure!(path.is_file(), "{} is not a file", path.display());
+        let me = Self::new(shards);
+        let contents = std::fs::read(path)?;
+        let mut slice: &[u8] = &contents;
+        while !slice.is_empty() {
+            let (mut node_addr, next_contents) =
+                postcard::take_from_bytes(slice).context("failed to load node data")?;
+            if let Some(filter) = addr_filter {
+                addr_filter::apply_to_node_addr(filter, &mut node_addr);
+            }
+            me.add_node_addr(node_addr);
+            slice = next_contents;
+        }
+        Ok(me)
     }
 
+    #[cfg(test)]
     fn from_inner(inner: NodeMapInner) -> Self {
         Self {
-            inner: Mutex::new(inner),
+            by_ip_port: inner.by_ip_port.clone(),
+            shards: vec![Mutex::new(inner)],
         }
     }
 
+    /// Locks the shard of `node`.
+    fn shard(&self, node: &PublicKey) -> MutexGuard<'_, NodeMapInner> {
+        self.shards[shard_index(node, self.shards.len())].lock()
+    }
+
+    /// Locks the shard of the endpoint with `id`.
+    fn shard_of_id(&self, id: usize) -> MutexGuard<'_, NodeMapInner> {
+        self.shards[id % self.shards.len()].lock()
+    }
+
+    /// Locks the shards one after the other.
+    fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, NodeMapInner>> {
+        self.shards.iter().map(|shard| shard.lock())
+    }
+
+    /// Splits `limit` for all nodes across the shards.
+    fn per_shard(&self, limit: usize) -> usize {
+        limit.div_ceil(self.shards.len())
+    }
+
     /// Get the known node addresses stored in the map. Nodes with empty addressing information are
     /// filtered out.
-    #[cfg(test)]
     pub fn known_node_addresses(&self) -> Vec<NodeAddr> {
-        self.inner.lock().known_node_addresses().collect()
+        self.shards()
+            .flat_map(|inner| inner.known_node_addresses().collect::<Vec<_>>())
+            .collect()
     }
 
     /// Add the contact information for a node.
     pub fn add_node_addr(&self, node_addr: NodeAddr) {
-        self.inner.lock().add_node_addr(node_addr)
+        self.shard(&node_addr.node_id).add_node_addr(node_addr)
     }
 
     /// Number of nodes currently listed.
     pub fn node_count(&self) -> usize {
-        self.inner.lock().node_count()
+        self.shards().map(|inner| inner.node_count()).sum()
     }
 
-    pub fn receive_udp(&self, udp_addr: SocketAddr) -> Option<(PublicKey, QuicMappedAddr)> {
-        self.inner.lock().receive_udp(udp_addr)
+    /// Marks the node at `udp_addr` as having sent us `len` bytes of QUIC packets.
+    pub fn receive_udp(
+        &self,
+        udp_addr: SocketAddr,
+        len: usize,
+    ) -> Option<(PublicKey, QuicMappedAddr)> {
+        let id = self.by_ip_port.read().get(&udp_addr.into()).copied();
+        let Some(id) = id else {
+            info!(src=%udp_addr, "receive_udp: no node_map state found for addr, ignore");
+            return None;
+        };
+        self.shard_of_id(id).receive_udp(udp_addr, len)
     }
 
-    pub fn receive_relay(&self, relay_url: &RelayUrl, src: PublicKey) -> QuicMappedAddr {
-        self.inner.lock().receive_relay(relay_url, &src)
+    /// Marks `src` as having sent us `len` bytes of QUIC packets through the relay.
+    pub fn receive_relay(
+        &self,
+        relay_url: &RelayUrl,
+        src: PublicKey,
+        len: usize,
+    ) -> QuicMappedAddr {
+        self.shard(&src).receive_relay(relay_url, &src, len)
     }
 
     pub fn notify_ping_sent(
@@ -123,16 +292,24 @@ impl NodeMap {
         dst: SendAddr,
         tx_id: stun::TransactionId,
         purpose: DiscoPingPurpose,
-        msg_sender: tokio::sync::mpsc::Sender<ActorMessage>,
+        msg_sender: ActorSender,
     ) {
-        if let Some(ep) = self.inner.lock().get_mut(EndpointId::Id(&id)) {
+        if let Some(ep) = self.shard_of_id(id).get_mut(EndpointId::Id(&id)) {
             ep.ping_sent(dst, tx_id, purpose, msg_sender);
         }
     }
 
-    pub fn notify_ping_timeout(&self, id: usize, tx_id: stun::TransactionId) {
-        if let Some(ep) = self.inner.lock().get_mut(EndpointId::Id(&id)) {
-            ep.ping_timeout(tx_id);
+    /// Handles a ping which was not answered in time.
+    ///
+    /// Returns the pings to direct paths which were waiting for earlier probes to complete.
+    #[must_use = "actions must be handled"]
+    pub fn notify_ping_timeout(&self, id: usize, tx_id: stun::TransactionId) -> Vec<PingAction> {
+        match self.shard_of_id(id).get_mut(EndpointId::Id(&id)) {
+            Some(ep) => {
+                ep.ping_timeout(tx_id);
+                ep.continue_probing(Instant::now())
+            }
+            None => Vec::new(),
         }
     }
 
@@ -140,8 +317,7 @@ impl NodeMap {
         &self,
         node_key: &PublicKey,
     ) -> Option<QuicMappedAddr> {
-        self.inner
-            .lock()
+        self.shard(node_key)
             .get(EndpointId::NodeKey(node_key))
             .map(|ep| *ep.quic_mapped_addr())
     }
@@ -154,16 +330,41 @@ impl NodeMap {
         src: SendAddr,
         tx_id: TransactionId,
     ) -> PingHandled {
-        self.inner.lock().handle_ping(sender, src, tx_id)
+        self.shard(&sender).handle_ping(sender, src, tx_id)
+    }
+
+    /// Whether we are waiting for the pong to the ping `tx_id` sent to `dst` of `node_key`.
+    pub fn is_ping_pending(
+        &self,
+        node_key: &PublicKey,
+        dst: &SendAddr,
+        tx_id: TransactionId,
+    ) -> bool {
+        self.shard(node_key)
+            .get(EndpointId::NodeKey(node_key))
+            .is_some_and(|ep| ep.is_ping_pending(dst, tx_id))
     }
 
-    pub fn handle_pong(&self, sender: PublicKey, src: &DiscoMessageSource, pong: Pong) {
-        self.inner.lock().handle_pong(sender, src, pong)
+    #[must_use = "actions must be handled"]
+    pub fn handle_pong(
+        &self,
+        sender: PublicKey,
+        src: &DiscoMessageSource,
+        pong: Pong,
+    ) -> Vec<PingAction> {
+        self.shard(&sender).handle_pong(sender, src, pong)
     }
 
     #[must_use = "actions must be handled"]
-    pub fn handle_call_me_maybe(&self, sender: PublicKey, cm: CallMeMaybe) -> Vec<PingAction> {
-        self.inner.lock().handle_call_me_maybe(sender, cm)
+    pub fn handle_call_me_maybe(
+        &self,
+        sender: PublicKey,
+        relay_url: &RelayUrl,
+        cm: CallMeMaybe,
+    ) -> Vec<PingAction> {
+        let max_unconfirmed = self.per_shard(MAX_UNCONFIRMED_NODES);
+        self.shard(&sender)
+            .handle_call_me_maybe(sender, relay_url, cm, max_unconfirmed)
     }
 
     #[allow(clippy::type_complexity)]
@@ -176,40 +377,232 @@ impl NodeMap {
         Option<SocketAddr>,
         Option<RelayUrl>,
         Vec<PingAction>,
+        Arc<AtomicU64>,
     )> {
-        let mut inner = self.inner.lock();
+        let mut inner = self.shard_of_id(addr.endpoint_id()?);
+        let varying_mapping = inner.varying_mapping;
+        let metered = inner.metered;
         let ep = inner.get_mut(EndpointId::QuicMappedAddr(addr))?;
         let public_key = *ep.public_key();
-        let (udp_addr, relay_url, msgs) = ep.get_send_addrs(have_ipv6);
-        Some((public_key, udp_addr, relay_url, msgs))
+        let (udp_addr, relay_url, msgs) = ep.get_send_addrs(have_ipv6, varying_mapping, metered);
+        Some((public_key, udp_addr, relay_url, msgs, ep.sent_counter()))
+    }
+
+    /// Sets whether our NAT mapping varies by destination, which adjusts the hole punching.
+    pub fn set_varying_mapping(&self, varying_mapping: bool) {
+        for mut inner in self.shards() {
+            inner.varying_mapping = varying_mapping;
+        }
+    }
+
+    /// Sets whether the network is metered, which reduces hole punching retries.
+    pub fn set_metered(&self, metered: bool) {
+        for mut inner in self.shards() {
+            inner.metered = metered;
+        }
+    }
+
+    /// Sets the limits for probing direct paths.
+    pub fn set_path_tuning(&self, path_tuning: PathTuning) {
+        for mut inner in self.shards() {
+            inner.path_tuning = path_tuning;
+            for (_, ep) in inner.endpoints_mut() {
+                ep.set_path_tuning(path_tuning);
+            }
+        }
+    }
+
+    /// Sets the timeouts, of which the endpoints use the ping timeout.
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        for mut inner in self.shards() {
+            inner.timeouts = timeouts;
+            for (_, ep) in inner.endpoints_mut() {
+                ep.set_ping_timeout(timeouts.ping);
+            }
+        }
+    }
+
+    /// Sets whether IPv4 paths are preferred over IPv6 paths of similar latency.
+    pub fn set_prefer_ipv4(&self, prefer_ipv4: bool) {
+        for mut inner in self.shards() {
+            inner.prefer_ipv4 = prefer_ipv4;
+            for (_, ep) in inner.endpoints_mut() {
+                ep.set_prefer_ipv4(prefer_ipv4);
+            }
+        }
+    }
+
+    /// Outcomes of recent probes to the direct paths of all nodes, per IP family.
+    pub fn family_stats(&self) -> FamilyStats {
+        // All shards share the same stats.
+        self.shards[0].lock().family_stats.get()
+    }
+
+    /// Sets where changes of nodes and their paths are reported.
+    pub fn set_event_watchers(&self, events: EventWatchers) {
+        for mut inner in self.shards() {
+            for (_, ep) in inner.endpoints_mut() {
+                ep.set_event_watchers(events.clone());
+            }
+            inner.events = events.clone();
+        }
+    }
+
+    /// Sets whether QUIC packets to `node` may be sent through relays.
+    pub fn set_relay_policy(&self, node: PublicKey, policy: RelayPolicy) {
+        let mut inner = self.shard(&node);
+        match policy {
+            RelayPolicy::Allow => inner.relay_policies.remove(&node),
+            policy => inner.relay_policies.insert(node, policy),
+        };
+        if let Some(ep) = inner.get_mut(EndpointId::NodeKey(&node)) {
+            ep.set_relay_policy(policy);
+        }
+    }
+
+    /// Whether the [`RelayPolicy`] of `node` currently withholds its relay.
+    pub fn relay_withheld(&self, node: &PublicKey) -> bool {
+        self.shard(node)
+            .get(EndpointId::NodeKey(node))
+            .is_some_and(|ep| ep.relay_url().is_some() && !ep.relay_allowed(Instant::now()))
+    }
+
+    /// Updates what we know about our network, from the latest netcheck report.
+    pub fn set_local_conditions(&self, conditions: LocalConditions) {
+        for mut inner in self.shards() {
+            inner.local_conditions = conditions;
+        }
+    }
+
+    /// Notifies the node that the relay server at `url` reported it as disconnected.
+    pub fn notify_relay_peer_gone(&self, url: &RelayUrl, node_id: PublicKey) {
+        if let Some(ep) = self.shard(&node_id).get_mut(EndpointId::NodeKey(&node_id)) {
+            ep.relay_peer_gone(url);
+        }
+    }
+
+    /// Notifies the node that sends over its relay path are backing up.
+    pub fn notify_relay_congested(&self, node_id: &PublicKey) {
+        if let Some(ep) = self.shard(node_id).get_mut(EndpointId::NodeKey(node_id)) {
+            ep.relay_congested(Instant::now());
+        }
+    }
+
+    /// Handles a goodbye disco message, the node shut down.
+    pub fn handle_goodbye(&self, sender: PublicKey, goodbye: &Goodbye) {
+        if let Some(ep) = self.shard(&sender).get_mut(EndpointId::NodeKey(&sender)) {
+            ep.handle_goodbye(goodbye, SystemTime::now());
+        }
+    }
+
+    /// Returns the nodes in active use together with the paths to say goodbye on.
+    pub fn goodbye_addrs(&self, now: Instant) -> Vec<(PublicKey, Vec<SendAddr>)> {
+        let mut addrs = Vec::new();
+        for inner in self.shards() {
+            addrs.extend(
+                inner
+                    .endpoints()
+                    .map(|(_, ep)| (*ep.public_key(), ep.goodbye_addrs(&now)))
+                    .filter(|(_, addrs)| !addrs.is_empty()),
+            );
+        }
+        addrs
+    }
+
+    /// Records the home relay a node advertised in a disco ping.
+    ///
+    /// Only relays of our relay map may be recorded.
+    pub fn set_home_relay(&self, node_id: PublicKey, home_relay: RelayUrl) {
+        if let Some(ep) = self.shard(&node_id).get_mut(EndpointId::NodeKey(&node_id)) {
+            ep.set_home_relay(home_relay);
+        }
     }
 
     pub fn notify_shutdown(&self) {
-        let mut inner = self.inner.lock();
-        for (_, ep) in inner.endpoints_mut() {
-            ep.reset();
+        for mut inner in self.shards() {
+            for (_, ep) in inner.endpoints_mut() {
+                ep.reset();
+            }
         }
     }
 
     pub fn reset_endpoint_states(&self) {
-        let mut inner = self.inner.lock();
-        for (_, ep) in inner.endpoints_mut() {
-            ep.note_connectivity_change();
+        for mut inner in self.shards() {
+            for (_, ep) in inner.endpoints_mut() {
+                ep.note_connectivity_change();
+            }
         }
     }
 
-    pub fn endpoints_stayin_alive(&self) -> Vec<PingAction> {
+    /// Pings all paths of the active nodes and sends them a call-me-maybe.
+    ///
+    /// Used to recover all paths at once, e.g. after the system woke up.
+    pub fn call_me_maybe_active(&self) -> Vec<PingAction> {
+        let mut msgs = Vec::new();
+        for mut inner in self.shards() {
+            for (_, ep) in inner.endpoints_mut() {
+                msgs.extend(ep.call_me_maybe_if_active());
+            }
+        }
+        msgs
+    }
+
+    /// Pings all paths of the node, see [`MagicSock::ping`].
+    ///
+    /// Returns `None` if the node is not known.
+    ///
+    /// [`MagicSock::ping`]: super::MagicSock::ping
+    pub fn ping_all_paths(
+        &self,
+        node_id: &PublicKey,
+    ) -> Option<(Vec<PingAction>, Vec<PingWaiter>)> {
+        self.shard(node_id)
+            .get_mut(EndpointId::NodeKey(node_id))
+            .map(|ep| ep.ping_all_paths())
+    }
+
+    /// Pings the direct path of the active nodes after our IPv4 socket moved to a new port.
+    pub fn notify_local_port_changed(&self) -> Vec<PingAction> {
+        let mut msgs = Vec::new();
+        for mut inner in self.shards() {
+            msgs.extend(
+                inner
+                    .endpoints_mut()
+                    .filter_map(|(_, ep)| ep.local_port_changed()),
+            );
+        }
+        msgs
+    }
+
+    /// Sends a call-me-maybe with our changed endpoints to the active nodes.
+    pub fn notify_endpoints_changed(&self) -> Vec<PingAction> {
+        let mut msgs = Vec::new();
+        for mut inner in self.shards() {
+            msgs.extend(
+                inner
+                    .endpoints_mut()
+                    .filter_map(|(_, ep)| ep.endpoints_changed()),
+            );
+        }
+        msgs
+    }
+
+    /// Sends the heartbeats of the nodes in `shard`, see [`NodeMap::new`].
+    pub fn endpoints_stayin_alive(&self, shard: usize) -> Vec<PingAction> {
         let mut msgs = Vec::new();
-        let mut inner = self.inner.lock();
+        let mut inner = self.shards[shard].lock();
+        let conditions = inner.local_conditions;
         for (_, ep) in inner.endpoints_mut() {
-            msgs.extend(ep.stayin_alive());
+            msgs.extend(ep.stayin_alive(&conditions));
         }
         msgs
     }
 
     /// Get the [`EndpointInfo`]s for each endpoint
     pub fn endpoint_infos(&self, now: Instant) -> Vec<EndpointInfo> {
-        self.inner.lock().endpoint_infos(now)
+        self.shards()
+            .flat_map(|inner| inner.endpoint_infos(now))
+            .collect()
     }
 
     /// Returns a stream of [`ConnectionType`].
@@ -222,12 +615,12 @@ impl NodeMap {
     /// Will return an error if there is not an entry in the [`NodeMap`] for
     /// the `public_key`
     pub fn conn_type_stream(&self, public_key: &PublicKey) -> anyhow::Result<ConnectionTypeStream> {
-        self.inner.lock().conn_type_stream(public_key)
+        self.shard(public_key).conn_type_stream(public_key)
     }
 
     /// Get the [`EndpointInfo`]s for each endpoint
     pub fn endpoint_info(&self, public_key: &PublicKey) -> Option<EndpointInfo> {
-        self.inner.lock().endpoint_info(public_key)
+        self.shard(public_key).endpoint_info(public_key)
     }
 
     /// Saves the known node info to the given path, returning the number of nodes persisted.
@@ -235,13 +628,7 @@ impl NodeMap {
         ensure!(!path.is_dir(), "{} must be a file", path.display());
 
         // So, not sure what to do here.
-        let mut known_nodes = self
-            .inner
-            .lock()
-            .known_node_addresses()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .peekable();
+        let mut known_nodes = self.known_node_addresses().into_iter().peekable();
         if known_nodes.peek().is_none() {
             // prevent file handling if unnecessary
             return Ok(0);
@@ -283,10 +670,40 @@ impl NodeMap {
 
     /// Prunes nodes without recent activity so that at most [`MAX_INACTIVE_NODES`] are kept.
     pub fn prune_inactive(&self) {
-        self.inner.lock().prune_inactive();
+        for shard in 0..self.shards.len() {
+            self.prune_inactive_shard(shard);
+        }
+    }
+
+    /// Prunes the inactive nodes of `shard`, which keeps its part of [`MAX_INACTIVE_NODES`].
+    pub fn prune_inactive_shard(&self, shard: usize) {
+        let max_inactive = self.per_shard(MAX_INACTIVE_NODES);
+        self.shards[shard].lock().prune_inactive(max_inactive);
+    }
+
+    /// Removes the node, returning the mapped address it had.
+    pub fn remove_node(&self, node_key: &PublicKey) -> Option<QuicMappedAddr> {
+        self.shard(node_key)
+            .remove_node(node_key)
+            .map(|ep| *ep.quic_mapped_addr())
+    }
+
+    /// Number of mapped addresses currently allocated, one per node.
+    pub fn mapped_addr_count(&self) -> usize {
+        self.shards()
+            .map(|inner| inner.by_quic_mapped_addr.len())
+            .sum()
     }
 }
 
+/// Picks the shard of `node`, out of `count`.
+///
+/// Node keys are uniformly random, so their first bytes spread the nodes evenly.
+pub(super) fn shard_index(node: &PublicKey, count: usize) -> usize {
+    let bytes: [u8; 8] = node.as_bytes()[..8].try_into().expect("key is long enough");
+    (u64::from_le_bytes(bytes) % count as u64) as usize
+}
+
 impl NodeMapInner {
     /// Get the known node addresses stored in the map. Nodes with empty addressing information are
     /// filtered out.
@@ -297,22 +714,6 @@ impl NodeMapInner {
         })
     }
 
-    /// Create a new [`NodeMap`] from data stored in `path`.
-    fn load_from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let path = path.as_ref();
-        ensure!(path.is_file(), "{} is not a file", path.display());
-        let mut me = NodeMapInner::default();
-        let contents = std::fs::read(path)?;
-        let mut slice: &[u8] = &contents;
-        while !slice.is_empty() {
-            let (node_addr, next_contents) =
-                postcard::take_from_bytes(slice).context("failed to load node data")?;
-            me.add_node_addr(node_addr);
-            slice = next_contents;
-        }
-        Ok(me)
-    }
-
     /// Add the contact information for a node.
     #[instrument(skip_all, fields(node = %node_addr.node_id.fmt_short()))]
     fn add_node_addr(&mut self, node_addr: NodeAddr) {
@@ -326,6 +727,8 @@ impl NodeMapInner {
 
         endpoint.update_from_node_addr(&info);
         let id = endpoint.id();
+        self.unconfirmed.remove(&id);
+        self.announce(id);
         for endpoint in &info.direct_addresses {
             self.set_endpoint_for_ip_port(*endpoint, id);
         }
@@ -336,7 +739,7 @@ impl NodeMapInner {
             EndpointId::Id(id) => Some(*id),
             EndpointId::NodeKey(node_key) => self.by_node_key.get(node_key).copied(),
             EndpointId::QuicMappedAddr(addr) => self.by_quic_mapped_addr.get(addr).copied(),
-            EndpointId::IpPort(ipp) => self.by_ip_port.get(ipp).copied(),
+            EndpointId::IpPort(ipp) => self.by_ip_port.read().get(ipp).copied(),
         }
     }
 
@@ -362,18 +765,27 @@ impl NodeMapInner {
     }
 
     /// Marks the node we believe to be at `ipp` as recently used, returning the [`Endpoint`] if found.
-    fn receive_udp(&mut self, udp_addr: SocketAddr) -> Option<(PublicKey, QuicMappedAddr)> {
+    fn receive_udp(
+        &mut self,
+        udp_addr: SocketAddr,
+        len: usize,
+    ) -> Option<(PublicKey, QuicMappedAddr)> {
         let ip_port: IpPort = udp_addr.into();
         let Some(endpoint) = self.get_mut(EndpointId::IpPort(&ip_port)) else {
             info!(src=%udp_addr, "receive_udp: no node_map state found for addr, ignore");
             return None;
         };
-        endpoint.receive_udp(ip_port, Instant::now());
+        endpoint.receive_udp(ip_port, len, Instant::now());
         Some((*endpoint.public_key(), *endpoint.quic_mapped_addr()))
     }
 
     #[instrument(skip_all, fields(src = %src.fmt_short()))]
-    fn receive_relay(&mut self, relay_url: &RelayUrl, src: &PublicKey) -> QuicMappedAddr {
+    fn receive_relay(
+        &mut self,
+        relay_url: &RelayUrl,
+        src: &PublicKey,
+        len: usize,
+    ) -> QuicMappedAddr {
         let endpoint = self.get_or_insert_with(EndpointId::NodeKey(src), || {
             trace!("packets from unknown node, insert into node map");
             Options {
@@ -382,7 +794,7 @@ impl NodeMapInner {
                 active: true,
             }
         });
-        endpoint.receive_relay(relay_url, src, Instant::now());
+        endpoint.receive_relay(relay_url, src, len, Instant::now());
         *endpoint.quic_mapped_addr()
     }
 
@@ -424,39 +836,67 @@ impl NodeMapInner {
         }
     }
 
-    fn handle_pong(&mut self, sender: PublicKey, src: &DiscoMessageSource, pong: Pong) {
+    #[must_use = "actions must be handled"]
+    fn handle_pong(
+        &mut self,
+        sender: PublicKey,
+        src: &DiscoMessageSource,
+        pong: Pong,
+    ) -> Vec<PingAction> {
         if let Some(ep) = self.get_mut(EndpointId::NodeKey(&sender)).as_mut() {
             let insert = ep.handle_pong(&pong, src.into());
+            let msgs = ep.continue_probing(Instant::now());
+            let id = ep.id();
             if let Some((src, key)) = insert {
+                self.unconfirmed.remove(&id);
+                self.announce(id);
                 self.set_node_key_for_ip_port(src, &key);
             }
-            trace!(?insert, "received pong")
+            trace!(?insert, "received pong");
+            msgs
         } else {
-            warn!("received pong: node unknown, ignore")
+            warn_limited!("received pong: node unknown, ignore");
+            Vec::new()
         }
     }
 
     #[must_use = "actions must be handled"]
-    fn handle_call_me_maybe(&mut self, sender: PublicKey, cm: CallMeMaybe) -> Vec<PingAction> {
-        let ep_id = EndpointId::NodeKey(&sender);
-        if let Some(id) = self.get_id(ep_id.clone()) {
-            for number in &cm.my_numbers {
-                // ensure the new addrs are known
-                self.set_endpoint_for_ip_port(*number, id);
+    fn handle_call_me_maybe(
+        &mut self,
+        sender: PublicKey,
+        relay_url: &RelayUrl,
+        cm: CallMeMaybe,
+        max_unconfirmed: usize,
+    ) -> Vec<PingAction> {
+        let id = match self.get_id(EndpointId::NodeKey(&sender)) {
+            Some(id) => id,
+            // A node connecting to us sends its endpoints right away, which may well arrive
+            // before anything else from it.
+            None if self.unconfirmed.len() < max_unconfirmed => {
+                debug!("received call-me-maybe: node unknown, add to node map");
+                let id = self
+                    .insert_endpoint(Options {
+                        public_key: sender,
+                        relay_url: Some(relay_url.clone()),
+                        active: true,
+                    })
+                    .id();
+                self.unconfirmed.insert(id);
+                id
             }
-        }
-        match self.get_mut(ep_id) {
             None => {
                 inc!(MagicsockMetrics, recv_disco_call_me_maybe_bad_disco);
-                debug!("received call-me-maybe: ignore, node is unknown");
-                vec![]
+                debug!("received call-me-maybe: ignore, too many unconfirmed nodes");
+                return Vec::new();
             }
-            Some(ep) => {
-                debug!(endpoints = ?cm.my_numbers, "received call-me-maybe");
-
-                ep.handle_call_me_maybe(cm)
-            }
-        }
+        };
+        // The addresses are not mapped to the node for receiving yet, anyone could claim
+        // them.  This happens once a ping to them is answered, see `handle_pong`.
+        let Some(ep) = self.by_id.get_mut(&id) else {
+            return Vec::new();
+        };
+        debug!(endpoints = ?cm.my_numbers, "received call-me-maybe");
+        ep.handle_call_me_maybe(cm)
     }
 
     fn handle_ping(
@@ -476,7 +916,13 @@ impl NodeMapInner {
 
         let handled = endpoint.handle_ping(src.clone(), tx_id);
         if let SendAddr::Udp(ref addr) = src {
-            if matches!(handled.role, PingRole::NewEndpoint) {
+            // A possibly roamed node is only mapped to its new address once the challenge
+            // ping is answered, see `handle_pong`.
+            let is_roaming = handled
+                .needs_ping_back
+                .as_ref()
+                .is_some_and(|ping| ping.purpose == DiscoPingPurpose::Roaming);
+            if matches!(handled.role, PingRole::NewEndpoint) && !is_roaming {
                 self.set_node_key_for_ip_port(*addr, &sender);
             }
         }
@@ -490,12 +936,24 @@ impl NodeMapInner {
             relay_url = ?options.relay_url,
             "inserting new node endpoint in NodeMap",
         );
-        let id = self.next_id;
-        self.next_id = self.next_id.wrapping_add(1);
-        let ep = Endpoint::new(id, options);
+        let id = self.ids.next();
+        let mut ep = Endpoint::new(id, options);
+        ep.set_path_tuning(self.path_tuning);
+        ep.set_ping_timeout(self.timeouts.ping);
+        ep.set_prefer_ipv4(self.prefer_ipv4);
+        ep.set_event_watchers(self.events.clone());
+        ep.set_global_family_stats(self.family_stats.clone());
+        if let Some(policy) = self.relay_policies.get(ep.public_key()) {
+            ep.set_relay_policy(*policy);
+        }
 
         // update indices
-        self.by_quic_mapped_addr.insert(*ep.quic_mapped_addr(), id);
+        // Mapped addresses are derived from the id and never handed out twice by this map,
+        // so a node which is removed and comes back never receives packets quinn meant for
+        // an older mapping.
+        let previous = self.by_quic_mapped_addr.insert(*ep.quic_mapped_addr(), id);
+        debug_assert!(previous.is_none(), "mapped address reused");
+        inc!(MagicsockMetrics, mapped_addrs_allocated);
         self.by_node_key.insert(*ep.public_key(), id);
 
         self.by_id.insert(id, ep);
@@ -509,26 +967,29 @@ impl NodeMapInner {
     /// WireGuard for packets received from ipp.
     fn set_node_key_for_ip_port(&mut self, ipp: impl Into<IpPort>, nk: &PublicKey) {
         let ipp = ipp.into();
-        if let Some(id) = self.by_ip_port.get(&ipp) {
-            if !self.by_node_key.contains_key(nk) {
-                self.by_node_key.insert(*nk, *id);
+        let mut by_ip_port = self.by_ip_port.write();
+        if let Some(id) = by_ip_port.remove(&ipp) {
+            // The endpoint at ipp may be of another shard.
+            if !self.by_node_key.contains_key(nk) && self.by_id.contains_key(&id) {
+                self.by_node_key.insert(*nk, id);
             }
-            self.by_ip_port.remove(&ipp);
         }
-        if let Some(id) = self.by_node_key.get(nk) {
+        if let Some(&id) = self.by_node_key.get(nk) {
             trace!("insert ip -> id: {:?} -> {}", ipp, id);
-            self.by_ip_port.insert(ipp, *id);
+            by_ip_port.insert(ipp, id);
+            self.ip_ports_by_id.entry(id).or_default().insert(ipp);
         }
     }
 
     fn set_endpoint_for_ip_port(&mut self, ipp: impl Into<IpPort>, id: usize) {
         let ipp = ipp.into();
         trace!(?ipp, ?id, "set endpoint for ip:port");
-        self.by_ip_port.insert(ipp, id);
+        self.by_ip_port.write().insert(ipp, id);
+        self.ip_ports_by_id.entry(id).or_default().insert(ipp);
     }
 
-    /// Prunes nodes without recent activity so that at most [`MAX_INACTIVE_NODES`] are kept.
-    fn prune_inactive(&mut self) {
+    /// Prunes nodes without recent activity so that at most `max_inactive` are kept.
+    fn prune_inactive(&mut self, max_inactive: usize) {
         let now = Instant::now();
         let mut prune_candidates: Vec<_> = self
             .by_id
@@ -537,7 +998,7 @@ impl NodeMapInner {
             .map(|node| (*node.public_key(), node.last_used()))
             .collect();
 
-        let prune_count = prune_candidates.len().saturating_sub(MAX_INACTIVE_NODES);
+        let prune_count = prune_candidates.len().saturating_sub(max_inactive);
         if prune_count == 0 {
             // within limits
             return;
@@ -551,22 +1012,49 @@ impl NodeMapInner {
                 Some(last_used) => trace!(%node, ?last_used, "pruning inactive"),
                 None => trace!(%node, last_used=%"never", "pruning inactive"),
             }
+            let removed = self.remove_node(&public_key);
+            debug_assert!(
+                removed.is_some(),
+                "missing by_node_key entry for pk in by_id"
+            );
+        }
+    }
 
-            let Some(id) = self.by_node_key.remove(&public_key) else {
-                debug_assert!(false, "missing by_node_key entry for pk in by_id");
-                continue;
-            };
-
-            let Some(ep) = self.by_id.remove(&id) else {
-                debug_assert!(false, "missing by_id entry for id in by_node_key");
-                continue;
-            };
-
-            for ip_port in ep.direct_addresses() {
-                self.by_ip_port.remove(&ip_port);
+    /// Removes the node and all its indices, releasing its mapped address.
+    fn remove_node(&mut self, public_key: &PublicKey) -> Option<Endpoint> {
+        let id = self.by_node_key.remove(public_key)?;
+        let Some(ep) = self.by_id.remove(&id) else {
+            debug_assert!(false, "missing by_id entry for id in by_node_key");
+            return None;
+        };
+        self.unconfirmed.remove(&id);
+        if let Some(ip_ports) = self.ip_ports_by_id.remove(&id) {
+            let mut by_ip_port = self.by_ip_port.write();
+            for ip_port in ip_ports {
+                if by_ip_port.get(&ip_port) == Some(&id) {
+                    by_ip_port.remove(&ip_port);
+                }
             }
+        }
+        self.by_quic_mapped_addr.remove(ep.quic_mapped_addr());
+        inc!(MagicsockMetrics, mapped_addrs_released);
+        if self.announced.remove(&id) {
+            self.events.notify(MagicSockEvent::NodeRemoved(*public_key));
+        }
+        Some(ep)
+    }
 
-            self.by_quic_mapped_addr.remove(ep.quic_mapped_addr());
+    /// Reports the node as added, unless it was already.
+    ///
+    /// Nodes which only contacted us are not reported, anyone can claim any node id when
+    /// sending through a relay.
+    fn announce(&mut self, id: usize) {
+        if !self.announced.insert(id) {
+            return;
+        }
+        if let Some(ep) = self.by_id.get(&id) {
+            self.events
+                .notify(MagicSockEvent::NodeAdded(*ep.public_key()));
         }
     }
 }
@@ -672,7 +1160,7 @@ mod tests {
         let path = root.join("nodes.postcard");
         node_map.save_to_file(&path).await.unwrap();
 
-        let loaded_node_map = NodeMap::load_from_file(&path).unwrap();
+        let loaded_node_map = NodeMap::load_from_file(&path, 1, None).unwrap();
         let loaded: HashMap<PublicKey, AddrInfo> = loaded_node_map
             .known_node_addresses()
             .into_iter()
@@ -686,6 +1174,26 @@ mod tests {
             .collect();
         // compare the node maps via their known nodes
         assert_eq!(og, loaded);
+
+        // The stored addresses go through the filter.
+        #[derive(Debug)]
+        struct DropPort4000;
+        impl AddrFilter for DropPort4000 {
+            fn filter(&self, _node: &PublicKey, addr: SocketAddr) -> Option<SocketAddr> {
+                (addr.port() != 4000).then_some(addr)
+            }
+        }
+        let filtered_node_map = NodeMap::load_from_file(&path, 1, Some(&DropPort4000)).unwrap();
+        let info = filtered_node_map
+            .known_node_addresses()
+            .into_iter()
+            .find(|node_addr| node_addr.node_id == node_a)
+            .unwrap()
+            .info;
+        assert_eq!(
+            info.direct_addresses,
+            std::collections::BTreeSet::from([addr(4001)])
+        );
     }
 
     #[test]
@@ -694,8 +1202,7 @@ mod tests {
 
         let node_map = NodeMap::default();
         let public_key = SecretKey::generate().public();
-        let id = node_map
-            .inner
+        let id = node_map.shards[0]
             .lock()
             .insert_endpoint(Options {
                 public_key,
@@ -716,7 +1223,7 @@ mod tests {
             // add address
             node_map.add_node_addr(node_addr);
             // make it active
-            node_map.inner.lock().receive_udp(addr);
+            node_map.shards[0].lock().receive_udp(addr, 0);
         }
 
         info!("Adding offline/inactive addresses");
@@ -726,7 +1233,7 @@ mod tests {
             node_map.add_node_addr(node_addr);
         }
 
-        let mut node_map_inner = node_map.inner.lock();
+        let mut node_map_inner = node_map.shards[0].lock();
         let endpoint = node_map_inner.by_id.get_mut(&id).unwrap();
 
         info!("Adding alive addresses");
@@ -765,7 +1272,10 @@ mod tests {
         let active_node = SecretKey::generate().public();
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 167);
         node_map.add_node_addr(NodeAddr::new(active_node).with_direct_addresses([addr]));
-        node_map.inner.lock().receive_udp(addr).expect("registered");
+        node_map.shards[0]
+            .lock()
+            .receive_udp(addr, 0)
+            .expect("registered");
 
         for _ in 0..MAX_INACTIVE_NODES + 1 {
             let node = SecretKey::generate().public();
@@ -775,10 +1285,376 @@ mod tests {
         assert_eq!(node_map.node_count(), MAX_INACTIVE_NODES + 2);
         node_map.prune_inactive();
         assert_eq!(node_map.node_count(), MAX_INACTIVE_NODES + 1);
-        node_map
-            .inner
+        node_map.shards[0]
             .lock()
             .get(EndpointId::NodeKey(&active_node))
             .expect("should not be pruned");
+        assert_eq!(node_map.mapped_addr_count(), MAX_INACTIVE_NODES + 1);
+    }
+
+    #[test]
+    fn test_remove_node() {
+        let node_map = NodeMap::default();
+        let node = SecretKey::generate().public();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 167);
+        node_map.add_node_addr(NodeAddr::new(node).with_direct_addresses([addr]));
+        let mapped_addr = node_map.get_quic_mapped_addr_for_node_key(&node).unwrap();
+        assert_eq!(node_map.mapped_addr_count(), 1);
+
+        assert_eq!(node_map.remove_node(&node), Some(mapped_addr));
+        assert_eq!(node_map.remove_node(&node), None);
+        assert_eq!(node_map.mapped_addr_count(), 0);
+        assert!(node_map.receive_udp(addr, 0).is_none());
+
+        // A node coming back gets a fresh address.
+        node_map.add_node_addr(NodeAddr::new(node).with_direct_addresses([addr]));
+        let new_mapped_addr = node_map.get_quic_mapped_addr_for_node_key(&node).unwrap();
+        assert_ne!(new_mapped_addr, mapped_addr);
+        assert_eq!(node_map.mapped_addr_count(), 1);
+
+        // An address another node took over stays mapped to it.
+        let other = SecretKey::generate().public();
+        node_map.add_node_addr(NodeAddr::new(other).with_direct_addresses([addr]));
+        node_map.remove_node(&node);
+        assert_eq!(
+            node_map.receive_udp(addr, 0).map(|(node, _)| node),
+            Some(other)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping_all_paths() {
+        let node_map = NodeMap::default();
+        let (msg_sender, _msg_receiver) = crate::magicsock::actor_queue::channel(8);
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let node = SecretKey::generate().public();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 167);
+        node_map.add_node_addr(
+            NodeAddr::new(node)
+                .with_relay_url(relay_url.clone())
+                .with_direct_addresses([addr]),
+        );
+        assert!(node_map
+            .ping_all_paths(&SecretKey::generate().public())
+            .is_none());
+
+        let (msgs, waiters) = node_map.ping_all_paths(&node).unwrap();
+        let [relay_ping, direct_ping]: [SendPing; 2] = msgs
+            .into_iter()
+            .map(|msg| match msg {
+                PingAction::SendPing(ping) => ping,
+                other => panic!("expected a ping, got {other:?}"),
+            })
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+        assert_eq!(relay_ping.purpose, DiscoPingPurpose::Requested);
+        ping_pong(&node_map, node, direct_ping, &msg_sender);
+        node_map.notify_ping_sent(
+            relay_ping.id,
+            relay_ping.dst.clone(),
+            relay_ping.tx_id,
+            relay_ping.purpose,
+            msg_sender.clone(),
+        );
+        let _ = node_map.notify_ping_timeout(relay_ping.id, relay_ping.tx_id);
+
+        let [(relay_path, relay_waiter), (direct_path, direct_waiter)]: [PingWaiter; 2] =
+            waiters.try_into().unwrap();
+        assert_eq!(relay_path, PingPath::Relay(relay_url));
+        assert!(relay_waiter.await.is_err(), "timed out");
+        assert_eq!(direct_path, PingPath::Direct(addr));
+        assert!(direct_waiter.await.is_ok(), "pong received");
+    }
+
+    /// Pings `addr` of `node` and handles the pong coming back from it.
+    fn ping_pong(node_map: &NodeMap, node: PublicKey, ping: SendPing, msg_sender: &ActorSender) {
+        let SendAddr::Udp(addr) = ping.dst else {
+            panic!("expected a UDP ping");
+        };
+        node_map.notify_ping_sent(
+            ping.id,
+            ping.dst.clone(),
+            ping.tx_id,
+            ping.purpose,
+            msg_sender.clone(),
+        );
+        let pong = Pong {
+            tx_id: ping.tx_id,
+            src: SendAddr::Udp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1)),
+        };
+        let msgs = node_map.handle_pong(node, &DiscoMessageSource::Udp(addr), pong);
+        assert!(msgs.is_empty());
+    }
+
+    /// Sets up a node with an established direct path on `addr`.
+    fn node_with_direct_path(
+        node_map: &NodeMap,
+        addr: SocketAddr,
+        msg_sender: &ActorSender,
+    ) -> (PublicKey, QuicMappedAddr) {
+        let node = SecretKey::generate().public();
+        let handled = node_map.handle_ping(node, SendAddr::Udp(addr), TransactionId::default());
+        let ping = handled.needs_ping_back.expect("no direct path yet");
+        assert_eq!(ping.purpose, DiscoPingPurpose::Discovery);
+        ping_pong(node_map, node, ping, msg_sender);
+
+        let (key, quic_mapped_addr) = node_map.receive_udp(addr, 0).expect("known addr");
+        assert_eq!(key, node);
+        (node, quic_mapped_addr)
+    }
+
+    #[tokio::test]
+    async fn test_roaming_node() {
+        let _guard = iroh_test::logging::setup();
+        let node_map = NodeMap::default();
+        let (msg_sender, _msg_receiver) = crate::magicsock::actor_queue::channel(8);
+
+        let old_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4000);
+        let new_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4001);
+        let (node, quic_mapped_addr) = node_with_direct_path(&node_map, old_addr, &msg_sender);
+
+        // Mid-transfer all data goes to the old address.
+        let (_, udp_addr, _, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(udp_addr, Some(old_addr));
+
+        // The node moves and pings us from its new address.  This needs to be validated
+        // before packets from the new address are accepted.
+        let handled = node_map.handle_ping(node, SendAddr::Udp(new_addr), TransactionId::default());
+        assert!(matches!(handled.role, PingRole::NewEndpoint));
+        let challenge = handled.needs_ping_back.expect("challenge ping");
+        assert_eq!(challenge.purpose, DiscoPingPurpose::Roaming);
+        assert_eq!(challenge.dst, SendAddr::Udp(new_addr));
+        assert!(node_map.receive_udp(new_addr, 0).is_none());
+
+        // Once the challenge is answered, the new address is used for the same QUIC mapped
+        // address, so existing connections keep working.
+        ping_pong(&node_map, node, challenge, &msg_sender);
+        let (key, addr) = node_map.receive_udp(new_addr, 0).expect("validated addr");
+        assert_eq!(key, node);
+        assert_eq!(addr, quic_mapped_addr);
+        let (_, udp_addr, _, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(udp_addr, Some(new_addr));
+    }
+
+    #[tokio::test]
+    async fn test_roaming_unanswered_challenge() {
+        let _guard = iroh_test::logging::setup();
+        let node_map = NodeMap::default();
+        let (msg_sender, _msg_receiver) = crate::magicsock::actor_queue::channel(8);
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4000);
+        let spoofed_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+        let (node, quic_mapped_addr) = node_with_direct_path(&node_map, addr, &msg_sender);
+
+        // A replayed ping from another address is challenged but never answered.
+        let handled =
+            node_map.handle_ping(node, SendAddr::Udp(spoofed_addr), TransactionId::default());
+        let challenge = handled.needs_ping_back.expect("challenge ping");
+        assert_eq!(challenge.purpose, DiscoPingPurpose::Roaming);
+
+        assert!(node_map.receive_udp(spoofed_addr, 0).is_none());
+        let (_, udp_addr, _, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(udp_addr, Some(addr));
+    }
+
+    /// Each node map hands out its own mapped addresses, without a process wide counter.
+    #[test]
+    fn test_quic_mapped_addrs_per_map() {
+        let node = SecretKey::generate().public();
+        let mapped_addr = || {
+            let node_map = NodeMap::default();
+            node_map.add_node_addr(NodeAddr::new(node));
+            node_map.add_node_addr(NodeAddr::new(SecretKey::generate().public()));
+            node_map.get_quic_mapped_addr_for_node_key(&node).unwrap()
+        };
+        assert_eq!(mapped_addr(), mapped_addr());
+    }
+
+    #[test]
+    fn test_advertised_home_relay() {
+        let node_map = NodeMap::default();
+        let node = SecretKey::generate().public();
+        let our_relay: RelayUrl = "https://our.relay.example".parse().unwrap();
+        let their_relay: RelayUrl = "https://their.relay.example".parse().unwrap();
+
+        // The node reached us over our home relay.
+        node_map.handle_ping(
+            node,
+            SendAddr::Relay(our_relay.clone()),
+            TransactionId::default(),
+        );
+        let quic_mapped_addr = node_map.get_quic_mapped_addr_for_node_key(&node).unwrap();
+        let (_, _, relay_url, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(relay_url, Some(our_relay.clone()));
+
+        // Once it advertised its home relay, we relay via that one.
+        node_map.set_home_relay(node, their_relay.clone());
+        let (_, _, relay_url, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(relay_url, Some(their_relay.clone()));
+
+        // Further pings over our relay do not change this.
+        node_map.handle_ping(
+            node,
+            SendAddr::Relay(our_relay),
+            TransactionId::from([1u8; 12]),
+        );
+        let (_, _, relay_url, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(relay_url, Some(their_relay));
+    }
+
+    /// A call-me-maybe from a node we have never heard of adds it to the map.
+    #[test]
+    fn test_call_me_maybe_unknown_node() {
+        let node_map = NodeMap::default();
+        let node = SecretKey::generate().public();
+        let relay_url: RelayUrl = "https://my-relay.example".parse().unwrap();
+        let addr = SocketAddr::from((Ipv4Addr::new(203, 0, 113, 1), 4000));
+
+        let ping_actions = node_map.handle_call_me_maybe(
+            node,
+            &relay_url,
+            CallMeMaybe {
+                my_numbers: vec![addr],
+            },
+        );
+        assert_eq!(node_map.node_count(), 1);
+        assert!(ping_actions.iter().any(|action| matches!(
+            action,
+            PingAction::SendPing(SendPing { dst: SendAddr::Udp(dst), .. }) if *dst == addr
+        )));
+
+        let quic_mapped_addr = node_map.get_quic_mapped_addr_for_node_key(&node).unwrap();
+        let (_, _, url, _, _) = node_map
+            .get_send_addrs_for_quic_mapped_addr(&quic_mapped_addr, false)
+            .unwrap();
+        assert_eq!(url, Some(relay_url));
+        assert!(
+            node_map.receive_udp(addr, 0).is_none(),
+            "call-me-maybe addresses are not trusted before a pong"
+        );
+    }
+
+    /// Nodes which only contacted us are not reported as added.
+    #[tokio::test]
+    async fn test_node_added_events() {
+        use futures::StreamExt;
+
+        let node_map = NodeMap::default();
+        let events = EventWatchers::default();
+        node_map.set_event_watchers(events.clone());
+        let stream = events.subscribe();
+        let contacted = SecretKey::generate().public();
+        let added = SecretKey::generate().public();
+        let relay_url: RelayUrl = "https://my-relay.example".parse().unwrap();
+
+        let _ = node_map.handle_call_me_maybe(
+            contacted,
+            &relay_url,
+            CallMeMaybe {
+                my_numbers: vec![SocketAddr::from((Ipv4Addr::new(203, 0, 113, 1), 4000))],
+            },
+        );
+        node_map.add_node_addr(NodeAddr::new(added));
+        assert_eq!(node_map.node_count(), 2);
+        assert!(node_map.remove_node(&contacted).is_some());
+        assert!(node_map.remove_node(&added).is_some());
+        events.close();
+
+        let got: Vec<_> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(
+            got,
+            [
+                MagicSockEvent::NodeAdded(added),
+                MagicSockEvent::NodeRemoved(added)
+            ]
+        );
+    }
+
+    /// Only a limited number of unknown nodes is added by call-me-maybe messages.
+    #[test]
+    fn test_call_me_maybe_unknown_nodes_capped() {
+        let node_map = NodeMap::default();
+        let relay_url: RelayUrl = "https://my-relay.example".parse().unwrap();
+        let call_me_maybe = || CallMeMaybe {
+            my_numbers: vec![SocketAddr::from((Ipv4Addr::new(203, 0, 113, 1), 4000))],
+        };
+        let nodes: Vec<_> = (0..MAX_UNCONFIRMED_NODES)
+            .map(|_| SecretKey::generate().public())
+            .collect();
+        for node in &nodes {
+            let _ = node_map.handle_call_me_maybe(*node, &relay_url, call_me_maybe());
+        }
+        assert_eq!(node_map.node_count(), MAX_UNCONFIRMED_NODES);
+
+        let late = SecretKey::generate().public();
+        let ping_actions = node_map.handle_call_me_maybe(late, &relay_url, call_me_maybe());
+        assert!(ping_actions.is_empty());
+        assert_eq!(node_map.node_count(), MAX_UNCONFIRMED_NODES);
+
+        // Known nodes still get through, and make room once vouched for.
+        let ping_actions = node_map.handle_call_me_maybe(nodes[0], &relay_url, call_me_maybe());
+        assert!(!ping_actions.is_empty());
+        node_map.add_node_addr(NodeAddr::new(nodes[0]));
+        let _ = node_map.handle_call_me_maybe(late, &relay_url, call_me_maybe());
+        assert_eq!(node_map.node_count(), MAX_UNCONFIRMED_NODES + 1);
+    }
+
+    #[test]
+    fn test_shard_index() {
+        let count = 4;
+        let mut per_shard = [0usize; 4];
+        for _ in 0..400 {
+            let key = SecretKey::generate().public();
+            let shard = shard_index(&key, count);
+            // the same node always lands on the same shard
+            assert_eq!(shard, shard_index(&key, count));
+            per_shard[shard] += 1;
+        }
+        assert!(per_shard.iter().all(|n| *n > 50), "{per_shard:?}");
+    }
+
+    /// The nodes of a sharded map are found by all kinds of lookups.
+    #[test]
+    fn test_sharded_lookups() {
+        let node_map = NodeMap::new(4);
+        let nodes: Vec<_> = (0..64u16)
+            .map(|i| {
+                let node = SecretKey::generate().public();
+                let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1000 + i));
+                node_map.add_node_addr(NodeAddr::new(node).with_direct_addresses([addr]));
+                (node, addr)
+            })
+            .collect();
+        assert_eq!(node_map.node_count(), nodes.len());
+        assert_eq!(node_map.mapped_addr_count(), nodes.len());
+
+        for (node, addr) in &nodes {
+            let mapped_addr = node_map.get_quic_mapped_addr_for_node_key(node).unwrap();
+            let id = mapped_addr.endpoint_id().unwrap();
+            assert_eq!(id % 4, shard_index(node, 4));
+            assert_eq!(node_map.receive_udp(*addr, 0), Some((*node, mapped_addr)));
+            let (public_key, ..) = node_map
+                .get_send_addrs_for_quic_mapped_addr(&mapped_addr, false)
+                .unwrap();
+            assert_eq!(public_key, *node);
+        }
+
+        let (node, addr) = nodes[0];
+        assert!(node_map.remove_node(&node).is_some());
+        assert_eq!(node_map.receive_udp(addr, 0), None);
+        assert_eq!(node_map.node_count(), nodes.len() - 1);
     }
 }
@@ -59,6 +59,8 @@ pub enum ClearReason {
     Reset,
     Inactive,
     PongTimeout,
+    Roamed,
+    Goodbye,
 }
 
 impl BestAddr {
@@ -138,6 +140,24 @@ impl BestAddr {
         }
     }
 
+    /// Extends the trust in `addr` if it is the current, trusted, best address.
+    ///
+    /// Used when an authenticated disco ping received on the address shows it still works,
+    /// so that no ping of our own is needed to keep it trusted.
+    pub fn extend_trust_if_equals(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(state) = self.0.as_mut() {
+            if state.addr.addr == addr {
+                if let Some(trust_until) = state.trust_until.as_mut() {
+                    *trust_until = (*trust_until).max(now + TRUST_UDP_ADDR_DURATION);
+                }
+            }
+        }
+    }
+
+    /// Inserts `addr` if it is better than the current best address, or reconfirms it.
+    ///
+    /// IPv6 addresses are preferred over IPv4 addresses of similar latency, or the other way
+    /// around with `prefer_ipv4`.
     pub fn insert_if_better_or_reconfirm(
         &mut self,
         addr: SocketAddr,
@@ -145,6 +165,7 @@ impl BestAddr {
         source: Source,
         confirmed_at: Instant,
         has_relay: bool,
+        prefer_ipv4: bool,
     ) {
         match self.0.as_mut() {
             None => {
@@ -152,7 +173,9 @@ impl BestAddr {
             }
             Some(state) => {
                 let candidate = AddrLatency { addr, latency };
-                if !state.is_trusted(confirmed_at) || candidate.is_better_than(&state.addr) {
+                if !state.is_trusted(confirmed_at)
+                    || candidate.is_better_than(&state.addr, prefer_ipv4)
+                {
                     self.insert(addr, latency, source, confirmed_at, has_relay);
                 } else if state.addr.addr == addr {
                     state.confirmed_at = confirmed_at;
@@ -234,17 +257,22 @@ pub struct AddrLatency {
 
 impl AddrLatency {
     /// Reports whether `self` is a better addr to use than `other`.
-    fn is_better_than(&self, other: &Self) -> bool {
+    fn is_better_than(&self, other: &Self, prefer_ipv4: bool) -> bool {
         if self.addr == other.addr {
             return false;
         }
-        if self.addr.is_ipv6() && other.addr.is_ipv4() {
+        let preferred = |addr: &SocketAddr| addr.is_ipv4() == prefer_ipv4;
+        if preferred(&self.addr) && !preferred(&other.addr) {
             // Prefer IPv6 for being a bit more robust, as long as
-            // the latencies are roughly equivalent.
+            // the latencies are roughly equivalent.  Unless IPv6 is
+            // known to be unreliable, then IPv4 is preferred.
             if self.latency / 10 * 9 < other.latency {
                 return true;
             }
-        } else if self.addr.is_ipv4() && other.addr.is_ipv6() && other.is_better_than(self) {
+        } else if !preferred(&self.addr)
+            && preferred(&other.addr)
+            && other.is_better_than(self, prefer_ipv4)
+        {
             return false;
         }
         self.latency < other.latency
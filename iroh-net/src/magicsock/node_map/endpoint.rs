@@ -2,13 +2,17 @@ use std::{
     collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
     hash::Hash,
     net::{IpAddr, SocketAddr},
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use iroh_metrics::inc;
+use iroh_metrics::{inc, inc_by, observe};
 use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::sync::oneshot;
 use tracing::{debug, info, instrument, trace, warn};
 use watchable::Watchable;
 
@@ -16,17 +20,24 @@ use crate::{
     disco::{self, SendAddr},
     key::PublicKey,
     magic_endpoint::AddrInfo,
-    magicsock::{Timer, HEARTBEAT_INTERVAL},
-    net::ip::is_unicast_link_local,
+    magicsock::{PathTuning, Timer, HEARTBEAT_INTERVAL},
+    net::ip::{self, is_unicast_link_local},
     relay::RelayUrl,
     stun,
     util::relay_only_mode,
     NodeAddr, NodeId,
 };
 
-use crate::magicsock::{metrics::Metrics as MagicsockMetrics, ActorMessage, QuicMappedAddr};
+use crate::magicsock::{
+    actor_queue::ActorSender,
+    events::{EventWatchers, MagicSockEvent},
+    log_limit::warn_limited,
+    metrics::Metrics as MagicsockMetrics,
+    ActorMessage, QuicMappedAddr,
+};
 
 use super::best_addr::{self, BestAddr, ClearReason};
+use super::family_stats::{FamilyStats, SharedFamilyStats};
 use super::IpPort;
 
 /// Number of addresses that are not active that we keep around per node.
@@ -38,7 +49,7 @@ pub(super) const MAX_INACTIVE_DIRECT_ADDRESSES: usize = 20;
 const LAST_ALIVE_PRUNE_DURATION: Duration = Duration::from_secs(120);
 
 /// How long we wait for a pong reply before assuming it's never coming.
-const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
+pub(in crate::magicsock) const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
 
 /// The minimum time between pings to an endpoint. (Except in the case of CallMeMaybe frames
 /// resetting the counter, as the first pings likely didn't through the firewall)
@@ -57,6 +68,35 @@ const UPGRADE_INTERVAL: Duration = Duration::from_secs(60);
 /// How long until we send a stayin alive ping
 const STAYIN_ALIVE_MIN_ELAPSED: Duration = Duration::from_secs(2);
 
+/// How often we retry call-me-maybe messages when our NAT mapping varies by destination.
+///
+/// With such an endpoint-dependent mapping the address the node learned from the relay is
+/// not the one our pings to it leave from, so the first attempts to punch a hole fail more
+/// often and we retry more aggressively than once per [`HEARTBEAT_INTERVAL`].
+const VARYING_MAPPING_CALL_ME_MAYBE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many pings over the relay may go unanswered before the node is considered unreachable.
+const RELAY_UNREACHABLE_PING_TIMEOUTS: u8 = 2;
+
+/// How long the relay path is avoided after the relay actor could not keep up with sends.
+const RELAY_CONGESTION_DURATION: Duration = Duration::from_secs(1);
+
+/// How often we retry call-me-maybe messages on a metered network.
+const METERED_CALL_ME_MAYBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The longest we back off pinging a direct address which keeps timing out.
+const UNREACHABLE_PATH_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long after the first contact a node without a verified direct path is reported as
+/// having none, see [`NoDirectPathReason`].
+const DIRECT_PATH_DEADLINE: Duration = Duration::from_secs(30);
+
+/// How far the send time of a goodbye may be from our clock for it to be handled.
+///
+/// Older goodbyes are replays, and this bounds the time a captured goodbye can be replayed
+/// after the node restarted, or after we forgot its last goodbye.
+const GOODBYE_MAX_AGE: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub(in crate::magicsock) enum PingAction {
     SendCallMeMaybe {
@@ -123,11 +163,26 @@ pub(super) struct Endpoint {
     ///
     /// The fallback/bootstrap path, if non-zero (non-zero for well-behaved clients).
     relay_url: Option<(RelayUrl, PathState)>,
+    /// The home relay the node advertised in its disco pings, if any.
+    ///
+    /// Once known, relayed traffic is sent to this relay rather than to whichever relay
+    /// the node's messages arrived on.
+    home_relay: Option<RelayUrl>,
+    /// Whether the node answers over its relay.
+    relay_reachability: RelayReachability,
+    /// Number of consecutive pings over the relay which timed out.
+    relay_ping_timeouts: u8,
+    /// Until when the relay path is considered congested.
+    ///
+    /// While congested, data is only sent via a direct path if we have any.
+    relay_congested_until: Option<Instant>,
     /// Best non-relay path, i.e. a UDP address.
     best_addr: BestAddr,
     /// State for each of this node's direct paths.
     direct_addr_state: BTreeMap<IpPort, PathState>,
     sent_pings: HashMap<stun::TransactionId, SentPing>,
+    /// Where the latencies of the pings requested with [`Endpoint::ping_all_paths`] go.
+    ping_waiters: HashMap<stun::TransactionId, oneshot::Sender<Duration>>,
     /// Last time this node was used.
     ///
     /// A node is marked as in use when an endpoint to contact them is requested or if UDP activity
@@ -144,6 +199,38 @@ pub(super) struct Endpoint {
     last_call_me_maybe: Option<Instant>,
     /// The type of connection we have to the node, either direct, relay, mixed, or none.
     pub conn_type: Watchable<ConnectionType>,
+    /// When this node was first used, the start of the time to a direct path.
+    first_contact: Option<Instant>,
+    /// Time from the first contact until the first verified direct path.
+    time_to_direct: Option<Duration>,
+    /// Why no direct path was verified within [`DIRECT_PATH_DEADLINE`].
+    no_direct_path: Option<NoDirectPathReason>,
+    /// Limits for probing the direct paths.
+    path_tuning: PathTuning,
+    /// How long to wait for the pong to a ping.
+    ping_timeout: Duration,
+    /// Whether the last full ping left direct paths unprobed because of
+    /// [`PathTuning::max_concurrent_probes`].
+    deferred_probes: bool,
+    /// Whether QUIC packets may be sent through the relay.
+    relay_policy: RelayPolicy,
+    /// Last time a valid direct path was used, for [`RelayPolicy::FallbackAfter`].
+    last_direct: Option<Instant>,
+    /// Whether IPv4 paths are preferred over IPv6 paths of similar latency.
+    prefer_ipv4: bool,
+    /// Where the path switching from a relay to a direct address is reported.
+    events: EventWatchers,
+    /// Bytes of QUIC packets sent to the node, counted by the send path, see
+    /// [`Endpoint::sent_counter`].
+    bytes_sent: Arc<AtomicU64>,
+    /// Bytes of QUIC packets received from the node.
+    bytes_recv: u64,
+    /// Outcomes of the probes to the global direct paths of this node.
+    family_stats: FamilyStats,
+    /// Outcomes of the probes to the global direct paths of all nodes.
+    global_family_stats: SharedFamilyStats,
+    /// Send time of the last goodbye handled from the node, see [`disco::Goodbye::sent_at`].
+    last_goodbye: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -156,25 +243,46 @@ pub(super) struct Options {
 
 impl Endpoint {
     pub(super) fn new(id: usize, options: Options) -> Self {
-        let quic_mapped_addr = QuicMappedAddr::generate();
+        let quic_mapped_addr = QuicMappedAddr::for_endpoint(id);
 
         if options.relay_url.is_some() {
             // we potentially have a relay connection to the node
             inc!(MagicsockMetrics, num_relay_conns_added);
         }
 
+        let last_used = options.active.then(Instant::now);
         Endpoint {
             id,
             quic_mapped_addr,
             node_id: options.public_key,
             last_full_ping: None,
             relay_url: options.relay_url.map(|url| (url, PathState::default())),
+            home_relay: None,
+            relay_reachability: RelayReachability::Unknown,
+            relay_ping_timeouts: 0,
+            relay_congested_until: None,
             best_addr: Default::default(),
             sent_pings: HashMap::new(),
+            ping_waiters: HashMap::new(),
             direct_addr_state: BTreeMap::new(),
-            last_used: options.active.then(Instant::now),
+            last_used,
             last_call_me_maybe: None,
             conn_type: Watchable::new(ConnectionType::None),
+            first_contact: last_used,
+            time_to_direct: None,
+            no_direct_path: None,
+            path_tuning: Default::default(),
+            ping_timeout: PING_TIMEOUT_DURATION,
+            deferred_probes: false,
+            relay_policy: RelayPolicy::Allow,
+            last_direct: None,
+            prefer_ipv4: false,
+            events: Default::default(),
+            bytes_sent: Default::default(),
+            bytes_recv: 0,
+            family_stats: FamilyStats::default(),
+            global_family_stats: Default::default(),
+            last_goodbye: None,
         }
     }
 
@@ -182,6 +290,53 @@ impl Endpoint {
         &self.node_id
     }
 
+    pub(super) fn set_path_tuning(&mut self, path_tuning: PathTuning) {
+        self.path_tuning = path_tuning;
+    }
+
+    pub(super) fn set_ping_timeout(&mut self, ping_timeout: Duration) {
+        self.ping_timeout = ping_timeout;
+    }
+
+    pub(super) fn set_prefer_ipv4(&mut self, prefer_ipv4: bool) {
+        self.prefer_ipv4 = prefer_ipv4;
+    }
+
+    pub(super) fn set_event_watchers(&mut self, events: EventWatchers) {
+        self.events = events;
+    }
+
+    pub(super) fn set_global_family_stats(&mut self, stats: SharedFamilyStats) {
+        self.global_family_stats = stats;
+    }
+
+    /// Records the outcome of a probe to a direct path.
+    ///
+    /// Paths on the local network are left out, they fail whenever we are on another network.
+    fn record_probe(&mut self, addr: SocketAddr, success: bool) {
+        if ProbeClass::of(&addr.ip()) == ProbeClass::Lan {
+            return;
+        }
+        self.family_stats.record(addr.ip(), success);
+        self.global_family_stats.record(addr.ip(), success);
+    }
+
+    /// Whether IPv6 paths to this node fail much more often than IPv4 paths.
+    ///
+    /// Falls back to the outcomes of all nodes until enough probes to this node completed,
+    /// so a host with broken IPv6 does not try IPv6 first for every new node.
+    fn ipv6_unreliable(&self) -> bool {
+        self.family_stats
+            .ipv6_unreliable()
+            .or_else(|| self.global_family_stats.get().ipv6_unreliable())
+            .unwrap_or(false)
+    }
+
+    /// Whether IPv4 paths are preferred over IPv6 paths of similar latency.
+    fn prefers_ipv4(&self) -> bool {
+        self.prefer_ipv4 || self.ipv6_unreliable()
+    }
+
     pub(super) fn quic_mapped_addr(&self) -> &QuicMappedAddr {
         &self.quic_mapped_addr
     }
@@ -239,9 +394,186 @@ impl Endpoint {
             conn_type,
             latency,
             last_used: self.last_used.map(|instant| now.duration_since(instant)),
+            relay_reachability: self.relay_reachability,
+            time_to_direct: self.time_to_direct,
+            no_direct_path: self.no_direct_path,
+            relay_policy: self.relay_policy,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_recv: self.bytes_recv,
+            family_stats: self.family_stats,
         }
     }
 
+    /// The counter of the bytes of QUIC packets sent to the node.
+    ///
+    /// Handed out with the send addresses, so the bytes can be counted once they were sent
+    /// without locking the node map again.
+    pub(super) fn sent_counter(&self) -> Arc<AtomicU64> {
+        self.bytes_sent.clone()
+    }
+
+    pub(super) fn set_relay_policy(&mut self, policy: RelayPolicy) {
+        self.relay_policy = policy;
+    }
+
+    /// Whether the [`RelayPolicy`] currently allows sending QUIC packets through the relay.
+    pub(super) fn relay_allowed(&self, now: Instant) -> bool {
+        match self.relay_policy {
+            RelayPolicy::Allow => true,
+            RelayPolicy::Forbid => false,
+            RelayPolicy::FallbackAfter(after) => {
+                let since = self.last_direct.or(self.first_contact).unwrap_or(now);
+                now.saturating_duration_since(since) >= after
+            }
+        }
+    }
+
+    /// Records the time to the first verified direct path.
+    fn note_direct_path(&mut self, now: Instant) {
+        if self.time_to_direct.is_some() || self.best_addr.addr().is_none() {
+            return;
+        }
+        let Some(first_contact) = self.first_contact else {
+            return;
+        };
+        let elapsed = now.saturating_duration_since(first_contact);
+        debug!(?elapsed, "first direct path verified");
+        self.time_to_direct = Some(elapsed);
+        record_time_to_direct(elapsed);
+    }
+
+    /// Reports why there is no direct path, once [`DIRECT_PATH_DEADLINE`] passed without one.
+    fn check_direct_path_deadline(&mut self, now: Instant, conditions: &LocalConditions) {
+        if self.time_to_direct.is_some() || self.no_direct_path.is_some() {
+            return;
+        }
+        let Some(first_contact) = self.first_contact else {
+            return;
+        };
+        if now.saturating_duration_since(first_contact) < DIRECT_PATH_DEADLINE {
+            return;
+        }
+        let reason = self.no_direct_path_reason(conditions);
+        info!(%reason, "no direct path found");
+        match reason {
+            NoDirectPathReason::Policy => inc!(MagicsockMetrics, direct_path_failed_policy),
+            NoDirectPathReason::NoAddresses => {
+                inc!(MagicsockMetrics, direct_path_failed_no_addrs)
+            }
+            NoDirectPathReason::IpFamilyMismatch => {
+                inc!(MagicsockMetrics, direct_path_failed_ip_family)
+            }
+            NoDirectPathReason::SymmetricNat => {
+                inc!(MagicsockMetrics, direct_path_failed_symmetric_nat)
+            }
+            NoDirectPathReason::HolePunchingFailed => {
+                inc!(MagicsockMetrics, direct_path_failed_hole_punching)
+            }
+        }
+        self.no_direct_path = Some(reason);
+    }
+
+    /// Best guess why no direct path could be verified.
+    fn no_direct_path_reason(&self, conditions: &LocalConditions) -> NoDirectPathReason {
+        if conditions.direct_disabled {
+            return NoDirectPathReason::Policy;
+        }
+        if self.direct_addr_state.is_empty() {
+            return NoDirectPathReason::NoAddresses;
+        }
+        let usable = self.direct_addr_state.keys().any(|addr| {
+            let can_send = match addr.ip() {
+                IpAddr::V4(_) => conditions.ipv4,
+                IpAddr::V6(_) => conditions.ipv6,
+            };
+            can_send != Some(false)
+        });
+        if !usable {
+            return NoDirectPathReason::IpFamilyMismatch;
+        }
+        if conditions.symmetric_nat {
+            return NoDirectPathReason::SymmetricNat;
+        }
+        NoDirectPathReason::HolePunchingFailed
+    }
+
+    /// Records that the node answered or reached us over its relay.
+    fn relay_reachable(&mut self) {
+        self.relay_ping_timeouts = 0;
+        self.relay_reachability = RelayReachability::Reachable;
+    }
+
+    /// Records that the relay server reported the node as disconnected from `url`.
+    pub(super) fn relay_peer_gone(&mut self, url: &RelayUrl) {
+        if self.relay_url.as_ref().is_some_and(|(home, _)| home == url) {
+            debug!(%url, "node disconnected from its relay");
+            self.relay_reachability = RelayReachability::Unreachable;
+        }
+    }
+
+    /// Handles a goodbye from the node, it shut down so none of its paths work anymore.
+    ///
+    /// The addresses are kept, so the node can be reached again once it is back.  Goodbyes
+    /// which are not recent, or not newer than the last one handled, are replays or copies
+    /// from another path, which are ignored.  Returns whether the goodbye was handled, in
+    /// which case [`MagicSockEvent::NodeOffline`] is reported.
+    pub(super) fn handle_goodbye(&mut self, goodbye: &disco::Goodbye, now: SystemTime) -> bool {
+        let tx = hex::encode(goodbye.tx_id);
+        if !goodbye.is_recent(now, GOODBYE_MAX_AGE) {
+            debug!(%tx, "ignoring goodbye which is not recent");
+            return false;
+        }
+        if self
+            .last_goodbye
+            .is_some_and(|last| goodbye.sent_at <= last)
+        {
+            debug!(%tx, "ignoring replayed goodbye");
+            return false;
+        }
+        self.last_goodbye = Some(goodbye.sent_at);
+        debug!(%tx, "node said goodbye");
+        self.best_addr
+            .clear(ClearReason::Goodbye, self.relay_url.is_some());
+        for state in self.direct_addr_state.values_mut() {
+            state.clear();
+        }
+        if let Some((_url, state)) = self.relay_url.as_mut() {
+            state.clear();
+        }
+        self.relay_reachability = RelayReachability::Unreachable;
+        self.last_full_ping = None;
+        self.last_used = None;
+        let _ = self.conn_type.update(ConnectionType::None);
+        self.events
+            .notify(MagicSockEvent::NodeOffline(self.node_id));
+        true
+    }
+
+    /// Returns the paths to say goodbye on when shutting down, if the node is in active use.
+    pub(super) fn goodbye_addrs(&self, now: &Instant) -> Vec<SendAddr> {
+        if !self.is_active(now) {
+            return Vec::new();
+        }
+        self.best_addr
+            .addr()
+            .map(SendAddr::Udp)
+            .into_iter()
+            .chain(self.relay_url().map(SendAddr::Relay))
+            .collect()
+    }
+
+    /// Marks the relay path as congested, because the relay actor could not queue a send.
+    pub(super) fn relay_congested(&mut self, now: Instant) {
+        if !self.is_relay_congested(&now) {
+            debug!("relay path congested");
+        }
+        self.relay_congested_until = Some(now + RELAY_CONGESTION_DURATION);
+    }
+
+    fn is_relay_congested(&self, now: &Instant) -> bool {
+        self.relay_congested_until.is_some_and(|until| *now < until)
+    }
+
     /// Returns the relay url of this endpoint
     pub(super) fn relay_url(&self) -> Option<RelayUrl> {
         self.relay_url.as_ref().map(|(url, _state)| url.clone())
@@ -250,14 +582,25 @@ impl Endpoint {
     /// Returns the address(es) that should be used for sending the next packet.
     ///
     /// Any or all of the UDP and relay addrs may be non-zero.
+    ///
+    /// When `varying_mapping` is set, i.e. our NAT mapping varies by destination, and no
+    /// direct path was confirmed yet, we only send via the relay instead of trying an
+    /// unconfirmed candidate address, as these are unlikely to work before a hole is punched.
+    ///
+    /// While the relay path is congested it is skipped whenever a direct address is
+    /// available, rather than adding to the backlog of the relay.
+    ///
+    /// The relay is only returned if the [`RelayPolicy`] allows it.
     fn addr_for_send(
         &mut self,
         now: &Instant,
         have_ipv6: bool,
+        varying_mapping: bool,
     ) -> (Option<SocketAddr>, Option<RelayUrl>) {
+        let relay = self.relay_url().filter(|_| self.relay_allowed(*now));
         if relay_only_mode() {
             debug!("in `DEV_relay_ONLY` mode, giving the relay address as the only viable address for this endpoint");
-            return (None, self.relay_url());
+            return (None, relay);
         }
         // Update our best addr from candidate addresses (only if it is empty and if we have
         // recent pongs).
@@ -267,6 +610,11 @@ impl Endpoint {
                 // If we have a valid address we use it.
                 trace!(addr = %best_addr.addr, latency = ?best_addr.latency,
                        "best_addr is set and valid, use best_addr only");
+                self.last_direct = Some(*now);
+                (Some(best_addr.addr), None)
+            }
+            best_addr::State::Outdated(best_addr) if self.is_relay_congested(now) => {
+                trace!(addr = %best_addr.addr, "best_addr is outdated, relay congested, use best_addr only");
                 (Some(best_addr.addr), None)
             }
             best_addr::State::Outdated(best_addr) => {
@@ -275,7 +623,13 @@ impl Endpoint {
                 // works (i.e. we don't need to holepunch again).
                 trace!(addr = %best_addr.addr, latency = ?best_addr.latency,
                        "best_addr is set but outdated, use best_addr and relay");
-                (Some(best_addr.addr), self.relay_url())
+                (Some(best_addr.addr), relay)
+            }
+            best_addr::State::Empty if varying_mapping && relay.is_some() => {
+                // With a mapping varying by destination an unconfirmed candidate is unlikely
+                // to work, rely on the relay until the pings confirm a direct path.
+                trace!("best_addr is unset and mapping varies by destination, use relay only");
+                (None, relay)
             }
             best_addr::State::Empty => {
                 // No direct connection has been used before.  If we know of any possible
@@ -290,8 +644,13 @@ impl Endpoint {
                     })
                     .choose_stable(&mut rand::thread_rng())
                     .map(|ipp| SocketAddr::from(*ipp));
-                trace!(udp_addr = ?addr, "best_addr is unset, use candidate addr and relay");
-                (addr, self.relay_url())
+                if addr.is_some() && self.is_relay_congested(now) {
+                    trace!(udp_addr = ?addr, "best_addr is unset, relay congested, use candidate addr only");
+                    (addr, None)
+                } else {
+                    trace!(udp_addr = ?addr, "best_addr is unset, use candidate addr and relay");
+                    (addr, relay)
+                }
             }
         };
         match (best_addr, relay_url.clone()) {
@@ -301,7 +660,13 @@ impl Endpoint {
                     .update(ConnectionType::Mixed(best_addr, relay_url));
             }
             (Some(best_addr), None) => {
-                let _ = self.conn_type.update(ConnectionType::Direct(best_addr));
+                let old = self.conn_type.update(ConnectionType::Direct(best_addr));
+                if let Ok(ConnectionType::Relay(_) | ConnectionType::Mixed(..)) = old {
+                    self.events.notify(MagicSockEvent::PathUpgraded {
+                        node_id: self.node_id,
+                        addr: best_addr,
+                    });
+                }
             }
             (None, Some(relay_url)) => {
                 let _ = self.conn_type.update(ConnectionType::Relay(relay_url));
@@ -326,6 +691,7 @@ impl Endpoint {
         // The highest acceptable latency for an endpoint path.  If the latency is higher
         // then this the path will be ignored.
         const MAX_LATENCY: Duration = Duration::from_secs(60 * 60);
+        let prefer_ipv4 = self.prefers_ipv4();
         let best_pong = self
             .direct_addr_state
             .iter()
@@ -335,10 +701,11 @@ impl Endpoint {
                     .unwrap_or(MAX_LATENCY);
                 match state.recent_pong() {
                     // This pong is better if it has a lower latency, or if it has the same
-                    // latency but on an IPv6 path.
+                    // latency but on an IPv6 path, or IPv4 path if that is preferred.
                     Some(pong)
                         if pong.latency < best_latency
-                            || (pong.latency == best_latency && ipp.ip().is_ipv6()) =>
+                            || (pong.latency == best_latency
+                                && ipp.ip().is_ipv6() != prefer_ipv4) =>
                     {
                         Some(pong)
                     }
@@ -356,7 +723,9 @@ impl Endpoint {
                     best_addr::Source::BestCandidate,
                     pong.pong_at,
                     self.relay_url.is_some(),
-                )
+                    prefer_ipv4,
+                );
+                self.note_direct_path(pong.pong_at);
             }
         }
     }
@@ -402,13 +771,15 @@ impl Endpoint {
     /// Cleanup the expired ping for the passed in txid.
     #[instrument("disco", skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(super) fn ping_timeout(&mut self, txid: stun::TransactionId) {
+        self.ping_waiters.remove(&txid);
         if let Some(sp) = self.sent_pings.remove(&txid) {
             debug!(tx = %hex::encode(txid), addr = %sp.to, "pong not received in timeout");
             match sp.to {
                 SendAddr::Udp(addr) => {
                     if let Some(ep_state) = self.direct_addr_state.get_mut(&addr.into()) {
-                        ep_state.last_ping = None;
+                        ep_state.note_ping_timeout(Instant::now());
                     }
+                    self.record_probe(addr, false);
 
                     // If we fail to ping our current best addr, it is not that good anymore.
                     self.best_addr.clear_if_addr_older(
@@ -423,6 +794,10 @@ impl Endpoint {
                         if home_relay == url {
                             // lost connectivity via relay
                             relay_state.last_ping = None;
+                            self.relay_ping_timeouts = self.relay_ping_timeouts.saturating_add(1);
+                            if self.relay_ping_timeouts >= RELAY_UNREACHABLE_PING_TIMEOUTS {
+                                self.relay_reachability = RelayReachability::Unreachable;
+                            }
                         }
                     }
                 }
@@ -449,13 +824,47 @@ impl Endpoint {
         })
     }
 
+    /// Whether the ping `tx_id` to `dst` was sent and not answered yet.
+    pub(super) fn is_ping_pending(&self, dst: &SendAddr, tx_id: stun::TransactionId) -> bool {
+        self.sent_pings
+            .get(&tx_id)
+            .is_some_and(|sent| sent.to == *dst)
+    }
+
+    /// Pings the relay path and all direct paths, for [`crate::magicsock::MagicSock::ping`].
+    ///
+    /// Returns the pings to send and, per path, where the latency is reported.  The sender
+    /// is dropped if no pong arrives in time.
+    #[must_use = "pings must be handled"]
+    pub(super) fn ping_all_paths(&mut self) -> (Vec<PingAction>, Vec<PingWaiter>) {
+        // Forget the waiters of pings which were never sent.
+        self.ping_waiters.retain(|_, waiter| !waiter.is_closed());
+        let relay = self.relay_url().map(SendAddr::Relay);
+        let direct = self
+            .direct_addr_state
+            .keys()
+            .map(|ipp| SendAddr::Udp((*ipp).into()));
+        let mut msgs = Vec::new();
+        let mut waiters = Vec::new();
+        for dst in relay.into_iter().chain(direct) {
+            let Some(ping) = self.start_ping(dst.clone(), DiscoPingPurpose::Requested) else {
+                continue;
+            };
+            let (sender, receiver) = oneshot::channel();
+            self.ping_waiters.insert(ping.tx_id, sender);
+            waiters.push((dst.into(), receiver));
+            msgs.push(PingAction::SendPing(ping));
+        }
+        (msgs, waiters)
+    }
+
     /// Record the fact that a ping has been sent out.
     pub(super) fn ping_sent(
         &mut self,
         to: SendAddr,
         tx_id: stun::TransactionId,
         purpose: DiscoPingPurpose,
-        sender: mpsc::Sender<ActorMessage>,
+        sender: ActorSender,
     ) {
         trace!(%to, tx = %hex::encode(tx_id), ?purpose, "record ping sent");
 
@@ -480,11 +889,12 @@ impl Endpoint {
         if !path_found {
             // Shouldn't happen. But don't ping an endpoint that's not active for us.
             warn!(%to, ?purpose, "unexpected attempt to ping no longer live path");
+            self.ping_waiters.remove(&tx_id);
             return;
         }
 
         let id = self.id;
-        let timer = Timer::after(PING_TIMEOUT_DURATION, async move {
+        let timer = Timer::after(self.ping_timeout, async move {
             sender
                 .send(ActorMessage::EndpointPingExpired(id, tx_id))
                 .await
@@ -509,17 +919,17 @@ impl Endpoint {
     /// connection upon our subsequent pong response.
     ///
     /// For [`SendCallMeMaybe::IfNoRecent`], **no** paths will be pinged if there already
-    /// was a recent call-me-maybe sent.
+    /// was a call-me-maybe sent within the given interval.
     ///
     /// The caller is responsible for sending the messages.
     #[must_use = "actions must be handled"]
     fn send_call_me_maybe(&mut self, now: Instant, always: SendCallMeMaybe) -> Vec<PingAction> {
         match always {
             SendCallMeMaybe::Always => (),
-            SendCallMeMaybe::IfNoRecent => {
+            SendCallMeMaybe::IfNoRecent(interval) => {
                 let had_recent_call_me_maybe = self
                     .last_call_me_maybe
-                    .map(|when| when.elapsed() < HEARTBEAT_INTERVAL)
+                    .map(|when| when.elapsed() < interval)
                     .unwrap_or(false);
                 if had_recent_call_me_maybe {
                     trace!("skipping call-me-maybe, still recent");
@@ -576,29 +986,70 @@ impl Endpoint {
             return ping_msgs;
         }
         self.prune_direct_addresses();
-        let mut ping_dsts = String::from("[");
-        self.direct_addr_state
+        ping_msgs.extend(self.ping_direct_paths(now));
+        self.last_full_ping.replace(now);
+        ping_msgs
+    }
+
+    /// Sends DISCO pings to the direct paths which need one, within the probe limit.
+    ///
+    /// When the number of probes in flight is limited, the fastest classes of paths are
+    /// probed first, see [`ProbeClass`].  The remaining paths are probed as earlier probes
+    /// complete, see [`Endpoint::continue_probing`].
+    fn ping_direct_paths(&mut self, now: Instant) -> Vec<PingAction> {
+        let mut candidates: Vec<IpPort> = self
+            .direct_addr_state
             .iter()
             .filter_map(|(ipp, state)| state.needs_ping(&now).then_some(*ipp))
+            .collect();
+        // Stable, so the order within a class is kept.
+        let ipv6_unreliable = self.ipv6_unreliable();
+        candidates.sort_by_key(|ipp| ProbeClass::of(ipp.ip()).rank(ipv6_unreliable));
+        let in_flight = self
+            .sent_pings
+            .values()
+            .filter(|sp| !sp.to.is_relay())
+            .count();
+        let budget = self.path_tuning.probe_budget(in_flight);
+        let deferred = candidates.len().saturating_sub(budget);
+        candidates.truncate(budget);
+        self.deferred_probes = deferred > 0;
+        if deferred > 0 {
+            inc_by!(MagicsockMetrics, probes_deferred, deferred as u64);
+        }
+
+        let mut ping_dsts = String::from("[");
+        let ping_msgs: Vec<_> = candidates
+            .into_iter()
             .filter_map(|ipp| {
                 self.start_ping(SendAddr::Udp(ipp.into()), DiscoPingPurpose::Discovery)
             })
-            .for_each(|msg| {
+            .map(|msg| {
                 use std::fmt::Write;
                 write!(&mut ping_dsts, " {} ", msg.dst).ok();
-                ping_msgs.push(PingAction::SendPing(msg));
-            });
+                PingAction::SendPing(msg)
+            })
+            .collect();
         ping_dsts.push(']');
         debug!(
             %ping_dsts,
+            deferred,
             dst = %self.node_id.fmt_short(),
             paths = %summarize_endpoint_paths(&self.direct_addr_state),
             "sending pings to endpoint",
         );
-        self.last_full_ping.replace(now);
         ping_msgs
     }
 
+    /// Probes the direct paths deferred by the probe limit, once earlier probes completed.
+    #[must_use = "actions must be handled"]
+    pub(super) fn continue_probing(&mut self, now: Instant) -> Vec<PingAction> {
+        if !self.deferred_probes || relay_only_mode() {
+            return Vec::new();
+        }
+        self.ping_direct_paths(now)
+    }
+
     pub(super) fn update_from_node_addr(&mut self, n: &AddrInfo) {
         if self.best_addr.is_empty() {
             // we do not have a direct connection, so changing the relay information may
@@ -631,6 +1082,18 @@ impl Endpoint {
         debug!(new = ?n.direct_addresses , %paths, "added new direct paths for endpoint");
     }
 
+    /// Sets the home relay the node advertised, which is then used for relayed traffic.
+    pub(super) fn set_home_relay(&mut self, url: RelayUrl) {
+        if self.home_relay.as_ref() == Some(&url) {
+            return;
+        }
+        if self.relay_url() != Some(url.clone()) {
+            info!(%url, "node advertised new home relay");
+            self.relay_url = Some((url.clone(), PathState::default()));
+        }
+        self.home_relay = Some(url);
+    }
+
     /// Clears all the endpoint's p2p state, reverting it to a relay-only endpoint.
     #[instrument(skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(super) fn reset(&mut self) {
@@ -670,6 +1133,14 @@ impl Endpoint {
             },
             SendAddr::Relay(ref url) => {
                 match self.relay_url.as_mut() {
+                    Some((home_url, _state))
+                        if home_url != url && self.home_relay.as_ref() == Some(home_url) =>
+                    {
+                        // the node told us its home relay, keep using that one even if
+                        // it reached us over another relay
+                        trace!(%url, home = %home_url, "ping via relay other than node's home");
+                        PingRole::LikelyHeartbeat
+                    }
                     Some((home_url, _state)) if home_url != url => {
                         // either the node changed relays or we didn't have a relay address for the
                         // node. In both cases, trust the new confirmed url
@@ -691,20 +1162,35 @@ impl Endpoint {
             self.prune_direct_addresses();
         }
 
-        // if the endpoint does not yet have a best_addrr
-        let needs_ping_back = if matches!(path, SendAddr::Udp(_))
-            && matches!(
-                self.best_addr.state(now),
-                best_addr::State::Empty | best_addr::State::Outdated(_)
-            ) {
-            // We also need to send a ping to make this path available to us as well.  This
-            // is always sent togehter with a pong.  So in the worst case the pong gets lost
-            // and this ping does not.  In that case we ping-pong until both sides have
-            // received at least one pong.  Once both sides have received one pong they both
-            // have a best_addr and this ping will stop being sent.
-            self.start_ping(path, DiscoPingPurpose::Discovery)
-        } else {
-            None
+        // The node's heartbeat, authenticated by disco, shows the path still works.
+        if let SendAddr::Udp(addr) = path {
+            if !matches!(role, PingRole::Duplicate | PingRole::NewEndpoint) {
+                self.best_addr.extend_trust_if_equals(addr, now);
+            }
+        }
+
+        let needs_ping_back = match path {
+            SendAddr::Udp(addr) => match self.best_addr.state(now) {
+                // if the endpoint does not yet have a best_addrr
+                best_addr::State::Empty | best_addr::State::Outdated(_) => {
+                    // We also need to send a ping to make this path available to us as well.
+                    // This is always sent togehter with a pong.  So in the worst case the pong
+                    // gets lost and this ping does not.  In that case we ping-pong until both
+                    // sides have received at least one pong.  Once both sides have received
+                    // one pong they both have a best_addr and this ping will stop being sent.
+                    self.start_ping(path, DiscoPingPurpose::Discovery)
+                }
+                best_addr::State::Valid(best)
+                    if matches!(role, PingRole::NewEndpoint) && best.addr != addr =>
+                {
+                    // The node pinged us from a new address while we have a working direct
+                    // path: it might have roamed.  Only use the new address once it answered
+                    // a ping of our own, this way a replayed ping can not redirect traffic.
+                    self.start_ping(path, DiscoPingPurpose::Roaming)
+                }
+                best_addr::State::Valid(_) => None,
+            },
+            SendAddr::Relay(_) => None,
         };
 
         debug!(
@@ -801,7 +1287,7 @@ impl Endpoint {
         match self.sent_pings.remove(&m.tx_id) {
             None => {
                 // This is not a pong for a ping we sent.
-                warn!(tx = %hex::encode(m.tx_id), "received pong with unknown transaction id");
+                warn_limited!(tx = %hex::encode(m.tx_id), "received pong with unknown transaction id");
                 None
             }
             Some(sp) => {
@@ -811,6 +1297,9 @@ impl Endpoint {
 
                 let now = Instant::now();
                 let latency = now - sp.at;
+                if let Some(waiter) = self.ping_waiters.remove(&m.tx_id) {
+                    waiter.send(latency).ok();
+                }
 
                 debug!(
                     tx = %hex::encode(m.tx_id),
@@ -853,6 +1342,7 @@ impl Endpoint {
                                 from: src,
                                 pong_src: m.src.clone(),
                             });
+                            self.relay_reachable();
                         }
                         other => {
                             // if we are here then we sent this ping, but the url changed
@@ -868,13 +1358,30 @@ impl Endpoint {
                 // TODO(bradfitz): decide how latency vs. preference order affects decision
                 if let SendAddr::Udp(to) = sp.to {
                     debug_assert!(!is_relay, "mismatching relay & udp");
+                    if sp.purpose == DiscoPingPurpose::Roaming {
+                        // The node moved to a new address in the same address family, the old
+                        // best address is most likely gone.
+                        let roamed = self
+                            .best_addr
+                            .addr()
+                            .is_some_and(|best| best != to && best.is_ipv4() == to.is_ipv4());
+                        if roamed {
+                            info!(new_addr = %to, "node roamed to a new address");
+                            self.best_addr
+                                .clear(ClearReason::Roamed, self.relay_url.is_some());
+                        }
+                    }
+                    self.record_probe(to, true);
+                    let prefer_ipv4 = self.prefers_ipv4();
                     self.best_addr.insert_if_better_or_reconfirm(
                         to,
                         latency,
                         best_addr::Source::ReceivedPong,
                         now,
                         self.relay_url.is_some(),
+                        prefer_ipv4,
                     );
+                    self.note_direct_path(now);
                 }
 
                 node_map_insert
@@ -945,20 +1452,31 @@ impl Endpoint {
     }
 
     /// Marks this endpoint as having received a UDP payload message.
-    pub(super) fn receive_udp(&mut self, addr: IpPort, now: Instant) {
+    pub(super) fn receive_udp(&mut self, addr: IpPort, len: usize, now: Instant) {
         let Some(state) = self.direct_addr_state.get_mut(&addr) else {
             debug_assert!(false, "node map inconsistency by_ip_port <-> direct addr");
             return;
         };
+        self.bytes_recv += len as u64;
         state.last_payload_msg = Some(now);
+        state.note_alive();
         self.last_used = Some(now);
+        self.first_contact.get_or_insert(now);
     }
 
-    pub(super) fn receive_relay(&mut self, url: &RelayUrl, _src: &PublicKey, now: Instant) {
+    pub(super) fn receive_relay(
+        &mut self,
+        url: &RelayUrl,
+        _src: &PublicKey,
+        len: usize,
+        now: Instant,
+    ) {
+        self.bytes_recv += len as u64;
         match self.relay_url.as_mut() {
             Some((current_home, state)) if current_home == url => {
                 // We received on the expected url. update state.
                 state.last_payload_msg = Some(now);
+                self.relay_reachable();
             }
             Some((_current_home, _state)) => {
                 // we have a different url. we only update on ping, not on receive_relay.
@@ -968,6 +1486,7 @@ impl Endpoint {
             }
         }
         self.last_used = Some(now);
+        self.first_contact.get_or_insert(now);
     }
 
     pub(super) fn last_ping(&self, addr: &SendAddr) -> Option<Instant> {
@@ -992,16 +1511,63 @@ impl Endpoint {
         }
     }
 
+    /// Pings all paths and sends a call-me-maybe, if the session is active.
+    #[must_use = "actions must be handled"]
+    pub(super) fn call_me_maybe_if_active(&mut self) -> Vec<PingAction> {
+        let now = Instant::now();
+        if !self.is_active(&now) {
+            return Vec::new();
+        }
+        self.send_call_me_maybe(now, SendCallMeMaybe::Always)
+    }
+
+    /// Pings the IPv4 direct path after our IPv4 socket moved to a new port.
+    ///
+    /// The node only accepts QUIC packets from addresses it knows, the ping from the new port
+    /// makes it learn the address before the call-me-maybe with our new endpoints arrives.
+    #[must_use = "actions must be handled"]
+    pub(super) fn local_port_changed(&mut self) -> Option<PingAction> {
+        let now = Instant::now();
+        if !self.is_active(&now) {
+            return None;
+        }
+        let addr = self.best_addr.addr().filter(|addr| addr.is_ipv4())?;
+        self.start_ping(SendAddr::Udp(addr), DiscoPingPurpose::StayinAlive)
+            .map(PingAction::SendPing)
+    }
+
+    /// Sends a call-me-maybe with our changed endpoints, if the session is active.
+    ///
+    /// Unlike [`Self::call_me_maybe_if_active`] the paths are not pinged: the node pings our
+    /// new endpoints once it receives them.
+    #[must_use = "actions must be handled"]
+    pub(super) fn endpoints_changed(&mut self) -> Option<PingAction> {
+        let now = Instant::now();
+        if !self.is_active(&now) {
+            return None;
+        }
+        let relay_url = self.relay_url()?;
+        self.last_call_me_maybe = Some(now);
+        Some(PingAction::SendCallMeMaybe {
+            relay_url,
+            dst_node: self.node_id,
+        })
+    }
+
     /// Send a heartbeat to the node to keep the connection alive, or trigger a full ping
     /// if necessary.
+    ///
+    /// Without a direct path the full ping includes a ping over the relay, which keeps the
+    /// relay path warm and tracks the node's [`RelayReachability`].
     #[instrument("stayin_alive", skip_all, fields(node = %self.node_id.fmt_short()))]
-    pub(super) fn stayin_alive(&mut self) -> Vec<PingAction> {
+    pub(super) fn stayin_alive(&mut self, conditions: &LocalConditions) -> Vec<PingAction> {
         trace!("stayin_alive");
         let now = Instant::now();
         if !self.is_active(&now) {
             trace!("skipping stayin alive: session is inactive");
             return Vec::new();
         }
+        self.check_direct_path_deadline(now, conditions);
 
         // If we do not have an optimal addr, send pings to all known places.
         if self.want_call_me_maybe(&now) {
@@ -1011,6 +1577,10 @@ impl Endpoint {
 
         // Send heartbeat ping to keep the current addr going as long as we need it.
         if let Some(udp_addr) = self.best_addr.addr() {
+            if self.peer_keeps_alive(udp_addr, now) {
+                trace!(dst = %udp_addr, "skipping stayin alive ping: node pings the path");
+                return Vec::new();
+            }
             let elapsed = self.last_ping(&SendAddr::Udp(udp_addr)).map(|l| now - l);
             // Send a ping if the last ping is older than 2 seconds.
             let needs_ping = match elapsed {
@@ -1035,21 +1605,53 @@ impl Endpoint {
         Vec::new()
     }
 
+    /// Whether the node's heartbeats keep the path to `addr` alive, making our pings redundant.
+    ///
+    /// While QUIC traffic flows both nodes would otherwise send heartbeat pings on the same
+    /// path.  The QUIC payload itself is not authenticated before it reaches quinn, so only
+    /// the node's disco pings, which also extend the trust in the path, are relied on.  This
+    /// is only done while the latency measured by our own pings is recent, so it is still
+    /// refreshed every [`UPGRADE_INTERVAL`].
+    fn peer_keeps_alive(&self, addr: SocketAddr, now: Instant) -> bool {
+        let Some(state) = self.direct_addr_state.get(&addr.into()) else {
+            return false;
+        };
+        let recent_payload = state
+            .last_payload_msg
+            .is_some_and(|at| now.duration_since(at) < HEARTBEAT_INTERVAL);
+        let recent_ping = state
+            .last_got_ping
+            .is_some_and(|at| now.duration_since(at) < HEARTBEAT_INTERVAL);
+        let recent_pong = state
+            .recent_pong
+            .as_ref()
+            .is_some_and(|pong| now.duration_since(pong.pong_at) < UPGRADE_INTERVAL);
+        recent_payload && recent_ping && recent_pong
+    }
+
     /// Returns the addresses on which a payload should be sent right now.
     ///
     /// This is in the hot path of `.poll_send()`.
+    ///
+    /// When `varying_mapping` is set, call-me-maybe messages are retried more often and
+    /// unconfirmed direct paths are not used, see [`Endpoint::addr_for_send`].  When
+    /// `metered` is set call-me-maybe messages are retried less often instead.
     #[instrument("get_send_addrs", skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(crate) fn get_send_addrs(
         &mut self,
         have_ipv6: bool,
+        varying_mapping: bool,
+        metered: bool,
     ) -> (Option<SocketAddr>, Option<RelayUrl>, Vec<PingAction>) {
         let now = Instant::now();
         self.last_used.replace(now);
-        let (udp_addr, relay_url) = self.addr_for_send(&now, have_ipv6);
+        self.first_contact.get_or_insert(now);
+        let (udp_addr, relay_url) = self.addr_for_send(&now, have_ipv6, varying_mapping);
         let mut ping_msgs = Vec::new();
 
         if self.want_call_me_maybe(&now) {
-            ping_msgs = self.send_call_me_maybe(now, SendCallMeMaybe::IfNoRecent);
+            let interval = call_me_maybe_interval(varying_mapping, metered);
+            ping_msgs = self.send_call_me_maybe(now, SendCallMeMaybe::IfNoRecent(interval));
         }
 
         trace!(
@@ -1117,6 +1719,15 @@ pub(super) struct PathState {
     pub(super) recent_pong: Option<PongReply>,
     /// When was this endpoint last used to transmit payload data (removing ping, pong, etc).
     pub(super) last_payload_msg: Option<Instant>,
+
+    /// Number of consecutive pings to this path which timed out.
+    failed_pings: u32,
+    /// Until when no pings are sent to this path because previous ones timed out.
+    ///
+    /// Reset by [`PathState::clear`] on connectivity changes, as the path may work on the new
+    /// network.  A call-me-maybe advertising the address again keeps it, so dead addresses
+    /// are not probed again on every call-me-maybe.
+    ping_backoff_until: Option<Instant>,
 }
 
 impl PathState {
@@ -1137,6 +1748,27 @@ impl PathState {
 
     pub(super) fn add_pong_reply(&mut self, r: PongReply) {
         self.recent_pong = Some(r);
+        self.note_alive();
+    }
+
+    /// Records a ping to this path which timed out.
+    ///
+    /// Each consecutive timeout doubles the time until the path is pinged again, starting
+    /// at [`DISCO_PING_INTERVAL`] and capped at [`UNREACHABLE_PATH_MAX_BACKOFF`].
+    fn note_ping_timeout(&mut self, now: Instant) {
+        self.last_ping = None;
+        self.failed_pings = self.failed_pings.saturating_add(1);
+        let exponent = (self.failed_pings - 1).min(16);
+        let backoff = DISCO_PING_INTERVAL
+            .saturating_mul(1 << exponent)
+            .min(UNREACHABLE_PATH_MAX_BACKOFF);
+        self.ping_backoff_until = Some(now + backoff);
+    }
+
+    /// Forgets about earlier ping timeouts, the path was seen working.
+    fn note_alive(&mut self) {
+        self.failed_pings = 0;
+        self.ping_backoff_until = None;
     }
 
     #[cfg(test)]
@@ -1216,6 +1848,9 @@ impl PathState {
     }
 
     fn needs_ping(&self, now: &Instant) -> bool {
+        if self.ping_backoff_until.is_some_and(|until| *now < until) {
+            return false;
+        }
         match self.last_ping {
             None => true,
             Some(last_ping) => {
@@ -1237,6 +1872,7 @@ impl PathState {
             PingRole::Duplicate
         } else {
             self.last_got_ping_tx_id.replace(tx_id);
+            self.note_alive();
             let last = self.last_got_ping.replace(now);
             match last {
                 None => PingRole::Reactivate,
@@ -1251,12 +1887,17 @@ impl PathState {
         }
     }
 
+    /// Forgets everything learned about the path, e.g. after a network change.
+    ///
+    /// This includes earlier ping timeouts: the path may work on the new network.
     fn clear(&mut self) {
         self.last_ping = None;
         self.last_got_ping = None;
         self.last_got_ping_tx_id = None;
         self.call_me_maybe_time = None;
         self.recent_pong = None;
+        self.failed_pings = 0;
+        self.ping_backoff_until = None;
     }
 
     fn summary(&self, mut w: impl std::fmt::Write) -> std::fmt::Result {
@@ -1273,6 +1914,9 @@ impl PathState {
         if let Some(ref when) = self.last_ping {
             write!(w, "ping-sent({:?} ago) ", when.elapsed())?;
         }
+        if self.failed_pings > 0 {
+            write!(w, "ping-failures({}) ", self.failed_pings)?;
+        }
         write!(w, "}}")
     }
 }
@@ -1294,14 +1938,26 @@ fn summarize_endpoint_paths(paths: &BTreeMap<IpPort, PathState>) -> String {
     w
 }
 
+/// How long to wait before retrying a call-me-maybe while no direct path works.
+///
+/// Being on a metered network takes precedence over retrying more often when our mapping
+/// varies by destination.
+fn call_me_maybe_interval(varying_mapping: bool, metered: bool) -> Duration {
+    match (metered, varying_mapping) {
+        (true, _) => METERED_CALL_ME_MAYBE_INTERVAL,
+        (false, true) => VARYING_MAPPING_CALL_ME_MAYBE_INTERVAL,
+        (false, false) => HEARTBEAT_INTERVAL,
+    }
+}
+
 /// Whether to send a call-me-maybe message after sending pings to all known paths.
 ///
-/// `IfNoRecent` will only send a call-me-maybe if no previous one was sent in the last
-/// [`HEARTBEAT_INTERVAL`].
+/// `IfNoRecent` will only send a call-me-maybe if no previous one was sent within the
+/// given interval, usually [`HEARTBEAT_INTERVAL`].
 #[derive(Debug)]
 enum SendCallMeMaybe {
     Always,
-    IfNoRecent,
+    IfNoRecent(Duration),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -1319,7 +1975,6 @@ pub(super) struct PongReply {
 pub(super) struct SentPing {
     pub(super) to: SendAddr,
     pub(super) at: Instant,
-    #[allow(dead_code)]
     pub(super) purpose: DiscoPingPurpose,
     pub(super) timer: Timer,
 }
@@ -1331,6 +1986,11 @@ pub enum DiscoPingPurpose {
     Discovery,
     /// Ping to ensure the current route is still valid.
     StayinAlive,
+    /// Ping to validate a new address a node with an established direct path contacted us
+    /// from.
+    Roaming,
+    /// Ping requested by the application, see [`crate::magicsock::MagicSock::ping`].
+    Requested,
 }
 
 /// The type of control message we have received.
@@ -1360,6 +2020,45 @@ pub struct DirectAddrInfo {
     pub last_payload: Option<Duration>,
 }
 
+/// Whether a node can be reached over its relay.
+///
+/// While a node has no direct path, it is pinged over its relay regularly, which also
+/// keeps the relay connections warm.
+#[derive(
+    Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, derive_more::Display,
+)]
+pub enum RelayReachability {
+    /// Not known yet, no ping over the relay was answered or lost.
+    #[default]
+    #[display("unknown")]
+    Unknown,
+    /// The node recently answered a ping or sent data over its relay.
+    #[display("reachable")]
+    Reachable,
+    /// Recent pings went unanswered, or the relay server reported the node as gone.
+    #[display("unreachable")]
+    Unreachable,
+}
+
+/// Whether QUIC packets to a node may be sent through relay servers.
+///
+/// For traffic which must not pass through third-party relays, even while the direct path
+/// flaps.  Disco messages carry no application data and are still sent through relays, so
+/// direct paths can be found by hole punching.  While the policy withholds the relay and no
+/// direct path is known, sends to the node fail as if it had no address.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RelayPolicy {
+    /// Relays are used as usual.
+    #[default]
+    Allow,
+    /// Only direct paths are used.
+    Forbid,
+    /// The relay is only used once no direct path was used for the given time.
+    ///
+    /// The time counts from the first send to the node if there never was a direct path.
+    FallbackAfter(Duration),
+}
+
 /// Details about an Endpoint.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct EndpointInfo {
@@ -1378,6 +2077,22 @@ pub struct EndpointInfo {
     pub latency: Option<Duration>,
     /// Duration since the last time this node was used.
     pub last_used: Option<Duration>,
+    /// Whether the node can be reached over its relay.
+    pub relay_reachability: RelayReachability,
+    /// Time from the first contact with the node until the first verified direct path.
+    pub time_to_direct: Option<Duration>,
+    /// Why no direct path was verified in time, if so.
+    pub no_direct_path: Option<NoDirectPathReason>,
+    /// Whether QUIC packets to the node may be sent through relays.
+    pub relay_policy: RelayPolicy,
+    /// Bytes of QUIC packets sent to the node.
+    pub bytes_sent: u64,
+    /// Bytes of QUIC packets received from the node.
+    ///
+    /// For packets received through a relay this includes the framing of the relay.
+    pub bytes_recv: u64,
+    /// Outcomes of recent probes to the global direct paths of the node, per IP family.
+    pub family_stats: FamilyStats,
 }
 
 impl EndpointInfo {
@@ -1391,6 +2106,117 @@ impl EndpointInfo {
     }
 }
 
+/// Why no direct path to a node was verified.
+///
+/// This is a best guess based on what we know about our own network and the node's
+/// addresses.  The node's network, e.g. a symmetric NAT on its side, is not known.
+#[derive(derive_more::Display, Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum NoDirectPathReason {
+    /// Direct paths are disabled, e.g. by relay-only mode or an unusable UDP proxy.
+    #[display("policy")]
+    Policy,
+    /// The node advertised no direct addresses.
+    #[display("no addresses")]
+    NoAddresses,
+    /// None of the node's direct addresses are of an IP family we can send on.
+    #[display("ip family mismatch")]
+    IpFamilyMismatch,
+    /// Our NAT maps to a different port per destination, which defeats hole punching.
+    #[display("symmetric nat")]
+    SymmetricNat,
+    /// Hole punching failed for another reason.
+    #[display("hole punching failed")]
+    HolePunchingFailed,
+}
+
+/// What we know about our own network, used to explain missing direct paths.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LocalConditions {
+    /// Whether we can send over IPv4, `None` if not known yet.
+    pub(crate) ipv4: Option<bool>,
+    /// Whether we can send over IPv6, `None` if not known yet.
+    pub(crate) ipv6: Option<bool>,
+    /// Whether our NAT maps to a different port per destination.
+    pub(crate) symmetric_nat: bool,
+    /// Whether direct paths are disabled.
+    pub(crate) direct_disabled: bool,
+}
+
+/// Records the time to a direct path in the metrics.
+fn record_time_to_direct(elapsed: Duration) {
+    inc!(MagicsockMetrics, direct_path_established);
+    observe!(MagicsockMetrics, direct_path_time, elapsed.as_secs_f64());
+}
+
+/// Classes of direct paths, in the order they are probed when probes are limited.
+///
+/// Paths on the local network answer fastest, and IPv6 paths usually need no hole punching,
+/// so these are tried before the reflexive IPv4 addresses, unless IPv6 probes mostly fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProbeClass {
+    /// Private, link-local or loopback addresses.
+    Lan,
+    /// Global IPv6 addresses.
+    Ipv6,
+    /// Global IPv4 addresses, usually discovered via STUN.
+    Ipv4,
+}
+
+impl ProbeClass {
+    fn of(addr: &IpAddr) -> Self {
+        if ip::is_private(addr) || ip::is_link_local(*addr) || addr.is_loopback() {
+            Self::Lan
+        } else if addr.is_ipv6() {
+            Self::Ipv6
+        } else {
+            Self::Ipv4
+        }
+    }
+
+    /// The position in the probing order, with IPv6 after IPv4 if IPv6 is unreliable.
+    fn rank(self, ipv6_unreliable: bool) -> u8 {
+        match self {
+            Self::Lan => 0,
+            Self::Ipv6 if ipv6_unreliable => 2,
+            Self::Ipv6 => 1,
+            Self::Ipv4 if ipv6_unreliable => 1,
+            Self::Ipv4 => 2,
+        }
+    }
+}
+
+/// Where the latency of a ping to a path is reported, see [`Endpoint::ping_all_paths`].
+pub(in crate::magicsock) type PingWaiter = (PingPath, oneshot::Receiver<Duration>);
+
+/// A path to a node, see [`PathPing`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+pub enum PingPath {
+    /// A direct UDP path to the address.
+    #[display("direct {_0}")]
+    Direct(SocketAddr),
+    /// The path through the relay server.
+    #[display("relay {_0}")]
+    Relay(RelayUrl),
+}
+
+impl From<SendAddr> for PingPath {
+    fn from(addr: SendAddr) -> Self {
+        match addr {
+            SendAddr::Udp(addr) => Self::Direct(addr),
+            SendAddr::Relay(url) => Self::Relay(url),
+        }
+    }
+}
+
+/// The result of pinging one path of a node, see [`crate::magicsock::MagicSock::ping`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathPing {
+    /// The path pinged.
+    pub path: PingPath,
+    /// The round trip time of the ping, `None` if no pong arrived in time.
+    pub latency: Option<Duration>,
+}
+
 /// The type of connection we have to the endpoint.
 #[derive(derive_more::Display, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ConnectionType {
@@ -1415,225 +2241,775 @@ pub enum ConnectionType {
 mod tests {
     use std::net::Ipv4Addr;
 
+    use futures::StreamExt;
+
     use super::{
         super::{NodeMap, NodeMapInner},
         *,
     };
     use crate::key::SecretKey;
 
+    #[tokio::test]
+    async fn test_relay_reachability() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some(relay_url.clone()),
+                active: true,
+            },
+        );
+        let (msg_sender, _msg_receiver) = crate::magicsock::actor_queue::channel(8);
+        assert_eq!(
+            ep.info(Instant::now()).relay_reachability,
+            RelayReachability::Unknown
+        );
+
+        // Pings over the relay go unanswered.
+        for i in 0..RELAY_UNREACHABLE_PING_TIMEOUTS {
+            let tx_id = stun::TransactionId::from([i; 12]);
+            ep.ping_sent(
+                SendAddr::Relay(relay_url.clone()),
+                tx_id,
+                DiscoPingPurpose::Discovery,
+                msg_sender.clone(),
+            );
+            ep.ping_timeout(tx_id);
+        }
+        assert_eq!(
+            ep.info(Instant::now()).relay_reachability,
+            RelayReachability::Unreachable
+        );
+
+        // Data from the node over the relay makes it reachable again.
+        let node_id = ep.node_id;
+        ep.receive_relay(&relay_url, &node_id, 0, Instant::now());
+        assert_eq!(
+            ep.info(Instant::now()).relay_reachability,
+            RelayReachability::Reachable
+        );
+
+        // Disconnects from other relays are ignored.
+        ep.relay_peer_gone(&"https://other-relay.com".parse().unwrap());
+        assert_eq!(
+            ep.info(Instant::now()).relay_reachability,
+            RelayReachability::Reachable
+        );
+        ep.relay_peer_gone(&relay_url);
+        assert_eq!(
+            ep.info(Instant::now()).relay_reachability,
+            RelayReachability::Unreachable
+        );
+    }
+
     #[test]
-    fn test_endpoint_infos() {
-        let new_relay_and_state =
-            |url: Option<RelayUrl>| url.map(|url| (url, PathState::default()));
+    fn test_stayin_alive_peer_heartbeat() {
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some("https://my-relay.com".parse().unwrap()),
+                active: true,
+            },
+        );
+        let now = Instant::now();
+        let earlier = now - Duration::from_secs(3);
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        let ipp = IpPort::from(addr);
+        ep.direct_addr_state.insert(
+            ipp,
+            PathState {
+                last_ping: Some(earlier),
+                recent_pong: Some(PongReply {
+                    latency: Duration::from_millis(1),
+                    pong_at: earlier,
+                    from: SendAddr::Udp(addr),
+                    pong_src: SendAddr::Udp(addr),
+                }),
+                ..Default::default()
+            },
+        );
+        ep.best_addr = BestAddr::from_parts(
+            addr,
+            Duration::from_millis(1),
+            earlier,
+            now + Duration::from_secs(10),
+        );
+        ep.last_full_ping = Some(now);
+
+        // Without QUIC traffic disco keeps the path alive.
+        let actions = ep.stayin_alive(&Default::default());
+        assert!(matches!(
+            &actions[..],
+            [PingAction::SendPing(SendPing { dst: SendAddr::Udp(dst), .. })] if *dst == addr
+        ));
+
+        // Unauthenticated QUIC traffic alone does not make the ping redundant.
+        ep.direct_addr_state.get_mut(&ipp).unwrap().last_ping = Some(earlier);
+        ep.receive_udp(ipp, 0, Instant::now());
+        assert_eq!(ep.stayin_alive(&Default::default()).len(), 1);
+
+        // The node's heartbeats on the path do, and extend the trust in it.
+        let trusted_until = now + Duration::from_secs(1);
+        ep.best_addr = BestAddr::from_parts(addr, Duration::from_millis(1), earlier, trusted_until);
+        ep.direct_addr_state.get_mut(&ipp).unwrap().last_ping = Some(earlier);
+        ep.direct_addr_state.get_mut(&ipp).unwrap().last_got_ping = Some(earlier);
+        ep.handle_ping(SendAddr::Udp(addr), stun::TransactionId::default());
+        assert!(ep.stayin_alive(&Default::default()).is_empty());
+        assert!(matches!(
+            ep.best_addr.state(trusted_until),
+            best_addr::State::Valid(_)
+        ));
+    }
 
+    #[test]
+    fn test_relay_congested() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some(relay_url.clone()),
+                active: true,
+            },
+        );
+        let now = Instant::now();
+
+        // Without any direct address the relay is used regardless.
+        ep.relay_congested(now);
+        assert_eq!(
+            ep.addr_for_send(&now, false, false),
+            (None, Some(relay_url.clone()))
+        );
+
+        // With a candidate address the congested relay is skipped.
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(addr.into(), PathState::default());
+        assert_eq!(ep.addr_for_send(&now, false, false), (Some(addr), None));
+
+        // Once the congestion expired both paths are used again.
+        let later = now + RELAY_CONGESTION_DURATION;
+        assert_eq!(
+            ep.addr_for_send(&later, false, false),
+            (Some(addr), Some(relay_url))
+        );
+    }
+
+    #[test]
+    fn test_call_me_maybe_interval() {
+        assert_eq!(call_me_maybe_interval(false, false), HEARTBEAT_INTERVAL);
+        assert_eq!(
+            call_me_maybe_interval(true, false),
+            VARYING_MAPPING_CALL_ME_MAYBE_INTERVAL
+        );
+        assert_eq!(
+            call_me_maybe_interval(false, true),
+            METERED_CALL_ME_MAYBE_INTERVAL
+        );
+        assert_eq!(
+            call_me_maybe_interval(true, true),
+            METERED_CALL_ME_MAYBE_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_call_me_maybe_keeps_backoff() {
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: None,
+                active: true,
+            },
+        );
+        ep.set_path_tuning(PathTuning {
+            max_concurrent_probes: 0,
+        });
+        let dead: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        let fresh: SocketAddr = "203.0.113.2:4000".parse().unwrap();
+        let mut state = PathState::default();
+        state.note_ping_timeout(Instant::now());
+        ep.direct_addr_state.insert(dead.into(), state);
+
+        let msgs = ep.handle_call_me_maybe(disco::CallMeMaybe {
+            my_numbers: vec![dead, fresh],
+        });
+        let pinged: Vec<_> = msgs
+            .iter()
+            .filter_map(|msg| match msg {
+                PingAction::SendPing(ping) => Some(ping.dst.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pinged, [SendAddr::Udp(fresh)]);
+    }
+
+    #[test]
+    fn test_call_me_maybe_if_active() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some(relay_url.clone()),
+                active: false,
+            },
+        );
+        let now = Instant::now();
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(addr.into(), PathState::with_last_payload(now));
+        assert!(ep.call_me_maybe_if_active().is_empty());
+
+        ep.last_used = Some(now);
+        ep.note_connectivity_change();
+        let msgs = ep.call_me_maybe_if_active();
+        let pinged = msgs.iter().any(|msg| match msg {
+            PingAction::SendPing(ping) => ping.dst == SendAddr::Udp(addr),
+            _ => false,
+        });
+        assert!(pinged);
+        let called = msgs.iter().any(|msg| match msg {
+            PingAction::SendCallMeMaybe { relay_url: url, .. } => *url == relay_url,
+            _ => false,
+        });
+        assert!(called);
+    }
+
+    #[test]
+    fn test_endpoints_changed() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let node_id = SecretKey::generate().public();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: node_id,
+                relay_url: Some(relay_url.clone()),
+                active: false,
+            },
+        );
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(addr.into(), PathState::with_last_payload(Instant::now()));
+        assert!(ep.endpoints_changed().is_none());
+
+        // Only the call-me-maybe is sent, the node pings us.
+        ep.last_used = Some(Instant::now());
+        match ep.endpoints_changed() {
+            Some(PingAction::SendCallMeMaybe {
+                relay_url: url,
+                dst_node,
+            }) => {
+                assert_eq!(url, relay_url);
+                assert_eq!(dst_node, node_id);
+            }
+            msg => panic!("expected a call-me-maybe, got {msg:?}"),
+        }
+        assert!(ep.last_call_me_maybe.is_some());
+    }
+
+    #[test]
+    fn test_traffic_counters() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let node_id = SecretKey::generate().public();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: node_id,
+                relay_url: Some(relay_url.clone()),
+                active: false,
+            },
+        );
+        let now = Instant::now();
+        ep.receive_relay(&relay_url, &node_id, 1200, now);
+        ep.receive_relay(&relay_url, &node_id, 300, now);
+        ep.sent_counter().fetch_add(800, Ordering::Relaxed);
+        let info = ep.info(now + Duration::from_secs(1));
+        assert_eq!(info.bytes_recv, 1500);
+        assert_eq!(info.bytes_sent, 800);
+        assert_eq!(info.last_used, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_family_stats_steer_probes() {
+        let global = SharedFamilyStats::default();
+        let new_ep = |global: &SharedFamilyStats| {
+            let mut ep = Endpoint::new(
+                0,
+                Options {
+                    public_key: SecretKey::generate().public(),
+                    relay_url: None,
+                    active: false,
+                },
+            );
+            ep.set_global_family_stats(global.clone());
+            ep
+        };
+        let v4: SocketAddr = "1.1.1.1:4433".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4433".parse().unwrap();
+        let lan: SocketAddr = "[fe80::1]:4433".parse().unwrap();
+
+        let mut ep = new_ep(&global);
+        for _ in 0..10 {
+            ep.record_probe(v4, true);
+            ep.record_probe(v6, false);
+            ep.record_probe(lan, false);
+        }
+        assert!(ep.ipv6_unreliable());
+        assert!(ep.prefers_ipv4());
+        assert_eq!(ep.info(Instant::now()).family_stats.ipv6.failures, 10);
+        assert_eq!(global.get().ipv6.failures, 10);
+
+        // A new node falls back to the outcomes of all nodes.
+        let mut other = new_ep(&global);
+        assert!(other.ipv6_unreliable());
+        let mut order = vec![ProbeClass::Ipv6, ProbeClass::Lan, ProbeClass::Ipv4];
+        order.sort_by_key(|class| class.rank(true));
+        assert_eq!(order, [ProbeClass::Lan, ProbeClass::Ipv4, ProbeClass::Ipv6]);
+
+        // Until its own outcomes show IPv6 works for it.
+        for _ in 0..10 {
+            other.record_probe(v4, true);
+            other.record_probe(v6, true);
+        }
+        assert!(!other.ipv6_unreliable());
+    }
+
+    #[test]
+    fn test_prefer_ipv4() {
+        let now = Instant::now();
+        let latency = Duration::from_millis(10);
+        let v4: SocketAddr = "1.1.1.1:4433".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4433".parse().unwrap();
+        for (prefer_ipv4, expected) in [(false, v6), (true, v4)] {
+            for order in [[v4, v6], [v6, v4]] {
+                let mut best = BestAddr::default();
+                for addr in order {
+                    best.insert_if_better_or_reconfirm(
+                        addr,
+                        latency,
+                        best_addr::Source::ReceivedPong,
+                        now,
+                        false,
+                        prefer_ipv4,
+                    );
+                }
+                assert_eq!(best.addr(), Some(expected), "prefer_ipv4: {prefer_ipv4}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_relay_policy() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some(relay_url.clone()),
+                active: true,
+            },
+        );
+        let now = Instant::now();
+        ep.first_contact = Some(now);
+        assert_eq!(
+            ep.addr_for_send(&now, false, false),
+            (None, Some(relay_url.clone()))
+        );
+
+        ep.set_relay_policy(RelayPolicy::Forbid);
+        assert_eq!(ep.addr_for_send(&now, false, false), (None, None));
+        assert_eq!(ep.conn_type.get(), ConnectionType::None);
+
+        // Candidates are still tried, without the relay.
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(addr.into(), PathState::default());
+        assert_eq!(ep.addr_for_send(&now, false, false), (Some(addr), None));
+        ep.direct_addr_state.clear();
+
+        // The relay is used once there was no direct path for long enough.
+        ep.set_relay_policy(RelayPolicy::FallbackAfter(Duration::from_secs(10)));
+        assert_eq!(ep.addr_for_send(&now, false, false), (None, None));
+        let later = now + Duration::from_secs(11);
+        assert_eq!(
+            ep.addr_for_send(&later, false, false),
+            (None, Some(relay_url))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_goodbye() {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let node_id = SecretKey::generate().public();
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: node_id,
+                relay_url: Some(relay_url.clone()),
+                active: true,
+            },
+        );
+        let watchers = EventWatchers::default();
+        ep.set_event_watchers(watchers.clone());
+        let mut events = watchers.subscribe();
+        let now = Instant::now();
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(addr.into(), PathState::with_last_payload(now));
+        ep.best_addr.insert_if_better_or_reconfirm(
+            addr,
+            Duration::from_millis(10),
+            best_addr::Source::ReceivedPong,
+            now,
+            true,
+            false,
+        );
+        ep.last_used = Some(now);
+        assert_eq!(
+            ep.goodbye_addrs(&now),
+            [SendAddr::Udp(addr), SendAddr::Relay(relay_url.clone())]
+        );
+
+        ep.addr_for_send(&now, false, false);
+        assert_eq!(ep.conn_type.get(), ConnectionType::Direct(addr));
+
+        // A goodbye sent long ago is a replay.
+        let sent = SystemTime::now();
+        let goodbye = disco::Goodbye::new(sent);
+        let later = sent + GOODBYE_MAX_AGE + Duration::from_secs(1);
+        assert!(!ep.handle_goodbye(&goodbye, later));
+        assert_eq!(ep.conn_type.get(), ConnectionType::Direct(addr));
+
+        assert!(ep.handle_goodbye(&goodbye, sent));
+        assert_eq!(
+            events.next().await,
+            Some(Ok(MagicSockEvent::NodeOffline(node_id)))
+        );
+        assert_eq!(ep.conn_type.get(), ConnectionType::None);
+        assert!(ep.best_addr.addr().is_none());
+        assert!(!ep.is_active(&now));
+        assert!(ep.goodbye_addrs(&now).is_empty());
+        assert_eq!(ep.relay_reachability, RelayReachability::Unreachable);
+        // The addresses are kept to reach the node once it is back.
+        assert!(ep.direct_addr_state.contains_key(&addr.into()));
+
+        // Once the node is back, a replay of its goodbye changes nothing.
+        ep.best_addr.insert_if_better_or_reconfirm(
+            addr,
+            Duration::from_millis(10),
+            best_addr::Source::ReceivedPong,
+            now,
+            true,
+            false,
+        );
+        ep.addr_for_send(&now, false, false);
+        assert!(!ep.handle_goodbye(&goodbye, sent));
+        assert_eq!(ep.conn_type.get(), ConnectionType::Direct(addr));
+        let next = disco::Goodbye::new(sent + Duration::from_secs(1));
+        assert!(ep.handle_goodbye(&next, sent));
+    }
+
+    #[tokio::test]
+    async fn test_probe_limit() {
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: None,
+                active: true,
+            },
+        );
+        ep.set_path_tuning(PathTuning {
+            max_concurrent_probes: 2,
+        });
+        let v4: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let lan: SocketAddr = "192.168.1.2:4000".parse().unwrap();
+        for addr in [v4, v6, lan] {
+            ep.direct_addr_state
+                .insert(addr.into(), PathState::default());
+        }
+        let ping_dsts = |msgs: Vec<PingAction>| -> Vec<SendAddr> {
+            msgs.into_iter()
+                .map(|msg| match msg {
+                    PingAction::SendPing(ping) => ping.dst,
+                    PingAction::SendCallMeMaybe { .. } => panic!("unexpected call-me-maybe"),
+                })
+                .collect()
+        };
+
+        let now = Instant::now();
+        let (msg_sender, _msg_receiver) = crate::magicsock::actor_queue::channel(8);
+        let msgs = ep.send_pings(now);
+        let mut tx_ids = Vec::new();
+        for msg in &msgs {
+            if let PingAction::SendPing(ping) = msg {
+                ep.ping_sent(
+                    ping.dst.clone(),
+                    ping.tx_id,
+                    ping.purpose,
+                    msg_sender.clone(),
+                );
+                tx_ids.push(ping.tx_id);
+            }
+        }
+        // The fastest classes are probed first.
+        assert_eq!(ping_dsts(msgs), [SendAddr::Udp(lan), SendAddr::Udp(v6)]);
+        assert!(ep.continue_probing(now).is_empty());
+
+        // Once a probe completes the deferred one follows.
+        ep.ping_timeout(tx_ids[0]);
+        assert_eq!(
+            ping_dsts(ep.continue_probing(Instant::now())),
+            [SendAddr::Udp(v4)]
+        );
+        assert!(!ep.deferred_probes);
+        assert!(ep.continue_probing(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_time_to_direct_path() {
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some("https://my-relay.com".parse().unwrap()),
+                active: true,
+            },
+        );
+        let first_contact = ep.first_contact.expect("active endpoint");
+        let conditions = LocalConditions {
+            ipv4: Some(true),
+            ipv6: Some(false),
+            ..Default::default()
+        };
+
+        // Without addresses.
+        assert_eq!(
+            ep.no_direct_path_reason(&conditions),
+            NoDirectPathReason::NoAddresses
+        );
+        let v6_addr: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(v6_addr.into(), PathState::default());
+        assert_eq!(
+            ep.no_direct_path_reason(&conditions),
+            NoDirectPathReason::IpFamilyMismatch
+        );
+        let addr: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+        ep.direct_addr_state
+            .insert(addr.into(), PathState::default());
+        assert_eq!(
+            ep.no_direct_path_reason(&conditions),
+            NoDirectPathReason::HolePunchingFailed
+        );
+        let symmetric = LocalConditions {
+            symmetric_nat: true,
+            ..conditions
+        };
+        assert_eq!(
+            ep.no_direct_path_reason(&symmetric),
+            NoDirectPathReason::SymmetricNat
+        );
+        let disabled = LocalConditions {
+            direct_disabled: true,
+            ..conditions
+        };
+        assert_eq!(
+            ep.no_direct_path_reason(&disabled),
+            NoDirectPathReason::Policy
+        );
+
+        // Nothing is reported before the deadline.
+        ep.check_direct_path_deadline(first_contact + Duration::from_secs(1), &conditions);
+        assert_eq!(ep.no_direct_path, None);
+        ep.check_direct_path_deadline(first_contact + DIRECT_PATH_DEADLINE, &conditions);
+        assert_eq!(
+            ep.no_direct_path,
+            Some(NoDirectPathReason::HolePunchingFailed)
+        );
+
+        // A direct path found later is still recorded.
+        let now = first_contact + Duration::from_secs(40);
+        ep.best_addr.insert_if_better_or_reconfirm(
+            addr,
+            Duration::from_millis(10),
+            best_addr::Source::ReceivedPong,
+            now,
+            true,
+            false,
+        );
+        ep.note_direct_path(now);
+        assert_eq!(ep.time_to_direct, Some(Duration::from_secs(40)));
+        ep.note_direct_path(now + Duration::from_secs(1));
+        let info = ep.info(now);
+        assert_eq!(info.time_to_direct, Some(Duration::from_secs(40)));
+        assert_eq!(
+            info.no_direct_path,
+            Some(NoDirectPathReason::HolePunchingFailed)
+        );
+    }
+
+    #[test]
+    fn test_unreachable_path_backoff() {
+        let mut state = PathState::default();
+        let now = Instant::now();
+        assert!(state.needs_ping(&now));
+
+        // Each timeout doubles the backoff.
+        state.note_ping_timeout(now);
+        assert!(!state.needs_ping(&now));
+        assert!(state.needs_ping(&(now + DISCO_PING_INTERVAL)));
+        state.note_ping_timeout(now);
+        assert!(!state.needs_ping(&(now + DISCO_PING_INTERVAL)));
+        assert!(state.needs_ping(&(now + DISCO_PING_INTERVAL * 2)));
+
+        // The backoff is capped.
+        for _ in 0..64 {
+            state.note_ping_timeout(now);
+        }
+        assert!(!state.needs_ping(&(now + UNREACHABLE_PATH_MAX_BACKOFF - Duration::from_secs(1))));
+        assert!(state.needs_ping(&(now + UNREACHABLE_PATH_MAX_BACKOFF)));
+
+        // Hearing from the path makes us probe it again.
+        state.add_pong_reply(PongReply {
+            latency: Duration::from_millis(10),
+            pong_at: now,
+            from: SendAddr::Udp("203.0.113.1:4000".parse().unwrap()),
+            pong_src: SendAddr::Udp("203.0.113.2:4000".parse().unwrap()),
+        });
+        assert!(state.needs_ping(&now));
+        assert_eq!(state.failed_pings, 0);
+
+        // So does a connectivity change, the path may work on the new network.
+        state.note_ping_timeout(now);
+        assert!(!state.needs_ping(&now));
+        state.clear();
+        assert!(state.needs_ping(&now));
+        assert_eq!(state.failed_pings, 0);
+    }
+
+    #[test]
+    fn test_endpoint_infos() {
         let now = Instant::now();
         let elapsed = Duration::from_secs(3);
         let later = now + elapsed;
         let send_addr: RelayUrl = "https://my-relay.com".parse().unwrap();
-        // endpoint with a `best_addr` that has a latency
         let pong_src = SendAddr::Udp("0.0.0.0:1".parse().unwrap());
         let latency = Duration::from_millis(50);
-        let (a_endpoint, a_socket_addr) = {
-            let ip_port = IpPort {
-                ip: Ipv4Addr::UNSPECIFIED.into(),
-                port: 10,
-            };
-            let endpoint_state = BTreeMap::from([(
-                ip_port,
+        let relay_state = PathState::with_pong_reply(PongReply {
+            latency,
+            pong_at: now,
+            from: SendAddr::Relay(send_addr.clone()),
+            pong_src: pong_src.clone(),
+        });
+        let direct_state = |addr: SocketAddr| {
+            BTreeMap::from([(
+                IpPort::from(addr),
                 PathState::with_pong_reply(PongReply {
                     latency,
                     pong_at: now,
-                    from: SendAddr::Udp(ip_port.into()),
+                    from: SendAddr::Udp(addr),
                     pong_src: pong_src.clone(),
                 }),
-            )]);
-            let key = SecretKey::generate();
-            (
-                Endpoint {
-                    id: 0,
-                    quic_mapped_addr: QuicMappedAddr::generate(),
-                    node_id: key.public(),
-                    last_full_ping: None,
-                    relay_url: new_relay_and_state(Some(send_addr.clone())),
-                    best_addr: BestAddr::from_parts(
-                        ip_port.into(),
-                        latency,
-                        now,
-                        now + Duration::from_secs(100),
-                    ),
-                    direct_addr_state: endpoint_state,
-                    sent_pings: HashMap::new(),
-                    last_used: Some(now),
-                    last_call_me_maybe: None,
-                    conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
-                },
-                ip_port.into(),
-            )
+            )])
         };
-        // endpoint w/ no best addr but a relay w/ latency
-        let b_endpoint = {
-            // let socket_addr = "0.0.0.0:9".parse().unwrap();
-            let relay_state = PathState::with_pong_reply(PongReply {
-                latency,
-                pong_at: now,
-                from: SendAddr::Relay(send_addr.clone()),
-                pong_src: pong_src.clone(),
+
+        let mut inner = NodeMapInner::default();
+        let mut insert = |conn_type: ConnectionType| {
+            let ep = inner.insert_endpoint(Options {
+                public_key: SecretKey::generate().public(),
+                relay_url: Some(send_addr.clone()),
+                active: false,
             });
-            let key = SecretKey::generate();
-            Endpoint {
-                id: 1,
-                quic_mapped_addr: QuicMappedAddr::generate(),
-                node_id: key.public(),
-                last_full_ping: None,
-                relay_url: Some((send_addr.clone(), relay_state)),
-                best_addr: BestAddr::default(),
-                direct_addr_state: BTreeMap::default(),
-                sent_pings: HashMap::new(),
-                last_used: Some(now),
-                last_call_me_maybe: None,
-                conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
-            }
+            ep.last_used = Some(now);
+            ep.conn_type = Watchable::new(conn_type);
+            (ep.id, ep.node_id)
         };
 
+        // endpoint with a `best_addr` that has a latency
+        let a_socket_addr: SocketAddr = (Ipv4Addr::UNSPECIFIED, 10).into();
+        let a_endpoint = insert(ConnectionType::Direct(a_socket_addr));
+        // endpoint w/ no best addr but a relay w/ latency
+        let b_endpoint = insert(ConnectionType::Relay(send_addr.clone()));
         // endpoint w/ no best addr but a relay w/ no latency
-        let c_endpoint = {
-            // let socket_addr = "0.0.0.0:8".parse().unwrap();
-            let endpoint_state = BTreeMap::new();
-            let key = SecretKey::generate();
-            Endpoint {
-                id: 2,
-                quic_mapped_addr: QuicMappedAddr::generate(),
-                node_id: key.public(),
-                last_full_ping: None,
-                relay_url: new_relay_and_state(Some(send_addr.clone())),
-                best_addr: BestAddr::default(),
-                direct_addr_state: endpoint_state,
-                sent_pings: HashMap::new(),
-                last_used: Some(now),
-                last_call_me_maybe: None,
-                conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
-            }
-        };
-
+        let c_endpoint = insert(ConnectionType::Relay(send_addr.clone()));
         // endpoint w/ expired best addr
-        let (d_endpoint, d_socket_addr) = {
-            let socket_addr: SocketAddr = "0.0.0.0:7".parse().unwrap();
-            let expired = now.checked_sub(Duration::from_secs(100)).unwrap();
-            let endpoint_state = BTreeMap::from([(
-                IpPort::from(socket_addr),
-                PathState::with_pong_reply(PongReply {
-                    latency,
-                    pong_at: now,
-                    from: SendAddr::Udp(socket_addr),
-                    pong_src: pong_src.clone(),
-                }),
-            )]);
-            let relay_state = PathState::with_pong_reply(PongReply {
-                latency,
-                pong_at: now,
-                from: SendAddr::Relay(send_addr.clone()),
-                pong_src,
-            });
-            let key = SecretKey::generate();
-            (
-                Endpoint {
-                    id: 3,
-                    quic_mapped_addr: QuicMappedAddr::generate(),
-                    node_id: key.public(),
-                    last_full_ping: None,
-                    relay_url: Some((send_addr.clone(), relay_state)),
-                    best_addr: BestAddr::from_parts(
-                        socket_addr,
-                        Duration::from_millis(80),
-                        now,
-                        expired,
-                    ),
-                    direct_addr_state: endpoint_state,
-                    sent_pings: HashMap::new(),
-                    last_used: Some(now),
-                    last_call_me_maybe: None,
-                    conn_type: Watchable::new(ConnectionType::Mixed(
-                        socket_addr,
-                        send_addr.clone(),
-                    )),
-                },
-                socket_addr,
-            )
+        let d_socket_addr: SocketAddr = "0.0.0.0:7".parse().unwrap();
+        let d_endpoint = insert(ConnectionType::Mixed(d_socket_addr, send_addr.clone()));
+
+        let ep = inner.by_id.get_mut(&a_endpoint.0).unwrap();
+        ep.best_addr =
+            BestAddr::from_parts(a_socket_addr, latency, now, now + Duration::from_secs(100));
+        ep.direct_addr_state = direct_state(a_socket_addr);
+        inner.set_endpoint_for_ip_port(a_socket_addr, a_endpoint.0);
+
+        let ep = inner.by_id.get_mut(&b_endpoint.0).unwrap();
+        ep.relay_url = Some((send_addr.clone(), relay_state.clone()));
+
+        let ep = inner.by_id.get_mut(&d_endpoint.0).unwrap();
+        let expired = now.checked_sub(Duration::from_secs(100)).unwrap();
+        ep.best_addr = BestAddr::from_parts(d_socket_addr, Duration::from_millis(80), now, expired);
+        ep.direct_addr_state = direct_state(d_socket_addr);
+        ep.relay_url = Some((send_addr.clone(), relay_state));
+        inner.set_endpoint_for_ip_port(d_socket_addr, d_endpoint.0);
+
+        let info = |(id, node_id): (usize, PublicKey),
+                    addrs: Vec<DirectAddrInfo>,
+                    conn_type: ConnectionType,
+                    latency: Option<Duration>| EndpointInfo {
+            id,
+            node_id,
+            relay_url: Some(send_addr.clone()),
+            addrs,
+            conn_type,
+            latency,
+            last_used: Some(elapsed),
+            relay_reachability: RelayReachability::Unknown,
+            time_to_direct: None,
+            no_direct_path: None,
+            relay_policy: RelayPolicy::Allow,
+            bytes_sent: 0,
+            bytes_recv: 0,
+            family_stats: FamilyStats::default(),
+        };
+        let direct_info = |addr: SocketAddr| DirectAddrInfo {
+            addr,
+            latency: Some(latency),
+            last_control: Some((elapsed, ControlMsg::Pong)),
+            last_payload: None,
         };
         let expect = Vec::from([
-            EndpointInfo {
-                id: a_endpoint.id,
-                node_id: a_endpoint.node_id,
-                relay_url: a_endpoint.relay_url(),
-                addrs: Vec::from([DirectAddrInfo {
-                    addr: a_socket_addr,
-                    latency: Some(latency),
-                    last_control: Some((elapsed, ControlMsg::Pong)),
-                    last_payload: None,
-                }]),
-                conn_type: ConnectionType::Direct(a_socket_addr),
-                latency: Some(latency),
-                last_used: Some(elapsed),
-            },
-            EndpointInfo {
-                id: b_endpoint.id,
-                node_id: b_endpoint.node_id,
-                relay_url: b_endpoint.relay_url(),
-                addrs: Vec::new(),
-                conn_type: ConnectionType::Relay(send_addr.clone()),
-                latency: Some(latency),
-                last_used: Some(elapsed),
-            },
-            EndpointInfo {
-                id: c_endpoint.id,
-                node_id: c_endpoint.node_id,
-                relay_url: c_endpoint.relay_url(),
-                addrs: Vec::new(),
-                conn_type: ConnectionType::Relay(send_addr.clone()),
-                latency: None,
-                last_used: Some(elapsed),
-            },
-            EndpointInfo {
-                id: d_endpoint.id,
-                node_id: d_endpoint.node_id,
-                relay_url: d_endpoint.relay_url(),
-                addrs: Vec::from([DirectAddrInfo {
-                    addr: d_socket_addr,
-                    latency: Some(latency),
-                    last_control: Some((elapsed, ControlMsg::Pong)),
-                    last_payload: None,
-                }]),
-                conn_type: ConnectionType::Mixed(d_socket_addr, send_addr.clone()),
-                latency: Some(Duration::from_millis(50)),
-                last_used: Some(elapsed),
-            },
+            info(
+                a_endpoint,
+                Vec::from([direct_info(a_socket_addr)]),
+                ConnectionType::Direct(a_socket_addr),
+                Some(latency),
+            ),
+            info(
+                b_endpoint,
+                Vec::new(),
+                ConnectionType::Relay(send_addr.clone()),
+                Some(latency),
+            ),
+            info(
+                c_endpoint,
+                Vec::new(),
+                ConnectionType::Relay(send_addr.clone()),
+                None,
+            ),
+            info(
+                d_endpoint,
+                Vec::from([direct_info(d_socket_addr)]),
+                ConnectionType::Mixed(d_socket_addr, send_addr.clone()),
+                Some(latency),
+            ),
         ]);
 
-        let node_map = NodeMap::from_inner(NodeMapInner {
-            by_node_key: HashMap::from([
-                (a_endpoint.node_id, a_endpoint.id),
-                (b_endpoint.node_id, b_endpoint.id),
-                (c_endpoint.node_id, c_endpoint.id),
-                (d_endpoint.node_id, d_endpoint.id),
-            ]),
-            by_ip_port: HashMap::from([
-                (a_socket_addr.into(), a_endpoint.id),
-                (d_socket_addr.into(), d_endpoint.id),
-            ]),
-            by_quic_mapped_addr: HashMap::from([
-                (a_endpoint.quic_mapped_addr, a_endpoint.id),
-                (b_endpoint.quic_mapped_addr, b_endpoint.id),
-                (c_endpoint.quic_mapped_addr, c_endpoint.id),
-                (d_endpoint.quic_mapped_addr, d_endpoint.id),
-            ]),
-            by_id: HashMap::from([
-                (a_endpoint.id, a_endpoint),
-                (b_endpoint.id, b_endpoint),
-                (c_endpoint.id, c_endpoint),
-                (d_endpoint.id, d_endpoint),
-            ]),
-            next_id: 5,
-        });
+        let node_map = NodeMap::from_inner(inner);
         let mut got = node_map.endpoint_infos(later);
         got.sort_by_key(|p| p.id);
         assert_eq!(expect, got);
@@ -1651,6 +3027,10 @@ mod tests {
             active: true,
         };
         let mut ep = Endpoint::new(0, opts);
+        // Probe all addresses at once.
+        ep.set_path_tuning(PathTuning {
+            max_concurrent_probes: 0,
+        });
 
         let my_numbers_count: u16 = (MAX_INACTIVE_DIRECT_ADDRESSES + 5).try_into().unwrap();
         let my_numbers = (0u16..my_numbers_count)
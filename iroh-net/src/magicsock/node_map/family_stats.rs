@@ -0,0 +1,117 @@
+//! Success rates of direct path probes per IP family.
+//!
+//! On hosts with broken IPv6, e.g. router advertisements but no forwarding, every IPv6 probe
+//! times out.  The outcomes of the probes to global addresses are tracked for all nodes
+//! together and per node, so once IPv6 is known to fail IPv4 paths are probed and preferred
+//! first, also for nodes we did not talk to before.
+
+use std::{net::IpAddr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+/// Once more outcomes are recorded, the counts are halved, so older outcomes fade out.
+const WINDOW: u32 = 64;
+
+/// The minimum number of outcomes before a success rate is reported.
+const MIN_SAMPLES: u32 = 8;
+
+/// Outcomes of recent probes to the direct paths of one IP family.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeStats {
+    /// Recent probes which were answered.
+    pub successes: u32,
+    /// Recent probes which timed out.
+    pub failures: u32,
+}
+
+impl ProbeStats {
+    fn record(&mut self, success: bool) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+        if self.successes + self.failures > WINDOW {
+            self.successes /= 2;
+            self.failures /= 2;
+        }
+    }
+
+    /// The share of recent probes which were answered, if there were enough of them.
+    pub fn success_rate(&self) -> Option<f64> {
+        let total = self.successes + self.failures;
+        (total >= MIN_SAMPLES).then(|| self.successes as f64 / total as f64)
+    }
+}
+
+/// Outcomes of recent probes to direct paths, per IP family.
+///
+/// Only probes to global addresses are counted, the addresses of other local networks are
+/// expected to fail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FamilyStats {
+    /// Probes to IPv4 addresses.
+    pub ipv4: ProbeStats,
+    /// Probes to IPv6 addresses.
+    pub ipv6: ProbeStats,
+}
+
+impl FamilyStats {
+    pub(super) fn record(&mut self, addr: IpAddr, success: bool) {
+        match addr {
+            IpAddr::V4(_) => self.ipv4.record(success),
+            IpAddr::V6(_) => self.ipv6.record(success),
+        }
+    }
+
+    /// Whether IPv6 probes fail much more often than IPv4 probes.
+    ///
+    /// Returns `None` if there are not enough outcomes of both families to tell.
+    pub fn ipv6_unreliable(&self) -> Option<bool> {
+        let ipv4 = self.ipv4.success_rate()?;
+        let ipv6 = self.ipv6.success_rate()?;
+        Some(ipv6 < ipv4 / 2.0)
+    }
+}
+
+/// The [`FamilyStats`] of all nodes, shared by the endpoints of the node map.
+#[derive(Debug, Clone, Default)]
+pub(super) struct SharedFamilyStats(Arc<parking_lot::Mutex<FamilyStats>>);
+
+impl SharedFamilyStats {
+    pub(super) fn record(&self, addr: IpAddr, success: bool) {
+        self.0.lock().record(addr, success);
+    }
+
+    pub(super) fn get(&self) -> FamilyStats {
+        *self.0.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv6_unreliable() {
+        let v4: IpAddr = "1.1.1.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut stats = FamilyStats::default();
+        for _ in 0..MIN_SAMPLES {
+            stats.record(v4, true);
+        }
+        assert_eq!(stats.ipv6_unreliable(), None);
+
+        for _ in 0..MIN_SAMPLES {
+            stats.record(v6, false);
+        }
+        assert_eq!(stats.ipv6_unreliable(), Some(true));
+
+        // Once IPv6 recovers the old failures fade out.
+        for _ in 0..WINDOW {
+            stats.record(v6, true);
+        }
+        assert_eq!(stats.ipv6_unreliable(), Some(false));
+        assert!(stats.ipv6.successes + stats.ipv6.failures <= WINDOW);
+    }
+}
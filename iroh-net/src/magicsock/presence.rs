@@ -0,0 +1,145 @@
+//! Watching whether other nodes are connected to our home relay server.
+//!
+//! The home relay server knows which nodes are connected to it, and reports when the ones we
+//! ask it about connect or disconnect.  [`super::MagicSock::watch_presence`] subscribes to
+//! these reports for a set of nodes.  All subscriptions are combined into the one set of
+//! nodes the relay server is asked to watch.
+
+use std::{
+    collections::BTreeSet,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+
+use super::events::{Lagged, MagicSockEvent, MagicSockEventStream};
+use crate::key::PublicKey;
+
+/// A change in the presence of a node on the home relay server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresenceEvent {
+    /// The node whose presence changed.
+    pub node: PublicKey,
+    /// Whether the node is connected to our home relay server.
+    pub online: bool,
+}
+
+/// Stream of [`PresenceEvent`]s, returned by [`super::MagicSock::watch_presence`].
+///
+/// A view of the [`MagicSockEvent::Presence`] events of the watched nodes.  Dropping the
+/// stream ends the subscription.
+#[derive(Debug)]
+pub struct PresenceStream {
+    nodes: Arc<BTreeSet<PublicKey>>,
+    events: MagicSockEventStream,
+}
+
+impl Stream for PresenceStream {
+    type Item = Result<PresenceEvent, Lagged>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let nodes = &this.nodes;
+        this.events.poll_filtered(cx, |event| match event {
+            MagicSockEvent::Presence(event) if nodes.contains(&event.node) => Some(event),
+            _ => None,
+        })
+    }
+}
+
+/// The nodes watched by all open [`PresenceStream`]s.
+#[derive(Debug, Default)]
+pub(super) struct PresenceWatchers {
+    watchers: parking_lot::Mutex<Vec<Weak<BTreeSet<PublicKey>>>>,
+}
+
+impl PresenceWatchers {
+    /// Adds a subscription for `nodes`, if the nodes watched altogether stay within `limit`.
+    pub(super) fn add(
+        &self,
+        nodes: BTreeSet<PublicKey>,
+        limit: usize,
+        events: MagicSockEventStream,
+    ) -> Result<PresenceStream, usize> {
+        let mut watchers = self.watchers.lock();
+        watchers.retain(|w| w.strong_count() > 0);
+        let mut all: BTreeSet<_> = watchers
+            .iter()
+            .filter_map(Weak::upgrade)
+            .flat_map(|nodes| nodes.iter().copied().collect::<Vec<_>>())
+            .collect();
+        all.extend(nodes.iter());
+        if all.len() > limit {
+            return Err(all.len());
+        }
+        let nodes = Arc::new(nodes);
+        watchers.push(Arc::downgrade(&nodes));
+        Ok(PresenceStream { nodes, events })
+    }
+
+    /// All nodes watched by open subscriptions.
+    pub(super) fn nodes(&self) -> Vec<PublicKey> {
+        let mut watchers = self.watchers.lock();
+        watchers.retain(|w| w.strong_count() > 0);
+        let all: BTreeSet<_> = watchers
+            .iter()
+            .filter_map(Weak::upgrade)
+            .flat_map(|nodes| nodes.iter().copied().collect::<Vec<_>>())
+            .collect();
+        all.into_iter().collect()
+    }
+
+    /// Whether an open subscription watches `node`.
+    pub(super) fn is_watched(&self, node: &PublicKey) -> bool {
+        self.watchers
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|nodes| nodes.contains(node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{key::SecretKey, magicsock::events::EventWatchers};
+
+    #[tokio::test]
+    async fn test_watchers() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let events = EventWatchers::default();
+        let watchers = PresenceWatchers::default();
+        let mut stream_a = watchers.add([a].into(), 2, events.subscribe()).unwrap();
+        let stream_ab = watchers.add([a, b].into(), 2, events.subscribe()).unwrap();
+        let c = SecretKey::generate().public();
+        assert_eq!(
+            watchers.add([c].into(), 2, events.subscribe()).unwrap_err(),
+            3
+        );
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(watchers.nodes(), expected);
+
+        // events of other nodes are skipped
+        for node in [b, a] {
+            events.notify(MagicSockEvent::Presence(PresenceEvent {
+                node,
+                online: true,
+            }));
+        }
+        let event = PresenceEvent {
+            node: a,
+            online: true,
+        };
+        assert_eq!(stream_a.next().await, Some(Ok(event)));
+
+        drop(stream_ab);
+        assert!(!watchers.is_watched(&b));
+        assert_eq!(watchers.nodes(), vec![a]);
+    }
+}
@@ -9,7 +9,7 @@ use anyhow::Context;
 use backoff::backoff::Backoff;
 use bytes::{Bytes, BytesMut};
 use futures::Future;
-use iroh_metrics::{inc, inc_by};
+use iroh_metrics::{inc, inc_by, observe, set};
 use tokio::{
     sync::{mpsc, oneshot},
     task::{JoinHandle, JoinSet},
@@ -23,7 +23,10 @@ use crate::{
     relay::{self, http::ClientError, ReceivedMessage, RelayUrl, MAX_PACKET_SIZE},
 };
 
-use super::{ActorMessage, Inner};
+use super::{
+    actor_queue::ActorSender, log_limit::warn_limited, presence::PresenceEvent, ActorMessage,
+    Inner, OverflowPolicy,
+};
 use super::{Metrics as MagicsockMetrics, RelayContents};
 
 /// How long a non-home relay connection needs to be idle (last written to) before we close it.
@@ -32,16 +35,33 @@ const RELAY_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
 /// How often `clean_stale_relay` runs when there are potentially-stale relay connections to close.
 const RELAY_CLEAN_STALE_INTERVAL: Duration = Duration::from_secs(15);
 
+/// How often the latency of an active relay connection is measured.
+const RELAY_LATENCY_INTERVAL: Duration = Duration::from_secs(15);
+
 pub(super) enum RelayActorMessage {
     Send {
         url: RelayUrl,
         contents: RelayContents,
         peer: PublicKey,
     },
+    /// Like [`RelayActorMessage::Send`], but retried over other connected relays if the
+    /// node's relay cannot be reached.
+    ///
+    /// Used for call-me-maybe messages, so hole punching does not stall on an unreachable
+    /// relay.
+    SendCallMeMaybe {
+        url: RelayUrl,
+        contents: RelayContents,
+        peer: PublicKey,
+    },
     MaybeCloseRelaysOnRebind(Vec<IpAddr>),
+    /// Closes all relay connections, used when going offline.
+    CloseAll,
     SetHome {
         url: RelayUrl,
     },
+    /// Replaces the nodes whose presence the home relay reports.
+    WatchPresence(Vec<PublicKey>),
 }
 
 /// Contains fields for an active relay connection.
@@ -50,7 +70,9 @@ struct ActiveRelay {
     /// The time of the last request for its write
     /// channel (currently even if there was no write).
     last_write: Instant,
-    msg_sender: mpsc::Sender<ActorMessage>,
+    msg_sender: ActorSender,
+    /// What happens to received packets while the queue of `msg_sender` is full.
+    overflow: OverflowPolicy,
     /// Contains optional alternate routes to use as an optimization instead of
     /// contacting a peer via their home relay connection. If they sent us a message
     /// on this relay connection (which should really only be on our relay
@@ -66,6 +88,10 @@ struct ActiveRelay {
     backoff: backoff::exponential::ExponentialBackoff<backoff::SystemClock>,
     last_packet_time: Option<Instant>,
     last_packet_src: Option<PublicKey>,
+    /// The currently running latency measurement, if any.
+    latency_ping: Option<JoinHandle<()>>,
+    /// The currently running reconnect, if any.
+    reconnect: Option<JoinHandle<()>>,
 }
 
 #[derive(Debug)]
@@ -77,6 +103,11 @@ enum ActiveRelayMessage {
     GetPeerRoute(PublicKey, oneshot::Sender<Option<relay::http::Client>>),
     GetClient(oneshot::Sender<relay::http::Client>),
     NotePreferred(bool),
+    WatchPresence(Vec<PublicKey>),
+    /// Drop the underlying connection and immediately dial the relay server again.
+    ///
+    /// Unlike closing the [`ActiveRelay`], this keeps the routes learned on this connection.
+    Reconnect(&'static str),
     Shutdown,
 }
 
@@ -85,11 +116,13 @@ impl ActiveRelay {
         url: RelayUrl,
         relay_client: relay::http::Client,
         relay_client_receiver: relay::http::ClientReceiver,
-        msg_sender: mpsc::Sender<ActorMessage>,
+        msg_sender: ActorSender,
+        overflow: OverflowPolicy,
     ) -> Self {
         ActiveRelay {
             last_write: Instant::now(),
             msg_sender,
+            overflow,
             relay_routes: Default::default(),
             url,
             peer_present: HashSet::new(),
@@ -99,6 +132,8 @@ impl ActiveRelay {
                 .build(),
             last_packet_time: None,
             last_packet_src: None,
+            latency_ping: None,
+            reconnect: None,
             relay_client,
             relay_client_receiver,
         }
@@ -111,6 +146,9 @@ impl ActiveRelay {
             .await
             .context("initial connection")?;
 
+        let mut latency_timer = time::interval(RELAY_LATENCY_INTERVAL);
+        latency_timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
         loop {
             tokio::select! {
                 Some(msg) = inbox.recv() => {
@@ -132,6 +170,12 @@ impl ActiveRelay {
                         ActiveRelayMessage::NotePreferred(is_preferred) => {
                             self.relay_client.note_preferred(is_preferred).await;
                         }
+                        ActiveRelayMessage::WatchPresence(nodes) => {
+                            self.relay_client.watch_presence(nodes).await;
+                        }
+                        ActiveRelayMessage::Reconnect(why) => {
+                            self.reconnect(why);
+                        }
                         ActiveRelayMessage::GetPeerRoute(peer, r) => {
                             let res = if self.relay_routes.contains(&peer) {
                                 Some(self.relay_client.clone())
@@ -146,6 +190,10 @@ impl ActiveRelay {
                         }
                     }
                 }
+                _ = latency_timer.tick() => {
+                    trace!("tick: latency_timer");
+                    self.measure_latency();
+                }
                 msg = self.relay_client_receiver.recv() => {
                     trace!("tick: relay_client_receiver");
                     if let Some(msg) = msg {
@@ -162,9 +210,69 @@ impl ActiveRelay {
             }
         }
 
+        if let Some(task) = self.latency_ping.take() {
+            task.abort();
+        }
+        if let Some(task) = self.reconnect.take() {
+            task.abort();
+        }
         Ok(())
     }
 
+    /// Drops the connection and dials the relay server again, in the background.
+    ///
+    /// The connection keeps being read meanwhile, so the inbox and the messages of the new
+    /// connection are not held up by the dial.
+    fn reconnect(&mut self, why: &'static str) {
+        if self
+            .reconnect
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            debug!(url = %self.url, %why, "already reconnecting");
+            return;
+        }
+        debug!(url = %self.url, %why, "reconnecting");
+        self.backoff.reset();
+        let client = self.relay_client.clone();
+        let url = self.url.clone();
+        let task = async move {
+            client.close_for_reconnect().await.ok();
+            if let Err(err) = client.connect().await {
+                record_client_error(&err);
+                warn!(%url, "reconnect failed: {:?}", err);
+            }
+        };
+        self.reconnect = Some(tokio::spawn(task.instrument(tracing::Span::current())));
+    }
+
+    /// Pings the relay server and reports the latency to the magicsock actor.
+    fn measure_latency(&mut self) {
+        if self
+            .latency_ping
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            return;
+        }
+        let client = self.relay_client.clone();
+        let url = self.url.clone();
+        let msg_sender = self.msg_sender.clone();
+        let task = async move {
+            match client.ping().await {
+                Ok(latency) => {
+                    trace!(?latency, "relay latency");
+                    msg_sender
+                        .send(ActorMessage::RelayLatency(url, latency))
+                        .await
+                        .ok();
+                }
+                Err(err) => debug!("latency ping failed: {:?}", err),
+            }
+        };
+        self.latency_ping = Some(tokio::spawn(task.instrument(tracing::Span::current())));
+    }
+
     async fn handle_relay_msg(
         &mut self,
         msg: Result<(ReceivedMessage, usize), ClientError>,
@@ -172,6 +280,7 @@ impl ActiveRelay {
         match msg {
             Err(err) => {
                 warn!("recv error {:?}", err);
+                record_client_error(&err);
 
                 // Forget that all these peers have routes.
                 let peers: Vec<_> = self.peer_present.drain().collect();
@@ -241,9 +350,17 @@ impl ActiveRelay {
                             src: source,
                             buf: data,
                         };
-                        if let Err(err) = self.msg_sender.try_send(ActorMessage::ReceiveRelay(res))
-                        {
-                            warn!("dropping received relay packet: {:?}", err);
+                        let msg = ActorMessage::ReceiveRelay(res);
+                        match self.overflow {
+                            OverflowPolicy::Drop => {
+                                if let Err(err) = self.msg_sender.try_send(msg) {
+                                    inc!(MagicsockMetrics, relay_recv_dropped);
+                                    warn_limited!("dropping received relay packet: {:?}", err);
+                                }
+                            }
+                            OverflowPolicy::Block => {
+                                self.msg_sender.send(msg).await.ok();
+                            }
                         }
 
                         ReadResult::Continue
@@ -261,6 +378,21 @@ impl ActiveRelay {
                     relay::ReceivedMessage::Health { .. } => ReadResult::Continue,
                     relay::ReceivedMessage::PeerGone(key) => {
                         self.relay_routes.retain(|peer| peer != &key);
+                        // Waiting for room in the queue would hold up the keepalives and
+                        // control frames read after this one.
+                        let msg = ActorMessage::RelayPeerGone(self.url.clone(), key);
+                        if let Err(err) = self.msg_sender.try_send(msg) {
+                            warn_limited!("dropping relay peer gone: {:?}", err);
+                        }
+                        ReadResult::Continue
+                    }
+                    relay::ReceivedMessage::PeerPresence { peer, online } => {
+                        let event = PresenceEvent { node: peer, online };
+                        if let Err(err) =
+                            self.msg_sender.try_send(ActorMessage::RelayPresence(event))
+                        {
+                            warn_limited!("dropping relay presence: {:?}", err);
+                        }
                         ReadResult::Continue
                     }
                     other => {
@@ -278,19 +410,25 @@ pub(super) struct RelayActor {
     conn: Arc<Inner>,
     /// relay Url -> connection to the node
     active_relay: BTreeMap<RelayUrl, (mpsc::Sender<ActiveRelayMessage>, JoinHandle<()>)>,
-    msg_sender: mpsc::Sender<ActorMessage>,
+    msg_sender: ActorSender,
     ping_tasks: JoinSet<(RelayUrl, bool)>,
+    /// The nodes whose presence the home relay is asked to report.
+    watched_presence: Vec<PublicKey>,
+    /// The relay which was last asked to report presence.
+    presence_relay: Option<RelayUrl>,
     cancel_token: CancellationToken,
 }
 
 impl RelayActor {
-    pub(super) fn new(conn: Arc<Inner>, msg_sender: mpsc::Sender<ActorMessage>) -> Self {
+    pub(super) fn new(conn: Arc<Inner>, msg_sender: ActorSender) -> Self {
         let cancel_token = CancellationToken::new();
         Self {
             conn,
             active_relay: Default::default(),
             msg_sender,
             ping_tasks: Default::default(),
+            watched_presence: Vec::new(),
+            presence_relay: None,
             cancel_token,
         }
     }
@@ -322,7 +460,14 @@ impl RelayActor {
                     }
                 }
                 Some(msg) = receiver.recv() => {
+                    set!(MagicsockMetrics, relay_actor_queue_len, receiver.len() as i64);
+                    let start = Instant::now();
                     with_cancel(self.cancel_token.child_token(), self.handle_msg(msg)).await;
+                    observe!(
+                        MagicsockMetrics,
+                        relay_actor_msg_duration,
+                        start.elapsed().as_secs_f64()
+                    );
                 }
                 _ = cleanup_timer.tick() => {
                     trace!("tick: cleanup");
@@ -348,13 +493,30 @@ impl RelayActor {
             } => {
                 self.send_relay(&url, contents, peer).await;
             }
+            RelayActorMessage::SendCallMeMaybe {
+                url,
+                contents,
+                peer,
+            } => {
+                if !self.send_relay(&url, contents.clone(), peer).await {
+                    self.send_via_alternate_relay(&url, contents, peer).await;
+                }
+            }
             RelayActorMessage::SetHome { url } => {
                 self.note_preferred(&url).await;
                 self.connect_relay(&url, None).await;
+                self.update_presence().await;
+            }
+            RelayActorMessage::WatchPresence(nodes) => {
+                self.watched_presence = nodes;
+                self.update_presence().await;
             }
             RelayActorMessage::MaybeCloseRelaysOnRebind(ifs) => {
                 self.maybe_close_relays_on_rebind(&ifs).await;
             }
+            RelayActorMessage::CloseAll => {
+                self.close_all_relay("offline").await;
+            }
         }
     }
 
@@ -368,7 +530,38 @@ impl RelayActor {
         .await;
     }
 
-    async fn send_relay(&mut self, url: &RelayUrl, contents: RelayContents, peer: PublicKey) {
+    /// Asks the home relay to report the presence of the watched nodes, and the relay asked
+    /// before to stop if it is no longer needed.
+    async fn update_presence(&mut self) {
+        let home = self.conn.my_relay();
+        if let Some(old) = self.presence_relay.take() {
+            if Some(&old) != home.as_ref() || self.watched_presence.is_empty() {
+                self.send_to_active(&old, ActiveRelayMessage::WatchPresence(Vec::new()))
+                    .await;
+            }
+        }
+        let Some(home) = home else {
+            return;
+        };
+        if self.watched_presence.is_empty() {
+            return;
+        }
+        self.connect_relay(&home, None).await;
+        let msg = ActiveRelayMessage::WatchPresence(self.watched_presence.clone());
+        if self.send_to_active(&home, msg).await {
+            self.presence_relay = Some(home);
+        }
+    }
+
+    /// Sends the contents to `peer` over the relay at `url`.
+    ///
+    /// Returns `false` if any of the packets could not be sent.
+    async fn send_relay(
+        &mut self,
+        url: &RelayUrl,
+        contents: RelayContents,
+        peer: PublicKey,
+    ) -> bool {
         trace!(%url, peer = %peer.fmt_short(),len = contents.iter().map(|c| c.len()).sum::<usize>(),  "sending over relay");
         // Relay Send
         let relay_client = self.connect_relay(url, Some(&peer)).await;
@@ -383,6 +576,7 @@ impl RelayActor {
         // In almost all cases this will be a single packet.
         // But we have no guarantee that the total size of the contents including
         // length prefix will be smaller than the payload size.
+        let mut sent = true;
         for packet in PacketizeIter::<_, PAYLAOD_SIZE>::new(contents) {
             match relay_client.send(peer, packet).await {
                 Ok(_) => {
@@ -391,6 +585,8 @@ impl RelayActor {
                 Err(err) => {
                     warn!(%url, "send: failed {:?}", err);
                     inc!(MagicsockMetrics, send_relay_error);
+                    record_client_error(&err);
+                    sent = false;
                 }
             }
         }
@@ -400,6 +596,55 @@ impl RelayActor {
         if let Some(waker) = wakers.take() {
             waker.wake();
         }
+        sent
+    }
+
+    /// Sends the contents to `peer` over a connected relay other than `failed`.
+    ///
+    /// Relays on which we heard from the peer are tried first, then our home relay, which
+    /// the peer may well be connected to as well.  Returns whether any relay accepted the
+    /// contents.
+    async fn send_via_alternate_relay(
+        &mut self,
+        failed: &RelayUrl,
+        contents: RelayContents,
+        peer: PublicKey,
+    ) -> bool {
+        let mut candidates = Vec::new();
+        for url in self
+            .active_relay
+            .keys()
+            .filter(|url| *url != failed)
+            .cloned()
+            .collect::<Vec<_>>()
+        {
+            let (os, or) = oneshot::channel();
+            if self
+                .send_to_active(&url, ActiveRelayMessage::GetPeerRoute(peer, os))
+                .await
+                && matches!(or.await, Ok(Some(_)))
+            {
+                candidates.push(url);
+            }
+        }
+        if let Some(home) = self.conn.my_relay() {
+            if &home != failed
+                && !candidates.contains(&home)
+                && self.active_relay.contains_key(&home)
+            {
+                candidates.push(home);
+            }
+        }
+
+        for url in candidates {
+            debug!(%url, %failed, peer = %peer.fmt_short(), "retrying over alternate relay");
+            if self.send_relay(&url, contents.clone(), peer).await {
+                inc!(MagicsockMetrics, relay_call_me_maybe_alternate);
+                return true;
+            }
+        }
+        debug!(%failed, peer = %peer.fmt_short(), "no alternate relay available");
+        false
     }
 
     /// Returns `true`if the message was sent successfully.
@@ -446,7 +691,10 @@ impl RelayActor {
         // perhaps peer's home is Frankfurt, but they dialed our home relay
         // node in SF to reach us, so we can reply to them using our
         // SF connection rather than dialing Frankfurt.
-        if let Some(peer) = peer {
+        //
+        // Relays from our own map are dialed directly however, routing via the
+        // peer's home relay is usually the lower latency path.
+        if let Some(peer) = peer.filter(|_| !self.conn.relay_map.contains_node(url)) {
             for url in self
                 .active_relay
                 .keys()
@@ -485,7 +733,15 @@ impl RelayActor {
                 Box::pin(async move { ipv6_reported.load(Ordering::Relaxed) })
             })
             .can_ack_pings(true)
-            .is_preferred(my_relay.as_ref() == Some(&url1));
+            .is_preferred(my_relay.as_ref() == Some(&url1))
+            .socket_options(self.conn.socket_options.clone())
+            .dial_timeout(self.conn.timeouts.relay_dial)
+            .connect_timeout(self.conn.timeouts.relay_connect)
+            .ping_timeout(self.conn.timeouts.relay_ping);
+        let builder = match self.conn.socks5_proxy {
+            Some(ref proxy) => builder.socks5_proxy(proxy.clone()),
+            None => builder,
+        };
 
         #[cfg(any(test, feature = "test-utils"))]
         let builder = builder.insecure_skip_cert_verify(self.conn.insecure_skip_relay_cert_verify);
@@ -497,10 +753,11 @@ impl RelayActor {
 
         let c = dc.clone();
         let msg_sender = self.msg_sender.clone();
+        let overflow = self.conn.buffers.overflow;
         let url1 = url.clone();
-        let handle = tokio::task::spawn(
+        let handle = self.conn.rt.spawn(
             async move {
-                let ad = ActiveRelay::new(url1, c, dc_receiver, msg_sender);
+                let ad = ActiveRelay::new(url1, c, dc_receiver, msg_sender, overflow);
 
                 if let Err(err) = ad.run(r).await {
                     warn!("connection error: {:?}", err);
@@ -582,13 +839,26 @@ impl RelayActor {
         self.log_active_relay();
     }
 
-    /// Closes the relay connection to the provided `url` and starts reconnecting it if it's
-    /// our current home relay.
+    /// Closes the relay connection to the provided `url`, or reconnects it right away if
+    /// it's our current home relay.
+    ///
+    /// The home relay is reconnected in place, so that the routes to the peers which reached
+    /// us over it survive the reconnect.
     async fn close_or_reconnect_relay(&mut self, url: &RelayUrl, why: &'static str) {
-        self.close_relay(url, why).await;
-        if self.conn.my_relay().as_ref() == Some(url) {
-            self.connect_relay(url, None).await;
+        if self.conn.my_relay().as_ref() != Some(url) {
+            self.close_relay(url, why).await;
+            return;
+        }
+        if self
+            .send_to_active(url, ActiveRelayMessage::Reconnect(why))
+            .await
+        {
+            inc!(MagicsockMetrics, relay_home_reconnect);
+            return;
         }
+        self.close_relay(url, why).await;
+        self.connect_relay(url, None).await;
+        self.update_presence().await;
     }
 
     async fn clean_stale_relay(&mut self) {
@@ -650,6 +920,9 @@ impl RelayActor {
 
             s.send(ActiveRelayMessage::Shutdown).await.ok();
             t.abort(); // ensure the task is shutdown
+            if self.presence_relay.as_ref() == Some(url) {
+                self.presence_relay = None;
+            }
 
             inc!(MagicsockMetrics, num_relay_conns_removed);
         }
@@ -691,6 +964,13 @@ pub(super) enum ReadResult {
     Continue,
 }
 
+/// Records relay client errors which are worth telling apart in the metrics.
+fn record_client_error(err: &ClientError) {
+    if let ClientError::Tls(_) = err {
+        inc!(MagicsockMetrics, relay_connect_error_tls);
+    }
+}
+
 /// Combines blobs into packets of at most MAX_PACKET_SIZE.
 ///
 /// Each item in a packet has a little-endian 2-byte length prefix.
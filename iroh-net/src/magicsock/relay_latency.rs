@@ -0,0 +1,166 @@
+//! Continuously updated latency estimates for the relay servers.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::relay::RelayUrl;
+
+/// The weight of a new latency sample in the moving average.
+const EWMA_ALPHA: f64 = 0.3;
+
+/// The latency estimates for one relay server, see [`RelayLatencyMap`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayLatency {
+    /// The latency of the STUN probes of the netcheck reports, `None` if not measured yet.
+    ///
+    /// This is what netcheck picks the preferred relay by.
+    pub stun: Option<Duration>,
+    /// The round trip time of the pings on the relay connection, `None` if not measured yet.
+    ///
+    /// Only measured while connected to the relay, and includes the TLS and relay server
+    /// overhead, so it is not comparable to [`RelayLatency::stun`].
+    pub rtt: Option<Duration>,
+}
+
+/// Exponentially weighted moving averages of the latency to each relay server.
+///
+/// The STUN latencies of the netcheck reports and the round trip times of the periodic pings
+/// on the active relay connections measure different paths, so they are averaged separately.
+/// The round trip times keep the estimates current between netcheck runs.
+#[derive(Debug, Default)]
+pub(super) struct RelayLatencyMap(parking_lot::Mutex<BTreeMap<RelayUrl, RelayLatency>>);
+
+impl RelayLatencyMap {
+    /// Adds a new STUN latency sample for the relay, from a netcheck report.
+    pub(super) fn add_stun_sample(&self, url: &RelayUrl, sample: Duration) {
+        let mut latencies = self.0.lock();
+        let latency = latencies.entry(url.clone()).or_default();
+        latency.stun = Some(ewma(latency.stun, sample));
+    }
+
+    /// Adds a new round trip time sample for the relay, from a ping on its connection.
+    pub(super) fn add_rtt_sample(&self, url: &RelayUrl, sample: Duration) {
+        let mut latencies = self.0.lock();
+        let latency = latencies.entry(url.clone()).or_default();
+        latency.rtt = Some(ewma(latency.rtt, sample));
+    }
+
+    /// Returns the current latency estimates for all relays we have samples for.
+    pub(super) fn latencies(&self) -> BTreeMap<RelayUrl, RelayLatency> {
+        self.0.lock().clone()
+    }
+
+    /// Returns a relay whose connection is significantly faster than the `home` relay's, if
+    /// any.
+    ///
+    /// Only the round trip times are compared, the STUN latencies are left to netcheck.  Only
+    /// switch if the other relay is at least a third faster, which is the same hysteresis
+    /// netcheck applies when picking the preferred relay.
+    pub(super) fn better_than(&self, home: &RelayUrl) -> Option<RelayUrl> {
+        let latencies = self.0.lock();
+        let home_rtt = latencies.get(home)?.rtt?;
+        let (best, best_rtt) = latencies
+            .iter()
+            .filter_map(|(url, latency)| Some((url, latency.rtt?)))
+            .min_by_key(|(_, rtt)| *rtt)?;
+        if best != home && best_rtt < home_rtt / 3 * 2 {
+            Some(best.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Adds `sample` to the moving average `current`.
+fn ewma(current: Option<Duration>, sample: Duration) -> Duration {
+    match current {
+        Some(current) => current.mul_f64(1.0 - EWMA_ALPHA) + sample.mul_f64(EWMA_ALPHA),
+        None => sample,
+    }
+}
+
+/// Why the home relay was chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum HomeRelayReason {
+    /// Netcheck reported it as the preferred relay.
+    #[display("netcheck")]
+    Netcheck,
+    /// Netcheck did not find a preferred relay, for instance because UDP is blocked, so
+    /// the previous home relay was kept or an arbitrary one picked.
+    #[display("fallback")]
+    Fallback,
+    /// The latency measured on the relay connections showed it to be significantly faster
+    /// than the previous home relay.
+    #[display("latency")]
+    Latency,
+}
+
+/// The inputs of the decision for the current home relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HomeRelayDecision {
+    /// The chosen home relay.
+    pub url: RelayUrl,
+    /// Why it was chosen.
+    pub reason: HomeRelayReason,
+    /// The latency estimates for each relay at the time of the decision.
+    pub latencies: BTreeMap<RelayUrl, RelayLatency>,
+    /// When the decision was made.
+    pub at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_latency_ewma() {
+        let map = RelayLatencyMap::default();
+        let url: RelayUrl = "https://relay.example".parse().unwrap();
+
+        map.add_stun_sample(&url, Duration::from_millis(100));
+        assert_eq!(
+            map.latencies()[&url],
+            RelayLatency {
+                stun: Some(Duration::from_millis(100)),
+                rtt: None,
+            }
+        );
+
+        map.add_stun_sample(&url, Duration::from_millis(200));
+        let latency = map.latencies()[&url].stun.unwrap().as_secs_f64();
+        assert!((latency - 0.130).abs() < 1e-6, "got {latency}");
+
+        // The round trip times are averaged on their own.
+        map.add_rtt_sample(&url, Duration::from_millis(400));
+        let latency = map.latencies()[&url];
+        assert_eq!(latency.rtt, Some(Duration::from_millis(400)));
+        assert!((latency.stun.unwrap().as_secs_f64() - 0.130).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_relay_latency_better_than() {
+        let map = RelayLatencyMap::default();
+        let home: RelayUrl = "https://home.example".parse().unwrap();
+        let other: RelayUrl = "https://other.example".parse().unwrap();
+
+        assert_eq!(map.better_than(&home), None);
+        map.add_rtt_sample(&other, Duration::from_millis(50));
+        assert_eq!(map.better_than(&home), None, "no samples for home");
+
+        map.add_rtt_sample(&home, Duration::from_millis(60));
+        assert_eq!(map.better_than(&home), None, "not significantly faster");
+
+        // STUN latencies don't count.
+        map.add_stun_sample(&other, Duration::from_millis(1));
+        assert_eq!(
+            map.better_than(&home),
+            None,
+            "only the STUN latency is faster"
+        );
+
+        map.add_rtt_sample(&home, Duration::from_millis(500));
+        assert_eq!(map.better_than(&home), Some(other));
+    }
+}
@@ -0,0 +1,104 @@
+//! Sealing the QUIC packets sent through relay servers.
+//!
+//! QUIC encrypts its payloads, but not the connection IDs and header bits in front of them.
+//! A relay server can use these to correlate the packets of a connection, even across
+//! changes of the relay.  With [`super::Options::seal_relay_packets`] every QUIC packet sent
+//! through a relay is additionally sealed with the disco shared secret of the two nodes, so
+//! the relay only sees random bytes.
+//!
+//! A sealed packet is [`SEALED_MAGIC`], followed by the packet encrypted with
+//! ChaCha20-Poly1305 and its 24-byte nonce.  This adds [`SEAL_OVERHEAD`] bytes to every
+//! packet: 3.7% for a packet of 1200 bytes, the common size of QUIC packets sent through a
+//! relay.  Sealing and opening each cost one ChaCha20-Poly1305 pass over the packet, the
+//! `seal_to` and `open_from` benchmarks measure this for packets of 1200 bytes.  The relay
+//! path is not limited by a UDP MTU, so the overhead does not reduce the size of the QUIC
+//! packets.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::disco_crypto::DiscoCipher;
+
+/// Prefix of a sealed packet.
+///
+/// The first byte has the fixed bit of QUIC v1 cleared, so no QUIC packet starts like this,
+/// and it differs from [`crate::disco::MAGIC`].
+pub(super) const SEALED_MAGIC: [u8; 4] = [0x00, b'i', b's', 0x01];
+
+/// Length of the Poly1305 tag.
+const TAG_LEN: usize = 16;
+
+/// Length of the nonce appended by the version 0 disco suite.
+const NONCE_LEN: usize = 24;
+
+/// Number of bytes sealing adds to a packet.
+pub(super) const SEAL_OVERHEAD: usize = SEALED_MAGIC.len() + TAG_LEN + NONCE_LEN;
+
+/// Whether `packet` was sealed by [`seal`].
+pub(super) fn is_sealed(packet: &[u8]) -> bool {
+    packet.starts_with(&SEALED_MAGIC)
+}
+
+/// Seals `packet` for the node `secret` is shared with.
+pub(super) fn seal(secret: &dyn DiscoCipher, packet: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(packet.len() + SEAL_OVERHEAD);
+    buf.put_slice(&SEALED_MAGIC);
+    let mut sealed = buf.split_off(SEALED_MAGIC.len());
+    sealed.put_slice(packet);
+    secret.seal(&mut sealed);
+    // The halves are contiguous, this does not copy.
+    buf.unsplit(sealed);
+    buf.freeze()
+}
+
+/// Opens a packet sealed by [`seal`].
+///
+/// Returns `None` if the packet is not sealed, or was not sealed with `secret`.
+pub(super) fn open(secret: &dyn DiscoCipher, packet: &[u8]) -> Option<Bytes> {
+    let sealed = packet.strip_prefix(&SEALED_MAGIC)?;
+    let mut buf = BytesMut::from(sealed);
+    secret.open(&mut buf).ok()?;
+    Some(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_seal_open() {
+        let a = SecretKey::generate();
+        let b = SecretKey::generate();
+        let packet = vec![0xc3; 1200];
+
+        let sealed = seal(&a.shared(&b.public()), &packet);
+        assert_eq!(sealed.len(), packet.len() + SEAL_OVERHEAD);
+        assert!(is_sealed(&sealed));
+        // the relay can not see the QUIC header
+        assert!(!sealed.windows(16).any(|w| w == &packet[..16]));
+
+        let opened = open(&b.shared(&a.public()), &sealed).unwrap();
+        assert_eq!(&opened[..], &packet[..]);
+
+        // sealing the same packet again looks different
+        let again = seal(&a.shared(&b.public()), &packet);
+        assert_ne!(sealed, again);
+    }
+
+    #[test]
+    fn test_open_rejects() {
+        let a = SecretKey::generate();
+        let b = SecretKey::generate();
+        let packet = b"\x40quic short header".to_vec();
+        assert!(!is_sealed(&packet));
+        assert!(open(&b.shared(&a.public()), &packet).is_none());
+
+        let mut sealed = seal(&a.shared(&b.public()), &packet).to_vec();
+        let other = SecretKey::generate();
+        assert!(open(&b.shared(&other.public()), &sealed).is_none());
+
+        let last = sealed.len() - NONCE_LEN - 1;
+        sealed[last] ^= 1;
+        assert!(open(&b.shared(&a.public()), &sealed).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Routing virtual IP addresses to nodes.
+//!
+//! A VPN built on top of the [`super::MagicSock`] reads IP packets from a TUN device and has
+//! to decide which node to send each of them to.  Like the allowed IPs of a WireGuard peer,
+//! the [`RouteTable`] assigns prefixes of the virtual network to nodes, and looks up the node
+//! for a destination address by longest prefix match.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+use ipnet::IpNet;
+
+use crate::key::PublicKey;
+
+/// Maps IP prefixes to the nodes they are routed to.
+///
+/// Every prefix is routed to at most one node, a node can have any number of prefixes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteTable {
+    /// The routes by prefix length, with the prefixes truncated to their network address.
+    by_len: BTreeMap<u8, HashMap<IpNet, PublicKey>>,
+}
+
+impl RouteTable {
+    /// Creates an empty routing table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes `prefix` to `node`, returning the node it was routed to before.
+    ///
+    /// Host bits of the prefix are ignored, `10.0.0.1/24` is the same as `10.0.0.0/24`.
+    pub fn insert(&mut self, prefix: IpNet, node: PublicKey) -> Option<PublicKey> {
+        let prefix = prefix.trunc();
+        self.by_len
+            .entry(prefix.prefix_len())
+            .or_default()
+            .insert(prefix, node)
+    }
+
+    /// Removes the route for `prefix`, returning the node it was routed to.
+    pub fn remove(&mut self, prefix: &IpNet) -> Option<PublicKey> {
+        let prefix = prefix.trunc();
+        let routes = self.by_len.get_mut(&prefix.prefix_len())?;
+        let node = routes.remove(&prefix);
+        if routes.is_empty() {
+            self.by_len.remove(&prefix.prefix_len());
+        }
+        node
+    }
+
+    /// Removes all routes to `node`.
+    pub fn remove_node(&mut self, node: &PublicKey) {
+        for routes in self.by_len.values_mut() {
+            routes.retain(|_, n| n != node);
+        }
+        self.by_len.retain(|_, routes| !routes.is_empty());
+    }
+
+    /// Returns the node `ip` is routed to, using the most specific matching prefix.
+    pub fn lookup(&self, ip: IpAddr) -> Option<PublicKey> {
+        self.by_len.iter().rev().find_map(|(len, routes)| {
+            // Fails for IPv6 prefix lengths when looking up an IPv4 address.
+            let network = IpNet::new(ip, *len).ok()?.trunc();
+            routes.get(&network).copied()
+        })
+    }
+
+    /// Whether `node` may use `ip` as its source address.
+    ///
+    /// This is the case if `ip` is routed to `node`.  A VPN should drop packets received
+    /// from a node with any other source address, so nodes can not spoof each other.
+    pub fn allows(&self, node: &PublicKey, ip: IpAddr) -> bool {
+        self.lookup(ip).as_ref() == Some(node)
+    }
+
+    /// Iterates over all routes, most specific prefixes first.
+    pub fn iter(&self) -> impl Iterator<Item = (IpNet, PublicKey)> + '_ {
+        self.by_len
+            .values()
+            .rev()
+            .flat_map(|routes| routes.iter().map(|(prefix, node)| (*prefix, *node)))
+    }
+
+    /// Number of routes.
+    pub fn len(&self) -> usize {
+        self.by_len.values().map(HashMap::len).sum()
+    }
+
+    /// Whether there are no routes.
+    pub fn is_empty(&self) -> bool {
+        self.by_len.is_empty()
+    }
+}
+
+impl FromIterator<(IpNet, PublicKey)> for RouteTable {
+    fn from_iter<T: IntoIterator<Item = (IpNet, PublicKey)>>(iter: T) -> Self {
+        let mut table = Self::new();
+        for (prefix, node) in iter {
+            table.insert(prefix, node);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_lookup() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let c = SecretKey::generate().public();
+        let mut table: RouteTable = [
+            ("10.0.0.0/8".parse().unwrap(), a),
+            ("10.1.0.5/16".parse().unwrap(), b),
+            ("10.1.2.3/32".parse().unwrap(), c),
+            ("fd00::/8".parse().unwrap(), c),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(table.len(), 4);
+
+        assert_eq!(table.lookup("10.2.0.1".parse().unwrap()), Some(a));
+        assert_eq!(table.lookup("10.1.0.1".parse().unwrap()), Some(b));
+        assert_eq!(table.lookup("10.1.2.3".parse().unwrap()), Some(c));
+        assert_eq!(table.lookup("fd12::1".parse().unwrap()), Some(c));
+        assert_eq!(table.lookup("192.168.0.1".parse().unwrap()), None);
+        assert_eq!(table.lookup("fe80::1".parse().unwrap()), None);
+        assert!(table.allows(&b, "10.1.0.1".parse().unwrap()));
+        assert!(!table.allows(&a, "10.1.0.1".parse().unwrap()));
+
+        assert_eq!(table.remove(&"10.1.0.0/16".parse().unwrap()), Some(b));
+        assert_eq!(table.lookup("10.1.0.1".parse().unwrap()), Some(a));
+        table.remove_node(&c);
+        assert_eq!(table.lookup("10.1.2.3".parse().unwrap()), Some(a));
+        assert_eq!(
+            table.iter().collect::<Vec<_>>(),
+            vec![("10.0.0.0/8".parse().unwrap(), a)]
+        );
+    }
+}
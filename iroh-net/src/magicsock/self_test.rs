@@ -0,0 +1,301 @@
+//! Checking that nodes can connect from this host, see [`MagicEndpoint::self_test`].
+//!
+//! The endpoint under test connects to an ephemeral target node in this process, which is
+//! configured with the same relays and DNS resolver.  It connects to one target with only
+//! its direct addresses, and to another with only its relay.  For each path the disco pings
+//! and a QUIC handshake with an echoed stream are checked.
+//! The targets stay in the node map of the endpoint under test until they are pruned as
+//! inactive: removing a node while its connection is still closing would break the QUIC
+//! endpoint.
+
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use tracing::debug;
+
+use super::{MagicSock, RelayReachability};
+use crate::{relay::RelayMode, MagicEndpoint, NodeAddr, NodeId};
+
+/// The ALPN spoken between the nodes of the self-test.
+const SELF_TEST_ALPN: &[u8] = b"n0/iroh-net/self-test/0";
+
+/// How long each check may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the probe's view of the target is polled while waiting for disco.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The payload echoed over the QUIC connection.
+const ECHO_PAYLOAD: &[u8] = b"iroh-net self-test";
+
+/// Outcome of a single check of a [`SelfTestReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// The check passed after the given time.
+    Passed(Duration),
+    /// The check failed, for the given reason.
+    Failed(String),
+    /// The check was not run, for the given reason.
+    Skipped(&'static str),
+}
+
+impl CheckOutcome {
+    /// Whether the check did not fail.
+    pub fn is_ok(&self) -> bool {
+        !matches!(self, Self::Failed(_))
+    }
+}
+
+impl fmt::Display for CheckOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Passed(took) => write!(f, "passed in {took:?}"),
+            Self::Failed(reason) => write!(f, "failed: {reason}"),
+            Self::Skipped(reason) => write!(f, "skipped: {reason}"),
+        }
+    }
+}
+
+/// The checks of a single path of a [`SelfTestReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathReport {
+    /// A disco ping over the path was answered, with the time until the pong.
+    pub disco: CheckOutcome,
+    /// A QUIC handshake over the path completed and a stream was echoed.
+    pub quic: CheckOutcome,
+}
+
+impl PathReport {
+    fn skipped(reason: &'static str) -> Self {
+        Self {
+            disco: CheckOutcome::Skipped(reason),
+            quic: CheckOutcome::Skipped(reason),
+        }
+    }
+
+    /// Both checks failed, as the path could not be set up.
+    fn failed(err: anyhow::Error) -> Self {
+        let reason = format!("{err:#}");
+        Self {
+            disco: CheckOutcome::Failed(reason.clone()),
+            quic: CheckOutcome::Failed(reason),
+        }
+    }
+
+    /// Whether no check of the path failed.
+    pub fn is_ok(&self) -> bool {
+        self.disco.is_ok() && self.quic.is_ok()
+    }
+}
+
+/// Result of [`MagicEndpoint::self_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// Connecting over direct UDP addresses.
+    pub direct: PathReport,
+    /// Connecting over the home relay, skipped if no relays are configured.
+    pub relay: PathReport,
+}
+
+impl SelfTestReport {
+    /// Whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.direct.is_ok() && self.relay.is_ok()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "direct disco: {}", self.direct.disco)?;
+        writeln!(f, "direct quic:  {}", self.direct.quic)?;
+        writeln!(f, "relay disco:  {}", self.relay.disco)?;
+        write!(f, "relay quic:   {}", self.relay.quic)
+    }
+}
+
+/// Runs the self-test from `endpoint`.
+pub(crate) async fn run(endpoint: &MagicEndpoint) -> SelfTestReport {
+    let direct = check_path(endpoint, Path::Direct).await;
+    let relay = if endpoint.magic_sock().inner.relay_map.is_empty() {
+        PathReport::skipped("no relays configured")
+    } else {
+        check_path(endpoint, Path::Relay).await
+    };
+    let report = SelfTestReport { direct, relay };
+    debug!(passed = report.passed(), "self-test done:\n{report}");
+    report
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Path {
+    Direct,
+    Relay,
+}
+
+/// Binds an ephemeral target node configured like `msock`.
+async fn bind(msock: &MagicSock) -> Result<MagicEndpoint> {
+    let inner = &msock.inner;
+    let relay_mode = match inner.relay_map.is_empty() {
+        true => RelayMode::Disabled,
+        false => RelayMode::Custom(inner.relay_map.clone()),
+    };
+    let builder = MagicEndpoint::builder()
+        .alpns(vec![SELF_TEST_ALPN.to_vec()])
+        .relay_mode(relay_mode)
+        .dns_resolver(inner.dns_resolver.clone());
+    #[cfg(any(test, feature = "test-utils"))]
+    let builder = builder.insecure_skip_relay_cert_verify(inner.insecure_skip_relay_cert_verify);
+    builder.bind(0).await
+}
+
+/// Checks one path from `endpoint` to a fresh target node.
+async fn check_path(endpoint: &MagicEndpoint, path: Path) -> PathReport {
+    let target = match bind(endpoint.magic_sock()).await {
+        Ok(target) => target,
+        Err(err) => return PathReport::failed(err.context("failed to bind")),
+    };
+    let accept = tokio::task::spawn(echo(target.clone()));
+    let report = check_target(endpoint, &target, path).await;
+    accept.abort();
+    target.close(0u32.into(), b"self-test done").await.ok();
+    report
+}
+
+async fn check_target(endpoint: &MagicEndpoint, target: &MagicEndpoint, path: Path) -> PathReport {
+    let addr = match within(target_addr(target, path)).await {
+        Ok(addr) => addr,
+        Err(err) => return PathReport::failed(err),
+    };
+
+    let start = Instant::now();
+    let quic = match within(connect_and_echo(endpoint, addr.clone())).await {
+        Ok(()) => CheckOutcome::Passed(start.elapsed()),
+        Err(err) => CheckOutcome::Failed(format!("{err:#}")),
+    };
+    let disco = match within(disco_confirmed(endpoint.magic_sock(), addr.node_id, path)).await {
+        Ok(latency) => CheckOutcome::Passed(latency),
+        Err(err) => CheckOutcome::Failed(format!("{err:#}")),
+    };
+    PathReport { disco, quic }
+}
+
+/// The address of `target` with only the addresses of `path`.
+async fn target_addr(target: &MagicEndpoint, path: Path) -> Result<NodeAddr> {
+    match path {
+        Path::Direct => {
+            let addr = target.my_addr().await?;
+            ensure!(
+                !addr.info.direct_addresses.is_empty(),
+                "no direct addresses"
+            );
+            Ok(NodeAddr::new(addr.node_id).with_direct_addresses(addr.info.direct_addresses))
+        }
+        Path::Relay => loop {
+            if let Some(relay) = target.my_relay() {
+                return Ok(NodeAddr::new(target.node_id()).with_relay_url(relay));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        },
+    }
+}
+
+async fn connect_and_echo(endpoint: &MagicEndpoint, addr: NodeAddr) -> Result<()> {
+    let conn = endpoint
+        .connect(addr, SELF_TEST_ALPN)
+        .await
+        .context("QUIC handshake failed")?;
+    let (mut send, mut recv) = conn.open_bi().await.context("failed to open stream")?;
+    send.write_all(ECHO_PAYLOAD).await?;
+    send.finish().await?;
+    let echoed = recv.read_to_end(ECHO_PAYLOAD.len()).await?;
+    ensure!(echoed == ECHO_PAYLOAD, "stream was not echoed");
+    conn.close(0u32.into(), b"self-test done");
+    Ok(())
+}
+
+/// Waits for `msock` to have a disco pong from `node` over `path`.
+///
+/// Returns the latency measured by the ping.
+async fn disco_confirmed(msock: &MagicSock, node: NodeId, path: Path) -> Result<Duration> {
+    loop {
+        if let Some(info) = msock.tracked_endpoint(node) {
+            match path {
+                Path::Direct => {
+                    if let Some(latency) = info.addrs.iter().filter_map(|a| a.latency).min() {
+                        return Ok(latency);
+                    }
+                }
+                Path::Relay => {
+                    if info.relay_reachability == RelayReachability::Reachable {
+                        return Ok(info.latency.unwrap_or_default());
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Echoes the streams of the connections to `target`.
+async fn echo(target: MagicEndpoint) {
+    while let Some(connecting) = target.accept().await {
+        tokio::task::spawn(async move {
+            let conn = connecting.await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(ECHO_PAYLOAD.len()).await?;
+            send.write_all(&data).await?;
+            send.finish().await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+    }
+}
+
+async fn within<T>(fut: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(CHECK_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow!("timed out after {CHECK_TIMEOUT:?}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::run_relay_server;
+
+    #[tokio::test]
+    async fn test_self_test_direct() {
+        let _guard = iroh_test::logging::setup();
+        let ep = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind(0)
+            .await
+            .unwrap();
+        let report = ep.self_test().await;
+        assert!(report.passed(), "{report}");
+        assert!(matches!(report.direct.quic, CheckOutcome::Passed(_)));
+        assert!(matches!(report.direct.disco, CheckOutcome::Passed(_)));
+        assert!(matches!(report.relay.quic, CheckOutcome::Skipped(_)));
+        ep.close(0u32.into(), b"done").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_self_test_relay() {
+        let _guard = iroh_test::logging::setup();
+        let (relay_map, _relay_url, _cleanup) = run_relay_server().await.unwrap();
+        let ep = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind(0)
+            .await
+            .unwrap();
+        let report = ep.self_test().await;
+        assert!(report.passed(), "{report}");
+        assert!(matches!(report.relay.quic, CheckOutcome::Passed(_)));
+        assert!(matches!(report.relay.disco, CheckOutcome::Passed(_)));
+        ep.close(0u32.into(), b"done").await.unwrap();
+    }
+}
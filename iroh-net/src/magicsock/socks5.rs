@@ -0,0 +1,474 @@
+//! Sending the direct UDP path through a SOCKS5 proxy.
+//!
+//! Some networks block UDP egress but provide a SOCKS5 proxy supporting the `UDP ASSOCIATE`
+//! command, see [RFC 1928].  The proxy relays datagrams which are wrapped in a small header
+//! carrying the real destination, or source on the way back.
+//!
+//! The connections to relay servers go through the proxy as well, using the `CONNECT`
+//! command, so that no traffic reveals our real address.
+//!
+//! [RFC 1928]: https://datatracker.ietf.org/doc/html/rfc1928
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use super::udp_relay::Relay;
+
+const VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+/// How long setting up the association with the proxy may take.
+const ASSOCIATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A SOCKS5 proxy to send the direct UDP path through.
+#[derive(derive_more::Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Config {
+    /// The address of the proxy's TCP control port.
+    pub proxy: SocketAddr,
+    /// Username and password to authenticate with, if the proxy requires them.
+    #[debug("{}", auth.as_ref().map(|(user, _)| user.as_str()).unwrap_or("None"))]
+    pub auth: Option<(String, String)>,
+}
+
+impl Socks5Config {
+    /// Creates the config for a proxy which does not require authentication.
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self { proxy, auth: None }
+    }
+
+    /// Authenticates with `username` and `password`.
+    pub fn with_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some((username.into(), password.into()));
+        self
+    }
+}
+
+/// A UDP association with a SOCKS5 proxy.
+///
+/// The association lives as long as the TCP control connection to the proxy, which is kept
+/// open by a task until this is dropped.
+#[derive(Debug)]
+pub(super) struct Association {
+    /// The address to send wrapped datagrams to, and receive them from.
+    relay_addr: SocketAddr,
+    /// Set when the proxy closed the control connection, ending the association.
+    closed: Arc<AtomicBool>,
+    cancel: CancellationToken,
+}
+
+impl Association {
+    /// Sets up a UDP association with the proxy in `config`.
+    ///
+    /// Fails if the proxy can not be reached or does not support `UDP ASSOCIATE`.
+    pub(super) async fn connect(config: &Socks5Config) -> Result<Self> {
+        let (stream, relay_addr) = time::timeout(ASSOCIATE_TIMEOUT, async {
+            let mut stream = TcpStream::connect(config.proxy)
+                .await
+                .context("connect to proxy")?;
+            let relay_addr = associate(&mut stream, config).await?;
+            anyhow::Ok((stream, relay_addr))
+        })
+        .await
+        .context("timeout")??;
+        // Proxies commonly reply with the unspecified address, meaning the proxy's own.
+        let relay_addr = if relay_addr.ip().is_unspecified() {
+            SocketAddr::new(config.proxy.ip(), relay_addr.port())
+        } else {
+            relay_addr
+        };
+        debug!(proxy = %config.proxy, %relay_addr, "SOCKS5 UDP association established");
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let cancel = CancellationToken::new();
+        tokio::task::spawn(hold_control_connection(
+            stream,
+            closed.clone(),
+            cancel.clone(),
+        ));
+        Ok(Self {
+            relay_addr,
+            closed,
+            cancel,
+        })
+    }
+
+    /// The address of the proxy's UDP relay.
+    pub(super) fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// Whether the proxy still relays datagrams for us.
+    pub(super) fn is_alive(&self) -> bool {
+        !self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Relay for Association {
+    fn server(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    fn is_alive(&self) -> bool {
+        Association::is_alive(self)
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, dst: SocketAddr, payload: &[u8]) -> bool {
+        encode_datagram(buf, dst, payload);
+        true
+    }
+
+    fn decode(&self, datagram: &[u8]) -> io::Result<Option<(SocketAddr, Range<usize>)>> {
+        let (src, offset) = decode_datagram(datagram)?;
+        Ok(Some((src, offset..datagram.len())))
+    }
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+async fn hold_control_connection(
+    mut stream: TcpStream,
+    closed: Arc<AtomicBool>,
+    cancel: CancellationToken,
+) {
+    // The proxy sends nothing more, reading only detects the connection closing.
+    let mut buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            res = stream.read(&mut buf) => match res {
+                Ok(0) => {
+                    warn!("SOCKS5 proxy closed the control connection");
+                    break;
+                }
+                Ok(_) => continue,
+                Err(err) => {
+                    warn!("SOCKS5 control connection failed: {err:?}");
+                    break;
+                }
+            }
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+}
+
+/// Where a TCP connection through the proxy goes, see [`connect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Destination {
+    Addr(SocketAddr),
+    /// A hostname, resolved by the proxy.
+    Domain(String, u16),
+}
+
+/// Opens a TCP connection to `dst` through the proxy in `config`.
+pub(crate) async fn connect(config: &Socks5Config, dst: Destination) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(config.proxy)
+        .await
+        .context("connect to proxy")?;
+    authenticate(&mut stream, config).await?;
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+    match dst {
+        Destination::Addr(addr) => write_addr(&mut request, addr),
+        Destination::Domain(ref domain, port) => {
+            ensure!(domain.len() <= 255, "hostname too long");
+            request.extend_from_slice(&[ATYP_DOMAIN, domain.len() as u8]);
+            request.extend_from_slice(domain.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(&request).await?;
+    read_reply(&mut stream, "CONNECT").await?;
+    Ok(stream)
+}
+
+/// Runs the SOCKS5 handshake on `stream`, returning the proxy's UDP relay address.
+async fn associate<S>(stream: &mut S, config: &Socks5Config) -> Result<SocketAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    authenticate(stream, config).await?;
+
+    // We do not know from which address our datagrams reach the proxy, leave it open.
+    let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0x00];
+    write_addr(
+        &mut request,
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+    );
+    stream.write_all(&request).await?;
+    read_reply(stream, "UDP ASSOCIATE")
+        .await?
+        .context("unsupported relay address type")
+}
+
+/// Negotiates the authentication method and authenticates.
+async fn authenticate<S>(stream: &mut S, config: &Socks5Config) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods: &[u8] = match config.auth {
+        Some(_) => &[AUTH_NONE, AUTH_USERNAME_PASSWORD],
+        None => &[AUTH_NONE],
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[0] == VERSION, "unexpected SOCKS version {}", reply[0]);
+    match (reply[1], &config.auth) {
+        (AUTH_NONE, _) => Ok(()),
+        (AUTH_USERNAME_PASSWORD, Some((username, password))) => {
+            ensure!(
+                username.len() <= 255 && password.len() <= 255,
+                "username or password too long"
+            );
+            let mut msg = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+            msg.extend_from_slice(username.as_bytes());
+            msg.push(password.len() as u8);
+            msg.extend_from_slice(password.as_bytes());
+            stream.write_all(&msg).await?;
+            stream.read_exact(&mut reply).await?;
+            ensure!(reply[1] == 0, "proxy authentication failed");
+            Ok(())
+        }
+        (AUTH_NO_ACCEPTABLE, _) => bail!("proxy accepts none of our authentication methods"),
+        (method, _) => bail!("proxy chose unsupported authentication method {method}"),
+    }
+}
+
+/// Reads the reply to a `command` request, returning the address bound by the proxy.
+///
+/// The address is `None` if the proxy replied with a hostname.
+async fn read_reply<S>(stream: &mut S, command: &str) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[0] == VERSION, "unexpected SOCKS version {}", reply[0]);
+    ensure!(
+        reply[1] == REPLY_SUCCEEDED,
+        "proxy refused {command}: {}",
+        reply_message(reply[1])
+    );
+    let ip: Option<IpAddr> = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Some(Ipv4Addr::from(octets).into())
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Some(Ipv6Addr::from(octets).into())
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            None
+        }
+        atyp => bail!("unsupported bound address type {atyp}"),
+    };
+    let port = stream.read_u16().await?;
+    Ok(ip.map(|ip| SocketAddr::new(ip, port)))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Wraps `payload` for sending to `dst` through the proxy's UDP relay.
+fn encode_datagram(buf: &mut Vec<u8>, dst: SocketAddr, payload: &[u8]) {
+    buf.clear();
+    // Reserved and fragment number, we never fragment.
+    buf.extend_from_slice(&[0, 0, 0]);
+    write_addr(buf, dst);
+    buf.extend_from_slice(payload);
+}
+
+/// Unwraps a datagram received from the proxy's UDP relay.
+///
+/// Returns the original source address and the offset of the payload.
+fn decode_datagram(datagram: &[u8]) -> io::Result<(SocketAddr, usize)> {
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+    if datagram.len() < 4 || datagram[..2] != [0, 0] {
+        return Err(invalid("invalid SOCKS5 UDP header"));
+    }
+    if datagram[2] != 0 {
+        return Err(invalid("fragmented SOCKS5 datagrams are not supported"));
+    }
+    let rest = &datagram[4..];
+    let (ip, len): (IpAddr, usize) = match datagram[3] {
+        ATYP_IPV4 if rest.len() >= 6 => {
+            let octets: [u8; 4] = rest[..4].try_into().expect("checked length");
+            (Ipv4Addr::from(octets).into(), 4)
+        }
+        ATYP_IPV6 if rest.len() >= 18 => {
+            let octets: [u8; 16] = rest[..16].try_into().expect("checked length");
+            (Ipv6Addr::from(octets).into(), 16)
+        }
+        ATYP_DOMAIN => return Err(invalid("domain sources are not supported")),
+        _ => return Err(invalid("invalid SOCKS5 UDP source address")),
+    };
+    let port = u16::from_be_bytes([rest[len], rest[len + 1]]);
+    Ok((SocketAddr::new(ip, port), 4 + len + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_datagram_roundtrip() {
+        let mut buf = Vec::new();
+        for addr in ["1.2.3.4:5678", "[2001:db8::1]:443"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            encode_datagram(&mut buf, addr, b"hello");
+            let (src, offset) = decode_datagram(&buf).unwrap();
+            assert_eq!(src, addr);
+            assert_eq!(&buf[offset..], b"hello");
+        }
+
+        // Fragments are rejected.
+        let addr: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        encode_datagram(&mut buf, addr, b"hello");
+        buf[2] = 1;
+        assert!(decode_datagram(&buf).is_err());
+        assert!(decode_datagram(&[0, 0, 0, ATYP_IPV4, 1, 2]).is_err());
+    }
+
+    /// Serves a single SOCKS5 handshake, replying to the UDP ASSOCIATE with `reply`.
+    async fn mock_proxy(reply: u8, relay_addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await?;
+            assert_eq!(greeting, [VERSION, 1, AUTH_NONE]);
+            stream.write_all(&[VERSION, AUTH_NONE]).await?;
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await?;
+            assert_eq!(request[..4], [VERSION, CMD_UDP_ASSOCIATE, 0, ATYP_IPV4]);
+            let mut msg = vec![VERSION, reply, 0];
+            write_addr(&mut msg, relay_addr);
+            stream.write_all(&msg).await?;
+            // Keep the control connection open until the client closes it.
+            let mut buf = [0u8; 1];
+            let _ = stream.read(&mut buf).await;
+            anyhow::Ok(())
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn test_associate() -> Result<()> {
+        let relay: SocketAddr = "0.0.0.0:4321".parse().unwrap();
+        let proxy = mock_proxy(REPLY_SUCCEEDED, relay).await?;
+        let assoc = Association::connect(&Socks5Config::new(proxy)).await?;
+        // The unspecified address is replaced with the proxy's.
+        assert_eq!(assoc.relay_addr(), "127.0.0.1:4321".parse().unwrap());
+        assert!(assoc.is_alive());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_associate_unsupported() -> Result<()> {
+        let relay: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let proxy = mock_proxy(0x07, relay).await?;
+        let err = Association::connect(&Socks5Config::new(proxy))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("command not supported"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_domain() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = listener.local_addr()?;
+        let server = tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await?;
+            stream.write_all(&[VERSION, AUTH_NONE]).await?;
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).await?;
+            assert_eq!(request[..4], [VERSION, CMD_CONNECT, 0, ATYP_DOMAIN]);
+            let mut domain = vec![0u8; request[4] as usize];
+            stream.read_exact(&mut domain).await?;
+            let port = stream.read_u16().await?;
+            let mut msg = vec![VERSION, REPLY_SUCCEEDED, 0];
+            write_addr(&mut msg, "10.0.0.1:1080".parse().unwrap());
+            stream.write_all(&msg).await?;
+            stream.write_all(b"hello").await?;
+            anyhow::Ok((String::from_utf8(domain)?, port))
+        });
+
+        let dst = Destination::Domain("relay.example".into(), 443);
+        let mut stream = connect(&Socks5Config::new(proxy), dst).await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        assert_eq!(server.await??, ("relay.example".to_string(), 443));
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! Snapshots of the connectivity state for bug reports, see [`super::MagicSock::dump_state`].
+//!
+//! A [`StateDump`] collects what is needed to understand why nodes do or do not connect: our
+//! endpoints, the relay status, the last netcheck report, the state of every known node and
+//! the recent events of the socket.  It serializes with serde, and
+//! [`StateDump::write_to_file`] stores it as zstd-compressed JSON to attach to an issue.
+//!
+//! A redacted dump replaces IP addresses by their kind, e.g. `private-v4:4433`, and node ids
+//! by their index in the node map, so it can be shared publicly.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{events::MagicSockEvent, ConnectionType, EndpointInfo};
+use crate::{config, key::PublicKey, netcheck};
+
+/// Number of events kept for dumps.
+const EVENT_LOG_CAPACITY: usize = 128;
+
+/// Compression level of dumps written to files.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The connectivity state of a [`super::MagicSock`], see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateDump {
+    /// When the dump was taken, in milliseconds since the unix epoch.
+    pub taken_at_unix_ms: u64,
+    /// Whether addresses and node ids are redacted.
+    pub redacted: bool,
+    /// Our node id, `None` if redacted.
+    pub node_id: Option<String>,
+    /// Whether the socket is online, see [`super::MagicSock::set_offline`].
+    pub online: bool,
+    /// Whether the network is metered, see [`super::MagicSock::set_metered`].
+    pub metered: bool,
+    /// The addresses of the local UDP sockets.
+    pub local_addrs: Vec<String>,
+    /// The endpoints we advertise.
+    pub endpoints: Vec<EndpointDump>,
+    /// The status of the relays.
+    pub relay: RelayDump,
+    /// The last netcheck report, if any netcheck finished.
+    pub netcheck: Option<NetcheckDump>,
+    /// The known nodes.
+    pub nodes: Vec<NodeDump>,
+    /// The recent events, oldest first.
+    pub events: Vec<RecordedEvent>,
+}
+
+/// One of our endpoints in a [`StateDump`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndpointDump {
+    /// The address of the endpoint.
+    pub addr: String,
+    /// How the endpoint was discovered.
+    pub typ: String,
+}
+
+/// The relay status in a [`StateDump`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelayDump {
+    /// The home relay.
+    pub home: Option<String>,
+    /// Why the home relay was chosen.
+    pub reason: Option<String>,
+    /// The moving average of the STUN latency to each relay.
+    pub latencies_ms: BTreeMap<String, f64>,
+    /// The moving average of the round trip time on the connection to each relay.
+    pub rtts_ms: BTreeMap<String, f64>,
+}
+
+/// The last netcheck report in a [`StateDump`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetcheckDump {
+    /// A UDP STUN round trip completed.
+    pub udp: bool,
+    /// An IPv4 STUN round trip completed.
+    pub ipv4: bool,
+    /// An IPv6 STUN round trip completed.
+    pub ipv6: bool,
+    /// An IPv4 packet could be sent.
+    pub ipv4_can_send: bool,
+    /// An IPv6 packet could be sent.
+    pub ipv6_can_send: bool,
+    /// Whether the STUN results depend on the STUN server, i.e. the NAT is symmetric.
+    pub mapping_varies_by_dest_ip: Option<bool>,
+    /// Whether we are behind a carrier-grade NAT.
+    pub cgnat: Option<bool>,
+    /// Whether the router supports hair pinning.
+    pub hair_pinning: Option<bool>,
+    /// Whether a captive portal intercepts HTTP.
+    pub captive_portal: Option<bool>,
+    /// The relay with the lowest latency.
+    pub preferred_relay: Option<String>,
+    /// The latency to each relay.
+    pub relay_latencies_ms: BTreeMap<String, f64>,
+    /// Our global IPv4 address.
+    pub global_v4: Option<String>,
+    /// Our global IPv6 address.
+    pub global_v6: Option<String>,
+}
+
+/// A known node in a [`StateDump`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeDump {
+    /// The node id, or its index in the node map if redacted.
+    pub node: String,
+    /// The node's relay.
+    pub relay_url: Option<String>,
+    /// How the node is reached.
+    pub conn_type: String,
+    /// The path the node is reached on, if any.
+    pub path: Option<String>,
+    /// The latency of the path.
+    pub latency_ms: Option<f64>,
+    /// Time since the node was last used.
+    pub last_used_ms: Option<f64>,
+    /// Whether the node can be reached over its relay.
+    pub relay_reachability: String,
+    /// Why no direct path was verified, if so.
+    pub no_direct_path: Option<String>,
+    /// The node's direct addresses.
+    pub addrs: Vec<NodeAddrDump>,
+}
+
+/// A direct address of a node in a [`NodeDump`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeAddrDump {
+    /// The address.
+    pub addr: String,
+    /// The latency to the address, if a ping was answered.
+    pub latency_ms: Option<f64>,
+    /// The last control message received from the address, and how long ago.
+    pub last_control: Option<(f64, String)>,
+    /// Time since the last payload was received from the address.
+    pub last_payload_ms: Option<f64>,
+}
+
+/// An event of the socket, kept for [`StateDump`]s.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// When the event happened, in milliseconds since the unix epoch.
+    pub at_unix_ms: u64,
+    /// What happened, with addresses and node ids redacted like the rest of the dump.
+    pub event: String,
+}
+
+impl StateDump {
+    /// Writes the dump to `path` as zstd-compressed JSON.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("failed to create {path:?}"))?;
+        let mut encoder = zstd::Encoder::new(BufWriter::new(file), ZSTD_LEVEL)?;
+        serde_json::to_writer(&mut encoder, self)?;
+        encoder.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Reads a dump written with [`Self::write_to_file`].
+    pub fn read_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+        let decoder = zstd::Decoder::new(file)?;
+        Ok(serde_json::from_reader(decoder)?)
+    }
+}
+
+/// The recent events of the socket, recorded by [`super::events::EventWatchers`].
+#[derive(Debug, Default)]
+pub(super) struct EventLog {
+    events: parking_lot::Mutex<VecDeque<(u64, MagicSockEvent)>>,
+}
+
+impl EventLog {
+    /// Records an event, forgetting the oldest one if the log is full.
+    pub(super) fn record(&self, event: MagicSockEvent) {
+        let at_unix_ms = unix_ms(SystemTime::now());
+        let mut events = self.events.lock();
+        if events.len() == EVENT_LOG_CAPACITY {
+            events.pop_front();
+        }
+        events.push_back((at_unix_ms, event));
+    }
+
+    pub(super) fn snapshot(&self, redactor: Redactor) -> Vec<RecordedEvent> {
+        self.events
+            .lock()
+            .iter()
+            .map(|(at_unix_ms, event)| RecordedEvent {
+                at_unix_ms: *at_unix_ms,
+                event: redactor.event(event),
+            })
+            .collect()
+    }
+}
+
+/// Formats the parts of a dump, redacting them if asked to.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Redactor {
+    pub(super) redact: bool,
+}
+
+impl Redactor {
+    pub(super) fn addr(&self, addr: SocketAddr) -> String {
+        match self.redact {
+            true => format!("{}:{}", ip_kind(addr.ip()), addr.port()),
+            false => addr.to_string(),
+        }
+    }
+
+    fn node_id(&self, node_id: &PublicKey) -> String {
+        match self.redact {
+            true => "node".to_string(),
+            false => format!("node {}", node_id.fmt_short()),
+        }
+    }
+
+    pub(super) fn event(&self, event: &MagicSockEvent) -> String {
+        match event {
+            MagicSockEvent::NodeAdded(node_id) => format!("{} added", self.node_id(node_id)),
+            MagicSockEvent::NodeRemoved(node_id) => format!("{} removed", self.node_id(node_id)),
+            MagicSockEvent::NodeOffline(node_id) => {
+                format!("{} said goodbye", self.node_id(node_id))
+            }
+            MagicSockEvent::PathUpgraded { node_id, addr } => {
+                format!(
+                    "{} is direct via {}",
+                    self.node_id(node_id),
+                    self.addr(*addr)
+                )
+            }
+            MagicSockEvent::HomeRelayChanged(Some(url)) => format!("home relay is now {url}"),
+            MagicSockEvent::HomeRelayChanged(None) => "no home relay".to_string(),
+            MagicSockEvent::EndpointUpdated(update) => format!(
+                "endpoint update ({}) done in {:?}, {} endpoints",
+                update.reason,
+                update.duration,
+                update.endpoints.len()
+            ),
+            MagicSockEvent::Presence(presence) => format!(
+                "{} is {}",
+                self.node_id(&presence.node),
+                if presence.online { "online" } else { "offline" }
+            ),
+            MagicSockEvent::NetworkChanged { major: true } => "major network change".to_string(),
+            MagicSockEvent::NetworkChanged { major: false } => "minor network change".to_string(),
+            MagicSockEvent::Resumed { slept } => format!("resumed after about {slept:?}"),
+            MagicSockEvent::Offline(true) => "went offline".to_string(),
+            MagicSockEvent::Offline(false) => "went online".to_string(),
+        }
+    }
+
+    pub(super) fn endpoint(&self, ep: &config::Endpoint) -> EndpointDump {
+        EndpointDump {
+            addr: self.addr(ep.addr),
+            typ: ep.typ.to_string(),
+        }
+    }
+
+    pub(super) fn node(&self, info: EndpointInfo) -> NodeDump {
+        let (path, relay) = match &info.conn_type {
+            ConnectionType::Direct(addr) => (Some(self.addr(*addr)), None),
+            ConnectionType::Relay(url) => (None, Some(url.to_string())),
+            ConnectionType::Mixed(addr, url) => (Some(self.addr(*addr)), Some(url.to_string())),
+            ConnectionType::None => (None, None),
+        };
+        NodeDump {
+            node: match self.redact {
+                true => format!("node-{}", info.id),
+                false => info.node_id.to_string(),
+            },
+            relay_url: info.relay_url.map(|url| url.to_string()),
+            conn_type: info.conn_type.to_string(),
+            path: path.or(relay),
+            latency_ms: info.latency.map(ms),
+            last_used_ms: info.last_used.map(ms),
+            relay_reachability: info.relay_reachability.to_string(),
+            no_direct_path: info.no_direct_path.map(|reason| reason.to_string()),
+            addrs: info
+                .addrs
+                .into_iter()
+                .map(|addr| NodeAddrDump {
+                    addr: self.addr(addr.addr),
+                    latency_ms: addr.latency.map(ms),
+                    last_control: addr
+                        .last_control
+                        .map(|(ago, msg)| (ms(ago), msg.to_string())),
+                    last_payload_ms: addr.last_payload.map(ms),
+                })
+                .collect(),
+        }
+    }
+
+    pub(super) fn netcheck(&self, report: &netcheck::Report) -> NetcheckDump {
+        NetcheckDump {
+            udp: report.udp,
+            ipv4: report.ipv4,
+            ipv6: report.ipv6,
+            ipv4_can_send: report.ipv4_can_send,
+            ipv6_can_send: report.ipv6_can_send,
+            mapping_varies_by_dest_ip: report.mapping_varies_by_dest_ip,
+            cgnat: report.cgnat,
+            hair_pinning: report.hair_pinning,
+            captive_portal: report.captive_portal,
+            preferred_relay: report.preferred_relay.as_ref().map(|url| url.to_string()),
+            relay_latencies_ms: report
+                .relay_latency
+                .iter()
+                .map(|(url, latency)| (url.to_string(), ms(latency)))
+                .collect(),
+            global_v4: report.global_v4.map(|addr| self.addr(addr.into())),
+            global_v6: report.global_v6.map(|addr| self.addr(addr.into())),
+        }
+    }
+}
+
+/// The kind of `ip`, which is all a redacted dump shows of it.
+fn ip_kind(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            if ip.is_loopback() {
+                "loopback-v4"
+            } else if ip.is_private() {
+                "private-v4"
+            } else if ip.is_link_local() {
+                "link-local-v4"
+            } else if a == 100 && (b & 0xc0) == 64 {
+                "cgnat-v4"
+            } else if ip.is_unspecified() {
+                "unspecified-v4"
+            } else {
+                "global-v4"
+            }
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            if ip.is_loopback() {
+                "loopback-v6"
+            } else if (first & 0xfe00) == 0xfc00 {
+                "unique-local-v6"
+            } else if (first & 0xffc0) == 0xfe80 {
+                "link-local-v6"
+            } else if ip.is_unspecified() {
+                "unspecified-v6"
+            } else if let Some(ip) = ip.to_ipv4_mapped() {
+                ip_kind(ip.into())
+            } else {
+                "global-v6"
+            }
+        }
+    }
+}
+
+pub(super) fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub(super) fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_addr() {
+        let plain = Redactor { redact: false };
+        let redacted = Redactor { redact: true };
+        for (addr, kind) in [
+            ("127.0.0.1:1", "loopback-v4:1"),
+            ("192.168.1.7:4433", "private-v4:4433"),
+            ("100.100.3.4:2", "cgnat-v4:2"),
+            ("1.2.3.4:5", "global-v4:5"),
+            ("[fd00::1]:6", "unique-local-v6:6"),
+            ("[fe80::1]:7", "link-local-v6:7"),
+            ("[::ffff:10.0.0.1]:8", "private-v4:8"),
+            ("[2001:db8::1]:9", "global-v6:9"),
+        ] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(plain.addr(addr), addr.to_string());
+            assert_eq!(redacted.addr(addr), kind);
+        }
+    }
+
+    #[test]
+    fn test_event_log_capacity() {
+        let log = EventLog::default();
+        log.record(MagicSockEvent::Offline(true));
+        log.record(MagicSockEvent::Offline(true));
+        for _ in 0..EVENT_LOG_CAPACITY {
+            log.record(MagicSockEvent::Offline(false));
+        }
+        let events = log.snapshot(Redactor { redact: false });
+        assert_eq!(events.len(), EVENT_LOG_CAPACITY);
+        assert_eq!(events[0].event, "went online");
+    }
+
+    #[test]
+    fn test_redact_event() {
+        let event = MagicSockEvent::PathUpgraded {
+            node_id: crate::key::SecretKey::generate().public(),
+            addr: "192.168.1.7:4433".parse().unwrap(),
+        };
+        assert_eq!(
+            Redactor { redact: true }.event(&event),
+            "node is direct via private-v4:4433"
+        );
+    }
+
+    #[test]
+    fn test_write_read_file() {
+        let dir = testdir::testdir!();
+        let path = dir.join("state.json.zst");
+        let log = EventLog::default();
+        log.record(MagicSockEvent::Offline(true));
+        let dump = StateDump {
+            taken_at_unix_ms: unix_ms(SystemTime::now()),
+            redacted: true,
+            node_id: None,
+            online: false,
+            metered: true,
+            local_addrs: vec![Redactor { redact: true }.addr("10.0.0.2:1234".parse().unwrap())],
+            endpoints: Vec::new(),
+            relay: RelayDump {
+                home: Some("https://relay.example".into()),
+                reason: Some("netcheck".into()),
+                latencies_ms: [("https://relay.example".to_string(), 12.5)].into(),
+                rtts_ms: [("https://relay.example".to_string(), 20.0)].into(),
+            },
+            netcheck: None,
+            nodes: Vec::new(),
+            events: log.snapshot(Redactor { redact: true }),
+        };
+        dump.write_to_file(&path).unwrap();
+        assert_eq!(StateDump::read_from_file(&path).unwrap(), dump);
+    }
+
+    #[tokio::test]
+    async fn test_dump_state() {
+        let msock = super::super::MagicSock::new(Default::default())
+            .await
+            .unwrap();
+        let dump = msock.dump_state(false);
+        assert!(!dump.redacted);
+        assert!(dump.node_id.is_some());
+        assert!(dump.local_addrs[0].starts_with("0.0.0.0:"));
+
+        let dump = msock.dump_state(true);
+        assert!(dump.redacted);
+        assert_eq!(dump.node_id, None);
+        assert!(dump.local_addrs[0].starts_with("unspecified-v4:"));
+        msock.close().await.unwrap();
+    }
+}
@@ -0,0 +1,980 @@
+//! Sending the direct UDP path through a TURN server.
+//!
+//! Deployments which already run TURN servers, see [RFC 8656], can use them to reach nodes
+//! behind NATs which defeat hole punching.  An allocation on the TURN server gives us a
+//! relayed transport address.  Other nodes send to it like to any other direct address, the
+//! server forwards their datagrams to us in Data indications.  We send to them in Send
+//! indications, which the server forwards from the relayed address.
+//!
+//! The server only forwards datagrams from and to peers we created a permission for, which
+//! happens on demand for every peer we send to.
+//!
+//! [RFC 8656]: https://datatracker.ietf.org/doc/html/rfc8656
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use stun_rs::{
+    attributes::{
+        stun::{MessageIntegrity, Nonce, Realm, UserName},
+        turn::{LifeTime, RequestedTrasport, XorPeerAddress},
+    },
+    methods, protocols, Algorithm, AlgorithmId, DecoderContextBuilder, HMACKey, MessageClass,
+    MessageDecoder, MessageDecoderBuilder, MessageEncoderBuilder, MessageMethod, StunAttribute,
+    StunMessageBuilder, TransactionId,
+};
+use tokio::{
+    sync::{mpsc, Notify},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use super::udp_relay::Relay;
+use crate::net::UdpSocket;
+
+/// How long creating the allocation may take.
+const ALLOCATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The allocation lifetime we ask for, the default of RFC 8656.
+const ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+
+/// How long a permission lasts, fixed by RFC 8656.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
+/// How long before they expire permissions still in use are refreshed.
+const PERMISSION_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// How often permissions are checked for refreshing.
+const PERMISSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The most peers in a single CreatePermission request.
+const MAX_PERMISSIONS_PER_REQUEST: usize = 32;
+
+/// The initial retransmission timeout of requests, doubled after every attempt.
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+
+/// How often a request is sent before giving up.
+const MAX_TRANSMISSIONS: usize = 5;
+
+/// The buffer size for encoding requests.
+const MAX_REQUEST_SIZE: usize = 2048;
+
+/// The fixed magic cookie of STUN messages, see RFC 8489.
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+/// The length of the STUN message header.
+const HEADER_LEN: usize = 20;
+
+/// The message type of Send indications.
+const SEND_INDICATION: u16 = 0x0016;
+
+/// The message type of Data indications.
+const DATA_INDICATION: u16 = 0x0017;
+
+/// The type of the XOR-PEER-ADDRESS attribute.
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+
+/// The type of the DATA attribute.
+const ATTR_DATA: u16 = 0x0013;
+
+/// A TURN server to send the direct UDP path through.
+#[derive(derive_more::Debug, Clone, PartialEq, Eq)]
+pub struct TurnConfig {
+    /// The UDP address of the TURN server.
+    pub server: SocketAddr,
+    /// Username of the long-term credentials to authenticate with.
+    pub username: String,
+    /// Password of the long-term credentials to authenticate with.
+    #[debug("***")]
+    pub password: String,
+}
+
+impl TurnConfig {
+    /// Creates the config for the TURN server at `server`.
+    pub fn new(
+        server: SocketAddr,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            server,
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// An allocation on a TURN server.
+///
+/// The allocation is refreshed by a task until this is dropped, when it is deleted.
+#[derive(Debug)]
+pub(super) struct Allocation {
+    server: SocketAddr,
+    relayed_addr: SocketAddr,
+    shared: Arc<Shared>,
+    responses: mpsc::Sender<Vec<u8>>,
+    cancel: CancellationToken,
+}
+
+/// State shared between the [`Allocation`] and the task maintaining it.
+#[derive(Debug, Default)]
+struct Shared {
+    permissions: parking_lot::Mutex<HashMap<IpAddr, Permission>>,
+    /// Notified when a permission for a new peer is needed.
+    new_permission: Notify,
+    /// Set when the allocation could not be refreshed.
+    closed: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Permission {
+    last_used: Instant,
+    /// When the installed permission expires, `None` until it is installed.
+    expires: Option<Instant>,
+}
+
+impl Allocation {
+    /// Creates an allocation on the TURN server in `config` for `socket`.
+    ///
+    /// Responses are read from the socket until this returns, afterwards they must be
+    /// passed to [`Allocation::handle_response`].
+    pub(super) async fn allocate(socket: Arc<UdpSocket>, config: &TurnConfig) -> Result<Self> {
+        ensure!(
+            config.server.is_ipv4(),
+            "only TURN servers reachable over IPv4 are supported"
+        );
+        let mut client = Client {
+            socket,
+            config: config.clone(),
+            challenge: None,
+            responses: Responses::Socket,
+        };
+        let response = time::timeout(ALLOCATE_TIMEOUT, client.request(&Request::Allocate))
+            .await
+            .context("timeout")??;
+        let relayed_addr = response
+            .relayed_addr
+            .context("no relayed address in allocate response")?;
+        ensure!(relayed_addr.is_ipv4(), "relayed address is not IPv4");
+        let lifetime = response.lifetime.unwrap_or(ALLOCATION_LIFETIME);
+        debug!(server = %config.server, %relayed_addr, ?lifetime, "TURN allocation created");
+
+        let (responses, responses_rx) = mpsc::channel(16);
+        client.responses = Responses::Forwarded(responses_rx);
+        let shared = Arc::new(Shared::default());
+        let cancel = CancellationToken::new();
+        tokio::task::spawn(maintain(client, lifetime, shared.clone(), cancel.clone()));
+        Ok(Self {
+            server: config.server,
+            relayed_addr,
+            shared,
+            responses,
+            cancel,
+        })
+    }
+
+    /// Records sending to `peer`, creating a permission for it if there is none yet.
+    ///
+    /// Datagrams sent before the permission is created are dropped by the server.
+    fn use_permission(&self, peer: IpAddr) {
+        let now = Instant::now();
+        let mut permissions = self.shared.permissions.lock();
+        permissions
+            .entry(peer)
+            .and_modify(|permission| permission.last_used = now)
+            .or_insert_with(|| {
+                self.shared.new_permission.notify_one();
+                Permission {
+                    last_used: now,
+                    expires: None,
+                }
+            });
+    }
+
+    /// Passes a STUN response received from the server to the task maintaining the
+    /// allocation.
+    fn handle_response(&self, response: Vec<u8>) {
+        // Requests are retransmitted, dropping a response when busy is fine.
+        self.responses.try_send(response).ok();
+    }
+}
+
+impl Relay for Allocation {
+    fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Whether the allocation is still refreshed.
+    fn is_alive(&self) -> bool {
+        !self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    fn relayed_addr(&self) -> Option<SocketAddr> {
+        Some(self.relayed_addr)
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, dst: SocketAddr, payload: &[u8]) -> bool {
+        // The allocation only relays IPv4.
+        if !dst.is_ipv4() {
+            return false;
+        }
+        self.use_permission(dst.ip());
+        encode_send(buf, dst, payload)
+    }
+
+    fn decode(&self, datagram: &[u8]) -> io::Result<Option<(SocketAddr, Range<usize>)>> {
+        match decode_datagram(datagram)? {
+            Datagram::Data { peer, payload } => Ok(Some((peer, payload))),
+            Datagram::Response => {
+                self.handle_response(datagram.to_vec());
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Shared {
+    /// Returns the peers whose permissions need to be created or refreshed.
+    ///
+    /// Forgets the peers we did not send to for the lifetime of a permission.
+    fn permissions_due(&self, now: Instant) -> Vec<IpAddr> {
+        let mut permissions = self.permissions.lock();
+        permissions.retain(|_, permission| {
+            now.saturating_duration_since(permission.last_used) < PERMISSION_LIFETIME
+        });
+        permissions
+            .iter()
+            .filter(|(_, permission)| match permission.expires {
+                Some(expires) => expires.saturating_duration_since(now) < PERMISSION_REFRESH_MARGIN,
+                None => true,
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    fn permissions_installed(&self, peers: &[IpAddr], expires: Instant) {
+        let mut permissions = self.permissions.lock();
+        for peer in peers {
+            if let Some(permission) = permissions.get_mut(peer) {
+                permission.expires = Some(expires);
+            }
+        }
+    }
+}
+
+/// Refreshes the allocation and creates permissions until cancelled.
+async fn maintain(
+    mut client: Client,
+    mut lifetime: Duration,
+    shared: Arc<Shared>,
+    cancel: CancellationToken,
+) {
+    let mut refresh_at = time::Instant::now() + lifetime / 2;
+    let mut permissions_check = time::interval(PERMISSION_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                client.deallocate().await;
+                return;
+            }
+            _ = time::sleep_until(refresh_at) => {
+                let request = Request::Refresh { lifetime: ALLOCATION_LIFETIME };
+                match client.request(&request).await {
+                    Ok(response) => {
+                        lifetime = response.lifetime.unwrap_or(lifetime);
+                        refresh_at = time::Instant::now() + lifetime / 2;
+                    }
+                    Err(err) => {
+                        warn!("failed to refresh TURN allocation: {err:#}");
+                        break;
+                    }
+                }
+            }
+            _ = shared.new_permission.notified() => {
+                client.create_permissions(&shared).await;
+            }
+            _ = permissions_check.tick() => {
+                client.create_permissions(&shared).await;
+            }
+        }
+    }
+    shared.closed.store(true, Ordering::Relaxed);
+}
+
+/// Sends requests to the TURN server and receives the responses.
+#[derive(Debug)]
+struct Client {
+    socket: Arc<UdpSocket>,
+    config: TurnConfig,
+    /// The realm and nonce the server challenged us with, if any.
+    challenge: Option<Challenge>,
+    responses: Responses,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Challenge {
+    realm: String,
+    nonce: String,
+}
+
+/// Where responses of the TURN server are received from.
+#[derive(Debug)]
+enum Responses {
+    /// Read directly from the socket, while nothing else receives from it.
+    Socket,
+    /// Passed on by the socket's reader.
+    Forwarded(mpsc::Receiver<Vec<u8>>),
+}
+
+impl Client {
+    /// Sends `request`, authenticating if the server asks for it.
+    async fn request(&mut self, request: &Request) -> Result<Response> {
+        // The first attempt is answered with a challenge, or a new nonce once ours is stale.
+        for _ in 0..3 {
+            let tx = TransactionId::default();
+            let auth = self
+                .challenge
+                .as_ref()
+                .map(|challenge| (&self.config, challenge));
+            let packet = request.encode(tx, auth)?;
+            let challenge = self.challenge.clone();
+            let response = self.transact(tx, &packet, challenge.as_ref()).await?;
+            let Some((code, reason)) = response.error else {
+                return Ok(response);
+            };
+            let challenge = match (response.realm, response.nonce) {
+                (Some(realm), Some(nonce)) => Some(Challenge { realm, nonce }),
+                _ => None,
+            };
+            match (code, challenge) {
+                // Unauthenticated, or a stale nonce.
+                (401 | 438, Some(challenge)) if self.challenge.as_ref() != Some(&challenge) => {
+                    self.challenge = Some(challenge);
+                }
+                (401, _) => bail!("{request} failed: invalid credentials"),
+                _ => bail!("{request} failed: {code} {reason}"),
+            }
+        }
+        bail!("{request} failed: authentication did not succeed")
+    }
+
+    /// Sends `packet` until a response for `tx` is received.
+    ///
+    /// If the request was authenticated with `challenge`, only authenticated success
+    /// responses are accepted.
+    async fn transact(
+        &mut self,
+        tx: TransactionId,
+        packet: &[u8],
+        challenge: Option<&Challenge>,
+    ) -> Result<Response> {
+        let mut rto = INITIAL_RTO;
+        for _ in 0..MAX_TRANSMISSIONS {
+            self.socket.send_to(packet, self.config.server).await?;
+            let deadline = time::Instant::now() + rto;
+            while let Ok(response) = time::timeout_at(deadline, self.recv()).await {
+                let auth = challenge.map(|challenge| (&self.config, challenge));
+                match parse_response(&response?, auth) {
+                    Some((response_tx, response)) if response_tx == tx => return Ok(response),
+                    _ => continue,
+                }
+            }
+            rto *= 2;
+        }
+        bail!("no response from the TURN server")
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        match self.responses {
+            Responses::Socket => {
+                let mut buf = vec![0u8; 1500];
+                loop {
+                    let (len, src) = self.socket.recv_from(&mut buf).await?;
+                    if src == self.config.server {
+                        buf.truncate(len);
+                        return Ok(buf);
+                    }
+                }
+            }
+            Responses::Forwarded(ref mut responses) => {
+                responses.recv().await.context("allocation dropped")
+            }
+        }
+    }
+
+    /// Creates or refreshes the permissions for the peers we send to.
+    async fn create_permissions(&mut self, shared: &Shared) {
+        let peers = shared.permissions_due(Instant::now());
+        for peers in peers.chunks(MAX_PERMISSIONS_PER_REQUEST) {
+            let request = Request::CreatePermission {
+                peers: peers.to_vec(),
+            };
+            match self.request(&request).await {
+                Ok(_) => {
+                    debug!(?peers, "TURN permissions created");
+                    shared.permissions_installed(peers, Instant::now() + PERMISSION_LIFETIME);
+                }
+                // Retried with the next check.
+                Err(err) => warn!(?peers, "failed to create TURN permissions: {err:#}"),
+            }
+        }
+    }
+
+    /// Deletes the allocation, without waiting for the server to confirm.
+    async fn deallocate(&mut self) {
+        let request = Request::Refresh {
+            lifetime: Duration::ZERO,
+        };
+        let auth = self
+            .challenge
+            .as_ref()
+            .map(|challenge| (&self.config, challenge));
+        if let Ok(packet) = request.encode(TransactionId::default(), auth) {
+            self.socket.send_to(&packet, self.config.server).await.ok();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    Allocate,
+    Refresh { lifetime: Duration },
+    CreatePermission { peers: Vec<IpAddr> },
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Request::Allocate => write!(f, "Allocate"),
+            Request::Refresh { .. } => write!(f, "Refresh"),
+            Request::CreatePermission { .. } => write!(f, "CreatePermission"),
+        }
+    }
+}
+
+impl Request {
+    fn method(&self) -> MessageMethod {
+        match self {
+            Request::Allocate => methods::ALLOCATE,
+            Request::Refresh { .. } => methods::REFRESH,
+            Request::CreatePermission { .. } => methods::CREATE_PERMMISSION,
+        }
+    }
+
+    /// Encodes the request, with the long-term credentials of `auth` if set.
+    fn encode(
+        &self,
+        tx: TransactionId,
+        auth: Option<(&TurnConfig, &Challenge)>,
+    ) -> Result<Vec<u8>> {
+        let mut msg =
+            StunMessageBuilder::new(self.method(), MessageClass::Request).with_transaction_id(tx);
+        match self {
+            Request::Allocate => {
+                msg = msg
+                    .with_attribute(RequestedTrasport::new(protocols::UDP))
+                    .with_attribute(LifeTime::new(ALLOCATION_LIFETIME.as_secs() as u32));
+            }
+            Request::Refresh { lifetime } => {
+                msg = msg.with_attribute(LifeTime::new(lifetime.as_secs() as u32));
+            }
+            Request::CreatePermission { peers } => {
+                for peer in peers {
+                    // The port is ignored by the server.
+                    msg = msg.with_attribute(XorPeerAddress::from(SocketAddr::new(*peer, 0)));
+                }
+            }
+        }
+        if let Some((config, challenge)) = auth {
+            let key = long_term_key(config, challenge)?;
+            msg = msg
+                .with_attribute(UserName::new(&config.username).map_err(stun_error)?)
+                .with_attribute(Realm::new(&challenge.realm).map_err(stun_error)?)
+                .with_attribute(Nonce::new(&challenge.nonce).map_err(stun_error)?)
+                .with_attribute(MessageIntegrity::new(key));
+        }
+        let msg = msg.build();
+        let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+        let len = MessageEncoderBuilder::default()
+            .build()
+            .encode(&mut buf, &msg)
+            .map_err(stun_error)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+/// The parts of a response to a [`Request`] we use.
+#[derive(Debug, Default)]
+struct Response {
+    /// The error code and reason of an error response.
+    error: Option<(u16, String)>,
+    realm: Option<String>,
+    nonce: Option<String>,
+    relayed_addr: Option<SocketAddr>,
+    lifetime: Option<Duration>,
+}
+
+/// Parses a response from the TURN server, returning its transaction ID.
+///
+/// If the request was authenticated with `auth`, success responses without a valid
+/// MESSAGE-INTEGRITY are ignored, as anyone on the path could have sent them.
+fn parse_response(
+    packet: &[u8],
+    auth: Option<(&TurnConfig, &Challenge)>,
+) -> Option<(TransactionId, Response)> {
+    let (msg, _) = MessageDecoder::default().decode(packet).ok()?;
+    let mut response = Response::default();
+    match msg.class() {
+        MessageClass::SuccessResponse => {
+            if let Some((config, challenge)) = auth {
+                if msg.get::<MessageIntegrity>().is_none()
+                    || !is_authentic(packet, config, challenge)
+                {
+                    debug!("ignoring unauthenticated response from the TURN server");
+                    return None;
+                }
+            }
+        }
+        MessageClass::ErrorResponse => response.error = Some((0, String::new())),
+        _ => return None,
+    }
+    for attr in msg.attributes() {
+        match attr {
+            StunAttribute::ErrorCode(code) => {
+                let code = code.error_code();
+                response.error = Some((code.error_code(), code.reason().to_string()));
+            }
+            StunAttribute::Realm(realm) => response.realm = Some(realm.as_str().to_string()),
+            StunAttribute::Nonce(nonce) => response.nonce = Some(nonce.as_str().to_string()),
+            StunAttribute::XorRelayedAddress(addr) => {
+                response.relayed_addr = Some(*addr.socket_address())
+            }
+            StunAttribute::LifeTime(lifetime) => {
+                response.lifetime = Some(Duration::from_secs(lifetime.as_u32().into()))
+            }
+            _ => {}
+        }
+    }
+    Some((*msg.transaction_id(), response))
+}
+
+/// Whether the MESSAGE-INTEGRITY of `packet` matches our long-term credentials.
+fn is_authentic(packet: &[u8], config: &TurnConfig, challenge: &Challenge) -> bool {
+    let Ok(key) = long_term_key(config, challenge) else {
+        return false;
+    };
+    let ctx = DecoderContextBuilder::default()
+        .with_key(key)
+        .with_validation()
+        .build();
+    MessageDecoderBuilder::default()
+        .with_context(ctx)
+        .build()
+        .decode(packet)
+        .is_ok()
+}
+
+fn long_term_key(config: &TurnConfig, challenge: &Challenge) -> Result<HMACKey> {
+    HMACKey::new_long_term(
+        &config.username,
+        &challenge.realm,
+        &config.password,
+        Algorithm::from(AlgorithmId::MD5),
+    )
+    .map_err(stun_error)
+}
+
+/// A datagram received from the TURN server.
+#[derive(Debug, PartialEq, Eq)]
+enum Datagram {
+    /// A datagram a peer sent to our relayed address, with the range of its payload.
+    Data {
+        peer: SocketAddr,
+        payload: Range<usize>,
+    },
+    /// A response to one of our requests, see [`Allocation::handle_response`].
+    Response,
+}
+
+/// Decodes a datagram received from the TURN server.
+///
+/// Data indications are on the data path, so they are parsed here rather than by stun-rs,
+/// which would copy the payload.
+fn decode_datagram(datagram: &[u8]) -> io::Result<Datagram> {
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+    if datagram.len() < HEADER_LEN || datagram[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err(invalid("not a STUN message"));
+    }
+    let typ = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let end = HEADER_LEN + usize::from(u16::from_be_bytes([datagram[2], datagram[3]]));
+    if typ & 0xc000 != 0 || end > datagram.len() {
+        return Err(invalid("invalid STUN message"));
+    }
+    // The class is encoded in two bits of the type, both set for error responses.
+    match typ & 0x0110 {
+        0x0100 | 0x0110 => return Ok(Datagram::Response),
+        _ if typ == DATA_INDICATION => {}
+        _ => return Err(invalid("unexpected STUN message")),
+    }
+
+    let tx_id = &datagram[8..HEADER_LEN];
+    let mut peer = None;
+    let mut payload = None;
+    let mut offset = HEADER_LEN;
+    while offset + 4 <= end {
+        let attr = u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+        let len = usize::from(u16::from_be_bytes([
+            datagram[offset + 2],
+            datagram[offset + 3],
+        ]));
+        let value = offset + 4..offset + 4 + len;
+        if value.end > end {
+            return Err(invalid("truncated STUN attribute"));
+        }
+        match attr {
+            ATTR_XOR_PEER_ADDRESS => {
+                let addr = decode_xor_addr(&datagram[value.clone()], tx_id)
+                    .ok_or_else(|| invalid("invalid XOR-PEER-ADDRESS"))?;
+                peer = Some(addr);
+            }
+            ATTR_DATA => payload = Some(value.clone()),
+            _ => {}
+        }
+        // Attribute values are padded to a multiple of four bytes.
+        offset = (value.end + 3) & !3;
+    }
+    match (peer, payload) {
+        (Some(peer), Some(payload)) => Ok(Datagram::Data { peer, payload }),
+        _ => Err(invalid("incomplete Data indication")),
+    }
+}
+
+/// Wraps `payload` in a Send indication to `peer`, replacing the contents of `buf`.
+///
+/// Returns `false` if the payload does not fit in a STUN message.
+fn encode_send(buf: &mut Vec<u8>, peer: SocketAddr, payload: &[u8]) -> bool {
+    let Ok(payload_len) = u16::try_from(payload.len()) else {
+        return false;
+    };
+    let tx_id: [u8; 12] = rand::random();
+    buf.clear();
+    buf.extend_from_slice(&SEND_INDICATION.to_be_bytes());
+    // The message length, set once known.
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buf.extend_from_slice(&tx_id);
+    encode_xor_addr(buf, ATTR_XOR_PEER_ADDRESS, peer, &tx_id);
+    buf.extend_from_slice(&ATTR_DATA.to_be_bytes());
+    buf.extend_from_slice(&payload_len.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf.resize((buf.len() + 3) & !3, 0);
+    let Ok(len) = u16::try_from(buf.len() - HEADER_LEN) else {
+        return false;
+    };
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+    true
+}
+
+/// Appends the attribute `attr` holding `addr` XOR-ed with the magic cookie and `tx_id`.
+fn encode_xor_addr(buf: &mut Vec<u8>, attr: u16, addr: SocketAddr, tx_id: &[u8]) {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let (family, len) = match addr {
+        SocketAddr::V4(_) => (0x01, 8u16),
+        SocketAddr::V6(_) => (0x02, 20u16),
+    };
+    buf.extend_from_slice(&attr.to_be_bytes());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(&[0, family]);
+    buf.extend_from_slice(&port.to_be_bytes());
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes()),
+        IpAddr::V6(ip) => {
+            let mask = MAGIC_COOKIE
+                .to_be_bytes()
+                .into_iter()
+                .chain(tx_id.iter().copied());
+            buf.extend(ip.octets().into_iter().zip(mask).map(|(b, m)| b ^ m));
+        }
+    }
+}
+
+/// Decodes the value of an XOR-ed address attribute, see [`encode_xor_addr`].
+fn decode_xor_addr(value: &[u8], tx_id: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes(value.get(2..4)?.try_into().ok()?) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip: IpAddr = match *value.get(1)? {
+        0x01 => {
+            let ip = u32::from_be_bytes(value.get(4..8)?.try_into().ok()?);
+            Ipv4Addr::from(ip ^ MAGIC_COOKIE).into()
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            let mask = MAGIC_COOKIE
+                .to_be_bytes()
+                .into_iter()
+                .chain(tx_id.iter().copied());
+            for (b, m) in octets.iter_mut().zip(mask) {
+                *b ^= m;
+            }
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn stun_error(err: impl Display) -> anyhow::Error {
+    anyhow!("STUN: {err}")
+}
+
+#[cfg(test)]
+mod tests {
+    use stun_rs::attributes::{
+        stun::ErrorCode,
+        turn::{Data, XorRelayedAddress},
+    };
+
+    use super::*;
+
+    const REALM: &str = "example.org";
+    const NONCE: &str = "abcdefgh";
+
+    fn config(server: SocketAddr) -> TurnConfig {
+        TurnConfig::new(server, "user", "secret")
+    }
+
+    /// Answers the requests of a single client, challenging it first.
+    ///
+    /// Reports the peers of CreatePermission requests and the Send indications.
+    fn mock_server(events: mpsc::Sender<(MessageMethod, Vec<SocketAddr>)>) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind_local_v4(0)?;
+        let addr = socket.local_addr()?;
+        tokio::task::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await?;
+                let Some((reply, event)) = mock_reply(&buf[..len]) else {
+                    continue;
+                };
+                if let Some(event) = event {
+                    events.send(event).await.ok();
+                }
+                if let Some(reply) = reply {
+                    socket.send_to(&reply, src).await?;
+                }
+            }
+            #[allow(unreachable_code)]
+            anyhow::Ok(())
+        });
+        Ok(addr)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn mock_reply(
+        packet: &[u8],
+    ) -> Option<(Option<Vec<u8>>, Option<(MessageMethod, Vec<SocketAddr>)>)> {
+        let (msg, _) = MessageDecoder::default().decode(packet).ok()?;
+        let peers = msg
+            .attributes()
+            .iter()
+            .filter_map(|attr| match attr {
+                StunAttribute::XorPeerAddress(addr) => Some(*addr.socket_address()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let event = Some((msg.method(), peers));
+        if msg.class() != MessageClass::Request {
+            return Some((None, event));
+        }
+
+        // Check the credentials.
+        let key =
+            HMACKey::new_long_term("user", REALM, "secret", Algorithm::from(AlgorithmId::MD5))
+                .unwrap();
+        let ctx = DecoderContextBuilder::default()
+            .with_key(key.clone())
+            .with_validation()
+            .build();
+        let authenticated = msg.get::<MessageIntegrity>().is_some()
+            && MessageDecoderBuilder::default()
+                .with_context(ctx)
+                .build()
+                .decode(packet)
+                .is_ok();
+        let reply = if authenticated {
+            let mut reply = StunMessageBuilder::new(msg.method(), MessageClass::SuccessResponse)
+                .with_transaction_id(*msg.transaction_id());
+            if msg.method() == methods::ALLOCATE {
+                let relayed: SocketAddr = "203.0.113.1:5000".parse().unwrap();
+                reply = reply
+                    .with_attribute(XorRelayedAddress::from(relayed))
+                    .with_attribute(LifeTime::new(600));
+            }
+            reply.with_attribute(MessageIntegrity::new(key)).build()
+        } else {
+            StunMessageBuilder::new(msg.method(), MessageClass::ErrorResponse)
+                .with_transaction_id(*msg.transaction_id())
+                .with_attribute(ErrorCode::new(
+                    stun_rs::ErrorCode::new(401, "Unauthorized").unwrap(),
+                ))
+                .with_attribute(Realm::new(REALM).unwrap())
+                .with_attribute(Nonce::new(NONCE).unwrap())
+                .build()
+        };
+        let mut buf = vec![0u8; 1500];
+        let len = MessageEncoderBuilder::default()
+            .build()
+            .encode(&mut buf, &reply)
+            .unwrap();
+        buf.truncate(len);
+        Some((Some(buf), event))
+    }
+
+    #[test]
+    fn test_send_data_roundtrip() {
+        let mut buf = Vec::new();
+        for peer in ["198.51.100.7:1234", "[2001:db8::7]:1234"] {
+            let peer: SocketAddr = peer.parse().unwrap();
+
+            // Send indications are understood by other STUN implementations.
+            assert!(encode_send(&mut buf, peer, b"hello"));
+            let (msg, _) = MessageDecoder::default().decode(&buf).unwrap();
+            assert_eq!(msg.method(), methods::SEND);
+            assert_eq!(msg.class(), MessageClass::Indication);
+            let addr = msg.get::<XorPeerAddress>().unwrap();
+            assert_eq!(*addr.as_xor_peer_address().unwrap().socket_address(), peer);
+            let data = msg.get::<Data>().unwrap();
+            assert_eq!(data.as_data().unwrap().as_bytes(), b"hello");
+            // A Send indication is no Data indication.
+            assert!(decode_datagram(&buf).is_err());
+
+            let msg = StunMessageBuilder::new(methods::DATA, MessageClass::Indication)
+                .with_attribute(XorPeerAddress::from(peer))
+                .with_attribute(Data::from(&b"world!"[..]))
+                .build();
+            let mut buf = vec![0u8; 128];
+            let len = MessageEncoderBuilder::default()
+                .build()
+                .encode(&mut buf, &msg)
+                .unwrap();
+            let Datagram::Data { peer: src, payload } = decode_datagram(&buf[..len]).unwrap()
+            else {
+                panic!("not a Data indication");
+            };
+            assert_eq!(src, peer);
+            assert_eq!(&buf[payload], b"world!");
+        }
+        assert!(decode_datagram(b"not stun").is_err());
+    }
+
+    #[test]
+    fn test_response_integrity() {
+        let server: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let config = config(server);
+        let challenge = Challenge {
+            realm: REALM.to_string(),
+            nonce: NONCE.to_string(),
+        };
+        let request = Request::Refresh {
+            lifetime: ALLOCATION_LIFETIME,
+        };
+        let packet = request
+            .encode(TransactionId::default(), Some((&config, &challenge)))
+            .unwrap();
+        let (reply, _) = mock_reply(&packet).unwrap();
+        let reply = reply.unwrap();
+        assert!(parse_response(&reply, Some((&config, &challenge))).is_some());
+
+        // A response with the wrong key, or without any, is ignored.
+        let mut wrong = config.clone();
+        wrong.password = "wrong".to_string();
+        assert!(parse_response(&reply, Some((&wrong, &challenge))).is_none());
+        let unauthenticated =
+            StunMessageBuilder::new(methods::REFRESH, MessageClass::SuccessResponse)
+                .with_transaction_id(TransactionId::default())
+                .build();
+        let mut buf = vec![0u8; 128];
+        let len = MessageEncoderBuilder::default()
+            .build()
+            .encode(&mut buf, &unauthenticated)
+            .unwrap();
+        assert!(parse_response(&buf[..len], Some((&config, &challenge))).is_none());
+        assert!(parse_response(&buf[..len], None).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_and_permissions() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        let (events, mut events_rx) = mpsc::channel(16);
+        let server = mock_server(events)?;
+        let socket = Arc::new(UdpSocket::bind_local_v4(0)?);
+
+        let allocation = Arc::new(Allocation::allocate(socket.clone(), &config(server)).await?);
+        assert_eq!(
+            allocation.relayed_addr(),
+            Some("203.0.113.1:5000".parse().unwrap())
+        );
+        assert!(allocation.is_alive());
+        // The unauthenticated attempt and the authenticated one.
+        for _ in 0..2 {
+            let (method, _) = events_rx.recv().await.unwrap();
+            assert_eq!(method, methods::ALLOCATE);
+        }
+
+        // Forward the responses, like the socket's reader does.
+        let reader = {
+            let allocation = allocation.clone();
+            tokio::task::spawn(async move {
+                let mut buf = vec![0u8; 1500];
+                loop {
+                    let (len, _) = socket.recv_from(&mut buf).await?;
+                    if let Ok(Datagram::Response) = decode_datagram(&buf[..len]) {
+                        allocation.handle_response(buf[..len].to_vec());
+                    }
+                }
+                #[allow(unreachable_code)]
+                anyhow::Ok(())
+            })
+        };
+
+        let peer: IpAddr = "198.51.100.7".parse().unwrap();
+        allocation.use_permission(peer);
+        let (method, peers) = events_rx.recv().await.unwrap();
+        assert_eq!(method, methods::CREATE_PERMMISSION);
+        assert_eq!(peers, vec![SocketAddr::new(peer, 0)]);
+        reader.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allocate_wrong_password() -> Result<()> {
+        let (events, _events_rx) = mpsc::channel(16);
+        let server = mock_server(events)?;
+        let socket = Arc::new(UdpSocket::bind_local_v4(0)?);
+        let mut config = config(server);
+        config.password = "wrong".to_string();
+        let err = Allocation::allocate(socket, &config).await.unwrap_err();
+        assert!(err.to_string().contains("invalid credentials"), "{err}");
+        Ok(())
+    }
+}
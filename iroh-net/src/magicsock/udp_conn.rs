@@ -2,39 +2,223 @@ use std::{
     fmt::Debug,
     io,
     net::SocketAddr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
 
-use anyhow::{bail, Context as _};
+use anyhow::{bail, ensure, Context as _};
+use arc_swap::{ArcSwap, ArcSwapOption};
 use futures::ready;
 use quinn::AsyncUdpSocket;
-use tokio::io::Interest;
+use tokio::io::{Interest, ReadBuf};
 use tracing::{debug, trace, warn};
 
+use super::udp_relay::Relay;
 use crate::net::IpFamily;
+use crate::net::SocketOptions;
 use crate::net::UdpSocket;
 
 /// A UDP socket implementing Quinn's [`AsyncUdpSocket`].
+///
+/// Clones share the socket, including moving it to another port with [`UdpConn::hop`].
 #[derive(Clone, Debug)]
 pub struct UdpConn {
-    io: Arc<UdpSocket>,
+    io: Arc<ArcSwap<UdpSocket>>,
+    /// The socket replaced by the last [`UdpConn::hop`], still received on until dropped.
+    previous: Arc<ArcSwapOption<UdpSocket>>,
+    /// Counts the receive polls, to alternate which socket is polled first.
+    recv_rotation: Arc<AtomicUsize>,
     state: Arc<quinn_udp::UdpSocketState>,
+    /// The relay all datagrams are sent through, if any.
+    relay: Option<Arc<dyn Relay>>,
+    /// The buffer datagrams are wrapped in for the relay, reused across sends.
+    relay_buf: Arc<parking_lot::Mutex<Vec<u8>>>,
+    /// The options of the socket, also set on the sockets bound by [`UdpConn::hop`].
+    opts: SocketOptions,
 }
 
 impl UdpConn {
     pub(super) fn as_socket(&self) -> Arc<UdpSocket> {
-        self.io.clone()
+        self.io.load_full()
     }
 
+    #[cfg(test)]
     pub(super) fn bind(port: u16, network: IpFamily) -> anyhow::Result<Self> {
-        let sock = bind(port, network)?;
+        Self::bind_with_options(port, network, SocketOptions::default())
+    }
+
+    /// Binds the socket, setting the given [`SocketOptions`].
+    pub(super) fn bind_with_options(
+        port: u16,
+        network: IpFamily,
+        opts: SocketOptions,
+    ) -> anyhow::Result<Self> {
+        let sock = bind(port, network, &opts)?;
         Ok(Self {
-            io: Arc::new(sock),
+            io: Arc::new(ArcSwap::from_pointee(sock)),
+            previous: Default::default(),
+            recv_rotation: Default::default(),
             state: Default::default(),
+            relay: None,
+            relay_buf: Default::default(),
+            opts,
         })
     }
 
+    /// Sends and receives all datagrams through `relay`.
+    pub(super) fn with_relay(mut self, relay: impl Relay) -> Self {
+        self.relay = Some(Arc::new(relay));
+        self
+    }
+
+    /// Whether datagrams are sent through a relay, a SOCKS5 proxy or a TURN server.
+    pub(super) fn is_proxied(&self) -> bool {
+        self.relay.is_some()
+    }
+
+    /// Whether the relay still forwards datagrams, `false` if not proxied.
+    pub(super) fn proxy_alive(&self) -> bool {
+        self.relay.as_ref().is_some_and(|relay| relay.is_alive())
+    }
+
+    /// The address of the relay other nodes can send to, if it accepts datagrams from any
+    /// node.
+    pub(super) fn relayed_addr(&self) -> Option<SocketAddr> {
+        self.relay.as_ref().and_then(|relay| relay.relayed_addr())
+    }
+
+    /// Moves to a newly bound socket on a random port, returning its address.
+    ///
+    /// Datagrams are sent from the new socket right away.  The old socket is still received
+    /// on until [`UdpConn::drop_previous`], so datagrams from nodes which did not learn the
+    /// new port yet are not lost.  A socket replaced by an earlier hop is closed.
+    pub(super) fn hop(&self) -> anyhow::Result<SocketAddr> {
+        ensure!(!self.is_proxied(), "datagrams are sent through a proxy");
+        let network = IpFamily::from(self.local_addr()?.ip());
+        let sock = bind(0, network, &self.opts)?;
+        let addr = sock.local_addr().context("UDP socket not bound")?;
+        let old = self.io.swap(Arc::new(sock));
+        if self.previous.swap(Some(old)).is_some() {
+            debug!("closing socket of an earlier port hop");
+        }
+        Ok(addr)
+    }
+
+    /// Closes the socket replaced by the last [`UdpConn::hop`].
+    pub(super) fn drop_previous(&self) {
+        if let Some(previous) = self.previous.swap(None) {
+            debug!(addr = ?previous.local_addr().ok(), "closing socket of the previous port");
+        }
+    }
+
+    /// Receives datagrams from `io`, the current or the previous socket.
+    fn poll_recv_socket(
+        &self,
+        io: &UdpSocket,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(io.poll_recv_ready(cx))?;
+            if let Ok(res) = io.try_io(Interest::READABLE, || {
+                self.state.recv(io.into(), bufs, meta)
+            }) {
+                for meta in meta.iter().take(res) {
+                    trace!(
+                        src = %meta.addr,
+                        len = meta.len,
+                        count = meta.len / meta.stride,
+                        dst = %meta.dst_ip.map(|x| x.to_string()).unwrap_or_default(),
+                        "UDP recv"
+                    );
+                }
+
+                return Poll::Ready(Ok(res));
+            }
+        }
+    }
+
+    /// Sends the transmits wrapped for the relay.
+    ///
+    /// Relays do not support GSO, so every segment is sent as its own datagram.
+    fn poll_send_relayed(
+        &self,
+        relay: &dyn Relay,
+        cx: &mut Context,
+        transmits: &[quinn_udp::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let io = self.io.load();
+        let mut buf = self.relay_buf.lock();
+        for (sent, t) in transmits.iter().enumerate() {
+            let segment_size = t.segment_size.unwrap_or(t.contents.len()).max(1);
+            for (i, segment) in t.contents.chunks(segment_size).enumerate() {
+                if !relay.encode(&mut buf, t.destination, segment) {
+                    // Counts as sent, QUIC recovers it as lost.
+                    trace!(dst = %t.destination, "relay can not forward datagram, dropping");
+                    break;
+                }
+                let res = io.poll_send_to(cx, &buf, relay.server());
+                match res {
+                    Poll::Ready(Ok(_)) => {}
+                    // Once part of a transmit is sent it counts as sent, QUIC recovers the
+                    // remaining segments as lost.
+                    _ if i > 0 => break,
+                    _ if sent > 0 => return Poll::Ready(Ok(sent)),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            trace!(dst = %t.destination, len = t.contents.len(), relay = %relay.server(), "UDP send (relayed)");
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    /// Receives a single datagram from the relay and unwraps it.
+    fn poll_recv_relayed(
+        &self,
+        relay: &dyn Relay,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let io = self.io.load();
+        loop {
+            let mut read_buf = ReadBuf::new(&mut bufs[0]);
+            let src = ready!(io.poll_recv_from(cx, &mut read_buf))?;
+            let len = read_buf.filled().len();
+            if src != relay.server() {
+                // Only datagrams forwarded by the relay are accepted, we are not reachable
+                // at other addresses.
+                trace!(%src, len, "dropping datagram not from the relay");
+                continue;
+            }
+            let (addr, payload) = match relay.decode(&bufs[0][..len]) {
+                Ok(Some(res)) => res,
+                // Addressed to the relay client, e.g. a TURN response.
+                Ok(None) => continue,
+                Err(err) => {
+                    trace!(len, "dropping invalid datagram from the relay: {err}");
+                    continue;
+                }
+            };
+            let len = payload.len();
+            bufs[0].copy_within(payload, 0);
+            meta[0] = quinn_udp::RecvMeta {
+                addr,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+            trace!(src = %addr, len, "UDP recv (relayed)");
+            return Poll::Ready(Ok(1));
+        }
+    }
+
     pub fn port(&self) -> u16 {
         self.local_addr().map(|p| p.port()).unwrap_or_default()
     }
@@ -53,12 +237,15 @@ impl AsyncUdpSocket for UdpConn {
         cx: &mut Context,
         transmits: &[quinn_udp::Transmit],
     ) -> Poll<io::Result<usize>> {
+        if let Some(relay) = &self.relay {
+            return self.poll_send_relayed(&**relay, cx, transmits);
+        }
         let inner = &self.state;
-        let io = &self.io;
+        let io = self.io.load();
         loop {
             ready!(io.poll_send_ready(cx))?;
             if let Ok(res) = io.try_io(Interest::WRITABLE, || {
-                inner.send(Arc::as_ref(io).into(), state, transmits)
+                inner.send((&**io).into(), state, transmits)
             }) {
                 for t in transmits.iter().take(res) {
                     trace!(
@@ -81,32 +268,39 @@ impl AsyncUdpSocket for UdpConn {
         bufs: &mut [io::IoSliceMut<'_>],
         meta: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
-        loop {
-            ready!(self.io.poll_recv_ready(cx))?;
-            if let Ok(res) = self.io.try_io(Interest::READABLE, || {
-                self.state.recv(Arc::as_ref(&self.io).into(), bufs, meta)
-            }) {
-                for meta in meta.iter().take(res) {
-                    trace!(
-                        src = %meta.addr,
-                        len = meta.len,
-                        count = meta.len / meta.stride,
-                        dst = %meta.dst_ip.map(|x| x.to_string()).unwrap_or_default(),
-                        "UDP recv"
-                    );
-                }
-
-                return Poll::Ready(Ok(res));
+        if let Some(relay) = &self.relay {
+            return self.poll_recv_relayed(&**relay, cx, bufs, meta);
+        }
+        let io = self.io.load();
+        let Some(previous) = self.previous.load_full() else {
+            return self.poll_recv_socket(&io, cx, bufs, meta);
+        };
+        // Quinn polls until we return pending, so a busy socket would starve the one polled
+        // after it.  Alternate which one is polled first while both receive.
+        let mut sockets = [&**io, &*previous];
+        if self.recv_rotation.fetch_add(1, Ordering::Relaxed) % 2 == 1 {
+            sockets.swap(0, 1);
+        }
+        for io in sockets {
+            match self.poll_recv_socket(io, cx, bufs, meta) {
+                Poll::Pending => {}
+                res => return res,
             }
         }
+        Poll::Pending
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.io.local_addr()
+        self.io.load().local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        // Wrapped datagrams are larger than the ones they carry.
+        self.relay.is_some() || quinn_udp::may_fragment()
     }
 }
 
-fn bind(port: u16, network: IpFamily) -> anyhow::Result<UdpSocket> {
+fn bind(port: u16, network: IpFamily, opts: &SocketOptions) -> anyhow::Result<UdpSocket> {
     debug!(?network, %port, "binding");
 
     // Build a list of preferred ports.
@@ -125,7 +319,7 @@ fn bind(port: u16, network: IpFamily) -> anyhow::Result<UdpSocket> {
     debug!(?ports, "candidate ports");
 
     for port in &ports {
-        match UdpSocket::bind(network, *port) {
+        match UdpSocket::bind_with_options(network, *port, opts) {
             Ok(pconn) => {
                 let local_addr = pconn.local_addr().context("UDP socket not bound")?;
                 debug!(?network, %local_addr, "successfully bound");
@@ -185,6 +379,67 @@ mod tests {
         rebinding_conn_send_recv(IpFamily::V6).await
     }
 
+    #[tokio::test]
+    async fn test_hop() -> Result<()> {
+        let conn = UdpConn::bind(0, IpFamily::V4)?;
+        let clone = conn.clone();
+        let old_addr = conn.local_addr()?;
+        let new_addr = conn.hop()?;
+        assert_ne!(old_addr.port(), new_addr.port());
+        assert_eq!(clone.local_addr()?, new_addr);
+
+        // Both the new and the previous port receive.
+        let sender = UdpConn::bind(0, IpFamily::V4)?.as_socket();
+        for port in [old_addr.port(), new_addr.port()] {
+            let dst = SocketAddr::new(IpFamily::V4.local_addr(), port);
+            sender.send_to(b"hello", dst).await?;
+            let mut buf = [0u8; 64];
+            let mut meta = [quinn_udp::RecvMeta::default()];
+            let n = futures::future::poll_fn(|cx| {
+                clone.poll_recv(cx, &mut [io::IoSliceMut::new(&mut buf)], &mut meta)
+            })
+            .await?;
+            assert_eq!(n, 1);
+            assert_eq!(&buf[..meta[0].len], b"hello");
+        }
+
+        conn.drop_previous();
+        assert!(clone.previous.load().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hop_recv_fair() -> Result<()> {
+        let conn = UdpConn::bind(0, IpFamily::V4)?;
+        let old_addr = conn.local_addr()?;
+        let new_addr = conn.hop()?;
+
+        // The new port is busy, the previous one still receives its share.
+        let sender = UdpConn::bind(0, IpFamily::V4)?.as_socket();
+        for (port, msg) in [
+            (new_addr.port(), b"new"),
+            (new_addr.port(), b"new"),
+            (old_addr.port(), b"old"),
+        ] {
+            let dst = SocketAddr::new(IpFamily::V4.local_addr(), port);
+            sender.send_to(msg, dst).await?;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let mut buf = [0u8; 64];
+            let mut meta = [quinn_udp::RecvMeta::default()];
+            futures::future::poll_fn(|cx| {
+                conn.poll_recv(cx, &mut [io::IoSliceMut::new(&mut buf)], &mut meta)
+            })
+            .await?;
+            received.push(buf[..meta[0].len].to_vec());
+        }
+        assert!(received.contains(&b"old".to_vec()));
+        Ok(())
+    }
+
     async fn rebinding_conn_send_recv(network: IpFamily) -> Result<()> {
         let m1 = UdpConn::bind(0, network)?;
         let (m1, _m1_key) = wrap_socket(m1)?;
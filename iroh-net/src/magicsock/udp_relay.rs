@@ -0,0 +1,34 @@
+//! Relays which forward all datagrams of the UDP socket, see [`Relay`].
+
+use std::{fmt::Debug, io, net::SocketAddr, ops::Range};
+
+/// A relay all datagrams of the UDP socket are sent through.
+///
+/// Implemented by a SOCKS5 proxy's UDP association and by a TURN allocation.  Datagrams
+/// are wrapped with their destination and sent to [`Relay::server`], which forwards them.
+/// Datagrams from other nodes arrive wrapped from the server as well.
+pub(super) trait Relay: Debug + Send + Sync + 'static {
+    /// The address wrapped datagrams are sent to and received from.
+    fn server(&self) -> SocketAddr;
+
+    /// Whether the relay still forwards datagrams.
+    fn is_alive(&self) -> bool;
+
+    /// The address of the relay other nodes can send to, to reach us.
+    ///
+    /// `None` if the relay only forwards replies to the destinations we sent to.
+    fn relayed_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Wraps `payload` for sending to `dst`, replacing the contents of `buf`.
+    ///
+    /// Returns `false` if the relay can not forward datagrams to `dst`.
+    fn encode(&self, buf: &mut Vec<u8>, dst: SocketAddr, payload: &[u8]) -> bool;
+
+    /// Unwraps a datagram received from [`Relay::server`].
+    ///
+    /// Returns the address the datagram was sent from and where its payload is in
+    /// `datagram`, or `None` if the datagram was addressed to the relay client itself.
+    fn decode(&self, datagram: &[u8]) -> io::Result<Option<(SocketAddr, Range<usize>)>>;
+}
@@ -3,8 +3,11 @@
 pub mod interfaces;
 pub mod ip;
 mod ip_family;
+pub mod multicast;
 pub mod netmon;
+mod sockopt;
 mod udp;
 
 pub use self::ip_family::IpFamily;
+pub use self::sockopt::SocketOptions;
 pub use self::udp::UdpSocket;
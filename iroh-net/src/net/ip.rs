@@ -2,6 +2,8 @@
 
 use std::net::{IpAddr, Ipv6Addr};
 
+use super::interfaces::State;
+
 const IFF_UP: u32 = 0x1;
 const IFF_LOOPBACK: u32 = 0x8;
 
@@ -26,50 +28,74 @@ impl LocalAddresses {
     /// addresses because we know of environments where these are used with NAT to provide connectivity.
     pub fn new() -> Self {
         let ifaces = default_net::interface::get_interfaces();
+        Self::from_addrs(
+            ifaces
+                .iter()
+                // Skip down interfaces
+                .filter(|iface| is_up(iface))
+                .flat_map(|iface| {
+                    let ifc_is_loopback = is_loopback(iface);
+                    iface
+                        .ipv4
+                        .iter()
+                        .map(|a| IpAddr::V4(a.addr))
+                        .chain(iface.ipv6.iter().map(|a| IpAddr::V6(a.addr)))
+                        .map(move |ip| (ip, ifc_is_loopback))
+                }),
+        )
+    }
 
+    /// Returns the IP addresses of the interfaces in `state`.
+    ///
+    /// Unlike [`LocalAddresses::new`] this does not enumerate the interfaces again.
+    pub fn from_state(state: &State) -> Self {
+        Self::from_addrs(
+            state
+                .interfaces
+                .values()
+                .filter(|iface| iface.is_up())
+                .flat_map(|iface| {
+                    let ifc_is_loopback = iface.is_loopback();
+                    iface
+                        .addrs()
+                        .map(move |ipnet| (ipnet.addr(), ifc_is_loopback))
+                }),
+        )
+    }
+
+    /// Classifies the addresses of the interfaces which are up, with whether their
+    /// interface is a loopback interface.
+    fn from_addrs(addrs: impl Iterator<Item = (IpAddr, bool)>) -> Self {
         let mut loopback = Vec::new();
         let mut regular4 = Vec::new();
         let mut regular6 = Vec::new();
         let mut linklocal4 = Vec::new();
         let mut ula6 = Vec::new();
 
-        for iface in ifaces {
-            if !is_up(&iface) {
-                // Skip down interfaces
-                continue;
-            }
-            let ifc_is_loopback = is_loopback(&iface);
-            let addrs = iface
-                .ipv4
-                .iter()
-                .map(|a| IpAddr::V4(a.addr))
-                .chain(iface.ipv6.iter().map(|a| IpAddr::V6(a.addr)));
-
-            for ip in addrs {
-                let ip = to_canonical(ip);
-
-                if ip.is_loopback() || ifc_is_loopback {
-                    loopback.push(ip);
-                } else if is_link_local(ip) {
-                    if ip.is_ipv4() {
-                        linklocal4.push(ip);
-                    }
-
-                    // We know of no cases where the IPv6 fe80:: addresses
-                    // are used to provide WAN connectivity. It is also very
-                    // common for users to have no IPv6 WAN connectivity,
-                    // but their OS supports IPv6 so they have an fe80::
-                    // address. We don't want to report all of those
-                    // IPv6 LL to Control.
-                } else if ip.is_ipv6() && is_private(&ip) {
-                    // Google Cloud Run uses NAT with IPv6 Unique
-                    // Local Addresses to provide IPv6 connectivity.
-                    ula6.push(ip);
-                } else if ip.is_ipv4() {
-                    regular4.push(ip);
-                } else {
-                    regular6.push(ip);
+        for (ip, ifc_is_loopback) in addrs {
+            let ip = to_canonical(ip);
+
+            if ip.is_loopback() || ifc_is_loopback {
+                loopback.push(ip);
+            } else if is_link_local(ip) {
+                if ip.is_ipv4() {
+                    linklocal4.push(ip);
                 }
+
+                // We know of no cases where the IPv6 fe80:: addresses
+                // are used to provide WAN connectivity. It is also very
+                // common for users to have no IPv6 WAN connectivity,
+                // but their OS supports IPv6 so they have an fe80::
+                // address. We don't want to report all of those
+                // IPv6 LL to Control.
+            } else if ip.is_ipv6() && is_private(&ip) {
+                // Google Cloud Run uses NAT with IPv6 Unique
+                // Local Addresses to provide IPv6 connectivity.
+                ula6.push(ip);
+            } else if ip.is_ipv4() {
+                regular4.push(ip);
+            } else {
+                regular6.push(ip);
             }
         }
 
@@ -121,7 +147,7 @@ pub(crate) fn is_private_v6(ip: &Ipv6Addr) -> bool {
     ip.octets()[0] & 0xfe == 0xfc
 }
 
-pub(super) fn is_link_local(ip: IpAddr) -> bool {
+pub(crate) fn is_link_local(ip: IpAddr) -> bool {
     match ip {
         IpAddr::V4(ip) => ip.is_link_local(),
         IpAddr::V6(ip) => is_unicast_link_local(ip),
@@ -156,6 +182,25 @@ pub const fn is_unicast_link_local(addr: Ipv6Addr) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_local_addresses_from_state() {
+        use crate::net::interfaces::Interface;
+
+        let iface = Interface::fake();
+        let state = State {
+            interfaces: [(iface.name().to_string(), iface)].into_iter().collect(),
+            have_v6: false,
+            have_v4: true,
+            is_expensive: false,
+            default_route_interface: None,
+            http_proxy: None,
+            pac: None,
+        };
+        let addrs = LocalAddresses::from_state(&state);
+        assert_eq!(addrs.regular, ["192.168.0.189".parse::<IpAddr>().unwrap()]);
+        assert!(addrs.loopback.is_empty());
+    }
+
     #[test]
     fn test_local_addresses() {
         let addrs = LocalAddresses::new();
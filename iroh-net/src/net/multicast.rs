@@ -0,0 +1,191 @@
+//! Announcing nodes to the local network using UDP multicast.
+//!
+//! A [`LanAnnouncer`] sends and receives [`NodeTicket`]s to and from an IPv4 multicast group,
+//! which is the transport underneath local discovery or pairing devices on the same network.
+
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use futures::FutureExt;
+use iroh_base::ticket::Ticket;
+use tracing::{debug, trace, warn};
+
+use super::{interfaces, netmon};
+use crate::ticket::NodeTicket;
+
+/// The multicast group announcements are sent to by default.
+///
+/// This is in the administratively scoped range, so it does not leave the local network.
+pub const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 73, 82), 7382);
+
+/// Prefix of every announcement, to ignore unrelated traffic on the group.
+const MAGIC: &[u8] = b"iroh-lan-announce";
+
+/// The largest announcement we accept.
+const MAX_ANNOUNCEMENT_SIZE: usize = 2048;
+
+/// Sends and receives node announcements on a LAN multicast group.
+#[derive(Debug)]
+pub struct LanAnnouncer {
+    socket: tokio::net::UdpSocket,
+    group: SocketAddrV4,
+    /// The interface addresses on which the group was joined.
+    joined: parking_lot::Mutex<BTreeSet<Ipv4Addr>>,
+}
+
+impl LanAnnouncer {
+    /// Binds to the multicast `group` and joins it on all interfaces which are up.
+    ///
+    /// Several announcers on the same host can bind the same group.
+    pub async fn bind(group: SocketAddrV4) -> Result<Self> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV4,
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )
+        .context("socket create")?;
+        socket.set_reuse_address(true).context("reuse address")?;
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port());
+        socket.bind(&addr.into()).context("binding")?;
+        socket
+            .set_multicast_loop_v4(true)
+            .context("multicast loop")?;
+        // Announcements are for the local network only.
+        socket.set_multicast_ttl_v4(1).context("multicast ttl")?;
+        socket.set_nonblocking(true).context("nonblocking: true")?;
+        let socket =
+            tokio::net::UdpSocket::from_std(socket.into()).context("conversion to tokio")?;
+
+        let announcer = Self {
+            socket,
+            group,
+            joined: Default::default(),
+        };
+        announcer.rejoin().await;
+        Ok(announcer)
+    }
+
+    /// The multicast group this announcer uses.
+    pub fn group(&self) -> SocketAddrV4 {
+        self.group
+    }
+
+    /// Joins the multicast group on interfaces which came up since the last time.
+    ///
+    /// Must be called after network changes, see [`LanAnnouncer::follow_network_changes`].
+    pub async fn rejoin(&self) {
+        let ifs = interfaces::State::new().await;
+        let addrs: BTreeSet<Ipv4Addr> = ifs
+            .interfaces
+            .values()
+            .filter(|netif| netif.is_up() && !netif.is_loopback())
+            .flat_map(|netif| netif.addrs())
+            .filter_map(|ipnet| match ipnet.addr() {
+                std::net::IpAddr::V4(addr) => Some(addr),
+                std::net::IpAddr::V6(_) => None,
+            })
+            .collect();
+        let mut joined = self.joined.lock();
+        // Memberships of interfaces which are gone are dropped by the OS.
+        joined.retain(|addr| addrs.contains(addr));
+        for addr in addrs {
+            if joined.contains(&addr) {
+                continue;
+            }
+            match self.socket.join_multicast_v4(*self.group.ip(), addr) {
+                Ok(()) => {
+                    debug!(group = %self.group, interface = %addr, "joined multicast group");
+                    joined.insert(addr);
+                }
+                Err(err) => warn!(interface = %addr, "failed to join multicast group: {err:?}"),
+            }
+        }
+    }
+
+    /// Rejoins the multicast group whenever `monitor` reports a network change.
+    ///
+    /// Pass the same network monitor which makes the magic socket rebind, so announcements
+    /// keep working on the same networks as the node itself.
+    pub async fn follow_network_changes(
+        self: &Arc<Self>,
+        monitor: &netmon::Monitor,
+    ) -> Result<netmon::CallbackToken> {
+        let this = Arc::downgrade(self);
+        monitor
+            .subscribe(move |_is_major| {
+                let this = this.clone();
+                async move {
+                    if let Some(this) = this.upgrade() {
+                        this.rejoin().await;
+                    }
+                }
+                .boxed()
+            })
+            .await
+    }
+
+    /// Announces the node in `ticket` to the group.
+    pub async fn announce(&self, ticket: &NodeTicket) -> Result<()> {
+        let msg = encode_announcement(ticket);
+        self.socket
+            .send_to(&msg, SocketAddr::V4(self.group))
+            .await
+            .context("send announcement")?;
+        Ok(())
+    }
+
+    /// Receives the next announcement, with the address it was sent from.
+    ///
+    /// Includes our own announcements.  Invalid packets on the group are skipped.
+    pub async fn recv(&self) -> Result<(NodeTicket, SocketAddr)> {
+        let mut buf = vec![0u8; MAX_ANNOUNCEMENT_SIZE];
+        loop {
+            let (len, src) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .context("recv announcement")?;
+            match decode_announcement(&buf[..len]) {
+                Some(ticket) => return Ok((ticket, src)),
+                None => trace!(%src, len, "ignoring invalid announcement"),
+            }
+        }
+    }
+}
+
+fn encode_announcement(ticket: &NodeTicket) -> Vec<u8> {
+    let mut msg = MAGIC.to_vec();
+    msg.extend_from_slice(&ticket.to_bytes());
+    msg
+}
+
+fn decode_announcement(msg: &[u8]) -> Option<NodeTicket> {
+    let bytes = msg.strip_prefix(MAGIC)?;
+    NodeTicket::from_bytes(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::node_addr::NodeAddr;
+
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_announcement_roundtrip() {
+        let node_id = SecretKey::generate().public();
+        let addr =
+            NodeAddr::new(node_id).with_direct_addresses(["192.168.1.2:1234".parse().unwrap()]);
+        let ticket = NodeTicket::new(addr).unwrap();
+        let msg = encode_announcement(&ticket);
+        assert_eq!(decode_announcement(&msg), Some(ticket));
+
+        assert_eq!(decode_announcement(b"hello"), None);
+        assert_eq!(decode_announcement(MAGIC), None);
+        assert_eq!(decode_announcement(&msg[..msg.len() - 1]), None);
+    }
+}
@@ -3,7 +3,7 @@
 use anyhow::Result;
 use futures::future::BoxFuture;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{mpsc, oneshot, watch},
     task::JoinHandle,
 };
 
@@ -25,6 +25,7 @@ mod windows;
 
 pub use self::actor::CallbackToken;
 use self::actor::{Actor, ActorMessage};
+use super::ip::LocalAddresses;
 
 /// Monitors networking interface and route changes.
 #[derive(Debug)]
@@ -32,6 +33,7 @@ pub struct Monitor {
     /// Task handle for the monitor task.
     handle: JoinHandle<()>,
     actor_tx: mpsc::Sender<ActorMessage>,
+    local_addrs: watch::Receiver<LocalAddresses>,
 }
 
 impl Drop for Monitor {
@@ -45,12 +47,17 @@ impl Monitor {
     pub async fn new() -> Result<Self> {
         let actor = Actor::new().await?;
         let actor_tx = actor.subscribe();
+        let local_addrs = actor.local_addresses();
 
         let handle = tokio::task::spawn(async move {
             actor.run().await;
         });
 
-        Ok(Monitor { handle, actor_tx })
+        Ok(Monitor {
+            handle,
+            actor_tx,
+            local_addrs,
+        })
     }
 
     /// Subscribe to network changes.
@@ -76,6 +83,14 @@ impl Monitor {
         Ok(())
     }
 
+    /// Watches the IP addresses of the local interfaces.
+    ///
+    /// The addresses are enumerated when the OS reports a change, reading them does not
+    /// enumerate the interfaces.
+    pub fn local_addresses(&self) -> watch::Receiver<LocalAddresses> {
+        self.local_addrs.clone()
+    }
+
     /// Potential change detected outside
     pub async fn network_change(&self) -> Result<()> {
         self.actor_tx.send(ActorMessage::NetworkChange).await?;
@@ -6,7 +6,7 @@ use std::{
 
 use anyhow::Result;
 use futures::future::BoxFuture;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{debug, info, trace, warn};
 
 #[cfg(target_os = "android")]
@@ -28,7 +28,7 @@ use os::{is_interesting_interface, RouteMonitor};
 
 use crate::net::{
     interfaces::{IpNet, State},
-    ip::is_link_local,
+    ip::{is_link_local, LocalAddresses},
 };
 
 /// The message sent by the OS specific monitors.
@@ -52,6 +52,10 @@ const ACTOR_CHAN_CAPACITY: usize = 16;
 pub(super) struct Actor {
     /// Latest known interface state.
     interface_state: State,
+    /// The addresses of the interfaces as of the latest change.
+    ///
+    /// Unlike `interface_state` this is updated on every change, including minor ones.
+    local_addrs: watch::Sender<LocalAddresses>,
     /// Latest observed wall time.
     wall_time: Instant,
     /// OS specific monitor.
@@ -81,6 +85,7 @@ pub(super) enum ActorMessage {
 impl Actor {
     pub(super) async fn new() -> Result<Self> {
         let interface_state = State::new().await;
+        let (local_addrs, _) = watch::channel(LocalAddresses::from_state(&interface_state));
         let wall_time = Instant::now();
 
         // Use flume channels, as tokio::mpsc is not safe to use across ffi boundaries.
@@ -90,6 +95,7 @@ impl Actor {
 
         Ok(Actor {
             interface_state,
+            local_addrs,
             wall_time,
             route_monitor,
             mon_receiver,
@@ -104,6 +110,10 @@ impl Actor {
         self.actor_sender.clone()
     }
 
+    pub(super) fn local_addresses(&self) -> watch::Receiver<LocalAddresses> {
+        self.local_addrs.subscribe()
+    }
+
     pub(super) async fn run(mut self) {
         const DEBOUNCE: Duration = Duration::from_millis(250);
 
@@ -169,6 +179,16 @@ impl Actor {
         let new_state = State::new().await;
         let old_state = &self.interface_state;
 
+        let local_addrs = LocalAddresses::from_state(&new_state);
+        self.local_addrs.send_if_modified(|addrs| {
+            if *addrs == local_addrs {
+                return false;
+            }
+            debug!(?local_addrs, "local addresses changed");
+            *addrs = local_addrs;
+            true
+        });
+
         // No major changes, continue on
         if !time_jumped && old_state == &new_state {
             debug!("no changes detected");
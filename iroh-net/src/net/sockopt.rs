@@ -0,0 +1,106 @@
+//! Options applied to every socket of a node, for deployments with policy routing or several
+//! uplinks.
+
+use std::io;
+
+use super::IpFamily;
+
+/// Options set on the UDP sockets of the magic socket and the TCP connections to relays.
+///
+/// They are also set on the sockets bound later on, e.g. when the IPv6 socket is bound late
+/// or when hopping ports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// The firewall mark (`SO_MARK`), used by policy routing.
+    ///
+    /// Only supported on Linux and Android, and requires `CAP_NET_ADMIN`.
+    pub fwmark: Option<u32>,
+    /// The name of the network interface all traffic is pinned to.
+    ///
+    /// Uses `SO_BINDTODEVICE` on Linux and Android, which requires `CAP_NET_RAW` before
+    /// Linux 5.7, and `IP_BOUND_IF` on macOS and iOS.
+    pub bind_device: Option<String>,
+}
+
+impl SocketOptions {
+    /// Sets the options on a socket of the given family, before it is bound or connected.
+    ///
+    /// Fails if an option is set which is not supported on this platform.
+    pub(crate) fn apply(&self, socket: socket2::SockRef<'_>, family: IpFamily) -> io::Result<()> {
+        if let Some(mark) = self.fwmark {
+            set_fwmark(&socket, mark)?;
+        }
+        if let Some(ref device) = self.bind_device {
+            bind_device(&socket, device, family)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_fwmark(socket: &socket2::SockRef<'_>, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_fwmark(_socket: &socket2::SockRef<'_>, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "firewall marks are only supported on Linux",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &socket2::SockRef<'_>, device: &str, _family: IpFamily) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn bind_device(socket: &socket2::SockRef<'_>, device: &str, family: IpFamily) -> io::Result<()> {
+    let name = std::ffi::CString::new(device)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    // SAFETY: `name` is a valid nul terminated string.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    let index = std::num::NonZeroU32::new(index).ok_or_else(io::Error::last_os_error)?;
+    match family {
+        IpFamily::V4 => socket.bind_device_by_index_v4(Some(index)),
+        IpFamily::V6 => socket.bind_device_by_index_v6(Some(index)),
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn bind_device(_socket: &socket2::SockRef<'_>, _device: &str, _family: IpFamily) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_none() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        SocketOptions::default()
+            .apply((&socket).into(), IpFamily::V4)
+            .unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_bind_unknown_device() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let opts = SocketOptions {
+            bind_device: Some("iroh-no-such-if".into()),
+            ..Default::default()
+        };
+        assert!(opts.apply((&socket).into(), IpFamily::V4).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use std::net::SocketAddr;
 use anyhow::{ensure, Context, Result};
 use tracing::warn;
 
-use super::IpFamily;
+use super::{IpFamily, SocketOptions};
 
 /// Wrapper around a tokio UDP socket that handles the fact that
 /// on drop `libc::close` can block for UDP sockets.
@@ -37,21 +37,32 @@ impl UdpSocket {
     /// Bind to the given port only on localhost.
     pub fn bind_local(network: IpFamily, port: u16) -> Result<Self> {
         let addr = SocketAddr::new(network.local_addr(), port);
-        Self::bind_raw(addr, true).with_context(|| format!("{addr:?}"))
+        Self::bind_raw(addr, true, &SocketOptions::default()).with_context(|| format!("{addr:?}"))
     }
 
     /// Bind to the given port and listen on all interfaces.
     pub fn bind(network: IpFamily, port: u16) -> Result<Self> {
+        Self::bind_with_options(network, port, &SocketOptions::default())
+    }
+
+    /// Bind to the given port and listen on all interfaces, setting the [`SocketOptions`].
+    ///
+    /// Binding fails if the options can not be set on this platform.
+    pub fn bind_with_options(network: IpFamily, port: u16, opts: &SocketOptions) -> Result<Self> {
         let addr = SocketAddr::new(network.unspecified_addr(), port);
-        Self::bind_raw(addr, true).with_context(|| format!("{addr:?}"))
+        Self::bind_raw(addr, true, opts).with_context(|| format!("{addr:?}"))
     }
 
     /// Bind to any provided [`SocketAddr`]. Does not prepare for using the socket as QUIC socket.
     pub fn bind_full(addr: impl Into<SocketAddr>) -> Result<Self> {
-        Self::bind_raw(addr, false)
+        Self::bind_raw(addr, false, &SocketOptions::default())
     }
 
-    fn bind_raw(addr: impl Into<SocketAddr>, prepare_for_quinn: bool) -> Result<Self> {
+    fn bind_raw(
+        addr: impl Into<SocketAddr>,
+        prepare_for_quinn: bool,
+        opts: &SocketOptions,
+    ) -> Result<Self> {
         let addr = addr.into();
         let network = IpFamily::from(addr.ip());
         let socket = socket2::Socket::new(
@@ -73,6 +84,8 @@ impl UdpSocket {
                 SOCKET_BUFFER_SIZE, err
             );
         }
+        opts.apply((&socket).into(), network)
+            .context("socket options")?;
         if network == IpFamily::V6 {
             // Avoid dualstack
             socket.set_only_v6(true).context("only IPv6")?;
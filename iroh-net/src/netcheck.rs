@@ -9,16 +9,18 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::{self, Debug};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context as _, Result};
 use bytes::Bytes;
 use iroh_metrics::inc;
-use tokio::sync::{self, mpsc, oneshot};
+use tokio::sync::{self, mpsc, oneshot, watch};
 use tokio::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info_span, trace, warn, Instrument};
 
+use crate::defaults::DEFAULT_RELAY_STUN_PORT;
 use crate::dns::DnsResolver;
 use crate::net::ip::to_canonical;
 use crate::net::{IpFamily, UdpSocket};
@@ -74,6 +76,12 @@ pub struct Report {
     /// Note that we don't really expect this to happen and are merely logging this if
     /// detecting rather than using it.  For now.
     pub mapping_varies_by_dest_ipv6: Option<bool>,
+    /// Whether we appear to be behind a carrier-grade NAT (on IPv4), `None` if unknown.
+    ///
+    /// This is set when a STUN server reports a global address from the shared address
+    /// space (`100.64.0.0/10`) or when different STUN servers report different global IPs,
+    /// which indicates more than one layer of NAT.
+    pub cgnat: Option<bool>,
     /// Whether the router supports communicating between two local devices through the NATted
     /// public IP address (on IPv4).
     pub hair_pinning: Option<bool>,
@@ -157,6 +165,74 @@ impl RelayLatencies {
     }
 }
 
+/// Progress of a report, see [`Client::watch_progress`].
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ReportProgress {
+    /// The number of relays probed by the report.
+    pub relays_total: usize,
+    /// The number of relays which answered so far.
+    pub relays_done: usize,
+    /// The fastest relay so far, with its latency.
+    pub fastest: Option<(RelayUrl, Duration)>,
+    /// Whether the report is finished, successfully or not.
+    pub done: bool,
+}
+
+/// A plain STUN server, probed for our public addresses in addition to the relay nodes.
+///
+/// Unlike STUN-only nodes in the [`RelayMap`], these are not used to measure latencies and
+/// are never picked as preferred relay.  This allows using public STUN infrastructure
+/// without pretending it is a relay.
+///
+/// Parsed from `host:port`, where the port defaults to the standard STUN port.  IPv6
+/// addresses need to be in brackets, e.g. `[2001:db8::1]:3478`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StunServer {
+    host: url::Host,
+    port: u16,
+}
+
+impl StunServer {
+    /// Creates a STUN server for `host`, which is a domain name or IP address.
+    pub fn new(host: &str, port: u16) -> Result<Self> {
+        let host = url::Host::parse(host).with_context(|| format!("invalid host {host:?}"))?;
+        Ok(Self { host, port })
+    }
+
+    /// The domain name or IP address of the server.
+    pub fn host(&self) -> &url::Host {
+        &self.host
+    }
+
+    /// The UDP port of the server.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl FromStr for StunServer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Only split off a port after the closing bracket of an IPv6 address.
+        match s.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("invalid port {port:?}"))?;
+                Self::new(host, port)
+            }
+            _ => Self::new(s, DEFAULT_RELAY_STUN_PORT),
+        }
+    }
+}
+
+impl fmt::Display for StunServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
 /// Client to run netchecks.
 ///
 /// Creating this creates a netcheck actor which runs in the background.  Most of the time
@@ -175,6 +251,8 @@ pub struct Client {
     /// If all senders are dropped, in other words all clones of this struct are dropped,
     /// the actor will terminate.
     addr: Addr,
+    /// The progress of the current or last report.
+    progress: watch::Receiver<Option<ReportProgress>>,
     /// Ensures the actor is terminated when the client is dropped.
     _drop_guard: Arc<CancelOnDrop>,
 }
@@ -210,11 +288,13 @@ impl Client {
     pub fn new(port_mapper: Option<portmapper::Client>, dns_resolver: DnsResolver) -> Result<Self> {
         let mut actor = Actor::new(port_mapper, dns_resolver)?;
         let addr = actor.addr();
+        let progress = actor.progress.subscribe();
         let task =
             tokio::spawn(async move { actor.run().await }.instrument(info_span!("netcheck.actor")));
         let drop_guard = CancelOnDrop::new("netcheck actor", task.abort_handle());
         Ok(Client {
             addr,
+            progress,
             _drop_guard: Arc::new(drop_guard),
         })
     }
@@ -242,6 +322,23 @@ impl Client {
         }
     }
 
+    /// Sets the plain STUN servers probed in addition to the relay nodes.
+    ///
+    /// Applies from the next report on.
+    pub async fn set_stun_servers(&self, servers: Vec<StunServer>) -> Result<()> {
+        self.addr.send(Message::SetStunServers(servers)).await?;
+        Ok(())
+    }
+
+    /// Watches the progress of the reports.
+    ///
+    /// Updated whenever a relay answers, so UIs can show how finding the nearest relay
+    /// progresses instead of waiting for the report.  `None` until the first report
+    /// starts, after which it holds the progress of the current or last report.
+    pub fn watch_progress(&self) -> watch::Receiver<Option<ReportProgress>> {
+        self.progress.clone()
+    }
+
     /// Runs a netcheck, returning the report.
     ///
     /// It may not be called concurrently with itself, `&mut self` takes care of that.
@@ -341,6 +438,8 @@ pub(crate) enum Message {
     /// The sender is signalled once the STUN packet is registered with the actor and will
     /// correctly accept the STUN response.
     InFlightStun(Inflight, oneshot::Sender<()>),
+    /// Set the plain STUN servers, see [`Client::set_stun_servers`].
+    SetStunServers(Vec<StunServer>),
 }
 
 /// Sender to the [`Actor`].
@@ -400,6 +499,8 @@ struct Actor {
     /// The port mapper is responsible for talking to routers via UPnP and the like to try
     /// and open ports.
     port_mapper: Option<portmapper::Client>,
+    /// The plain STUN servers to probe in addition to the relay nodes.
+    stun_servers: Vec<StunServer>,
 
     // Actor state.
     /// Information about the currently in-flight STUN requests.
@@ -411,6 +512,8 @@ struct Actor {
 
     /// The DNS resolver to use for probes that need to perform DNS lookups
     dns_resolver: DnsResolver,
+    /// Publishes the progress of the reports, see [`Client::watch_progress`].
+    progress: watch::Sender<Option<ReportProgress>>,
 }
 
 impl Actor {
@@ -426,9 +529,11 @@ impl Actor {
             sender,
             reports: Default::default(),
             port_mapper,
+            stun_servers: Vec::new(),
             in_flight_stun_requests: Default::default(),
             current_report_run: None,
             dns_resolver,
+            progress: watch::channel(None).0,
         })
     }
 
@@ -468,6 +573,10 @@ impl Actor {
                 Message::InFlightStun(inflight, response_tx) => {
                     self.handle_in_flight_stun(inflight, response_tx);
                 }
+                Message::SetStunServers(servers) => {
+                    debug!(?servers, "STUN servers set");
+                    self.stun_servers = servers;
+                }
             }
         }
     }
@@ -528,9 +637,11 @@ impl Actor {
             self.reports.last.clone(),
             self.port_mapper.clone(),
             relay_map,
+            self.stun_servers.clone(),
             stun_sock_v4,
             stun_sock_v6,
             self.dns_resolver.clone(),
+            self.progress.clone(),
         );
 
         self.current_report_run = Some(ReportRun {
@@ -543,6 +654,7 @@ impl Actor {
     fn handle_report_ready(&mut self, report: Box<Report>) {
         let report = self.finish_and_store_report(*report);
         self.in_flight_stun_requests.clear();
+        self.finish_progress();
         if let Some(ReportRun { report_tx, .. }) = self.current_report_run.take() {
             report_tx.send(Ok(report)).ok();
         }
@@ -550,11 +662,21 @@ impl Actor {
 
     fn handle_report_aborted(&mut self) {
         self.in_flight_stun_requests.clear();
+        self.finish_progress();
         if let Some(ReportRun { report_tx, .. }) = self.current_report_run.take() {
             report_tx.send(Err(anyhow!("report aborted"))).ok();
         }
     }
 
+    /// Marks the progress of the current report as done.
+    fn finish_progress(&self) {
+        self.progress.send_modify(|progress| {
+            if let Some(progress) = progress {
+                progress.done = true;
+            }
+        });
+    }
+
     /// Handles [`Message::StunPacket`].
     ///
     /// If there are currently no in-flight stun requests registered this is dropped,
@@ -801,6 +923,8 @@ mod tests {
         let resolver = crate::dns::default_resolver();
         let mut client = Client::new(None, resolver.clone())?;
         let dm = stun::test::relay_map_of([stun_addr].into_iter());
+        let progress = client.watch_progress();
+        assert_eq!(*progress.borrow(), None);
 
         // Note that the ProbePlan will change with each iteration.
         for i in 0..5 {
@@ -821,6 +945,15 @@ mod tests {
             );
             assert!(r.global_v4.is_some(), "expected globalV4 set");
             assert!(r.preferred_relay.is_some(),);
+
+            let report_progress = progress.borrow().clone().expect("progress reported");
+            assert!(report_progress.done);
+            assert_eq!(report_progress.relays_total, 1);
+            assert_eq!(report_progress.relays_done, 1);
+            assert_eq!(
+                report_progress.fastest.map(|(url, _)| url),
+                r.preferred_relay.clone()
+            );
         }
 
         assert!(
@@ -844,6 +977,7 @@ mod tests {
             url: url.clone(),
             stun_only: true,
             stun_port: DEFAULT_RELAY_STUN_PORT,
+            metadata: Default::default(),
         }])
         .expect("hardcoded");
 
@@ -883,6 +1017,59 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_stun_servers() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let (stun_addr, stun_stats, _cleanup_guard) =
+            stun::test::serve("0.0.0.0".parse().unwrap()).await?;
+
+        let resolver = crate::dns::default_resolver();
+        let mut client = Client::new(None, resolver.clone())?;
+        client
+            .set_stun_servers(vec![stun_addr.to_string().parse()?])
+            .await?;
+
+        // The STUN server is probed without any relays.
+        let r = client.get_report(RelayMap::empty(), None, None).await?;
+        assert!(r.udp, "want UDP");
+        assert_eq!(
+            r.global_v4.map(|addr| *addr.ip()),
+            Some(Ipv4Addr::LOCALHOST)
+        );
+        // It is not a relay, so it has no latencies and is not preferred.
+        assert!(r.relay_latency.is_empty());
+        assert!(r.preferred_relay.is_none());
+        assert!(stun_stats.total().await >= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stun_server_parse() {
+        let server: StunServer = "stun.example.com:19302".parse().unwrap();
+        assert_eq!(
+            server.host(),
+            &url::Host::Domain("stun.example.com".to_string())
+        );
+        assert_eq!(server.port(), 19302);
+
+        let server: StunServer = "stun.example.com".parse().unwrap();
+        assert_eq!(server.port(), DEFAULT_RELAY_STUN_PORT);
+
+        let server: StunServer = "[2001:db8::1]:3479".parse().unwrap();
+        assert_eq!(
+            server.host(),
+            &url::Host::<String>::Ipv6("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(server.port(), 3479);
+        assert_eq!(server.to_string(), "[2001:db8::1]:3479");
+
+        let server: StunServer = "[2001:db8::1]".parse().unwrap();
+        assert_eq!(server.port(), DEFAULT_RELAY_STUN_PORT);
+
+        assert!("stun.example.com:port".parse::<StunServer>().is_err());
+        assert!("2001:db8::1".parse::<StunServer>().is_err());
+    }
+
     #[tokio::test]
     async fn test_udp_blocked() -> Result<()> {
         let _guard = iroh_test::logging::setup();
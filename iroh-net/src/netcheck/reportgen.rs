@@ -11,13 +11,15 @@
 //! - Creates captive portal detection future.
 //! - Creates Probe Set futures.
 //!   - These send messages to the reportgen actor.
+//! - Creates probes for the plain STUN servers.
 //! - Loops driving the futures and handling actor messages:
 //!   - Disables futures as they are completed or aborted.
 //!   - Stop if there are no outstanding tasks/futures, or on timeout.
 //! - Sends the completed report to the netcheck actor.
 
+use std::collections::BTreeMap;
 use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
@@ -25,7 +27,7 @@ use std::time::Duration;
 use anyhow::{anyhow, bail, Context, Result};
 use iroh_metrics::inc;
 use rand::seq::IteratorRandom;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinSet;
 use tokio::time::{self, Instant};
 use tracing::{debug, debug_span, error, info_span, trace, warn, Instrument, Span};
@@ -35,8 +37,8 @@ use crate::defaults::DEFAULT_RELAY_STUN_PORT;
 use crate::dns::{lookup_ipv4, lookup_ipv6, DnsResolver};
 use crate::net::interfaces;
 use crate::net::ip;
-use crate::net::UdpSocket;
-use crate::netcheck::{self, Report};
+use crate::net::{IpFamily, UdpSocket};
+use crate::netcheck::{self, Report, ReportProgress, StunServer};
 use crate::ping::{PingError, Pinger};
 use crate::relay::{RelayMap, RelayNode, RelayUrl};
 use crate::util::{CancelOnDrop, MaybeFuture};
@@ -71,8 +73,22 @@ const CAPTIVE_PORTAL_TIMEOUT: Duration = Duration::from_secs(2);
 
 const ENOUGH_NODES: usize = 3;
 
+/// How much slower than the fastest relay all other relays must be to stop probing early.
+///
+/// Once every other relay answered, or did not answer for, longer than this factor of the
+/// fastest latency plus [`EARLY_EXIT_SLACK`], the fastest relay is known and the remaining
+/// probes are aborted, see [`fastest_relay`].
+const EARLY_EXIT_FACTOR: f64 = 1.5;
+
+/// The absolute margin added to [`EARLY_EXIT_FACTOR`], so jitter on fast networks does not
+/// decide the fastest relay.
+const EARLY_EXIT_SLACK: Duration = Duration::from_millis(10);
+
 const DNS_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// How long to wait for the response of a plain STUN server before retrying once.
+const STUN_SERVER_RETRY_DELAY: Duration = Duration::from_millis(500);
+
 /// Holds the state for a single invocation of [`netcheck::Client::get_report`].
 ///
 /// Dropping this will cancel the actor and stop the report generation.
@@ -87,14 +103,17 @@ impl Client {
     ///
     /// The actor starts running immediately and only generates a single report, after which
     /// it shuts down.  Dropping this handle will abort the actor.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         netcheck: netcheck::Addr,
         last_report: Option<Arc<Report>>,
         port_mapper: Option<portmapper::Client>,
         relay_map: RelayMap,
+        stun_servers: Vec<StunServer>,
         stun_sock4: Option<Arc<UdpSocket>>,
         stun_sock6: Option<Arc<UdpSocket>>,
         dns_resolver: DnsResolver,
+        progress: watch::Sender<Option<ReportProgress>>,
     ) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
@@ -107,12 +126,17 @@ impl Client {
             last_report,
             port_mapper,
             relay_map,
+            stun_servers,
             stun_sock4,
             stun_sock6,
             report: Report::default(),
             hairpin_actor: hairpin::Client::new(netcheck, addr),
             outstanding_tasks: OutstandingTasks::default(),
             dns_resolver,
+            probes_started: Instant::now(),
+            stun_starts: BTreeMap::new(),
+            expect_ipv6: false,
+            progress,
         };
         let task = tokio::spawn(
             async move { actor.run().await }.instrument(info_span!("reportgen.actor")),
@@ -157,6 +181,8 @@ enum Message {
     ProbeWouldHelp(Probe, Arc<RelayNode>, oneshot::Sender<bool>),
     /// Abort all remaining probes.
     AbortProbes,
+    /// Check again whether the fastest relay is known, see [`Actor::maybe_exit_early`].
+    CheckEarlyExit,
 }
 
 /// The reportstate actor.
@@ -178,6 +204,8 @@ struct Actor {
     port_mapper: Option<portmapper::Client>,
     /// The relay configuration.
     relay_map: RelayMap,
+    /// The plain STUN servers to probe in addition to the relay nodes.
+    stun_servers: Vec<StunServer>,
     /// Socket to send IPv4 STUN requests from.
     stun_sock4: Option<Arc<UdpSocket>>,
     /// Socket so send IPv6 STUN requests from.
@@ -194,6 +222,14 @@ struct Actor {
     outstanding_tasks: OutstandingTasks,
     /// The DNS resolver to use for probes that need to resolve DNS records
     dns_resolver: DnsResolver,
+    /// When the probes of the [`ProbePlan`] were started.
+    probes_started: Instant,
+    /// When the first STUN probe to each relay starts, relative to `probes_started`.
+    stun_starts: BTreeMap<RelayUrl, Duration>,
+    /// Whether an IPv6 result is needed before stopping the probes early.
+    expect_ipv6: bool,
+    /// Publishes the progress of the report, see [`netcheck::Client::watch_progress`].
+    progress: watch::Sender<Option<ReportProgress>>,
 }
 
 impl Actor {
@@ -239,6 +275,7 @@ impl Actor {
         let mut port_mapping = self.prepare_portmapper_task();
         let mut captive_task = self.prepare_captive_portal_task();
         let mut probes = self.spawn_probes_task().await?;
+        let mut stun_server_probes = self.spawn_stun_server_probes();
 
         let total_timer = tokio::time::sleep(OVERALL_REPORT_TIMEOUT);
         tokio::pin!(total_timer);
@@ -251,6 +288,10 @@ impl Actor {
                 debug!("all tasks done");
                 break;
             }
+            if !self.outstanding_tasks.probes && !probes.is_empty() {
+                // Also keeps the probes of waves which did not start yet from being sent.
+                probes.abort_all();
+            }
             tokio::select! {
                 biased;
                 _ = &mut total_timer => {
@@ -261,6 +302,7 @@ impl Actor {
                 _ = &mut probe_timer => {
                     warn!("tick: probes timed out");
                     probes.abort_all();
+                    stun_server_probes.abort_all();
                     self.handle_abort_probes();
                 }
 
@@ -288,6 +330,17 @@ impl Actor {
                     trace!("tick: probes handled");
                 }
 
+                // Check for probes of the plain STUN servers finishing.
+                set_result = stun_server_probes.join_next(), if self.outstanding_tasks.stun_servers => {
+                    match set_result {
+                        Some(Ok(Ok(addr))) => self.handle_stun_server_report(addr),
+                        Some(Ok(Err(err))) => debug!("STUN server probe failed: {err:#}"),
+                        Some(Err(err)) if err.is_cancelled() => (),
+                        Some(Err(err)) => warn!("STUN server probe task error: {err:?}"),
+                        None => self.outstanding_tasks.stun_servers = false,
+                    }
+                }
+
                 // Drive the captive task.
                 found = &mut captive_task, if self.outstanding_tasks.captive_task => {
                     trace!("tick: captive portal task done");
@@ -344,20 +397,17 @@ impl Actor {
             Message::AbortProbes => {
                 self.handle_abort_probes();
             }
+            Message::CheckEarlyExit => {
+                self.maybe_exit_early();
+            }
         }
     }
 
     fn handle_probe_report(&mut self, probe_report: ProbeReport) {
         debug!(?probe_report, "finished probe");
         update_report(&mut self.report, probe_report);
-
-        // When we discover the first IPv4 address we want to start the hairpin actor.
-        if let Some(ref addr) = self.report.global_v4 {
-            if !self.hairpin_actor.has_started() {
-                self.hairpin_actor.start_check(*addr);
-                self.outstanding_tasks.hairpin = true;
-            }
-        }
+        self.maybe_start_hairpin();
+        self.update_progress();
 
         // Once we've heard from enough relay servers (3), start a timer to give up on the other
         // probes. The timer's duration is a function of whether this is our initial full
@@ -390,6 +440,80 @@ impl Actor {
                 .instrument(Span::current()),
             );
         }
+
+        self.maybe_exit_early();
+    }
+
+    /// Publishes the relays which answered so far.
+    fn update_progress(&self) {
+        let latencies = &self.report.relay_latency;
+        let fastest = latencies
+            .iter()
+            .min_by_key(|(_, latency)| *latency)
+            .map(|(url, latency)| (url.clone(), latency));
+        self.progress.send_modify(|progress| {
+            if let Some(progress) = progress {
+                progress.relays_done = latencies.len();
+                progress.fastest = fastest;
+            }
+        });
+    }
+
+    /// Aborts the remaining probes once the fastest relay is known.
+    ///
+    /// The report also needs to tell whether our IPv4 mapping varies by destination and,
+    /// unless IPv6 did not work last time, whether IPv6 works, otherwise the probes continue
+    /// until enough relays answered.
+    fn maybe_exit_early(&mut self) {
+        if !self.outstanding_tasks.probes || self.relay_map.len() < 2 {
+            return;
+        }
+        if self.report.ipv4 && self.report.mapping_varies_by_dest_ip.is_none() {
+            return;
+        }
+        if self.expect_ipv6 && self.report.relay_v6_latency.is_empty() {
+            return;
+        }
+        let elapsed = self.probes_started.elapsed();
+        match fastest_relay(&self.report.relay_latency, &self.stun_starts, elapsed) {
+            FastestRelay::Unknown => (),
+            FastestRelay::KnownAfter(wait) => {
+                trace!(
+                    ?wait,
+                    "fastest relay known unless a slower relay answers soon"
+                );
+                let reportcheck = self.addr();
+                tokio::spawn(
+                    async move {
+                        time::sleep(wait).await;
+                        reportcheck.send(Message::CheckEarlyExit).await.ok();
+                    }
+                    .instrument(Span::current()),
+                );
+            }
+            FastestRelay::Known(url) => {
+                debug!(%url, ?elapsed, "fastest relay known, aborting remaining probes");
+                inc!(NetcheckMetrics, reports_early_exit);
+                self.handle_abort_probes();
+            }
+        }
+    }
+
+    /// Handles the public address `addr` a plain STUN server reported.
+    fn handle_stun_server_report(&mut self, addr: SocketAddr) {
+        debug!(%addr, "finished STUN server probe");
+        update_report_stun_server(&mut self.report, addr);
+        self.maybe_start_hairpin();
+    }
+
+    /// Starts the hairpin actor once we discovered the first IPv4 address.
+    fn maybe_start_hairpin(&mut self) {
+        if let Some(ref addr) = self.report.global_v4 {
+            if !self.hairpin_actor.has_started() {
+                self.hairpin_actor.start_check(*addr);
+                self.outstanding_tasks.hairpin = true;
+            }
+        }
     }
 
     /// Whether running this probe would still improve our report.
@@ -537,6 +661,14 @@ impl Actor {
             None => ProbePlan::initial(&self.relay_map, &if_state),
         };
         trace!(%plan, "probe plan");
+        self.probes_started = Instant::now();
+        self.stun_starts = plan.stun_starts();
+        self.expect_ipv6 = plan.has_proto(ProbeProto::StunIpv6)
+            && self.last_report.as_ref().map_or(true, |report| report.ipv6);
+        self.progress.send_replace(Some(ReportProgress {
+            relays_total: plan.relay_count(),
+            ..Default::default()
+        }));
 
         // The pinger is created here so that any sockets that might be bound for it are
         // shared between the probes that use it.  It binds sockets lazily, so we can always
@@ -604,6 +736,83 @@ impl Actor {
 
         Ok(probes)
     }
+
+    /// Creates a probe for every plain STUN server and address family we have a socket for.
+    ///
+    /// Each probe resolves to the public address the server reports.
+    fn spawn_stun_server_probes(&mut self) -> JoinSet<Result<SocketAddr>> {
+        let mut probes = JoinSet::default();
+        for server in &self.stun_servers {
+            let socks = [
+                (IpFamily::V4, self.stun_sock4.clone()),
+                (IpFamily::V6, self.stun_sock6.clone()),
+            ];
+            for (family, sock) in socks {
+                let Some(sock) = sock else {
+                    continue;
+                };
+                let span = debug_span!("stun_server_probe", %server, ?family);
+                let server = server.clone();
+                let netcheck = self.netcheck.clone();
+                let dns_resolver = self.dns_resolver.clone();
+                probes.spawn(
+                    async move {
+                        let addr = get_stun_server_addr(&dns_resolver, &server, family).await?;
+                        run_stun_server_probe(&sock, addr, netcheck).await
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+        self.outstanding_tasks.stun_servers = !probes.is_empty();
+        probes
+    }
+}
+
+/// Whether the fastest relay is known, see [`fastest_relay`].
+#[derive(Debug, PartialEq, Eq)]
+enum FastestRelay {
+    /// Another relay answered almost as fast, or no relay answered yet.
+    Unknown,
+    /// The fastest relay is known, unless a relay which did not answer yet answers within
+    /// this time.
+    KnownAfter(Duration),
+    /// The fastest relay is known.
+    Known(RelayUrl),
+}
+
+/// Determines whether the fastest relay is clearly separated from all other relays.
+///
+/// All other relays need to be slower than [`EARLY_EXIT_FACTOR`] times the fastest latency
+/// plus [`EARLY_EXIT_SLACK`].  A relay which did not answer yet is at least as slow as the
+/// time since its first STUN probe was sent, according to `stun_starts` and the `elapsed`
+/// time since the probes started.  Relays of a later wave whose first probe was not sent
+/// yet do not count, so once the fastest relay is known their probes are aborted unsent.
+fn fastest_relay(
+    latencies: &netcheck::RelayLatencies,
+    stun_starts: &BTreeMap<RelayUrl, Duration>,
+    elapsed: Duration,
+) -> FastestRelay {
+    let Some((fastest, latency)) = latencies.iter().min_by_key(|(_, latency)| *latency) else {
+        return FastestRelay::Unknown;
+    };
+    let bound = latency.mul_f64(EARLY_EXIT_FACTOR) + EARLY_EXIT_SLACK;
+    if latencies
+        .iter()
+        .any(|(url, latency)| url != fastest && latency < bound)
+    {
+        return FastestRelay::Unknown;
+    }
+    let wait = stun_starts
+        .iter()
+        .filter(|(url, start)| **start <= elapsed && latencies.get(url).is_none())
+        .map(|(_, start)| bound.saturating_sub(elapsed.saturating_sub(*start)))
+        .max()
+        .unwrap_or_default();
+    match wait.is_zero() {
+        true => FastestRelay::Known(fastest.clone()),
+        false => FastestRelay::KnownAfter(wait),
+    }
 }
 
 /// Tasks on which the reportgen [`Actor`] is still waiting.
@@ -613,6 +822,7 @@ impl Actor {
 #[derive(Debug, Default)]
 struct OutstandingTasks {
     probes: bool,
+    stun_servers: bool,
     port_mapper: bool,
     captive_task: bool,
     hairpin: bool,
@@ -620,7 +830,7 @@ struct OutstandingTasks {
 
 impl OutstandingTasks {
     fn all_done(&self) -> bool {
-        !(self.probes || self.port_mapper || self.captive_task || self.hairpin)
+        !(self.probes || self.stun_servers || self.port_mapper || self.captive_task || self.hairpin)
     }
 }
 
@@ -853,6 +1063,49 @@ async fn run_stun_probe(
     }
 }
 
+/// Runs a STUN probe against a plain STUN server, returning the address it reports.
+///
+/// The request is retransmitted once if there is no timely response.
+async fn run_stun_server_probe(
+    sock: &UdpSocket,
+    server_addr: SocketAddr,
+    netcheck: netcheck::Addr,
+) -> Result<SocketAddr> {
+    let txid = stun::TransactionId::default();
+    let req = stun::request(txid);
+
+    // Setup netcheck to give us back the incoming STUN response.
+    let (stun_tx, mut stun_rx) = oneshot::channel();
+    let (inflight_ready_tx, inflight_ready_rx) = oneshot::channel();
+    netcheck
+        .send(netcheck::Message::InFlightStun(
+            netcheck::Inflight {
+                txn: txid,
+                start: Instant::now(),
+                s: stun_tx,
+            },
+            inflight_ready_tx,
+        ))
+        .await?;
+    inflight_ready_rx.await?;
+
+    for attempt in 0..2 {
+        debug!(%server_addr, %txid, attempt, "sending STUN server probe");
+        sock.send_to(&req, server_addr)
+            .await
+            .context("failed to send STUN request")?;
+        match server_addr {
+            SocketAddr::V4(_) => inc!(NetcheckMetrics, stun_packets_sent_ipv4),
+            SocketAddr::V6(_) => inc!(NetcheckMetrics, stun_packets_sent_ipv6),
+        }
+        if let Ok(res) = time::timeout(STUN_SERVER_RETRY_DELAY, &mut stun_rx).await {
+            let (_latency, addr) = res?;
+            return Ok(addr);
+        }
+    }
+    bail!("no response from STUN server {server_addr}")
+}
+
 /// Reports whether or not we think the system is behind a
 /// captive portal, detected by making a request to a URL that we know should
 /// return a "204 No Content" response and checking if that's what we get.
@@ -980,6 +1233,34 @@ async fn get_relay_addr(
     }
 }
 
+/// Returns the address of a plain STUN server for the address family `family`.
+async fn get_stun_server_addr(
+    dns_resolver: &DnsResolver,
+    server: &StunServer,
+    family: IpFamily,
+) -> Result<SocketAddr> {
+    let ip: IpAddr = match (server.host(), family) {
+        (url::Host::Ipv4(ip), IpFamily::V4) => (*ip).into(),
+        (url::Host::Ipv6(ip), IpFamily::V6) => (*ip).into(),
+        (url::Host::Domain(hostname), IpFamily::V4) => {
+            lookup_ipv4(dns_resolver, hostname, DNS_TIMEOUT)
+                .await?
+                .first()
+                .copied()
+                .context("no IPv4 address for STUN server")?
+        }
+        (url::Host::Domain(hostname), IpFamily::V6) => {
+            lookup_ipv6(dns_resolver, hostname, DNS_TIMEOUT)
+                .await?
+                .first()
+                .copied()
+                .context("no IPv6 address for STUN server")?
+        }
+        _ => bail!("STUN server has no {family:?} address"),
+    };
+    Ok(SocketAddr::new(ip::to_canonical(ip), server.port()))
+}
+
 /// Runs an ICMP IPv4 or IPv6 probe.
 ///
 /// The `pinger` is passed in so the ping sockets are only bound once
@@ -1067,31 +1348,16 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
 
             match probe_report.addr {
                 Some(SocketAddr::V4(ipp)) => {
-                    report.ipv4 = true;
                     report
                         .relay_v4_latency
                         .update_relay(relay_node.url.clone(), latency);
-                    if report.global_v4.is_none() {
-                        report.global_v4 = Some(ipp);
-                    } else if report.global_v4 != Some(ipp) {
-                        report.mapping_varies_by_dest_ip = Some(true);
-                    } else if report.mapping_varies_by_dest_ip.is_none() {
-                        report.mapping_varies_by_dest_ip = Some(false);
-                    }
+                    update_global_v4(report, ipp);
                 }
                 Some(SocketAddr::V6(ipp)) => {
-                    report.ipv6 = true;
                     report
                         .relay_v6_latency
                         .update_relay(relay_node.url.clone(), latency);
-                    if report.global_v6.is_none() {
-                        report.global_v6 = Some(ipp);
-                    } else if report.global_v6 != Some(ipp) {
-                        report.mapping_varies_by_dest_ipv6 = Some(true);
-                        warn!("IPv6 Address detected by STUN varies by destination");
-                    } else if report.mapping_varies_by_dest_ipv6.is_none() {
-                        report.mapping_varies_by_dest_ipv6 = Some(false);
-                    }
+                    update_global_v6(report, ipp);
                 }
                 None => {
                     // If we are here we had a relay server latency reported from a STUN probe.
@@ -1113,14 +1379,128 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
         .or(probe_report.icmpv6);
 }
 
+/// Updates a netcheck [`Report`] with the public address a plain STUN server reported.
+///
+/// Plain STUN servers are no relays, so no latencies are recorded.
+fn update_report_stun_server(report: &mut Report, addr: SocketAddr) {
+    report.udp = true;
+    match addr {
+        SocketAddr::V4(ipp) => {
+            report.ipv4_can_send = true;
+            update_global_v4(report, ipp);
+        }
+        SocketAddr::V6(ipp) => {
+            report.ipv6_can_send = true;
+            update_global_v6(report, ipp);
+        }
+    }
+}
+
+/// Updates the report with a public IPv4 address reported by a STUN server.
+fn update_global_v4(report: &mut Report, ipp: SocketAddrV4) {
+    report.ipv4 = true;
+    // A different global IP from another STUN server means there is
+    // more than one layer of NAT between us and the internet.
+    let ip_varies = report.global_v4.is_some_and(|g| g.ip() != ipp.ip());
+    let cgnat = ip_varies || is_shared_address_space(ipp.ip());
+    if cgnat && report.cgnat != Some(true) {
+        debug!(global_v4 = %ipp, "likely behind a carrier-grade NAT");
+    }
+    report.cgnat = Some(report.cgnat.unwrap_or_default() || cgnat);
+    if report.global_v4.is_none() {
+        report.global_v4 = Some(ipp);
+    } else if report.global_v4 != Some(ipp) {
+        report.mapping_varies_by_dest_ip = Some(true);
+    } else if report.mapping_varies_by_dest_ip.is_none() {
+        report.mapping_varies_by_dest_ip = Some(false);
+    }
+}
+
+/// Updates the report with a public IPv6 address reported by a STUN server.
+fn update_global_v6(report: &mut Report, ipp: SocketAddrV6) {
+    report.ipv6 = true;
+    if report.global_v6.is_none() {
+        report.global_v6 = Some(ipp);
+    } else if report.global_v6 != Some(ipp) {
+        report.mapping_varies_by_dest_ipv6 = Some(true);
+        warn!("IPv6 Address detected by STUN varies by destination");
+    } else if report.mapping_varies_by_dest_ipv6.is_none() {
+        report.mapping_varies_by_dest_ipv6 = Some(false);
+    }
+}
+
+/// Whether the address is in the shared address space used by carrier-grade NATs.
+///
+/// This is `100.64.0.0/10` as defined by RFC 6598, a STUN server seeing us from such an
+/// address means our "global" address is still behind another NAT.
+fn is_shared_address_space(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && (octets[1] & 0b1100_0000) == 0b0100_0000
+}
+
 #[cfg(test)]
 mod tests {
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::net::Ipv6Addr;
 
     use super::*;
 
     use crate::defaults::{default_eu_relay_node, default_na_relay_node};
 
+    #[test]
+    fn test_fastest_relay() {
+        let eu: RelayUrl = default_eu_relay_node().url;
+        let na: RelayUrl = default_na_relay_node().url;
+        let later: RelayUrl = "https://later.example".parse().unwrap();
+        let starts: BTreeMap<_, _> = [
+            (eu.clone(), Duration::ZERO),
+            (na.clone(), Duration::ZERO),
+            // in a later wave, which is cancelled unless started before the decision
+            (later, Duration::from_millis(50)),
+        ]
+        .into_iter()
+        .collect();
+        let mut latencies = netcheck::RelayLatencies::new();
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(5)),
+            FastestRelay::Unknown
+        );
+
+        // The NA relay may still answer faster than 1.5 * 20ms + 10ms.
+        latencies.update_relay(eu.clone(), Duration::from_millis(20));
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(25)),
+            FastestRelay::KnownAfter(Duration::from_millis(15))
+        );
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(40)),
+            FastestRelay::Known(eu.clone())
+        );
+
+        // Too close to tell.
+        latencies.update_relay(na.clone(), Duration::from_millis(30));
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(40)),
+            FastestRelay::Unknown
+        );
+
+        let mut latencies = netcheck::RelayLatencies::new();
+        latencies.update_relay(eu.clone(), Duration::from_millis(20));
+        latencies.update_relay(na, Duration::from_millis(100));
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(45)),
+            FastestRelay::Known(eu.clone())
+        );
+        // Once the later wave started, its relay may still answer faster.
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(60)),
+            FastestRelay::KnownAfter(Duration::from_millis(30))
+        );
+        assert_eq!(
+            fastest_relay(&latencies, &starts, Duration::from_millis(100)),
+            FastestRelay::Known(eu)
+        );
+    }
+
     #[test]
     fn test_update_report_stun_working() {
         let eu_relayer = Arc::new(default_eu_relay_node());
@@ -1268,6 +1648,67 @@ mod tests {
         assert_eq!(report.icmpv4, Some(true));
     }
 
+    #[test]
+    fn test_update_report_cgnat() {
+        let eu_relayer = Arc::new(default_eu_relay_node());
+        let na_relayer = Arc::new(default_na_relay_node());
+
+        let stun_probe = |node: &Arc<RelayNode>, ip: Ipv4Addr| ProbeReport {
+            ipv4_can_send: true,
+            ipv6_can_send: false,
+            icmpv4: None,
+            icmpv6: None,
+            latency: Some(Duration::from_millis(5)),
+            probe: Probe::StunIpv4 {
+                delay: Duration::ZERO,
+                node: node.clone(),
+            },
+            addr: Some((ip, 1234).into()),
+        };
+
+        // A regular global address, seen the same by both relays.
+        let mut report = Report::default();
+        update_report(
+            &mut report,
+            stun_probe(&eu_relayer, Ipv4Addr::new(203, 0, 113, 1)),
+        );
+        update_report(
+            &mut report,
+            stun_probe(&na_relayer, Ipv4Addr::new(203, 0, 113, 1)),
+        );
+        assert_eq!(report.cgnat, Some(false));
+
+        // An address from the shared address space.
+        let mut report = Report::default();
+        update_report(
+            &mut report,
+            stun_probe(&eu_relayer, Ipv4Addr::new(100, 72, 0, 1)),
+        );
+        assert_eq!(report.cgnat, Some(true));
+
+        // 100.128.0.0 is just outside of 100.64.0.0/10.
+        let mut report = Report::default();
+        update_report(
+            &mut report,
+            stun_probe(&eu_relayer, Ipv4Addr::new(100, 128, 0, 1)),
+        );
+        assert_eq!(report.cgnat, Some(false));
+
+        // Different global IPs from different relays.
+        let mut report = Report::default();
+        update_report(
+            &mut report,
+            stun_probe(&eu_relayer, Ipv4Addr::new(203, 0, 113, 1)),
+        );
+        assert_eq!(report.cgnat, Some(false));
+        update_report(
+            &mut report,
+            stun_probe(&na_relayer, Ipv4Addr::new(203, 0, 113, 2)),
+        );
+        assert_eq!(report.cgnat, Some(true));
+        assert_eq!(report.mapping_varies_by_dest_ip, Some(true));
+    }
+
     // # ICMP permissions on Linux
     //
     // ## Using capabilities: CAP_NET_RAW
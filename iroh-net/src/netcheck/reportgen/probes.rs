@@ -4,7 +4,7 @@
 //! probes work and we also learn about our public IP addresses and ports.  But fallback
 //! probes for HTTPS and ICMP exist as well.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::sync::Arc;
 
@@ -43,6 +43,16 @@ const ACTIVE_RETRANSMIT_EXTRA_DELAY: Duration = Duration::from_millis(50);
 /// reports. (During a full report, all relay servers are scanned.)
 const NUM_INCREMENTAL_RELAYS: usize = 3;
 
+/// The number of relays probed at once by an initial probe plan.
+///
+/// Large relay maps are probed in waves of this many relays, [`INITIAL_WAVE_DELAY`] apart.
+/// On good networks the fastest relay is usually known before the later waves start, which
+/// are then never sent.
+const INITIAL_WAVE_SIZE: usize = 4;
+
+/// The delay between the waves of an initial probe plan, see [`INITIAL_WAVE_SIZE`].
+const INITIAL_WAVE_DELAY: Duration = Duration::from_millis(50);
+
 /// The protocol used to time a node's latency.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, derive_more::Display)]
 #[repr(u8)]
@@ -202,12 +212,13 @@ impl ProbePlan {
     pub(super) fn initial(relay_map: &RelayMap, if_state: &interfaces::State) -> Self {
         let mut plan = Self(BTreeSet::new());
 
-        for relay_node in relay_map.nodes() {
+        for (i, relay_node) in relay_map.nodes().enumerate() {
             let mut stun_ipv4_probes = ProbeSet::new(ProbeProto::StunIpv4);
             let mut stun_ipv6_probes = ProbeSet::new(ProbeProto::StunIpv6);
 
+            let wave_start = INITIAL_WAVE_DELAY * (i / INITIAL_WAVE_SIZE) as u32;
             for attempt in 0..3 {
-                let delay = DEFAULT_INITIAL_RETRANSMIT * attempt as u32;
+                let delay = wave_start + DEFAULT_INITIAL_RETRANSMIT * attempt as u32;
 
                 if if_state.have_v4 {
                     stun_ipv4_probes
@@ -396,6 +407,35 @@ impl ProbePlan {
         }
     }
 
+    /// Returns whether the plan contains probes of `proto`.
+    pub(super) fn has_proto(&self, proto: ProbeProto) -> bool {
+        self.0.iter().any(|set| set.proto == proto)
+    }
+
+    /// Returns the number of relays probed by this plan.
+    pub(super) fn relay_count(&self) -> usize {
+        self.0
+            .iter()
+            .flatten()
+            .map(|probe| &probe.node().url)
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    /// Returns when the first STUN probe to each relay starts.
+    pub(super) fn stun_starts(&self) -> BTreeMap<RelayUrl, Duration> {
+        let mut starts = BTreeMap::new();
+        for probe in self.0.iter().flatten() {
+            if matches!(probe.proto(), ProbeProto::StunIpv4 | ProbeProto::StunIpv6) {
+                let start = starts
+                    .entry(probe.node().url.clone())
+                    .or_insert(probe.delay());
+                *start = (*start).min(probe.delay());
+            }
+        }
+        starts
+    }
+
     /// Returns the delay of the last probe in the probe plan.
     fn max_delay(&self) -> Duration {
         self.0
@@ -703,6 +743,7 @@ mod tests {
                 icmpv6: None,
                 mapping_varies_by_dest_ip: Some(false),
                 mapping_varies_by_dest_ipv6: Some(false),
+                cgnat: None,
                 hair_pinning: Some(true),
                 portmap_probe: None,
                 preferred_relay: Some(relay_node_1.url.clone()),
@@ -925,6 +966,7 @@ mod tests {
             icmpv6: None,
             mapping_varies_by_dest_ip: Some(false),
             mapping_varies_by_dest_ipv6: Some(false),
+            cgnat: None,
             hair_pinning: Some(true),
             portmap_probe: None,
             preferred_relay: Some(url_1.clone()),
@@ -10,20 +10,26 @@
 
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
+mod access_log;
 pub(crate) mod client;
 pub(crate) mod client_conn;
 pub(crate) mod clients;
 mod codec;
 pub mod http;
+mod limits;
 mod map;
 mod metrics;
 pub(crate) mod server;
 pub(crate) mod types;
 
+pub use self::access_log::{AccessLog, AccessLogEntry, AccessLogEvent, JsonLinesLog};
 pub use self::client::{Client as RelayClient, ReceivedMessage};
-pub use self::codec::MAX_PACKET_SIZE;
+pub use self::codec::{MAX_PACKET_SIZE, MAX_WATCHED_PEERS};
 pub use self::http::Client as HttpClient;
-pub use self::map::{RelayMap, RelayMode, RelayNode};
+pub use self::limits::ServerLimits;
+pub use self::map::{
+    GeoLocation, RelayMap, RelayMode, RelayNode, RelayNodeMetadata, SignedRelayMap,
+};
 pub use self::metrics::Metrics;
 pub use self::server::{ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, Server};
 pub use iroh_base::node_addr::RelayUrl;
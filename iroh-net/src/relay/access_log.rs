@@ -0,0 +1,249 @@
+//! Structured logs of the clients of a relay server.
+//!
+//! Operators need to know who used a relay server and how much, without enabling debug
+//! logging.  The server records an [`AccessLogEntry`] in its [`AccessLog`] when a client
+//! connects, is rejected, or disconnects.  The entry for a disconnect summarises what was
+//! relayed for the client over the whole connection.
+//!
+//! [`JsonLinesLog`] writes the entries as JSON, one per line.
+
+use std::{
+    fmt,
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::key::PublicKey;
+
+/// Receives the [`AccessLogEntry`]s of a relay server.
+pub trait AccessLog: fmt::Debug + Send + Sync + 'static {
+    /// Records `entry`.
+    ///
+    /// This is called from the tasks serving the clients, so it should not block for long.
+    fn record(&self, entry: AccessLogEntry);
+}
+
+/// An event in the lifetime of a client connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessLogEntry {
+    /// Milliseconds since the unix epoch.
+    pub timestamp_ms: u64,
+    /// The key of the client.
+    pub client: PublicKey,
+    /// The server-wide number of the connection, to tell connections of the same client apart.
+    pub conn_num: usize,
+    /// The address the client connected from, if known.
+    pub remote_addr: Option<SocketAddr>,
+    /// What happened.
+    #[serde(flatten)]
+    pub event: AccessLogEvent,
+}
+
+/// What happened to a client connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AccessLogEvent {
+    /// The client connected.
+    Connected,
+    /// The client was not accepted.
+    Rejected {
+        /// Why the client was not accepted.
+        reason: String,
+    },
+    /// The client disconnected.
+    Disconnected {
+        /// How long the client was connected, in milliseconds.
+        duration_ms: u64,
+        /// Bytes of the packets relayed to the client.
+        bytes_sent: u64,
+        /// Bytes of the packets the client sent to be relayed.
+        bytes_received: u64,
+        /// Number of packets relayed to the client.
+        packets_sent: u64,
+        /// Number of packets the client sent to be relayed.
+        packets_received: u64,
+        /// Why the connection ended.
+        reason: String,
+    },
+}
+
+/// An [`AccessLog`] writing every entry as a line of JSON.
+pub struct JsonLinesLog<W> {
+    writer: parking_lot::Mutex<W>,
+}
+
+impl<W> fmt::Debug for JsonLinesLog<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLinesLog").finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> JsonLinesLog<W> {
+    /// Creates a log writing to `writer`, flushing it after every entry.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: parking_lot::Mutex::new(writer),
+        }
+    }
+
+    /// Returns the writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+impl<W: Write + Send + 'static> AccessLog for JsonLinesLog<W> {
+    fn record(&self, entry: AccessLogEntry) {
+        let mut writer = self.writer.lock();
+        let res = serde_json::to_writer(&mut *writer, &entry)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"))
+            .and_then(|_| writer.flush());
+        if let Err(err) = res {
+            tracing::warn!("failed to write access log: {err}");
+        }
+    }
+}
+
+/// Counts what is relayed for a connection, and records it in the [`AccessLog`].
+#[derive(Debug)]
+pub(crate) struct ConnectionLog {
+    log: Arc<dyn AccessLog>,
+    client: PublicKey,
+    conn_num: usize,
+    remote_addr: Option<SocketAddr>,
+    connected_at: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+}
+
+impl ConnectionLog {
+    /// Records that the client connected.
+    pub(crate) fn connected(
+        log: Arc<dyn AccessLog>,
+        client: PublicKey,
+        conn_num: usize,
+        remote_addr: Option<SocketAddr>,
+    ) -> Self {
+        log.record(entry(
+            client,
+            conn_num,
+            remote_addr,
+            AccessLogEvent::Connected,
+        ));
+        Self {
+            log,
+            client,
+            conn_num,
+            remote_addr,
+            connected_at: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
+        }
+    }
+
+    /// Counts a packet of `len` bytes relayed to the client.
+    pub(crate) fn sent(&mut self, len: usize) {
+        self.bytes_sent += len as u64;
+        self.packets_sent += 1;
+    }
+
+    /// Counts a packet of `len` bytes the client sent to be relayed.
+    pub(crate) fn received(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+        self.packets_received += 1;
+    }
+
+    /// Records that the client disconnected, with the summary of the connection.
+    pub(crate) fn disconnected(&self, reason: String) {
+        let event = AccessLogEvent::Disconnected {
+            duration_ms: millis(self.connected_at.elapsed()),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_sent: self.packets_sent,
+            packets_received: self.packets_received,
+            reason,
+        };
+        self.log
+            .record(entry(self.client, self.conn_num, self.remote_addr, event));
+    }
+}
+
+/// Records that a client was not accepted.
+pub(crate) fn rejected(
+    log: &dyn AccessLog,
+    client: PublicKey,
+    conn_num: usize,
+    remote_addr: Option<SocketAddr>,
+    reason: String,
+) {
+    let event = AccessLogEvent::Rejected { reason };
+    log.record(entry(client, conn_num, remote_addr, event));
+}
+
+fn entry(
+    client: PublicKey,
+    conn_num: usize,
+    remote_addr: Option<SocketAddr>,
+    event: AccessLogEvent,
+) -> AccessLogEntry {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    AccessLogEntry {
+        timestamp_ms: millis(since_epoch),
+        client,
+        conn_num,
+        remote_addr,
+        event,
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_json_lines() {
+        let client = SecretKey::generate().public();
+        let log = Arc::new(JsonLinesLog::new(Vec::new()));
+        let remote_addr = "127.0.0.1:1234".parse().ok();
+        let mut conn = ConnectionLog::connected(log.clone(), client, 7, remote_addr);
+        conn.sent(100);
+        conn.received(20);
+        conn.received(30);
+        conn.disconnected("read stream ended".to_string());
+        drop(conn);
+
+        let out = Arc::into_inner(log).unwrap().into_inner();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "connected");
+        assert_eq!(lines[0]["client"], client.to_string());
+        assert_eq!(lines[0]["conn_num"], 7);
+        assert_eq!(lines[0]["remote_addr"], "127.0.0.1:1234");
+        assert_eq!(lines[1]["event"], "disconnected");
+        assert_eq!(lines[1]["bytes_sent"], 100);
+        assert_eq!(lines[1]["bytes_received"], 50);
+        assert_eq!(lines[1]["packets_sent"], 1);
+        assert_eq!(lines[1]["packets_received"], 2);
+        assert_eq!(lines[1]["reason"], "read stream ended");
+    }
+}
@@ -14,8 +14,8 @@ use tracing::{debug, info_span, trace, Instrument};
 use super::codec::PER_CLIENT_READ_QUEUE_DEPTH;
 use super::{
     codec::{
-        write_frame, DerpCodec, Frame, MAX_PACKET_SIZE, PER_CLIENT_SEND_QUEUE_DEPTH,
-        PROTOCOL_VERSION,
+        write_frame, DerpCodec, Frame, MAX_PACKET_SIZE, MAX_WATCHED_PEERS,
+        PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
     },
     types::{ClientInfo, RateLimiter},
 };
@@ -73,6 +73,8 @@ pub struct InnerClient {
     /// JoinHandle for the [`ClientWriter`] task
     writer_task: AbortingJoinHandle<Result<()>>,
     reader_task: AbortingJoinHandle<()>,
+    /// Whether the server announced support for [`Client::watch_presence`].
+    supports_presence: bool,
 }
 
 impl Client {
@@ -119,6 +121,36 @@ impl Client {
         Ok(())
     }
 
+    /// Replaces the peers whose presence the server reports with
+    /// [`ReceivedMessage::PeerPresence`].
+    ///
+    /// The server reports the current presence of each of the `peers` right away.  An empty
+    /// list stops the reports.  Errors if there are more than [`MAX_WATCHED_PEERS`] peers, or
+    /// if the server does not support presence, see [`Client::supports_presence`].
+    pub async fn watch_presence(&self, peers: Vec<PublicKey>) -> Result<()> {
+        ensure!(
+            self.inner.supports_presence,
+            "the relay server does not support presence"
+        );
+        ensure!(
+            peers.len() <= MAX_WATCHED_PEERS,
+            "watching too many peers: {}",
+            peers.len()
+        );
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::WatchPresence(peers))
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the server supports [`Client::watch_presence`].
+    ///
+    /// Servers without support close the connection on the unknown frame.
+    pub fn supports_presence(&self) -> bool {
+        self.inner.supports_presence
+    }
+
     /// The local address that the [`Client`] is listening on.
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(self.inner.local_addr)
@@ -157,6 +189,7 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
             Ok(ReceivedMessage::KeepAlive)
         }
         Frame::PeerGone { peer } => Ok(ReceivedMessage::PeerGone(peer)),
+        Frame::PeerPresence { peer, online } => Ok(ReceivedMessage::PeerPresence { peer, online }),
         Frame::RecvPacket { src_key, content } => {
             let packet = ReceivedMessage::ReceivedPacket {
                 source: src_key,
@@ -197,6 +230,8 @@ enum ClientWriterMessage {
     Ping([u8; 8]),
     /// Tell the server whether or not this client is the user's preferred client
     NotePreferred(bool),
+    /// Replace the peers whose presence the server reports
+    WatchPresence(Vec<PublicKey>),
     /// Shutdown the writer
     Shutdown,
 }
@@ -231,6 +266,10 @@ impl<W: AsyncWrite + Unpin + Send + 'static> ClientWriter<W> {
                     write_frame(&mut self.writer, Frame::NotePreferred { preferred }, None).await?;
                     self.writer.flush().await?;
                 }
+                ClientWriterMessage::WatchPresence(peers) => {
+                    write_frame(&mut self.writer, Frame::WatchPresence { peers }, None).await?;
+                    self.writer.flush().await?;
+                }
                 ClientWriterMessage::Shutdown => {
                     return Ok(());
                 }
@@ -247,6 +286,7 @@ pub struct ClientBuilder {
     reader: RelayReader,
     writer: FramedWrite<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>, DerpCodec>,
     local_addr: SocketAddr,
+    supports_presence: bool,
 }
 
 impl ClientBuilder {
@@ -261,9 +301,20 @@ impl ClientBuilder {
             reader: FramedRead::new(reader, DerpCodec),
             writer: FramedWrite::new(writer, DerpCodec),
             local_addr,
+            supports_presence: false,
         }
     }
 
+    /// Sets whether the server announced support for presence, see
+    /// [`Client::watch_presence`].
+    ///
+    /// The server announces it during the HTTP upgrade, the relay protocol has no
+    /// negotiation of its own.
+    pub fn supports_presence(mut self, supported: bool) -> Self {
+        self.supports_presence = supported;
+        self
+    }
+
     async fn server_handshake(&mut self) -> Result<Option<RateLimiter>> {
         debug!("server_handshake: started");
         let client_info = ClientInfo {
@@ -344,6 +395,7 @@ impl ClientBuilder {
                 writer_channel: writer_sender,
                 writer_task: writer_task.into(),
                 reader_task: reader_task.into(),
+                supports_presence: self.supports_presence,
             }),
         };
 
@@ -369,6 +421,16 @@ pub enum ReceivedMessage {
     /// Indicates that the client identified by the underlying public key had previously sent you a
     /// packet but has now disconnected from the server.
     PeerGone(PublicKey),
+    /// Indicates that a peer watched with [`Client::watch_presence`] connected to or
+    /// disconnected from the server.
+    ///
+    /// Also sent with the current presence of each peer when the watched peers are replaced.
+    PeerPresence {
+        /// The [`PublicKey`] of the watched peer.
+        peer: PublicKey,
+        /// Whether the peer is connected to the server.
+        online: bool,
+    },
     /// Sent by the server upon first connect.
     ServerInfo {
         /// How many bytes per second the server says it will accept, including all framing bytes.
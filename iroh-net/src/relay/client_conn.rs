@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -18,7 +19,9 @@ use iroh_metrics::{inc, inc_by};
 use super::codec::{DerpCodec, Frame};
 use super::server::MaybeTlsStream;
 use super::{
+    access_log::{self, AccessLog, ConnectionLog},
     codec::{write_frame, KEEP_ALIVE},
+    limits::ClientBudget,
     metrics::Metrics,
     types::{Packet, ServerMessage},
 };
@@ -50,6 +53,8 @@ pub(crate) struct ClientConnManager {
     /// the client messages. These `Senders` correspond to `Receivers` on the
     /// [`ClientConnIo`].
     pub(crate) client_channels: ClientChannels,
+    /// Bytes of the packets queued in the `client_channels`.
+    pub(crate) budget: ClientBudget,
 }
 
 /// Channels that the [`ClientConnManager`] uses to communicate with the
@@ -57,6 +62,7 @@ pub(crate) struct ClientConnManager {
 ///  - information about a peer leaving the network (This should only happen for peers that this
 ///  client was previously communciating with)
 ///  - packets sent to this client from another client in the network
+///  - changes in the presence of peers the client watches
 #[derive(Debug)]
 pub(crate) struct ClientChannels {
     /// Queue of packets intended for the client
@@ -65,6 +71,8 @@ pub(crate) struct ClientChannels {
     pub(crate) disco_send_queue: mpsc::Sender<Packet>,
     /// Notify the client that a previous sender has disconnected
     pub(crate) peer_gone: mpsc::Sender<PublicKey>,
+    /// Notify the client that a watched peer connected (`true`) or disconnected (`false`)
+    pub(crate) presence: mpsc::Sender<(PublicKey, bool)>,
 }
 
 /// A builds a [`ClientConnManager`] from a [`PublicKey`] and an io connection.
@@ -76,12 +84,17 @@ pub struct ClientConnBuilder {
     pub(crate) write_timeout: Option<Duration>,
     pub(crate) channel_capacity: usize,
     pub(crate) server_channel: mpsc::Sender<ServerMessage>,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    pub(crate) access_log: Option<Arc<dyn AccessLog>>,
 }
 
 impl ClientConnBuilder {
     /// Creates a client from a connection, which starts a read and write loop to handle
     /// io to the client
-    pub(crate) fn build(self) -> ClientConnManager {
+    pub(crate) fn build(self, budget: ClientBudget) -> ClientConnManager {
+        let access_log = self
+            .access_log
+            .map(|log| ConnectionLog::connected(log, self.key, self.conn_num, self.remote_addr));
         ClientConnManager::new(
             self.key,
             self.conn_num,
@@ -89,8 +102,25 @@ impl ClientConnBuilder {
             self.write_timeout,
             self.channel_capacity,
             self.server_channel,
+            budget,
+            access_log,
         )
     }
+
+    /// Tells the client about the `problem` it is not accepted for, and closes the connection.
+    pub(crate) async fn reject(mut self, problem: &str) -> Result<()> {
+        if let Some(log) = &self.access_log {
+            access_log::rejected(
+                log.as_ref(),
+                self.key,
+                self.conn_num,
+                self.remote_addr,
+                problem.to_string(),
+            );
+        }
+        let problem = Bytes::copy_from_slice(problem.as_bytes());
+        write_frame(&mut self.io, Frame::Health { problem }, self.write_timeout).await
+    }
 }
 
 impl ClientConnManager {
@@ -105,6 +135,8 @@ impl ClientConnManager {
         write_timeout: Option<Duration>,
         channel_capacity: usize,
         server_channel: mpsc::Sender<ServerMessage>,
+        budget: ClientBudget,
+        access_log: Option<ConnectionLog>,
     ) -> ClientConnManager {
         let done = CancellationToken::new();
         let client_id = (key, conn_num);
@@ -112,6 +144,7 @@ impl ClientConnManager {
 
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
+        let (presence_s, presence_r) = mpsc::channel(channel_capacity);
 
         let preferred = Arc::from(AtomicBool::from(false));
 
@@ -121,9 +154,12 @@ impl ClientConnManager {
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
+            presence: presence_r,
             key,
             preferred: Arc::clone(&preferred),
             server_channel: server_channel.clone(),
+            budget: budget.clone(),
+            access_log,
         };
 
         // start io loop
@@ -162,7 +198,9 @@ impl ClientConnManager {
                 send_queue: send_queue_s,
                 disco_send_queue: disco_send_queue_s,
                 peer_gone: peer_gone_s,
+                presence: presence_s,
             },
+            budget,
         }
     }
 
@@ -193,12 +231,14 @@ impl ClientConnManager {
 ///  - a KEEP_ALIVE frame
 ///  - a PEER_GONE frame to inform the client that a peer they have previously sent messages to
 ///  is gone from the network
+///  - a PEER_PRESENCE frame when a peer the client watches connects or disconnects
 ///  - packets from other peers
 ///
 /// On the "read" side, it can:
 ///     - receive a ping and write a pong back
 ///     - note whether the client is `preferred`, aka this client is the preferred way
 ///     to speak to the node ID associated with that client.
+///     - forward the peers the client wants to watch the presence of to the server
 #[derive(Debug)]
 pub(crate) struct ClientConnIo {
     /// Io to talk to the client
@@ -211,6 +251,8 @@ pub(crate) struct ClientConnIo {
     disco_send_queue: mpsc::Receiver<Packet>,
     /// Notify the client that a previous sender has disconnected
     peer_gone: mpsc::Receiver<PublicKey>,
+    /// Notify the client that a watched peer connected or disconnected
+    presence: mpsc::Receiver<(PublicKey, bool)>,
 
     /// [`PublicKey`] of this client
     key: PublicKey,
@@ -226,10 +268,41 @@ pub(crate) struct ClientConnIo {
     // might find that the alternative is better, once I have a better idea of how this is supposed
     // to be read.
     preferred: Arc<AtomicBool>,
+
+    /// Bytes of the packets in the `send_queue` and `disco_send_queue`
+    budget: ClientBudget,
+
+    /// Summary of the connection for the server's access log
+    access_log: Option<ConnectionLog>,
+}
+
+impl Drop for ClientConnIo {
+    fn drop(&mut self) {
+        // Release the packets still queued.  Closing the queues first makes the server release
+        // the bytes of any packet it fails to queue from now on itself.
+        for queue in [&mut self.send_queue, &mut self.disco_send_queue] {
+            queue.close();
+            while let Ok(packet) = queue.try_recv() {
+                self.budget.release(packet.bytes.len());
+            }
+        }
+    }
 }
 
 impl ClientConnIo {
     async fn run(mut self, done: CancellationToken) -> Result<()> {
+        let res = self.run_loop(done).await;
+        if let Some(access_log) = &self.access_log {
+            let reason = match &res {
+                Ok(()) => "closed by server".to_string(),
+                Err(err) => format!("{err:#}"),
+            };
+            access_log.disconnected(reason);
+        }
+        res
+    }
+
+    async fn run_loop(&mut self, done: CancellationToken) -> Result<()> {
         let jitter = Duration::from_secs(5);
         let mut keep_alive = tokio::time::interval(KEEP_ALIVE + jitter);
         // ticks immediately
@@ -266,8 +339,14 @@ impl ClientConnIo {
                     trace!("peer gone: {:?}", peer);
                     self.send_peer_gone(peer).await?;
                 }
+                presence = self.presence.recv() => {
+                    let (peer, online) = presence.context("Server.presence dropped")?;
+                    trace!("peer presence: {:?} {}", peer, online);
+                    self.send_peer_presence(peer, online).await?;
+                }
                 packet = self.send_queue.recv() => {
                     let packet = packet.context("Server.send_queue dropped")?;
+                    self.budget.release(packet.bytes.len());
                     trace!("send packet");
                     self.send_packet(packet).await.context("send packet")?;
                     // TODO: stats
@@ -275,6 +354,7 @@ impl ClientConnIo {
                 }
                 packet = self.disco_send_queue.recv() => {
                     let packet = packet.context("Server.disco_send_queue dropped")?;
+                    self.budget.release(packet.bytes.len());
                     trace!("send disco packet");
                     self.send_packet(packet).await.context("send packet")?;
                     // TODO: stats
@@ -317,6 +397,18 @@ impl ClientConnIo {
         write_frame(&mut self.io, Frame::PeerGone { peer }, self.timeout).await
     }
 
+    /// Sends a peer presence frame, does not flush
+    ///
+    /// Errors if the send does not happen within the `timeout` duration
+    async fn send_peer_presence(&mut self, peer: PublicKey, online: bool) -> Result<()> {
+        write_frame(
+            &mut self.io,
+            Frame::PeerPresence { peer, online },
+            self.timeout,
+        )
+        .await
+    }
+
     /// Writes contents to the client in a `RECV_PACKET` frame. If `srcKey.is_zero`, it uses the
     /// old DERPv1 framing format, otherwise uses the DERPv2 framing format. The bytes of contents
     /// are only valid until this function returns, do not retain the slices.
@@ -324,6 +416,9 @@ impl ClientConnIo {
     async fn send_packet(&mut self, packet: Packet) -> Result<()> {
         let src_key = packet.src;
         let content = packet.bytes;
+        if let Some(access_log) = &mut self.access_log {
+            access_log.sent(content.len());
+        }
 
         if let Ok(len) = content.len().try_into() {
             inc_by!(Metrics, bytes_sent, len);
@@ -349,6 +444,9 @@ impl ClientConnIo {
             }
             Frame::SendPacket { dst_key, packet } => {
                 let packet_len = packet.len();
+                if let Some(access_log) = &mut self.access_log {
+                    access_log.received(packet_len);
+                }
                 self.handle_frame_send_packet(dst_key, packet).await?;
                 inc_by!(Metrics, bytes_recv, packet_len as u64);
             }
@@ -359,6 +457,11 @@ impl ClientConnIo {
             Frame::Health { .. } => {
                 inc!(Metrics, other_packets_recv);
             }
+            Frame::WatchPresence { peers } => {
+                self.send_server(ServerMessage::WatchPresence((self.key, peers)))
+                    .await?;
+                inc!(Metrics, other_packets_recv);
+            }
             _ => {
                 inc!(Metrics, unknown_frames);
             }
@@ -453,6 +556,7 @@ impl ClientConnIo {
 mod tests {
     use crate::key::SecretKey;
     use crate::relay::codec::{recv_frame, FrameType};
+    use crate::relay::limits::MemoryBudget;
 
     use super::*;
 
@@ -463,12 +567,14 @@ mod tests {
         let (send_queue_s, send_queue_r) = mpsc::channel(10);
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (presence_s, presence_r) = mpsc::channel(10);
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = SecretKey::generate().public();
         let (io, io_rw) = tokio::io::duplex(1024);
         let mut io_rw = Framed::new(io_rw, DerpCodec);
         let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
+        let budget = MemoryBudget::default().client();
 
         let conn_io = ClientConnIo {
             io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
@@ -476,10 +582,13 @@ mod tests {
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
+            presence: presence_r,
 
             key,
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            budget: budget.clone(),
+            access_log: None,
         };
 
         let done = CancellationToken::new();
@@ -496,6 +605,7 @@ mod tests {
             src: key,
             bytes: Bytes::from(&data[..]),
         };
+        assert!(budget.reserve(data.len()));
         send_queue_s.send(packet.clone()).await?;
         let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
         assert_eq!(
@@ -508,6 +618,7 @@ mod tests {
 
         // send disco packet
         println!("  send disco packet");
+        assert!(budget.reserve(data.len()));
         disco_send_queue_s.send(packet.clone()).await?;
         let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
         assert_eq!(
@@ -518,12 +629,27 @@ mod tests {
             }
         );
 
+        // the bytes of written packets are released
+        assert_eq!(budget.queued_bytes(), 0);
+
         // send peer_gone
         println!("send peer gone");
         peer_gone_s.send(key).await?;
         let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
         assert_eq!(frame, Frame::PeerGone { peer: key });
 
+        // send peer presence
+        println!("send peer presence");
+        presence_s.send((key, true)).await?;
+        let frame = recv_frame(FrameType::PeerPresence, &mut io_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::PeerPresence {
+                peer: key,
+                online: true
+            }
+        );
+
         // Read tests
         println!("--read");
 
@@ -550,6 +676,27 @@ mod tests {
 
         let target = SecretKey::generate().public();
 
+        // watch presence
+        println!("  watch presence");
+        write_frame(
+            &mut io_rw,
+            Frame::WatchPresence {
+                peers: vec![target],
+            },
+            None,
+        )
+        .await?;
+        let msg = server_channel_r.recv().await.unwrap();
+        match msg {
+            ServerMessage::WatchPresence((watcher, peers)) => {
+                assert_eq!(key, watcher);
+                assert_eq!(vec![target], peers);
+            }
+            m => {
+                bail!("expected ServerMessage::WatchPresence, got {m:?}");
+            }
+        }
+
         // send packet
         println!("  send packet");
         let data = b"hello world!";
@@ -597,6 +744,7 @@ mod tests {
         let (_send_queue_s, send_queue_r) = mpsc::channel(10);
         let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (_presence_s, presence_r) = mpsc::channel(10);
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = SecretKey::generate().public();
@@ -611,10 +759,13 @@ mod tests {
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
+            presence: presence_r,
 
             key,
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            budget: MemoryBudget::default().client(),
+            access_log: None,
         };
 
         let done = CancellationToken::new();
@@ -12,6 +12,7 @@ use tracing::{Instrument, Span};
 
 use super::{
     client_conn::{ClientConnBuilder, ClientConnManager},
+    limits::MemoryBudget,
     metrics::Metrics,
     types::Packet,
 };
@@ -40,6 +41,10 @@ struct Client {
     conn: ClientConnManager,
     /// list of peers we have sent messages to
     sent_to: HashSet<PublicKey>,
+    /// peers whose presence this client watches
+    watching: HashSet<PublicKey>,
+    /// watched peers which sent a packet to this client, making their presence visible to it
+    contacted_by: HashSet<PublicKey>,
 }
 
 impl Client {
@@ -47,6 +52,8 @@ impl Client {
         Self {
             conn,
             sent_to: HashSet::default(),
+            watching: HashSet::default(),
+            contacted_by: HashSet::default(),
         }
     }
 
@@ -70,7 +77,7 @@ impl Client {
     }
 
     pub fn send_packet(&self, packet: Packet) -> Result<(), SendError> {
-        let res = try_send(&self.conn.client_channels.send_queue, packet);
+        let res = self.try_queue(&self.conn.client_channels.send_queue, packet);
         if res.is_ok() {
             // there is a chance that we have a packet forwarder for
             // this peer, so we must check that route before
@@ -81,7 +88,7 @@ impl Client {
     }
 
     pub fn send_disco_packet(&self, packet: Packet) -> Result<(), SendError> {
-        let res = try_send(&self.conn.client_channels.disco_send_queue, packet);
+        let res = self.try_queue(&self.conn.client_channels.disco_send_queue, packet);
         if res.is_ok() {
             // there is a chance that we have a packet forwarder for
             // this peer, so we must check that route before
@@ -91,6 +98,20 @@ impl Client {
         res
     }
 
+    /// Queues a packet if its bytes fit into the memory budget.
+    fn try_queue(&self, queue: &mpsc::Sender<Packet>, packet: Packet) -> Result<(), SendError> {
+        let len = packet.bytes.len();
+        if !self.conn.budget.reserve(len) {
+            inc!(Metrics, packets_dropped_over_budget);
+            return Err(SendError::OverBudget);
+        }
+        let res = try_send(queue, packet);
+        if res.is_err() {
+            self.conn.budget.release(len);
+        }
+        res
+    }
+
     pub fn send_peer_gone(&self, key: PublicKey) -> Result<(), SendError> {
         let res = try_send(&self.conn.client_channels.peer_gone, key);
         match res {
@@ -103,6 +124,19 @@ impl Client {
         }
         res
     }
+
+    pub fn send_presence(&self, key: PublicKey, online: bool) -> Result<(), SendError> {
+        let res = try_send(&self.conn.client_channels.presence, (key, online));
+        match res {
+            Ok(_) => {
+                inc!(Metrics, other_packets_sent);
+            }
+            Err(_) => {
+                inc!(Metrics, other_packets_dropped);
+            }
+        }
+        res
+    }
 }
 
 // TODO: in the goimpl, it also tries 3 times to send a packet. But, in go we can clone receiver
@@ -129,11 +163,16 @@ fn try_send<T>(sender: &mpsc::Sender<T>, msg: T) -> Result<(), SendError> {
 enum SendError {
     PacketDropped,
     SenderClosed,
+    OverBudget,
 }
 
 #[derive(Debug)]
 pub(crate) struct Clients {
     inner: HashMap<PublicKey, Client>,
+    /// The bytes queued for all clients
+    budget: MemoryBudget,
+    /// The clients watching the presence of each peer
+    presence_watchers: HashMap<PublicKey, HashSet<PublicKey>>,
 }
 
 impl Drop for Clients {
@@ -141,9 +180,11 @@ impl Drop for Clients {
 }
 
 impl Clients {
-    pub fn new() -> Self {
+    pub fn new(budget: MemoryBudget) -> Self {
         Self {
             inner: HashMap::default(),
+            budget,
+            presence_watchers: HashMap::default(),
         }
     }
 
@@ -163,12 +204,30 @@ impl Clients {
         if let Some(client) = self.inner.get_mut(src) {
             client.record_send(dst);
         }
+        // a watched peer contacting the watcher makes its presence visible to it
+        let watched = self
+            .inner
+            .get(&dst)
+            .is_some_and(|client| client.watching.contains(src));
+        if !watched || self.is_visible(&dst, src) {
+            return;
+        }
+        if let Some(client) = self.inner.get_mut(&dst) {
+            client.contacted_by.insert(*src);
+        }
+        let online = self.inner.contains_key(src);
+        self.send_presence(&dst, *src, online);
     }
 
     pub fn contains_key(&self, key: &PublicKey) -> bool {
         self.inner.contains_key(key)
     }
 
+    /// Number of connected clients
+    pub fn num_clients(&self) -> usize {
+        self.inner.len()
+    }
+
     pub fn has_client(&self, key: &PublicKey, conn_num: usize) -> bool {
         if let Some(client) = self.inner.get(key) {
             return client.conn.conn_num == conn_num;
@@ -180,14 +239,19 @@ impl Clients {
         // this builds the client handler & starts the read & write loops to that client connection
         let key = client_builder.key;
         tracing::trace!("registering client: {:?}", key);
-        let client = client_builder.build();
+        let client = client_builder.build(self.budget.client());
         // TODO: in future, do not remove clients that share a publicKey, instead,
         // expand the `Client` struct to handle multiple connections & a policy for
         // how to handle who we write to when multiple connections exist.
         let client = Client::new(client);
-        if let Some(old_client) = self.inner.insert(key, client) {
-            tracing::warn!("multiple connections found for {key:?}, pruning old connection",);
-            old_client.shutdown();
+        match self.inner.insert(key, client) {
+            Some(old_client) => {
+                tracing::warn!("multiple connections found for {key:?}, pruning old connection",);
+                // The new connection sends its own watched peers.
+                self.stop_watching(&key, &old_client.watching);
+                old_client.shutdown();
+            }
+            None => self.notify_presence(key, true),
         }
     }
 
@@ -196,10 +260,15 @@ impl Clients {
     /// peer is gone from the network.
     pub fn unregister(&mut self, peer: &PublicKey) {
         tracing::trace!("unregistering client: {:?}", peer);
+        if self.inner.contains_key(peer) {
+            // before removing the client, as the peers it watches can see it
+            self.notify_presence(*peer, false);
+        }
         if let Some(client) = self.inner.remove(peer) {
             for key in client.sent_to.iter() {
                 self.send_peer_gone(key, *peer);
             }
+            self.stop_watching(peer, &client.watching);
             tracing::warn!("pruning connection {peer:?}");
             client.shutdown();
         }
@@ -233,6 +302,89 @@ impl Clients {
         tracing::warn!("Could not find client for {key:?}, dropping peer gone packet");
     }
 
+    /// Replaces the peers the client `watcher` watches the presence of, and sends it the
+    /// current presence of those visible to it, see [`Clients::is_visible`].
+    pub fn watch_presence(&mut self, watcher: &PublicKey, peers: Vec<PublicKey>) {
+        let Some(client) = self.inner.get_mut(watcher) else {
+            return;
+        };
+        let peers: HashSet<PublicKey> = peers.into_iter().collect();
+        client.contacted_by.retain(|peer| peers.contains(peer));
+        let old = std::mem::replace(&mut client.watching, peers.clone());
+        self.stop_watching(watcher, &old);
+        for peer in &peers {
+            self.presence_watchers
+                .entry(*peer)
+                .or_default()
+                .insert(*watcher);
+            if self.is_visible(watcher, peer) {
+                let online = self.inner.contains_key(peer);
+                self.send_presence(watcher, *peer, online);
+            }
+        }
+        // newly watched peers which watch the `watcher` can now see it
+        for peer in peers.difference(&old) {
+            let newly_visible = self.inner.get(peer).is_some_and(|client| {
+                client.watching.contains(watcher) && !client.contacted_by.contains(watcher)
+            });
+            if newly_visible {
+                self.send_presence(peer, *watcher, true);
+            }
+        }
+    }
+
+    /// Whether the presence of `peer` is reported to the client `watcher`.
+    ///
+    /// Only peers which sent a packet to the watcher, or which watch the watcher themselves,
+    /// are visible.  Otherwise any client could probe whether a node is connected.
+    fn is_visible(&self, watcher: &PublicKey, peer: &PublicKey) -> bool {
+        let contacted = self
+            .inner
+            .get(watcher)
+            .is_some_and(|client| client.contacted_by.contains(peer));
+        let mutual = self
+            .inner
+            .get(peer)
+            .is_some_and(|client| client.watching.contains(watcher));
+        contacted || mutual
+    }
+
+    fn stop_watching(&mut self, watcher: &PublicKey, peers: &HashSet<PublicKey>) {
+        for peer in peers {
+            if let Some(watchers) = self.presence_watchers.get_mut(peer) {
+                watchers.remove(watcher);
+                if watchers.is_empty() {
+                    self.presence_watchers.remove(peer);
+                }
+            }
+        }
+    }
+
+    /// Tells the clients watching `peer`, which it is visible to, that it connected or
+    /// disconnected.
+    fn notify_presence(&mut self, peer: PublicKey, online: bool) {
+        let Some(watchers) = self.presence_watchers.get(&peer) else {
+            return;
+        };
+        let watchers: Vec<_> = watchers
+            .iter()
+            .filter(|watcher| self.is_visible(watcher, &peer))
+            .copied()
+            .collect();
+        for watcher in watchers {
+            self.send_presence(&watcher, peer, online);
+        }
+    }
+
+    fn send_presence(&mut self, key: &PublicKey, peer: PublicKey, online: bool) {
+        if let Some(client) = self.inner.get(key) {
+            let res = client.send_presence(peer, online);
+            let _ = self.process_result(key, res);
+            return;
+        };
+        tracing::warn!("Could not find client for {key:?}, dropping peer presence packet");
+    }
+
     fn process_result(
         &mut self,
         key: &PublicKey,
@@ -243,6 +395,12 @@ impl Clients {
             Err(SendError::PacketDropped) => {
                 tracing::warn!("client {key:?} too busy to receive packet, dropping packet");
             }
+            Err(SendError::OverBudget) => {
+                tracing::warn!(
+                    "too many bytes queued for client {key:?} ({} bytes for all clients), dropping packet",
+                    self.budget.buffered_bytes()
+                );
+            }
             Err(SendError::SenderClosed) => {
                 tracing::warn!("Can no longer write to client {key:?}, dropping message and pruning connection");
                 self.unregister(key);
@@ -258,11 +416,15 @@ mod tests {
 
     use crate::{
         key::SecretKey,
-        relay::codec::{recv_frame, DerpCodec, Frame, FrameType},
+        relay::{
+            codec::{recv_frame, DerpCodec, Frame, FrameType},
+            limits::ServerLimits,
+        },
     };
 
-    use anyhow::Result;
+    use anyhow::{Context, Result};
     use bytes::Bytes;
+    use futures::StreamExt;
     use tokio::io::DuplexStream;
     use tokio_util::codec::{Framed, FramedRead};
 
@@ -280,6 +442,8 @@ mod tests {
                 write_timeout: None,
                 channel_capacity: 10,
                 server_channel,
+                remote_addr: None,
+                access_log: None,
             },
             FramedRead::new(test_io, DerpCodec),
         )
@@ -292,7 +456,7 @@ mod tests {
 
         let (builder_a, mut a_rw) = test_client_builder(a_key, 0);
 
-        let mut clients = Clients::new();
+        let mut clients = Clients::new(MemoryBudget::default());
         clients.register(builder_a);
 
         // send packet
@@ -331,6 +495,97 @@ mod tests {
 
         assert!(clients.inner.get(&a_key).is_none());
 
+        clients.shutdown().await;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_over_budget() -> Result<()> {
+        let a_key = SecretKey::generate().public();
+        let b_key = SecretKey::generate().public();
+
+        let (builder_a, mut a_rw) = test_client_builder(a_key, 0);
+        let budget = MemoryBudget::new(&ServerLimits {
+            max_client_queued_bytes: Some(10),
+            ..Default::default()
+        });
+        let mut clients = Clients::new(budget.clone());
+        clients.register(builder_a);
+
+        // too large for the budget
+        let packet = Packet {
+            src: b_key,
+            bytes: Bytes::from_static(b"hello world!"),
+        };
+        assert!(clients.send_packet(&a_key, packet).is_err());
+        assert_eq!(budget.buffered_bytes(), 0);
+        assert!(clients.contains_key(&a_key));
+
+        let packet = Packet {
+            src: b_key,
+            bytes: Bytes::from_static(b"hello"),
+        };
+        clients.send_packet(&a_key, packet)?;
+        let frame = recv_frame(FrameType::RecvPacket, &mut a_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::RecvPacket {
+                src_key: b_key,
+                content: Bytes::from_static(b"hello"),
+            }
+        );
+        assert_eq!(budget.buffered_bytes(), 0);
+
+        clients.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_presence() -> Result<()> {
+        let a_key = SecretKey::generate().public();
+        let b_key = SecretKey::generate().public();
+        let c_key = SecretKey::generate().public();
+        let presence = |peer, online| Frame::PeerPresence { peer, online };
+
+        let (builder_a, mut a_rw) = test_client_builder(a_key, 0);
+        let mut clients = Clients::new(MemoryBudget::default());
+        clients.register(builder_a);
+
+        // peers which never contacted the watcher are not visible, online or not
+        clients.watch_presence(&a_key, vec![b_key]);
+        let (builder_b, _b_rw) = test_client_builder(b_key, 1);
+        clients.register(builder_b);
+        clients.watch_presence(&a_key, vec![b_key]);
+
+        // a watched peer becomes visible by sending to the watcher
+        clients.record_send(&b_key, a_key);
+        let frame = recv_frame(FrameType::PeerPresence, &mut a_rw).await?;
+        assert_eq!(frame, presence(b_key, true));
+        clients.unregister(&b_key);
+        // b sent to a, so a is also told that b is gone, in no particular order
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            frames.push(a_rw.next().await.context("stream ended")??);
+        }
+        assert!(frames.contains(&presence(b_key, false)));
+        assert!(frames.contains(&Frame::PeerGone { peer: b_key }));
+
+        // peers watching each other are visible to each other
+        let (builder_c, mut c_rw) = test_client_builder(c_key, 2);
+        clients.register(builder_c);
+        clients.watch_presence(&c_key, vec![a_key]);
+        clients.watch_presence(&a_key, vec![c_key]);
+        let frame = recv_frame(FrameType::PeerPresence, &mut a_rw).await?;
+        assert_eq!(frame, presence(c_key, true));
+        let frame = recv_frame(FrameType::PeerPresence, &mut c_rw).await?;
+        assert_eq!(frame, presence(a_key, true));
+        clients.unregister(&c_key);
+        let frame = recv_frame(FrameType::PeerPresence, &mut a_rw).await?;
+        assert_eq!(frame, presence(c_key, false));
+
+        // watches end with the watching client
+        clients.unregister(&a_key);
+        assert!(clients.presence_watchers.is_empty());
+
         clients.shutdown().await;
         Ok(())
     }
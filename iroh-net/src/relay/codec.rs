@@ -25,6 +25,12 @@ pub(super) const SERVER_CHANNEL_SIZE: usize = 1024 * 100;
 /// The number of packets buffered for sending per client
 pub(super) const PER_CLIENT_SEND_QUEUE_DEPTH: usize = 512; //32;
 pub(super) const PER_CLIENT_READ_QUEUE_DEPTH: usize = 512;
+/// The maximum number of peers a client can watch the presence of.
+///
+/// The current presence of all watched peers is queued at once when the watched peers are
+/// replaced.  Half the client's queue is left for updates which are still pending, so these
+/// reports are not dropped.
+pub const MAX_WATCHED_PEERS: usize = PER_CLIENT_SEND_QUEUE_DEPTH / 2;
 
 /// ProtocolVersion is bumped whenever there's a wire-incompatible change.
 ///  - version 1 (zero on wire): consistent box headers, in use by employee dev nodes a bit
@@ -52,6 +58,15 @@ pub(super) const PROTOCOL_VERSION: usize = 3;
 ///  * clients sends FrameType::SendPacket
 ///  * server then sends FrameType::RecvPacket to recipient
 ///
+///  Presence (optional):
+///  * server announces support in the `relay-features` header of the HTTP upgrade response,
+///    clients only send FrameType::WatchPresence to servers which do
+///  * client sends FrameType::WatchPresence with the peers it is interested in
+///  * server sends FrameType::PeerPresence for each of them which is visible to the client,
+///    and again whenever one of them connects to or disconnects from the server
+///  * a peer is visible once it sent a packet to the client, or while it watches the client
+///    itself, so presence can not be probed for nodes which never agreed to it
+///
 
 const PREFERRED: u8 = 1u8;
 /// indicates this is NOT the client's home node
@@ -101,6 +116,16 @@ pub(crate) enum FrameType {
     Restarting = 15,
     /// 32B src pub key + 32B dst pub key + packet bytes
     ForwardPacket = 16,
+    /// Sent from client to server to replace the set of peers it watches the presence of.
+    ///
+    /// Up to [`MAX_WATCHED_PEERS`] 32B pub keys, none to stop watching.
+    WatchPresence = 17,
+    /// Sent from server to client when a watched peer connects or disconnects, and for each
+    /// peer once when the watched set is replaced.  Only sent for peers which are visible to
+    /// the client, see the presence protocol flow above.
+    ///
+    /// 32B pub key + 1 byte: 0x01 if the peer is connected to the server, 0x00 otherwise
+    PeerPresence = 18,
     #[num_enum(default)]
     Unknown = 255,
 }
@@ -223,6 +248,13 @@ pub(crate) enum Frame {
         reconnect_in: u32,
         try_for: u32,
     },
+    WatchPresence {
+        peers: Vec<PublicKey>,
+    },
+    PeerPresence {
+        peer: PublicKey,
+        online: bool,
+    },
 }
 
 impl Frame {
@@ -238,6 +270,8 @@ impl Frame {
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
             Frame::Restarting { .. } => FrameType::Restarting,
+            Frame::WatchPresence { .. } => FrameType::WatchPresence,
+            Frame::PeerPresence { .. } => FrameType::PeerPresence,
         }
     }
 
@@ -261,6 +295,8 @@ impl Frame {
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
             Frame::Restarting { .. } => 4 + 4,
+            Frame::WatchPresence { peers } => peers.len() * PUBLIC_KEY_LENGTH,
+            Frame::PeerPresence { .. } => PUBLIC_KEY_LENGTH + 1,
         }
     }
 
@@ -312,6 +348,15 @@ impl Frame {
                 dst.put_u32(*reconnect_in);
                 dst.put_u32(*try_for);
             }
+            Frame::WatchPresence { peers } => {
+                for peer in peers {
+                    dst.put(peer.as_ref());
+                }
+            }
+            Frame::PeerPresence { peer, online } => {
+                dst.put(peer.as_ref());
+                dst.put_u8(u8::from(*online));
+            }
         }
     }
 
@@ -420,6 +465,37 @@ impl Frame {
                     try_for,
                 }
             }
+            FrameType::WatchPresence => {
+                ensure!(
+                    content.len() % PUBLIC_KEY_LENGTH == 0,
+                    "invalid watch presence frame length: {}",
+                    content.len()
+                );
+                let count = content.len() / PUBLIC_KEY_LENGTH;
+                ensure!(
+                    count <= MAX_WATCHED_PEERS,
+                    "watching more peers ({count}) than max of {MAX_WATCHED_PEERS}"
+                );
+                let peers = content
+                    .chunks_exact(PUBLIC_KEY_LENGTH)
+                    .map(PublicKey::try_from)
+                    .collect::<Result<_, _>>()?;
+                Self::WatchPresence { peers }
+            }
+            FrameType::PeerPresence => {
+                ensure!(
+                    content.len() == PUBLIC_KEY_LENGTH + 1,
+                    "invalid peer presence frame length: {}",
+                    content.len()
+                );
+                let peer = PublicKey::try_from(&content[..PUBLIC_KEY_LENGTH])?;
+                let online = match content[PUBLIC_KEY_LENGTH] {
+                    0 => false,
+                    1 => true,
+                    _ => bail!("invalid peer presence frame content"),
+                };
+                Self::PeerPresence { peer, online }
+            }
             _ => {
                 anyhow::bail!("invalid frame type: {:?}", frame_type);
             }
@@ -561,6 +637,41 @@ mod tests {
         assert_eq!(client_info, got_client_info);
         Ok(())
     }
+
+    #[test]
+    fn test_presence_frames() {
+        let peers: Vec<_> = (0..3).map(|_| SecretKey::generate().public()).collect();
+        let frames = [
+            Frame::WatchPresence {
+                peers: peers.clone(),
+            },
+            Frame::WatchPresence { peers: Vec::new() },
+            Frame::PeerPresence {
+                peer: peers[0],
+                online: true,
+            },
+            Frame::PeerPresence {
+                peer: peers[1],
+                online: false,
+            },
+        ];
+        for frame in frames {
+            let mut buf = BytesMut::new();
+            DerpCodec.encode(frame.clone(), &mut buf).unwrap();
+            assert_eq!(buf.len(), HEADER_LEN + frame.len());
+            assert_eq!(DerpCodec.decode(&mut buf).unwrap(), Some(frame));
+        }
+
+        // Too many peers.
+        let mut buf = BytesMut::new();
+        buf.put_u8(FrameType::WatchPresence.into());
+        let len = (MAX_WATCHED_PEERS + 1) * PUBLIC_KEY_LENGTH;
+        buf.put_u32(len as u32);
+        for _ in 0..=MAX_WATCHED_PEERS {
+            buf.put(peers[0].as_ref());
+        }
+        assert!(DerpCodec.decode(&mut buf).is_err());
+    }
 }
 
 /// these test are slow in debug mode, so only run them in release mode
@@ -1,13 +1,47 @@
 //! An http specific relay Client and relay Server. Allows for using tls or non tls connection
 //! upgrades.
 //!
+//! Besides carrying the traffic of the magicsock, the [`Client`] can be used on its own to send
+//! small messages to other clients of the same relay server, addressed by their public key.
+//! This makes the relay usable as a signaling channel, e.g. to exchange addresses before a
+//! [`crate::MagicEndpoint`] is bound.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use bytes::Bytes;
+//! use iroh_net::{key::SecretKey, relay::{http::ClientBuilder, RelayUrl}};
+//!
+//! let url: RelayUrl = "https://relay.example.com".parse()?;
+//! let dns_resolver = iroh_net::dns::default_resolver().clone();
+//! let (client, mut receiver) = ClientBuilder::new(url).build(SecretKey::generate(), dns_resolver);
+//! # let peer = SecretKey::generate().public();
+//! client.send(peer, Bytes::from_static(b"hello")).await?;
+//! while let Some((source, data)) = receiver.recv_packet().await? {
+//!     println!("{source}: {data:?}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The relay server keeps a single connection per public key, so a client used for signaling
+//! should not share its key with a running magicsock on the same relay.  Messages are limited
+//! to [`super::MAX_PACKET_SIZE`] bytes and are not delivered if the destination is not connected.
+//!
 mod client;
 mod server;
 
-pub use self::client::{Client, ClientBuilder, ClientError, ClientReceiver};
+pub use self::client::{
+    Client, ClientBuilder, ClientError, ClientReceiver, DEFAULT_CONNECT_TIMEOUT,
+    DEFAULT_DIAL_TIMEOUT, DEFAULT_PING_TIMEOUT, DEFAULT_RECONNECT_BUFFER_PACKETS,
+    DEFAULT_RECONNECT_BUFFER_WINDOW,
+};
 pub use self::server::{Server, ServerBuilder, TlsAcceptor, TlsConfig};
 
 pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
+/// Header of the upgrade response listing the protocol extensions the server supports.
+pub(crate) const RELAY_FEATURES_HEADER: &str = "relay-features";
+/// The feature of servers which answer `FrameType::WatchPresence`.
+pub(crate) const PRESENCE_FEATURE: &str = "presence";
 
 #[cfg(any(test, feature = "test-utils"))]
 pub(crate) fn make_tls_config() -> TlsConfig {
@@ -119,6 +153,41 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_http_client_signaling() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let relay_addr: Url = format!("http://{}", server.addr()).parse().unwrap();
+
+        let dns_resolver = crate::dns::default_resolver();
+        let a_key = SecretKey::generate();
+        let b_key = SecretKey::generate();
+        let (client_a, _a_recv) =
+            ClientBuilder::new(relay_addr.clone()).build(a_key.clone(), dns_resolver.clone());
+        let (client_b, mut b_recv) =
+            ClientBuilder::new(relay_addr).build(b_key.clone(), dns_resolver.clone());
+        client_b.connect().await?;
+
+        let msg = Bytes::from_static(b"offer");
+        client_a.send(b_key.public(), msg.clone()).await?;
+        let (source, data) = b_recv.recv_packet().await?.expect("expected packet");
+        assert_eq!(source, a_key.public());
+        assert_eq!(data, msg);
+
+        let too_large = Bytes::from(vec![0u8; crate::relay::MAX_PACKET_SIZE + 1]);
+        let res = client_a.send(b_key.public(), too_large).await;
+        assert!(matches!(res, Err(ClientError::PacketTooLarge(_))));
+
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown().await;
+        Ok(())
+    }
+
     fn create_test_client(
         key: SecretKey,
         server_url: Url,
@@ -1,6 +1,8 @@
+//! An HTTP relay client, which keeps a connection to a relay server.
+//!
 //! Based on tailscale/derp/derphttp/derphttp_client.go
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
@@ -15,7 +17,7 @@ use hyper::Request;
 use rand::Rng;
 use rustls::client::Resumption;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinSet;
 use tokio::time::Instant;
@@ -24,18 +26,28 @@ use url::Url;
 
 use crate::dns::{lookup_ipv4_ipv6, DnsResolver};
 use crate::key::{PublicKey, SecretKey};
+use crate::magicsock::{socks5, Socks5Config};
+use crate::net::SocketOptions;
 use crate::relay::RelayUrl;
 use crate::relay::{
     client::Client as RelayClient, client::ClientBuilder as RelayClientBuilder,
-    client::ClientReceiver as RelayClientReceiver, ReceivedMessage,
+    client::ClientReceiver as RelayClientReceiver, ReceivedMessage, MAX_PACKET_SIZE,
 };
 use crate::util::AbortingJoinHandle;
 
-const DIAL_NODE_TIMEOUT: Duration = Duration::from_millis(1500);
-const PING_TIMEOUT: Duration = Duration::from_secs(5);
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time to wait for the TCP connection to the server, see [`ClientBuilder::dial_timeout`].
+pub const DEFAULT_DIAL_TIMEOUT: Duration = Duration::from_millis(1500);
+/// Default time to wait for the answer to a ping, see [`ClientBuilder::ping_timeout`].
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default time to wait for a connection, see [`ClientBuilder::connect_timeout`].
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 const DNS_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Default number of packets buffered while reconnecting, see [`ClientBuilder::reconnect_buffer`].
+pub const DEFAULT_RECONNECT_BUFFER_PACKETS: usize = 128;
+/// Default time packets are buffered while reconnecting, see [`ClientBuilder::reconnect_buffer`].
+pub const DEFAULT_RECONNECT_BUFFER_WINDOW: Duration = Duration::from_secs(2);
+
 /// Possible connection errors on the [`Client`]
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
@@ -66,6 +78,9 @@ pub enum ClientError {
     /// There was an error dialing
     #[error("dial error")]
     DialIO(#[from] std::io::Error),
+    /// The TLS handshake with the relay server failed
+    #[error("tls handshake failed")]
+    Tls(#[source] std::io::Error),
     /// There was an error from the task doing the dialing
     #[error("dial error")]
     DialTask(#[from] tokio::task::JoinError),
@@ -111,6 +126,9 @@ pub enum ClientError {
     /// The inner actor is gone, likely means things are shutdown.
     #[error("actor gone")]
     ActorGone,
+    /// The packet is larger than [`MAX_PACKET_SIZE`].
+    #[error("packet of {0} bytes exceeds the maximum of {MAX_PACKET_SIZE}")]
+    PacketTooLarge(usize),
 }
 
 /// An HTTP Relay client.
@@ -128,6 +146,7 @@ pub struct Client {
 enum ActorMessage {
     Connect(oneshot::Sender<Result<(RelayClient, usize), ClientError>>),
     NotePreferred(bool),
+    WatchPresence(Vec<PublicKey>),
     LocalAddr(oneshot::Sender<Result<Option<SocketAddr>, ClientError>>),
     Ping(oneshot::Sender<Result<Duration, ClientError>>),
     Pong([u8; 8], oneshot::Sender<Result<(), ClientError>>),
@@ -148,6 +167,8 @@ struct Actor {
     secret_key: SecretKey,
     can_ack_pings: bool,
     is_preferred: bool,
+    /// Peers whose presence is watched, sent again on every new connection.
+    watched_presence: Vec<PublicKey>,
     relay_client: Option<(RelayClient, RelayClientReceiver)>,
     is_closed: bool,
     #[debug("address family selector callback")]
@@ -160,6 +181,12 @@ struct Actor {
     pings: PingTracker,
     ping_tasks: JoinSet<()>,
     dns_resolver: DnsResolver,
+    reconnect_buffer: ReconnectBuffer,
+    socket_options: SocketOptions,
+    socks5_proxy: Option<Socks5Config>,
+    dial_timeout: Duration,
+    connect_timeout: Duration,
+    ping_timeout: Duration,
 }
 
 #[derive(Default, Debug)]
@@ -184,6 +211,67 @@ impl PingTracker {
     }
 }
 
+/// Packets which could not be sent because the connection to the relay server was lost.
+///
+/// Only packets sent within `window` after losing the connection are buffered, so a relay
+/// server which stays unreachable makes sends fail again.  Once reconnected, the packets which
+/// are not older than `window` are sent.
+#[derive(Debug)]
+struct ReconnectBuffer {
+    packets: VecDeque<(Instant, PublicKey, Bytes)>,
+    capacity: usize,
+    window: Duration,
+    /// When the last connection was lost, `None` while connected.
+    lost_at: Option<Instant>,
+}
+
+impl ReconnectBuffer {
+    fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            packets: VecDeque::new(),
+            capacity,
+            window,
+            lost_at: None,
+        }
+    }
+
+    /// Notes that the connection was lost.
+    fn disconnected(&mut self) {
+        self.lost_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Buffers a packet, dropping the oldest packet if the buffer is full.
+    ///
+    /// Returns `false` if the packet was not buffered, because buffering is disabled or the
+    /// connection was lost too long ago.
+    fn push(&mut self, dst_key: PublicKey, packet: Bytes) -> bool {
+        let now = Instant::now();
+        if self.capacity == 0 || !self.lost_at.is_some_and(|t| now - t < self.window) {
+            return false;
+        }
+        if self.packets.len() == self.capacity {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((now, dst_key, packet));
+        true
+    }
+
+    /// Notes that the client is connected again, returning the packets to send.
+    fn connected(&mut self) -> Vec<(PublicKey, Bytes)> {
+        self.lost_at = None;
+        let now = Instant::now();
+        self.packets
+            .drain(..)
+            .filter(|(t, _, _)| now - *t < self.window)
+            .map(|(_, dst_key, packet)| (dst_key, packet))
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
 /// Build a Client.
 pub struct ClientBuilder {
     /// Default is false
@@ -202,6 +290,20 @@ pub struct ClientBuilder {
     /// Allow self-signed certificates from relay servers
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
+    /// Default is [`DEFAULT_RECONNECT_BUFFER_PACKETS`]
+    reconnect_buffer_packets: usize,
+    /// Default is [`DEFAULT_RECONNECT_BUFFER_WINDOW`]
+    reconnect_buffer_window: Duration,
+    /// Default is no options
+    socket_options: SocketOptions,
+    /// Default is connecting directly
+    socks5_proxy: Option<Socks5Config>,
+    /// Default is [`DEFAULT_DIAL_TIMEOUT`]
+    dial_timeout: Duration,
+    /// Default is [`DEFAULT_CONNECT_TIMEOUT`]
+    connect_timeout: Duration,
+    /// Default is [`DEFAULT_PING_TIMEOUT`]
+    ping_timeout: Duration,
 }
 
 impl std::fmt::Debug for ClientBuilder {
@@ -226,6 +328,13 @@ impl ClientBuilder {
             url: url.into(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: false,
+            reconnect_buffer_packets: DEFAULT_RECONNECT_BUFFER_PACKETS,
+            reconnect_buffer_window: DEFAULT_RECONNECT_BUFFER_WINDOW,
+            socket_options: SocketOptions::default(),
+            socks5_proxy: None,
+            dial_timeout: DEFAULT_DIAL_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
         }
     }
 
@@ -268,6 +377,51 @@ impl ClientBuilder {
         self
     }
 
+    /// Buffer up to `packets` packets sent while the connection to the relay server is lost.
+    ///
+    /// Packets are buffered when sent within `window` after the connection was lost, and are
+    /// sent once the client is connected again, unless they are older than `window` by then.
+    /// This smooths over brief restarts of the relay server.  Set `packets` to 0 to drop
+    /// packets while disconnected instead.
+    pub fn reconnect_buffer(mut self, packets: usize, window: Duration) -> Self {
+        self.reconnect_buffer_packets = packets;
+        self.reconnect_buffer_window = window;
+        self
+    }
+
+    /// Sets the [`SocketOptions`] of the TCP connections to the relay server.
+    ///
+    /// Dialing fails if the options can not be set on this platform.
+    pub fn socket_options(mut self, opts: SocketOptions) -> Self {
+        self.socket_options = opts;
+        self
+    }
+
+    /// Connects to the relay server through a SOCKS5 proxy, which also resolves its hostname.
+    pub fn socks5_proxy(mut self, proxy: Socks5Config) -> Self {
+        self.socks5_proxy = Some(proxy);
+        self
+    }
+
+    /// Sets how long to wait for the TCP connection to the relay server.
+    pub fn dial_timeout(mut self, timeout: Duration) -> Self {
+        self.dial_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for a connection to the relay server, including dialing and
+    /// the handshake.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for the answer to a ping sent with [`Client::ping`].
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
     /// Skip the verification of the relay server's SSL certificates.
     ///
     /// May only be used in tests.
@@ -309,6 +463,7 @@ impl ClientBuilder {
             secret_key: key,
             can_ack_pings: self.can_ack_pings,
             is_preferred: self.is_preferred,
+            watched_presence: Vec::new(),
             relay_client: None,
             is_closed: false,
             address_family_selector: self.address_family_selector,
@@ -318,6 +473,15 @@ impl ClientBuilder {
             url: self.url,
             tls_connector,
             dns_resolver,
+            reconnect_buffer: ReconnectBuffer::new(
+                self.reconnect_buffer_packets,
+                self.reconnect_buffer_window,
+            ),
+            socket_options: self.socket_options,
+            socks5_proxy: self.socks5_proxy,
+            dial_timeout: self.dial_timeout,
+            connect_timeout: self.connect_timeout,
+            ping_timeout: self.ping_timeout,
         };
 
         let (msg_sender, inbox) = mpsc::channel(64);
@@ -349,6 +513,20 @@ impl ClientReceiver {
     pub async fn recv(&mut self) -> Option<Result<(ReceivedMessage, usize), ClientError>> {
         self.msg_receiver.recv().await
     }
+
+    /// Reads the next packet sent to this client by another client.
+    ///
+    /// Returns the sender and the data of the packet, or `None` once the [`Client`] is closed.
+    /// All other messages from the server are skipped, so this should not be combined with
+    /// [`ClientReceiver::recv`] on the same receiver.
+    pub async fn recv_packet(&mut self) -> Result<Option<(PublicKey, Bytes)>, ClientError> {
+        while let Some(msg) = self.msg_receiver.recv().await {
+            if let (ReceivedMessage::ReceivedPacket { source, data }, _) = msg? {
+                return Ok(Some((source, data)));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl Client {
@@ -390,6 +568,18 @@ impl Client {
             .ok();
     }
 
+    /// Replaces the peers whose presence the server reports, see
+    /// [`RelayClient::watch_presence`].
+    ///
+    /// The peers are watched again whenever the client reconnects.  Servers which do not
+    /// announce support for presence are not asked, so no presence is reported by them.
+    pub async fn watch_presence(&self, peers: Vec<PublicKey>) {
+        self.inner
+            .send(ActorMessage::WatchPresence(peers))
+            .await
+            .ok();
+    }
+
     /// Get the local addr of the connection. If there is no current underlying relay connection
     /// or the [`Client`] is closed, returns `None`.
     pub async fn local_addr(&self) -> Option<SocketAddr> {
@@ -423,8 +613,15 @@ impl Client {
     /// send the message.
     ///
     /// If there is an error sending the packet, it closes the underlying relay connection before
-    /// returning.
+    /// returning.  Shortly after the connection was lost, the packet is buffered and sent once
+    /// reconnected instead, see [`ClientBuilder::reconnect_buffer`].
+    ///
+    /// Returns [`ClientError::PacketTooLarge`] for packets larger than [`MAX_PACKET_SIZE`],
+    /// which the server would reject by closing the connection.
     pub async fn send(&self, dst_key: PublicKey, b: Bytes) -> Result<(), ClientError> {
+        if b.len() > MAX_PACKET_SIZE {
+            return Err(ClientError::PacketTooLarge(b.len()));
+        }
         self.send_actor(|s| ActorMessage::Send(dst_key, b, s)).await
     }
 
@@ -482,6 +679,9 @@ impl Actor {
                         ActorMessage::NotePreferred(is_preferred) => {
                             self.note_preferred(is_preferred).await;
                         },
+                        ActorMessage::WatchPresence(peers) => {
+                            self.watch_presence(peers).await;
+                        },
                         ActorMessage::LocalAddr(s) => {
                             let res = self.local_addr();
                             s.send(Ok(res)).ok();
@@ -539,12 +739,13 @@ impl Actor {
             if self.relay_client.is_none() {
                 trace!("no connection, trying to connect");
                 let (relay_client, receiver) =
-                    tokio::time::timeout(CONNECT_TIMEOUT, self.connect_0())
+                    tokio::time::timeout(self.connect_timeout, self.connect_0())
                         .await
                         .map_err(|_| ClientError::ConnectTimeout)??;
 
                 self.relay_client = Some((relay_client.clone(), receiver));
                 self.next_conn();
+                self.send_buffered(&relay_client).await;
             } else {
                 trace!("already had connection");
             }
@@ -575,7 +776,11 @@ impl Actor {
             let hostname = self
                 .tls_servername()
                 .ok_or_else(|| ClientError::InvalidUrl("No tls servername".into()))?;
-            let tls_stream = self.tls_connector.connect(hostname, tcp_stream).await?;
+            let tls_stream = self
+                .tls_connector
+                .connect(hostname, tcp_stream)
+                .await
+                .map_err(ClientError::Tls)?;
             debug!("tls_connector connect success");
             Self::start_upgrade(tls_stream).await?
         } else {
@@ -594,6 +799,16 @@ impl Actor {
             ));
         }
 
+        // Presence is only sent to servers which announce it, older ones close the
+        // connection on the unknown frame.
+        let supports_presence = response
+            .headers()
+            .get_all(super::RELAY_FEATURES_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|feature| feature.trim() == super::PRESENCE_FEATURE);
+
         debug!("starting upgrade");
         let upgraded = match hyper::upgrade::on(response).await {
             Ok(upgraded) => upgraded,
@@ -609,6 +824,7 @@ impl Actor {
 
         let (relay_client, receiver) =
             RelayClientBuilder::new(self.secret_key.clone(), local_addr, reader, writer)
+                .supports_presence(supports_presence)
                 .build()
                 .await
                 .map_err(|e| ClientError::Build(e.to_string()))?;
@@ -617,6 +833,17 @@ impl Actor {
             relay_client.close().await;
             return Err(ClientError::Send);
         }
+        if !self.watched_presence.is_empty() && !relay_client.supports_presence() {
+            warn!("relay server does not support presence, not watching peers");
+        } else if !self.watched_presence.is_empty()
+            && relay_client
+                .watch_presence(self.watched_presence.clone())
+                .await
+                .is_err()
+        {
+            relay_client.close().await;
+            return Err(ClientError::Send);
+        }
 
         trace!("connect_0 done");
         Ok((relay_client, receiver))
@@ -672,6 +899,27 @@ impl Actor {
         }
     }
 
+    async fn watch_presence(&mut self, peers: Vec<PublicKey>) {
+        // Sent even if unchanged, as the server replies with the current presence.
+        self.watched_presence = peers;
+
+        // only send the peers if we already have a connection
+        let res = {
+            if let Some((ref client, _)) = self.relay_client {
+                if !client.supports_presence() {
+                    warn!("relay server does not support presence, not watching peers");
+                    return;
+                }
+                client.watch_presence(self.watched_presence.clone()).await
+            } else {
+                return;
+            }
+        };
+        if res.is_err() {
+            self.close_for_reconnect().await;
+        }
+    }
+
     fn local_addr(&self) -> Option<SocketAddr> {
         if self.is_closed {
             return None;
@@ -689,6 +937,7 @@ impl Actor {
         let connect_res = self.connect("ping").await.map(|(c, _, _)| c);
         let (ping, recv) = self.pings.register();
         trace!("ping: {}", hex::encode(ping));
+        let ping_timeout = self.ping_timeout;
 
         self.ping_tasks.spawn(async move {
             let res = match connect_res {
@@ -698,7 +947,7 @@ impl Actor {
                         warn!("failed to send ping: {:?}", err);
                         Err(ClientError::Send)
                     } else {
-                        match tokio::time::timeout(PING_TIMEOUT, recv).await {
+                        match tokio::time::timeout(ping_timeout, recv).await {
                             Ok(Ok(())) => Ok(start.elapsed()),
                             Err(_) => Err(ClientError::PingTimeout),
                             Ok(Err(_)) => Err(ClientError::PingAborted),
@@ -713,14 +962,45 @@ impl Actor {
 
     async fn send(&mut self, dst_key: PublicKey, b: Bytes) -> Result<(), ClientError> {
         trace!(dst = %dst_key.fmt_short(), len = b.len(), "send");
-        let (client, _, _) = self.connect("send").await?;
-        if client.send(dst_key, b).await.is_err() {
+        let client = match self.connect("send").await {
+            Ok((client, _, _)) => client,
+            Err(err) => {
+                if !self.is_closed && self.reconnect_buffer.push(dst_key, b) {
+                    trace!("buffered packet until reconnected: {err}");
+                    return Ok(());
+                }
+                return Err(err);
+            }
+        };
+        if client.send(dst_key, b.clone()).await.is_err() {
             self.close_for_reconnect().await;
+            if self.reconnect_buffer.push(dst_key, b) {
+                trace!("buffered packet until reconnected");
+                return Ok(());
+            }
             return Err(ClientError::Send);
         }
         Ok(())
     }
 
+    /// Sends the packets buffered while reconnecting on the new connection.
+    async fn send_buffered(&mut self, client: &RelayClient) {
+        let packets = self.reconnect_buffer.connected();
+        if packets.is_empty() {
+            return;
+        }
+        debug!(
+            "sending {} packets buffered while reconnecting",
+            packets.len()
+        );
+        for (dst_key, packet) in packets {
+            if let Err(err) = client.send(dst_key, packet).await {
+                warn!("failed to send buffered packets: {err:?}");
+                break;
+            }
+        }
+    }
+
     async fn send_pong(&mut self, data: [u8; 8]) -> Result<(), ClientError> {
         debug!("send_pong");
         if self.can_ack_pings {
@@ -738,6 +1018,7 @@ impl Actor {
     async fn close(mut self) {
         if !self.is_closed {
             self.is_closed = true;
+            self.reconnect_buffer.clear();
             self.close_for_reconnect().await;
         }
     }
@@ -786,6 +1067,9 @@ impl Actor {
 
     async fn dial_url(&self) -> Result<TcpStream, ClientError> {
         debug!(%self.url, "dial url");
+        if let Some(ref proxy) = self.socks5_proxy {
+            return self.dial_url_proxied(proxy).await;
+        }
 
         let prefer_ipv6 = self.prefer_ipv6().await;
         let dst_ip = resolve_host(&self.dns_resolver, &self.url, prefer_ipv6).await?;
@@ -796,20 +1080,44 @@ impl Actor {
         let addr = SocketAddr::new(dst_ip, port);
 
         debug!("connecting to {}", addr);
-        let tcp_stream =
-            tokio::time::timeout(
-                DIAL_NODE_TIMEOUT,
-                async move { TcpStream::connect(addr).await },
-            )
-            .await
-            .map_err(|_| ClientError::ConnectTimeout)?
-            .map_err(ClientError::DialIO)?;
+        let socket_options = self.socket_options.clone();
+        let tcp_stream = tokio::time::timeout(self.dial_timeout, async move {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket_options.apply((&socket).into(), addr.ip().into())?;
+            socket.connect(addr).await
+        })
+        .await
+        .map_err(|_| ClientError::ConnectTimeout)?
+        .map_err(ClientError::DialIO)?;
 
         tcp_stream.set_nodelay(true)?;
 
         Ok(tcp_stream)
     }
 
+    /// Connects through the SOCKS5 proxy, so the relay server never sees our own address.
+    async fn dial_url_proxied(&self, proxy: &Socks5Config) -> Result<TcpStream, ClientError> {
+        let port = self
+            .url_port()
+            .ok_or_else(|| ClientError::InvalidUrl("missing url port".into()))?;
+        let dst = match self.url.host() {
+            Some(url::Host::Domain(domain)) => socks5::Destination::Domain(domain.into(), port),
+            Some(url::Host::Ipv4(ip)) => socks5::Destination::Addr((ip, port).into()),
+            Some(url::Host::Ipv6(ip)) => socks5::Destination::Addr((ip, port).into()),
+            None => return Err(ClientError::InvalidUrl("missing host".into())),
+        };
+        debug!(proxy = %proxy.proxy, ?dst, "connecting through SOCKS5 proxy");
+        let tcp_stream = tokio::time::timeout(self.dial_timeout, socks5::connect(proxy, dst))
+            .await
+            .map_err(|_| ClientError::ConnectTimeout)?
+            .map_err(|err| ClientError::DialIO(std::io::Error::other(err)))?;
+        tcp_stream.set_nodelay(true)?;
+        Ok(tcp_stream)
+    }
+
     /// Reports whether IPv4 dials should be slightly
     /// delayed to give IPv6 a better chance of winning dial races.
     /// Implementations should only return true if IPv6 is expected
@@ -848,6 +1156,7 @@ impl Actor {
     async fn close_for_reconnect(&mut self) {
         debug!("close for reconnect");
         if let Some((client, _)) = self.relay_client.take() {
+            self.reconnect_buffer.disconnected();
             client.close().await
         }
     }
@@ -960,4 +1269,30 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_buffer() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let packet = |i: u8| Bytes::from(vec![i]);
+        let mut buffer = ReconnectBuffer::new(2, Duration::from_secs(2));
+
+        // nothing is buffered before a connection is lost
+        assert!(!buffer.push(a, packet(0)));
+
+        buffer.disconnected();
+        assert!(buffer.push(a, packet(1)));
+        assert!(buffer.push(b, packet(2)));
+        // the oldest packet is dropped when full
+        assert!(buffer.push(a, packet(3)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(buffer.push(b, packet(4)));
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        // too long after the connection was lost
+        assert!(!buffer.push(a, packet(5)));
+
+        // packets older than the window are not sent
+        assert_eq!(buffer.connected(), vec![(b, packet(4))]);
+        assert!(!buffer.push(a, packet(6)));
+    }
 }
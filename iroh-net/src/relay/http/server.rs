@@ -20,9 +20,9 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::key::SecretKey;
-use crate::relay::http::HTTP_UPGRADE_PROTOCOL;
+use crate::relay::http::{HTTP_UPGRADE_PROTOCOL, PRESENCE_FEATURE, RELAY_FEATURES_HEADER};
 use crate::relay::server::{ClientConnHandler, MaybeTlsStream};
-use crate::relay::MaybeTlsStreamServer;
+use crate::relay::{AccessLog, MaybeTlsStreamServer, ServerLimits};
 
 type BytesBody = http_body_util::Full<hyper::body::Bytes>;
 type HyperError = Box<dyn std::error::Error + Send + Sync>;
@@ -143,6 +143,10 @@ pub struct ServerBuilder {
     /// When `None`, a default is provided.
     #[debug("{}", not_found_fn.as_ref().map_or("None", |_| "Some(Box<Fn(ResponseBuilder) -> Result<Response<Body>> + Send + Sync + 'static>)"))]
     not_found_fn: Option<HyperHandler>,
+    /// Limits on the clients of the relay server and their memory use.
+    limits: ServerLimits,
+    /// Where to record the clients connecting and disconnecting.
+    access_log: Option<Arc<dyn AccessLog>>,
 }
 
 impl ServerBuilder {
@@ -157,6 +161,8 @@ impl ServerBuilder {
             relay_override: None,
             headers: HeaderMap::new(),
             not_found_fn: None,
+            limits: ServerLimits::default(),
+            access_log: None,
         }
     }
 
@@ -211,11 +217,31 @@ impl ServerBuilder {
         self
     }
 
+    /// Limit the clients of the relay server and their memory use.
+    ///
+    /// Ignored when no [`SecretKey`] was provided.
+    pub fn limits(mut self, limits: ServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Record the clients connecting to the relay server and disconnecting from it in `log`.
+    ///
+    /// Ignored when no [`SecretKey`] was provided.
+    pub fn access_log(mut self, log: Arc<dyn AccessLog>) -> Self {
+        self.access_log = Some(log);
+        self
+    }
+
     /// Build and spawn an HTTP(S) relay Server
     pub async fn spawn(self) -> Result<Server> {
         ensure!(self.secret_key.is_some() || self.relay_override.is_some(), "Must provide a `SecretKey` for the relay server OR pass in an override function for the 'relay' endpoint");
         let (relay_handler, relay_server) = if let Some(secret_key) = self.secret_key {
-            let server = crate::relay::server::Server::new(secret_key.clone());
+            let mut server =
+                crate::relay::server::Server::with_limits(secret_key.clone(), self.limits);
+            if let Some(log) = self.access_log {
+                server.set_access_log(log);
+            }
             (
                 RelayHandler::ConnHandler(server.client_conn_handler(self.headers.clone())),
                 Some(server),
@@ -383,6 +409,10 @@ impl Service<Request<Incoming>> for ClientConnHandler {
                 *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
                 res.headers_mut()
                     .insert(UPGRADE, HeaderValue::from_static(HTTP_UPGRADE_PROTOCOL));
+                res.headers_mut().insert(
+                    RELAY_FEATURES_HEADER,
+                    HeaderValue::from_static(PRESENCE_FEATURE),
+                );
                 Ok(res)
             }
         }
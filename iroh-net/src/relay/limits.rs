@@ -0,0 +1,138 @@
+//! Limits on the clients and memory of a relay [`super::Server`].
+//!
+//! A client which reads slower than packets are sent to it accumulates them in its send
+//! queue.  Without limits, many slow clients can make the server buffer more data than it
+//! has memory.  The [`MemoryBudget`] counts the bytes of all packets queued for clients, and
+//! the server drops packets instead of queueing them once a budget is exhausted.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Limits on the resources a relay server uses for its clients.
+///
+/// All limits are disabled by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Maximum number of clients connected at the same time.
+    ///
+    /// Further clients are sent a health problem and disconnected.  A client reconnecting
+    /// with the key of a connected client is always accepted, as it replaces the old
+    /// connection.
+    pub max_clients: Option<usize>,
+    /// Maximum number of packet bytes queued for a single client.
+    ///
+    /// Packets for a client which would exceed this are dropped.
+    pub max_client_queued_bytes: Option<usize>,
+    /// Maximum number of packet bytes queued for all clients together.
+    ///
+    /// Packets which would exceed this are dropped, whichever client they are for.
+    pub max_buffered_bytes: Option<usize>,
+}
+
+/// Counts the bytes queued for all clients, see the [module docs](self).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryBudget {
+    max_client_queued_bytes: Option<usize>,
+    max_buffered_bytes: Option<usize>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limits: &ServerLimits) -> Self {
+        Self {
+            max_client_queued_bytes: limits.max_client_queued_bytes,
+            max_buffered_bytes: limits.max_buffered_bytes,
+            buffered: Default::default(),
+        }
+    }
+
+    /// Creates the budget of a newly connected client.
+    pub(crate) fn client(&self) -> ClientBudget {
+        ClientBudget {
+            queued: Default::default(),
+            budget: self.clone(),
+        }
+    }
+
+    /// Number of bytes queued for all clients.
+    pub(crate) fn buffered_bytes(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the bytes queued for one client.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientBudget {
+    queued: Arc<AtomicUsize>,
+    budget: MemoryBudget,
+}
+
+impl ClientBudget {
+    /// Counts `len` bytes as queued for the client, unless this exceeds a limit.
+    ///
+    /// Returns whether the bytes were counted.  They must be released with
+    /// [`Self::release`] once they are no longer queued.
+    pub(crate) fn reserve(&self, len: usize) -> bool {
+        let queued = self.queued.fetch_add(len, Ordering::Relaxed) + len;
+        let buffered = self.budget.buffered.fetch_add(len, Ordering::Relaxed) + len;
+        let over_client = self
+            .budget
+            .max_client_queued_bytes
+            .is_some_and(|max| queued > max);
+        let over_total = self
+            .budget
+            .max_buffered_bytes
+            .is_some_and(|max| buffered > max);
+        if over_client || over_total {
+            self.release(len);
+            return false;
+        }
+        true
+    }
+
+    /// Releases `len` bytes counted by [`Self::reserve`].
+    pub(crate) fn release(&self, len: usize) {
+        self.queued.fetch_sub(len, Ordering::Relaxed);
+        self.budget.buffered.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Number of bytes queued for the client.
+    #[cfg(test)]
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let budget = MemoryBudget::new(&ServerLimits {
+            max_clients: None,
+            max_client_queued_bytes: Some(100),
+            max_buffered_bytes: Some(150),
+        });
+        let a = budget.client();
+        let b = budget.client();
+
+        assert!(a.reserve(60));
+        assert!(!a.reserve(60));
+        assert!(a.reserve(40));
+        assert_eq!(a.queued_bytes(), 100);
+
+        // the total limit applies across clients
+        assert!(b.reserve(50));
+        assert!(!b.reserve(1));
+        assert_eq!(budget.buffered_bytes(), 150);
+
+        a.release(100);
+        assert!(b.reserve(50));
+        assert_eq!(a.queued_bytes(), 0);
+        assert_eq!(b.queued_bytes(), 100);
+        assert_eq!(budget.buffered_bytes(), 100);
+    }
+}
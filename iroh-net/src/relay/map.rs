@@ -2,10 +2,13 @@
 
 use std::{collections::BTreeMap, fmt, sync::Arc};
 
-use anyhow::{ensure, Result};
+use anyhow::{ensure, Context, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::defaults::DEFAULT_RELAY_STUN_PORT;
+use crate::{
+    defaults::DEFAULT_RELAY_STUN_PORT,
+    key::{PublicKey, SecretKey, Signature},
+};
 
 use super::RelayUrl;
 
@@ -18,6 +21,13 @@ pub enum RelayMode {
     Default,
     /// Use a custom relay map.
     Custom(RelayMap),
+    /// Use a custom relay map signed by its publisher.
+    ///
+    /// The signature is verified when binding the endpoint, against the publisher key set
+    /// with [`MagicEndpointBuilder::relay_map_publisher`], which is required.
+    ///
+    /// [`MagicEndpointBuilder::relay_map_publisher`]: crate::magic_endpoint::MagicEndpointBuilder::relay_map_publisher
+    Signed(SignedRelayMap),
 }
 
 /// Configuration of all the relay servers that can be used.
@@ -45,6 +55,45 @@ impl RelayMap {
         self.nodes.values()
     }
 
+    /// Returns an `Iterator` over the nodes which can relay traffic, skipping STUN-only nodes.
+    pub fn relay_nodes(&self) -> impl Iterator<Item = &Arc<RelayNode>> {
+        self.nodes().filter(|node| !node.stun_only)
+    }
+
+    /// Returns an `Iterator` over the nodes in the region with the given code.
+    ///
+    /// Region codes are compared case-insensitively. Nodes without a region are never
+    /// returned.
+    pub fn nodes_in_region<'a>(
+        &'a self,
+        code: &'a str,
+    ) -> impl Iterator<Item = &'a Arc<RelayNode>> + 'a {
+        self.nodes().filter(move |node| {
+            node.metadata
+                .region
+                .as_deref()
+                .is_some_and(|region| region.eq_ignore_ascii_case(code))
+        })
+    }
+
+    /// Returns the relay node closest to the given coordinates.
+    ///
+    /// Only nodes which can relay traffic and have a [`RelayNodeMetadata::location`] are
+    /// considered, if there are none `None` is returned.
+    pub fn nearest_to(&self, latitude: f64, longitude: f64) -> Option<&Arc<RelayNode>> {
+        let here = GeoLocation {
+            latitude,
+            longitude,
+        };
+        self.relay_nodes()
+            .filter_map(|node| {
+                let location = node.metadata.location.as_ref()?;
+                Some((location.distance_km(&here), node))
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, node)| node)
+    }
+
     /// Is this a known node?
     pub fn contains_node(&self, url: &RelayUrl) -> bool {
         self.nodes.contains_key(url)
@@ -77,6 +126,7 @@ impl RelayMap {
                 url,
                 stun_only: false,
                 stun_port,
+                metadata: Default::default(),
             }
             .into(),
         );
@@ -105,6 +155,117 @@ impl RelayMap {
     }
 }
 
+/// A [`RelayMap`] together with a signature by its publisher.
+///
+/// Lets nodes which obtain their relay map from an untrusted location, like a CDN, make sure
+/// the map was not tampered with, see [`SignedRelayMap::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRelayMap {
+    nodes: Vec<SignedRelayNode>,
+    publisher: PublicKey,
+    signature: Signature,
+}
+
+impl SignedRelayMap {
+    /// Prefix of the signed message, so the signature can not be reused for anything else.
+    const SIGNATURE_DOMAIN: &'static [u8] = b"iroh-relay-map-v1";
+
+    /// Signs the relay map with the publisher's secret key.
+    pub fn sign(map: &RelayMap, secret_key: &SecretKey) -> Self {
+        let nodes: Vec<SignedRelayNode> = map
+            .nodes()
+            .map(|node| SignedRelayNode::from(node.as_ref().clone()))
+            .collect();
+        let signature = secret_key.sign(&Self::signed_message(&nodes));
+        Self {
+            nodes,
+            publisher: secret_key.public(),
+            signature,
+        }
+    }
+
+    /// The key of the publisher which signed this map.
+    pub fn publisher(&self) -> PublicKey {
+        self.publisher
+    }
+
+    /// Verifies the map was signed by the `trusted` publisher key and returns it.
+    ///
+    /// The publisher key included in the map is not trusted on its own, anyone can sign a map
+    /// with their own key.
+    pub fn verify(&self, trusted: &PublicKey) -> Result<RelayMap> {
+        ensure!(
+            self.publisher == *trusted,
+            "relay map published by untrusted key {}",
+            self.publisher.fmt_short()
+        );
+        self.publisher
+            .verify(&Self::signed_message(&self.nodes), &self.signature)
+            .context("invalid relay map signature")?;
+        RelayMap::from_nodes(self.nodes.iter().cloned().map(RelayNode::from))
+    }
+
+    fn signed_message(nodes: &[SignedRelayNode]) -> Vec<u8> {
+        let mut message = Self::SIGNATURE_DOMAIN.to_vec();
+        // Serializing plain data structures into a Vec can not fail.
+        let nodes = postcard::to_stdvec(nodes).expect("serialization failed");
+        message.extend_from_slice(&nodes);
+        message
+    }
+}
+
+/// A [`RelayNode`] as it is encoded in a [`SignedRelayMap`].
+///
+/// Unlike [`RelayNode`] no empty fields are skipped, which postcard could not decode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SignedRelayNode {
+    url: RelayUrl,
+    stun_only: bool,
+    stun_port: u16,
+    region: Option<String>,
+    location: Option<GeoLocation>,
+    provider: Option<String>,
+}
+
+impl From<RelayNode> for SignedRelayNode {
+    fn from(node: RelayNode) -> Self {
+        let RelayNode {
+            url,
+            stun_only,
+            stun_port,
+            metadata:
+                RelayNodeMetadata {
+                    region,
+                    location,
+                    provider,
+                },
+        } = node;
+        Self {
+            url,
+            stun_only,
+            stun_port,
+            region,
+            location,
+            provider,
+        }
+    }
+}
+
+impl From<SignedRelayNode> for RelayNode {
+    fn from(node: SignedRelayNode) -> Self {
+        Self {
+            url: node.url,
+            stun_only: node.stun_only,
+            stun_port: node.stun_port,
+            metadata: RelayNodeMetadata {
+                region: node.region,
+                location: node.location,
+                provider: node.provider,
+            },
+        }
+    }
+}
+
 impl fmt::Display for RelayMap {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Debug::fmt(&self, f)
@@ -127,6 +288,9 @@ pub struct RelayNode {
     ///
     /// Setting this to `0` means the default STUN port is used.
     pub stun_port: u16,
+    /// Optional information about where and by whom this relay server is run.
+    #[serde(default, skip_serializing_if = "RelayNodeMetadata::is_empty")]
+    pub metadata: RelayNodeMetadata,
 }
 
 impl fmt::Display for RelayNode {
@@ -134,3 +298,196 @@ impl fmt::Display for RelayNode {
         write!(f, "{}", self.url)
     }
 }
+
+/// Optional descriptive information about a [`RelayNode`].
+///
+/// None of this is needed to use the relay server, it helps with picking a relay server and
+/// with operating a fleet of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, PartialOrd, Ord)]
+pub struct RelayNodeMetadata {
+    /// Short code of the region the relay server is in, e.g. `"eu"` or `"us-east"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Geographic location of the relay server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<GeoLocation>,
+    /// Name of the provider hosting the relay server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+impl RelayNodeMetadata {
+    /// Whether no metadata is set at all.
+    pub fn is_empty(&self) -> bool {
+        self.region.is_none() && self.location.is_none() && self.provider.is_none()
+    }
+}
+
+/// A point on earth, in degrees.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoLocation {
+    /// The latitude in degrees, positive north of the equator.
+    pub latitude: f64,
+    /// The longitude in degrees, positive east of Greenwich.
+    pub longitude: f64,
+}
+
+impl GeoLocation {
+    /// The mean radius of the earth in kilometres.
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+
+    /// Returns the great-circle distance to `other` in kilometres.
+    pub fn distance_km(&self, other: &GeoLocation) -> f64 {
+        // The haversine formula.
+        let lat1 = self.latitude.to_radians();
+        let lat2 = other.latitude.to_radians();
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * Self::EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+// Coordinates are compared with `f64::total_cmp` so that the location can be part of the
+// `Eq` and `Ord` relay node.
+impl PartialEq for GeoLocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for GeoLocation {}
+
+impl PartialOrd for GeoLocation {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for GeoLocation {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.latitude
+            .total_cmp(&other.latitude)
+            .then_with(|| self.longitude.total_cmp(&other.longitude))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(url: &str, region: Option<&str>, location: Option<(f64, f64)>) -> RelayNode {
+        RelayNode {
+            url: url.parse().unwrap(),
+            stun_only: false,
+            stun_port: 0,
+            metadata: RelayNodeMetadata {
+                region: region.map(ToString::to_string),
+                location: location.map(|(latitude, longitude)| GeoLocation {
+                    latitude,
+                    longitude,
+                }),
+                provider: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_nodes_in_region() {
+        let map = RelayMap::from_nodes([
+            node("https://a.example", Some("eu"), None),
+            node("https://b.example", Some("na"), None),
+            node("https://c.example", Some("EU"), None),
+            node("https://d.example", None, None),
+        ])
+        .unwrap();
+        let urls: Vec<_> = map
+            .nodes_in_region("eu")
+            .map(|node| node.url.to_string())
+            .collect();
+        assert_eq!(urls, ["https://a.example./", "https://c.example./"]);
+        assert_eq!(map.nodes_in_region("ap").count(), 0);
+    }
+
+    #[test]
+    fn test_nearest_to() {
+        let frankfurt = (50.11, 8.68);
+        let new_york = (40.71, -74.01);
+        let mut stun_only = node("https://stun.example", None, Some((52.52, 13.40)));
+        stun_only.stun_only = true;
+        let map = RelayMap::from_nodes([
+            node("https://eu.example", None, Some(frankfurt)),
+            node("https://na.example", None, Some(new_york)),
+            node("https://unknown.example", None, None),
+            stun_only,
+        ])
+        .unwrap();
+
+        // Berlin is closer to the STUN-only node, which can not relay.
+        let nearest = map.nearest_to(52.52, 13.40).unwrap();
+        assert_eq!(nearest.url.to_string(), "https://eu.example./");
+        // Boston
+        let nearest = map.nearest_to(42.36, -71.06).unwrap();
+        assert_eq!(nearest.url.to_string(), "https://na.example./");
+
+        assert!(RelayMap::empty().nearest_to(0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_distance_km() {
+        let london = GeoLocation {
+            latitude: 51.5074,
+            longitude: -0.1278,
+        };
+        let paris = GeoLocation {
+            latitude: 48.8566,
+            longitude: 2.3522,
+        };
+        let d = london.distance_km(&paris);
+        assert!((d - 343.5).abs() < 1.0, "distance {d}");
+        assert_eq!(london.distance_km(&london), 0.0);
+    }
+
+    #[test]
+    fn test_signed_relay_map() {
+        let publisher = SecretKey::generate();
+        let map = RelayMap::from_nodes([
+            node("https://a.example", Some("eu"), Some((50.11, 8.68))),
+            node("https://b.example", None, None),
+        ])
+        .unwrap();
+        let signed = SignedRelayMap::sign(&map, &publisher);
+        assert_eq!(signed.publisher(), publisher.public());
+        assert_eq!(signed.verify(&publisher.public()).unwrap(), map);
+
+        // Signed by someone else.
+        let other = SecretKey::generate();
+        assert!(signed.verify(&other.public()).is_err());
+        let own = SignedRelayMap::sign(&map, &other);
+        assert!(own.verify(&publisher.public()).is_err());
+
+        // Tampered with after signing.
+        let mut tampered = signed.clone();
+        tampered.nodes[1].url = "https://rogue.example".parse().unwrap();
+        assert!(tampered.verify(&publisher.public()).is_err());
+
+        // Re-signed by someone else claiming to be the publisher.
+        let mut forged = SignedRelayMap::sign(&map, &other);
+        forged.publisher = publisher.public();
+        assert!(forged.verify(&publisher.public()).is_err());
+
+        // Survives a round trip.
+        let bytes = postcard::to_stdvec(&signed).unwrap();
+        let decoded: SignedRelayMap = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.verify(&publisher.public()).unwrap(), map);
+    }
+
+    #[test]
+    fn test_metadata_is_optional() {
+        let node = node("https://a.example", None, None);
+        let json = serde_json::to_string(&node).unwrap();
+        assert!(!json.contains("metadata"));
+        let decoded: RelayNode = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, node);
+    }
+}
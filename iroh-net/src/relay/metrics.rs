@@ -60,6 +60,10 @@ pub struct Metrics {
     pub accepts: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
+    /// Number of connections rejected because the server is full
+    pub rejected_clients: Counter,
+    /// Packets dropped because too many bytes are queued for clients
+    pub packets_dropped_over_budget: Counter,
     // TODO: enable when we can have multiple connections for one node id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -115,6 +119,12 @@ impl Default for Metrics {
 
             accepts: Counter::new("Number of times this server has accepted a connection."),
             disconnects: Counter::new("Number of clients that have then disconnected."),
+            rejected_clients: Counter::new(
+                "Number of connections rejected because the maximum number of clients is reached.",
+            ),
+            packets_dropped_over_budget: Counter::new(
+                "Number of packets dropped because too many bytes are queued for clients.",
+            ),
             // TODO: enable when we can have multiple connections for one node id
             // pub duplicate_client_keys: Counter::new("Number of duplicate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
@@ -1,4 +1,5 @@
 //! based on tailscale/derp/derp_server.go
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -19,12 +20,14 @@ use tracing::{info_span, trace, Instrument};
 use crate::key::{PublicKey, SecretKey};
 
 use super::{
+    access_log::{self, AccessLog},
     client_conn::ClientConnBuilder,
     clients::Clients,
     codec::{
         recv_client_key, DerpCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
         SERVER_CHANNEL_SIZE,
     },
+    limits::{MemoryBudget, ServerLimits},
     metrics::Metrics,
     types::ServerMessage,
 };
@@ -38,6 +41,9 @@ fn new_conn_num() -> usize {
 
 pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// The health problem sent to clients rejected because the server has too many clients.
+const SERVER_FULL_PROBLEM: &str = "relay server is full, try again later";
+
 /// A relay server.
 ///
 /// Responsible for managing connections to relay [`super::client::Client`]s, sending packets from one client to another.
@@ -58,14 +64,21 @@ pub struct Server {
     loop_handler: JoinHandle<Result<()>>,
     /// Done token, forces a hard shutdown. To gracefully shutdown, use [`Server::close`]
     cancel: CancellationToken,
+    /// Where to record the clients connecting and disconnecting
+    access_log: Option<Arc<dyn AccessLog>>,
     // TODO: stats collection
 }
 
 impl Server {
     /// TODO: replace with builder
     pub fn new(key: SecretKey) -> Self {
+        Self::with_limits(key, ServerLimits::default())
+    }
+
+    /// Creates a server which limits its clients and their memory use with `limits`.
+    pub fn with_limits(key: SecretKey, limits: ServerLimits) -> Self {
         let (server_channel_s, server_channel_r) = mpsc::channel(SERVER_CHANNEL_SIZE);
-        let server_actor = ServerActor::new(key.public(), server_channel_r);
+        let server_actor = ServerActor::new(key.public(), server_channel_r, &limits);
         let cancel_token = CancellationToken::new();
         let done = cancel_token.clone();
         let server_task = tokio::spawn(
@@ -81,9 +94,17 @@ impl Server {
             closed: false,
             loop_handler: server_task,
             cancel: cancel_token,
+            access_log: None,
         }
     }
 
+    /// Records the clients connecting to the server and disconnecting from it in `log`.
+    ///
+    /// Only applies to the [`ClientConnHandler`]s created afterwards.
+    pub fn set_access_log(&mut self, log: Arc<dyn AccessLog>) {
+        self.access_log = Some(log);
+    }
+
     /// Returns the server's secret key.
     pub fn secret_key(&self) -> &SecretKey {
         &self.secret_key
@@ -126,6 +147,7 @@ impl Server {
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
             default_headers: Arc::new(default_headers),
+            access_log: self.access_log.clone(),
         }
     }
 
@@ -147,6 +169,7 @@ pub struct ClientConnHandler {
     secret_key: SecretKey,
     write_timeout: Option<Duration>,
     pub(super) default_headers: Arc<HeaderMap>,
+    access_log: Option<Arc<dyn AccessLog>>,
 }
 
 impl Clone for ClientConnHandler {
@@ -156,6 +179,7 @@ impl Clone for ClientConnHandler {
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
             default_headers: Arc::clone(&self.default_headers),
+            access_log: self.access_log.clone(),
         }
     }
 }
@@ -169,14 +193,20 @@ impl ClientConnHandler {
     ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
     pub async fn accept(&self, io: MaybeTlsStream) -> Result<()> {
+        let remote_addr = io.peer_addr();
         let mut io = Framed::new(io, DerpCodec);
         trace!("accept: start");
         trace!("accept: recv client key");
         let (client_key, info) = recv_client_key(&mut io)
             .await
             .context("unable to receive client information")?;
+        let conn_num = new_conn_num();
 
         if info.version != PROTOCOL_VERSION {
+            if let Some(log) = &self.access_log {
+                let reason = format!("unsupported protocol version {}", info.version);
+                access_log::rejected(log.as_ref(), client_key, conn_num, remote_addr, reason);
+            }
             bail!(
                 "unexpected client version {}, expected {}",
                 info.version,
@@ -187,11 +217,13 @@ impl ClientConnHandler {
         trace!("accept: build client conn");
         let client_conn_builder = ClientConnBuilder {
             key: client_key,
-            conn_num: new_conn_num(),
+            conn_num,
             io,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
             server_channel: self.server_channel.clone(),
+            remote_addr,
+            access_log: self.access_log.clone(),
         };
         trace!("accept: create client");
         self.server_channel
@@ -209,17 +241,33 @@ pub(crate) struct ServerActor {
     receiver: mpsc::Receiver<ServerMessage>,
     /// All clients connected to this server
     clients: Clients,
+    /// Maximum number of clients connected at the same time
+    max_clients: Option<usize>,
 }
 
 impl ServerActor {
-    pub(crate) fn new(key: PublicKey, receiver: mpsc::Receiver<ServerMessage>) -> Self {
+    pub(crate) fn new(
+        key: PublicKey,
+        receiver: mpsc::Receiver<ServerMessage>,
+        limits: &ServerLimits,
+    ) -> Self {
         Self {
             key,
             receiver,
-            clients: Clients::new(),
+            clients: Clients::new(MemoryBudget::new(limits)),
+            max_clients: limits.max_clients,
         }
     }
 
+    /// Whether the client with `key` can not connect, because the server is full.
+    ///
+    /// Clients replacing their own connection are always accepted.
+    fn is_full(&self, key: &PublicKey) -> bool {
+        self.max_clients
+            .is_some_and(|max| self.clients.num_clients() >= max)
+            && !self.clients.contains_key(key)
+    }
+
     pub(crate) async fn run(mut self, done: CancellationToken) -> Result<()> {
         loop {
             tokio::select! {
@@ -271,10 +319,20 @@ impl ServerActor {
                             }
                        }
                        ServerMessage::CreateClient(client_builder) => {
+                           let key = client_builder.key;
+                           if self.is_full(&key) {
+                               inc!(Metrics, rejected_clients);
+                               tracing::warn!("rejecting client {key:?}: server is full");
+                               tokio::spawn(async move {
+                                   if let Err(err) = client_builder.reject(SERVER_FULL_PROBLEM).await {
+                                       trace!("failed to reject client {key:?}: {err:?}");
+                                   }
+                               }.instrument(tracing::Span::current()));
+                               continue;
+                           }
                            inc!(Metrics, accepts);
 
-                           tracing::trace!("create client: {:?}", client_builder.key);
-                           let key = client_builder.key;
+                           tracing::trace!("create client: {:?}", key);
 
                            report_usage_stats(&UsageStatsReport::new(
                                 "relay_accepts".to_string(),
@@ -299,6 +357,10 @@ impl ServerActor {
                                self.clients.unregister(&key);
                             }
                        }
+                       ServerMessage::WatchPresence((key, peers)) => {
+                           tracing::trace!("watch presence: {:?} watches {} peers", key, peers.len());
+                           self.clients.watch_presence(&key, peers);
+                       }
                        ServerMessage::Shutdown => {
                         tracing::info!("server gracefully shutting down...");
                         // close all client connections and client read/write loops
@@ -353,6 +415,18 @@ pub enum MaybeTlsStream {
     Test(tokio::io::DuplexStream),
 }
 
+impl MaybeTlsStream {
+    /// The address of the remote end of the connection, if known.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.peer_addr().ok(),
+            MaybeTlsStream::Tls(s) => s.get_ref().0.peer_addr().ok(),
+            #[cfg(test)]
+            MaybeTlsStream::Test(_) => None,
+        }
+    }
+}
+
 impl AsyncRead for MaybeTlsStream {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -434,6 +508,7 @@ mod tests {
     use tracing_subscriber::{prelude::*, EnvFilter};
 
     use bytes::Bytes;
+    use futures::StreamExt;
     use tokio::io::DuplexStream;
 
     fn test_client_builder(
@@ -450,6 +525,8 @@ mod tests {
                 write_timeout: None,
                 channel_capacity: 10,
                 server_channel,
+                remote_addr: None,
+                access_log: None,
             },
             Framed::new(test_io, DerpCodec),
         )
@@ -461,7 +538,8 @@ mod tests {
 
         // make server actor
         let (server_channel, server_channel_r) = mpsc::channel(20);
-        let server_actor: ServerActor = ServerActor::new(server_key, server_channel_r);
+        let server_actor: ServerActor =
+            ServerActor::new(server_key, server_channel_r, &ServerLimits::default());
         let done = CancellationToken::new();
         let server_done = done.clone();
 
@@ -522,6 +600,52 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_actor_max_clients() -> Result<()> {
+        let server_key = SecretKey::generate().public();
+        let limits = ServerLimits {
+            max_clients: Some(1),
+            ..Default::default()
+        };
+        let (server_channel, server_channel_r) = mpsc::channel(20);
+        let server_actor = ServerActor::new(server_key, server_channel_r, &limits);
+        let server_task = tokio::spawn(
+            server_actor
+                .run(CancellationToken::new())
+                .instrument(info_span!("relay.server")),
+        );
+
+        let key_a = SecretKey::generate().public();
+        let (client_a, _a_io) = test_client_builder(key_a, 1, server_channel.clone());
+        server_channel
+            .send(ServerMessage::CreateClient(client_a))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+
+        // b is told the server is full and disconnected
+        let key_b = SecretKey::generate().public();
+        let (client_b, mut b_io) = test_client_builder(key_b, 2, server_channel.clone());
+        server_channel
+            .send(ServerMessage::CreateClient(client_b))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        let frame = recv_frame(FrameType::Health, &mut b_io).await?;
+        assert_eq!(
+            frame,
+            Frame::Health {
+                problem: SERVER_FULL_PROBLEM.into()
+            }
+        );
+        assert!(b_io.next().await.is_none());
+
+        server_channel
+            .send(ServerMessage::Shutdown)
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        server_task.await??;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_conn_handler() -> Result<()> {
         // create client connection handler
@@ -532,6 +656,7 @@ mod tests {
             write_timeout: None,
             server_channel: server_channel_s,
             default_headers: Default::default(),
+            access_log: None,
         };
 
         // create the parts needed for a client
@@ -64,5 +64,7 @@ pub(crate) enum ServerMessage {
     #[debug("CreateClient")]
     CreateClient(ClientConnBuilder),
     RemoveClient((PublicKey, usize)),
+    /// Replaces the peers the client watches the presence of.
+    WatchPresence((PublicKey, Vec<PublicKey>)),
     Shutdown,
 }
@@ -0,0 +1,281 @@
+//! Persisting state of a node across restarts.
+//!
+//! A [`Storage`] is a small key-value store for the state the networking layer keeps between
+//! runs, e.g. the addresses of known nodes or the secret key.  [`FsStorage`] keeps every entry
+//! in a file in one data directory, [`MemStorage`] keeps them in memory.
+//!
+//! Entries written with [`store`] are versioned and checksummed, [`load`] treats entries which
+//! are corrupted or written by an incompatible version as missing.  Losing cached state is
+//! never worse than not having it, so a damaged data directory does not prevent a node from
+//! starting.  The secret key is the exception: it is read with [`try_load`], as replacing
+//! it would change the identity of the node.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+/// Key under which the known node addresses are stored.
+pub(crate) const NODES_KEY: &str = "nodes";
+/// Version of the entry under [`NODES_KEY`].
+pub(crate) const NODES_VERSION: u16 = 1;
+/// Key under which the secret key is stored.
+pub(crate) const SECRET_KEY_KEY: &str = "secret-key";
+/// Version of the entry under [`SECRET_KEY_KEY`].
+pub(crate) const SECRET_KEY_VERSION: u16 = 1;
+
+/// Marks entries written by [`store`].
+const MAGIC: &[u8; 4] = b"iroh";
+/// Length of the checksum of the payload.
+const CHECKSUM_LEN: usize = 8;
+/// Length of the header preceding the payload: magic, version and checksum.
+const HEADER_LEN: usize = MAGIC.len() + 2 + CHECKSUM_LEN;
+
+/// A key-value store for the persistent state of a node.
+///
+/// Keys are short ASCII names chosen by iroh, made of alphanumeric characters, `-` and `_`.
+/// The operations are blocking, they are run outside of the async runtime.
+pub trait Storage: Debug + Send + Sync + 'static {
+    /// Reads the entry for `key`, `None` if there is none.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Writes the entry for `key`, replacing any previous one.
+    ///
+    /// Implementations should replace entries atomically, so that a crash leaves either the
+    /// old or the new entry.
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()>;
+
+    /// Removes the entry for `key`, if any.
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// A [`Storage`] keeping each entry in a file in a directory.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    dir: PathBuf,
+}
+
+impl FsStorage {
+    /// Creates a storage in `dir`.
+    ///
+    /// The directory is created when the first entry is written.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory entries are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let valid = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid storage key {key:?}"),
+            ));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl Storage for FsStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(key)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp_path = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Entries may be secret, e.g. the secret key.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(value)?;
+        // Without this a crash after the rename can leave an empty entry.
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match std::fs::remove_file(self.path(key)?) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// A [`Storage`] keeping entries in memory, for tests and nodes which must not touch the disk.
+#[derive(Debug, Default)]
+pub struct MemStorage {
+    entries: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemStorage {
+    /// Creates an empty storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.entries.lock().insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.entries.lock().remove(key);
+        Ok(())
+    }
+}
+
+/// Writes `value` under `key`, tagged with the `version` of its format.
+pub fn store<T: Serialize>(
+    storage: &dyn Storage,
+    key: &str,
+    version: u16,
+    value: &T,
+) -> anyhow::Result<()> {
+    let payload = postcard::to_stdvec(value)?;
+    let mut entry = Vec::with_capacity(HEADER_LEN + payload.len());
+    entry.extend_from_slice(MAGIC);
+    entry.extend_from_slice(&version.to_be_bytes());
+    entry.extend_from_slice(&checksum(&payload));
+    entry.extend_from_slice(&payload);
+    storage.put(key, &entry)?;
+    Ok(())
+}
+
+/// Reads the value under `key` written with [`store`] for `version`.
+///
+/// Returns `None` if there is no entry, or if it cannot be used: it is corrupted, was written
+/// for another version or fails to read.  Such entries are logged and otherwise ignored, the
+/// next [`store`] replaces them.
+pub fn load<T: DeserializeOwned>(storage: &dyn Storage, key: &str, version: u16) -> Option<T> {
+    match try_load(storage, key, version) {
+        Ok(value) => value,
+        Err(err) => {
+            warn!(key, "ignoring stored state: {err:#}");
+            None
+        }
+    }
+}
+
+/// Reads the value under `key` written with [`store`] for `version`.
+///
+/// Unlike [`load`], fails if the entry can not be read or used, for entries which must not
+/// be replaced.  Returns `None` only if there is no entry.
+pub fn try_load<T: DeserializeOwned>(
+    storage: &dyn Storage,
+    key: &str,
+    version: u16,
+) -> anyhow::Result<Option<T>> {
+    let Some(entry) = storage.get(key).context("failed to read")? else {
+        return Ok(None);
+    };
+    decode(&entry, version).map(Some)
+}
+
+fn decode<T: DeserializeOwned>(entry: &[u8], version: u16) -> anyhow::Result<T> {
+    anyhow::ensure!(
+        entry.len() >= HEADER_LEN && entry.starts_with(MAGIC),
+        "not a state entry"
+    );
+    let (header, payload) = entry.split_at(HEADER_LEN);
+    let stored_version = u16::from_be_bytes([header[4], header[5]]);
+    anyhow::ensure!(
+        stored_version == version,
+        "stored with version {stored_version}, expected {version}"
+    );
+    anyhow::ensure!(header[6..] == checksum(payload), "checksum mismatch");
+    Ok(postcard::from_bytes(payload)?)
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest.as_ref()[..CHECKSUM_LEN]);
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_load() {
+        let storage = MemStorage::new();
+        assert_eq!(load::<Vec<u32>>(&storage, "numbers", 1), None);
+
+        store(&storage, "numbers", 1, &vec![1u32, 2, 3]).unwrap();
+        assert_eq!(load(&storage, "numbers", 1), Some(vec![1u32, 2, 3]));
+        // Another version of the format is not read.
+        assert_eq!(load::<Vec<u32>>(&storage, "numbers", 2), None);
+
+        storage.delete("numbers").unwrap();
+        assert_eq!(load::<Vec<u32>>(&storage, "numbers", 1), None);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let storage = MemStorage::new();
+        store(&storage, "numbers", 1, &vec![1u32, 2, 3]).unwrap();
+        let mut entry = storage.get("numbers").unwrap().unwrap();
+        *entry.last_mut().unwrap() ^= 0xff;
+        storage.put("numbers", &entry).unwrap();
+        assert_eq!(load::<Vec<u32>>(&storage, "numbers", 1), None);
+
+        storage.put("numbers", b"garbage").unwrap();
+        assert_eq!(load::<Vec<u32>>(&storage, "numbers", 1), None);
+        assert!(try_load::<Vec<u32>>(&storage, "numbers", 1).is_err());
+        storage.delete("numbers").unwrap();
+        assert_eq!(try_load::<Vec<u32>>(&storage, "numbers", 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_fs_storage() {
+        let root = testdir::testdir!();
+        let storage = FsStorage::new(root.join("state"));
+        assert_eq!(storage.get("nodes").unwrap(), None);
+        storage.put("nodes", b"one").unwrap();
+        storage.put("nodes", b"two").unwrap();
+        assert_eq!(storage.get("nodes").unwrap().as_deref(), Some(&b"two"[..]));
+        storage.delete("nodes").unwrap();
+        storage.delete("nodes").unwrap();
+        assert_eq!(storage.get("nodes").unwrap(), None);
+
+        assert!(storage.put("../escape", b"").is_err());
+        assert!(storage.get("").is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            storage.put("secret-key", b"secret").unwrap();
+            let metadata = std::fs::metadata(storage.dir().join("secret-key")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
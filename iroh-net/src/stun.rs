@@ -194,6 +194,7 @@ pub(crate) mod test {
                 url,
                 stun_port: port,
                 stun_only,
+                metadata: Default::default(),
             }
         });
         RelayMap::from_nodes(nodes).expect("generated invalid nodes")
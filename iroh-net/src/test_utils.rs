@@ -1,10 +1,13 @@
 //! Internal utilities to support testing.
 
+use std::net::{IpAddr, SocketAddr};
+
 use anyhow::Result;
 use tokio::sync::oneshot;
 use tracing::{error_span, info_span, Instrument};
 
-use crate::key::SecretKey;
+use crate::disco;
+use crate::key::{PublicKey, SecretKey};
 use crate::relay::{RelayMap, RelayNode, RelayUrl};
 
 /// A drop guard to clean up test infrastructure.
@@ -24,10 +27,33 @@ pub struct CleanupDropGuard(pub(crate) oneshot::Sender<()>);
 ///
 /// [`MagicEndpoint::connect`]: crate::magic_endpoint::MagicEndpoint
 pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, CleanupDropGuard)> {
+    run_relay_server_at([127, 0, 0, 1].into(), "localhost").await
+}
+
+/// Runs a relay server with STUN enabled on the given IP address.
+///
+/// Unlike [`run_relay_server`] the relay URL uses the IP address itself, so it can be
+/// reached from other network namespaces.  Clients need to skip the certificate
+/// verification.
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) async fn run_relay_server_on(
+    ip: IpAddr,
+) -> Result<(RelayMap, RelayUrl, CleanupDropGuard)> {
+    let host = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    };
+    run_relay_server_at(ip, &host).await
+}
+
+async fn run_relay_server_at(
+    ip: IpAddr,
+    host: &str,
+) -> Result<(RelayMap, RelayUrl, CleanupDropGuard)> {
     let server_key = SecretKey::generate();
     let me = server_key.public().fmt_short();
     let tls_config = crate::relay::http::make_tls_config();
-    let server = crate::relay::http::ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+    let server = crate::relay::http::ServerBuilder::new((ip, 0).into())
         .secret_key(Some(server_key))
         .tls_config(Some(tls_config))
         .spawn()
@@ -38,13 +64,14 @@ pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, CleanupDropGuard)
     println!("relay listening on {:?}", https_addr);
 
     let (stun_addr, _, stun_drop_guard) = crate::stun::test::serve(server.addr().ip()).await?;
-    let url: RelayUrl = format!("https://localhost:{}", https_addr.port())
+    let url: RelayUrl = format!("https://{host}:{}", https_addr.port())
         .parse()
         .unwrap();
     let m = RelayMap::from_nodes([RelayNode {
         url: url.clone(),
         stun_only: false,
         stun_port: stun_addr.port(),
+        metadata: Default::default(),
     }])
     .expect("hardcoded");
 
@@ -63,6 +90,55 @@ pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, CleanupDropGuard)
     Ok((m, url, CleanupDropGuard(tx)))
 }
 
+/// Prepares transmits for sending over a UDP and a relay path at the same time.
+///
+/// This is what the magic socket does while no direct path is confirmed yet: the transmits
+/// are readdressed to `addr` into `buf` and split into relay packets.  Returns the number
+/// of relay packets.  Exposed for the benchmarks.
+pub fn prepare_dual_path_send(
+    buf: &mut Vec<quinn_udp::Transmit>,
+    transmits: &[quinn_udp::Transmit],
+    addr: SocketAddr,
+) -> usize {
+    crate::magicsock::address_transmits(buf, transmits, addr);
+    crate::magicsock::split_packets(transmits).len()
+}
+
+/// Encodes a disco ping from `sender` to `dst`, as sent over UDP.
+///
+/// Exposed for the benchmarks.
+pub fn disco_ping(sender: &SecretKey, dst: &PublicKey, tx_id: [u8; 12]) -> Vec<u8> {
+    let msg = disco::Message::Ping(disco::Ping {
+        tx_id: tx_id.into(),
+        node_key: sender.public(),
+        home_relay: None,
+        cookie: None,
+    });
+    let mut seal = msg.as_bytes();
+    sender.shared(dst).seal(&mut seal);
+    disco::encode_message(&sender.public(), seal)
+}
+
+/// Returns the transaction ID of `packet` if it is a disco pong for `receiver`.
+///
+/// Exposed for the benchmarks.
+pub fn disco_pong_tx_id(receiver: &SecretKey, packet: &[u8]) -> Option<[u8; 12]> {
+    let (sender, sealed_box) = disco::source_and_box(packet)?;
+    let mut payload = sealed_box.sealed.to_vec();
+    receiver.shared(&sender).open(&mut payload).ok()?;
+    match disco::Message::from_bytes(&payload).ok()? {
+        disco::Message::Pong(pong) => {
+            let mut tx_id = [0u8; 12];
+            tx_id.copy_from_slice(&pong.tx_id);
+            Some(tx_id)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) mod netsim;
+
 #[cfg(test)]
 pub(crate) mod dns_server {
     use std::net::{Ipv4Addr, SocketAddr};
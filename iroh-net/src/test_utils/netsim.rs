@@ -0,0 +1,406 @@
+//! Simulated networks with NAT routers for end-to-end hole punching tests.
+//!
+//! Each node runs in its own network namespace behind a router namespace, which NATs the
+//! node's traffic using nftables.  All routers are connected to a shared "internet"
+//! namespace which also runs the relay server:
+//!
+//! ```text
+//! node 1 (192.168.1.2) -- router 1 (198.51.100.1) --+
+//!                                                   +-- internet (relay on 198.51.100.254)
+//! node 2 (192.168.2.2) -- router 2 (198.51.100.2) --+
+//! ```
+//!
+//! Setting this up requires root, `iproute2` and `nftables`, so the tests are ignored by
+//! default.  Run them with:
+//!
+//! ```text
+//! sudo -E cargo test -p iroh-net netsim -- --ignored
+//! ```
+
+use std::{
+    fs::File,
+    future::Future,
+    io::Write,
+    net::Ipv4Addr,
+    os::fd::AsRawFd,
+    process::{Command, Stdio},
+    thread::JoinHandle,
+};
+
+use anyhow::{ensure, Context, Result};
+
+/// The address of the relay server in the internet namespace.
+pub(crate) const RELAY_IP: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 254);
+
+/// The NAT behaviour of a router.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Nat {
+    /// Endpoint-independent mapping and filtering.
+    ///
+    /// The node is reachable by anyone on the port it used for sending.
+    FullCone,
+    /// Endpoint-dependent mapping with random ports, unsolicited inbound traffic is dropped.
+    Symmetric,
+}
+
+impl Nat {
+    /// The nftables ruleset for a router with this NAT.
+    fn ruleset(&self, wan_ip: Ipv4Addr, lan_ip: Ipv4Addr) -> String {
+        match self {
+            Nat::FullCone => format!(
+                r#"
+                table ip nat {{
+                    chain prerouting {{
+                        type nat hook prerouting priority dstnat;
+                        iifname "wan0" dnat to {lan_ip}
+                    }}
+                    chain postrouting {{
+                        type nat hook postrouting priority srcnat;
+                        oifname "wan0" snat to {wan_ip}
+                    }}
+                }}
+                "#
+            ),
+            Nat::Symmetric => r#"
+                table ip nat {
+                    chain postrouting {
+                        type nat hook postrouting priority srcnat;
+                        oifname "wan0" masquerade fully-random
+                    }
+                }
+                table ip filter {
+                    chain forward {
+                        type filter hook forward priority filter; policy drop;
+                        ct state established,related accept
+                        iifname "lan0" accept
+                    }
+                }
+                "#
+            .to_string(),
+        }
+    }
+}
+
+/// The network namespaces for two NATed nodes and the internet, removed on drop.
+#[derive(Debug)]
+pub(crate) struct NatLab {
+    prefix: String,
+}
+
+impl NatLab {
+    /// Creates the namespaces, with node 1 behind `nats[0]` and node 2 behind `nats[1]`.
+    pub(crate) fn new(nats: [Nat; 2]) -> Result<Self> {
+        // Created first so that a partial setup is cleaned up as well.
+        let lab = Self {
+            prefix: format!("iroh{}", std::process::id()),
+        };
+        lab.setup(nats)?;
+        Ok(lab)
+    }
+
+    /// The namespace of the internet, where the relay server should run.
+    pub(crate) fn internet(&self) -> String {
+        format!("{}-inet", self.prefix)
+    }
+
+    /// The namespace of node 1 or 2.
+    pub(crate) fn node(&self, i: u8) -> String {
+        format!("{}-n{i}", self.prefix)
+    }
+
+    fn router(&self, i: u8) -> String {
+        format!("{}-r{i}", self.prefix)
+    }
+
+    fn setup(&self, nats: [Nat; 2]) -> Result<()> {
+        let inet = self.internet();
+        add_netns(&inet)?;
+        ip(&["-n", &inet, "link", "add", "br0", "type", "bridge"])?;
+        ip(&[
+            "-n",
+            &inet,
+            "addr",
+            "add",
+            &format!("{RELAY_IP}/24"),
+            "dev",
+            "br0",
+        ])?;
+        ip(&["-n", &inet, "link", "set", "br0", "up"])?;
+
+        for (i, nat) in (1..).zip(nats) {
+            let router = self.router(i);
+            let node = self.node(i);
+            let wan_ip = Ipv4Addr::new(198, 51, 100, i);
+            let gateway = Ipv4Addr::new(192, 168, i, 1);
+            let lan_ip = Ipv4Addr::new(192, 168, i, 2);
+            add_netns(&router)?;
+            add_netns(&node)?;
+
+            let port = format!("port{i}");
+            veth(&inet, &port, &router, "wan0")?;
+            ip(&["-n", &inet, "link", "set", &port, "master", "br0", "up"])?;
+            ip(&[
+                "-n",
+                &router,
+                "addr",
+                "add",
+                &format!("{wan_ip}/24"),
+                "dev",
+                "wan0",
+            ])?;
+            ip(&["-n", &router, "link", "set", "wan0", "up"])?;
+
+            veth(&router, "lan0", &node, "eth0")?;
+            ip(&[
+                "-n",
+                &router,
+                "addr",
+                "add",
+                &format!("{gateway}/24"),
+                "dev",
+                "lan0",
+            ])?;
+            ip(&["-n", &router, "link", "set", "lan0", "up"])?;
+            ip(&[
+                "-n",
+                &node,
+                "addr",
+                "add",
+                &format!("{lan_ip}/24"),
+                "dev",
+                "eth0",
+            ])?;
+            ip(&["-n", &node, "link", "set", "eth0", "up"])?;
+            ip(&[
+                "-n",
+                &node,
+                "route",
+                "add",
+                "default",
+                "via",
+                &gateway.to_string(),
+            ])?;
+
+            run(
+                Command::new("ip").args([
+                    "netns",
+                    "exec",
+                    &router,
+                    "sysctl",
+                    "-qw",
+                    "net.ipv4.ip_forward=1",
+                ]),
+                None,
+            )?;
+            run(
+                Command::new("ip").args(["netns", "exec", &router, "nft", "-f", "-"]),
+                Some(&nat.ruleset(wan_ip, lan_ip)),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NatLab {
+    fn drop(&mut self) {
+        let mut namespaces = vec![self.internet()];
+        for i in 1..=2 {
+            namespaces.push(self.router(i));
+            namespaces.push(self.node(i));
+        }
+        for ns in namespaces {
+            ip(&["netns", "delete", &ns]).ok();
+        }
+    }
+}
+
+/// Runs `f` on a new thread inside the network namespace, with its own tokio runtime.
+///
+/// Network namespaces are per thread, so all sockets created by `f` belong to the
+/// namespace.
+pub(crate) fn spawn_in<F, Fut, T>(ns: &str, f: F) -> JoinHandle<Result<T>>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>>,
+    T: Send + 'static,
+{
+    let path = format!("/run/netns/{ns}");
+    std::thread::Builder::new()
+        .name(ns.to_string())
+        .spawn(move || {
+            let file = File::open(&path).with_context(|| format!("failed to open {path}"))?;
+            // SAFETY: the file descriptor is valid for the duration of the call.
+            if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("failed to enter netns");
+            }
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            rt.block_on(f())
+        })
+        .expect("failed to spawn thread")
+}
+
+fn add_netns(ns: &str) -> Result<()> {
+    ip(&["netns", "add", ns])?;
+    ip(&["-n", ns, "link", "set", "lo", "up"])
+}
+
+/// Creates a veth pair between two namespaces.
+fn veth(ns_a: &str, name_a: &str, ns_b: &str, name_b: &str) -> Result<()> {
+    ip(&[
+        "link", "add", name_a, "netns", ns_a, "type", "veth", "peer", "name", name_b, "netns", ns_b,
+    ])
+}
+
+fn ip(args: &[&str]) -> Result<()> {
+    run(Command::new("ip").args(args), None)
+}
+
+fn run(cmd: &mut Command, stdin: Option<&str>) -> Result<()> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {cmd:?}"))?;
+    if let Some(input) = stdin {
+        let mut pipe = child.stdin.take().expect("piped");
+        pipe.write_all(input.as_bytes())?;
+    }
+    drop(child.stdin.take());
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "{cmd:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::sync::oneshot;
+
+    use crate::{
+        magicsock::ConnectionType,
+        relay::{RelayMap, RelayMode},
+        test_utils::run_relay_server_on,
+        MagicEndpoint,
+    };
+
+    use super::*;
+
+    const ALPN: &[u8] = b"n0/iroh/test-netsim";
+
+    /// How long to wait for hole punching after the connection is established.
+    const HOLEPUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+    async fn endpoint(relay_map: RelayMap) -> Result<MagicEndpoint> {
+        MagicEndpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .alpns(vec![ALPN.to_vec()])
+            .bind(0)
+            .await
+    }
+
+    /// Connects node 2 to node 1 and echoes a message.
+    ///
+    /// Returns whether a direct path was established.
+    fn connect_through_nats(nats: [Nat; 2]) -> Result<bool> {
+        let _guard = iroh_test::logging::setup();
+        let lab = NatLab::new(nats)?;
+
+        let (relay_tx, relay_rx) = oneshot::channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let relay = spawn_in(&lab.internet(), move || async move {
+            let (relay_map, _url, _guard) = run_relay_server_on(RELAY_IP.into()).await?;
+            relay_tx.send(relay_map).ok();
+            stop_rx.await.ok();
+            Ok(())
+        });
+        let Ok(relay_map) = relay_rx.blocking_recv() else {
+            return relay.join().expect("relay panicked").map(|_| false);
+        };
+
+        let (addr_tx, addr_rx) = oneshot::channel();
+        let listener = spawn_in(&lab.node(1), {
+            let relay_map = relay_map.clone();
+            move || async move {
+                let ep = endpoint(relay_map).await?;
+                addr_tx.send(ep.my_addr().await?).ok();
+                let conn = ep.accept().await.context("endpoint closed")?.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let msg = recv.read_to_end(100).await?;
+                send.write_all(&msg).await?;
+                send.finish().await?;
+                conn.closed().await;
+                Ok(())
+            }
+        });
+        let Ok(node1_addr) = addr_rx.blocking_recv() else {
+            return listener.join().expect("listener panicked").map(|_| false);
+        };
+
+        let dialer = spawn_in(&lab.node(2), move || async move {
+            let ep = endpoint(relay_map).await?;
+            let node1 = node1_addr.node_id;
+            let conn = ep.connect(node1_addr, ALPN).await?;
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(b"hello").await?;
+            send.finish().await?;
+            let echo = recv.read_to_end(100).await?;
+            ensure!(echo == b"hello", "wrong echo: {echo:?}");
+
+            let mut conn_types = ep.conn_type_stream(&node1)?;
+            let direct = tokio::time::timeout(HOLEPUNCH_TIMEOUT, async {
+                while let Some(conn_type) = conn_types.next().await {
+                    if matches!(conn_type, ConnectionType::Direct(_)) {
+                        return true;
+                    }
+                }
+                false
+            })
+            .await
+            .unwrap_or(false);
+
+            conn.close(0u32.into(), b"done");
+            ep.close(0u32.into(), b"done").await?;
+            Ok(direct)
+        });
+
+        let direct = dialer.join().expect("dialer panicked")?;
+        listener.join().expect("listener panicked")?;
+        stop_tx.send(()).ok();
+        relay.join().expect("relay panicked")?;
+        Ok(direct)
+    }
+
+    #[test]
+    #[ignore = "requires root, iproute2 and nftables"]
+    fn netsim_full_cone_holepunch() -> Result<()> {
+        let direct = connect_through_nats([Nat::FullCone, Nat::FullCone])?;
+        assert!(direct, "no direct path between full cone NATs");
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires root, iproute2 and nftables"]
+    fn netsim_symmetric_to_full_cone_holepunch() -> Result<()> {
+        let direct = connect_through_nats([Nat::Symmetric, Nat::FullCone])?;
+        assert!(direct, "no direct path from symmetric to full cone NAT");
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires root, iproute2 and nftables"]
+    fn netsim_symmetric_falls_back_to_relay() -> Result<()> {
+        let direct = connect_through_nats([Nat::Symmetric, Nat::Symmetric])?;
+        assert!(!direct, "unexpected direct path between symmetric NATs");
+        Ok(())
+    }
+}
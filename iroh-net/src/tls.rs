@@ -2,6 +2,12 @@
 //!
 //! See <https://github.com/libp2p/specs/blob/master/tls/tls.md>.
 //! Based on rust-libp2p/transports/tls
+//!
+//! Every node presents a self-signed certificate which embeds its node key, signed by that
+//! key.  When dialing a node the client verifies that the certificate is for the node it
+//! intended to connect to, so the connection is authenticated without any external PKI.
+//! RFC 7250 raw public keys would make the certificate unnecessary, but are not supported
+//! by the rustls version in use.
 
 use std::sync::Arc;
 
@@ -10,8 +16,24 @@ use crate::key::{PublicKey, SecretKey};
 pub mod certificate;
 mod verifier;
 
+pub use self::verifier::NodeIdMismatch;
+
+/// The TLS server name to use when connecting from `local` to `remote`.
+///
+/// Servers ignore the name, but clients look up the TLS sessions to resume by server name.
+/// A name per pair of nodes ensures session tickets are only ever offered to the node which
+/// issued them, and only by the node they were issued to: resuming a session skips the
+/// client certificate, so a ticket used by another local node would authenticate it as the
+/// node the session was established by.
+pub fn server_name(local: &PublicKey, remote: &PublicKey) -> String {
+    format!("{remote}.{local}.iroh.invalid")
+}
+
 /// Create a TLS client configuration.
 ///
+/// If *remote_peer_id* is set, the handshake fails with [`NodeIdMismatch`] unless the
+/// server presents the certificate of that node.
+///
 /// If *keylog* is `true` this will enable logging of the pre-master key to the file in the
 /// `SSLKEYLOGFILE` environment variable.  This can be used to inspect the traffic for
 /// debugging purposes.
@@ -67,3 +89,90 @@ pub fn make_server_config(
     }
     Ok(crypto)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a TLS handshake between `client` and `server` in memory.
+    fn handshake(
+        client: rustls::ClientConfig,
+        server: rustls::ServerConfig,
+        server_name: &str,
+    ) -> Result<(), rustls::Error> {
+        let mut client =
+            rustls::ClientConnection::new(Arc::new(client), server_name.try_into().unwrap())?;
+        let mut server = rustls::ServerConnection::new(Arc::new(server))?;
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut &buf[..]).unwrap();
+            server.process_new_packets()?;
+
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut &buf[..]).unwrap();
+            client.process_new_packets()?;
+        }
+        panic!("handshake did not complete");
+    }
+
+    #[test]
+    fn test_handshake_verifies_node_id() {
+        let alpn = vec![b"test".to_vec()];
+        let client_key = SecretKey::generate();
+        let server_key = SecretKey::generate();
+        let server = || make_server_config(&server_key, alpn.clone(), false).unwrap();
+
+        let client = make_client_config(&client_key, None, alpn.clone(), false).unwrap();
+        handshake(client, server(), "localhost").unwrap();
+
+        let client =
+            make_client_config(&client_key, Some(server_key.public()), alpn.clone(), false)
+                .unwrap();
+        handshake(client, server(), "localhost").unwrap();
+
+        let other = SecretKey::generate().public();
+        let client = make_client_config(&client_key, Some(other), alpn.clone(), false).unwrap();
+        let err = handshake(client, server(), "localhost").unwrap_err();
+        let rustls::Error::InvalidCertificate(rustls::CertificateError::Other(err)) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        let err = err.downcast_ref::<NodeIdMismatch>().unwrap();
+        assert_eq!(err.expected, other);
+        assert_eq!(err.actual, server_key.public());
+    }
+
+    #[test]
+    fn test_session_tickets_per_node() {
+        use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption};
+
+        let alpn = vec![b"test".to_vec()];
+        let client_key = SecretKey::generate();
+        let server_key = SecretKey::generate();
+        // The cache holds 8 tickets per server, a smaller one evicts every server right away.
+        let store = Arc::new(ClientSessionMemoryCache::new(32));
+
+        let mut client =
+            make_client_config(&client_key, Some(server_key.public()), alpn.clone(), false)
+                .unwrap();
+        client.resumption = Resumption::store(store.clone());
+        let server = make_server_config(&server_key, alpn, false).unwrap();
+        let name = server_name(&client_key.public(), &server_key.public());
+        handshake(client, server, &name).unwrap();
+
+        let other_server = server_name(&client_key.public(), &SecretKey::generate().public());
+        let other_client = server_name(&SecretKey::generate().public(), &server_key.public());
+        for other in [other_server, other_client] {
+            assert!(store
+                .take_tls13_ticket(&other.as_str().try_into().unwrap())
+                .is_none());
+        }
+        assert!(store
+            .take_tls13_ticket(&name.as_str().try_into().unwrap())
+            .is_some());
+    }
+}
@@ -13,8 +13,8 @@ use rustls::{
     },
     client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
     server::{ClientCertVerified, ClientCertVerifier},
-    Certificate, CertificateError, DigitallySignedStruct, DistinguishedName, PeerMisbehaved,
-    SignatureScheme, SupportedCipherSuite, SupportedProtocolVersion,
+    Certificate, CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    SupportedCipherSuite, SupportedProtocolVersion,
 };
 
 use crate::key::PublicKey;
@@ -96,9 +96,12 @@ impl ServerCertVerifier for Libp2pCertificateVerifier {
             // the certificate matches the peer ID they intended to connect to,
             // and MUST abort the connection if there is a mismatch.
             if remote_peer_id != &peer_id {
-                return Err(rustls::Error::PeerMisbehaved(
-                    PeerMisbehaved::BadCertChainExtensions,
-                ));
+                return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                    Arc::new(NodeIdMismatch {
+                        expected: *remote_peer_id,
+                        actual: peer_id,
+                    }),
+                )));
             }
         }
 
@@ -128,6 +131,16 @@ impl ServerCertVerifier for Libp2pCertificateVerifier {
     }
 }
 
+/// The certificate of the server is for a different node than the one we intended to connect to.
+#[derive(Debug, thiserror::Error)]
+#[error("certificate is for node {actual}, expected node {expected}")]
+pub struct NodeIdMismatch {
+    /// The node we intended to connect to.
+    pub expected: PublicKey,
+    /// The node the certificate is for.
+    pub actual: PublicKey,
+}
+
 /// libp2p requires the following of X.509 client certificate chains:
 ///
 /// - Exactly one certificate must be presented. In particular, client
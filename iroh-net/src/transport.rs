@@ -0,0 +1,241 @@
+//! Using a [`MagicEndpoint`] as the transport of another networking stack.
+//!
+//! Stacks modelled after libp2p expect a transport which dials peers by their ID, hands out
+//! inbound connections to be upgraded, and multiplexes streams over a connection.  The
+//! [`Transport`] provides exactly that on top of a [`MagicEndpoint`], so such a stack gets
+//! relaying and hole punching without depending on the QUIC implementation underneath.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    magic_endpoint::{accept_conn, get_remote_node_id},
+    MagicEndpoint, NodeAddr, NodeId,
+};
+
+/// A transport which dials and accepts connections to nodes, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Transport {
+    endpoint: MagicEndpoint,
+}
+
+impl Transport {
+    /// Creates a transport using `endpoint`.
+    ///
+    /// Only connections for the ALPN protocols the endpoint was built with are accepted.
+    pub fn new(endpoint: MagicEndpoint) -> Self {
+        Self { endpoint }
+    }
+
+    /// The underlying endpoint.
+    pub fn endpoint(&self) -> &MagicEndpoint {
+        &self.endpoint
+    }
+
+    /// The ID of the local peer.
+    pub fn local_peer(&self) -> NodeId {
+        self.endpoint.node_id()
+    }
+
+    /// Dials `peer` for `protocol`.
+    ///
+    /// Uses the addressing information the endpoint already has for the peer, or discovery.
+    /// Use [`Transport::dial_addr`] to pass addresses along.
+    pub async fn dial(&self, peer: NodeId, protocol: &[u8]) -> Result<Connection> {
+        self.dial_addr(NodeAddr::new(peer), protocol).await
+    }
+
+    /// Dials the peer at `addr` for `protocol`.
+    pub async fn dial_addr(&self, addr: NodeAddr, protocol: &[u8]) -> Result<Connection> {
+        let peer = addr.node_id;
+        let conn = self.endpoint.connect(addr, protocol).await?;
+        // The TLS handshake already verified this, but never hand out a mislabelled connection.
+        let remote = get_remote_node_id(&conn)?;
+        anyhow::ensure!(remote == peer, "connected to {remote} instead of {peer}");
+        Ok(Connection {
+            peer,
+            protocol: protocol.to_vec(),
+            conn,
+        })
+    }
+
+    /// Waits for the next inbound connection.
+    ///
+    /// Returns `None` once the endpoint is closed.
+    pub async fn accept(&self) -> Option<Inbound> {
+        let connecting = self.endpoint.accept().await?;
+        Some(Inbound { connecting })
+    }
+}
+
+/// An inbound connection which still needs to complete its handshake.
+#[derive(Debug)]
+pub struct Inbound {
+    connecting: quinn::Connecting,
+}
+
+impl Inbound {
+    /// The address the connection comes from.
+    ///
+    /// This is the mapped address of the peer, not a socket address on the network.
+    pub fn remote_address(&self) -> std::net::SocketAddr {
+        self.connecting.remote_address()
+    }
+
+    /// Completes the handshake, authenticating the peer.
+    pub async fn upgrade(self) -> Result<Connection> {
+        let (peer, protocol, conn) = accept_conn(self.connecting)
+            .await
+            .context("inbound handshake")?;
+        Ok(Connection {
+            peer,
+            protocol: protocol.into_bytes(),
+            conn,
+        })
+    }
+}
+
+/// An authenticated connection to a peer, multiplexing [`Substream`]s.
+///
+/// Dropping all clones of the connection closes it.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    peer: NodeId,
+    protocol: Vec<u8>,
+    conn: quinn::Connection,
+}
+
+impl Connection {
+    /// The authenticated ID of the remote peer.
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    /// The protocol negotiated for this connection.
+    pub fn protocol(&self) -> &[u8] {
+        &self.protocol
+    }
+
+    /// Opens a new stream to the peer.
+    ///
+    /// The peer only learns about the stream once data is written to it.
+    pub async fn open_stream(&self) -> Result<Substream> {
+        let (send, recv) = self.conn.open_bi().await?;
+        Ok(Substream { send, recv })
+    }
+
+    /// Waits for the peer to open a stream.
+    pub async fn accept_stream(&self) -> Result<Substream> {
+        let (send, recv) = self.conn.accept_bi().await?;
+        Ok(Substream { send, recv })
+    }
+
+    /// Closes the connection, aborting all its streams.
+    pub fn close(&self, code: u32, reason: &[u8]) {
+        self.conn.close(code.into(), reason)
+    }
+
+    /// Waits until the connection is closed, by either side.
+    ///
+    /// Returns why it was closed.
+    pub async fn closed(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionAborted, self.conn.closed().await)
+    }
+}
+
+/// A bidirectional stream on a [`Connection`].
+///
+/// Shutting down the writing side finishes the stream, the peer reads the end of the stream
+/// after all data written before.
+#[derive(Debug)]
+pub struct Substream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+}
+
+impl AsyncRead for Substream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Substream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::relay::RelayMode;
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/transport";
+
+    async fn transport() -> Transport {
+        let endpoint = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind(0)
+            .await
+            .unwrap();
+        Transport::new(endpoint)
+    }
+
+    #[tokio::test]
+    async fn test_dial_and_accept() {
+        let _logging_guard = iroh_test::logging::setup();
+        let listener = transport().await;
+        let dialer = transport().await;
+        let listener_addr = listener.endpoint().my_addr().await.unwrap();
+        let dialer_id = dialer.local_peer();
+
+        let accept = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap().upgrade().await.unwrap();
+            assert_eq!(conn.peer(), dialer_id);
+            assert_eq!(conn.protocol(), TEST_ALPN);
+            let mut stream = conn.accept_stream().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"hello");
+            stream.write_all(b"world").await.unwrap();
+            stream.shutdown().await.unwrap();
+            conn.closed().await;
+        });
+
+        let conn = dialer.dial_addr(listener_addr, TEST_ALPN).await.unwrap();
+        let mut stream = conn.open_stream().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"world");
+        conn.close(0, b"done");
+
+        accept.await.unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+//! Carrying IP packets between a TUN device and other nodes, for a VPN.
+//!
+//! Only built with the `tun` feature, and only on Linux for now.
+//!
+//! The [`TunAdapter`] creates a TUN device, assigns it an address of the virtual network and
+//! forwards the IP packets the kernel routes into it to other nodes.  The node for each packet
+//! is looked up in the routes set with [`MagicEndpoint::set_routes`], and the packet is sent
+//! as a QUIC datagram on a connection to that node.  Datagrams received from other nodes are
+//! written to the device, if their source address is routed to the node which sent them.
+//!
+//! Packets avoid the stream machinery and most copies on both paths.  Outgoing packets are
+//! read into slices of a preallocated buffer which are handed to QUIC as they are.  Incoming
+//! datagrams of all connections are queued on one [`datagram::channel`] and written to the
+//! device in batches.
+//!
+//! Creating a TUN device requires the `CAP_NET_ADMIN` capability.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use tokio::io::unix::AsyncFd;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    datagram::{self, Datagram, DatagramConnections, DatagramReceiver},
+    magic_endpoint::get_remote_node_id,
+    MagicEndpoint, NodeId,
+};
+
+/// The default MTU of the device.
+///
+/// Packets of this size fit in a QUIC datagram on paths with the minimum QUIC MTU of 1200
+/// bytes.  IPv6 needs an MTU of at least 1280, so it is only usable on the device if the MTU
+/// is raised, which requires all paths to carry larger datagrams.
+pub const DEFAULT_MTU: u16 = 1150;
+
+/// Size of the buffer outgoing packets are read into, reallocated once it is used up.
+const OUTBOUND_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Maximum number of incoming packets written to the device in one go.
+const INBOUND_BATCH_SIZE: usize = 64;
+
+/// `_IOW('T', 202, int)`, not exported by `libc`.
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+
+/// Configuration of the TUN device created by a [`TunAdapter`].
+#[derive(Debug, Clone)]
+pub struct TunConfig {
+    /// Name of the device, may contain `%d` to let the kernel pick a number.
+    pub name: String,
+    /// Address of this node in the virtual network, with the prefix of the network.
+    ///
+    /// The kernel routes packets for the whole prefix into the device.
+    pub address: IpNet,
+    /// MTU of the device, see [`DEFAULT_MTU`].
+    pub mtu: u16,
+}
+
+impl TunConfig {
+    /// Creates a configuration for the device `name` with `address` and the default MTU.
+    pub fn new(name: impl Into<String>, address: IpNet) -> Self {
+        Self {
+            name: name.into(),
+            address,
+            mtu: DEFAULT_MTU,
+        }
+    }
+}
+
+/// Forwards IP packets between a TUN device and other nodes, see the [module docs](self).
+///
+/// The adapter stops when dropped, removing the device.
+#[derive(Debug)]
+pub struct TunAdapter {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    endpoint: MagicEndpoint,
+    alpn: Vec<u8>,
+    device: TunDevice,
+    mtu: usize,
+    /// The connection used to send packets to each node.
+    connections: Mutex<HashMap<NodeId, quinn::Connection>>,
+    /// Nodes a connection is being established to.
+    dialing: Mutex<HashSet<NodeId>>,
+    /// Queues the datagrams received on the connections for [`Shared::run_inbound`].
+    datagrams: DatagramConnections,
+    cancel: CancellationToken,
+}
+
+impl TunAdapter {
+    /// Creates the TUN device described by `config` and starts forwarding packets.
+    ///
+    /// Connections to other nodes are made for the `alpn` protocol.  Connections other nodes
+    /// make to us for it have to be passed to [`TunAdapter::handle_connection`].
+    pub async fn spawn(endpoint: MagicEndpoint, alpn: &[u8], config: TunConfig) -> Result<Self> {
+        let device = TunDevice::create(&config.name)
+            .with_context(|| format!("failed to create TUN device {}", config.name))?;
+        configure(device.name(), config.address, config.mtu)
+            .await
+            .with_context(|| format!("failed to configure TUN device {}", device.name()))?;
+        info!(name = %device.name(), address = %config.address, "TUN device up");
+
+        let (datagrams, receiver) = datagram::channel(datagram::DEFAULT_CAPACITY);
+        let shared = Arc::new(Shared {
+            endpoint,
+            alpn: alpn.to_vec(),
+            device,
+            mtu: config.mtu.into(),
+            connections: Default::default(),
+            dialing: Default::default(),
+            datagrams,
+            cancel: CancellationToken::new(),
+        });
+        tokio::spawn(shared.clone().run_outbound());
+        tokio::spawn(shared.clone().run_inbound(receiver));
+        Ok(Self { shared })
+    }
+
+    /// The name of the TUN device.
+    pub fn name(&self) -> &str {
+        self.shared.device.name()
+    }
+
+    /// Forwards packets received on `conn`, a connection another node made to us.
+    ///
+    /// The connection is also used to send packets to the node.
+    pub fn handle_connection(&self, conn: quinn::Connection) -> Result<()> {
+        let node = get_remote_node_id(&conn)?;
+        self.shared.add_connection(node, conn);
+        Ok(())
+    }
+}
+
+impl Drop for TunAdapter {
+    fn drop(&mut self) {
+        self.shared.cancel.cancel();
+    }
+}
+
+impl Shared {
+    /// Forwards the packets read from the device to their nodes.
+    ///
+    /// Each packet is read into the front of `buf` and split off, so it can be sent without
+    /// copying.  The buffer is reused once all packets split off it are dropped.
+    async fn run_outbound(self: Arc<Self>) {
+        let mut buf = BytesMut::with_capacity(OUTBOUND_BUFFER_SIZE);
+        loop {
+            if buf.capacity() < self.mtu {
+                buf.reserve(OUTBOUND_BUFFER_SIZE);
+            }
+            buf.resize(self.mtu, 0);
+            let res = tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                res = self.device.recv(&mut buf) => res,
+            };
+            match res {
+                Ok(n) => {
+                    buf.truncate(n);
+                    self.send_packet(buf.split().freeze());
+                }
+                Err(err) => {
+                    warn!(name = %self.device.name(), "failed to read from TUN device: {err}");
+                    break;
+                }
+            }
+        }
+        debug!(name = %self.device.name(), "TUN adapter stopped");
+    }
+
+    /// Sends `packet` to the node its destination is routed to.
+    ///
+    /// Packets are dropped while there is no connection to the node yet, like a network
+    /// does while it resolves a link layer address.
+    fn send_packet(self: &Arc<Self>, packet: Bytes) {
+        let Some((_, dst)) = ip_addrs(&packet) else {
+            trace!(len = packet.len(), "dropping packet which is not IP");
+            return;
+        };
+        let Some(node) = self.endpoint.route_lookup(dst) else {
+            trace!(%dst, "no route, dropping packet");
+            return;
+        };
+        let Some(conn) = self.connection(&node) else {
+            self.dial(node);
+            return;
+        };
+        let len = packet.len();
+        if let Err(err) = conn.send_datagram(packet) {
+            debug!(node = %node.fmt_short(), %dst, len, "failed to send packet: {err}");
+        }
+    }
+
+    /// The open connection to `node`, forgetting it if it was closed.
+    fn connection(&self, node: &NodeId) -> Option<quinn::Connection> {
+        let mut connections = self.connections.lock();
+        let conn = connections.get(node)?;
+        if conn.close_reason().is_some() {
+            connections.remove(node);
+            return None;
+        }
+        Some(conn.clone())
+    }
+
+    /// Connects to `node` in the background, unless that is in progress already.
+    fn dial(self: &Arc<Self>, node: NodeId) {
+        if !self.dialing.lock().insert(node) {
+            return;
+        }
+        let this = self.clone();
+        tokio::spawn(async move {
+            let res = tokio::select! {
+                _ = this.cancel.cancelled() => return,
+                res = this.endpoint.connect_by_node_id(&node, &this.alpn) => res,
+            };
+            this.dialing.lock().remove(&node);
+            match res {
+                Ok(conn) => this.add_connection(node, conn),
+                Err(err) => warn!(node = %node.fmt_short(), "failed to connect: {err:#}"),
+            }
+        });
+    }
+
+    /// Uses `conn` to send to `node` and forwards the packets received on it.
+    fn add_connection(&self, node: NodeId, conn: quinn::Connection) {
+        debug!(node = %node.fmt_short(), "TUN connection added");
+        self.connections.lock().insert(node, conn.clone());
+        self.datagrams.add(node, conn);
+    }
+
+    /// Writes the packets received from other nodes to the device.
+    async fn run_inbound(self: Arc<Self>, mut receiver: DatagramReceiver) {
+        let mut batch = Vec::with_capacity(INBOUND_BATCH_SIZE);
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                _ = receiver.recv_batch(&mut batch, INBOUND_BATCH_SIZE) => {}
+            }
+            batch.retain(|(node, packet)| self.is_routed_from(node, packet));
+            if let Err(err) = self.device.send_batch(&batch).await {
+                warn!(name = %self.device.name(), "failed to write to TUN device: {err}");
+                break;
+            }
+            batch.clear();
+        }
+    }
+
+    /// Whether `packet` is an IP packet whose source address is routed to `node`.
+    fn is_routed_from(&self, node: &NodeId, packet: &[u8]) -> bool {
+        let Some((src, _)) = ip_addrs(packet) else {
+            trace!(node = %node.fmt_short(), "dropping packet which is not IP");
+            return false;
+        };
+        if self.endpoint.route_lookup(src).as_ref() != Some(node) {
+            debug!(node = %node.fmt_short(), %src, "dropping packet from unrouted source");
+            return false;
+        }
+        true
+    }
+}
+
+/// Returns the source and destination address of an IP packet.
+fn ip_addrs(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let src: [u8; 4] = packet[12..16].try_into().expect("checked length");
+            let dst: [u8; 4] = packet[16..20].try_into().expect("checked length");
+            Some((Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into()))
+        }
+        6 if packet.len() >= 40 => {
+            let src: [u8; 16] = packet[8..24].try_into().expect("checked length");
+            let dst: [u8; 16] = packet[24..40].try_into().expect("checked length");
+            Some((Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into()))
+        }
+        _ => None,
+    }
+}
+
+/// A TUN device, exchanging IP packets with the kernel.
+#[derive(Debug)]
+struct TunDevice {
+    name: String,
+    fd: AsyncFd<File>,
+}
+
+/// The part of `struct ifreq` used by `TUNSETIFF`.
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+impl TunDevice {
+    /// Creates the TUN device `name`, or attaches to it if it exists.
+    fn create(name: &str) -> io::Result<Self> {
+        if name.len() >= libc::IFNAMSIZ || name.as_bytes().contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid device name",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+            _pad: [0; 22],
+        };
+        for (dst, src) in req.name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        // SAFETY: `req` is a valid request for `TUNSETIFF` and outlives the call.
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req as *mut IfReq) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The kernel fills in the name it picked.
+        let name = req
+            .name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8 as char)
+            .collect();
+        Ok(Self {
+            name,
+            fd: AsyncFd::new(file)?,
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reads the next packet into `buf`, returning its length.
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            if let Ok(res) = guard.try_io(|fd| Read::read(&mut fd.get_ref(), buf)) {
+                return res;
+            }
+        }
+    }
+
+    /// Writes the packets of `batch` to the device.
+    ///
+    /// Writes as many packets as the device takes each time it becomes writable.  A packet
+    /// the kernel rejects is logged and skipped, only failing to wait for the device is an
+    /// error.
+    async fn send_batch(&self, batch: &[Datagram]) -> io::Result<()> {
+        // Index into the batch rather than holding an iterator adapter across the await,
+        // which would make the future not `Send`.
+        let mut next = 0;
+        while next < batch.len() {
+            let mut guard = self.fd.writable().await?;
+            while let Some((_, packet)) = batch.get(next) {
+                let packet: &[u8] = packet;
+                match guard.try_io(|fd| Write::write(&mut fd.get_ref(), packet)) {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => debug!(len = packet.len(), "failed to write packet: {err}"),
+                    // Not writable anymore, wait for it again.
+                    Err(_would_block) => break,
+                }
+                next += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets the MTU and `address` of the device `name` and brings it up.
+async fn configure(name: &str, address: IpNet, mtu: u16) -> Result<()> {
+    let c_name = std::ffi::CString::new(name)?;
+    // SAFETY: `c_name` is a valid C string which outlives the call.
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error()).context("device not found");
+    }
+
+    let (connection, handle, _receiver) = rtnetlink::new_connection()?;
+    let task = tokio::spawn(connection);
+    let res = async {
+        handle.link().set(index).mtu(mtu as u32).execute().await?;
+        handle
+            .address()
+            .add(index, address.addr(), address.prefix_len())
+            .execute()
+            .await?;
+        handle.link().set(index).up().execute().await?;
+        anyhow::Ok(())
+    }
+    .await;
+    task.abort();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_addrs() {
+        let mut v4 = [0u8; 20];
+        v4[0] = 0x45;
+        v4[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        v4[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        assert_eq!(
+            ip_addrs(&v4),
+            Some(("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()))
+        );
+        assert_eq!(ip_addrs(&v4[..19]), None);
+
+        let mut v6 = [0u8; 40];
+        v6[0] = 0x60;
+        v6[8..24].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6[24..40].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(
+            ip_addrs(&v6),
+            Some(("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap()))
+        );
+
+        assert_eq!(ip_addrs(&[]), None);
+        assert_eq!(ip_addrs(&[0x10; 40]), None);
+    }
+}
@@ -3,12 +3,13 @@ use std::collections::BTreeMap;
 use anyhow::Result;
 use futures::{Stream, TryStreamExt};
 use iroh_base::key::PublicKey;
-use iroh_net::magic_endpoint::ConnectionInfo;
+use iroh_net::{magic_endpoint::ConnectionInfo, magicsock::PathPing};
 use quic_rpc::{RpcClient, ServiceConnection};
 
 use crate::rpc_protocol::{
     CounterStats, NodeConnectionInfoRequest, NodeConnectionInfoResponse, NodeConnectionsRequest,
-    NodeShutdownRequest, NodeStatsRequest, NodeStatusRequest, NodeStatusResponse, ProviderService,
+    NodePingRequest, NodePingResponse, NodeShutdownRequest, NodeStatsRequest, NodeStatusRequest,
+    NodeStatusResponse, ProviderService,
 };
 
 use super::flatten;
@@ -44,6 +45,12 @@ where
         Ok(conn_info)
     }
 
+    /// Ping a node on all its known paths, returning the round trip time per path
+    pub async fn ping(&self, node_id: PublicKey) -> Result<Vec<PathPing>> {
+        let NodePingResponse { paths } = self.rpc.rpc(NodePingRequest { node_id }).await??;
+        Ok(paths)
+    }
+
     /// Get status information about a node
     pub async fn status(&self) -> Result<NodeStatusResponse> {
         let response = self.rpc.rpc(NodeStatusRequest).await??;
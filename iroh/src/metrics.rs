@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 
 use iroh_metrics::{
-    core::{Counter, Metric},
+    core::{Counter, Gauge, LabeledCounter, Metric},
     struct_iterable::Iterable,
 };
 
@@ -76,7 +76,7 @@ pub fn get_metrics() -> anyhow::Result<BTreeMap<String, CounterStats>> {
     Ok(map)
 }
 
-// TODO: support other things than counters
+// TODO: support histograms
 fn collect(metrics: Option<&impl Iterable>, map: &mut BTreeMap<String, CounterStats>) {
     let Some(metrics) = metrics else {
         return;
@@ -86,6 +86,17 @@ fn collect(metrics: Option<&impl Iterable>, map: &mut BTreeMap<String, CounterSt
             let value = counter.get();
             let description = counter.description.to_string();
             map.insert(name.to_string(), CounterStats { value, description });
+        } else if let Some(counter) = counter.downcast_ref::<LabeledCounter>() {
+            for label_value in counter.values() {
+                let value = counter.get(&label_value);
+                let description = counter.description.to_string();
+                let name = format!("{name}{{{}={label_value}}}", counter.label);
+                map.insert(name, CounterStats { value, description });
+            }
+        } else if let Some(gauge) = counter.downcast_ref::<Gauge>() {
+            let value = gauge.get().max(0) as u64;
+            let description = gauge.description.to_string();
+            map.insert(name.to_string(), CounterStats { value, description });
         }
     }
 }
@@ -38,9 +38,10 @@ use crate::rpc_protocol::{
     CreateCollectionResponse, DeleteTagRequest, DocExportFileRequest, DocExportFileResponse,
     DocImportFileRequest, DocImportFileResponse, DocImportProgress, DocSetHashRequest,
     ListTagsRequest, ListTagsResponse, NodeConnectionInfoRequest, NodeConnectionInfoResponse,
-    NodeConnectionsRequest, NodeConnectionsResponse, NodeShutdownRequest, NodeStatsRequest,
-    NodeStatsResponse, NodeStatusRequest, NodeStatusResponse, NodeWatchRequest, NodeWatchResponse,
-    ProviderRequest, ProviderService, SetTagOption,
+    NodeConnectionsRequest, NodeConnectionsResponse, NodePingRequest, NodePingResponse,
+    NodeShutdownRequest, NodeStatsRequest, NodeStatsResponse, NodeStatusRequest,
+    NodeStatusResponse, NodeWatchRequest, NodeWatchResponse, ProviderRequest, ProviderService,
+    SetTagOption,
 };
 
 use super::{Event, NodeInner};
@@ -76,6 +77,7 @@ impl<D: BaoStore> Handler<D> {
                         .await
                 }
                 NodeConnectionInfo(msg) => chan.rpc(msg, handler, Self::node_connection_info).await,
+                NodePing(msg) => chan.rpc(msg, handler, Self::node_ping).await,
                 BlobList(msg) => chan.server_streaming(msg, handler, Self::blob_list).await,
                 BlobListIncomplete(msg) => {
                     chan.server_streaming(msg, handler, Self::blob_list_incomplete)
@@ -1005,6 +1007,12 @@ impl<D: BaoStore> Handler<D> {
         Ok(NodeConnectionInfoResponse { conn_info })
     }
 
+    async fn node_ping(self, req: NodePingRequest) -> RpcResult<NodePingResponse> {
+        let NodePingRequest { node_id } = req;
+        let paths = self.inner.endpoint.ping(node_id).await?;
+        Ok(NodePingResponse { paths })
+    }
+
     async fn create_collection(
         self,
         req: CreateCollectionRequest,
@@ -20,6 +20,7 @@ use iroh_bytes::{
 use iroh_net::{
     key::PublicKey,
     magic_endpoint::{ConnectionInfo, NodeAddr},
+    magicsock::PathPing,
 };
 
 use iroh_sync::{
@@ -390,6 +391,24 @@ impl RpcMsg<ProviderService> for NodeConnectionInfoRequest {
     type Response = RpcResult<NodeConnectionInfoResponse>;
 }
 
+/// Ping a specific node on all its known paths
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodePingRequest {
+    /// The node identifier
+    pub node_id: PublicKey,
+}
+
+/// A response to a ping request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodePingResponse {
+    /// The round trip time per path
+    pub paths: Vec<PathPing>,
+}
+
+impl RpcMsg<ProviderService> for NodePingRequest {
+    type Response = RpcResult<NodePingResponse>;
+}
+
 /// A request to shutdown the node
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NodeShutdownRequest {
@@ -1111,6 +1130,7 @@ pub enum ProviderRequest {
     NodeShutdown(NodeShutdownRequest),
     NodeConnections(NodeConnectionsRequest),
     NodeConnectionInfo(NodeConnectionInfoRequest),
+    NodePing(NodePingRequest),
     NodeWatch(NodeWatchRequest),
 
     BlobReadAt(BlobReadAtRequest),
@@ -1168,6 +1188,7 @@ pub enum ProviderResponse {
     NodeStats(RpcResult<NodeStatsResponse>),
     NodeConnections(RpcResult<NodeConnectionsResponse>),
     NodeConnectionInfo(RpcResult<NodeConnectionInfoResponse>),
+    NodePing(RpcResult<NodePingResponse>),
     NodeShutdown(()),
     NodeWatch(NodeWatchResponse),
 